@@ -0,0 +1,583 @@
+//! Reference client of the measurement server. It answers every challenge kind of the protocol
+//! and is also used to test the server end to end.
+
+#[macro_use]
+extern crate log;
+
+use anyhow::{anyhow, Result};
+use futures::{future, Sink, SinkExt, Stream, StreamExt, TryStreamExt};
+use rand::rngs::OsRng;
+use rand::Rng;
+use shared::challenges::bandwidth::{Download, Upload};
+use shared::challenges::datagram::{
+    DatagramReceipt, DatagramTrain, HELLO_INTERVAL_MILLISECONDS, IDLE_TIMEOUT_MILLISECONDS,
+};
+use shared::challenges::timelock::Timelock;
+use shared::chunks::{self, ChunkAssembler};
+use shared::clock::{ClockDiscipline, TimeTick};
+use shared::framing::{self, FrameDecoder};
+use shared::hash::HashAlgorithm;
+use shared::kinds;
+use shared::mac::{KeyExchange, MessageAuthenticator, Role, TAG_SIZE};
+use shared::merkle::{self, merkle_root};
+use shared::nonce;
+use shared::{
+    Challenge, Data, Handshake, MeasurementReport, Message, NetworkType, ProgressUpdate,
+    RejectionReason, Response, SessionStatus,
+};
+use std::cmp;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+/// Bytes read from a TCP connection at once
+const TCP_READ_BUFFER_BYTES: usize = 64 << 10;
+
+#[derive(Clone)]
+pub struct Options {
+    /// WebSocket endpoint of the server, e.g. `ws://127.0.0.1:8080/ws`, or its TCP listener,
+    /// e.g. `tcp://127.0.0.1:8090`
+    pub url: String,
+    /// Answer CPU challenges with a Wesolowski proof, see `Response::CPUChallengeProofResponse`
+    pub with_proof: bool,
+    /// Directory blobs of disk challenges are written to
+    pub disk_directory: PathBuf,
+    /// Token server issued in an earlier session, so that this run joins the same history
+    pub client_token: Option<String>,
+    /// Kind of link declared in the handshake, so that the run is scored and reported with
+    /// others of its kind
+    pub network_type: Option<NetworkType>,
+    /// Version or deployment label declared in the handshake, runs are grouped by it
+    pub client_version: Option<String>,
+}
+
+impl Options {
+    /// `url` with the client token appended as query parameter. Rejections are asked to be sent
+    /// over the connection, as the websocket library only exposes status of a refused upgrade.
+    fn session_url(&self) -> String {
+        let separator = if self.url.contains('?') { '&' } else { '?' };
+        let url = format!("{}{}in_band_rejections=true", self.url, separator);
+        match &self.client_token {
+            Some(token) => format!("{}&client_token={}", url, token),
+            None => url,
+        }
+    }
+}
+
+/// How a session ended, as reported by the server
+#[derive(Debug)]
+pub struct Outcome {
+    pub status: SessionStatus,
+    /// `None` if session failed before the score was calculated
+    pub report: Option<MeasurementReport>,
+    pub stored_run_id: Option<String>,
+    /// Token to present in later sessions, `None` if server issues none
+    pub client_token: Option<String>,
+    /// Running aggregates the server sent after each completed round
+    pub progress: Vec<ProgressUpdate>,
+}
+
+/// Server refused to start a session, see `Message::Rejected`
+#[derive(Debug)]
+pub struct Rejected {
+    pub reason: RejectionReason,
+    /// `None` if retrying is pointless
+    pub retry_after: Option<Duration>,
+    pub message: String,
+}
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Server rejected the session ({:?}): {}",
+            self.reason, self.message
+        )
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+/// How `measure_with_retry` retries sessions the server rejected
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Sessions started at most, including the first one
+    pub max_attempts: u32,
+    /// Backoff stops growing at this, server asking to wait longer still takes precedence
+    pub max_delay: Duration,
+}
+
+/// Delay after `rejections` consecutive rejections. It starts at `retry_after` and doubles with
+/// every rejection up to `max_delay`, plus up to 50% of random jitter, so that clients rejected
+/// at the same time don't all come back at the same time.
+fn backoff(retry_after: Duration, rejections: u32, max_delay: Duration) -> Duration {
+    let delay = retry_after
+        .checked_mul(1 << cmp::min(rejections.saturating_sub(1), 16))
+        .unwrap_or(max_delay);
+    let delay = cmp::max(retry_after, cmp::min(delay, max_delay));
+    delay + delay.mul_f64(rand::thread_rng().gen_range(0.0, 0.5))
+}
+
+/// Answers a challenge, `hash_algorithm` is the one negotiated in the handshake. `nonce` is the
+/// one the challenge was bound to, empty for an unbound challenge.
+fn answer(
+    options: &Options,
+    hash_algorithm: HashAlgorithm,
+    challenge: Challenge,
+    nonce: &[u8],
+) -> Result<Response> {
+    Ok(match challenge {
+        Challenge::CPUChallenge(payload) => {
+            let timelock = Timelock::from_wire(payload)?.bind(nonce);
+            if options.with_proof {
+                let (answer, proof) = timelock.perform_challenge_with_proof();
+                Response::CPUChallengeProofResponse {
+                    answer: answer.to_bytes_be(),
+                    proof: proof.to_bytes_be(),
+                }
+            } else {
+                Response::CPUChallengeResponse(timelock.perform_challenge().to_bytes_be())
+            }
+        }
+        Challenge::NetworkChallenge(payload) => {
+            Response::NetworkChallengeResponse(nonce::bound_echo(nonce, payload))
+        }
+        Challenge::SpotCheckedNetworkChallenge(payload) => {
+            Response::SpotCheckedNetworkChallengeResponse {
+                merkle_root: nonce::bound_merkle_root(nonce, merkle_root(&payload)),
+                data: payload,
+            }
+        }
+        Challenge::DiskChallenge(payload) => {
+            let path = options.disk_directory.join("reliability-disk-challenge");
+            let mut file = fs::File::create(&path)?;
+            file.write_all(&payload)?;
+            // Blob must reach persistent storage, not just the page cache
+            file.sync_all()?;
+            drop(payload);
+            let blob = fs::read(&path)?;
+            fs::remove_file(&path)?;
+            Response::DiskChallengeResponse(nonce::bound_digest(hash_algorithm, nonce, &blob))
+        }
+        Challenge::LatencyProbe(probe) => Response::LatencyProbeResponse(probe),
+        Challenge::DownloadChallenge(payload) => Response::DownloadChallengeResponse(
+            Download::from_wire(payload).perform_challenge(hash_algorithm, nonce),
+        ),
+        Challenge::UploadChallenge(payload) => {
+            Response::UploadChallengeResponse(Upload::from_wire(payload)?.perform_challenge(nonce))
+        }
+        // Needs the server's address, `run_session` answers it before it gets here
+        Challenge::DatagramChallenge(_) => Response::UnsupportedChallenge {
+            kind_id: kinds::challenge::DATAGRAM_CHALLENGE,
+        },
+        // Needs the payload of the preceding round, `run_session` answers it too
+        Challenge::ChunkProofRequest(_) => Response::UnsupportedChallenge {
+            kind_id: kinds::challenge::CHUNK_PROOF_REQUEST,
+        },
+        Challenge::Unsupported { kind_id, .. } => Response::UnsupportedChallenge { kind_id },
+    })
+}
+
+/// Host of the server in `url`, e.g. `127.0.0.1` of `ws://127.0.0.1:8080/ws`
+fn server_host(url: &str) -> Result<&str> {
+    let authority = url
+        .split("://")
+        .nth(1)
+        .and_then(|rest| rest.split(&['/', '?'][..]).next())
+        .ok_or_else(|| anyhow!("No host in {}", url))?;
+    Ok(match authority.strip_prefix('[') {
+        // IPv6 literal, e.g. `[::1]:8080`
+        Some(literal) => literal.split(']').next().unwrap_or(literal),
+        None => authority.split(':').next().unwrap_or(authority),
+    })
+}
+
+/// Echoes probes of a `Challenge::DatagramChallenge` sent to the server at `url`, see
+/// `shared::challenges::datagram`, and returns how many of them arrived
+async fn echo_datagrams(url: &str, train: &DatagramTrain) -> Result<DatagramReceipt> {
+    let server = tokio::net::lookup_host((server_host(url)?, train.port))
+        .await?
+        .next()
+        .ok_or_else(|| anyhow!("Unable to resolve host of {}", url))?;
+    let local: SocketAddr = if server.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let mut socket = UdpSocket::bind(local).await?;
+    socket.connect(server).await?;
+
+    let hello = train.hello();
+    let hello_interval = Duration::from_millis(HELLO_INTERVAL_MILLISECONDS);
+    let idle_timeout = Duration::from_millis(IDLE_TIMEOUT_MILLISECONDS);
+    let started = Instant::now();
+    let mut received = vec![false; train.probes as usize];
+    let mut probes_received = 0;
+    let mut buffer = [0u8; 512];
+    loop {
+        // Hello may be lost as well, so it is repeated until the first probe arrives
+        let wait = if probes_received == 0 {
+            if started.elapsed() >= idle_timeout {
+                break;
+            }
+            socket.send(&hello).await?;
+            hello_interval
+        } else {
+            idle_timeout
+        };
+        let read = match tokio::time::timeout(wait, socket.recv(&mut buffer)).await {
+            Ok(Ok(read)) => read,
+            // E.g. ICMP port unreachable, server stopped listening
+            Ok(Err(e)) => {
+                warn!("Unable to receive datagram: {:?}", e);
+                break;
+            }
+            Err(_) if probes_received == 0 => continue,
+            Err(_) => break,
+        };
+        let datagram = &buffer[..read];
+        let sequence = match train.sequence(datagram) {
+            Some(sequence) if sequence < train.probes => sequence,
+            _ => continue,
+        };
+        socket.send(datagram).await?;
+        if !received[sequence as usize] {
+            received[sequence as usize] = true;
+            probes_received += 1;
+        }
+        if sequence + 1 == train.probes {
+            break;
+        }
+    }
+    Ok(DatagramReceipt { probes_received })
+}
+
+/// Connects to the server and answers its challenges until it closes the session
+pub async fn measure(options: Options) -> Result<Outcome> {
+    if let Some(address) = options.url.strip_prefix("tcp://") {
+        let stream = TcpStream::connect(address)
+            .await
+            .map_err(|e| anyhow!("Unable to connect to {}: {:?}", options.url, e))?;
+        let (reader, writer) = tokio::io::split(stream);
+        let writer = futures::sink::unfold(writer, |mut writer, bytes: Vec<u8>| async move {
+            writer.write_all(&framing::frame(&bytes)?).await?;
+            Ok::<_, anyhow::Error>(writer)
+        });
+        return run_session(options, Box::pin(writer), Box::pin(tcp_messages(reader))).await;
+    }
+    let (ws, _) = tokio_tungstenite::connect_async(options.session_url().as_str())
+        .await
+        .map_err(|e| anyhow!("Unable to connect to {}: {:?}", options.url, e))?;
+    let (writer, reader) = ws.split();
+    let writer = writer
+        .sink_map_err(anyhow::Error::from)
+        .with(|bytes: Vec<u8>| future::ok(WsMessage::binary(bytes)));
+    let reader = reader
+        .map_err(anyhow::Error::from)
+        .take_while(|message| future::ready(!matches!(message, Ok(WsMessage::Close(_)))))
+        .try_filter_map(|message| {
+            future::ok(match message {
+                WsMessage::Binary(bytes) => Some(bytes),
+                _ => None,
+            })
+        });
+    run_session(options, writer, Box::pin(reader)).await
+}
+
+/// Messages read from a TCP connection, each prefixed with its length, see `shared::framing`
+fn tcp_messages(reader: tokio::io::ReadHalf<TcpStream>) -> impl Stream<Item = Result<Vec<u8>>> {
+    // Server is trusted not to send messages larger than it can hold itself
+    let decoder = FrameDecoder::new(usize::MAX);
+    let buffer = vec![0; TCP_READ_BUFFER_BYTES];
+    futures::stream::unfold(Some((reader, decoder, buffer)), |state| async move {
+        let (mut reader, mut decoder, mut buffer) = state?;
+        loop {
+            if let Some(frame) = decoder.next_frame().transpose() {
+                return Some((frame, Some((reader, decoder, buffer))));
+            }
+            match reader.read(&mut buffer).await {
+                Ok(0) => return None,
+                Ok(read) => decoder.extend(&buffer[..read]),
+                Err(e) => return Some((Err(e.into()), None)),
+            }
+        }
+    })
+}
+
+/// Answers challenges read from `reader` until server closes the session
+async fn run_session<W, R>(options: Options, mut writer: W, mut reader: R) -> Result<Outcome>
+where
+    W: Sink<Vec<u8>, Error = anyhow::Error> + Unpin,
+    R: Stream<Item = Result<Vec<u8>>> + Unpin,
+{
+    // Solving challenges blocks, so they are solved on the blocking thread pool
+    let options = Arc::new(options);
+    let mut hash_algorithm = None;
+    let mut report = None;
+    let mut client_token = None;
+    let mut progress_updates = Vec::new();
+    // Server is trusted not to send messages larger than it can hold itself
+    let mut assembler = ChunkAssembler::new(usize::MAX);
+    let mut max_frame_bytes = None;
+    // Set once `ClientHello` is sent if server offered message authentication
+    let mut authenticator: Option<MessageAuthenticator> = None;
+    let mut agreed_authenticator = None;
+    // Payload of the last spot checked network round, server may ask for its chunks again
+    let mut spot_checked_payload: Option<Vec<u8>> = None;
+    // Messages which arrived while a challenge was solved, and whether the stream ended meanwhile
+    let mut received = VecDeque::new();
+    let mut closed = false;
+
+    while let Some(message) = match received.pop_front() {
+        Some(message) => Some(message),
+        None if closed => None,
+        None => reader.next().await,
+    } {
+        let message = match authenticator.as_mut() {
+            Some(authenticator) => authenticator.open(message?)?,
+            None => message?,
+        };
+        let message = Message::decode(&message)?;
+        let message = match message {
+            Message::ChallengeChunk { index, total, data } => {
+                match assembler.push(index, total, &data)? {
+                    Some(message) => message,
+                    None => continue,
+                }
+            }
+            message => message,
+        };
+        let (message, challenge_nonce) = match message {
+            Message::BoundChallenge { nonce, challenge } => (Message::Challenge(challenge), nonce),
+            message => (message, Vec::new()),
+        };
+        let reply = match message {
+            Message::Handshake(Handshake::ServerHello {
+                session_id,
+                hash_algorithms,
+                limits,
+                client_token: issued_token,
+                mac_public_key: server_public_key,
+            }) => {
+                info!("Session {} started, limits: {:?}", session_id, limits);
+                client_token = issued_token;
+                max_frame_bytes = limits
+                    .max_frame_size_kb
+                    .map(|max_frame_size_kb| max_frame_size_kb as usize * 1024);
+                let chosen = *hash_algorithms
+                    .first()
+                    .ok_or_else(|| anyhow!("Server offered no hash algorithm"))?;
+                hash_algorithm = Some(chosen);
+                let mac_public_key = match server_public_key {
+                    Some(server_public_key) => {
+                        let key_exchange = KeyExchange::generate(&mut OsRng);
+                        let public_key = key_exchange.public_key();
+                        agreed_authenticator =
+                            Some(key_exchange.agree(Role::Client, &server_public_key)?);
+                        // Sealed frames are longer by their tag, which must fit in the limit too
+                        max_frame_bytes =
+                            max_frame_bytes.map(|max_frame_bytes| max_frame_bytes - TAG_SIZE);
+                        Some(public_key)
+                    }
+                    None => None,
+                };
+                Message::Handshake(Handshake::ClientHello {
+                    hash_algorithm: chosen,
+                    supports_chunks: true,
+                    network_type: options.network_type,
+                    client_version: options.client_version.clone(),
+                    binds_nonces: true,
+                    mac_public_key,
+                })
+            }
+            // Echoed right away, a trip through the blocking pool would add to the round trip time
+            Message::Challenge(Challenge::LatencyProbe(probe)) => {
+                Message::Response(Response::LatencyProbeResponse(probe))
+            }
+            Message::Challenge(Challenge::DatagramChallenge(payload)) => {
+                let train = DatagramTrain::from_wire(&payload)?;
+                Message::Response(match echo_datagrams(&options.url, &train).await {
+                    Ok(receipt) => Response::DatagramChallengeResponse(receipt.to_wire()),
+                    Err(e) => {
+                        warn!("Unable to take part in datagram probes: {:?}", e);
+                        Response::UnsupportedChallenge {
+                            kind_id: kinds::challenge::DATAGRAM_CHALLENGE,
+                        }
+                    }
+                })
+            }
+            Message::Challenge(Challenge::ChunkProofRequest(request)) => {
+                let chunk_with_proof = merkle::decode_chunk_index(&request).and_then(|index| {
+                    merkle::chunk_with_proof(spot_checked_payload.as_deref()?, index as usize)
+                });
+                Message::Response(match chunk_with_proof {
+                    Some((chunk, proof)) => Response::ChunkProofResponse { chunk, proof },
+                    None => Response::UnsupportedChallenge {
+                        kind_id: kinds::challenge::CHUNK_PROOF_REQUEST,
+                    },
+                })
+            }
+            Message::Challenge(challenge) => {
+                let hash_algorithm =
+                    hash_algorithm.ok_or_else(|| anyhow!("Challenge sent before handshake"))?;
+                if let Challenge::SpotCheckedNetworkChallenge(payload) = &challenge {
+                    spot_checked_payload = Some(payload.clone());
+                }
+                let options = options.clone();
+                let mut solving = tokio::task::spawn_blocking(move || {
+                    answer(&options, hash_algorithm, challenge, &challenge_nonce)
+                });
+                // Reading on meanwhile lets the websocket answer keepalive pings of the server
+                let response = loop {
+                    tokio::select! {
+                        response = &mut solving => break response??,
+                        message = reader.next(), if !closed => match message {
+                            Some(message) => received.push_back(message),
+                            None => closed = true,
+                        },
+                    }
+                };
+                Message::Response(response)
+            }
+            Message::Data(Data::Info(info)) | Message::Data(Data::Result(info)) => {
+                info!("{}", info);
+                continue;
+            }
+            Message::Data(Data::Error { code, message }) => {
+                error!("{} ({:?})", message, code);
+                continue;
+            }
+            Message::Progress(progress) => {
+                info!(
+                    "Completed {}/{} rounds, sub-score of challenge kind {} so far is {}",
+                    progress.rounds_completed,
+                    progress.total_rounds,
+                    progress.kind_id,
+                    progress.sub_score
+                );
+                progress_updates.push(progress);
+                continue;
+            }
+            Message::MeasurementReport(measurement_report) => {
+                report = Some(*measurement_report);
+                continue;
+            }
+            Message::Rejected {
+                reason,
+                retry_after_seconds,
+                message,
+            } => {
+                return Err(Rejected {
+                    reason,
+                    retry_after: retry_after_seconds.map(Duration::from_secs),
+                    message,
+                }
+                .into())
+            }
+            Message::SessionClosed {
+                status,
+                stored_run_id,
+                ..
+            } => {
+                return Ok(Outcome {
+                    status,
+                    report,
+                    stored_run_id,
+                    client_token,
+                    progress: progress_updates,
+                })
+            }
+            message => {
+                warn!("Ignoring unexpected message {}", message);
+                continue;
+            }
+        };
+        // Responses are split the same way as the challenges, for the same proxies
+        let frames = match max_frame_bytes {
+            Some(max_frame_bytes) => chunks::split(reply.encode()?, max_frame_bytes)?,
+            None => vec![reply.encode()?],
+        };
+        for frame in frames {
+            let frame = match authenticator.as_mut() {
+                Some(authenticator) => authenticator.seal(frame),
+                None => frame,
+            };
+            writer.send(frame).await?;
+        }
+        // Messages following `ClientHello` are authenticated
+        if let Some(agreed) = agreed_authenticator.take() {
+            authenticator = Some(agreed);
+        }
+    }
+    Err(anyhow!(
+        "Server closed the connection without closing the session"
+    ))
+}
+
+/// Like `measure`, but when server rejects the session it waits at least as long as the server
+/// asked and tries again, up to `policy.max_attempts` sessions. Other errors are not retried.
+pub async fn measure_with_retry(options: Options, policy: &RetryPolicy) -> Result<Outcome> {
+    let mut rejections = 0;
+    loop {
+        let error = match measure(options.clone()).await {
+            Ok(outcome) => return Ok(outcome),
+            Err(e) => e,
+        };
+        let retry_after = match error.downcast_ref::<Rejected>() {
+            Some(Rejected {
+                retry_after: Some(retry_after),
+                ..
+            }) => *retry_after,
+            _ => return Err(error),
+        };
+        rejections += 1;
+        if rejections >= policy.max_attempts {
+            return Err(error);
+        }
+        let delay = backoff(retry_after, rejections, policy.max_delay);
+        warn!("{}, retrying in {:?}", error, delay);
+        tokio::time::delay_for(delay).await;
+    }
+}
+
+/// Feeds the next `ticks` ticks of the server's clock stream at `url`, e.g.
+/// `ws://127.0.0.1:8080/time`, to `discipline`. Clients monitoring for long call it every now and
+/// then to follow drift of their clock, see `shared::clock`.
+pub async fn discipline_clock(
+    url: &str,
+    discipline: &mut ClockDiscipline,
+    ticks: usize,
+) -> Result<()> {
+    let (mut ws, _) = tokio_tungstenite::connect_async(url)
+        .await
+        .map_err(|e| anyhow!("Unable to connect to {}: {:?}", url, e))?;
+    let mut received = 0;
+    while received < ticks {
+        let message = ws
+            .next()
+            .await
+            .ok_or_else(|| anyhow!("Server closed the clock stream"))??;
+        // Taken before decoding, as late as the tick is observed
+        let arrived_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_micros() as u64)
+            .unwrap_or(0);
+        if let WsMessage::Binary(bytes) = message {
+            discipline.observe(&TimeTick::from_wire(&bytes)?, arrived_at);
+            received += 1;
+        }
+    }
+    // Stream has no end of its own
+    if let Err(e) = ws.close(None).await {
+        debug!("Unable to close clock stream: {:?}", e);
+    }
+    Ok(())
+}
@@ -0,0 +1,112 @@
+use client::{measure_with_retry, Options, RetryPolicy};
+use shared::{NetworkType, SessionStatus};
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+#[structopt(
+    name = "client",
+    about = "Reference client of the reliability measurement server"
+)]
+struct Args {
+    /// WebSocket endpoint of the server, query parameters such as `?tags=region:eu` are passed along
+    #[structopt(default_value = "ws://127.0.0.1:8080/ws")]
+    url: String,
+    /// Answer CPU challenges with a proof anyone can verify
+    #[structopt(long)]
+    with_proof: bool,
+    /// Directory blobs of disk challenges are written to
+    #[structopt(long, parse(from_os_str))]
+    disk_directory: Option<PathBuf>,
+    /// Sessions started at most when server rejects them with a retry-after
+    #[structopt(long, default_value = "3")]
+    attempts: u32,
+    /// Upper bound of the backoff between rejected sessions
+    #[structopt(long, default_value = "300")]
+    max_retry_delay_seconds: u64,
+    /// File the token server issues is kept in, so that runs of this machine form a history
+    #[structopt(long, parse(from_os_str))]
+    client_token_file: Option<PathBuf>,
+    /// Kind of link the machine is on, `wifi`, `ethernet` or `cellular`
+    #[structopt(long, parse(try_from_str = parse_network_type))]
+    network_type: Option<NetworkType>,
+    /// Version or deployment label the run is stored with, version of this client by default
+    #[structopt(long)]
+    client_version: Option<String>,
+}
+
+fn parse_network_type(name: &str) -> anyhow::Result<NetworkType> {
+    NetworkType::from_name(name).ok_or_else(|| anyhow::anyhow!("Unknown network type `{}`", name))
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    pretty_env_logger::init();
+    let args = Args::from_args();
+    // Missing file means no session was measured yet, server issues a token in the first one
+    let client_token = args
+        .client_token_file
+        .as_ref()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .map(|token| token.trim().to_owned())
+        .filter(|token| !token.is_empty());
+
+    let outcome = measure_with_retry(
+        Options {
+            url: args.url,
+            with_proof: args.with_proof,
+            disk_directory: args.disk_directory.unwrap_or_else(std::env::temp_dir),
+            client_token: client_token.clone(),
+            network_type: args.network_type,
+            client_version: args
+                .client_version
+                .or_else(|| Some(env!("CARGO_PKG_VERSION").to_owned())),
+        },
+        &RetryPolicy {
+            max_attempts: args.attempts,
+            max_delay: Duration::from_secs(args.max_retry_delay_seconds),
+        },
+    )
+    .await?;
+
+    if let (Some(path), Some(token)) = (&args.client_token_file, &outcome.client_token) {
+        if Some(token) != client_token.as_ref() {
+            fs::write(path, token)?;
+        }
+    }
+
+    match outcome.report {
+        Some(report) => {
+            println!(
+                "Session {}: score {} ({:.2})",
+                report.session_id, report.score, report.score_breakdown.total
+            );
+            for challenge in report.challenges {
+                println!(
+                    "  kind {}: sub score {}, rounds {:?} ms",
+                    challenge.kind_id, challenge.sub_score, challenge.round_milliseconds
+                );
+            }
+            for rejection in report.score_breakdown.rejections {
+                println!(
+                    "  kind {}: round {} took {} ms, {} ms over the max of {} ms",
+                    rejection.kind_id,
+                    rejection.round,
+                    rejection.round_milliseconds,
+                    rejection.exceeded_by_milliseconds,
+                    rejection.max_milliseconds
+                );
+            }
+        }
+        None => println!("Session ended without a score"),
+    }
+    if outcome.status != SessionStatus::Completed {
+        return Err(anyhow::anyhow!(
+            "Session ended with status {:?}",
+            outcome.status
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,380 @@
+//! Near-line detection of runs whose score deviates from the client's own history or from the
+//! rest of its fleet, so that broken nodes get noticed without building analytics first.
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use crate::dead_letters::{DeadLetterQueue, SideEffect};
+use crate::types::{ClientData, Storage, Visibility};
+
+/// Tag anomalous runs are stored with, its value is the deviation of the first anomaly found
+pub(crate) const ANOMALY_TAG: &str = "anomaly";
+
+/// Least spread of a baseline, so that runs of a client whose scores barely vary aren't
+/// anomalous for being a point off
+const MIN_SPREAD: f64 = 1.0;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum AnomalyRule {
+    /// Scores more than `z_score_threshold` standard deviations away from the mean
+    ZScore,
+    /// Scores more than `iqr_multiplier` interquartile ranges below the first quartile or above
+    /// the third one
+    Iqr,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct AnomalyDetectionConfig {
+    pub(crate) rule: AnomalyRule,
+    pub(crate) z_score_threshold: f64,
+    pub(crate) iqr_multiplier: f64,
+    /// Runs are only compared against baselines of at least this many runs
+    pub(crate) min_samples: usize,
+    /// Number of most recent runs of the client its history is made of
+    pub(crate) client_runs: usize,
+    /// Number of most recent runs of the plan the fleet distribution is made of
+    pub(crate) fleet_runs: usize,
+    /// Tag identifying a client across runs measured without a client identity, e.g. `node`
+    pub(crate) client_tag: Option<String>,
+    /// `http://` endpoint anomalous runs are POSTed to as JSON, `None` only tags them
+    pub(crate) alert_url: Option<String>,
+}
+
+impl Default for AnomalyDetectionConfig {
+    fn default() -> Self {
+        AnomalyDetectionConfig {
+            rule: AnomalyRule::ZScore,
+            z_score_threshold: 3.0,
+            iqr_multiplier: 1.5,
+            min_samples: 5,
+            client_runs: 20,
+            fleet_runs: 500,
+            client_tag: None,
+            alert_url: None,
+        }
+    }
+}
+
+impl AnomalyDetectionConfig {
+    fn validate(&self) -> Result<()> {
+        if !(self.z_score_threshold > 0.0 && self.iqr_multiplier > 0.0) {
+            return Err(anyhow!(
+                "Z-score threshold and IQR multiplier must be positive, got {} and {}",
+                self.z_score_threshold,
+                self.iqr_multiplier
+            ));
+        }
+        if self.min_samples < 2 {
+            return Err(anyhow!(
+                "Baselines need at least 2 runs, got {}",
+                self.min_samples
+            ));
+        }
+        Ok(())
+    }
+
+    /// Expected score of `scores` along with the lowest and highest ones which aren't anomalous,
+    /// `None` if there are too few of them
+    fn expected_range(&self, scores: &mut [u128]) -> Option<(f64, f64, f64)> {
+        if scores.len() < self.min_samples {
+            return None;
+        }
+        scores.sort_unstable();
+        match self.rule {
+            AnomalyRule::ZScore => {
+                let samples = scores.len() as f64;
+                let mean = scores.iter().map(|score| *score as f64).sum::<f64>() / samples;
+                let variance = scores
+                    .iter()
+                    .map(|score| (*score as f64 - mean).powi(2))
+                    .sum::<f64>()
+                    / samples;
+                let spread = self.z_score_threshold * variance.sqrt().max(MIN_SPREAD);
+                Some((mean, mean - spread, mean + spread))
+            }
+            AnomalyRule::Iqr => {
+                let quartile = |quarters: usize| scores[scores.len() * quarters / 4] as f64;
+                let (first, third) = (quartile(1), quartile(3));
+                let spread = self.iqr_multiplier * (third - first).max(MIN_SPREAD);
+                Some((quartile(2), first - spread, third + spread))
+            }
+        }
+    }
+}
+
+/// Runs a run's score is compared against
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Baseline {
+    /// Earlier runs of the same client identity, or with the same value of `client_tag`
+    Client,
+    /// Earlier runs of the same plan
+    Fleet,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Deviation {
+    Low,
+    High,
+}
+
+impl Deviation {
+    fn name(self) -> &'static str {
+        match self {
+            Deviation::Low => "low",
+            Deviation::High => "high",
+        }
+    }
+}
+
+/// Score of a run outside of the range a baseline expects
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub(crate) struct Anomaly {
+    pub(crate) baseline: Baseline,
+    pub(crate) rule: AnomalyRule,
+    pub(crate) deviation: Deviation,
+    /// Mean score of the baseline with `z_score` rule, median with `iqr` one
+    pub(crate) expected_score: f64,
+    pub(crate) min_score: f64,
+    pub(crate) max_score: f64,
+    /// Runs the baseline is made of
+    pub(crate) samples: usize,
+}
+
+#[derive(Serialize)]
+struct AlertPayload<'a> {
+    run_id: String,
+    score: u128,
+    tags: &'a BTreeMap<String, String>,
+    anomalies: &'a [Anomaly],
+}
+
+pub(crate) struct AnomalyDetector {
+    config: AnomalyDetectionConfig,
+    storage: Arc<dyn Storage>,
+    dead_letters: DeadLetterQueue,
+}
+
+impl AnomalyDetector {
+    pub(crate) fn new(
+        config: AnomalyDetectionConfig,
+        storage: Arc<dyn Storage>,
+        dead_letters: DeadLetterQueue,
+    ) -> Result<Self> {
+        config.validate()?;
+        Ok(AnomalyDetector {
+            config,
+            storage,
+            dead_letters,
+        })
+    }
+
+    /// Compares stored run `client_id` against its baselines. Anomalous runs are stored again
+    /// with the anomalies found and the alert endpoint is notified of them.
+    pub(crate) async fn inspect(&self, client_id: u128) -> Result<Vec<Anomaly>> {
+        let mut data = self
+            .storage
+            .get(client_id, Visibility::All)
+            .await?
+            .ok_or_else(|| anyhow!("Run {:x} is not stored", client_id))?;
+        let runs = self.storage.runs(Visibility::All).await?;
+        let anomalies = self.detect(client_id, &data, &runs);
+        let deviation = match anomalies.first() {
+            Some(anomaly) => anomaly.deviation,
+            None => return Ok(anomalies),
+        };
+        warn!(
+            "Score {} of run {:x} is anomalous: {:?}",
+            data.score, client_id, anomalies
+        );
+        data.tags
+            .insert(ANOMALY_TAG.to_owned(), deviation.name().to_owned());
+        data.anomalies = anomalies.clone();
+        let alert = serde_json::to_value(&AlertPayload {
+            run_id: format!("{:x}", client_id),
+            score: data.score,
+            tags: &data.tags,
+            anomalies: &anomalies,
+        })
+        .expect("alert payload is always serializable");
+        self.storage.insert(client_id, data).await?;
+        if let Some(url) = &self.config.alert_url {
+            self.dead_letters
+                .deliver(SideEffect::Webhook {
+                    url: url.clone(),
+                    body: alert,
+                })
+                .await;
+        }
+        Ok(anomalies)
+    }
+
+    /// Anomalies of run `client_id` against completed `runs` started before it, client's
+    /// history first. Partial runs are neither inspected nor part of baselines.
+    fn detect(
+        &self,
+        client_id: u128,
+        data: &ClientData,
+        runs: &HashMap<u128, ClientData>,
+    ) -> Vec<Anomaly> {
+        if !data.is_completed() {
+            return vec![];
+        }
+        let client_tag = self
+            .config
+            .client_tag
+            .as_ref()
+            .and_then(|tag| Some((tag, data.tags.get(tag)?)));
+        let same_client = |run: &ClientData| match (data.identity, client_tag) {
+            (Some(identity), _) => run.identity == Some(identity),
+            (None, Some((tag, value))) => run.tags.get(tag) == Some(value),
+            (None, None) => false,
+        };
+        let same_fleet =
+            |run: &ClientData| run.configuration.plan_name == data.configuration.plan_name;
+
+        [
+            (
+                Baseline::Client,
+                self.config.client_runs,
+                &same_client as &dyn Fn(&ClientData) -> bool,
+            ),
+            (Baseline::Fleet, self.config.fleet_runs, &same_fleet),
+        ]
+        .iter()
+        .filter_map(|(baseline, window, belongs)| {
+            let mut earlier: Vec<&ClientData> = runs
+                .iter()
+                // Runs of other tenants are no baseline, their clients may differ entirely
+                .filter(|(run_id, run)| {
+                    **run_id != client_id
+                        && run.is_completed()
+                        && run.started_at < data.started_at
+                        && run.tenant == data.tenant
+                        && belongs(run)
+                })
+                .map(|(_, run)| run)
+                .collect();
+            earlier.sort_by_key(|run| std::cmp::Reverse(run.started_at));
+            let mut scores: Vec<u128> = earlier.iter().take(*window).map(|run| run.score).collect();
+            let (expected_score, min_score, max_score) = self.config.expected_range(&mut scores)?;
+            let score = data.score as f64;
+            let deviation = if score < min_score {
+                Deviation::Low
+            } else if score > max_score {
+                Deviation::High
+            } else {
+                return None;
+            };
+            Some(Anomaly {
+                baseline: *baseline,
+                rule: self.config.rule,
+                deviation,
+                expected_score,
+                min_score,
+                max_score,
+                samples: scores.len(),
+            })
+        })
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::anomaly::{
+        AnomalyDetectionConfig, AnomalyDetector, AnomalyRule, Baseline, Deviation,
+    };
+    use crate::storage::MemoryStorage;
+    use crate::types::test_utils::client_data;
+    use crate::types::{RunStatus, Storage, Visibility};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_expected_range() {
+        let z_score = AnomalyDetectionConfig::default();
+        assert_eq!(z_score.expected_range(&mut [80, 90, 70, 80]), None);
+        let (expected, min, max) = z_score
+            .expected_range(&mut [70, 80, 90, 70, 80, 90])
+            .unwrap();
+        assert_eq!(expected, 80.0);
+        assert!((min - 55.5).abs() < 0.1 && (max - 104.5).abs() < 0.1);
+        // Identical scores still leave room for a point of noise on each side
+        assert_eq!(
+            z_score.expected_range(&mut [90; 5]),
+            Some((90.0, 87.0, 93.0))
+        );
+
+        let iqr = AnomalyDetectionConfig {
+            rule: AnomalyRule::Iqr,
+            ..Default::default()
+        };
+        assert_eq!(
+            iqr.expected_range(&mut [10, 80, 82, 84, 86, 88, 90, 99]),
+            Some((86.0, 70.0, 102.0))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_inspect() {
+        let storage = Arc::new(MemoryStorage::default());
+        let detector = AnomalyDetector::new(
+            AnomalyDetectionConfig {
+                client_tag: Some("node".to_owned()),
+                ..Default::default()
+            },
+            storage.clone(),
+            Default::default(),
+        )
+        .unwrap();
+
+        for (client_id, score) in (1..=6u64).zip([88, 90, 92, 90, 88, 92]) {
+            let mut data = client_data(score);
+            data.started_at -= Duration::from_secs(60 * (10 - client_id));
+            data.tags.insert("node".to_owned(), "a".to_owned());
+            storage.insert(client_id as u128, data).await.unwrap();
+        }
+        // Another node, scoring lower all along, is only off for the fleet
+        for (client_id, score) in (7..=11u64).zip([50, 52, 48, 50, 50]) {
+            let mut data = client_data(score);
+            data.started_at -= Duration::from_secs(60 * (20 - client_id));
+            data.tags.insert("node".to_owned(), "b".to_owned());
+            storage.insert(client_id as u128, data).await.unwrap();
+        }
+        assert!(detector.inspect(6).await.unwrap().is_empty());
+        // Session of node a which failed midway is no evidence either way, nor part of baselines
+        let mut partial = client_data(0);
+        partial.started_at -= Duration::from_secs(30);
+        partial.tags.insert("node".to_owned(), "a".to_owned());
+        partial.status = RunStatus::FailedAtNetwork(3);
+        storage.insert(0xff, partial).await.unwrap();
+        assert!(detector.inspect(0xff).await.unwrap().is_empty());
+
+        let mut broken = client_data(5);
+        broken.tags.insert("node".to_owned(), "a".to_owned());
+        storage.insert(0x100, broken).await.unwrap();
+        let anomalies = detector.inspect(0x100).await.unwrap();
+        assert_eq!(anomalies.len(), 2);
+        assert_eq!(anomalies[0].baseline, Baseline::Client);
+        assert_eq!(anomalies[0].deviation, Deviation::Low);
+        // Partial run 0xff isn't one of the samples
+        assert_eq!(anomalies[0].samples, 6);
+        assert_eq!(anomalies[1].baseline, Baseline::Fleet);
+        assert_eq!(anomalies[1].samples, 11);
+        let stored = storage.get(0x100, Visibility::All).await.unwrap().unwrap();
+        assert_eq!(stored.tags["anomaly"], "low");
+        assert_eq!(stored.anomalies, anomalies);
+
+        let mut recovered = client_data(50);
+        recovered.tags.insert("node".to_owned(), "b".to_owned());
+        storage.insert(0x101, recovered).await.unwrap();
+        let anomalies = detector.inspect(0x101).await.unwrap();
+        assert!(anomalies.is_empty());
+    }
+}
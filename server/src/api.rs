@@ -0,0 +1,2488 @@
+use serde::{Deserialize, Serialize};
+use shared::challenges::bandwidth::{ThroughputStats, TransferProfile};
+use shared::challenges::datagram::DatagramStats;
+use shared::challenges::latency::LatencyStats;
+use shared::{NetworkType, ScoreBreakdown};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use warp::http::{HeaderValue, StatusCode};
+use warp::{Filter, Rejection, Reply};
+
+use crate::anomaly::Anomaly;
+use crate::capacity::capacity_report;
+use crate::comparison::{compare_dual_stack, compare_runs, compare_tls_overhead};
+use crate::config::{PlanConfig, ServerConfig};
+use crate::export::{ExportFormat, ExportRow};
+use crate::fleet::{summarize, trend, TagQuery};
+use crate::identity::{AuthError, IdentityProvider};
+use crate::measurements::{
+    preview_score, Aggregation, ImplausibleRound, RetriedRound, ScorePreview, ScoringModel,
+    SurpriseRound,
+};
+use crate::metrics::{route_label, tenant_label};
+use crate::progress::stream_progress;
+use crate::signal;
+use crate::storage::{TenantScope, TenantStorage};
+use crate::tuning::current_plan;
+use crate::types::{
+    AddressFamily, ClientData, DurationBreakdown, EventTimestamp, PriorityClass, RunConfiguration,
+    RunStatus, ServerContext, SessionEvent, SessionEventKind, Storage, Visibility,
+};
+use crate::watchdog::MemoryPressure;
+
+/// Dashboard of live sessions, score distribution and client history, served at `/dashboard`
+const DASHBOARD: &str = include_str!("../dashboard/index.html");
+
+pub(crate) fn unix_milliseconds(time: SystemTime) -> u128 {
+    // Time before epoch can only be caused by a badly misconfigured clock
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis())
+        .unwrap_or(0)
+}
+
+#[derive(Serialize)]
+struct EventRecord {
+    wall_clock_unix_milliseconds: u128,
+    session_offset_milliseconds: u128,
+    #[serde(flatten)]
+    kind: SessionEventKind,
+}
+
+impl EventRecord {
+    fn new(event: &SessionEvent) -> Self {
+        let EventTimestamp {
+            wall_clock,
+            session_offset,
+        } = event.timestamp;
+        EventRecord {
+            wall_clock_unix_milliseconds: unix_milliseconds(wall_clock),
+            session_offset_milliseconds: session_offset.as_millis(),
+            kind: event.kind.clone(),
+        }
+    }
+}
+
+/// Representation of a stored run served by the API
+#[derive(Serialize)]
+struct RunRecord<'a> {
+    id: String,
+    score: u128,
+    score_breakdown: &'a ScoreBreakdown,
+    started_at_unix_milliseconds: u128,
+    priority_class: PriorityClass,
+    tags: &'a BTreeMap<String, String>,
+    private: bool,
+    address_family: Option<AddressFamily>,
+    network_type: Option<&'static str>,
+    client_version: Option<&'a str>,
+    dual_stack_of: Option<String>,
+    tls_handshake_milliseconds: Option<u128>,
+    tls_comparison_of: Option<String>,
+    identity: Option<String>,
+    configuration: &'a RunConfiguration,
+    cpu_challenge_timings_in_milis: &'a [u128],
+    cpu_challenge_squarings_per_second: &'a [f64],
+    network_challenge_timings_in_milis: &'a [u128],
+    network_challenge_throughput_in_mbps: &'a [f64],
+    disk_challenge_timings_in_milis: &'a [u128],
+    cpu_verification_timings_in_micros: &'a [u128],
+    network_verification_timings_in_micros: &'a [u128],
+    disk_verification_timings_in_micros: &'a [u128],
+    latency_probe_timings_in_micros: &'a [u128],
+    latency: Option<LatencyStats>,
+    datagram: &'a Option<DatagramStats>,
+    network_round_overlapping_transfers: &'a [usize],
+    network_chunk_arrivals_in_micros: &'a [Vec<u128>],
+    network_chunk_bytes: &'a [Vec<usize>],
+    network_profiles: Vec<Option<TransferProfile>>,
+    download_challenge_timings_in_milis: &'a [u128],
+    upload_challenge_timings_in_milis: &'a [u128],
+    download: Option<ThroughputStats>,
+    upload: Option<ThroughputStats>,
+    events: Vec<EventRecord>,
+    duration_breakdown: &'a DurationBreakdown,
+    anomalies: &'a [Anomaly],
+    implausible_rounds: &'a [ImplausibleRound],
+    retried_rounds: &'a [RetriedRound],
+    status: RunStatus,
+    surprise_rounds: &'a [SurpriseRound],
+    surprise_cpu_slowdown: Option<f64>,
+    principal: Option<&'a str>,
+}
+
+impl<'a> RunRecord<'a> {
+    fn new(client_id: u128, data: &'a ClientData) -> Self {
+        RunRecord {
+            id: format!("{:x}", client_id),
+            score: data.score,
+            score_breakdown: &data.score_breakdown,
+            started_at_unix_milliseconds: unix_milliseconds(data.started_at),
+            priority_class: data.priority_class,
+            tags: &data.tags,
+            private: data.private,
+            address_family: data.address_family,
+            network_type: data.network_type.map(|network_type| network_type.name()),
+            client_version: data.client_version.as_deref(),
+            dual_stack_of: data.dual_stack_of.map(|run_id| format!("{:x}", run_id)),
+            tls_handshake_milliseconds: data.tls_handshake_milliseconds,
+            tls_comparison_of: data.tls_comparison_of.map(|run_id| format!("{:x}", run_id)),
+            identity: data.identity.map(|identity| format!("{:x}", identity)),
+            configuration: &data.configuration,
+            cpu_challenge_timings_in_milis: &data.cpu_challenge_timings_in_milis,
+            cpu_challenge_squarings_per_second: &data.cpu_challenge_squarings_per_second,
+            network_challenge_timings_in_milis: &data.network_challenge_timings_in_milis,
+            network_challenge_throughput_in_mbps: &data.network_challenge_throughput_in_mbps,
+            disk_challenge_timings_in_milis: &data.disk_challenge_timings_in_milis,
+            cpu_verification_timings_in_micros: &data.cpu_verification_timings_in_micros,
+            network_verification_timings_in_micros: &data.network_verification_timings_in_micros,
+            disk_verification_timings_in_micros: &data.disk_verification_timings_in_micros,
+            latency_probe_timings_in_micros: &data.latency_probe_timings_in_micros,
+            latency: data.latency(),
+            datagram: &data.datagram,
+            network_round_overlapping_transfers: &data.network_round_overlapping_transfers,
+            network_chunk_arrivals_in_micros: &data.network_chunk_arrivals_in_micros,
+            network_chunk_bytes: &data.network_chunk_bytes,
+            network_profiles: data.network_profiles(),
+            download_challenge_timings_in_milis: &data.download_challenge_timings_in_milis,
+            upload_challenge_timings_in_milis: &data.upload_challenge_timings_in_milis,
+            download: data.download(),
+            upload: data.upload(),
+            events: data.events.iter().map(EventRecord::new).collect(),
+            duration_breakdown: &data.duration_breakdown,
+            anomalies: &data.anomalies,
+            implausible_rounds: &data.implausible_rounds,
+            retried_rounds: &data.retried_rounds,
+            status: data.status,
+            surprise_rounds: &data.surprise_rounds,
+            surprise_cpu_slowdown: data.surprise_cpu_slowdown,
+            principal: data.principal.as_deref(),
+        }
+    }
+}
+
+/// Short representation of a stored run, used when listing runs
+#[derive(Serialize)]
+struct ClientSummary<'a> {
+    id: String,
+    score: u128,
+    started_at_unix_milliseconds: u128,
+    tags: &'a BTreeMap<String, String>,
+    status: RunStatus,
+}
+
+impl<'a> ClientSummary<'a> {
+    fn new(client_id: u128, data: &'a ClientData) -> Self {
+        ClientSummary {
+            id: format!("{:x}", client_id),
+            score: data.score,
+            started_at_unix_milliseconds: unix_milliseconds(data.started_at),
+            tags: &data.tags,
+            status: data.status,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CompareQuery {
+    a: String,
+    b: String,
+}
+
+fn parse_run_id(id: &str) -> Result<u128, Rejection> {
+    u128::from_str_radix(id, 16).map_err(|_| warp::reject::not_found())
+}
+
+#[derive(Debug)]
+struct StorageUnavailable;
+
+impl warp::reject::Reject for StorageUnavailable {}
+
+/// Logs failure of the storage backend, it's answered with `500 Internal Server Error`
+fn storage_error(e: anyhow::Error) -> Rejection {
+    error!("Unable to query storage: {:?}", e);
+    warp::reject::custom(StorageUnavailable)
+}
+
+/// Request without a valid token, answered with `401 Unauthorized`
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+/// Identity without any role that may read runs, or request to an admin route without an admin
+/// API key, answered with `403 Forbidden`
+#[derive(Debug)]
+struct Forbidden;
+
+impl warp::reject::Reject for Forbidden {}
+
+/// Runs served to a request. A known API key unlocks private runs, otherwise a bearer token
+/// of the identity provider unlocks runs its roles may read.
+async fn authenticate(
+    api_key: Option<String>,
+    authorization: Option<String>,
+    config: Arc<ServerConfig>,
+    identity_provider: Option<Arc<IdentityProvider>>,
+) -> Result<Visibility, Rejection> {
+    let visibility = config.visibility(api_key.as_deref());
+    let identity_provider = match (identity_provider, visibility) {
+        (Some(identity_provider), Visibility::Public) => identity_provider,
+        _ => return Ok(visibility),
+    };
+    match authorization
+        .as_deref()
+        .and_then(|authorization| authorization.strip_prefix("Bearer "))
+    {
+        Some(token) => identity_provider
+            .authorize(token.trim(), SystemTime::now())
+            .map(|authorization| authorization.visibility)
+            .map_err(|e| match e {
+                AuthError::Unauthenticated(e) => {
+                    info!("Rejected bearer token: {}", e);
+                    warp::reject::custom(Unauthorized)
+                }
+                AuthError::Forbidden => warp::reject::custom(Forbidden),
+            }),
+        None if identity_provider.require_login() => Err(warp::reject::custom(Unauthorized)),
+        None => Ok(Visibility::Public),
+    }
+}
+
+/// Tenant a request is confined to: that of its API key, or else that of the roles of its bearer
+/// token, which read runs of the tenant like its reader API keys do
+fn tenant_scope(
+    api_key: Option<String>,
+    authorization: Option<String>,
+    config: &ServerConfig,
+    identity_provider: Option<&IdentityProvider>,
+) -> Option<TenantScope> {
+    if let Some(scope) = config.tenant_scope(api_key.as_deref()) {
+        return Some(scope);
+    }
+    // Same as in `authenticate`, tokens count only for requests which no API key unlocked runs for
+    if config.visibility(api_key.as_deref()) != Visibility::Public {
+        return None;
+    }
+    let token = authorization.as_deref()?.strip_prefix("Bearer ")?;
+    // Invalid tokens are refused by `authenticate`
+    let name = identity_provider?
+        .authorize(token.trim(), SystemTime::now())
+        .ok()?
+        .tenant?;
+    Some(TenantScope {
+        name,
+        api_key_digest: None,
+    })
+}
+
+/// Answers failed authentication and refused admin requests, other rejections are left to warp
+async fn authentication_rejection(
+    rejection: Rejection,
+) -> Result<warp::reply::Response, Rejection> {
+    if rejection.find::<Unauthorized>().is_some() {
+        Ok(
+            warp::reply::with_header(StatusCode::UNAUTHORIZED, "www-authenticate", "Bearer")
+                .into_response(),
+        )
+    } else if rejection.find::<Forbidden>().is_some() {
+        Ok(StatusCode::FORBIDDEN.into_response())
+    } else {
+        Err(rejection)
+    }
+}
+
+async fn compare(
+    query: CompareQuery,
+    visibility: Visibility,
+    storage: Arc<dyn Storage>,
+) -> Result<impl Reply, Rejection> {
+    let a = parse_run_id(&query.a)?;
+    let b = parse_run_id(&query.b)?;
+    let a = storage.get(a, visibility).await.map_err(storage_error)?;
+    let b = storage.get(b, visibility).await.map_err(storage_error)?;
+    match (a, b) {
+        (Some(a), Some(b)) => Ok(warp::reply::json(&compare_runs(&a, &b))),
+        _ => Err(warp::reject::not_found()),
+    }
+}
+
+async fn get_run(
+    id: String,
+    visibility: Visibility,
+    storage: Arc<dyn Storage>,
+) -> Result<impl Reply, Rejection> {
+    let client_id = parse_run_id(&id)?;
+    match storage
+        .get(client_id, visibility)
+        .await
+        .map_err(storage_error)?
+    {
+        Some(data) => Ok(warp::reply::json(&RunRecord::new(client_id, &data))),
+        None => Err(warp::reject::not_found()),
+    }
+}
+
+async fn dual_stack(
+    id: String,
+    visibility: Visibility,
+    storage: Arc<dyn Storage>,
+) -> Result<impl Reply, Rejection> {
+    let client_id = parse_run_id(&id)?;
+    let runs = storage.runs(visibility).await.map_err(storage_error)?;
+    let run = runs.get(&client_id).ok_or_else(warp::reject::not_found)?;
+    // Either half of the pair can be queried, if a run was paired more than once the latest pairing wins
+    let (other_id, other) = match run.dual_stack_of {
+        Some(other_id) => runs.get_key_value(&other_id),
+        None => runs
+            .iter()
+            .filter(|(_, data)| data.dual_stack_of == Some(client_id))
+            .max_by_key(|(_, data)| data.started_at),
+    }
+    .ok_or_else(warp::reject::not_found)?;
+    Ok(warp::reply::json(&compare_dual_stack(
+        (client_id, run),
+        (*other_id, other),
+    )))
+}
+
+async fn tls_overhead(
+    id: String,
+    visibility: Visibility,
+    storage: Arc<dyn Storage>,
+) -> Result<impl Reply, Rejection> {
+    let client_id = parse_run_id(&id)?;
+    let runs = storage.runs(visibility).await.map_err(storage_error)?;
+    let run = runs.get(&client_id).ok_or_else(warp::reject::not_found)?;
+    // Same as for dual-stack pairs, either run can be queried and the latest pairing wins
+    let (other_id, other) = match run.tls_comparison_of {
+        Some(other_id) => runs.get_key_value(&other_id),
+        None => runs
+            .iter()
+            .filter(|(_, data)| data.tls_comparison_of == Some(client_id))
+            .max_by_key(|(_, data)| data.started_at),
+    }
+    .ok_or_else(warp::reject::not_found)?;
+    Ok(warp::reply::json(&compare_tls_overhead(
+        (client_id, run),
+        (*other_id, other),
+    )))
+}
+
+async fn get_certificate(
+    id: String,
+    visibility: Visibility,
+    storage: Arc<dyn Storage>,
+    context: ServerContext,
+) -> Result<impl Reply, Rejection> {
+    let client_id = parse_run_id(&id)?;
+    let signer = context
+        .certificate_signer
+        .as_ref()
+        .ok_or_else(warp::reject::not_found)?;
+    match storage
+        .get(client_id, visibility)
+        .await
+        .map_err(storage_error)?
+    {
+        Some(data) => Ok(warp::reply::json(&signer.sign(client_id, &data))),
+        None => Err(warp::reject::not_found()),
+    }
+}
+
+fn bad_request(e: anyhow::Error) -> warp::reply::Response {
+    warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "error": e.to_string() })),
+        StatusCode::BAD_REQUEST,
+    )
+    .into_response()
+}
+
+const CERTIFICATE_BODY_LIMIT_BYTES: u64 = 16 * 1024;
+
+async fn certificate_keys(context: ServerContext) -> Result<impl Reply, Rejection> {
+    let signer = context
+        .certificate_signer
+        .as_ref()
+        .ok_or_else(warp::reject::not_found)?;
+    Ok(warp::reply::json(&signer.verification_keys()))
+}
+
+/// Body of `POST /certificates/verify`, fields as served by `GET /runs/{id}/certificate`
+#[derive(Deserialize)]
+struct VerifyCertificateRequest {
+    certificate: String,
+    signature: String,
+}
+
+async fn verify_certificate(
+    request: VerifyCertificateRequest,
+    context: ServerContext,
+) -> Result<impl Reply, Rejection> {
+    let signer = context
+        .certificate_signer
+        .as_ref()
+        .ok_or_else(warp::reject::not_found)?;
+    Ok(
+        match signer.verify(&request.certificate, &request.signature) {
+            Ok(verification) => warp::reply::json(&verification).into_response(),
+            Err(e) => bad_request(e),
+        },
+    )
+}
+
+#[derive(Deserialize)]
+struct RotateKeyRequest {
+    /// Hex encoded secret key to sign with from now on
+    signing_key: String,
+}
+
+async fn rotate_certificate_key(
+    api_key: Option<String>,
+    request: RotateKeyRequest,
+    context: ServerContext,
+) -> Result<impl Reply, Rejection> {
+    let signer = context
+        .certificate_signer
+        .as_ref()
+        .ok_or_else(warp::reject::not_found)?;
+    if !signer.may_rotate(api_key.as_deref()) {
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    }
+    Ok(match signer.rotate(&request.signing_key) {
+        Ok(key) => {
+            info!("Rotated certificate signing key to {}", key.key_id);
+            warp::reply::json(&key).into_response()
+        }
+        Err(e) => bad_request(e),
+    })
+}
+
+async fn list_clients(
+    visibility: Visibility,
+    storage: Arc<dyn Storage>,
+) -> Result<impl Reply, Rejection> {
+    let runs = storage.runs(visibility).await.map_err(storage_error)?;
+    let mut clients = runs
+        .iter()
+        .map(|(client_id, data)| ClientSummary::new(*client_id, data))
+        .collect::<Vec<_>>();
+    // Most recent first
+    clients.sort_by_key(|client| Reverse(client.started_at_unix_milliseconds));
+    Ok(warp::reply::json(&clients))
+}
+
+/// Runs of a client identity, oldest first, so that a client's score can be followed over time
+async fn identity_history(
+    id: String,
+    visibility: Visibility,
+    storage: Arc<dyn Storage>,
+) -> Result<impl Reply, Rejection> {
+    let identity = parse_run_id(&id)?;
+    let history = storage
+        .history(identity, visibility)
+        .await
+        .map_err(storage_error)?;
+    if history.is_empty() {
+        return Err(warp::reject::not_found());
+    }
+    Ok(warp::reply::json(
+        &history
+            .iter()
+            .map(|(client_id, data)| ClientSummary::new(*client_id, data))
+            .collect::<Vec<_>>(),
+    ))
+}
+
+/// Score of every completed run, keyed by run id
+async fn scores(
+    visibility: Visibility,
+    storage: Arc<dyn Storage>,
+) -> Result<impl Reply, Rejection> {
+    let scores = storage
+        .runs(visibility)
+        .await
+        .map_err(storage_error)?
+        .iter()
+        .filter(|(_, data)| data.is_completed())
+        .map(|(client_id, data)| (format!("{:x}", client_id), data.score))
+        .collect::<BTreeMap<_, _>>();
+    Ok(warp::reply::json(&scores))
+}
+
+/// Runs with scores in `from..=to`
+#[derive(Serialize)]
+struct ScoreBucket {
+    from: u128,
+    to: u128,
+    runs: usize,
+}
+
+/// Scores of completed runs in buckets of 10 points, the last one including perfect scores
+async fn score_distribution(
+    visibility: Visibility,
+    storage: Arc<dyn Storage>,
+) -> Result<impl Reply, Rejection> {
+    let mut buckets = (0..10)
+        .map(|bucket| ScoreBucket {
+            from: bucket * 10,
+            to: bucket * 10 + if bucket == 9 { 10 } else { 9 },
+            runs: 0,
+        })
+        .collect::<Vec<_>>();
+    for data in storage
+        .runs(visibility)
+        .await
+        .map_err(storage_error)?
+        .values()
+        .filter(|data| data.is_completed())
+    {
+        buckets[(data.score / 10).min(9) as usize].runs += 1;
+    }
+    Ok(warp::reply::json(&buckets))
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    /// Tag query of the client, e.g. `host:web-1`
+    query: String,
+}
+
+/// Runs of clients with tags matching `query`, oldest first
+async fn tag_history(
+    query: HistoryQuery,
+    visibility: Visibility,
+    storage: Arc<dyn Storage>,
+) -> Result<impl Reply, Rejection> {
+    let tag_query = TagQuery::parse(&query.query).map_err(|_| warp::reject::not_found())?;
+    let runs = storage.runs(visibility).await.map_err(storage_error)?;
+    let mut history = runs
+        .iter()
+        .filter(|(_, data)| tag_query.matches(&data.tags))
+        .map(|(client_id, data)| ClientSummary::new(*client_id, data))
+        .collect::<Vec<_>>();
+    history.sort_by(|a, b| {
+        (a.started_at_unix_milliseconds, &a.id).cmp(&(b.started_at_unix_milliseconds, &b.id))
+    });
+    Ok(warp::reply::json(&history))
+}
+
+/// Stored run whose score deviated from earlier runs
+#[derive(Serialize)]
+struct AnomalousRun<'a> {
+    id: String,
+    score: u128,
+    started_at_unix_milliseconds: u128,
+    tags: &'a BTreeMap<String, String>,
+    anomalies: &'a [Anomaly],
+}
+
+/// Anomalous runs, most recent first
+async fn anomalies(
+    visibility: Visibility,
+    storage: Arc<dyn Storage>,
+) -> Result<impl Reply, Rejection> {
+    let runs = storage.runs(visibility).await.map_err(storage_error)?;
+    let mut anomalous = runs
+        .iter()
+        .filter(|(_, data)| !data.anomalies.is_empty())
+        .map(|(client_id, data)| AnomalousRun {
+            id: format!("{:x}", client_id),
+            score: data.score,
+            started_at_unix_milliseconds: unix_milliseconds(data.started_at),
+            tags: &data.tags,
+            anomalies: &data.anomalies,
+        })
+        .collect::<Vec<_>>();
+    anomalous.sort_by_key(|run| Reverse(run.started_at_unix_milliseconds));
+    Ok(warp::reply::json(&anomalous))
+}
+
+async fn live_sessions(
+    visibility: Visibility,
+    tenant: Option<TenantScope>,
+    context: ServerContext,
+) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(
+        &context
+            .session_progress
+            .live_sessions(visibility, tenant.as_ref()),
+    ))
+}
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    format: ExportFormat,
+}
+
+/// Every stored run as a line of `query.format`, oldest first. Lines are serialized as the
+/// response is streamed, rather than the whole export up front.
+async fn export(
+    query: ExportQuery,
+    visibility: Visibility,
+    storage: Arc<dyn Storage>,
+) -> Result<impl Reply, Rejection> {
+    let mut runs = storage
+        .runs(visibility)
+        .await
+        .map_err(storage_error)?
+        .into_iter()
+        .collect::<Vec<_>>();
+    runs.sort_by_key(|(client_id, data)| (data.started_at, *client_id));
+    let format = query.format;
+    let lines = format.header().map(Ok).into_iter().chain(
+        runs.into_iter()
+            .map(move |(client_id, data)| ExportRow::new(client_id, &data).line(format)),
+    );
+    let mut response =
+        warp::reply::Response::new(hyper::Body::wrap_stream(futures::stream::iter(lines)));
+    let headers = response.headers_mut();
+    headers.insert(
+        "content-type",
+        HeaderValue::from_static(format.content_type()),
+    );
+    headers.insert(
+        "content-disposition",
+        HeaderValue::from_static(format.content_disposition()),
+    );
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+struct FleetQuery {
+    /// Tag query, used instead of a named fleet
+    query: Option<String>,
+    /// Number of worst performers to return
+    worst: Option<usize>,
+    /// Width of trend buckets
+    bucket_minutes: Option<u64>,
+    /// Only runs of clients which declared this network type, e.g. `cellular`
+    network_type: Option<String>,
+    /// Only runs of clients which declared this version or deployment label
+    client_version: Option<String>,
+}
+
+fn resolve_fleet(
+    name: &str,
+    query: &FleetQuery,
+    config: &ServerConfig,
+) -> Result<TagQuery, Rejection> {
+    // `_` is reserved for ad-hoc fleets defined by `query` parameter
+    let tag_query = if name == "_" {
+        query.query.as_ref()
+    } else {
+        config.fleets.get(name)
+    }
+    .ok_or_else(warp::reject::not_found)?;
+
+    TagQuery::parse(tag_query).map_err(|_| warp::reject::not_found())
+}
+
+/// Runs of the network type and client version `query` asks for, all of them if it asks for none
+fn filter_runs(runs: &mut HashMap<u128, ClientData>, query: &FleetQuery) -> Result<(), Rejection> {
+    if let Some(name) = &query.network_type {
+        let network_type = NetworkType::from_name(name).ok_or_else(warp::reject::not_found)?;
+        runs.retain(|_, data| data.network_type == Some(network_type));
+    }
+    if let Some(client_version) = &query.client_version {
+        runs.retain(|_, data| data.client_version.as_ref() == Some(client_version));
+    }
+    Ok(())
+}
+
+async fn fleet_summary(
+    name: String,
+    query: FleetQuery,
+    visibility: Visibility,
+    storage: Arc<dyn Storage>,
+    config: Arc<ServerConfig>,
+) -> Result<impl Reply, Rejection> {
+    let tag_query = resolve_fleet(&name, &query, &config)?;
+    let mut runs = storage.runs(Visibility::All).await.map_err(storage_error)?;
+    filter_runs(&mut runs, &query)?;
+    Ok(warp::reply::json(&summarize(
+        &runs,
+        &tag_query,
+        query.worst.unwrap_or(10),
+        visibility,
+    )))
+}
+
+/// Widest bucket of `/fleets/{name}/trend`, 30 days
+const MAX_TREND_BUCKET_MINUTES: u64 = 30 * 24 * 60;
+
+async fn fleet_trend(
+    name: String,
+    query: FleetQuery,
+    storage: Arc<dyn Storage>,
+    config: Arc<ServerConfig>,
+) -> Result<impl Reply, Rejection> {
+    let tag_query = resolve_fleet(&name, &query, &config)?;
+    let bucket_minutes = query.bucket_minutes.unwrap_or(60);
+    if !(1..=MAX_TREND_BUCKET_MINUTES).contains(&bucket_minutes) {
+        return Ok(bad_request(anyhow::anyhow!(
+            "bucket_minutes must be between 1 and {}",
+            MAX_TREND_BUCKET_MINUTES
+        )));
+    }
+    let bucket = Duration::from_secs(bucket_minutes * 60);
+    let mut runs = storage.runs(Visibility::All).await.map_err(storage_error)?;
+    filter_runs(&mut runs, &query)?;
+    Ok(warp::reply::json(&trend(&runs, &tag_query, bucket)).into_response())
+}
+
+/// Admin routes expose state of the server and payloads of side effects, so they are only
+/// served to requests with an admin API key
+async fn authorize_admin(
+    api_key: Option<String>,
+    config: Arc<ServerConfig>,
+) -> Result<(), Rejection> {
+    if config.is_admin(api_key.as_deref()) {
+        Ok(())
+    } else {
+        Err(warp::reject::custom(Forbidden))
+    }
+}
+
+#[derive(Deserialize)]
+struct SignalQuery {
+    /// Width of the window scores are averaged over
+    window_minutes: Option<u64>,
+}
+
+/// Widest window of `/signal`, a week
+const MAX_SIGNAL_WINDOW_MINUTES: u64 = 7 * 24 * 60;
+
+async fn signal(query: SignalQuery, context: ServerContext) -> Result<impl Reply, Rejection> {
+    let now = SystemTime::now();
+    let window_minutes = query.window_minutes.unwrap_or(15);
+    if !(1..=MAX_SIGNAL_WINDOW_MINUTES).contains(&window_minutes) {
+        return Ok(bad_request(anyhow::anyhow!(
+            "window_minutes must be between 1 and {}",
+            MAX_SIGNAL_WINDOW_MINUTES
+        )));
+    }
+    let window = Duration::from_secs(window_minutes * 60);
+    let memory_pressure = context
+        .memory_watchdog
+        .as_ref()
+        .map(|memory_watchdog| memory_watchdog.pressure())
+        .unwrap_or(MemoryPressure::Normal);
+    let runs = context
+        .storage
+        .runs(Visibility::All)
+        .await
+        .map_err(storage_error)?;
+    let capacity = capacity_report(
+        &runs,
+        &context.verification_pool,
+        &context.active_sessions,
+        context.session_limit.as_ref(),
+        now,
+    );
+    Ok(warp::reply::json(&signal::signal(
+        &runs,
+        &capacity,
+        memory_pressure,
+        now,
+        window,
+    ))
+    .into_response())
+}
+
+/// Largest accepted body of `POST /score/preview`
+const SCORE_PREVIEW_BODY_LIMIT_BYTES: u64 = 1024 * 1024;
+
+/// Scoring thresholds to preview, ones left out are those of the configured plan
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct CandidateThresholds {
+    cpu_ideal_milliseconds: Option<u64>,
+    cpu_max_milliseconds: Option<u64>,
+    cpu_ideal_squarings_per_second: Option<f64>,
+    cpu_min_squarings_per_second: Option<f64>,
+    network_ideal_milliseconds: Option<u64>,
+    network_max_milliseconds: Option<u64>,
+    network_ideal_mbps: Option<f64>,
+    network_min_mbps: Option<f64>,
+    disk_ideal_milliseconds: Option<u64>,
+    disk_max_milliseconds: Option<u64>,
+    cpu_aggregation: Option<Aggregation>,
+    network_aggregation: Option<Aggregation>,
+    disk_aggregation: Option<Aggregation>,
+    scoring: Option<ScoringModel>,
+}
+
+impl CandidateThresholds {
+    fn apply(&self, plan: &PlanConfig) -> PlanConfig {
+        PlanConfig {
+            cpu_ideal_milliseconds: self
+                .cpu_ideal_milliseconds
+                .unwrap_or(plan.cpu_ideal_milliseconds),
+            cpu_max_milliseconds: self
+                .cpu_max_milliseconds
+                .unwrap_or(plan.cpu_max_milliseconds),
+            cpu_ideal_squarings_per_second: self
+                .cpu_ideal_squarings_per_second
+                .or(plan.cpu_ideal_squarings_per_second),
+            cpu_min_squarings_per_second: self
+                .cpu_min_squarings_per_second
+                .or(plan.cpu_min_squarings_per_second),
+            network_ideal_milliseconds: self
+                .network_ideal_milliseconds
+                .unwrap_or(plan.network_ideal_milliseconds),
+            network_max_milliseconds: self
+                .network_max_milliseconds
+                .unwrap_or(plan.network_max_milliseconds),
+            network_ideal_mbps: self.network_ideal_mbps.or(plan.network_ideal_mbps),
+            network_min_mbps: self.network_min_mbps.or(plan.network_min_mbps),
+            disk_ideal_milliseconds: self
+                .disk_ideal_milliseconds
+                .unwrap_or(plan.disk_ideal_milliseconds),
+            disk_max_milliseconds: self
+                .disk_max_milliseconds
+                .unwrap_or(plan.disk_max_milliseconds),
+            cpu_aggregation: self.cpu_aggregation.unwrap_or(plan.cpu_aggregation),
+            network_aggregation: self.network_aggregation.unwrap_or(plan.network_aggregation),
+            disk_aggregation: self.disk_aggregation.unwrap_or(plan.disk_aggregation),
+            scoring: self.scoring.unwrap_or(plan.scoring),
+            ..plan.clone()
+        }
+    }
+}
+
+/// Body of `POST /score/preview`. Timings are named as in `RunRecord`, so a run
+/// served by `GET /runs/{id}` can be posted as is, along with `thresholds`.
+#[derive(Deserialize)]
+struct ScorePreviewRequest {
+    #[serde(default)]
+    cpu_challenge_timings_in_milis: Vec<u128>,
+    #[serde(default)]
+    network_challenge_timings_in_milis: Vec<u128>,
+    #[serde(default)]
+    disk_challenge_timings_in_milis: Vec<u128>,
+    #[serde(default)]
+    thresholds: CandidateThresholds,
+}
+
+#[derive(Serialize)]
+struct ScorePreviewResponse {
+    /// Score with the candidate thresholds
+    candidate: ScorePreview,
+    /// Score with the thresholds server currently measures with
+    configured: ScorePreview,
+}
+
+async fn score_preview(
+    request: ScorePreviewRequest,
+    context: ServerContext,
+    config: Arc<ServerConfig>,
+) -> Result<impl Reply, Rejection> {
+    let plan = current_plan(&context, &config);
+    let preview = |plan: &PlanConfig| {
+        preview_score(
+            plan,
+            &request.cpu_challenge_timings_in_milis,
+            &request.network_challenge_timings_in_milis,
+            &request.disk_challenge_timings_in_milis,
+        )
+    };
+    let response = preview(&request.thresholds.apply(&plan)).and_then(|candidate| {
+        Ok(ScorePreviewResponse {
+            candidate,
+            configured: preview(&plan)?,
+        })
+    });
+    Ok(match response {
+        Ok(response) => warp::reply::json(&response).into_response(),
+        Err(e) => bad_request(e.into()),
+    })
+}
+
+async fn tasks(context: ServerContext) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&context.tasks.snapshot()))
+}
+
+async fn dead_letters(context: ServerContext) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&context.dead_letters.list()))
+}
+
+async fn requeue_dead_letter(id: String, context: ServerContext) -> Result<impl Reply, Rejection> {
+    if !context.dead_letters.requeue(&id) {
+        return Err(warp::reject::not_found());
+    }
+    info!("Requeued dead letter {}", id);
+    Ok(StatusCode::ACCEPTED)
+}
+
+async fn purge_dead_letter(id: String, context: ServerContext) -> Result<impl Reply, Rejection> {
+    if !context.dead_letters.purge(&id) {
+        return Err(warp::reject::not_found());
+    }
+    info!("Purged dead letter {}", id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn purge_dead_letters(context: ServerContext) -> Result<impl Reply, Rejection> {
+    let purged = context.dead_letters.purge_all();
+    info!("Purged {} dead letters", purged);
+    Ok(warp::reply::json(&serde_json::json!({ "purged": purged })))
+}
+
+/// Upgrades to a websocket streaming events of sessions in flight, see `progress`
+async fn watch_sessions(ws: warp::ws::Ws, context: ServerContext) -> Result<impl Reply, Rejection> {
+    let progress = context.session_progress.clone();
+    Ok(ws.on_upgrade(move |socket| stream_progress(socket, progress)))
+}
+
+async fn primes(context: ServerContext) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&context.primes.metrics()))
+}
+
+async fn thresholds(context: ServerContext) -> Result<impl Reply, Rejection> {
+    let threshold_tuner = context
+        .threshold_tuner
+        .as_ref()
+        .ok_or_else(warp::reject::not_found)?;
+    Ok(warp::reply::json(&threshold_tuner.report()))
+}
+
+async fn request_metrics(context: ServerContext) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&context.request_metrics.report()))
+}
+
+async fn capacity(context: ServerContext) -> Result<impl Reply, Rejection> {
+    let runs = context
+        .storage
+        .runs(Visibility::All)
+        .await
+        .map_err(storage_error)?;
+    Ok(warp::reply::json(&capacity_report(
+        &runs,
+        &context.verification_pool,
+        &context.active_sessions,
+        context.session_limit.as_ref(),
+        SystemTime::now(),
+    )))
+}
+
+/// HTTP API for querying stored runs and state of the server
+pub fn routes(
+    context: ServerContext,
+    config: Arc<ServerConfig>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    // Requests with an API key or a token of a tenant are confined to runs of its clients, other
+    // requests to runs of clients of no tenant
+    let tenant = {
+        let config = config.clone();
+        let identity_provider = context.identity_provider.clone();
+        warp::header::optional::<String>("x-api-key")
+            .and(warp::header::optional::<String>("authorization"))
+            .map(move |api_key, authorization| {
+                tenant_scope(
+                    api_key,
+                    authorization,
+                    &config,
+                    identity_provider.as_deref(),
+                )
+            })
+    };
+    let storage = {
+        let storage = context.storage.clone();
+        tenant.clone().map(move |tenant| {
+            Arc::new(TenantStorage::new(storage.clone(), tenant)) as Arc<dyn Storage>
+        })
+    };
+    // Private runs are only served to clients with a known API key or an authorized token
+    let visibility = {
+        let config = config.clone();
+        let identity_provider = context.identity_provider.clone();
+        warp::header::optional::<String>("x-api-key")
+            .and(warp::header::optional::<String>("authorization"))
+            .and(warp::any().map(move || config.clone()))
+            .and(warp::any().map(move || identity_provider.clone()))
+            .and_then(authenticate)
+    };
+    let admin = {
+        let config = config.clone();
+        warp::header::optional::<String>("x-api-key")
+            .and(warp::any().map(move || config.clone()))
+            .and_then(authorize_admin)
+            .untuple_one()
+    };
+    let record_metrics = {
+        let config = config.clone();
+        let request_metrics = context.request_metrics.clone();
+        warp::log::custom(move |info| {
+            if let Some(route) = route_label(&config.base_path, info.path()) {
+                let headers = info.request_headers();
+                let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+                request_metrics.record(
+                    route,
+                    info.method().as_str(),
+                    tenant_label(&config, header("x-api-key"), header("authorization")),
+                    info.status().as_u16(),
+                    info.elapsed(),
+                );
+            }
+        })
+    };
+    let context = warp::any().map(move || context.clone());
+    let config = warp::any().map(move || config.clone());
+
+    let get_run = warp::path!("runs" / String)
+        .or(warp::path!("clients" / String))
+        .unify()
+        .and(warp::get())
+        .and(visibility.clone())
+        .and(storage.clone())
+        .and_then(get_run);
+
+    let dual_stack = warp::path!("runs" / String / "dual_stack")
+        .and(warp::get())
+        .and(visibility.clone())
+        .and(storage.clone())
+        .and_then(dual_stack);
+
+    let tls_overhead = warp::path!("runs" / String / "tls_overhead")
+        .and(warp::get())
+        .and(visibility.clone())
+        .and(storage.clone())
+        .and_then(tls_overhead);
+
+    let get_certificate = warp::path!("runs" / String / "certificate")
+        .and(warp::get())
+        .and(visibility.clone())
+        .and(storage.clone())
+        .and(context.clone())
+        .and_then(get_certificate);
+
+    let certificate_keys = warp::path!("certificates" / "keys")
+        .and(warp::get())
+        .and(context.clone())
+        .and_then(certificate_keys);
+
+    let verify_certificate = warp::path!("certificates" / "verify")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(
+            CERTIFICATE_BODY_LIMIT_BYTES,
+        ))
+        .and(warp::body::json())
+        .and(context.clone())
+        .and_then(verify_certificate);
+
+    let rotate_certificate_key = warp::path!("admin" / "certificate" / "rotate")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("x-api-key"))
+        .and(warp::body::content_length_limit(
+            CERTIFICATE_BODY_LIMIT_BYTES,
+        ))
+        .and(warp::body::json())
+        .and(context.clone())
+        .and_then(rotate_certificate_key);
+
+    let list_clients = warp::path!("clients")
+        .and(warp::get())
+        .and(visibility.clone())
+        .and(storage.clone())
+        .and_then(list_clients);
+
+    let identity_history = warp::path!("identities" / String)
+        .and(warp::get())
+        .and(visibility.clone())
+        .and(storage.clone())
+        .and_then(identity_history);
+
+    let scores = warp::path!("scores")
+        .and(warp::get())
+        .and(visibility.clone())
+        .and(storage.clone())
+        .and_then(scores);
+
+    let anomalies = warp::path!("anomalies")
+        .and(warp::get())
+        .and(visibility.clone())
+        .and(storage.clone())
+        .and_then(anomalies);
+
+    let live_sessions = warp::path!("api" / "sessions")
+        .and(warp::get())
+        .and(visibility.clone())
+        .and(tenant)
+        .and(context.clone())
+        .and_then(live_sessions);
+
+    let score_distribution = warp::path!("api" / "scores" / "distribution")
+        .and(warp::get())
+        .and(visibility.clone())
+        .and(storage.clone())
+        .and_then(score_distribution);
+
+    let tag_history = warp::path!("api" / "history")
+        .and(warp::get())
+        .and(warp::query::<HistoryQuery>())
+        .and(visibility.clone())
+        .and(storage.clone())
+        .and_then(tag_history);
+
+    // Single page querying the routes above, the API key is entered on the page
+    let dashboard = warp::path!("dashboard")
+        .and(warp::get())
+        .map(|| warp::reply::html(DASHBOARD));
+
+    let export = warp::path!("export")
+        .and(warp::get())
+        .and(warp::query::<ExportQuery>())
+        .and(visibility.clone())
+        .and(storage.clone())
+        .and_then(export);
+
+    let compare = warp::path!("compare")
+        .and(warp::get())
+        .and(warp::query::<CompareQuery>())
+        .and(visibility.clone())
+        .and(storage.clone())
+        .and_then(compare);
+
+    let fleet_summary = warp::path!("fleets" / String)
+        .and(warp::get())
+        .and(warp::query::<FleetQuery>())
+        .and(visibility.clone())
+        .and(storage.clone())
+        .and(config.clone())
+        .and_then(fleet_summary);
+
+    let fleet_trend = warp::path!("fleets" / String / "trend")
+        .and(warp::get())
+        .and(warp::query::<FleetQuery>())
+        .and(storage)
+        .and(config.clone())
+        .and_then(fleet_trend);
+
+    let score_preview = warp::path!("score" / "preview")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(
+            SCORE_PREVIEW_BODY_LIMIT_BYTES,
+        ))
+        .and(warp::body::json())
+        .and(context.clone())
+        .and(config)
+        .and_then(score_preview);
+
+    let capacity = warp::path!("admin" / "capacity")
+        .and(warp::get())
+        .and(admin.clone())
+        .and(context.clone())
+        .and_then(capacity);
+
+    let signal = warp::path!("signal")
+        .and(warp::get())
+        .and(warp::query::<SignalQuery>())
+        .and(context.clone())
+        .and_then(signal);
+
+    let tasks = warp::path!("admin" / "tasks")
+        .and(warp::get())
+        .and(admin.clone())
+        .and(context.clone())
+        .and_then(tasks);
+
+    let primes = warp::path!("admin" / "primes")
+        .and(warp::get())
+        .and(admin.clone())
+        .and(context.clone())
+        .and_then(primes);
+
+    let thresholds = warp::path!("admin" / "thresholds")
+        .and(warp::get())
+        .and(admin.clone())
+        .and(context.clone())
+        .and_then(thresholds);
+
+    let metrics = warp::path!("admin" / "metrics")
+        .and(warp::get())
+        .and(admin.clone())
+        .and(context.clone())
+        .and_then(request_metrics);
+
+    let dead_letters = warp::path!("admin" / "dead_letters")
+        .and(warp::get())
+        .and(admin.clone())
+        .and(context.clone())
+        .and_then(dead_letters);
+
+    let requeue_dead_letter = warp::path!("admin" / "dead_letters" / String / "requeue")
+        .and(warp::post())
+        .and(admin.clone())
+        .and(context.clone())
+        .and_then(requeue_dead_letter);
+
+    let purge_dead_letter = warp::path!("admin" / "dead_letters" / String)
+        .and(warp::delete())
+        .and(admin.clone())
+        .and(context.clone())
+        .and_then(purge_dead_letter);
+
+    let purge_dead_letters = warp::path!("admin" / "dead_letters")
+        .and(warp::delete())
+        .and(admin.clone())
+        .and(context.clone())
+        .and_then(purge_dead_letters);
+
+    let watch_sessions = warp::path!("admin" / "ws")
+        .and(warp::ws())
+        .and(admin)
+        .and(context)
+        .and_then(watch_sessions);
+
+    get_run
+        .or(dual_stack)
+        .or(tls_overhead)
+        .or(get_certificate)
+        .or(certificate_keys)
+        .or(verify_certificate)
+        .or(rotate_certificate_key)
+        .or(list_clients)
+        .or(identity_history)
+        .or(scores)
+        .or(live_sessions)
+        .or(score_distribution)
+        .or(tag_history)
+        .or(dashboard)
+        .or(anomalies)
+        .or(export)
+        .or(compare)
+        .or(fleet_summary)
+        .or(fleet_trend)
+        .or(score_preview)
+        .or(capacity)
+        .or(signal)
+        .or(tasks)
+        .or(primes)
+        .or(thresholds)
+        .or(metrics)
+        .or(dead_letters)
+        .or(requeue_dead_letter)
+        .or(purge_dead_letter)
+        .or(purge_dead_letters)
+        .or(watch_sessions)
+        .recover(authentication_rejection)
+        .with(record_metrics)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::anomaly::{Anomaly, AnomalyRule, Baseline, Deviation};
+    use crate::api::routes;
+    use crate::certificate::{CertificateConfig, CertificateSigner};
+    use crate::config::{ServerConfig, TenantConfig};
+    use crate::dead_letters::SideEffect;
+    use crate::fleet::parse_tags;
+    use crate::identity::test_utils::{ec_key, identity_provider, sign, RS256_TOKEN};
+    use crate::storage::api_key_digest;
+    use crate::tasks::TaskKind;
+    use crate::tuning::ThresholdTuner;
+    use crate::types::test_utils::{client_data, server_context};
+    use crate::types::{
+        AddressFamily, PriorityClass, RunStatus, SessionClock, SessionEvent, SessionEventKind,
+        SessionEvents,
+    };
+    use shared::kinds;
+    use shared::NetworkType;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// Config whose `admin` API key may use admin routes
+    fn admin_config() -> Arc<ServerConfig> {
+        let mut config = ServerConfig::default();
+        config.admin_api_keys.insert("admin".to_owned());
+        Arc::new(config)
+    }
+
+    #[tokio::test]
+    async fn test_get_run() {
+        let context = server_context();
+        let mut data = client_data(90);
+        data.events.push(SessionEvent {
+            timestamp: SessionClock::start().now(),
+            kind: SessionEventKind::ScoreCalculated { score: 90 },
+        });
+        context.storage.insert(0xabc, data).await.unwrap();
+
+        let filter = routes(context, Default::default());
+
+        let response = warp::test::request().path("/runs/abc").reply(&filter).await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["id"], "abc");
+        assert_eq!(body["score"], 90);
+        assert_eq!(body["configuration"]["squarings"], 10);
+        assert_eq!(body["configuration"]["hash_algorithm"], "Sha256");
+        assert_eq!(body["network_challenge_timings_in_milis"][0], 200);
+        assert!(
+            body["network_challenge_throughput_in_mbps"][0]
+                .as_f64()
+                .unwrap()
+                > 0.08
+        );
+        assert_eq!(body["events"][0]["kind"], "score_calculated");
+        assert_eq!(body["duration_breakdown"]["verification_microseconds"], 0);
+
+        let response = warp::test::request().path("/runs/abd").reply(&filter).await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_tenants() {
+        let context = server_context();
+        let mut payments = client_data(60);
+        payments.tenant = Some("payments".to_owned());
+        payments.private = true;
+        payments.api_key_digest = Some(api_key_digest("k3y"));
+        context.storage.insert(0xabc, payments).await.unwrap();
+        let mut other_client = client_data(70);
+        other_client.tenant = Some("payments".to_owned());
+        other_client.private = true;
+        other_client.api_key_digest = Some(api_key_digest("0th3r"));
+        context.storage.insert(0xabe, other_client).await.unwrap();
+        context
+            .storage
+            .insert(0xabd, client_data(90))
+            .await
+            .unwrap();
+        let _session = SessionEvents::new(
+            &context,
+            0xabf,
+            "default".to_owned(),
+            true,
+            Some("payments".to_owned()),
+            Some(api_key_digest("0th3r")),
+            HashMap::new(),
+        );
+        let config = ServerConfig {
+            private_tenants: vec!["0ps".to_owned()].into_iter().collect(),
+            tenants: vec![(
+                "payments".to_owned(),
+                TenantConfig {
+                    api_keys: vec!["k3y".to_owned(), "0th3r".to_owned()]
+                        .into_iter()
+                        .collect(),
+                    reader_api_keys: vec!["r3ad".to_owned()].into_iter().collect(),
+                    plan: None,
+                },
+            )]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        };
+        let filter = routes(context, Arc::new(config));
+        let get = |path: &'static str, api_key: Option<&'static str>| {
+            let request = warp::test::request().path(path);
+            let request = match api_key {
+                Some(api_key) => request.header("x-api-key", api_key),
+                None => request,
+            };
+            request.reply(&filter)
+        };
+        let ids = |path: &'static str, api_key: &'static str, field: &'static str| async move {
+            let body: serde_json::Value =
+                serde_json::from_slice(get(path, Some(api_key)).await.body()).unwrap();
+            body.as_array()
+                .unwrap()
+                .iter()
+                .map(|run| run[field].as_str().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        // Client sees its own private run, but not those of other clients of the tenant
+        assert_eq!(get("/runs/abc", Some("k3y")).await.status(), 200);
+        assert_eq!(get("/runs/abe", Some("k3y")).await.status(), 404);
+        assert_eq!(get("/runs/abd", Some("k3y")).await.status(), 404);
+        assert_eq!(ids("/clients", "k3y", "id").await, vec!["abc"]);
+        assert!(ids("/api/sessions", "k3y", "run_id").await.is_empty());
+        assert_eq!(get("/runs/abe", Some("0th3r")).await.status(), 200);
+        assert_eq!(ids("/api/sessions", "0th3r", "run_id").await, vec!["abf"]);
+        // Readers of the tenant see private runs of all its clients
+        assert_eq!(get("/runs/abc", Some("r3ad")).await.status(), 200);
+        assert_eq!(get("/runs/abe", Some("r3ad")).await.status(), 200);
+        assert_eq!(get("/runs/abd", Some("r3ad")).await.status(), 404);
+        assert_eq!(ids("/api/sessions", "r3ad", "run_id").await, vec!["abf"]);
+        // Nobody else sees runs of the tenant, nor may the tenant reach admin routes
+        assert_eq!(get("/runs/abc", Some("0ps")).await.status(), 404);
+        assert_eq!(get("/runs/abc", None).await.status(), 404);
+        assert_eq!(get("/runs/abd", None).await.status(), 200);
+        assert_eq!(get("/admin/dead_letters", Some("k3y")).await.status(), 403);
+        assert_eq!(get("/admin/dead_letters", Some("r3ad")).await.status(), 403);
+    }
+
+    #[tokio::test]
+    async fn test_certificate() {
+        let mut context = server_context();
+        context
+            .storage
+            .insert(0xabc, client_data(90))
+            .await
+            .unwrap();
+
+        let filter = routes(context.clone(), Default::default());
+        let response = warp::test::request()
+            .path("/runs/abc/certificate")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 404);
+
+        context.certificate_signer = Some(Arc::new(
+            CertificateSigner::new(&CertificateConfig {
+                signing_key: "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60"
+                    .to_owned(),
+                participation_only: true,
+                retired_public_keys: vec![],
+                rotation_api_key: Some("admin".to_owned()),
+            })
+            .unwrap(),
+        ));
+        let filter = routes(context, Default::default());
+        let response = warp::test::request()
+            .path("/runs/abc/certificate")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        let certificate = body["certificate"].as_str().unwrap();
+        assert!(certificate.contains("\"run_id\":\"abc\""));
+        assert!(!certificate.contains("score"));
+
+        let response = warp::test::request()
+            .path("/runs/abd/certificate")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 404);
+
+        let rotate = |api_key: &'static str| {
+            warp::test::request()
+                .method("POST")
+                .path("/admin/certificate/rotate")
+                .header("x-api-key", api_key)
+                .json(&serde_json::json!({
+                    "signing_key": "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb"
+                }))
+        };
+        assert_eq!(rotate("tenant").reply(&filter).await.status(), 403);
+        let response = rotate("admin").reply(&filter).await;
+        assert_eq!(response.status(), 200);
+        let key: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_ne!(key["key_id"], body["key_id"]);
+        assert_eq!(rotate("admin").reply(&filter).await.status(), 400);
+
+        let response = warp::test::request()
+            .path("/certificates/keys")
+            .reply(&filter)
+            .await;
+        let keys: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(keys[0]["key_id"], key["key_id"]);
+        assert_eq!(keys[1]["key_id"], body["key_id"]);
+        assert_eq!(keys[1]["active"], false);
+
+        // Certificate signed before the rotation still verifies
+        let verify = |signature: &serde_json::Value| {
+            warp::test::request()
+                .method("POST")
+                .path("/certificates/verify")
+                .json(&serde_json::json!({
+                    "certificate": body["certificate"],
+                    "signature": signature,
+                }))
+        };
+        let response = verify(&body["signature"]).reply(&filter).await;
+        let verification: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(
+            verification,
+            serde_json::json!({"valid": true, "key_id": body["key_id"], "retired": true})
+        );
+        let response = verify(&serde_json::json!("abc")).reply(&filter).await;
+        assert_eq!(response.status(), 400);
+    }
+
+    #[tokio::test]
+    async fn test_private_runs() {
+        let context = server_context();
+        let mut private = client_data(40);
+        private.private = true;
+        private.tags = parse_tags("class:native").unwrap();
+        context.storage.insert(0xa, private).await.unwrap();
+        let mut public = client_data(90);
+        public.tags = parse_tags("class:native").unwrap();
+        context.storage.insert(0xb, public).await.unwrap();
+
+        let mut config = ServerConfig::default();
+        config.reader_api_keys.insert("reader".to_owned());
+        config
+            .priority_classes
+            .insert("client".to_owned(), PriorityClass::High);
+        config.private_tenants.insert("private".to_owned());
+        let filter = routes(context, Arc::new(config));
+
+        let response = warp::test::request().path("/scores").reply(&filter).await;
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body, serde_json::json!({"b": 90}));
+        let response = warp::test::request().path("/runs/a").reply(&filter).await;
+        assert_eq!(response.status(), 404);
+        let response = warp::test::request()
+            .path("/compare?a=a&b=b")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 404);
+
+        let response = warp::test::request()
+            .path("/runs/a")
+            .header("x-api-key", "reader")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["private"], true);
+        let response = warp::test::request()
+            .path("/scores")
+            .header("x-api-key", "reader")
+            .reply(&filter)
+            .await;
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body, serde_json::json!({"a": 40, "b": 90}));
+        // Keys clients are measured with don't unlock private runs of others
+        for api_key in &["client", "private"] {
+            let response = warp::test::request()
+                .path("/runs/a")
+                .header("x-api-key", *api_key)
+                .reply(&filter)
+                .await;
+            assert_eq!(response.status(), 404);
+            let response = warp::test::request()
+                .path("/scores")
+                .header("x-api-key", *api_key)
+                .reply(&filter)
+                .await;
+            let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+            assert_eq!(body, serde_json::json!({"b": 90}));
+        }
+
+        // Private runs still count toward fleet statistics
+        let response = warp::test::request()
+            .path("/fleets/_?query=class:native")
+            .reply(&filter)
+            .await;
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["runs"], 2);
+        assert_eq!(body["worst_performers"][0]["id"], "b");
+    }
+
+    #[tokio::test]
+    async fn test_partial_runs() {
+        let context = server_context();
+        let mut completed = client_data(90);
+        completed.tags = parse_tags("class:native").unwrap();
+        context.storage.insert(0xa, completed).await.unwrap();
+        let mut partial = client_data(10);
+        partial.tags = parse_tags("class:native").unwrap();
+        partial.status = RunStatus::FailedAtNetwork(7);
+        context.storage.insert(0xb, partial).await.unwrap();
+        let filter = &routes(context, Default::default());
+        let get = |path: &'static str| async move {
+            let response = warp::test::request().path(path).reply(filter).await;
+            serde_json::from_slice::<serde_json::Value>(response.body()).unwrap()
+        };
+
+        // Partial runs are listed with their status, but left out of statistics
+        let clients = get("/clients").await;
+        assert_eq!(clients.as_array().unwrap().len(), 2);
+        let status = |id| {
+            clients
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|client| client["id"] == id)
+                .unwrap()["status"]
+                .clone()
+        };
+        assert_eq!(status("a"), "completed");
+        assert_eq!(status("b"), serde_json::json!({"failed_at_network": 7}));
+        assert_eq!(get("/runs/b").await["status"]["failed_at_network"], 7);
+        assert_eq!(get("/scores").await, serde_json::json!({"a": 90}));
+        assert_eq!(get("/api/scores/distribution").await[1]["runs"], 0);
+        assert_eq!(get("/fleets/_?query=class:native").await["runs"], 1);
+        assert_eq!(get("/signal").await["mean_score"], 90);
+    }
+
+    #[tokio::test]
+    async fn test_bearer_token() {
+        let mut context = server_context();
+        let mut private = client_data(40);
+        private.private = true;
+        context.storage.insert(0xa, private).await.unwrap();
+        let mut payments = client_data(60);
+        payments.tenant = Some("payments".to_owned());
+        payments.private = true;
+        payments.api_key_digest = Some(api_key_digest("k3y"));
+        context.storage.insert(0xb, payments).await.unwrap();
+        let key = ec_key();
+        context.identity_provider = Some(Arc::new(identity_provider(&key, false)));
+        let mut config = ServerConfig::default();
+        config.reader_api_keys.insert("reader".to_owned());
+        let config = Arc::new(config);
+        let filter = routes(context.clone(), config.clone());
+
+        let get = |authorization: &str| {
+            warp::test::request()
+                .path("/runs/a")
+                .header("authorization", authorization)
+        };
+        let response = warp::test::request().path("/runs/a").reply(&filter).await;
+        assert_eq!(response.status(), 404);
+        let response = get(&format!("Bearer {}", RS256_TOKEN)).reply(&filter).await;
+        assert_eq!(response.status(), 200);
+        let response = get("Bearer abc").reply(&filter).await;
+        assert_eq!(response.status(), 401);
+        assert_eq!(response.headers()["www-authenticate"], "Bearer");
+
+        let token = sign(
+            &key,
+            serde_json::json!({
+                "iss": "https://sso.example.com",
+                "aud": "reliability-api",
+                "exp": 4102444800u64,
+                "roles": "sales",
+            }),
+        );
+        let response = get(&format!("Bearer {}", token)).reply(&filter).await;
+        assert_eq!(response.status(), 403);
+
+        // Roles of a tenant read private runs of its clients, and nothing else
+        let token = sign(
+            &key,
+            serde_json::json!({
+                "iss": "https://sso.example.com",
+                "aud": "reliability-api",
+                "exp": 4102444800u64,
+                "roles": "payments-team",
+            }),
+        );
+        let tenant_reader = |path: &'static str| {
+            warp::test::request()
+                .path(path)
+                .header("authorization", format!("Bearer {}", token))
+                .reply(&filter)
+        };
+        assert_eq!(tenant_reader("/runs/b").await.status(), 200);
+        assert_eq!(tenant_reader("/runs/a").await.status(), 404);
+        let response = warp::test::request()
+            .path("/runs/b")
+            .header("authorization", format!("Bearer {}", RS256_TOKEN))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 404);
+        // Unrelated routes are still not found
+        let response = warp::test::request().path("/nothing").reply(&filter).await;
+        assert_eq!(response.status(), 404);
+
+        context.identity_provider = Some(Arc::new(identity_provider(&key, true)));
+        let filter = routes(context, config);
+        let response = warp::test::request().path("/scores").reply(&filter).await;
+        assert_eq!(response.status(), 401);
+        let response = warp::test::request()
+            .path("/scores")
+            .header("x-api-key", "reader")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn test_request_metrics() {
+        let context = server_context();
+        context.storage.insert(0xa, client_data(90)).await.unwrap();
+        let mut config = ServerConfig::default();
+        config.reader_api_keys.insert("reader".to_owned());
+        config.admin_api_keys.insert("admin".to_owned());
+        let filter = routes(context, Arc::new(config));
+
+        for path in &["/runs/a", "/runs/b", "/nothing"] {
+            warp::test::request().path(path).reply(&filter).await;
+        }
+        warp::test::request()
+            .path("/scores")
+            .header("x-api-key", "reader")
+            .reply(&filter)
+            .await;
+
+        let response = warp::test::request()
+            .path("/admin/metrics")
+            .header("x-api-key", "admin")
+            .reply(&filter)
+            .await;
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        let routes = body.as_array().unwrap();
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[0]["route"], "/runs/{id}");
+        assert_eq!(routes[0]["method"], "GET");
+        assert_eq!(routes[0]["tenant"], "anonymous");
+        assert_eq!(routes[0]["requests"], 2);
+        assert_eq!(routes[0]["client_errors"], 1);
+        assert_eq!(routes[1]["route"], "/scores");
+        assert!(routes[1]["tenant"].as_str().unwrap().starts_with("key:"));
+        let histogram = routes[1]["latency_histogram"].as_array().unwrap();
+        let requests: u64 = histogram
+            .iter()
+            .map(|bucket| bucket["requests"].as_u64().unwrap())
+            .sum();
+        assert_eq!(requests, 1);
+    }
+
+    #[tokio::test]
+    async fn test_dual_stack() {
+        let context = server_context();
+        let mut ipv4 = client_data(90);
+        ipv4.address_family = Some(AddressFamily::Ipv4);
+        context.storage.insert(0xa, ipv4).await.unwrap();
+        let mut ipv6 = client_data(70);
+        ipv6.address_family = Some(AddressFamily::Ipv6);
+        ipv6.dual_stack_of = Some(0xa);
+        context.storage.insert(0xb, ipv6).await.unwrap();
+        context.storage.insert(0xc, client_data(80)).await.unwrap();
+
+        let filter = routes(context, Default::default());
+        for id in &["a", "b"] {
+            let response = warp::test::request()
+                .path(&format!("/runs/{}/dual_stack", id))
+                .reply(&filter)
+                .await;
+            assert_eq!(response.status(), 200);
+            let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+            assert_eq!(body["ipv4_run_id"], "a");
+            assert_eq!(body["ipv6_run_id"], "b");
+            assert_eq!(body["metrics"][0]["delta"], -20);
+        }
+
+        let response = warp::test::request()
+            .path("/runs/c/dual_stack")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 404);
+
+        let response = warp::test::request().path("/runs/b").reply(&filter).await;
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["address_family"], "ipv6");
+        assert_eq!(body["dual_stack_of"], "a");
+    }
+
+    #[tokio::test]
+    async fn test_tls_overhead() {
+        let context = server_context();
+        let mut encrypted = client_data(70);
+        encrypted.tls_handshake_milliseconds = Some(15);
+        context.storage.insert(0xa, encrypted).await.unwrap();
+        let mut cleartext = client_data(90);
+        cleartext.tls_comparison_of = Some(0xa);
+        context.storage.insert(0xb, cleartext).await.unwrap();
+
+        let filter = routes(context, Default::default());
+        for id in &["a", "b"] {
+            let response = warp::test::request()
+                .path(&format!("/runs/{}/tls_overhead", id))
+                .reply(&filter)
+                .await;
+            assert_eq!(response.status(), 200);
+            let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+            assert_eq!(body["cleartext_run_id"], "b");
+            assert_eq!(body["encrypted_run_id"], "a");
+            assert_eq!(body["tls_handshake_milliseconds"], 15);
+            assert_eq!(body["metrics"][0]["delta"], -20);
+        }
+
+        let response = warp::test::request().path("/runs/b").reply(&filter).await;
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["tls_comparison_of"], "a");
+    }
+
+    #[tokio::test]
+    async fn test_clients() {
+        let context = server_context();
+        let mut older = client_data(40);
+        older.started_at -= Duration::from_secs(60);
+        context.storage.insert(0xa, older).await.unwrap();
+        context.storage.insert(0xb, client_data(90)).await.unwrap();
+
+        let filter = routes(context, Default::default());
+
+        let response = warp::test::request().path("/clients").reply(&filter).await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body[0]["id"], "b");
+        assert_eq!(body[1]["id"], "a");
+        assert_eq!(body[1]["score"], 40);
+
+        let response = warp::test::request()
+            .path("/clients/a")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["configuration"]["squarings"], 10);
+
+        let response = warp::test::request().path("/scores").reply(&filter).await;
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body, serde_json::json!({"a": 40, "b": 90}));
+    }
+
+    #[tokio::test]
+    async fn test_dashboard() {
+        let context = server_context();
+        let mut tagged = client_data(40);
+        tagged.started_at -= Duration::from_secs(60);
+        tagged.tags = parse_tags("host:web-1").unwrap();
+        context.storage.insert(0xa, tagged).await.unwrap();
+        let mut tagged = client_data(100);
+        tagged.tags = parse_tags("host:web-1").unwrap();
+        context.storage.insert(0xb, tagged).await.unwrap();
+        context.storage.insert(0xc, client_data(45)).await.unwrap();
+        let mut config = ServerConfig::default();
+        config.reader_api_keys.insert("reader".to_owned());
+        let filter = routes(context.clone(), Arc::new(config));
+
+        let response = warp::test::request()
+            .path("/dashboard")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert!(response.headers()["content-type"]
+            .to_str()
+            .unwrap()
+            .starts_with("text/html"));
+
+        let response = warp::test::request()
+            .path("/api/scores/distribution")
+            .reply(&filter)
+            .await;
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body.as_array().unwrap().len(), 10);
+        assert_eq!(
+            body[4],
+            serde_json::json!({"from": 40, "to": 49, "runs": 2})
+        );
+        assert_eq!(
+            body[9],
+            serde_json::json!({"from": 90, "to": 100, "runs": 1})
+        );
+
+        let response = warp::test::request()
+            .path("/api/history?query=host:web-1")
+            .reply(&filter)
+            .await;
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body.as_array().unwrap().len(), 2);
+        assert_eq!(body[0]["id"], "a");
+        assert_eq!(body[1]["score"], 100);
+
+        let _public = SessionEvents::new(
+            &context,
+            0xd,
+            "default".to_owned(),
+            false,
+            None,
+            None,
+            HashMap::new(),
+        );
+        let mut private = SessionEvents::new(
+            &context,
+            0xe,
+            "default".to_owned(),
+            true,
+            None,
+            None,
+            HashMap::new(),
+        );
+        private.push(SessionEvent {
+            timestamp: SessionClock::start().now(),
+            kind: SessionEventKind::CPUChallengeCompleted {
+                round: 1,
+                milliseconds: 120,
+            },
+        });
+        let response = warp::test::request()
+            .path("/api/sessions")
+            .reply(&filter)
+            .await;
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert_eq!(body[0]["run_id"], "d");
+        assert_eq!(body[0]["last_event"], serde_json::Value::Null);
+
+        let sessions = || {
+            warp::test::request()
+                .path("/api/sessions")
+                .header("x-api-key", "reader")
+                .reply(&filter)
+        };
+        let body: serde_json::Value = serde_json::from_slice(sessions().await.body()).unwrap();
+        assert_eq!(body.as_array().unwrap().len(), 2);
+        // Sessions are no longer listed once their events are taken
+        private.into_vec();
+        let body: serde_json::Value = serde_json::from_slice(sessions().await.body()).unwrap();
+        assert_eq!(body.as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_anomalies() {
+        let context = server_context();
+        let anomaly = Anomaly {
+            baseline: Baseline::Client,
+            rule: AnomalyRule::ZScore,
+            deviation: Deviation::Low,
+            expected_score: 90.0,
+            min_score: 85.0,
+            max_score: 95.0,
+            samples: 6,
+        };
+        context.storage.insert(0xa, client_data(90)).await.unwrap();
+        let mut older = client_data(20);
+        older.started_at -= Duration::from_secs(60);
+        older.anomalies = vec![anomaly.clone()];
+        context.storage.insert(0xb, older).await.unwrap();
+        let mut anomalous = client_data(30);
+        anomalous.anomalies = vec![anomaly.clone()];
+        context.storage.insert(0xc, anomalous).await.unwrap();
+        let mut private = client_data(10);
+        private.private = true;
+        private.anomalies = vec![anomaly];
+        context.storage.insert(0xd, private).await.unwrap();
+
+        let filter = routes(context, Default::default());
+        let response = warp::test::request()
+            .path("/anomalies")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body.as_array().unwrap().len(), 2);
+        assert_eq!(body[0]["id"], "c");
+        assert_eq!(body[1]["id"], "b");
+        assert_eq!(body[1]["anomalies"][0]["baseline"], "client");
+        assert_eq!(body[1]["anomalies"][0]["deviation"], "low");
+
+        let response = warp::test::request().path("/runs/b").reply(&filter).await;
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["anomalies"][0]["expected_score"], 90.0);
+    }
+
+    #[tokio::test]
+    async fn test_export() {
+        let context = server_context();
+        let mut older = client_data(40);
+        older.started_at -= Duration::from_secs(60);
+        context.storage.insert(0xb, older).await.unwrap();
+        context.storage.insert(0xa, client_data(90)).await.unwrap();
+        let mut private = client_data(70);
+        private.private = true;
+        context.storage.insert(0xc, private).await.unwrap();
+
+        let filter = routes(context, Default::default());
+
+        let response = warp::test::request()
+            .path("/export?format=csv")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["content-type"], "text/csv");
+        let body = std::str::from_utf8(response.body()).unwrap();
+        let lines = body.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("id,started_at_unix_milliseconds,"));
+        // Oldest first, private runs need an API key
+        assert!(lines[1].starts_with("b,"));
+        assert!(lines[2].starts_with("a,"));
+
+        let response = warp::test::request()
+            .path("/export?format=jsonl")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+        let rows = std::str::from_utf8(response.body())
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["id"], "b");
+        assert_eq!(rows[0]["score"], 40);
+        assert_eq!(rows[1]["network_challenge_timings_in_milis"][0], 200);
+
+        let response = warp::test::request()
+            .path("/export?format=xml")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 400);
+    }
+
+    #[tokio::test]
+    async fn test_identity_history() {
+        let context = server_context();
+        let mut older = client_data(40);
+        older.identity = Some(0x7);
+        older.started_at -= Duration::from_secs(60);
+        context.storage.insert(0xa, older).await.unwrap();
+        let mut newer = client_data(90);
+        newer.identity = Some(0x7);
+        context.storage.insert(0xb, newer).await.unwrap();
+        let mut private = client_data(80);
+        private.identity = Some(0x7);
+        private.private = true;
+        context.storage.insert(0xc, private).await.unwrap();
+        context.storage.insert(0xd, client_data(70)).await.unwrap();
+
+        let filter = routes(context, Default::default());
+
+        let response = warp::test::request()
+            .path("/identities/7")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body.as_array().unwrap().len(), 2);
+        assert_eq!(body[0]["id"], "a");
+        assert_eq!(body[1]["score"], 90);
+
+        let response = warp::test::request().path("/runs/b").reply(&filter).await;
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["identity"], "7");
+
+        let response = warp::test::request()
+            .path("/identities/8")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_compare_runs() {
+        let context = server_context();
+        context.storage.insert(0xa, client_data(90)).await.unwrap();
+        context.storage.insert(0xb, client_data(80)).await.unwrap();
+
+        let filter = routes(context, Default::default());
+
+        let response = warp::test::request()
+            .path("/compare?a=a&b=b")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["metrics"][0]["delta"], -10);
+        assert_eq!(body["score_change_explanation"], "performance");
+
+        let response = warp::test::request()
+            .path("/compare?a=a&b=c")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_fleets() {
+        let context = server_context();
+        for (client_id, score, tags) in &[
+            (0xa, 90, "region:eu,class:native"),
+            (0xb, 50, "region:eu,class:browser"),
+            (0xc, 20, "region:us,class:native"),
+        ] {
+            let mut data = client_data(*score);
+            data.tags = parse_tags(tags).unwrap();
+            if *client_id == 0xa {
+                data.network_type = Some(NetworkType::Cellular);
+                data.client_version = Some("1.4.2".to_owned());
+            }
+            context.storage.insert(*client_id, data).await.unwrap();
+        }
+        let mut config = ServerConfig::default();
+        config
+            .fleets
+            .insert("eu".to_owned(), "region:eu".to_owned());
+
+        let filter = routes(context, Arc::new(config));
+
+        let response = warp::test::request()
+            .path("/fleets/eu?worst=1")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["runs"], 2);
+        assert_eq!(body["worst_performers"][0]["id"], "b");
+
+        let response = warp::test::request()
+            .path("/fleets/_/trend?query=class:native")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body[0]["runs"], 2);
+        assert_eq!(body[0]["mean_score"], 55);
+
+        for bucket_minutes in &["0", "43201", "400000000000000000"] {
+            let response = warp::test::request()
+                .path(&format!(
+                    "/fleets/_/trend?query=class:native&bucket_minutes={}",
+                    bucket_minutes
+                ))
+                .reply(&filter)
+                .await;
+            assert_eq!(response.status(), 400);
+        }
+
+        let response = warp::test::request()
+            .path("/fleets/eu?network_type=cellular")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["runs"], 1);
+        assert_eq!(body["network_types"]["cellular"]["mean_score"], 90);
+
+        let response = warp::test::request()
+            .path("/fleets/eu?client_version=1.4.2")
+            .reply(&filter)
+            .await;
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["runs"], 1);
+        assert_eq!(body["client_versions"]["1.4.2"]["runs"], 1);
+        assert!(body["client_versions"].get("undeclared").is_none());
+
+        let response = warp::test::request()
+            .path("/fleets/us")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 404);
+        let response = warp::test::request()
+            .path("/fleets/eu?network_type=satellite")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_capacity() {
+        let context = server_context();
+        context.storage.insert(0xa, client_data(90)).await.unwrap();
+
+        let filter = routes(context, admin_config());
+
+        let response = warp::test::request()
+            .path("/admin/capacity")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 403);
+        let response = warp::test::request()
+            .path("/admin/capacity")
+            .header("x-api-key", "admin")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["active_sessions"], 0);
+        assert_eq!(body["verification_threads"], 1);
+        assert_eq!(body["sessions_last_minute"], 1);
+        assert_eq!(body["mean_session_verification_micros"], 3);
+    }
+
+    #[tokio::test]
+    async fn test_signal() {
+        let context = server_context();
+        context.storage.insert(0xa, client_data(90)).await.unwrap();
+        let filter = routes(context, Default::default());
+
+        let response = warp::test::request()
+            .path("/signal?window_minutes=5")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["window_minutes"], 5);
+        assert_eq!(body["mean_score"], 90);
+        assert_eq!(body["memory_pressure"], "normal");
+        assert_eq!(body["recommendation"], "hold");
+
+        for window_minutes in &["0", "10081", "200000000000000000"] {
+            let response = warp::test::request()
+                .path(&format!("/signal?window_minutes={}", window_minutes))
+                .reply(&filter)
+                .await;
+            assert_eq!(response.status(), 400);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tasks() {
+        let context = server_context();
+        let _task = context.tasks.enter(TaskKind::Connection, "session abc");
+        let filter = routes(context, admin_config());
+
+        let response = warp::test::request()
+            .path("/admin/tasks")
+            .header("x-api-key", "admin")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body[0]["kind"], "connection");
+        assert_eq!(body[0]["name"], "session abc");
+    }
+
+    #[tokio::test]
+    async fn test_watch_sessions() {
+        let context = server_context();
+        let filter = routes(context.clone(), admin_config());
+        assert!(warp::test::ws()
+            .path("/admin/ws")
+            .handshake(filter.clone())
+            .await
+            .is_err());
+        let mut client = warp::test::ws()
+            .path("/admin/ws")
+            .header("x-api-key", "admin")
+            .handshake(filter)
+            .await
+            .unwrap();
+
+        let mut rounds = HashMap::new();
+        rounds.insert(kinds::challenge::CPU_CHALLENGE, 5);
+        let mut events = SessionEvents::new(
+            &context,
+            0xabc,
+            "default".to_owned(),
+            false,
+            None,
+            None,
+            rounds,
+        );
+        let clock = SessionClock::start();
+        // Watcher subscribes once the upgrade completed, events before that aren't streamed
+        let message = loop {
+            events.push(SessionEvent {
+                timestamp: clock.now(),
+                kind: SessionEventKind::CPUChallengeCompleted {
+                    round: 1,
+                    milliseconds: 120,
+                },
+            });
+            if let Ok(message) =
+                tokio::time::timeout(Duration::from_millis(50), client.recv()).await
+            {
+                break message.unwrap();
+            }
+        };
+        let body: serde_json::Value = serde_json::from_str(message.to_str().unwrap()).unwrap();
+        assert_eq!(body["run_id"], "abc");
+        assert_eq!(body["plan"], "default");
+        assert_eq!(body["round"], 1);
+        assert_eq!(body["rounds"], 5);
+        assert_eq!(body["milliseconds"], 120);
+    }
+
+    #[tokio::test]
+    async fn test_dead_letters() {
+        let context = server_context();
+        // Nothing listens on port 1, so both deliveries fail
+        for verdict in &["allow", "deny"] {
+            context
+                .dead_letters
+                .deliver(SideEffect::Webhook {
+                    url: "http://127.0.0.1:1/".to_owned(),
+                    body: serde_json::json!({ "verdict": verdict }),
+                })
+                .await;
+        }
+        let mut config = ServerConfig::default();
+        config.admin_api_keys.insert("admin".to_owned());
+        config.reader_api_keys.insert("reader".to_owned());
+        config
+            .priority_classes
+            .insert("client".to_owned(), PriorityClass::High);
+        let filter = routes(context.clone(), Arc::new(config));
+        let request = |method: &str, path: &str| {
+            warp::test::request()
+                .method(method)
+                .path(path)
+                .header("x-api-key", "admin")
+        };
+
+        let response = warp::test::request()
+            .path("/admin/dead_letters")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 403);
+        // Neither keys of clients nor of readers are admin keys
+        for api_key in &["client", "reader"] {
+            let response = warp::test::request()
+                .method("DELETE")
+                .path("/admin/dead_letters")
+                .header("x-api-key", *api_key)
+                .reply(&filter)
+                .await;
+            assert_eq!(response.status(), 403);
+        }
+        assert_eq!(context.dead_letters.len(), 2);
+        let response = request("GET", "/admin/dead_letters").reply(&filter).await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body.as_array().unwrap().len(), 2);
+        assert_eq!(body[0]["attempts"], 1);
+        assert_eq!(body[0]["effect"]["kind"], "webhook");
+        let id = body[0]["id"].as_str().unwrap().to_owned();
+
+        let path = format!("/admin/dead_letters/{}/requeue", id);
+        let response = request("POST", &path).reply(&filter).await;
+        assert_eq!(response.status(), 202);
+        assert_eq!(context.dead_letters.list()[0].attempts, 0);
+        let response = request("POST", "/admin/dead_letters/abc/requeue")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 404);
+
+        let path = format!("/admin/dead_letters/{}", id);
+        let response = request("DELETE", &path).reply(&filter).await;
+        assert_eq!(response.status(), 204);
+        let response = request("DELETE", &path).reply(&filter).await;
+        assert_eq!(response.status(), 404);
+        let response = request("DELETE", "/admin/dead_letters")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["purged"], 1);
+        assert_eq!(context.dead_letters.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_primes() {
+        let filter = routes(server_context(), admin_config());
+
+        let response = warp::test::request()
+            .path("/admin/primes")
+            .header("x-api-key", "admin")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["backend"], "glass_pumpkin");
+        assert_eq!(body["bits"], 128);
+        assert_eq!(body["generated"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_thresholds() {
+        let filter = routes(server_context(), admin_config());
+        let response = warp::test::request()
+            .path("/admin/thresholds")
+            .header("x-api-key", "admin")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 404);
+
+        let mut context = server_context();
+        context.threshold_tuner = Some(Arc::new(
+            ThresholdTuner::new(Default::default(), Default::default()).unwrap(),
+        ));
+        let filter = routes(context, admin_config());
+        let response = warp::test::request()
+            .path("/admin/thresholds")
+            .header("x-api-key", "admin")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["thresholds"]["cpu"]["ideal_milliseconds"], 4500);
+        assert_eq!(body["thresholds"]["disk"]["max_milliseconds"], 5000);
+        assert_eq!(body["changes"], serde_json::json!([]));
+    }
+
+    #[tokio::test]
+    async fn test_score_preview() {
+        let filter = routes(server_context(), Default::default());
+        let preview = |body: serde_json::Value| {
+            warp::test::request()
+                .method("POST")
+                .path("/score/preview")
+                .json(&body)
+                .reply(&filter)
+        };
+
+        let response = preview(serde_json::json!({
+            "cpu_challenge_timings_in_milis": [4500],
+            "network_challenge_timings_in_milis": [200],
+            "thresholds": {
+                "cpu_ideal_milliseconds": 500,
+                "cpu_max_milliseconds": 8500,
+            },
+        }))
+        .await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["configured"]["score"], 100);
+        assert_eq!(body["candidate"]["score"], 75);
+        assert_eq!(body["candidate"]["cpu_sub_score"], 50);
+        assert_eq!(body["candidate"]["network_sub_score"], 100);
+        assert_eq!(body["candidate"]["disk_sub_score"], serde_json::Value::Null);
+
+        let response = preview(serde_json::json!({
+            "cpu_challenge_timings_in_milis": [4500],
+            "thresholds": { "cpu_ideal_milliseconds": 200000 },
+        }))
+        .await;
+        assert_eq!(response.status(), 400);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert!(body["error"].as_str().unwrap().starts_with("Ideal time"));
+
+        // A single slow round no longer drags the candidate score down
+        let response = preview(serde_json::json!({
+            "network_challenge_timings_in_milis": [200, 200, 20000],
+            "thresholds": { "network_aggregation": { "kind": "median" } },
+        }))
+        .await;
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        // Mean of 6800ms is penalized by 26.6
+        assert_eq!(body["configured"]["score"], 73);
+        assert_eq!(body["candidate"]["score"], 100);
+
+        let response = preview(serde_json::json!({
+            "cpu_challenge_timings_in_milis": [62250],
+            "network_challenge_timings_in_milis": [200],
+            "thresholds": { "scoring": { "cpu_weight": 3.0 } },
+        }))
+        .await;
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["configured"]["score"], 75);
+        assert_eq!(body["candidate"]["score"], 63);
+
+        // Half the ideal throughput of 100 Mbps with a 1MB payload, minimum is 10 Mbps
+        let response = preview(serde_json::json!({
+            "network_challenge_timings_in_milis": [336],
+            "thresholds": { "network_ideal_mbps": 100.0, "network_min_mbps": 10.0 },
+        }))
+        .await;
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["configured"]["score"], 99);
+        assert_eq!(body["candidate"]["score"], 44);
+
+        // Half the ideal rate of 100,000 squarings per second, minimum is 10,000
+        let response = preview(serde_json::json!({
+            "cpu_challenge_timings_in_milis": [4000],
+            "thresholds": {
+                "cpu_ideal_squarings_per_second": 100000.0,
+                "cpu_min_squarings_per_second": 10000.0,
+            },
+        }))
+        .await;
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["configured"]["score"], 100);
+        assert_eq!(body["candidate"]["score"], 44);
+    }
+}
@@ -0,0 +1,388 @@
+//! Events of sessions published to NATS as they happen, for pipelines consuming measurements of
+//! a fleet of servers in real time. Speaks the NATS client protocol (`INFO`, `CONNECT`, `PUB`,
+//! `PING`/`PONG`) over a single connection, which is reopened on the next event once it fails.
+//! Events which couldn't be published are handed to the dead letter queue.
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+use crate::api::unix_milliseconds;
+use crate::dead_letters::{DeadLetterQueue, SideEffect};
+use crate::tasks::{TaskKind, TaskRegistry};
+use crate::types::{SessionEvent, SessionEventKind};
+
+/// Events waiting to be published before further ones are dropped
+const QUEUE_CAPACITY: usize = 1024;
+/// Time a retry of a dead letter has to be confirmed by the NATS server
+const CONFIRMATION_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct EventBusConfig {
+    /// `host:port` of the NATS server
+    pub(crate) address: String,
+    /// Events are published to `<subject_prefix>.session_started`,
+    /// `<subject_prefix>.challenge_completed` and `<subject_prefix>.score_computed`
+    #[serde(default = "default_subject_prefix")]
+    pub(crate) subject_prefix: String,
+    /// Name of the server in its events, to tell servers of a fleet apart
+    #[serde(default)]
+    pub(crate) server_name: String,
+    /// Token the NATS server authenticates clients with, `None` connects anonymously
+    pub(crate) auth_token: Option<String>,
+}
+
+fn default_subject_prefix() -> String {
+    "reliability".to_owned()
+}
+
+/// Event as published, a JSON object
+#[derive(Serialize)]
+struct PublishedEvent<'a> {
+    server: &'a str,
+    run_id: String,
+    wall_clock_unix_milliseconds: u128,
+    session_offset_milliseconds: u128,
+    #[serde(flatten)]
+    kind: &'a SessionEventKind,
+}
+
+/// Subject `kind` is published under, `None` for events which aren't published (failed, retried,
+/// implausible and surprise rounds)
+fn subject_suffix(kind: &SessionEventKind) -> Option<&'static str> {
+    match kind {
+        SessionEventKind::SessionStarted => Some("session_started"),
+        SessionEventKind::ScoreCalculated { .. } => Some("score_computed"),
+        SessionEventKind::CPUCalibrated { .. }
+        | SessionEventKind::CPUChallengeCompleted { .. }
+        | SessionEventKind::NetworkChallengeCompleted { .. }
+        | SessionEventKind::DiskChallengeCompleted { .. }
+        | SessionEventKind::DownloadChallengeCompleted { .. }
+        | SessionEventKind::UploadChallengeCompleted { .. }
+        | SessionEventKind::ChallengeCompleted { .. }
+        | SessionEventKind::LatencyProbesCompleted { .. }
+        | SessionEventKind::DatagramProbesCompleted { .. } => Some("challenge_completed"),
+        SessionEventKind::RoundFailed { .. }
+        | SessionEventKind::RoundImplausible { .. }
+        | SessionEventKind::RoundRetried { .. }
+        | SessionEventKind::SurpriseRoundCompleted { .. }
+        | SessionEventKind::CPUCalibrationFailed { .. }
+        | SessionEventKind::NetworkChallengeMismatched { .. } => None,
+    }
+}
+
+struct Publication {
+    subject: String,
+    payload: serde_json::Value,
+}
+
+/// Hands events to the task publishing them
+#[derive(Clone)]
+pub(crate) struct EventBus {
+    config: Arc<EventBusConfig>,
+    sender: mpsc::Sender<Publication>,
+}
+
+impl EventBus {
+    /// Bus of `config`, publishing in a background task of `tasks`
+    pub(crate) fn spawn(
+        config: EventBusConfig,
+        tasks: &TaskRegistry,
+        dead_letters: DeadLetterQueue,
+    ) -> Self {
+        let config = Arc::new(config);
+        let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        tasks.spawn(
+            TaskKind::Background,
+            "event bus",
+            publish(config.clone(), receiver, dead_letters),
+        );
+        EventBus { config, sender }
+    }
+
+    /// Publisher of events of session `client_id`
+    pub(crate) fn session(&self, client_id: u128) -> SessionPublisher {
+        SessionPublisher {
+            bus: self.clone(),
+            client_id,
+        }
+    }
+}
+
+pub(crate) struct SessionPublisher {
+    bus: EventBus,
+    client_id: u128,
+}
+
+impl SessionPublisher {
+    /// Queues `event` for publishing. Never waits for the bus, events are dropped if it falls
+    /// too far behind.
+    pub(crate) fn publish(&mut self, event: &SessionEvent) {
+        let suffix = match subject_suffix(&event.kind) {
+            Some(suffix) => suffix,
+            None => return,
+        };
+        let payload = serde_json::to_value(&PublishedEvent {
+            server: &self.bus.config.server_name,
+            run_id: format!("{:x}", self.client_id),
+            wall_clock_unix_milliseconds: unix_milliseconds(event.timestamp.wall_clock),
+            session_offset_milliseconds: event.timestamp.session_offset.as_millis(),
+            kind: &event.kind,
+        })
+        .expect("session events are always serializable");
+        let publication = Publication {
+            subject: format!("{}.{}", self.bus.config.subject_prefix, suffix),
+            payload,
+        };
+        if self.bus.sender.try_send(publication).is_err() {
+            warn!(
+                "Event bus is falling behind, dropped {} event of run {:x}",
+                suffix, self.client_id
+            );
+        }
+    }
+}
+
+/// Connection to the NATS server
+struct Connection {
+    reader: BufReader<ReadHalf<TcpStream>>,
+    writer: WriteHalf<TcpStream>,
+}
+
+impl Connection {
+    async fn open(address: &str, auth_token: Option<&str>) -> Result<Self> {
+        let (reader, writer) = tokio::io::split(TcpStream::connect(address).await?);
+        let mut connection = Connection {
+            reader: BufReader::new(reader),
+            writer,
+        };
+        let info = connection.read_line().await?;
+        if !info.starts_with("INFO ") {
+            return Err(anyhow!("Expected INFO from NATS server, got {:?}", info));
+        }
+        let connect = serde_json::json!({
+            "verbose": false,
+            "pedantic": false,
+            "name": "reliability-measurement-server",
+            "lang": "rust",
+            "version": env!("CARGO_PKG_VERSION"),
+            "auth_token": auth_token,
+        });
+        connection
+            .writer
+            .write_all(format!("CONNECT {}\r\n", connect).as_bytes())
+            .await?;
+        Ok(connection)
+    }
+
+    async fn publish(&mut self, subject: &str, payload: &serde_json::Value) -> Result<()> {
+        let payload = serde_json::to_vec(payload)?;
+        let mut message = format!("PUB {} {}\r\n", subject, payload.len()).into_bytes();
+        message.extend_from_slice(&payload);
+        message.extend_from_slice(b"\r\n");
+        self.writer.write_all(&message).await?;
+        Ok(())
+    }
+
+    /// Next line sent by the server, without the line break
+    async fn read_line(&mut self) -> Result<String> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line).await? == 0 {
+            return Err(anyhow!("NATS server closed the connection"));
+        }
+        Ok(line.trim_end().to_owned())
+    }
+
+    /// Answers the server's pings until it sends something else, which is returned
+    async fn next_message(&mut self) -> Result<String> {
+        loop {
+            let line = self.read_line().await?;
+            if line == "PING" {
+                self.writer.write_all(b"PONG\r\n").await?;
+            } else {
+                return Ok(line);
+            }
+        }
+    }
+}
+
+/// Publishes `payload` over a connection of its own and waits for the server to confirm it, for
+/// retries of dead letters
+pub(crate) async fn publish_once(
+    address: &str,
+    auth_token: Option<&str>,
+    subject: &str,
+    payload: &serde_json::Value,
+) -> Result<()> {
+    let mut connection = Connection::open(address, auth_token).await?;
+    connection.publish(subject, payload).await?;
+    connection.writer.write_all(b"PING\r\n").await?;
+    tokio::time::timeout(CONFIRMATION_TIMEOUT, async {
+        loop {
+            match connection.next_message().await?.as_str() {
+                "PONG" => return Ok(()),
+                line if line.starts_with("-ERR") => {
+                    return Err(anyhow!("NATS server refused event: {}", line))
+                }
+                _ => {}
+            }
+        }
+    })
+    .await
+    .map_err(|_| anyhow!("NATS server didn't confirm event in time"))?
+}
+
+enum Step {
+    Publish(Option<Publication>),
+    Message(Result<String>),
+}
+
+/// Message of the server, never resolves without a connection
+async fn next_message(connection: &mut Option<Connection>) -> Result<String> {
+    match connection {
+        Some(connection) => connection.next_message().await,
+        None => futures::future::pending().await,
+    }
+}
+
+async fn publish(
+    config: Arc<EventBusConfig>,
+    mut receiver: mpsc::Receiver<Publication>,
+    dead_letters: DeadLetterQueue,
+) {
+    let mut connection: Option<Connection> = None;
+    loop {
+        let step = tokio::select! {
+            publication = receiver.recv() => Step::Publish(publication),
+            message = next_message(&mut connection) => Step::Message(message),
+        };
+        let publication = match step {
+            Step::Publish(Some(publication)) => publication,
+            Step::Publish(None) => return,
+            Step::Message(Ok(message)) => {
+                if message.starts_with("-ERR") {
+                    warn!("NATS server reported {}", message);
+                }
+                continue;
+            }
+            Step::Message(Err(e)) => {
+                warn!("Lost connection to NATS server: {:?}", e);
+                connection = None;
+                continue;
+            }
+        };
+        if connection.is_none() {
+            match Connection::open(&config.address, config.auth_token.as_deref()).await {
+                Ok(opened) => {
+                    info!("Connected to NATS server at {}", config.address);
+                    connection = Some(opened);
+                }
+                Err(e) => warn!("Unable to connect to NATS server: {:?}", e),
+            }
+        }
+        let published = match &mut connection {
+            Some(opened) => opened
+                .publish(&publication.subject, &publication.payload)
+                .await
+                .is_ok(),
+            None => false,
+        };
+        if !published {
+            connection = None;
+            // Retried over a connection of its own, which may be down just the same
+            let effect = SideEffect::Publish {
+                address: config.address.clone(),
+                auth_token: config.auth_token.clone(),
+                subject: publication.subject,
+                payload: publication.payload,
+            };
+            let dead_letters = dead_letters.clone();
+            tokio::spawn(async move {
+                dead_letters.deliver(effect).await;
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bus::{EventBus, EventBusConfig};
+    use crate::dead_letters::DeadLetterQueue;
+    use crate::types::{SessionClock, SessionEvent, SessionEventKind};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_event_bus() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = EventBusConfig {
+            address: listener.local_addr().unwrap().to_string(),
+            subject_prefix: "fleet".to_owned(),
+            server_name: "eu-1".to_owned(),
+            auth_token: Some("secret".to_owned()),
+        };
+        let tasks = Default::default();
+        let bus = EventBus::spawn(config, &tasks, DeadLetterQueue::default());
+
+        let clock = SessionClock::start();
+        let mut publisher = bus.session(0xabc);
+        for kind in [
+            SessionEventKind::SessionStarted,
+            SessionEventKind::RoundFailed {
+                kind_id: 1,
+                round: 0,
+                milliseconds: 10,
+                scored: false,
+            },
+            SessionEventKind::CPUChallengeCompleted {
+                round: 0,
+                milliseconds: 120,
+            },
+            SessionEventKind::ScoreCalculated { score: 80 },
+        ] {
+            publisher.publish(&SessionEvent {
+                timestamp: clock.now(),
+                kind,
+            });
+        }
+
+        // Plays the NATS server
+        let (socket, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = tokio::io::split(socket);
+        let mut reader = BufReader::new(reader);
+        writer.write_all(b"INFO {}\r\nPING\r\n").await.unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        assert!(line.starts_with("CONNECT "));
+        assert!(line.contains("\"auth_token\":\"secret\""));
+        let mut published = vec![];
+        while published.len() < 3 {
+            line.clear();
+            reader.read_line(&mut line).await.unwrap();
+            let fields: Vec<&str> = line.trim_end().split(' ').collect();
+            if fields[0] != "PUB" {
+                assert_eq!(fields, ["PONG"]);
+                continue;
+            }
+            let mut payload = vec![0; fields[2].parse::<usize>().unwrap() + 2];
+            reader.read_exact(&mut payload).await.unwrap();
+            let payload: serde_json::Value =
+                serde_json::from_slice(&payload[..payload.len() - 2]).unwrap();
+            published.push((fields[1].to_owned(), payload));
+        }
+        // Failed round isn't published
+        assert_eq!(published[0].0, "fleet.session_started");
+        assert_eq!(published[1].0, "fleet.challenge_completed");
+        assert_eq!(published[2].0, "fleet.score_computed");
+        assert_eq!(published[2].1["kind"], "score_calculated");
+        assert_eq!(published[1].1["milliseconds"], 120);
+        assert_eq!(published[2].1["server"], "eu-1");
+        assert_eq!(published[2].1["run_id"], "abc");
+        assert_eq!(published[2].1["score"], 80);
+    }
+}
@@ -0,0 +1,457 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::{oneshot, OwnedSemaphorePermit, Semaphore};
+
+use crate::types::{ClientData, PriorityClass};
+use crate::verification::VerificationPool;
+
+/// Number of most recent runs used to estimate verification cost of a session
+const RECENT_RUNS: usize = 100;
+
+/// Counter of sessions currently being measured
+#[derive(Clone, Default)]
+pub(crate) struct ActiveSessions(Arc<AtomicUsize>);
+
+impl ActiveSessions {
+    /// Counts a session as active until returned guard is dropped
+    pub(crate) fn enter(&self) -> ActiveSessionGuard {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        ActiveSessionGuard(self.0.clone())
+    }
+
+    pub(crate) fn count(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+pub(crate) struct ActiveSessionGuard(Arc<AtomicUsize>);
+
+impl Drop for ActiveSessionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Cap of concurrent sessions. Connections above it wait for a free slot in a bounded queue,
+/// connections which find the queue full or wait longer than `queue_timeout_milliseconds`
+/// are rejected with `Capacity`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SessionLimitConfig {
+    pub(crate) max_sessions: usize,
+    /// Connections allowed to wait for a slot, zero rejects connections above the cap right away
+    #[serde(default)]
+    pub(crate) queue_length: usize,
+    #[serde(
+        rename = "queue_timeout_milliseconds",
+        deserialize_with = "crate::config::milliseconds",
+        default = "default_queue_timeout"
+    )]
+    pub(crate) queue_timeout: Duration,
+}
+
+fn default_queue_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+/// Slots of concurrent sessions, shared by all connections. A freed slot goes to the waiting
+/// session of the highest priority class, the one which waited longest within a class.
+#[derive(Clone)]
+pub(crate) struct SessionLimit {
+    config: SessionLimitConfig,
+    slots: Arc<Mutex<Slots>>,
+    queued: Arc<AtomicUsize>,
+}
+
+struct Slots {
+    free: usize,
+    /// Sessions waiting for a slot by priority class, in order of arrival within a class
+    waiting: BTreeMap<PriorityClass, VecDeque<oneshot::Sender<()>>>,
+}
+
+impl Slots {
+    /// Hands a freed slot over to the next waiting session, keeps it free if none is waiting
+    fn release(&mut self) {
+        for waiting in self.waiting.values_mut().rev() {
+            while let Some(waiter) = waiting.pop_front() {
+                // Fails if the session stopped waiting, the slot goes to the next one then
+                if waiter.send(()).is_ok() {
+                    return;
+                }
+            }
+        }
+        self.free += 1;
+    }
+}
+
+/// Slot taken by a session, freed once dropped
+pub(crate) struct SessionPermit(Arc<Mutex<Slots>>);
+
+impl Drop for SessionPermit {
+    fn drop(&mut self) {
+        self.0.lock().unwrap().release();
+    }
+}
+
+/// Admission of a connection, held for as long as its session runs
+pub(crate) enum SessionSlot {
+    /// Server has no session limit
+    Unlimited,
+    Admitted {
+        _permit: SessionPermit,
+    },
+    /// Waiting for a slot, see `SessionSlot::wait`
+    Queued(SessionLimit, QueueGuard, oneshot::Receiver<()>),
+}
+
+pub(crate) struct QueueGuard(Arc<AtomicUsize>);
+
+impl Drop for QueueGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl SessionLimit {
+    pub(crate) fn new(config: SessionLimitConfig) -> Self {
+        SessionLimit {
+            slots: Arc::new(Mutex::new(Slots {
+                free: config.max_sessions,
+                waiting: BTreeMap::new(),
+            })),
+            queued: Default::default(),
+            config,
+        }
+    }
+
+    /// Takes a free slot or a place in the queue of `priority`, `None` if both are full
+    pub(crate) fn admit(&self, priority: PriorityClass) -> Option<SessionSlot> {
+        let mut slots = self.slots.lock().unwrap();
+        if slots.free > 0 {
+            slots.free -= 1;
+            return Some(SessionSlot::Admitted {
+                _permit: SessionPermit(self.slots.clone()),
+            });
+        }
+        let queue_length = self.config.queue_length;
+        self.queued
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |queued| {
+                (queued < queue_length).then_some(queued + 1)
+            })
+            .ok()?;
+        let (waiter, handed_over) = oneshot::channel();
+        slots.waiting.entry(priority).or_default().push_back(waiter);
+        Some(SessionSlot::Queued(
+            self.clone(),
+            QueueGuard(self.queued.clone()),
+            handed_over,
+        ))
+    }
+
+    /// Connections waiting for a slot
+    pub(crate) fn queue_depth(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+}
+
+impl SessionSlot {
+    /// Waits for a slot if queued, `None` if queue timeout passed first
+    pub(crate) async fn wait(self) -> Option<SessionSlot> {
+        match self {
+            SessionSlot::Queued(limit, _queued, mut handed_over) => {
+                let admitted = match tokio::time::timeout(
+                    limit.config.queue_timeout,
+                    &mut handed_over,
+                )
+                .await
+                {
+                    Ok(handed_over) => handed_over.is_ok(),
+                    // Slot may have been handed over just as the wait timed out, it's taken then
+                    Err(_) => {
+                        handed_over.close();
+                        handed_over.try_recv().is_ok()
+                    }
+                };
+                admitted.then(|| SessionSlot::Admitted {
+                    _permit: SessionPermit(limit.slots.clone()),
+                })
+            }
+            slot => Some(slot),
+        }
+    }
+}
+
+/// Network transfers in progress across all sessions. Co-located sessions share uplink of the
+/// server, so a round overlapping transfers of other sessions is slower than the client's link.
+#[derive(Clone, Default)]
+pub(crate) struct ActiveTransfers {
+    counters: Arc<TransferCounters>,
+    /// Cap of transfers in progress, `None` doesn't cap them
+    slots: Option<Arc<Semaphore>>,
+}
+
+#[derive(Default)]
+struct TransferCounters {
+    active: AtomicUsize,
+    /// Transfers ever started, tells how many started while a given one was in progress
+    started: AtomicUsize,
+}
+
+impl ActiveTransfers {
+    /// Transfers of which at most `max_transfers` are in progress at a time
+    pub(crate) fn limited(max_transfers: usize) -> Self {
+        ActiveTransfers {
+            counters: Default::default(),
+            slots: Some(Arc::new(Semaphore::new(max_transfers))),
+        }
+    }
+
+    /// Counts a transfer as active until returned guard is dropped, once a slot is free if
+    /// transfers are capped
+    pub(crate) async fn enter(&self) -> TransferGuard {
+        let permit = match &self.slots {
+            Some(slots) => Some(slots.clone().acquire_owned().await),
+            None => None,
+        };
+        let started = self.counters.started.fetch_add(1, Ordering::SeqCst) + 1;
+        let others_active = self.counters.active.fetch_add(1, Ordering::SeqCst);
+        TransferGuard {
+            counters: self.counters.clone(),
+            others_active,
+            started,
+            _permit: permit,
+        }
+    }
+}
+
+pub(crate) struct TransferGuard {
+    counters: Arc<TransferCounters>,
+    /// Other transfers in progress when this one started
+    others_active: usize,
+    started: usize,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl TransferGuard {
+    /// Transfers which overlapped this one so far, either in progress when it started or started since
+    pub(crate) fn overlapping(&self) -> usize {
+        self.others_active + self.counters.started.load(Ordering::SeqCst) - self.started
+    }
+}
+
+impl Drop for TransferGuard {
+    fn drop(&mut self) {
+        self.counters.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Load of the server as reported to operators
+#[derive(Debug, Serialize)]
+pub(crate) struct CapacityReport {
+    /// Sessions currently being measured
+    pub(crate) active_sessions: usize,
+    /// Sessions waiting for admission, `None` while server admits every session immediately
+    queue_depth: Option<usize>,
+    /// Pre-generated puzzles ready to be sent, `None` while puzzles are generated on demand
+    puzzle_pool_level: Option<usize>,
+    pub(crate) verification_threads: usize,
+    /// Verification tasks waiting for a free thread
+    pub(crate) verification_backlog: usize,
+    /// Sessions started during the last minute
+    sessions_last_minute: usize,
+    /// Mean verification time of a session over recent runs
+    mean_session_verification_micros: Option<u128>,
+    /// Sessions per minute verification pool could absorb on top of the current rate,
+    /// `None` until there are runs to base the estimate on
+    pub(crate) additional_sessions_per_minute: Option<u128>,
+}
+
+/// Estimates capacity from verification cost of recent runs. Recorded verification
+/// timings include time spent in the pool queue, so the estimate errs on the low side
+/// when server is busy.
+pub(crate) fn capacity_report(
+    storage: &HashMap<u128, ClientData>,
+    verification_pool: &VerificationPool,
+    active_sessions: &ActiveSessions,
+    session_limit: Option<&SessionLimit>,
+    now: SystemTime,
+) -> CapacityReport {
+    let mut runs: Vec<&ClientData> = storage.values().collect();
+    runs.sort_by_key(|data| std::cmp::Reverse(data.started_at));
+    runs.truncate(RECENT_RUNS);
+
+    let sessions_last_minute = runs
+        .iter()
+        .filter(|data| {
+            now.duration_since(data.started_at)
+                .map(|elapsed| elapsed < Duration::from_secs(60))
+                .unwrap_or(true)
+        })
+        .count();
+
+    let mean_session_verification_micros = if runs.is_empty() {
+        None
+    } else {
+        let total: u128 = runs
+            .iter()
+            .map(|data| {
+                data.cpu_verification_timings_in_micros.iter().sum::<u128>()
+                    + data
+                        .network_verification_timings_in_micros
+                        .iter()
+                        .sum::<u128>()
+                    + data
+                        .disk_verification_timings_in_micros
+                        .iter()
+                        .sum::<u128>()
+            })
+            .sum();
+        Some(total / runs.len() as u128)
+    };
+
+    let verification_threads = verification_pool.threads();
+    let additional_sessions_per_minute = mean_session_verification_micros.map(|mean| {
+        let mean = std::cmp::max(mean, 1);
+        let budget = verification_threads as u128 * Duration::from_secs(60).as_micros();
+        budget.saturating_sub(sessions_last_minute as u128 * mean) / mean
+    });
+
+    CapacityReport {
+        active_sessions: active_sessions.count(),
+        queue_depth: session_limit.map(SessionLimit::queue_depth),
+        puzzle_pool_level: None,
+        verification_threads,
+        verification_backlog: verification_pool.backlog(),
+        sessions_last_minute,
+        mean_session_verification_micros,
+        additional_sessions_per_minute,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::capacity::{
+        capacity_report, ActiveSessions, ActiveTransfers, SessionLimit, SessionLimitConfig,
+        SessionSlot,
+    };
+    use crate::types::test_utils::client_data;
+    use crate::types::PriorityClass;
+    use crate::verification::VerificationPool;
+    use std::collections::HashMap;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_active_sessions() {
+        let active_sessions = ActiveSessions::default();
+        let first = active_sessions.enter();
+        let _second = active_sessions.enter();
+        assert_eq!(active_sessions.count(), 2);
+        drop(first);
+        assert_eq!(active_sessions.count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_overlapping_transfers() {
+        let transfers = ActiveTransfers::default();
+        let first = transfers.enter().await;
+        assert_eq!(first.overlapping(), 0);
+        let second = transfers.enter().await;
+        assert_eq!(second.overlapping(), 1);
+        drop(second);
+        // Transfers which already ended still overlapped
+        let third = transfers.enter().await;
+        assert_eq!(first.overlapping(), 2);
+        assert_eq!(third.overlapping(), 1);
+        drop(first);
+        assert_eq!(transfers.enter().await.overlapping(), 1);
+
+        let limited = ActiveTransfers::limited(1);
+        let first = limited.enter().await;
+        let mut waiting = tokio::spawn({
+            let limited = limited.clone();
+            async move { limited.enter().await.overlapping() }
+        });
+        tokio::time::delay_for(Duration::from_millis(20)).await;
+        assert!(futures::FutureExt::now_or_never(&mut waiting).is_none());
+        // It only starts once the first one ended, so they don't overlap
+        drop(first);
+        assert_eq!(waiting.await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_session_limit_priority() {
+        let limit = SessionLimit::new(SessionLimitConfig {
+            max_sessions: 1,
+            queue_length: 2,
+            queue_timeout: Duration::from_secs(5),
+        });
+        let admitted = limit.admit(PriorityClass::Normal).unwrap();
+        let low = limit.admit(PriorityClass::BestEffort).unwrap();
+        let high = limit.admit(PriorityClass::High).unwrap();
+        assert!(limit.admit(PriorityClass::High).is_none());
+        assert_eq!(limit.queue_depth(), 2);
+        let mut low = tokio::spawn(low.wait());
+        let high = tokio::spawn(high.wait());
+
+        // Session of the higher class is admitted first, though it was queued last
+        drop(admitted);
+        let high = high.await.unwrap().unwrap();
+        assert!(matches!(high, SessionSlot::Admitted { .. }));
+        tokio::time::delay_for(Duration::from_millis(20)).await;
+        assert!(futures::FutureExt::now_or_never(&mut low).is_none());
+        drop(high);
+        assert!(matches!(
+            low.await.unwrap(),
+            Some(SessionSlot::Admitted { .. })
+        ));
+        assert_eq!(limit.queue_depth(), 0);
+
+        // Slot isn't lost to a session which stopped waiting
+        let limit = SessionLimit::new(SessionLimitConfig {
+            max_sessions: 1,
+            queue_length: 1,
+            queue_timeout: Duration::from_millis(10),
+        });
+        let admitted = limit.admit(PriorityClass::Normal).unwrap();
+        let queued = limit.admit(PriorityClass::High).unwrap();
+        assert!(queued.wait().await.is_none());
+        drop(admitted);
+        assert!(matches!(
+            limit.admit(PriorityClass::BestEffort),
+            Some(SessionSlot::Admitted { .. })
+        ));
+    }
+
+    #[test]
+    fn test_capacity_report() {
+        let pool = VerificationPool::new(Some(2)).unwrap();
+        let active_sessions = ActiveSessions::default();
+        let now = SystemTime::now();
+
+        let report = capacity_report(&HashMap::new(), &pool, &active_sessions, None, now);
+        assert_eq!(report.additional_sessions_per_minute, None);
+
+        let mut storage = HashMap::new();
+        let mut recent = client_data(90);
+        recent.cpu_verification_timings_in_micros = vec![1_000_000];
+        recent.network_verification_timings_in_micros = vec![1_000_000];
+        storage.insert(1, recent);
+        let mut old = client_data(90);
+        old.cpu_verification_timings_in_micros = vec![2_000_000];
+        old.network_verification_timings_in_micros = vec![2_000_000];
+        old.started_at = now - Duration::from_secs(3600);
+        storage.insert(2, old);
+
+        let _session = active_sessions.enter();
+        let report = capacity_report(&storage, &pool, &active_sessions, None, now);
+        assert_eq!(report.active_sessions, 1);
+        assert_eq!(report.verification_threads, 2);
+        assert_eq!(report.sessions_last_minute, 1);
+        assert_eq!(report.mean_session_verification_micros, Some(3_000_000));
+        // 120s of verification time per minute, 3s of it already used by the recent session
+        assert_eq!(report.additional_sessions_per_minute, Some(39));
+    }
+}
@@ -0,0 +1,355 @@
+use anyhow::{anyhow, Result};
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer, Verifier};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::sync::RwLock;
+
+use crate::api::unix_milliseconds;
+use crate::types::ClientData;
+
+/// Signing of certificates attesting that a client was measured
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct CertificateConfig {
+    /// Hex encoded 32 byte Ed25519 secret key
+    pub(crate) signing_key: String,
+    /// Hex encoded public keys of retired signing keys, certificates they signed still verify
+    #[serde(default)]
+    pub(crate) retired_public_keys: Vec<String>,
+    /// API key `POST /admin/certificate/rotate` requires, `None` disables rotation over the API
+    #[serde(default)]
+    pub(crate) rotation_api_key: Option<String>,
+    /// Certificates only attest that the run completed under the plan, without the score.
+    /// For deployments treating scores as sensitive which still need proof of measurement.
+    #[serde(default)]
+    pub(crate) participation_only: bool,
+}
+
+/// Statement signed by the server
+#[derive(Debug, Serialize)]
+struct Certificate<'a> {
+    /// Id of the key which signed the certificate, signed along with it
+    key_id: String,
+    run_id: String,
+    plan_name: &'a str,
+    measured_at_unix_milliseconds: u128,
+    completed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    score: Option<u128>,
+}
+
+/// Certificate along with its signature. `certificate` holds the exact JSON
+/// which was signed, so it can be verified without canonicalizing it first.
+#[derive(Debug, Serialize)]
+pub(crate) struct SignedCertificate {
+    pub(crate) certificate: String,
+    /// Hex encoded Ed25519 signature of `certificate`
+    pub(crate) signature: String,
+    /// Id of the key which signed `certificate`
+    key_id: String,
+    /// Hex encoded key to verify `signature` with, verifiers should pin it rather than trust it
+    public_key: String,
+}
+
+/// Key certificates can be verified with
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct VerificationKey {
+    /// First 16 hex digits of `public_key`
+    pub(crate) key_id: String,
+    /// Hex encoded Ed25519 public key
+    pub(crate) public_key: String,
+    /// Whether the key signs new certificates, retired keys only verify
+    pub(crate) active: bool,
+}
+
+/// Outcome of verifying a certificate
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct Verification {
+    pub(crate) valid: bool,
+    /// Key the certificate was signed with, `None` if no known key signed it
+    pub(crate) key_id: Option<String>,
+    /// Whether that key has been retired since
+    pub(crate) retired: bool,
+}
+
+/// Active signing key and public keys of retired ones, most recently retired last
+struct KeyRing {
+    active: Keypair,
+    retired: Vec<PublicKey>,
+}
+
+impl KeyRing {
+    fn public_keys(&self) -> impl Iterator<Item = (&PublicKey, bool)> {
+        std::iter::once((&self.active.public, true))
+            .chain(self.retired.iter().rev().map(|public| (public, false)))
+    }
+}
+
+pub(crate) struct CertificateSigner {
+    keys: RwLock<KeyRing>,
+    participation_only: bool,
+    rotation_api_key: Option<String>,
+}
+
+impl CertificateSigner {
+    pub(crate) fn new(config: &CertificateConfig) -> Result<Self> {
+        let retired = config
+            .retired_public_keys
+            .iter()
+            .map(|public| {
+                PublicKey::from_bytes(&from_hex(public)?)
+                    .map_err(|e| anyhow!("Invalid retired public key {}: {}", public, e))
+            })
+            .collect::<Result<_>>()?;
+        Ok(CertificateSigner {
+            keys: RwLock::new(KeyRing {
+                active: keypair(&config.signing_key)?,
+                retired,
+            }),
+            participation_only: config.participation_only,
+            rotation_api_key: config.rotation_api_key.clone(),
+        })
+    }
+
+    /// Whether `api_key` may rotate the signing key
+    pub(crate) fn may_rotate(&self, api_key: Option<&str>) -> bool {
+        matches!((&self.rotation_api_key, api_key), (Some(expected), Some(api_key)) if expected == api_key)
+    }
+
+    /// Makes `signing_key` the active key, the previous one is retired and keeps verifying
+    /// certificates it signed. Rotation isn't persisted, configuration has to be updated alike.
+    pub(crate) fn rotate(&self, signing_key: &str) -> Result<VerificationKey> {
+        let keypair = keypair(signing_key)?;
+        let mut keys = self.keys.write().unwrap();
+        if keys
+            .public_keys()
+            .any(|(public, _)| *public == keypair.public)
+        {
+            return Err(anyhow!(
+                "Signing key {} was already used",
+                key_id(&keypair.public)
+            ));
+        }
+        let retired = std::mem::replace(&mut keys.active, keypair).public;
+        keys.retired.push(retired);
+        Ok(VerificationKey {
+            key_id: key_id(&keys.active.public),
+            public_key: to_hex(keys.active.public.as_bytes()),
+            active: true,
+        })
+    }
+
+    /// Active key followed by retired ones, most recently retired first
+    pub(crate) fn verification_keys(&self) -> Vec<VerificationKey> {
+        self.keys
+            .read()
+            .unwrap()
+            .public_keys()
+            .map(|(public, active)| VerificationKey {
+                key_id: key_id(public),
+                public_key: to_hex(public.as_bytes()),
+                active,
+            })
+            .collect()
+    }
+
+    /// Verifies `certificate` against the key its `key_id` names, certificates signed before
+    /// key ids were introduced carry none and are checked against every known key
+    pub(crate) fn verify(&self, certificate: &str, signature: &str) -> Result<Verification> {
+        let signature = Signature::try_from(&from_hex(signature)?[..])
+            .map_err(|e| anyhow!("Invalid signature: {}", e))?;
+        let claimed: serde_json::Value =
+            serde_json::from_str(certificate).map_err(|e| anyhow!("Invalid certificate: {}", e))?;
+        let claimed = claimed.get("key_id").and_then(|key_id| key_id.as_str());
+        let keys = self.keys.read().unwrap();
+        let signed_by = keys
+            .public_keys()
+            .filter(|(public, _)| claimed.is_none_or(|claimed| claimed == key_id(public)))
+            .find(|(public, _)| public.verify(certificate.as_bytes(), &signature).is_ok());
+        Ok(Verification {
+            valid: signed_by.is_some(),
+            key_id: signed_by.map(|(public, _)| key_id(public)),
+            retired: signed_by.is_some_and(|(_, active)| !active),
+        })
+    }
+
+    /// Signs a certificate of a stored run. Runs are only stored once all of their rounds
+    /// completed, so every certificate attests a completed measurement.
+    pub(crate) fn sign(&self, client_id: u128, data: &ClientData) -> SignedCertificate {
+        let keys = self.keys.read().unwrap();
+        let key_id = key_id(&keys.active.public);
+        let certificate = Certificate {
+            key_id: key_id.clone(),
+            run_id: format!("{:x}", client_id),
+            plan_name: &data.configuration.plan_name,
+            measured_at_unix_milliseconds: unix_milliseconds(data.started_at),
+            completed: true,
+            score: if self.participation_only {
+                None
+            } else {
+                Some(data.score)
+            },
+        };
+        let certificate =
+            serde_json::to_string(&certificate).expect("certificate is serializable to JSON");
+        let signature = keys.active.sign(certificate.as_bytes());
+        SignedCertificate {
+            certificate,
+            signature: to_hex(&signature.to_bytes()),
+            key_id,
+            public_key: to_hex(keys.active.public.as_bytes()),
+        }
+    }
+}
+
+fn keypair(signing_key: &str) -> Result<Keypair> {
+    let secret = SecretKey::from_bytes(&from_hex(signing_key)?)
+        .map_err(|e| anyhow!("Invalid certificate signing key: {}", e))?;
+    let public = PublicKey::from(&secret);
+    Ok(Keypair { secret, public })
+}
+
+fn key_id(public: &PublicKey) -> String {
+    to_hex(&public.as_bytes()[..8])
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub(crate) fn from_hex(hex: &str) -> Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return Err(anyhow!("Hex string has odd length"));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| anyhow!("Invalid hex string"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::certificate::{from_hex, CertificateConfig, CertificateSigner, Verification};
+    use crate::types::test_utils::client_data;
+    use ed25519_dalek::{PublicKey, Signature, Verifier};
+    use std::convert::TryFrom;
+
+    const SIGNING_KEY: &str = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+    const NEXT_SIGNING_KEY: &str =
+        "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb";
+
+    #[test]
+    fn test_participation_certificate() {
+        let signer = CertificateSigner::new(&CertificateConfig {
+            signing_key: SIGNING_KEY.to_owned(),
+            participation_only: true,
+            retired_public_keys: vec![],
+            rotation_api_key: None,
+        })
+        .unwrap();
+        let signed = signer.sign(0xabc, &client_data(73));
+
+        let certificate: serde_json::Value = serde_json::from_str(&signed.certificate).unwrap();
+        assert_eq!(certificate["run_id"], "abc");
+        assert_eq!(certificate["plan_name"], "default");
+        assert_eq!(certificate["completed"], true);
+        assert!(certificate.get("score").is_none());
+
+        let public_key = PublicKey::from_bytes(&from_hex(&signed.public_key).unwrap()).unwrap();
+        let signature = Signature::try_from(&from_hex(&signed.signature).unwrap()[..]).unwrap();
+        assert!(public_key
+            .verify(signed.certificate.as_bytes(), &signature)
+            .is_ok());
+        let tampered = signed.certificate.replace("default", "premium");
+        assert!(public_key.verify(tampered.as_bytes(), &signature).is_err());
+
+        let signer = CertificateSigner::new(&CertificateConfig {
+            signing_key: SIGNING_KEY.to_owned(),
+            participation_only: false,
+            retired_public_keys: vec![],
+            rotation_api_key: None,
+        })
+        .unwrap();
+        let certificate: serde_json::Value =
+            serde_json::from_str(&signer.sign(0xabc, &client_data(73)).certificate).unwrap();
+        assert_eq!(certificate["score"], 73);
+
+        assert!(CertificateSigner::new(&CertificateConfig {
+            signing_key: "abc".to_owned(),
+            participation_only: false,
+            retired_public_keys: vec![],
+            rotation_api_key: None,
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn test_key_rotation() {
+        let signer = CertificateSigner::new(&CertificateConfig {
+            signing_key: SIGNING_KEY.to_owned(),
+            retired_public_keys: vec![],
+            rotation_api_key: Some("admin".to_owned()),
+            participation_only: false,
+        })
+        .unwrap();
+        assert!(signer.may_rotate(Some("admin")));
+        assert!(!signer.may_rotate(Some("tenant")));
+        assert!(!signer.may_rotate(None));
+
+        let old = signer.sign(0xabc, &client_data(73));
+        let old_key = signer.verification_keys().remove(0);
+        assert_eq!(old.key_id, old_key.key_id);
+        assert!(old.certificate.contains(&old_key.key_id));
+
+        let new_key = signer.rotate(NEXT_SIGNING_KEY).unwrap();
+        assert!(signer.rotate(SIGNING_KEY).is_err());
+        assert!(signer.rotate("abc").is_err());
+        let keys = signer.verification_keys();
+        assert_eq!(keys.len(), 2);
+        assert_eq!((&keys[0].key_id, keys[0].active), (&new_key.key_id, true));
+        assert_eq!((&keys[1].key_id, keys[1].active), (&old_key.key_id, false));
+
+        // Certificates of the retired key keep verifying, new ones are signed by the new key
+        let new = signer.sign(0xabc, &client_data(73));
+        assert_eq!(new.key_id, new_key.key_id);
+        assert_eq!(
+            signer.verify(&old.certificate, &old.signature).unwrap(),
+            Verification {
+                valid: true,
+                key_id: Some(old_key.key_id.clone()),
+                retired: true,
+            }
+        );
+        assert_eq!(
+            signer.verify(&new.certificate, &new.signature).unwrap(),
+            Verification {
+                valid: true,
+                key_id: Some(new_key.key_id.clone()),
+                retired: false,
+            }
+        );
+        let tampered = new.certificate.replace("default", "premium");
+        assert!(!signer.verify(&tampered, &new.signature).unwrap().valid);
+        assert!(signer.verify(&new.certificate, "abc").is_err());
+
+        // Server restarted with the rotated configuration still verifies old certificates
+        let restarted = CertificateSigner::new(&CertificateConfig {
+            signing_key: NEXT_SIGNING_KEY.to_owned(),
+            retired_public_keys: vec![old_key.public_key],
+            rotation_api_key: None,
+            participation_only: false,
+        })
+        .unwrap();
+        assert!(!restarted.may_rotate(Some("admin")));
+        assert!(
+            restarted
+                .verify(&old.certificate, &old.signature)
+                .unwrap()
+                .retired
+        );
+    }
+}
@@ -0,0 +1,77 @@
+use rand::rngs::OsRng;
+use rand::RngCore;
+use shared::hash::HashAlgorithm;
+use std::convert::TryInto;
+
+use crate::certificate::{from_hex, to_hex};
+
+/// Random bytes of an issued token
+const TOKEN_BYTES: usize = 32;
+
+/// Identity of a client across sessions, so that runs of the same machine form a history.
+/// Server issues a random token which client presents on reconnect. Identity is derived
+/// from the token, so server keeps no record of issued tokens and a token can't be
+/// guessed from the identity, which is public as part of stored runs.
+#[derive(Clone, Debug)]
+pub(crate) struct ClientIdentity {
+    /// Secret of the client, sent to it in `ServerHello`
+    pub(crate) token: String,
+    /// Id runs of the client are stored under
+    pub(crate) id: u128,
+}
+
+impl ClientIdentity {
+    /// Identity of `token`, a newly issued one if client presented none or a malformed one
+    pub(crate) fn resolve(token: Option<&str>) -> Self {
+        token
+            .and_then(|token| {
+                let bytes = from_hex(token)
+                    .ok()
+                    .filter(|bytes| bytes.len() == TOKEN_BYTES)?;
+                Some(ClientIdentity {
+                    token: token.to_ascii_lowercase(),
+                    id: id_of(&bytes),
+                })
+            })
+            .unwrap_or_else(Self::issue)
+    }
+
+    pub(crate) fn issue() -> Self {
+        let mut bytes = [0; TOKEN_BYTES];
+        OsRng.fill_bytes(&mut bytes);
+        ClientIdentity {
+            token: to_hex(&bytes),
+            id: id_of(&bytes),
+        }
+    }
+}
+
+/// First 16 bytes of SHA-256 of the token
+fn id_of(token: &[u8]) -> u128 {
+    let digest = HashAlgorithm::Sha256.digest(token);
+    u128::from_be_bytes(digest[..16].try_into().expect("SHA-256 has 32 bytes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client_identity::ClientIdentity;
+
+    #[test]
+    fn test_resolve() {
+        let issued = ClientIdentity::issue();
+        assert_eq!(issued.token.len(), 64);
+        let resolved = ClientIdentity::resolve(Some(&issued.token));
+        assert_eq!(resolved.id, issued.id);
+        assert_eq!(resolved.token, issued.token);
+        assert_eq!(
+            ClientIdentity::resolve(Some(&issued.token.to_uppercase())).id,
+            issued.id
+        );
+
+        // Clients without a valid token get a new identity
+        assert_ne!(ClientIdentity::resolve(None).id, issued.id);
+        let other = ClientIdentity::resolve(Some("abc"));
+        assert_ne!(other.token, "abc");
+        assert_ne!(other.id, ClientIdentity::resolve(Some("abc")).id);
+    }
+}
@@ -0,0 +1,95 @@
+//! `/time`, a websocket streaming the server's wall clock, see `shared::clock`
+
+use futures::{SinkExt, StreamExt};
+use shared::clock::TimeTick;
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use warp::ws::{Message, WebSocket};
+use warp::{Filter, Rejection, Reply};
+
+use crate::config::ServerConfig;
+
+/// Upgrades `/time` to a websocket sending a `TimeTick` every `time_tick_interval` of `config`
+/// until the client goes away
+pub fn time_route(
+    config: Arc<ServerConfig>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let interval = config.time_tick_interval;
+    warp::path("time")
+        .and(warp::path::end())
+        .and(warp::ws())
+        .map(move |ws: warp::ws::Ws| ws.on_upgrade(move |socket| stream_ticks(socket, interval)))
+}
+
+async fn stream_ticks(socket: WebSocket, interval: Duration) {
+    let (mut sender, mut receiver) = socket.split();
+    let mut ticker = tokio::time::interval(interval);
+    let mut sequence = 0;
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {
+                let tick = TimeTick {
+                    sequence,
+                    unix_microseconds: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map(|elapsed| u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX))
+                        .unwrap_or(0),
+                };
+                if let Err(e) = sender.send(Message::binary(tick.to_wire())).await {
+                    debug!("Clock stream ended: {:?}", e);
+                    return;
+                }
+                sequence += 1;
+            }
+            // Client sends nothing but the close frame, which ends the stream like a dropped
+            // connection does
+            message = receiver.next() => match message {
+                Some(Ok(message)) if !message.is_close() => {}
+                _ => return,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::time_route;
+    use crate::config::ServerConfig;
+    use shared::clock::{ClockDiscipline, TimeTick};
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    #[tokio::test]
+    async fn test_time_route() {
+        let config = Arc::new(ServerConfig {
+            time_tick_interval: Duration::from_millis(5),
+            ..Default::default()
+        });
+        let filter = time_route(config.clone());
+        let mut client = warp::test::ws()
+            .path("/time")
+            .handshake(filter)
+            .await
+            .unwrap();
+        let first = TimeTick::from_wire(client.recv().await.unwrap().as_bytes()).unwrap();
+        let second = TimeTick::from_wire(client.recv().await.unwrap().as_bytes()).unwrap();
+        assert_eq!((first.sequence, second.sequence), (0, 1));
+        assert!(second.unix_microseconds >= first.unix_microseconds);
+
+        let (address, server) = warp::serve(time_route(config)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let mut discipline = ClockDiscipline::new(10);
+        client::discipline_clock(&format!("ws://{}/time", address), &mut discipline, 5)
+            .await
+            .unwrap();
+        assert_eq!(discipline.samples(), 5);
+        // Both ends read the same clock, only the delay of the stream is in between
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_micros();
+        let offset = discipline.offset_microseconds(now as u64).unwrap();
+        assert!(offset.abs() < 1_000_000, "{}", offset);
+    }
+}
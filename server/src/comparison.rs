@@ -0,0 +1,298 @@
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::measurements::find_mean;
+use crate::types::{AddressFamily, ClientData};
+
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct MetricDelta {
+    metric: &'static str,
+    a: u128,
+    b: u128,
+    /// `b - a`
+    delta: i128,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct ConfigurationDifference {
+    field: String,
+    a: Value,
+    b: Value,
+}
+
+/// What the difference in score of two runs can be attributed to
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ScoreChangeExplanation {
+    /// Both runs have the same score
+    Unchanged,
+    /// Runs were measured with the same configuration, so client's performance changed
+    Performance,
+    /// Runs were measured with different configurations, so scores are not directly comparable
+    Configuration,
+}
+
+/// Structured difference between two runs
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct RunComparison {
+    metrics: Vec<MetricDelta>,
+    configuration_differences: Vec<ConfigurationDifference>,
+    score_change_explanation: ScoreChangeExplanation,
+}
+
+fn metric_delta(metric: &'static str, a: u128, b: u128) -> MetricDelta {
+    MetricDelta {
+        metric,
+        a,
+        b,
+        delta: b as i128 - a as i128,
+    }
+}
+
+fn mean_delta(metric: &'static str, a: &[u128], b: &[u128]) -> Option<MetricDelta> {
+    Some(metric_delta(metric, find_mean(a)?, find_mean(b)?))
+}
+
+pub(crate) fn compare_runs(a: &ClientData, b: &ClientData) -> RunComparison {
+    let metrics = vec![
+        Some(metric_delta("score", a.score, b.score)),
+        mean_delta(
+            "mean_cpu_challenge_milliseconds",
+            &a.cpu_challenge_timings_in_milis,
+            &b.cpu_challenge_timings_in_milis,
+        ),
+        mean_delta(
+            "mean_network_challenge_milliseconds",
+            &a.network_challenge_timings_in_milis,
+            &b.network_challenge_timings_in_milis,
+        ),
+        mean_delta(
+            "mean_disk_challenge_milliseconds",
+            &a.disk_challenge_timings_in_milis,
+            &b.disk_challenge_timings_in_milis,
+        ),
+        mean_delta(
+            "mean_latency_probe_microseconds",
+            &a.latency_probe_timings_in_micros,
+            &b.latency_probe_timings_in_micros,
+        ),
+        mean_delta(
+            "mean_download_challenge_milliseconds",
+            &a.download_challenge_timings_in_milis,
+            &b.download_challenge_timings_in_milis,
+        ),
+        mean_delta(
+            "mean_upload_challenge_milliseconds",
+            &a.upload_challenge_timings_in_milis,
+            &b.upload_challenge_timings_in_milis,
+        ),
+        mean_delta(
+            "mean_cpu_verification_microseconds",
+            &a.cpu_verification_timings_in_micros,
+            &b.cpu_verification_timings_in_micros,
+        ),
+        mean_delta(
+            "mean_network_verification_microseconds",
+            &a.network_verification_timings_in_micros,
+            &b.network_verification_timings_in_micros,
+        ),
+        mean_delta(
+            "mean_disk_verification_microseconds",
+            &a.disk_verification_timings_in_micros,
+            &b.disk_verification_timings_in_micros,
+        ),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+
+    // Comparing serialized form, so that new configuration fields are picked up automatically
+    let configuration_differences = match (
+        serde_json::to_value(&a.configuration),
+        serde_json::to_value(&b.configuration),
+    ) {
+        (Ok(Value::Object(a_fields)), Ok(Value::Object(b_fields))) => a_fields
+            .into_iter()
+            .filter_map(|(field, a_value)| {
+                let b_value = b_fields.get(&field).cloned().unwrap_or(Value::Null);
+                if a_value != b_value {
+                    Some(ConfigurationDifference {
+                        field,
+                        a: a_value,
+                        b: b_value,
+                    })
+                } else {
+                    None
+                }
+            })
+            .collect(),
+        _ => unreachable!("run configuration always serializes to an object"),
+    };
+
+    let score_change_explanation = if a.score == b.score {
+        ScoreChangeExplanation::Unchanged
+    } else if a.configuration == b.configuration {
+        ScoreChangeExplanation::Performance
+    } else {
+        ScoreChangeExplanation::Configuration
+    };
+
+    RunComparison {
+        metrics,
+        configuration_differences,
+        score_change_explanation,
+    }
+}
+
+/// Comparison of the two halves of a dual-stack run, IPv4 run is `a` and IPv6 run is `b`
+#[derive(Debug, Serialize)]
+pub(crate) struct DualStackComparison {
+    ipv4_run_id: String,
+    ipv6_run_id: String,
+    #[serde(flatten)]
+    comparison: RunComparison,
+}
+
+pub(crate) fn compare_dual_stack(
+    first: (u128, &ClientData),
+    second: (u128, &ClientData),
+) -> DualStackComparison {
+    let (ipv4, ipv6) = if first.1.address_family == Some(AddressFamily::Ipv6) {
+        (second, first)
+    } else {
+        (first, second)
+    };
+    DualStackComparison {
+        ipv4_run_id: format!("{:x}", ipv4.0),
+        ipv6_run_id: format!("{:x}", ipv6.0),
+        comparison: compare_runs(ipv4.1, ipv6.1),
+    }
+}
+
+/// Comparison of a run measured in cleartext, `a`, with a run of the same client measured over
+/// TLS, `b`, so that metric deltas are the overhead of TLS
+#[derive(Debug, Serialize)]
+pub(crate) struct TlsOverheadComparison {
+    cleartext_run_id: String,
+    encrypted_run_id: String,
+    tls_handshake_milliseconds: Option<u128>,
+    /// Mean throughput of network rounds of each run, `None` if the run had none
+    cleartext_network_mbps: Option<f64>,
+    encrypted_network_mbps: Option<f64>,
+    /// Share of cleartext network throughput lost over TLS
+    network_overhead_percent: Option<f64>,
+    #[serde(flatten)]
+    comparison: RunComparison,
+}
+
+fn mean_mbps(data: &ClientData) -> Option<f64> {
+    let rounds = &data.network_challenge_throughput_in_mbps;
+    if rounds.is_empty() {
+        None
+    } else {
+        Some(rounds.iter().sum::<f64>() / rounds.len() as f64)
+    }
+}
+
+pub(crate) fn compare_tls_overhead(
+    first: (u128, &ClientData),
+    second: (u128, &ClientData),
+) -> TlsOverheadComparison {
+    let (cleartext, encrypted) = if first.1.tls_handshake_milliseconds.is_some() {
+        (second, first)
+    } else {
+        (first, second)
+    };
+    let cleartext_network_mbps = mean_mbps(cleartext.1);
+    let encrypted_network_mbps = mean_mbps(encrypted.1);
+    TlsOverheadComparison {
+        cleartext_run_id: format!("{:x}", cleartext.0),
+        encrypted_run_id: format!("{:x}", encrypted.0),
+        tls_handshake_milliseconds: encrypted.1.tls_handshake_milliseconds,
+        cleartext_network_mbps,
+        encrypted_network_mbps,
+        network_overhead_percent: match (cleartext_network_mbps, encrypted_network_mbps) {
+            (Some(cleartext), Some(encrypted)) if cleartext > 0.0 => {
+                Some((cleartext - encrypted) / cleartext * 100.0)
+            }
+            _ => None,
+        },
+        comparison: compare_runs(cleartext.1, encrypted.1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::comparison::{
+        compare_dual_stack, compare_runs, compare_tls_overhead, ScoreChangeExplanation,
+    };
+    use crate::types::test_utils::client_data;
+    use crate::types::AddressFamily;
+    use serde_json::json;
+
+    #[test]
+    fn test_compare_runs() {
+        let a = client_data(90);
+        let mut b = client_data(90);
+        b.cpu_challenge_timings_in_milis = vec![50, 70];
+
+        let comparison = compare_runs(&a, &b);
+        assert_eq!(
+            comparison.score_change_explanation,
+            ScoreChangeExplanation::Unchanged
+        );
+        assert!(comparison.configuration_differences.is_empty());
+        assert_eq!(comparison.metrics[1].delta, -40);
+
+        b.score = 70;
+        assert_eq!(
+            compare_runs(&a, &b).score_change_explanation,
+            ScoreChangeExplanation::Performance
+        );
+
+        b.configuration.squarings = 20;
+        let comparison = compare_runs(&a, &b);
+        assert_eq!(
+            comparison.score_change_explanation,
+            ScoreChangeExplanation::Configuration
+        );
+        assert_eq!(comparison.configuration_differences.len(), 1);
+        assert_eq!(comparison.configuration_differences[0].field, "squarings");
+        assert_eq!(comparison.configuration_differences[0].b, json!(20));
+    }
+
+    #[test]
+    fn test_compare_dual_stack() {
+        let mut ipv6 = client_data(60);
+        ipv6.address_family = Some(AddressFamily::Ipv6);
+        let mut ipv4 = client_data(90);
+        ipv4.address_family = Some(AddressFamily::Ipv4);
+
+        let comparison = compare_dual_stack((0xa, &ipv6), (0xb, &ipv4));
+        assert_eq!(comparison.ipv4_run_id, "b");
+        assert_eq!(comparison.ipv6_run_id, "a");
+        assert_eq!(comparison.comparison.metrics[0].a, 90);
+        assert_eq!(comparison.comparison.metrics[0].delta, -30);
+    }
+
+    #[test]
+    fn test_compare_tls_overhead() {
+        let mut encrypted = client_data(80);
+        encrypted.tls_handshake_milliseconds = Some(12);
+        encrypted.network_challenge_throughput_in_mbps = vec![70.0, 90.0];
+        let mut cleartext = client_data(90);
+        cleartext.network_challenge_throughput_in_mbps = vec![100.0];
+
+        let comparison = compare_tls_overhead((0xa, &encrypted), (0xb, &cleartext));
+        assert_eq!(comparison.cleartext_run_id, "b");
+        assert_eq!(comparison.encrypted_run_id, "a");
+        assert_eq!(comparison.tls_handshake_milliseconds, Some(12));
+        assert_eq!(comparison.network_overhead_percent, Some(20.0));
+        assert_eq!(comparison.comparison.metrics[0].delta, -10);
+
+        cleartext.network_challenge_throughput_in_mbps.clear();
+        let comparison = compare_tls_overhead((0xa, &encrypted), (0xb, &cleartext));
+        assert_eq!(comparison.cleartext_network_mbps, None);
+        assert_eq!(comparison.network_overhead_percent, None);
+    }
+}
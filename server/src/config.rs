@@ -0,0 +1,1395 @@
+use crate::anomaly::AnomalyDetectionConfig;
+#[cfg(feature = "event-bus")]
+use crate::bus::EventBusConfig;
+use crate::capacity::SessionLimitConfig;
+use crate::certificate::CertificateConfig;
+use crate::dead_letters::DeadLetterConfig;
+use crate::estimate::HardwareProfile;
+use crate::identity::OidcConfig;
+use crate::measurements::{
+    cpu_round_milliseconds, network_round_milliseconds, Aggregation, MeasurementAuthConfig,
+    PlausibilityBounds, Scale, ScoringModel, Strictness,
+};
+use crate::policy::PolicyHookConfig;
+use crate::primes::PrimesConfig;
+use crate::rate_limit::RateLimitConfig;
+use crate::sizing::HostSizingConfig;
+use crate::storage::{api_key_digest, StorageChaosConfig, StorageConfig, TenantScope};
+use crate::tls::TlsConfig;
+use crate::transport::{Keepalive, MessageAuthentication};
+use crate::tuning::ThresholdTuningConfig;
+use crate::types::{PriorityClass, Visibility};
+use crate::watchdog::MemoryWatchdogConfig;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Deserializer};
+use shared::NetworkType;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use structopt::StructOpt;
+
+/// Server wide configuration
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Address the server listens on
+    pub(crate) bind_address: SocketAddr,
+    /// Prefix all routes are mounted under, e.g. `/reliability/v1`
+    pub(crate) base_path: String,
+    /// Certificate and key to serve over TLS (`https://`, `wss://`) with, `None` serves plain HTTP
+    pub(crate) tls: Option<TlsConfig>,
+    /// Address clients are also measured at over plain TCP with length prefixed messages, for
+    /// clients without a websocket stack. `None` only measures over websockets.
+    pub(crate) tcp_bind_address: Option<SocketAddr>,
+    /// UDP address datagram probes of the plan are sent from, see `PlanConfig::datagram_probes`.
+    /// `None` skips datagram probes.
+    pub(crate) udp_bind_address: Option<SocketAddr>,
+    /// Number of threads used to verify client responses, `None` means one per CPU
+    pub(crate) verification_threads: Option<usize>,
+    /// Priority class of sessions, keyed by API key sent in `x-api-key` header
+    pub(crate) priority_classes: HashMap<String, PriorityClass>,
+    /// Priority class of sessions without API key or with an API key not listed in `priority_classes`
+    pub(crate) default_priority_class: PriorityClass,
+    /// API keys of tenants whose runs are always private
+    pub(crate) private_tenants: HashSet<String>,
+    /// API keys which read private runs and sessions through the HTTP API. Unlike keys of
+    /// `priority_classes` and `private_tenants`, which measured clients send, they're only meant
+    /// for operators.
+    pub(crate) reader_api_keys: HashSet<String>,
+    /// API keys which may use `/admin` routes. Admin routes are refused to everyone if empty.
+    pub(crate) admin_api_keys: HashSet<String>,
+    /// Teams sharing the server, keyed by name. Each sees only runs of clients measured with its
+    /// API keys, and none of runs of other teams.
+    pub(crate) tenants: HashMap<String, TenantConfig>,
+    /// How rounds which fail are treated in sessions, keyed by API key sent in `x-api-key` header
+    pub(crate) strictness_modes: HashMap<String, Strictness>,
+    /// Strictness of sessions without API key or with an API key not listed in `strictness_modes`
+    pub(crate) default_strictness: Strictness,
+    /// Named fleets, each defined by a tag query, e.g. `region:eu AND class:native`
+    pub(crate) fleets: HashMap<String, String>,
+    /// Hook to call when a node's score crosses acceptable threshold
+    pub(crate) policy_hook: Option<PolicyHookConfig>,
+    /// Retries of side effects which failed, e.g. calls of the policy hook
+    pub(crate) dead_letters: DeadLetterConfig,
+    /// Comparison of stored runs against earlier ones of the client and fleet, `None` disables it
+    pub(crate) anomaly_detection: Option<AnomalyDetectionConfig>,
+    /// NATS server events of sessions are published to, `None` doesn't publish them
+    #[cfg(feature = "event-bus")]
+    pub(crate) event_bus: Option<EventBusConfig>,
+    /// Key certificates of stored runs are signed with, `None` disables certificates
+    pub(crate) certificate: Option<CertificateConfig>,
+    /// Single sign-on provider whose tokens authenticate requests to the API, `None` only accepts API keys
+    pub(crate) oidc: Option<OidcConfig>,
+    /// Credentials clients need to be measured, `None` measures anyone
+    pub(crate) measurement_auth: Option<MeasurementAuthConfig>,
+    /// Approximate ceiling of memory a single session may hold (challenge payloads,
+    /// buffered responses), also caps size of a single client message. `None` means no ceiling.
+    pub(crate) session_memory_limit_bytes: Option<usize>,
+    /// Cap of concurrent sessions and queue of connections waiting for a slot, `None` admits every session
+    pub(crate) session_limit: Option<SessionLimitConfig>,
+    /// Sessions a single client IP may start, `None` doesn't limit them
+    pub(crate) rate_limit: Option<RateLimitConfig>,
+    /// Limits of resident memory of the whole server, `None` disables the watchdog
+    pub(crate) memory_watchdog: Option<MemoryWatchdogConfig>,
+    /// Benchmark of the host on startup, which caps payloads and concurrent transfers to what it
+    /// can serve
+    pub(crate) host_sizing: HostSizingConfig,
+    /// Number of disk challenge rounds of every session, zero disables the disk challenge.
+    /// Should only be enabled when all clients have persistent storage, e.g. validator hardware.
+    pub(crate) disk_challenge_rounds: usize,
+    /// Challenge parameters and scoring thresholds of sessions at `/ws`
+    pub(crate) plan: PlanConfig,
+    /// Further measurement endpoints, each served at `/ws/<name>` with a plan of its own
+    pub(crate) endpoints: HashMap<String, EndpointConfig>,
+    /// Periodic retuning of the plan's thresholds from percentiles of recent runs, `None` keeps them as configured
+    pub(crate) threshold_tuning: Option<ThresholdTuningConfig>,
+    /// Backend measured runs are stored in, in memory by default
+    pub(crate) storage: StorageConfig,
+    /// Latency and failures injected into every storage call, for development only. `None`
+    /// leaves storage alone.
+    pub(crate) storage_chaos: Option<StorageChaosConfig>,
+    /// Generator of the secret primes of CPU challenges
+    pub(crate) primes: PrimesConfig,
+    /// Time sessions in progress are given to finish on SIGTERM or SIGINT, before server exits anyway
+    #[serde(
+        rename = "shutdown_deadline_milliseconds",
+        deserialize_with = "milliseconds"
+    )]
+    pub(crate) shutdown_deadline: Duration,
+    /// Time between ticks of the clock stream at `/time`
+    #[serde(
+        rename = "time_tick_interval_milliseconds",
+        deserialize_with = "milliseconds"
+    )]
+    pub(crate) time_tick_interval: Duration,
+    /// Time between pings of websocket sessions, zero disables them
+    #[serde(
+        rename = "keepalive_interval_milliseconds",
+        deserialize_with = "milliseconds"
+    )]
+    pub(crate) keepalive_interval: Duration,
+    /// Time a ping may go unanswered before the connection is considered lost
+    #[serde(
+        rename = "keepalive_timeout_milliseconds",
+        deserialize_with = "milliseconds"
+    )]
+    pub(crate) keepalive_timeout: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind_address: ([0, 0, 0, 0], 8080).into(),
+            base_path: Default::default(),
+            tls: None,
+            tcp_bind_address: None,
+            udp_bind_address: None,
+            verification_threads: None,
+            priority_classes: Default::default(),
+            default_priority_class: Default::default(),
+            private_tenants: Default::default(),
+            reader_api_keys: Default::default(),
+            admin_api_keys: Default::default(),
+            tenants: Default::default(),
+            strictness_modes: Default::default(),
+            default_strictness: Default::default(),
+            fleets: Default::default(),
+            policy_hook: None,
+            dead_letters: Default::default(),
+            anomaly_detection: None,
+            #[cfg(feature = "event-bus")]
+            event_bus: None,
+            certificate: None,
+            oidc: None,
+            measurement_auth: None,
+            session_memory_limit_bytes: None,
+            session_limit: None,
+            rate_limit: None,
+            memory_watchdog: None,
+            host_sizing: Default::default(),
+            disk_challenge_rounds: 0,
+            plan: Default::default(),
+            endpoints: Default::default(),
+            threshold_tuning: None,
+            storage: Default::default(),
+            storage_chaos: None,
+            primes: Default::default(),
+            shutdown_deadline: Duration::from_secs(30),
+            time_tick_interval: Duration::from_secs(1),
+            keepalive_interval: Duration::from_secs(15),
+            keepalive_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Measurement endpoint for a use case of its own, e.g. `/ws/quick` with few short rounds next to
+/// `/ws/thorough` with many long ones. Clients pick the plan by the URL they connect to.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EndpointConfig {
+    /// Plan sessions of the endpoint are measured with, its thresholds are never retuned
+    pub(crate) plan: PlanConfig,
+    /// Cap of concurrent sessions of the endpoint, which then don't count towards the server
+    /// wide `session_limit`. `None` shares the server wide one.
+    pub(crate) session_limit: Option<SessionLimitConfig>,
+}
+
+/// Team sharing the server. Runs of its clients are stored in a namespace of their own, which
+/// is only served to requests with one of its API keys. Private runs are only served to the key
+/// they were measured with and to reader keys of the tenant.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TenantConfig {
+    /// Keys clients of the tenant send in `x-api-key` header or `api_key` query parameter
+    pub(crate) api_keys: HashSet<String>,
+    /// Keys which are served private runs of all clients of the tenant, not to be handed to
+    /// clients
+    pub(crate) reader_api_keys: HashSet<String>,
+    /// Plan clients of the tenant are measured with at `/ws`, never retuned. `None` measures them
+    /// with the server's plan. Endpoints at `/ws/<name>` keep their own plan.
+    pub(crate) plan: Option<PlanConfig>,
+}
+
+/// Parameters of the challenges and thresholds they are scored with.
+/// A round at or below `*_ideal_milliseconds` gets no penalty, a round above
+/// `*_max_milliseconds` scores the whole session 0.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PlanConfig {
+    /// Name runs measured with this plan are stored under
+    pub(crate) name: String,
+    pub(crate) cpu_rounds: usize,
+    pub(crate) squarings: u32,
+    /// Puzzles of all CPU rounds of a session share a modulus and their answers are verified
+    /// with a single check after the last round, instead of after each round. Cheaper to verify,
+    /// but a wrong answer is only noticed once all CPU rounds are done. Answers of a failed check
+    /// are verified one by one to tell which rounds failed.
+    pub(crate) batch_cpu_verification: bool,
+    pub(crate) cpu_ideal_milliseconds: u64,
+    pub(crate) cpu_max_milliseconds: u64,
+    /// How times of CPU rounds are aggregated before they are scored, mean by default
+    pub(crate) cpu_aggregation: Aggregation,
+    /// Rate in squarings per second at or above which a CPU round gets no penalty. Set along with
+    /// `cpu_min_squarings_per_second` to score CPU rounds on their rate, thresholds in milliseconds
+    /// are then derived from `squarings` and the ones above are ignored. Scores don't depend on
+    /// `squarings` then, so they stay comparable across plans.
+    pub(crate) cpu_ideal_squarings_per_second: Option<f64>,
+    /// Rate below which a CPU round scores the whole session 0
+    pub(crate) cpu_min_squarings_per_second: Option<f64>,
+    /// Time client has to answer a CPU challenge before the session is aborted,
+    /// `cpu_max_milliseconds` if not set
+    pub(crate) cpu_round_timeout_milliseconds: Option<u64>,
+    /// Time CPU rounds should take. When set, CPU rounds are preceded by a calibration round of
+    /// `cpu_calibration_squarings` and squarings of the session are scaled from its time to take
+    /// about this long, so that fast machines are measured over a long enough round to be told
+    /// apart. Round times are normalized to `squarings` before they are scored.
+    pub(crate) cpu_target_milliseconds: Option<u64>,
+    /// Should be large enough for solving to dominate the round trip of the calibration round
+    pub(crate) cpu_calibration_squarings: u32,
+    /// Small CPU challenges and latency probes sent unannounced at random points between the
+    /// CPU and network rounds, so that clients which only burst their resources when they expect
+    /// to be measured stand out. Recorded with the run, they don't affect the score.
+    pub(crate) surprise_rounds: usize,
+    /// Squarings of surprise CPU rounds
+    pub(crate) surprise_squarings: u32,
+    pub(crate) network_rounds: usize,
+    /// Payload of network challenges, may be scaled down under memory pressure
+    pub(crate) payload_size_kb: usize,
+    pub(crate) network_ideal_milliseconds: u64,
+    pub(crate) network_max_milliseconds: u64,
+    pub(crate) network_aggregation: Aggregation,
+    /// Throughput in megabits per second at or above which a network round gets no penalty.
+    /// Set along with `network_min_mbps` to score network rounds on throughput, thresholds in
+    /// milliseconds are then derived from the payload size and the ones above are ignored.
+    pub(crate) network_ideal_mbps: Option<f64>,
+    /// Throughput below which a network round scores the whole session 0
+    pub(crate) network_min_mbps: Option<f64>,
+    /// Network thresholds of clients declaring a network type in the handshake, keyed by its
+    /// name, e.g. `cellular`. Clients of other types and ones which declare none are scored
+    /// with the thresholds above.
+    pub(crate) network_types: HashMap<String, NetworkTypeThresholds>,
+    /// Time client has to return a network challenge payload before the session is aborted,
+    /// `network_max_milliseconds` if not set
+    pub(crate) network_round_timeout_milliseconds: Option<u64>,
+    /// Divide time of each network round by one plus the number of transfers of other sessions
+    /// which overlapped it, as if they shared uplink of the server evenly for the whole round.
+    /// Only fits servers whose uplink, not links of clients, is the bottleneck.
+    pub(crate) network_contention_correction: bool,
+    /// Verify network rounds by comparing this many chunks of the returned payload, drawn at
+    /// random and weighted by their length, along with the merkle root of the payload the client
+    /// sends, instead of hashing all of it. Keeps a copy of each payload to compare with, so it
+    /// trades server memory for CPU; fits plans with large payloads. Clients can't tell which
+    /// chunks are checked, so a corrupted payload is caught the more likely the more it differs.
+    pub(crate) spot_check_sample_chunks: Option<usize>,
+    /// Largest websocket frame network, download and upload challenges are sent in, larger ones
+    /// are split into chunks for clients which reassemble them. Useful behind proxies which
+    /// refuse large frames. Frames aren't limited if not set.
+    pub(crate) max_frame_size_kb: Option<usize>,
+    /// Stream bulk transfers in chunks of this size, if smaller than `max_frame_size_kb`, so that
+    /// profile of network rounds over time is recorded. Frames aren't limited if not set.
+    pub(crate) network_profile_chunk_kb: Option<usize>,
+    /// Gap between consecutive chunks of a network round counted as a stall of the link
+    pub(crate) network_stall_milliseconds: u64,
+    /// Probes of the latency measurement which follows network rounds, each has to come back
+    /// within the network round timeout. Reported along with the score but not part of it.
+    pub(crate) latency_probes: usize,
+    /// Rounds measuring each direction of the link on its own, with payloads of `payload_size_kb`
+    /// and the network round timeout. Reported along with the score but not part of it.
+    pub(crate) download_rounds: usize,
+    pub(crate) upload_rounds: usize,
+    /// Probes sent over UDP after latency probes, which unlike the session's connection doesn't
+    /// hide lost packets behind retransmissions. Requires `udp_bind_address`, clients which can't
+    /// send datagrams skip them. Loss is reported along with the score but not part of it.
+    pub(crate) datagram_probes: usize,
+    /// Time between consecutive datagram probes
+    pub(crate) datagram_interval_milliseconds: u32,
+    pub(crate) disk_payload_size_kb: usize,
+    pub(crate) disk_ideal_milliseconds: u64,
+    pub(crate) disk_max_milliseconds: u64,
+    pub(crate) disk_aggregation: Aggregation,
+    /// Weights sub-scores of challenge kinds are combined with, all kinds count the same by default
+    pub(crate) scoring: ScoringModel,
+    /// Retries a session may spend on CPU, network, download, upload and disk rounds which failed
+    /// without breaking the connection: client didn't answer in time, answered with an error or
+    /// with a message which can't be decoded. Sessions fail on such rounds if not set.
+    pub(crate) round_retries: usize,
+    /// Points each retry takes off the score
+    pub(crate) retry_penalty: f64,
+    /// Store runs of sessions which fail after completing some rounds, e.g. at network round 7
+    /// of 10, with the rounds they completed and where they failed in `status`. Runs of failed
+    /// sessions aren't stored if not set.
+    pub(crate) persist_partial_runs: bool,
+    /// Points each CPU, network and disk round a partial run didn't complete takes off the score
+    /// of the rounds it did. Partial runs are scored 0 if not set.
+    pub(crate) partial_run_penalty: Option<f64>,
+    /// Refuse clients which don't bind nonces into their responses, which could answer challenges
+    /// with payloads they have seen before from a cache. Clients which bind them are always sent
+    /// bound challenges.
+    pub(crate) require_nonces: bool,
+    /// Offer xxHash64 for hashing payloads of network, download and disk rounds too, ahead of
+    /// BLAKE3 and SHA-256, as it costs server the least CPU. It isn't a cryptographic hash, so
+    /// a client could answer without the data it was sent; only fit for deployments whose
+    /// clients are trusted not to cheat.
+    pub(crate) offer_xxhash64: bool,
+    /// Whether messages of sessions are authenticated with a key agreed per session, for
+    /// deployments whose transport doesn't protect them, e.g. plain TCP or TLS terminated
+    /// upstream. Browsers don't take it up, so `required` is only fit for native clients.
+    pub(crate) message_authentication: MessageAuthentication,
+    /// Speed of the fastest client the plan is meant for. A CPU round which would take even it
+    /// longer than the round timeout can never be answered, so such plans are refused. A round
+    /// answered faster than it could have scores the run 0.
+    pub(crate) fastest_squarings_per_second: u64,
+    /// Throughput of the fastest link the plan is meant for, in kilobytes per second each way.
+    /// Network rounds which would take even it longer than the round timeout are refused, ones
+    /// answered faster than it could have score the run 0.
+    pub(crate) fastest_throughput_kb_per_second: u64,
+}
+
+impl Default for PlanConfig {
+    fn default() -> Self {
+        PlanConfig {
+            name: "default".to_owned(),
+            cpu_rounds: 5,
+            squarings: 200000,
+            batch_cpu_verification: false,
+            cpu_ideal_milliseconds: 4500,
+            cpu_max_milliseconds: 120000,
+            cpu_aggregation: Aggregation::Mean,
+            cpu_ideal_squarings_per_second: None,
+            cpu_min_squarings_per_second: None,
+            cpu_round_timeout_milliseconds: None,
+            cpu_target_milliseconds: None,
+            cpu_calibration_squarings: 20000,
+            surprise_rounds: 0,
+            surprise_squarings: 20000,
+            network_rounds: 10,
+            payload_size_kb: 1024,
+            network_ideal_milliseconds: 200,
+            network_max_milliseconds: 25000,
+            network_aggregation: Aggregation::Mean,
+            network_ideal_mbps: None,
+            network_min_mbps: None,
+            network_types: HashMap::new(),
+            network_round_timeout_milliseconds: None,
+            network_contention_correction: false,
+            spot_check_sample_chunks: None,
+            max_frame_size_kb: None,
+            network_profile_chunk_kb: None,
+            network_stall_milliseconds: 1000,
+            latency_probes: 0,
+            download_rounds: 0,
+            upload_rounds: 0,
+            datagram_probes: 0,
+            datagram_interval_milliseconds: 10,
+            disk_payload_size_kb: 4096,
+            disk_ideal_milliseconds: 50,
+            disk_max_milliseconds: 5000,
+            disk_aggregation: Aggregation::Mean,
+            scoring: ScoringModel::default(),
+            round_retries: 0,
+            retry_penalty: 5.0,
+            persist_partial_runs: false,
+            partial_run_penalty: None,
+            require_nonces: false,
+            offer_xxhash64: false,
+            message_authentication: MessageAuthentication::Off,
+            fastest_squarings_per_second: 2_000_000,
+            // 1 Gbit/s
+            fastest_throughput_kb_per_second: 125_000,
+        }
+    }
+}
+
+/// Network thresholds of a plan for clients on one network type, unset ones are the plan's.
+/// Throughput thresholds of the plan take precedence over times set here, like they do over
+/// its own.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct NetworkTypeThresholds {
+    network_ideal_milliseconds: Option<u64>,
+    network_max_milliseconds: Option<u64>,
+    network_ideal_mbps: Option<f64>,
+    network_min_mbps: Option<f64>,
+    network_round_timeout_milliseconds: Option<u64>,
+}
+
+/// Reasons sessions of a plan could never finish
+#[derive(Debug, PartialEq)]
+pub(crate) enum PlanError {
+    /// Fastest client would need more than the round timeout to solve a CPU round
+    CpuRoundTooLong {
+        squarings: u32,
+        fastest_milliseconds: u64,
+        timeout_milliseconds: u64,
+    },
+    /// CPU rounds are calibrated to take longer than their timeout
+    CpuTargetTooLong {
+        target_milliseconds: u64,
+        timeout_milliseconds: u64,
+    },
+    /// Fastest link would need more than the round timeout to send a network payload and receive it back
+    NetworkRoundTooLong {
+        payload_size_kb: usize,
+        fastest_milliseconds: u64,
+        timeout_milliseconds: u64,
+    },
+    /// Speed of the fastest client or link is zero
+    ZeroSpeed,
+    /// Frames are limited to zero kilobytes, so no chunk fits them
+    ZeroFrameSize,
+    /// Network rounds are spot checked without comparing any chunk
+    ZeroSampleChunks,
+    /// Only one of the throughput thresholds is set, one isn't positive or ideal is below the minimum
+    InvalidThroughput {
+        ideal_mbps: Option<f64>,
+        min_mbps: Option<f64>,
+    },
+    /// Only one of the squarings rate thresholds is set, one isn't positive or ideal is below the minimum
+    InvalidSquaringsRate {
+        ideal_squarings_per_second: Option<f64>,
+        min_squarings_per_second: Option<f64>,
+    },
+    /// Thresholds are set for a network type clients can't declare
+    UnknownNetworkType { name: String },
+    /// Thresholds of a network type make the plan invalid for clients of that type
+    InvalidNetworkType { name: String, error: Box<PlanError> },
+}
+
+impl Display for PlanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlanError::CpuRoundTooLong {
+                squarings,
+                fastest_milliseconds,
+                timeout_milliseconds,
+            } => write!(
+                f,
+                "CPU round of {} squarings takes the fastest client {}ms, longer than its timeout of {}ms",
+                squarings, fastest_milliseconds, timeout_milliseconds
+            ),
+            PlanError::CpuTargetTooLong {
+                target_milliseconds,
+                timeout_milliseconds,
+            } => write!(
+                f,
+                "CPU target of {}ms is longer than CPU round timeout of {}ms",
+                target_milliseconds, timeout_milliseconds
+            ),
+            PlanError::NetworkRoundTooLong {
+                payload_size_kb,
+                fastest_milliseconds,
+                timeout_milliseconds,
+            } => write!(
+                f,
+                "Network round of {}KB takes the fastest link {}ms, longer than its timeout of {}ms",
+                payload_size_kb, fastest_milliseconds, timeout_milliseconds
+            ),
+            PlanError::ZeroSpeed => write!(f, "Speed of the fastest client can't be zero"),
+            PlanError::ZeroFrameSize => write!(f, "Max frame size can't be zero"),
+            PlanError::ZeroSampleChunks => write!(f, "Spot check sample chunks can't be zero"),
+            PlanError::InvalidThroughput {
+                ideal_mbps,
+                min_mbps,
+            } => write!(
+                f,
+                "Network throughput thresholds must be set together, be positive and ideal must not be \
+                 below min, got ideal {:?} and min {:?} Mbps",
+                ideal_mbps, min_mbps
+            ),
+            PlanError::InvalidSquaringsRate {
+                ideal_squarings_per_second,
+                min_squarings_per_second,
+            } => write!(
+                f,
+                "CPU squarings rate thresholds must be set together, be positive and ideal must not \
+                 be below min, got ideal {:?} and min {:?} squarings per second",
+                ideal_squarings_per_second, min_squarings_per_second
+            ),
+            PlanError::UnknownNetworkType { name } => write!(
+                f,
+                "Unknown network type `{}`, expected wifi, ethernet or cellular",
+                name
+            ),
+            PlanError::InvalidNetworkType { name, error } => {
+                write!(f, "Thresholds of network type {}: {}", name, error)
+            }
+        }
+    }
+}
+
+impl Error for PlanError {}
+
+impl PlanConfig {
+    /// Time client has to answer a CPU challenge
+    pub(crate) fn cpu_round_timeout(&self) -> Duration {
+        let (_, max_milliseconds) = self.cpu_thresholds();
+        Duration::from_millis(
+            self.cpu_round_timeout_milliseconds
+                .unwrap_or(max_milliseconds as u64),
+        )
+    }
+
+    /// Ideal and max time of CPU rounds of `squarings`
+    pub(crate) fn cpu_thresholds(&self) -> (u128, u128) {
+        match (
+            self.cpu_ideal_squarings_per_second,
+            self.cpu_min_squarings_per_second,
+        ) {
+            (Some(ideal_rate), Some(min_rate)) => (
+                cpu_round_milliseconds(self.squarings, ideal_rate),
+                cpu_round_milliseconds(self.squarings, min_rate),
+            ),
+            _ => (
+                self.cpu_ideal_milliseconds.into(),
+                self.cpu_max_milliseconds.into(),
+            ),
+        }
+    }
+
+    /// Quantity CPU rounds are scored on
+    pub(crate) fn cpu_scale(&self) -> Scale {
+        match (
+            self.cpu_ideal_squarings_per_second,
+            self.cpu_min_squarings_per_second,
+        ) {
+            (Some(_), Some(_)) => Scale::Throughput,
+            _ => Scale::Time,
+        }
+    }
+
+    /// Time client has to return a network challenge payload
+    pub(crate) fn network_round_timeout(&self) -> Duration {
+        let (_, max_milliseconds) = self.network_thresholds(self.payload_size_kb);
+        Duration::from_millis(
+            self.network_round_timeout_milliseconds
+                .unwrap_or(max_milliseconds as u64),
+        )
+    }
+
+    /// Ideal and max time of network rounds of `payload_size_kb`
+    pub(crate) fn network_thresholds(&self, payload_size_kb: usize) -> (u128, u128) {
+        match (self.network_ideal_mbps, self.network_min_mbps) {
+            (Some(ideal_mbps), Some(min_mbps)) => (
+                network_round_milliseconds(payload_size_kb, ideal_mbps),
+                network_round_milliseconds(payload_size_kb, min_mbps),
+            ),
+            _ => (
+                self.network_ideal_milliseconds.into(),
+                self.network_max_milliseconds.into(),
+            ),
+        }
+    }
+
+    /// Quantity network rounds are scored on
+    pub(crate) fn network_scale(&self) -> Scale {
+        match (self.network_ideal_mbps, self.network_min_mbps) {
+            (Some(_), Some(_)) => Scale::Throughput,
+            _ => Scale::Time,
+        }
+    }
+
+    /// Plan runs of clients which declared `network_type` are scored with, which has the
+    /// network thresholds of that type if the plan sets any
+    pub(crate) fn for_network_type(&self, network_type: NetworkType) -> PlanConfig {
+        let thresholds = match self.network_types.get(network_type.name()) {
+            Some(thresholds) => thresholds,
+            None => return self.clone(),
+        };
+        let (network_ideal_mbps, network_min_mbps) =
+            match (thresholds.network_ideal_mbps, thresholds.network_min_mbps) {
+                (None, None) => (self.network_ideal_mbps, self.network_min_mbps),
+                rates => rates,
+            };
+        PlanConfig {
+            network_ideal_milliseconds: thresholds
+                .network_ideal_milliseconds
+                .unwrap_or(self.network_ideal_milliseconds),
+            network_max_milliseconds: thresholds
+                .network_max_milliseconds
+                .unwrap_or(self.network_max_milliseconds),
+            network_ideal_mbps,
+            network_min_mbps,
+            network_round_timeout_milliseconds: thresholds
+                .network_round_timeout_milliseconds
+                .or(self.network_round_timeout_milliseconds),
+            network_types: HashMap::new(),
+            ..self.clone()
+        }
+    }
+
+    /// Largest frame bulk transfers are sent in to clients which reassemble chunks
+    pub(crate) fn frame_size_kb(&self) -> Option<usize> {
+        match (self.max_frame_size_kb, self.network_profile_chunk_kb) {
+            (Some(max_frame_size_kb), Some(chunk_kb)) => Some(max_frame_size_kb.min(chunk_kb)),
+            (max_frame_size_kb, chunk_kb) => max_frame_size_kb.or(chunk_kb),
+        }
+    }
+
+    pub(crate) fn plausibility_bounds(&self) -> PlausibilityBounds {
+        PlausibilityBounds {
+            fastest_squarings_per_second: self.fastest_squarings_per_second,
+            fastest_throughput_kb_per_second: self.fastest_throughput_kb_per_second,
+        }
+    }
+
+    /// Checks that the fastest client the plan is meant for can finish every round within its timeout
+    pub(crate) fn validate(&self) -> Result<(), PlanError> {
+        if self.fastest_squarings_per_second == 0 || self.fastest_throughput_kb_per_second == 0 {
+            return Err(PlanError::ZeroSpeed);
+        }
+        if self.frame_size_kb() == Some(0) {
+            return Err(PlanError::ZeroFrameSize);
+        }
+        if self.spot_check_sample_chunks == Some(0) {
+            return Err(PlanError::ZeroSampleChunks);
+        }
+        if !rate_thresholds_valid(self.network_ideal_mbps, self.network_min_mbps) {
+            return Err(PlanError::InvalidThroughput {
+                ideal_mbps: self.network_ideal_mbps,
+                min_mbps: self.network_min_mbps,
+            });
+        }
+        if !rate_thresholds_valid(
+            self.cpu_ideal_squarings_per_second,
+            self.cpu_min_squarings_per_second,
+        ) {
+            return Err(PlanError::InvalidSquaringsRate {
+                ideal_squarings_per_second: self.cpu_ideal_squarings_per_second,
+                min_squarings_per_second: self.cpu_min_squarings_per_second,
+            });
+        }
+        let cpu_timeout_milliseconds = self.cpu_round_timeout().as_millis() as u64;
+        if self.cpu_rounds > 0 {
+            let squarings = match self.cpu_target_milliseconds {
+                Some(target_milliseconds) if target_milliseconds > cpu_timeout_milliseconds => {
+                    return Err(PlanError::CpuTargetTooLong {
+                        target_milliseconds,
+                        timeout_milliseconds: cpu_timeout_milliseconds,
+                    })
+                }
+                // Rounds are scaled to the target, only the calibration round has fixed squarings
+                Some(_) => self.cpu_calibration_squarings,
+                None => self.squarings,
+            };
+            let fastest_milliseconds = self.plausibility_bounds().cpu_milliseconds(squarings);
+            if fastest_milliseconds > cpu_timeout_milliseconds {
+                return Err(PlanError::CpuRoundTooLong {
+                    squarings,
+                    fastest_milliseconds,
+                    timeout_milliseconds: cpu_timeout_milliseconds,
+                });
+            }
+        }
+        if self.surprise_rounds > 0 {
+            let fastest_milliseconds = self
+                .plausibility_bounds()
+                .cpu_milliseconds(self.surprise_squarings);
+            if fastest_milliseconds > cpu_timeout_milliseconds {
+                return Err(PlanError::CpuRoundTooLong {
+                    squarings: self.surprise_squarings,
+                    fastest_milliseconds,
+                    timeout_milliseconds: cpu_timeout_milliseconds,
+                });
+            }
+        }
+        if self.network_rounds + self.download_rounds + self.upload_rounds > 0 {
+            let network_timeout_milliseconds = self.network_round_timeout().as_millis() as u64;
+            let fastest_milliseconds = self
+                .plausibility_bounds()
+                .network_milliseconds(self.payload_size_kb);
+            if fastest_milliseconds > network_timeout_milliseconds {
+                return Err(PlanError::NetworkRoundTooLong {
+                    payload_size_kb: self.payload_size_kb,
+                    fastest_milliseconds,
+                    timeout_milliseconds: network_timeout_milliseconds,
+                });
+            }
+        }
+        for name in self.network_types.keys() {
+            let network_type = NetworkType::from_name(name)
+                .ok_or_else(|| PlanError::UnknownNetworkType { name: name.clone() })?;
+            self.for_network_type(network_type)
+                .validate()
+                .map_err(|error| PlanError::InvalidNetworkType {
+                    name: name.clone(),
+                    error: Box::new(error),
+                })?;
+        }
+        Ok(())
+    }
+}
+
+/// Rates are either both unset, or both set with a positive minimum the ideal isn't below
+fn rate_thresholds_valid(ideal: Option<f64>, min: Option<f64>) -> bool {
+    match (ideal, min) {
+        (None, None) => true,
+        (Some(ideal), Some(min)) => min > 0.0 && ideal >= min,
+        _ => false,
+    }
+}
+
+/// Command line flags, each can also be set by an environment variable.
+/// Flags override values of the configuration file.
+#[derive(Debug, Default, StructOpt)]
+#[structopt(name = "server", about = "Client reliability measurement server")]
+pub struct Args {
+    /// TOML configuration file, see `ServerConfig` for its keys
+    #[structopt(long, env = "RELIABILITY_CONFIG", parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// Address to listen on, e.g. `0.0.0.0:8080`
+    #[structopt(long, env = "RELIABILITY_BIND_ADDRESS")]
+    bind_address: Option<SocketAddr>,
+    /// Prefix all routes are mounted under, e.g. `/reliability/v1`
+    #[structopt(long, env = "RELIABILITY_BASE_PATH")]
+    base_path: Option<String>,
+    /// Address to measure clients at over plain TCP, e.g. `0.0.0.0:8090`
+    #[structopt(long, env = "RELIABILITY_TCP_BIND_ADDRESS")]
+    tcp_bind_address: Option<SocketAddr>,
+    /// UDP address to send datagram probes from, e.g. `0.0.0.0:8091`
+    #[structopt(long, env = "RELIABILITY_UDP_BIND_ADDRESS")]
+    udp_bind_address: Option<SocketAddr>,
+    /// PEM certificate chain to serve over TLS with, requires `--tls-key`
+    #[structopt(
+        long,
+        env = "RELIABILITY_TLS_CERT",
+        parse(from_os_str),
+        requires = "tls-key"
+    )]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key of `--tls-cert`
+    #[structopt(
+        long,
+        env = "RELIABILITY_TLS_KEY",
+        parse(from_os_str),
+        requires = "tls-cert"
+    )]
+    tls_key: Option<PathBuf>,
+    #[structopt(long, env = "RELIABILITY_VERIFICATION_THREADS")]
+    verification_threads: Option<usize>,
+    #[structopt(long, env = "RELIABILITY_DISK_CHALLENGE_ROUNDS")]
+    disk_challenge_rounds: Option<usize>,
+    #[structopt(long, env = "RELIABILITY_CPU_ROUNDS")]
+    cpu_rounds: Option<usize>,
+    #[structopt(long, env = "RELIABILITY_SQUARINGS")]
+    squarings: Option<u32>,
+    #[structopt(long, env = "RELIABILITY_NETWORK_ROUNDS")]
+    network_rounds: Option<usize>,
+    #[structopt(long, env = "RELIABILITY_PAYLOAD_SIZE_KB")]
+    payload_size_kb: Option<usize>,
+    #[structopt(subcommand)]
+    pub(crate) command: Option<Command>,
+}
+
+/// Runs instead of serving, with the configuration loaded the same way
+#[derive(Debug, StructOpt)]
+pub(crate) enum Command {
+    /// Prints expected round times, session length and score of the configured plan on the given
+    /// hardware, as JSON. The plan isn't validated, so values refused on start can be tried.
+    Estimate(HardwareProfile),
+}
+
+impl ServerConfig {
+    pub(crate) fn priority_class(&self, api_key: Option<&str>) -> PriorityClass {
+        api_key
+            .and_then(|api_key| self.priority_classes.get(api_key))
+            .copied()
+            .unwrap_or(self.default_priority_class)
+    }
+
+    pub(crate) fn strictness(&self, api_key: Option<&str>) -> Strictness {
+        api_key
+            .and_then(|api_key| self.strictness_modes.get(api_key))
+            .copied()
+            .unwrap_or(self.default_strictness)
+    }
+
+    /// Name and configuration of the tenant `api_key` belongs to, `None` if it belongs to none
+    pub(crate) fn tenant(&self, api_key: Option<&str>) -> Option<(&str, &TenantConfig)> {
+        let api_key = api_key?;
+        self.tenants
+            .iter()
+            .find(|(_, tenant)| {
+                tenant.api_keys.contains(api_key) || tenant.reader_api_keys.contains(api_key)
+            })
+            .map(|(name, tenant)| (name.as_str(), tenant))
+    }
+
+    /// Namespace requests with `api_key` are confined to, `None` if it belongs to no tenant
+    pub(crate) fn tenant_scope(&self, api_key: Option<&str>) -> Option<TenantScope> {
+        let (name, tenant) = self.tenant(api_key)?;
+        let api_key = api_key?;
+        Some(TenantScope {
+            name: name.to_owned(),
+            api_key_digest: (!tenant.reader_api_keys.contains(api_key))
+                .then(|| api_key_digest(api_key)),
+        })
+    }
+
+    /// Runs served to a request. API keys listed in `reader_api_keys` unlock private runs, unless
+    /// they belong to a tenant, whose requests are only served runs of the tenant.
+    pub(crate) fn visibility(&self, api_key: Option<&str>) -> Visibility {
+        match api_key {
+            Some(api_key)
+                if self.reader_api_keys.contains(api_key)
+                    && self.tenant(Some(api_key)).is_none() =>
+            {
+                Visibility::All
+            }
+            _ => Visibility::Public,
+        }
+    }
+
+    /// Whether `api_key` may use `/admin` routes, keys of tenants never do
+    pub(crate) fn is_admin(&self, api_key: Option<&str>) -> bool {
+        api_key.is_some_and(|api_key| {
+            self.admin_api_keys.contains(api_key) && self.tenant(Some(api_key)).is_none()
+        })
+    }
+
+    /// Pings of websocket sessions, `None` if they are disabled
+    pub(crate) fn keepalive(&self) -> Option<Keepalive> {
+        if self.keepalive_interval.is_zero() {
+            None
+        } else {
+            Some(Keepalive {
+                interval: self.keepalive_interval,
+                timeout: self.keepalive_timeout,
+            })
+        }
+    }
+
+    pub fn from_toml(toml: &str) -> Result<Self> {
+        toml::from_str(toml).map_err(|e| anyhow!("Invalid configuration: {}", e))
+    }
+
+    /// Reads the configuration file given in `args`, if any, applies flags on top of it and validates the plans
+    pub fn load(args: Args) -> Result<Self> {
+        let config = Self::read(args)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks the plan and the endpoints, names of endpoints have to be usable as a path segment
+    pub fn validate(&self) -> Result<()> {
+        if self.time_tick_interval.is_zero() {
+            return Err(anyhow!("Time tick interval must be positive"));
+        }
+        if self.keepalive().is_some() && self.keepalive_timeout.is_zero() {
+            return Err(anyhow!("Keepalive timeout must be positive"));
+        }
+        self.plan
+            .validate()
+            .map_err(|e| anyhow!("Invalid plan: {}", e))?;
+        for (name, endpoint) in &self.endpoints {
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                return Err(anyhow!(
+                    "Invalid endpoint name {:?}, only letters, digits, '-' and '_' are allowed",
+                    name
+                ));
+            }
+            endpoint
+                .plan
+                .validate()
+                .map_err(|e| anyhow!("Invalid plan of endpoint {}: {}", name, e))?;
+        }
+        let mut api_keys = HashSet::new();
+        for (name, tenant) in &self.tenants {
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                return Err(anyhow!(
+                    "Invalid tenant name {:?}, only letters, digits, '-' and '_' are allowed",
+                    name
+                ));
+            }
+            if tenant.api_keys.is_empty() {
+                return Err(anyhow!("Tenant {} has no API keys", name));
+            }
+            if tenant
+                .api_keys
+                .iter()
+                .chain(&tenant.reader_api_keys)
+                .any(|api_key| !api_keys.insert(api_key))
+            {
+                return Err(anyhow!(
+                    "Tenant {} lists an API key twice or shares it with another tenant",
+                    name
+                ));
+            }
+            if let Some(plan) = &tenant.plan {
+                plan.validate()
+                    .map_err(|e| anyhow!("Invalid plan of tenant {}: {}", name, e))?;
+            }
+        }
+        if let Some((role, tenant)) = self.oidc.iter().find_map(|oidc| {
+            oidc.tenants
+                .iter()
+                .find(|(_, tenant)| !self.tenants.contains_key(*tenant))
+        }) {
+            return Err(anyhow!(
+                "OIDC role {} is mapped to unknown tenant {}",
+                role,
+                tenant
+            ));
+        }
+        Ok(())
+    }
+
+    /// Reads the configuration file given in `args`, if any, and applies flags on top of it
+    pub fn read(args: Args) -> Result<Self> {
+        let mut config = match &args.config {
+            Some(path) => Self::from_toml(
+                &fs::read_to_string(path)
+                    .map_err(|e| anyhow!("Unable to read {}: {:?}", path.display(), e))?,
+            )?,
+            None => Self::default(),
+        };
+
+        if let Some(bind_address) = args.bind_address {
+            config.bind_address = bind_address;
+        }
+        if let Some(base_path) = args.base_path {
+            config.base_path = base_path;
+        }
+        if let Some(tcp_bind_address) = args.tcp_bind_address {
+            config.tcp_bind_address = Some(tcp_bind_address);
+        }
+        if let Some(udp_bind_address) = args.udp_bind_address {
+            config.udp_bind_address = Some(udp_bind_address);
+        }
+        if let (Some(cert_path), Some(key_path)) = (args.tls_cert, args.tls_key) {
+            config.tls = Some(TlsConfig {
+                cert_path,
+                key_path,
+                cleartext_bind_address: config
+                    .tls
+                    .as_ref()
+                    .and_then(|tls| tls.cleartext_bind_address),
+            });
+        }
+        if let Some(verification_threads) = args.verification_threads {
+            config.verification_threads = Some(verification_threads);
+        }
+        if let Some(disk_challenge_rounds) = args.disk_challenge_rounds {
+            config.disk_challenge_rounds = disk_challenge_rounds;
+        }
+        if let Some(cpu_rounds) = args.cpu_rounds {
+            config.plan.cpu_rounds = cpu_rounds;
+        }
+        if let Some(squarings) = args.squarings {
+            config.plan.squarings = squarings;
+        }
+        if let Some(network_rounds) = args.network_rounds {
+            config.plan.network_rounds = network_rounds;
+        }
+        if let Some(payload_size_kb) = args.payload_size_kb {
+            config.plan.payload_size_kb = payload_size_kb;
+        }
+        Ok(config)
+    }
+}
+
+/// Reads a `Duration` given in milliseconds
+pub(crate) fn milliseconds<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_millis)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::anomaly::AnomalyRule;
+    use crate::config::{Args, PlanConfig, PlanError, ServerConfig};
+    use crate::measurements::{Scale, Strictness};
+    use crate::storage::api_key_digest;
+    use crate::types::{PriorityClass, Visibility};
+    use shared::NetworkType;
+    use std::time::Duration;
+
+    #[test]
+    fn test_config_file() {
+        let config = ServerConfig::from_toml(
+            r#"
+            bind_address = "127.0.0.1:9000"
+            tcp_bind_address = "127.0.0.1:9001"
+            udp_bind_address = "127.0.0.1:9002"
+            disk_challenge_rounds = 2
+            admin_api_keys = ["0ps"]
+            keepalive_interval_milliseconds = 0
+            default_strictness = "lenient"
+
+            [priority_classes]
+            secret = "high"
+
+            [strictness_modes]
+            secret = "audit_only"
+
+            [memory_watchdog]
+            soft_limit_bytes = 100
+            hard_limit_bytes = 200
+            poll_interval_milliseconds = 500
+
+            [dead_letters]
+            path = "dead_letters.json"
+            retry_delay_milliseconds = 2000
+
+            [anomaly_detection]
+            rule = "iqr"
+            client_tag = "node"
+
+            [plan]
+            squarings = 1000
+            network_max_milliseconds = 30000
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.bind_address, ([127, 0, 0, 1], 9000).into());
+        assert_eq!(config.tcp_bind_address, Some(([127, 0, 0, 1], 9001).into()));
+        assert_eq!(config.udp_bind_address, Some(([127, 0, 0, 1], 9002).into()));
+        assert_eq!(config.disk_challenge_rounds, 2);
+        assert_eq!(config.keepalive(), None);
+        assert_eq!(config.priority_class(Some("secret")), PriorityClass::High);
+        assert!(config.is_admin(Some("0ps")));
+        assert!(!config.is_admin(Some("secret")));
+        assert_eq!(config.strictness(Some("secret")), Strictness::AuditOnly);
+        assert_eq!(config.strictness(None), Strictness::Lenient);
+        assert_eq!(
+            config.memory_watchdog.unwrap().poll_interval,
+            Duration::from_millis(500)
+        );
+        assert_eq!(config.dead_letters.retry_delay, Duration::from_secs(2));
+        assert_eq!(config.dead_letters.max_attempts, 8);
+        let anomaly_detection = config.anomaly_detection.unwrap();
+        assert_eq!(anomaly_detection.rule, AnomalyRule::Iqr);
+        assert_eq!(anomaly_detection.min_samples, 5);
+        assert_eq!(config.plan.squarings, 1000);
+        assert_eq!(config.plan.network_max_milliseconds, 30000);
+        // Keys missing from the file keep their defaults
+        assert_eq!(config.plan.cpu_rounds, 5);
+
+        assert!(ServerConfig::from_toml("bind_adress = \"127.0.0.1:9000\"").is_err());
+    }
+
+    #[test]
+    fn test_endpoints() {
+        let config = ServerConfig::from_toml(
+            r#"
+            [endpoints.quick.plan]
+            name = "quick"
+            cpu_rounds = 1
+
+            [endpoints.thorough.plan]
+            name = "thorough"
+            cpu_rounds = 20
+
+            [endpoints.thorough.session_limit]
+            max_sessions = 4
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.endpoints["quick"].plan.cpu_rounds, 1);
+        assert!(config.endpoints["quick"].session_limit.is_none());
+        assert_eq!(
+            config.endpoints["thorough"]
+                .session_limit
+                .as_ref()
+                .unwrap()
+                .max_sessions,
+            4
+        );
+        // Server wide plan is unaffected
+        assert_eq!(config.plan.cpu_rounds, 5);
+
+        let invalid_name = ServerConfig::from_toml("[endpoints.\"a/b\"]").unwrap();
+        assert!(invalid_name.validate().is_err());
+        let invalid_plan =
+            ServerConfig::from_toml("[endpoints.slow.plan]\nsquarings = 4294967295").unwrap();
+        let e = invalid_plan.validate().unwrap_err().to_string();
+        assert!(e.contains("endpoint slow"), "{}", e);
+    }
+
+    #[test]
+    fn test_tenants() {
+        let config = ServerConfig::from_toml(
+            r#"
+            private_tenants = ["k3y", "0ps"]
+            reader_api_keys = ["k3y", "r3ad"]
+            admin_api_keys = ["k3y", "0ps"]
+
+            [tenants.payments]
+            api_keys = ["k3y"]
+            reader_api_keys = ["p4y"]
+
+            [tenants.payments.plan]
+            name = "payments"
+            cpu_rounds = 1
+
+            [tenants.search]
+            api_keys = ["s3arch"]
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        let (name, tenant) = config.tenant(Some("k3y")).unwrap();
+        assert_eq!(name, "payments");
+        assert_eq!(tenant.plan.as_ref().unwrap().cpu_rounds, 1);
+        assert_eq!(config.tenant(Some("s3arch")).unwrap().0, "search");
+        assert!(config.tenant(Some("0ps")).is_none());
+        assert!(config.tenant(None).is_none());
+        // Clients of a tenant are only served their own private runs, readers all of them
+        let scope = config.tenant_scope(Some("k3y")).unwrap();
+        assert_eq!(scope.name, "payments");
+        assert_eq!(scope.api_key_digest, Some(api_key_digest("k3y")));
+        let scope = config.tenant_scope(Some("p4y")).unwrap();
+        assert_eq!(scope.name, "payments");
+        assert_eq!(scope.api_key_digest, None);
+        assert!(config.tenant_scope(Some("r3ad")).is_none());
+        // Keys of tenants never unlock runs of other tenants, keys clients are measured with
+        // don't unlock any
+        assert_eq!(config.visibility(Some("k3y")), Visibility::Public);
+        assert_eq!(config.visibility(Some("0ps")), Visibility::Public);
+        assert_eq!(config.visibility(Some("r3ad")), Visibility::All);
+        assert!(!config.is_admin(Some("r3ad")));
+        assert!(!config.is_admin(Some("k3y")));
+        assert!(config.is_admin(Some("0ps")));
+
+        let shared_key = ServerConfig::from_toml(
+            "[tenants.a]\napi_keys = [\"k\"]\n[tenants.b]\napi_keys = [\"k\"]",
+        )
+        .unwrap();
+        assert!(shared_key.validate().is_err());
+        let reader_key =
+            ServerConfig::from_toml("[tenants.a]\napi_keys = [\"k\"]\nreader_api_keys = [\"k\"]")
+                .unwrap();
+        assert!(reader_key.validate().is_err());
+        let unknown_tenant = ServerConfig::from_toml(
+            r#"
+            [tenants.a]
+            api_keys = ["k"]
+
+            [oidc]
+            issuer = "https://sso.example.com"
+            audience = "reliability-api"
+            jwks_path = "jwks.json"
+            tenants = { a-team = "a", b-team = "b" }
+            "#,
+        )
+        .unwrap();
+        let e = unknown_tenant.validate().unwrap_err().to_string();
+        assert!(e.contains("unknown tenant b"), "{}", e);
+        let no_keys = ServerConfig::from_toml("[tenants.a]").unwrap();
+        assert!(no_keys.validate().is_err());
+        let invalid_plan = ServerConfig::from_toml(
+            "[tenants.a]\napi_keys = [\"k\"]\n[tenants.a.plan]\nsquarings = 4294967295",
+        )
+        .unwrap();
+        let e = invalid_plan.validate().unwrap_err().to_string();
+        assert!(e.contains("tenant a"), "{}", e);
+    }
+
+    #[test]
+    fn test_network_type_thresholds() {
+        let config = ServerConfig::from_toml(
+            r#"
+            [plan]
+            network_ideal_milliseconds = 200
+            network_max_milliseconds = 25000
+
+            [plan.network_types.cellular]
+            network_ideal_milliseconds = 1000
+            network_round_timeout_milliseconds = 60000
+
+            [plan.network_types.wifi]
+            network_ideal_mbps = 50.0
+            network_min_mbps = 1.0
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        let cellular = config.plan.for_network_type(NetworkType::Cellular);
+        assert_eq!(cellular.network_thresholds(1024), (1000, 25000));
+        assert_eq!(
+            cellular.network_round_timeout(),
+            Duration::from_millis(60000)
+        );
+        let wifi = config.plan.for_network_type(NetworkType::Wifi);
+        assert_eq!(wifi.network_scale(), Scale::Throughput);
+        assert_eq!(wifi.network_thresholds(1024), (336, 16777));
+        // Types without thresholds of their own get the plan's
+        let ethernet = config.plan.for_network_type(NetworkType::Ethernet);
+        assert_eq!(ethernet.network_thresholds(1024), (200, 25000));
+
+        let unknown = ServerConfig::from_toml("[plan.network_types.satellite]").unwrap();
+        assert!(matches!(
+            unknown.plan.validate(),
+            Err(PlanError::UnknownNetworkType { .. })
+        ));
+        let invalid =
+            ServerConfig::from_toml("[plan.network_types.wifi]\nnetwork_min_mbps = 1.0").unwrap();
+        let e = invalid.validate().unwrap_err().to_string();
+        assert!(e.contains("network type wifi"), "{}", e);
+    }
+
+    #[test]
+    fn test_flags_override_defaults() {
+        let config = ServerConfig::load(Args {
+            squarings: Some(10),
+            base_path: Some("/v1".to_owned()),
+            tls_cert: Some("cert.pem".into()),
+            tls_key: Some("key.pem".into()),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(config.plan.squarings, 10);
+        assert_eq!(config.base_path, "/v1");
+        assert_eq!(config.tls.unwrap().key_path.to_str(), Some("key.pem"));
+        assert_eq!(config.bind_address, ([0, 0, 0, 0], 8080).into());
+    }
+
+    #[test]
+    fn test_plan_validation() {
+        assert_eq!(PlanConfig::default().validate(), Ok(()));
+
+        // 2^31 squarings take the fastest client over 17 minutes
+        let plan = PlanConfig {
+            squarings: 1 << 31,
+            ..Default::default()
+        };
+        assert_eq!(
+            plan.validate(),
+            Err(PlanError::CpuRoundTooLong {
+                squarings: 1 << 31,
+                fastest_milliseconds: 1_073_741,
+                timeout_milliseconds: 120_000,
+            })
+        );
+        // Calibrated rounds are bounded by the target instead
+        let calibrated = PlanConfig {
+            cpu_target_milliseconds: Some(5000),
+            ..plan
+        };
+        assert_eq!(calibrated.validate(), Ok(()));
+        let plan = PlanConfig {
+            cpu_target_milliseconds: Some(200_000),
+            ..calibrated
+        };
+        assert!(matches!(
+            plan.validate(),
+            Err(PlanError::CpuTargetTooLong { .. })
+        ));
+        let plan = PlanConfig {
+            cpu_rounds: 0,
+            ..plan
+        };
+        assert_eq!(plan.validate(), Ok(()));
+
+        let plan = PlanConfig {
+            payload_size_kb: 1024 * 1024,
+            network_round_timeout_milliseconds: Some(10_000),
+            ..Default::default()
+        };
+        assert!(matches!(
+            plan.validate(),
+            Err(PlanError::NetworkRoundTooLong {
+                fastest_milliseconds: 16_777,
+                ..
+            })
+        ));
+        assert!(PlanConfig {
+            network_rounds: 0,
+            ..plan
+        }
+        .validate()
+        .is_ok());
+
+        // Thresholds on throughput replace those on time and scale with the payload
+        let plan = PlanConfig {
+            payload_size_kb: 1024,
+            network_ideal_mbps: Some(100.0),
+            network_min_mbps: Some(1.0),
+            ..Default::default()
+        };
+        assert_eq!(plan.validate(), Ok(()));
+        assert_eq!(plan.network_thresholds(1024), (168, 16777));
+        assert_eq!(plan.network_thresholds(512), (84, 8389));
+        assert_eq!(plan.network_round_timeout(), Duration::from_millis(16777));
+        assert_eq!(plan.network_scale(), Scale::Throughput);
+        for (ideal_mbps, min_mbps) in [
+            (Some(100.0), None),
+            (Some(1.0), Some(100.0)),
+            (Some(1.0), Some(0.0)),
+        ] {
+            assert!(matches!(
+                PlanConfig {
+                    network_ideal_mbps: ideal_mbps,
+                    network_min_mbps: min_mbps,
+                    ..Default::default()
+                }
+                .validate(),
+                Err(PlanError::InvalidThroughput { .. })
+            ));
+        }
+
+        // Thresholds on squarings rate replace those on time and scale with squarings
+        let plan = PlanConfig {
+            squarings: 200_000,
+            cpu_ideal_squarings_per_second: Some(100_000.0),
+            cpu_min_squarings_per_second: Some(10_000.0),
+            ..Default::default()
+        };
+        assert_eq!(plan.validate(), Ok(()));
+        assert_eq!(plan.cpu_thresholds(), (2000, 20000));
+        assert_eq!(plan.cpu_round_timeout(), Duration::from_millis(20000));
+        assert_eq!(plan.cpu_scale(), Scale::Throughput);
+        assert!(matches!(
+            PlanConfig {
+                cpu_min_squarings_per_second: None,
+                ..plan
+            }
+            .validate(),
+            Err(PlanError::InvalidSquaringsRate { .. })
+        ));
+
+        let plan = PlanConfig {
+            max_frame_size_kb: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(plan.validate(), Err(PlanError::ZeroFrameSize));
+        let plan = PlanConfig {
+            max_frame_size_kb: Some(64),
+            network_profile_chunk_kb: Some(16),
+            ..Default::default()
+        };
+        assert_eq!(plan.frame_size_kb(), Some(16));
+        assert_eq!(
+            PlanConfig {
+                network_profile_chunk_kb: Some(0),
+                ..plan
+            }
+            .validate(),
+            Err(PlanError::ZeroFrameSize)
+        );
+        let plan = PlanConfig {
+            spot_check_sample_chunks: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(plan.validate(), Err(PlanError::ZeroSampleChunks));
+
+        assert!(ServerConfig::load(Args {
+            squarings: Some(u32::MAX),
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
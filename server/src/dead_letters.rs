@@ -0,0 +1,383 @@
+//! Side effects of sessions on other systems (webhooks) which failed, kept along with their
+//! payload and retried with exponential backoff, so that an outage of the receiving end doesn't
+//! lose them. Effects still failing after `max_attempts` are parked until an operator requeues or
+//! purges them at `/admin/dead_letters`.
+
+use anyhow::{anyhow, Result};
+use hyper::{Body, Client, Method, Request};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+use crate::api::unix_milliseconds;
+use crate::config::milliseconds;
+use crate::tasks::{TaskKind, TaskRegistry};
+
+/// How often entries are checked for a due retry
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct DeadLetterConfig {
+    /// JSON file the queue is kept in across restarts, `None` keeps it in memory only
+    pub(crate) path: Option<PathBuf>,
+    /// Deliveries of a side effect, the first one included, before it's parked
+    pub(crate) max_attempts: u32,
+    /// Delay before the first retry, doubled after every further failure
+    #[serde(rename = "retry_delay_milliseconds", deserialize_with = "milliseconds")]
+    pub(crate) retry_delay: Duration,
+    /// Ceiling of the delay between retries
+    #[serde(
+        rename = "max_retry_delay_milliseconds",
+        deserialize_with = "milliseconds"
+    )]
+    pub(crate) max_retry_delay: Duration,
+}
+
+impl Default for DeadLetterConfig {
+    fn default() -> Self {
+        DeadLetterConfig {
+            path: None,
+            max_attempts: 8,
+            retry_delay: Duration::from_secs(5),
+            max_retry_delay: Duration::from_secs(600),
+        }
+    }
+}
+
+/// Effect of a session on a system outside the server
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum SideEffect {
+    /// `body` POSTed to `url` as JSON
+    Webhook {
+        url: String,
+        body: serde_json::Value,
+    },
+    /// `payload` published to `subject` of the NATS server at `address`
+    #[cfg(feature = "event-bus")]
+    Publish {
+        address: String,
+        auth_token: Option<String>,
+        subject: String,
+        payload: serde_json::Value,
+    },
+}
+
+impl SideEffect {
+    async fn perform(&self) -> Result<()> {
+        match self {
+            SideEffect::Webhook { url, body } => {
+                let request = Request::builder()
+                    .method(Method::POST)
+                    .uri(url)
+                    .header("content-type", "application/json")
+                    .body(Body::from(serde_json::to_vec(body)?))?;
+                let response = Client::new().request(request).await?;
+                if !response.status().is_success() {
+                    return Err(anyhow!(
+                        "{} responded with status {}",
+                        url,
+                        response.status()
+                    ));
+                }
+                Ok(())
+            }
+            #[cfg(feature = "event-bus")]
+            SideEffect::Publish {
+                address,
+                auth_token,
+                subject,
+                payload,
+            } => crate::bus::publish_once(address, auth_token.as_deref(), subject, payload).await,
+        }
+    }
+}
+
+/// Side effect which failed, as listed at `/admin/dead_letters`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct DeadLetter {
+    pub(crate) id: String,
+    pub(crate) effect: SideEffect,
+    /// Failed deliveries since the effect was first tried or last requeued
+    pub(crate) attempts: u32,
+    pub(crate) first_failed_at_unix_milliseconds: u128,
+    pub(crate) last_error: String,
+    /// Time of the next retry, `None` once the entry is parked
+    pub(crate) next_attempt_at_unix_milliseconds: Option<u128>,
+}
+
+/// Failed side effects keyed by id, shared by the sessions delivering them, the retry task and
+/// the API
+#[derive(Clone, Default)]
+pub(crate) struct DeadLetterQueue {
+    config: Arc<DeadLetterConfig>,
+    letters: Arc<Mutex<BTreeMap<String, DeadLetter>>>,
+}
+
+impl DeadLetterQueue {
+    /// Queue of `config`, holding the entries left in its file by a previous run of the server
+    pub(crate) fn open(config: DeadLetterConfig) -> Result<Self> {
+        let letters = match &config.path {
+            Some(path) if path.exists() => {
+                let letters: Vec<DeadLetter> = serde_json::from_slice(&std::fs::read(path)?)?;
+                letters
+                    .into_iter()
+                    .map(|letter| (letter.id.clone(), letter))
+                    .collect()
+            }
+            _ => BTreeMap::new(),
+        };
+        if !letters.is_empty() {
+            info!("Loaded {} dead letters", letters.len());
+        }
+        Ok(DeadLetterQueue {
+            config: Arc::new(config),
+            letters: Arc::new(Mutex::new(letters)),
+        })
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.letters.lock().unwrap().len()
+    }
+
+    /// Performs `effect`, queueing it for retries if it fails. Returns whether it succeeded.
+    pub(crate) async fn deliver(&self, effect: SideEffect) -> bool {
+        let error = match effect.perform().await {
+            Ok(()) => return true,
+            Err(e) => e,
+        };
+        let now = unix_milliseconds(SystemTime::now());
+        let mut letter = DeadLetter {
+            id: format!("{:x}", Uuid::new_v4().as_u128()),
+            effect,
+            attempts: 0,
+            first_failed_at_unix_milliseconds: now,
+            last_error: String::new(),
+            next_attempt_at_unix_milliseconds: None,
+        };
+        self.record_failure(&mut letter, &error, now);
+        warn!(
+            "Side effect failed, queued as dead letter {}: {:?}",
+            letter.id, error
+        );
+        let mut letters = self.letters.lock().unwrap();
+        letters.insert(letter.id.clone(), letter);
+        self.save(&letters);
+        false
+    }
+
+    /// Counts a failed delivery of `letter` and schedules its next retry, if it has any left
+    fn record_failure(&self, letter: &mut DeadLetter, error: &anyhow::Error, now: u128) {
+        letter.attempts += 1;
+        letter.last_error = format!("{:?}", error);
+        letter.next_attempt_at_unix_milliseconds = if letter.attempts < self.config.max_attempts {
+            let delay = self
+                .config
+                .retry_delay
+                .checked_mul(1 << (letter.attempts - 1).min(31))
+                .unwrap_or(self.config.max_retry_delay)
+                .min(self.config.max_retry_delay);
+            Some(now + delay.as_millis())
+        } else {
+            None
+        };
+    }
+
+    /// Retries every entry due at `now`, entries delivered leave the queue. Returns number of
+    /// entries delivered.
+    pub(crate) async fn retry_due(&self, now: SystemTime) -> usize {
+        let now = unix_milliseconds(now);
+        let due: Vec<DeadLetter> = self
+            .letters
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|letter| {
+                matches!(letter.next_attempt_at_unix_milliseconds,
+                    Some(next_attempt_at) if next_attempt_at <= now)
+            })
+            .cloned()
+            .collect();
+        let mut delivered = 0;
+        for letter in due {
+            let result = letter.effect.perform().await;
+            let mut letters = self.letters.lock().unwrap();
+            match result {
+                Ok(()) => {
+                    letters.remove(&letter.id);
+                    delivered += 1;
+                    info!("Delivered dead letter {}", letter.id);
+                }
+                // Entry may have been purged in the meantime
+                Err(e) => match letters.get_mut(&letter.id) {
+                    Some(letter) => {
+                        self.record_failure(letter, &e, now);
+                        if letter.next_attempt_at_unix_milliseconds.is_none() {
+                            warn!(
+                                "Parked dead letter {} after {} attempts: {:?}",
+                                letter.id, letter.attempts, e
+                            );
+                        }
+                    }
+                    None => continue,
+                },
+            }
+            self.save(&letters);
+        }
+        delivered
+    }
+
+    /// Entries oldest first
+    pub(crate) fn list(&self) -> Vec<DeadLetter> {
+        let mut letters: Vec<DeadLetter> = self.letters.lock().unwrap().values().cloned().collect();
+        letters.sort_by_key(|letter| letter.first_failed_at_unix_milliseconds);
+        letters
+    }
+
+    /// Schedules entry `id` for an immediate retry with all of its attempts, parked or not.
+    /// Returns whether there is such an entry.
+    pub(crate) fn requeue(&self, id: &str) -> bool {
+        let mut letters = self.letters.lock().unwrap();
+        match letters.get_mut(id) {
+            Some(letter) => {
+                letter.attempts = 0;
+                letter.next_attempt_at_unix_milliseconds =
+                    Some(unix_milliseconds(SystemTime::now()));
+                self.save(&letters);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drops entry `id` for good. Returns whether there was such an entry.
+    pub(crate) fn purge(&self, id: &str) -> bool {
+        let mut letters = self.letters.lock().unwrap();
+        let purged = letters.remove(id).is_some();
+        if purged {
+            self.save(&letters);
+        }
+        purged
+    }
+
+    /// Drops every entry, returns number of entries dropped
+    pub(crate) fn purge_all(&self) -> usize {
+        let mut letters = self.letters.lock().unwrap();
+        let purged = letters.len();
+        letters.clear();
+        self.save(&letters);
+        purged
+    }
+
+    /// Rewrites the file of the queue, if it has one. Failures are logged, the entries stay in
+    /// memory either way.
+    fn save(&self, letters: &BTreeMap<String, DeadLetter>) {
+        let path = match &self.config.path {
+            Some(path) => path,
+            None => return,
+        };
+        let letters: Vec<&DeadLetter> = letters.values().collect();
+        // Written next to the file and renamed over it, so that a crash can't leave it truncated
+        let temporary = path.with_extension("tmp");
+        let result = serde_json::to_vec(&letters)
+            .map_err(anyhow::Error::from)
+            .and_then(|contents| Ok(std::fs::write(&temporary, contents)?))
+            .and_then(|()| Ok(std::fs::rename(&temporary, path)?));
+        if let Err(e) = result {
+            error!("Unable to save dead letters to {:?}: {:?}", path, e);
+        }
+    }
+
+    pub(crate) fn spawn(&self, tasks: &TaskRegistry) {
+        let queue = self.clone();
+        tasks.spawn(TaskKind::Background, "dead letters", async move {
+            let mut interval = tokio::time::interval(POLL_INTERVAL);
+            loop {
+                interval.tick().await;
+                if queue.len() > 0 {
+                    queue.retry_due(SystemTime::now()).await;
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dead_letters::{DeadLetterConfig, DeadLetterQueue, SideEffect};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+    use warp::http::StatusCode;
+    use warp::Filter;
+
+    #[tokio::test]
+    async fn test_dead_letters() {
+        // Endpoint fails until it's told to recover
+        let healthy = Arc::new(AtomicBool::new(false));
+        let endpoint = {
+            let healthy = healthy.clone();
+            warp::post().map(move || {
+                if healthy.load(Ordering::SeqCst) {
+                    StatusCode::OK
+                } else {
+                    StatusCode::SERVICE_UNAVAILABLE
+                }
+            })
+        };
+        let (address, server) = warp::serve(endpoint).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let path = std::env::temp_dir().join(format!("dead-letters-{}.json", uuid::Uuid::new_v4()));
+        let config = DeadLetterConfig {
+            path: Some(path.clone()),
+            max_attempts: 2,
+            retry_delay: Duration::from_secs(10),
+            ..Default::default()
+        };
+        let queue = DeadLetterQueue::open(config.clone()).unwrap();
+        let effect = SideEffect::Webhook {
+            url: format!("http://{}/", address),
+            body: serde_json::json!({"verdict": "allow"}),
+        };
+        assert!(!queue.deliver(effect.clone()).await);
+        let letter = queue.list().pop().unwrap();
+        assert_eq!(letter.effect, effect);
+        assert_eq!(letter.attempts, 1);
+        assert!(letter.last_error.contains("503"));
+
+        // Not due yet, then due and failing again, which parks it
+        let now = SystemTime::now();
+        assert_eq!(queue.retry_due(now).await, 0);
+        assert_eq!(queue.list()[0].attempts, 1);
+        assert_eq!(queue.retry_due(now + Duration::from_secs(11)).await, 0);
+        assert_eq!(queue.list()[0].attempts, 2);
+        assert_eq!(queue.list()[0].next_attempt_at_unix_milliseconds, None);
+        assert_eq!(queue.retry_due(now + Duration::from_secs(3600)).await, 0);
+
+        // Survives a restart
+        let reopened = DeadLetterQueue::open(config).unwrap();
+        assert_eq!(reopened.list()[0].id, letter.id);
+
+        healthy.store(true, Ordering::SeqCst);
+        assert!(!reopened.requeue("missing"));
+        assert!(reopened.requeue(&letter.id));
+        assert_eq!(reopened.retry_due(SystemTime::now()).await, 1);
+        assert_eq!(reopened.len(), 0);
+        assert!(reopened.deliver(effect.clone()).await);
+        assert_eq!(reopened.len(), 0);
+
+        healthy.store(false, Ordering::SeqCst);
+        reopened.deliver(effect.clone()).await;
+        reopened.deliver(effect).await;
+        let id = reopened.list()[0].id.clone();
+        assert!(reopened.purge(&id));
+        assert!(!reopened.purge(&id));
+        assert_eq!(reopened.purge_all(), 1);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,217 @@
+use serde::Serialize;
+use structopt::StructOpt;
+
+use crate::config::PlanConfig;
+use crate::measurements::{preview_score, ScorePreview};
+
+/// Performance of the clients a plan is tuned for
+#[derive(Clone, Debug, StructOpt)]
+pub(crate) struct HardwareProfile {
+    /// Modular squarings client performs per second, e.g. the `squarings` of a CPU round divided
+    /// by its time in a run of a similar machine
+    #[structopt(long)]
+    pub(crate) squarings_per_second: u64,
+    /// Throughput of the client's link in each direction, in kilobytes per second
+    #[structopt(long)]
+    pub(crate) throughput_kb_per_second: u64,
+    /// Round trip time between client and server
+    #[structopt(long, default_value = "50")]
+    pub(crate) round_trip_milliseconds: u64,
+    /// Throughput of the client's storage, in kilobytes per second, for both writes and reads
+    #[structopt(long, default_value = "200000")]
+    pub(crate) disk_kb_per_second: u64,
+}
+
+/// Expected time of a round of one challenge kind next to the limits of the plan
+#[derive(Debug, Serialize)]
+pub(crate) struct RoundEstimate {
+    pub(crate) rounds: usize,
+    /// Time round is expected to take, as it is scored
+    pub(crate) round_milliseconds: u64,
+    pub(crate) ideal_milliseconds: u64,
+    pub(crate) max_milliseconds: u64,
+    pub(crate) timeout_milliseconds: u64,
+    /// Round finishes before client is cut off
+    pub(crate) within_timeout: bool,
+}
+
+/// Dry run of a plan on a hardware profile
+#[derive(Debug, Serialize)]
+pub(crate) struct Estimate {
+    /// `None` for challenge kinds without rounds
+    pub(crate) cpu: Option<RoundEstimate>,
+    pub(crate) network: Option<RoundEstimate>,
+    pub(crate) disk: Option<RoundEstimate>,
+    /// Expected length of the whole session, including calibration, latency, datagram, download and upload rounds
+    pub(crate) session_milliseconds: u64,
+    /// Score the expected round times get with the thresholds of the plan, `None` if they can't be scored
+    pub(crate) score: Option<ScorePreview>,
+}
+
+/// Time of moving `kb` at `kb_per_second`, zero speed is treated as one kilobyte per second
+fn transfer_milliseconds(kb: usize, kb_per_second: u64) -> u64 {
+    kb as u64 * 1000 / kb_per_second.max(1)
+}
+
+/// Estimates round times of a session of `plan` with `disk_rounds` disk rounds on `profile`,
+/// so that squarings and payload sizes can be picked without trying them on live clients.
+/// Estimates ignore server side time (puzzle generation, verification) and contention.
+pub(crate) fn estimate(
+    plan: &PlanConfig,
+    disk_rounds: usize,
+    profile: &HardwareProfile,
+) -> Estimate {
+    let round_trip = profile.round_trip_milliseconds;
+    let round = |rounds: usize,
+                 round_milliseconds: u64,
+                 elapsed_milliseconds: u64,
+                 ideal,
+                 max,
+                 timeout: u64| {
+        (rounds > 0).then_some(RoundEstimate {
+            rounds,
+            round_milliseconds,
+            ideal_milliseconds: ideal,
+            max_milliseconds: max,
+            timeout_milliseconds: timeout,
+            within_timeout: elapsed_milliseconds <= timeout,
+        })
+    };
+
+    // Calibrated rounds take about the target, but are normalized back to `squarings` before scoring
+    let solve_milliseconds = u64::from(plan.squarings) * 1000 / profile.squarings_per_second.max(1);
+    let cpu_round_milliseconds = match plan.cpu_target_milliseconds {
+        Some(target_milliseconds) => target_milliseconds,
+        None => solve_milliseconds,
+    } + round_trip;
+    let (cpu_ideal_milliseconds, cpu_max_milliseconds) = plan.cpu_thresholds();
+    let cpu = round(
+        plan.cpu_rounds,
+        solve_milliseconds + round_trip,
+        cpu_round_milliseconds,
+        cpu_ideal_milliseconds as u64,
+        cpu_max_milliseconds as u64,
+        plan.cpu_round_timeout().as_millis() as u64,
+    );
+    let calibration_milliseconds = match plan.cpu_target_milliseconds {
+        Some(_) if plan.cpu_rounds > 0 => {
+            u64::from(plan.cpu_calibration_squarings) * 1000 / profile.squarings_per_second.max(1)
+                + round_trip
+        }
+        _ => 0,
+    };
+
+    // Payload is sent to the client and returned
+    let one_way = transfer_milliseconds(plan.payload_size_kb, profile.throughput_kb_per_second);
+    let network_round_milliseconds = 2 * one_way + round_trip;
+    let (network_ideal_milliseconds, network_max_milliseconds) =
+        plan.network_thresholds(plan.payload_size_kb);
+    let network = round(
+        plan.network_rounds,
+        network_round_milliseconds,
+        network_round_milliseconds,
+        network_ideal_milliseconds as u64,
+        network_max_milliseconds as u64,
+        plan.network_round_timeout().as_millis() as u64,
+    );
+    // Blob is received, written and read back
+    let disk_round_milliseconds =
+        transfer_milliseconds(plan.disk_payload_size_kb, profile.throughput_kb_per_second)
+            + 2 * transfer_milliseconds(plan.disk_payload_size_kb, profile.disk_kb_per_second)
+            + round_trip;
+    let disk = round(
+        disk_rounds,
+        disk_round_milliseconds,
+        disk_round_milliseconds,
+        plan.disk_ideal_milliseconds,
+        plan.disk_max_milliseconds,
+        plan.disk_max_milliseconds,
+    );
+
+    let session_milliseconds = plan.cpu_rounds as u64 * cpu_round_milliseconds
+        + calibration_milliseconds
+        + network.as_ref().map_or(0, |network| {
+            network.rounds as u64 * network.round_milliseconds
+        })
+        + disk
+            .as_ref()
+            .map_or(0, |disk| disk.rounds as u64 * disk.round_milliseconds)
+        + plan.latency_probes as u64 * round_trip
+        + plan.datagram_probes as u64 * u64::from(plan.datagram_interval_milliseconds)
+        + (plan.download_rounds + plan.upload_rounds) as u64 * (one_way + round_trip);
+
+    let results = |estimate: &Option<RoundEstimate>| -> Vec<u128> {
+        estimate.as_ref().map_or(vec![], |estimate| {
+            vec![u128::from(estimate.round_milliseconds); estimate.rounds]
+        })
+    };
+    let score = preview_score(plan, &results(&cpu), &results(&network), &results(&disk)).ok();
+
+    Estimate {
+        cpu,
+        network,
+        disk,
+        session_milliseconds,
+        score,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::PlanConfig;
+    use crate::estimate::{estimate, HardwareProfile};
+
+    fn profile() -> HardwareProfile {
+        HardwareProfile {
+            squarings_per_second: 50_000,
+            throughput_kb_per_second: 12_500,
+            round_trip_milliseconds: 20,
+            disk_kb_per_second: 100_000,
+        }
+    }
+
+    #[test]
+    fn test_estimate() {
+        let plan = PlanConfig {
+            latency_probes: 5,
+            ..Default::default()
+        };
+        let estimate = estimate(&plan, 1, &profile());
+        let cpu = estimate.cpu.unwrap();
+        assert_eq!(cpu.rounds, 5);
+        // 200000 squarings take 4s, plus the round trip
+        assert_eq!(cpu.round_milliseconds, 4020);
+        assert!(cpu.within_timeout);
+        let network = estimate.network.unwrap();
+        assert_eq!(network.round_milliseconds, 2 * 81 + 20);
+        let disk = estimate.disk.unwrap();
+        assert_eq!(disk.round_milliseconds, 327 + 2 * 40 + 20);
+        assert_eq!(
+            estimate.session_milliseconds,
+            5 * 4020 + 10 * 182 + 427 + 5 * 20
+        );
+        let score = estimate.score.unwrap();
+        assert_eq!(score.cpu_sub_score, Some(100));
+        assert!(score.disk_sub_score.unwrap() < 100);
+
+        // Rounds exceeding the timeout are flagged and score 0
+        let plan = PlanConfig {
+            squarings: 10_000_000,
+            ..Default::default()
+        };
+        let estimate = self::estimate(&plan, 0, &profile());
+        let cpu = estimate.cpu.unwrap();
+        assert!(!cpu.within_timeout);
+        assert!(estimate.disk.is_none());
+        assert_eq!(estimate.score.unwrap().score, 0);
+
+        // Calibrated rounds take the target, but are scored as if they had `squarings`
+        let plan = PlanConfig {
+            cpu_target_milliseconds: Some(10_000),
+            ..Default::default()
+        };
+        let estimate = self::estimate(&plan, 0, &profile());
+        assert_eq!(estimate.cpu.unwrap().round_milliseconds, 4020);
+        assert_eq!(estimate.session_milliseconds, 5 * 10_020 + 420 + 10 * 182);
+    }
+}
@@ -0,0 +1,209 @@
+//! Stored runs as a flat dataset, a row per run, to be loaded into e.g. pandas or R as is
+
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+use crate::api::unix_milliseconds;
+use crate::types::{ClientData, RunStatus};
+
+/// Format of `/export`
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ExportFormat {
+    /// Comma separated values with a header line, list fields are joined by `;`
+    Csv,
+    /// JSON object per line, list fields are arrays
+    Jsonl,
+}
+
+impl ExportFormat {
+    pub(crate) fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv",
+            ExportFormat::Jsonl => "application/x-ndjson",
+        }
+    }
+
+    /// Offers the export as a file download
+    pub(crate) fn content_disposition(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "attachment; filename=\"runs.csv\"",
+            ExportFormat::Jsonl => "attachment; filename=\"runs.jsonl\"",
+        }
+    }
+
+    /// First line of the export, before any row
+    pub(crate) fn header(self) -> Option<String> {
+        match self {
+            ExportFormat::Csv => Some(format!("{}\n", CSV_COLUMNS.join(","))),
+            ExportFormat::Jsonl => None,
+        }
+    }
+}
+
+/// Columns of CSV exports, in the order of the fields of `ExportRow`
+const CSV_COLUMNS: &[&str] = &[
+    "id",
+    "started_at_unix_milliseconds",
+    "finished_at_unix_milliseconds",
+    "plan",
+    "score",
+    "status",
+    "network_type",
+    "client_version",
+    "tags",
+    "cpu_challenge_timings_in_milis",
+    "network_challenge_timings_in_milis",
+    "disk_challenge_timings_in_milis",
+    "latency_probe_timings_in_micros",
+    "download_challenge_timings_in_milis",
+    "upload_challenge_timings_in_milis",
+];
+
+/// Run as exported
+#[derive(Serialize)]
+pub(crate) struct ExportRow<'a> {
+    id: String,
+    started_at_unix_milliseconds: u128,
+    /// Time of the last event of the session, when its score was calculated
+    finished_at_unix_milliseconds: u128,
+    plan: &'a str,
+    score: u128,
+    /// Whether the session completed, scores of partial runs aren't comparable to others
+    status: RunStatus,
+    network_type: Option<&'static str>,
+    client_version: Option<&'a str>,
+    tags: &'a BTreeMap<String, String>,
+    cpu_challenge_timings_in_milis: &'a [u128],
+    network_challenge_timings_in_milis: &'a [u128],
+    disk_challenge_timings_in_milis: &'a [u128],
+    latency_probe_timings_in_micros: &'a [u128],
+    download_challenge_timings_in_milis: &'a [u128],
+    upload_challenge_timings_in_milis: &'a [u128],
+}
+
+impl<'a> ExportRow<'a> {
+    pub(crate) fn new(client_id: u128, data: &'a ClientData) -> Self {
+        let started_at_unix_milliseconds = unix_milliseconds(data.started_at);
+        ExportRow {
+            id: format!("{:x}", client_id),
+            started_at_unix_milliseconds,
+            finished_at_unix_milliseconds: data
+                .events
+                .last()
+                .map(|event| unix_milliseconds(event.timestamp.wall_clock))
+                .unwrap_or(started_at_unix_milliseconds),
+            plan: &data.configuration.plan_name,
+            score: data.score,
+            status: data.status,
+            network_type: data.network_type.map(|network_type| network_type.name()),
+            client_version: data.client_version.as_deref(),
+            tags: &data.tags,
+            cpu_challenge_timings_in_milis: &data.cpu_challenge_timings_in_milis,
+            network_challenge_timings_in_milis: &data.network_challenge_timings_in_milis,
+            disk_challenge_timings_in_milis: &data.disk_challenge_timings_in_milis,
+            latency_probe_timings_in_micros: &data.latency_probe_timings_in_micros,
+            download_challenge_timings_in_milis: &data.download_challenge_timings_in_milis,
+            upload_challenge_timings_in_milis: &data.upload_challenge_timings_in_milis,
+        }
+    }
+
+    /// Row as a line of `format`, line break included
+    pub(crate) fn line(&self, format: ExportFormat) -> serde_json::Result<String> {
+        match format {
+            ExportFormat::Csv => Ok(format!("{}\n", self.csv_fields().join(","))),
+            ExportFormat::Jsonl => serde_json::to_string(self).map(|line| line + "\n"),
+        }
+    }
+
+    fn csv_fields(&self) -> Vec<String> {
+        let list = |values: &[u128]| {
+            values
+                .iter()
+                .map(u128::to_string)
+                .collect::<Vec<_>>()
+                .join(";")
+        };
+        let tags = self
+            .tags
+            .iter()
+            .map(|(key, value)| format!("{}:{}", key, value))
+            .collect::<Vec<_>>()
+            .join(";");
+        vec![
+            self.id.clone(),
+            self.started_at_unix_milliseconds.to_string(),
+            self.finished_at_unix_milliseconds.to_string(),
+            csv_field(self.plan).into_owned(),
+            self.score.to_string(),
+            status_field(self.status),
+            self.network_type.unwrap_or_default().to_owned(),
+            csv_field(self.client_version.unwrap_or_default()).into_owned(),
+            csv_field(&tags).into_owned(),
+            list(self.cpu_challenge_timings_in_milis),
+            list(self.network_challenge_timings_in_milis),
+            list(self.disk_challenge_timings_in_milis),
+            list(self.latency_probe_timings_in_micros),
+            list(self.download_challenge_timings_in_milis),
+            list(self.upload_challenge_timings_in_milis),
+        ]
+    }
+}
+
+/// Status as a single CSV field, e.g. `failed_at_network:7` or `failed_at_round:4:0`
+fn status_field(status: RunStatus) -> String {
+    match status {
+        RunStatus::Completed => "completed".to_owned(),
+        RunStatus::FailedAtCpu(round) => format!("failed_at_cpu:{}", round),
+        RunStatus::FailedAtNetwork(round) => format!("failed_at_network:{}", round),
+        RunStatus::FailedAtRound { kind_id, round } => {
+            format!("failed_at_round:{}:{}", kind_id, round)
+        }
+        RunStatus::Disconnected => "disconnected".to_owned(),
+    }
+}
+
+/// Quotes a client supplied value if it holds a separator, quote or line break
+fn csv_field(value: &str) -> Cow<'_, str> {
+    if value.contains(&[',', '"', '\n', '\r'][..]) {
+        Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::export::{ExportFormat, ExportRow, CSV_COLUMNS};
+    use crate::types::test_utils::client_data;
+    use crate::types::RunStatus;
+
+    #[test]
+    fn test_csv_row() {
+        let mut data = client_data(75);
+        data.tags.insert("region".to_owned(), "eu".to_owned());
+        data.tags
+            .insert("note".to_owned(), "say \"hi\", bye".to_owned());
+        let row = ExportRow::new(0xabc, &data);
+        let line = row.line(ExportFormat::Csv).unwrap();
+        assert!(line.starts_with("abc,"));
+        assert!(line.ends_with('\n'));
+        assert!(line.contains(",75,completed,"));
+        // Tags hold a separator and quotes, so they are quoted
+        assert!(line.contains(",\"note:say \"\"hi\"\", bye;region:eu\","));
+        let header = ExportFormat::Csv.header().unwrap();
+        assert_eq!(header.trim_end().split(',').count(), CSV_COLUMNS.len());
+        assert_eq!(ExportFormat::Jsonl.header(), None);
+
+        data.status = RunStatus::FailedAtNetwork(7);
+        let line = ExportRow::new(0xabc, &data)
+            .line(ExportFormat::Csv)
+            .unwrap();
+        assert!(line.contains(",75,failed_at_network:7,"));
+        let line = ExportRow::new(0xabc, &data)
+            .line(ExportFormat::Jsonl)
+            .unwrap();
+        assert!(line.contains(r#""status":{"failed_at_network":7}"#));
+    }
+}
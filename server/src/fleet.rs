@@ -0,0 +1,309 @@
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::types::{ClientData, Visibility};
+
+/// Key of runs whose client declared no network type or version in breakdowns by them
+const UNDECLARED: &str = "undeclared";
+
+/// Query selecting runs by their tags, e.g. `region:eu AND class:native OR class:browser`.
+/// `AND` binds tighter than `OR`, parentheses are not supported.
+#[derive(Debug, PartialEq)]
+pub(crate) struct TagQuery {
+    // Disjunction of conjunctions of `key:value` terms
+    alternatives: Vec<Vec<(String, String)>>,
+}
+
+impl TagQuery {
+    pub(crate) fn parse(query: &str) -> Result<Self> {
+        let alternatives = query
+            .split(" OR ")
+            .map(|alternative| {
+                alternative
+                    .split(" AND ")
+                    .map(|term| {
+                        let term = parse_tags(term)?.into_iter().next();
+                        term.ok_or_else(|| anyhow!("Empty tag query term"))
+                    })
+                    .collect::<Result<Vec<_>>>()
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(TagQuery { alternatives })
+    }
+
+    pub(crate) fn matches(&self, tags: &BTreeMap<String, String>) -> bool {
+        self.alternatives.iter().any(|terms| {
+            terms
+                .iter()
+                .all(|(key, value)| tags.get(key) == Some(value))
+        })
+    }
+}
+
+/// Parses comma separated `key:value` tags, e.g. `region:eu,class:native`
+pub(crate) fn parse_tags(tags: &str) -> Result<BTreeMap<String, String>> {
+    tags.split(',')
+        .filter(|tag| !tag.trim().is_empty())
+        .map(|tag| {
+            let tag = tag.trim();
+            match tag.find(':') {
+                Some(index) if index > 0 => {
+                    Ok((tag[..index].to_owned(), tag[index + 1..].to_owned()))
+                }
+                _ => Err(anyhow!("Invalid tag `{}`", tag)),
+            }
+        })
+        .collect()
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct RunScore {
+    id: String,
+    score: u128,
+}
+
+/// Score distribution of the runs belonging to a fleet
+#[derive(Debug, Serialize)]
+pub(crate) struct FleetSummary {
+    runs: usize,
+    min_score: Option<u128>,
+    max_score: Option<u128>,
+    mean_score: Option<u128>,
+    median_score: Option<u128>,
+    /// Number of runs per score bucket of width 10, last bucket also contains 100
+    histogram: Vec<usize>,
+    /// Runs with the lowest score, lowest first
+    worst_performers: Vec<RunScore>,
+    /// Score distribution of runs of each network type clients declared
+    network_types: BTreeMap<&'static str, GroupSummary>,
+    /// Score distribution of runs of each version or deployment label clients declared
+    client_versions: BTreeMap<String, GroupSummary>,
+}
+
+/// Score distribution of the runs of a fleet on one network type or client version
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct GroupSummary {
+    runs: usize,
+    mean_score: u128,
+    median_score: u128,
+}
+
+/// Mean score of the fleet over a time bucket
+#[derive(Debug, Serialize)]
+pub(crate) struct TrendPoint {
+    bucket_start_unix_seconds: u64,
+    runs: usize,
+    mean_score: u128,
+}
+
+fn fleet_runs<'a>(
+    storage: &'a HashMap<u128, ClientData>,
+    query: &'a TagQuery,
+) -> impl Iterator<Item = (&'a u128, &'a ClientData)> {
+    storage
+        .iter()
+        .filter(move |(_, data)| data.is_completed() && query.matches(&data.tags))
+}
+
+/// Summary of all completed runs of the fleet, private ones included. Only runs
+/// allowed by `visibility` are listed among worst performers.
+pub(crate) fn summarize(
+    storage: &HashMap<u128, ClientData>,
+    query: &TagQuery,
+    worst_performers: usize,
+    visibility: Visibility,
+) -> FleetSummary {
+    let mut runs: Vec<(u128, u128, bool)> = fleet_runs(storage, query)
+        .map(|(client_id, data)| (data.score, *client_id, visibility.allows(data)))
+        .collect();
+    runs.sort();
+
+    let mut histogram = vec![0; 10];
+    for (score, _, _) in &runs {
+        histogram[std::cmp::min(*score as usize / 10, 9)] += 1;
+    }
+
+    let mut network_type_scores: BTreeMap<&'static str, Vec<u128>> = BTreeMap::new();
+    let mut client_version_scores: BTreeMap<String, Vec<u128>> = BTreeMap::new();
+    for (_, data) in fleet_runs(storage, query) {
+        network_type_scores
+            .entry(
+                data.network_type
+                    .map_or(UNDECLARED, |network_type| network_type.name()),
+            )
+            .or_default()
+            .push(data.score);
+        client_version_scores
+            .entry(
+                data.client_version
+                    .clone()
+                    .unwrap_or_else(|| UNDECLARED.to_owned()),
+            )
+            .or_default()
+            .push(data.score);
+    }
+
+    FleetSummary {
+        runs: runs.len(),
+        min_score: runs.first().map(|(score, _, _)| *score),
+        max_score: runs.last().map(|(score, _, _)| *score),
+        mean_score: if runs.is_empty() {
+            None
+        } else {
+            Some(runs.iter().map(|(score, _, _)| score).sum::<u128>() / runs.len() as u128)
+        },
+        median_score: runs.get(runs.len() / 2).map(|(score, _, _)| *score),
+        histogram,
+        worst_performers: runs
+            .iter()
+            .filter(|(_, _, listed)| *listed)
+            .take(worst_performers)
+            .map(|(score, client_id, _)| RunScore {
+                id: format!("{:x}", client_id),
+                score: *score,
+            })
+            .collect(),
+        network_types: group_summaries(network_type_scores),
+        client_versions: group_summaries(client_version_scores),
+    }
+}
+
+fn group_summaries<K: Ord>(groups: BTreeMap<K, Vec<u128>>) -> BTreeMap<K, GroupSummary> {
+    groups
+        .into_iter()
+        .map(|(key, mut scores)| {
+            scores.sort_unstable();
+            let summary = GroupSummary {
+                runs: scores.len(),
+                mean_score: scores.iter().sum::<u128>() / scores.len() as u128,
+                median_score: scores[scores.len() / 2],
+            };
+            (key, summary)
+        })
+        .collect()
+}
+
+/// Mean score of the fleet per `bucket` of time runs were started in, oldest first
+pub(crate) fn trend(
+    storage: &HashMap<u128, ClientData>,
+    query: &TagQuery,
+    bucket: Duration,
+) -> Vec<TrendPoint> {
+    let bucket_seconds = std::cmp::max(bucket.as_secs(), 1);
+    let mut buckets: BTreeMap<u64, (usize, u128)> = BTreeMap::new();
+
+    for (_, data) in fleet_runs(storage, query) {
+        let started_at = data
+            .started_at
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+        let entry = buckets
+            .entry(started_at - started_at % bucket_seconds)
+            .or_insert((0, 0));
+        entry.0 += 1;
+        entry.1 += data.score;
+    }
+
+    buckets
+        .into_iter()
+        .map(|(bucket_start, (runs, score_sum))| TrendPoint {
+            bucket_start_unix_seconds: bucket_start,
+            runs,
+            mean_score: score_sum / runs as u128,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fleet::{parse_tags, summarize, GroupSummary, TagQuery};
+    use crate::types::test_utils::client_data;
+    use crate::types::Visibility;
+    use shared::NetworkType;
+    use std::collections::{BTreeMap, HashMap};
+
+    fn tags(tags: &[(&str, &str)]) -> BTreeMap<String, String> {
+        tags.iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_tag_query() {
+        let query = TagQuery::parse("region:eu AND class:native OR class:browser").unwrap();
+        assert!(query.matches(&tags(&[("region", "eu"), ("class", "native")])));
+        assert!(query.matches(&tags(&[("class", "browser")])));
+        assert!(!query.matches(&tags(&[("region", "us"), ("class", "native")])));
+        assert!(!query.matches(&tags(&[])));
+
+        assert!(TagQuery::parse("region").is_err());
+        assert!(TagQuery::parse("region:eu AND :native").is_err());
+        assert!(TagQuery::parse("region:eu AND ").is_err());
+    }
+
+    #[test]
+    fn test_parse_tags() {
+        assert_eq!(
+            parse_tags("region:eu, class:native").unwrap(),
+            tags(&[("region", "eu"), ("class", "native")])
+        );
+        assert_eq!(parse_tags("").unwrap(), tags(&[]));
+        assert!(parse_tags("region").is_err());
+    }
+
+    #[test]
+    fn test_fleet_summary() {
+        let mut storage = HashMap::new();
+        for (client_id, score, region) in
+            &[(1, 90, "eu"), (2, 40, "eu"), (3, 100, "eu"), (4, 0, "us")]
+        {
+            let mut data = client_data(*score);
+            data.tags = tags(&[("region", region)]);
+            storage.insert(*client_id, data);
+        }
+
+        let query = TagQuery::parse("region:eu").unwrap();
+        let summary = summarize(&storage, &query, 2, Visibility::Public);
+        assert_eq!(summary.runs, 3);
+        assert_eq!(summary.min_score, Some(40));
+        assert_eq!(summary.max_score, Some(100));
+        assert_eq!(summary.mean_score, Some(76));
+        assert_eq!(summary.median_score, Some(90));
+        assert_eq!(summary.histogram[4], 1);
+        assert_eq!(summary.histogram[9], 2);
+        assert_eq!(summary.worst_performers.len(), 2);
+        assert_eq!(summary.worst_performers[0].id, "2");
+
+        storage.get_mut(&2).unwrap().private = true;
+        let summary = summarize(&storage, &query, 2, Visibility::Public);
+        assert_eq!(summary.min_score, Some(40));
+        assert_eq!(summary.worst_performers[0].id, "1");
+        let summary = summarize(&storage, &query, 2, Visibility::All);
+        assert_eq!(summary.worst_performers[0].id, "2");
+
+        storage.get_mut(&1).unwrap().network_type = Some(NetworkType::Cellular);
+        storage.get_mut(&3).unwrap().network_type = Some(NetworkType::Cellular);
+        let summary = summarize(&storage, &query, 2, Visibility::Public);
+        assert_eq!(
+            summary.network_types["cellular"],
+            GroupSummary {
+                runs: 2,
+                mean_score: 95,
+                median_score: 100,
+            }
+        );
+        assert_eq!(summary.network_types["undeclared"].runs, 1);
+        assert!(!summary.network_types.contains_key("wifi"));
+
+        storage.get_mut(&2).unwrap().client_version = Some("1.4.2".to_owned());
+        storage.get_mut(&3).unwrap().client_version = Some("1.5.0".to_owned());
+        let summary = summarize(&storage, &query, 2, Visibility::Public);
+        assert_eq!(summary.client_versions["1.4.2"].mean_score, 40);
+        assert_eq!(summary.client_versions["1.5.0"].mean_score, 100);
+        assert_eq!(summary.client_versions["undeclared"].runs, 1);
+    }
+}
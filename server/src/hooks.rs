@@ -0,0 +1,123 @@
+//! Custom processing of sessions by applications embedding the server, registered with
+//! `ServerContext::with_session_hook`
+
+use crate::measurements::SessionRejection;
+use crate::types::{ClientData, SessionParameters};
+
+use async_trait::async_trait;
+use std::net::SocketAddr;
+
+/// Invoked around every session, in order of registration. Both methods do nothing by default.
+#[async_trait]
+pub trait SessionHook: Send + Sync {
+    /// Called once server's own admission checks (draining, memory, rate limit) passed, before
+    /// the client takes a session slot. Tags of `session_parameters` can be changed, returning
+    /// `Err` rejects the client with it and later hooks aren't called.
+    async fn before_session(
+        &self,
+        client_id: u128,
+        remote: Option<SocketAddr>,
+        session_parameters: &mut SessionParameters,
+    ) -> Result<(), SessionRejection> {
+        let _ = (client_id, remote, session_parameters);
+        Ok(())
+    }
+
+    /// Called with the run of a measured session before it is stored under `client_id`, changes
+    /// to its tags are stored along with it. Not called for sessions which failed before scoring.
+    async fn after_session(&self, client_id: u128, run: &mut ClientData) {
+        let _ = (client_id, run);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{PlanConfig, ServerConfig};
+    use crate::hooks::SessionHook;
+    use crate::measurements::{measurement_routes, SessionRejection};
+    use crate::types::{ClientData, ServerContext, SessionParameters, Visibility};
+    use async_trait::async_trait;
+    use shared::{RejectionReason, SessionStatus};
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    /// Admits only clients tagged with their node and records which hook saw the run
+    struct NodeHook;
+
+    #[async_trait]
+    impl SessionHook for NodeHook {
+        async fn before_session(
+            &self,
+            _client_id: u128,
+            remote: Option<SocketAddr>,
+            session_parameters: &mut SessionParameters,
+        ) -> Result<(), SessionRejection> {
+            assert!(remote.is_some());
+            if !session_parameters.tags().contains_key("node") {
+                return Err(SessionRejection::new(
+                    RejectionReason::Banned,
+                    None,
+                    "Untagged clients aren't measured".to_owned(),
+                ));
+            }
+            session_parameters
+                .tags_mut()
+                .insert("admitted_by".to_owned(), "node_hook".to_owned());
+            Ok(())
+        }
+
+        async fn after_session(&self, _client_id: u128, run: &mut ClientData) {
+            let score = run.score().to_string();
+            run.tags_mut().insert("enriched_score".to_owned(), score);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_session_hooks() {
+        let config = Arc::new(ServerConfig {
+            plan: PlanConfig {
+                cpu_rounds: 1,
+                squarings: 1000,
+                network_rounds: 1,
+                payload_size_kb: 16,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let context = ServerContext::new(&config)
+            .unwrap()
+            .with_session_hook(Arc::new(NodeHook));
+        let (address, server) = warp::serve(measurement_routes(context.clone(), config))
+            .bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let options = |url: String| client::Options {
+            url,
+            with_proof: false,
+            disk_directory: std::env::temp_dir(),
+            client_token: None,
+            network_type: None,
+            client_version: None,
+        };
+
+        let error = client::measure(options(format!("ws://{}/ws", address)))
+            .await
+            .unwrap_err();
+        let rejected = error.downcast_ref::<client::Rejected>().unwrap();
+        assert_eq!(rejected.reason, RejectionReason::Banned);
+
+        let outcome = client::measure(options(format!("ws://{}/ws?tags=node:a", address)))
+            .await
+            .unwrap();
+        assert_eq!(outcome.status, SessionStatus::Completed);
+        let run_id = u128::from_str_radix(&outcome.stored_run_id.unwrap(), 16).unwrap();
+        let run = context
+            .storage
+            .get(run_id, Visibility::All)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(run.tags()["node"], "a");
+        assert_eq!(run.tags()["admitted_by"], "node_hook");
+        assert_eq!(run.tags()["enriched_score"], run.score().to_string());
+    }
+}
@@ -0,0 +1,155 @@
+//! Measures reliability of clients connected over websocket. The `server` binary runs it on its
+//! own, warp applications can embed it instead: build a `ServerContext` of a `ServerConfig` and
+//! mount `measurement_routes` (`/ws` and the configured endpoints) or `measurement_route` (no
+//! path of its own) next to their routes, along with `api_routes` if they serve the HTTP API
+//! and `time_route` if their clients follow the server's clock.
+
+// Futures of sessions nest deep within the filters of `measurement_routes`
+#![recursion_limit = "256"]
+
+#[macro_use]
+extern crate log;
+
+#[macro_use]
+mod utils;
+
+mod anomaly;
+mod api;
+#[cfg(feature = "event-bus")]
+mod bus;
+mod capacity;
+mod certificate;
+mod client_identity;
+mod clock;
+mod comparison;
+mod config;
+mod dead_letters;
+mod estimate;
+mod export;
+mod fleet;
+mod hooks;
+mod identity;
+mod measurements;
+mod metrics;
+mod policy;
+mod primes;
+mod progress;
+mod rate_limit;
+mod shutdown;
+mod signal;
+mod sizing;
+mod storage;
+mod tasks;
+mod tls;
+mod transport;
+mod tuning;
+mod types;
+mod verification;
+mod watchdog;
+
+pub use api::routes as api_routes;
+pub use clock::time_route;
+pub use config::{Args, EndpointConfig, PlanConfig, ServerConfig, TenantConfig};
+pub use hooks::SessionHook;
+pub use measurements::{
+    measurement_route, measurement_routes, ClientChallenger, DatagramEndpoint, SessionRejection,
+};
+pub use transport::{Keepalive, Transport, WebSocketTransport};
+pub use types::{ClientData, ServerContext, SessionParameters, Storage, Visibility};
+
+use config::Command;
+use futures::FutureExt;
+use std::sync::Arc;
+use tls::TlsIdentity;
+use tokio::net::TcpListener;
+use utils::routing::base_path;
+use warp::Filter;
+
+/// Runs the server of `args` until SIGTERM or SIGINT, as the `server` binary does
+pub async fn run(mut args: Args) {
+    if let Some(Command::Estimate(profile)) = args.command.take() {
+        let config = ServerConfig::read(args).expect("Unable to load configuration");
+        let estimate = estimate::estimate(&config.plan, config.disk_challenge_rounds, &profile);
+        println!("{}", serde_json::to_string_pretty(&estimate).unwrap());
+        return;
+    }
+    let config = Arc::new(ServerConfig::load(args).expect("Unable to load configuration"));
+    let mut context = ServerContext::new(&config).expect("Unable to set up the server");
+    if let Some(udp_bind_address) = config.udp_bind_address {
+        let endpoint = DatagramEndpoint::bind(udp_bind_address)
+            .await
+            .expect("Unable to bind the UDP address");
+        context = context.with_datagram_endpoint(endpoint);
+    }
+
+    let api_routes = api_routes(context.clone(), config.clone());
+    let ws_route = measurement_routes(context.clone(), config.clone());
+    let time_route = time_route(config.clone());
+
+    let routes = base_path(&config.base_path).and(ws_route.or(time_route).or(api_routes));
+
+    // Server stops accepting connections on the signal, upgraded websockets aren't tracked
+    // by it, so sessions in progress are drained afterwards
+    let draining = context.draining.clone();
+    let shutdown = async move {
+        shutdown::termination_signal().await;
+        draining.start();
+    }
+    .boxed()
+    .shared();
+    if let Some(tcp_bind_address) = config.tcp_bind_address {
+        let listener = TcpListener::bind(tcp_bind_address)
+            .await
+            .expect("Unable to bind the TCP address");
+        tokio::spawn(measurements::serve_tcp(
+            context.clone(),
+            config.clone(),
+            listener,
+            shutdown.clone(),
+        ));
+    }
+    match &config.tls {
+        Some(tls_config) => {
+            let identity = TlsIdentity::load(tls_config).expect("Unable to load TLS certificate");
+            let listener = TcpListener::bind(config.bind_address)
+                .await
+                .expect("Unable to bind the address");
+            let cleartext = tls_config.cleartext_bind_address.map(|address| {
+                let (_, server) = warp::serve(routes.clone())
+                    .bind_with_graceful_shutdown(address, shutdown.clone());
+                server
+            });
+            let tls = tls::serve(routes, &identity, listener, shutdown);
+            let (served, _) = futures::join!(tls, async {
+                if let Some(cleartext) = cleartext {
+                    cleartext.await
+                }
+            });
+            served.expect("Unable to serve over TLS")
+        }
+        None => {
+            let (_, server) =
+                warp::serve(routes).bind_with_graceful_shutdown(config.bind_address, shutdown);
+            server.await
+        }
+    }
+
+    let abandoned = shutdown::drain(&context.active_sessions, config.shutdown_deadline).await;
+    if abandoned > 0 {
+        warn!(
+            "{} sessions didn't finish within the shutdown deadline and are lost",
+            abandoned
+        );
+    }
+    if context.pending_runs.len() > 0 {
+        context.pending_runs.persist(&*context.storage).await;
+        let lost = context.pending_runs.len();
+        if lost > 0 {
+            error!("{} runs couldn't be stored before exit and are lost", lost);
+        }
+    }
+    if let Err(e) = context.storage.flush().await {
+        error!("Unable to flush storage: {:?}", e);
+    }
+    info!("Server stopped");
+}
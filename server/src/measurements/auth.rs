@@ -0,0 +1,150 @@
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use shared::RejectionReason;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::identity::IdentityProvider;
+use crate::measurements::SessionRejection;
+
+/// Clients measured at `/ws` and `/ws/<name>`, without it anyone is measured
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct MeasurementAuthConfig {
+    /// Principal runs of each API key are stored with, keyed by the key
+    pub(crate) api_keys: HashMap<String, String>,
+    /// Bearer tokens of the `[oidc]` provider authenticate clients as their `sub` claim
+    pub(crate) tokens: bool,
+}
+
+/// What a client presented to authenticate: `x-api-key` and `Authorization: Bearer` headers, or
+/// `api_key` and `access_token` query parameters of browsers, which can't set headers of websockets
+#[derive(Clone, Default)]
+pub(crate) struct Credentials {
+    pub(crate) api_key: Option<String>,
+    pub(crate) token: Option<String>,
+}
+
+// Credentials are secrets, logging session parameters mustn't reveal them
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("api_key", &self.api_key.as_ref().map(|_| "..."))
+            .field("token", &self.token.as_ref().map(|_| "..."))
+            .finish()
+    }
+}
+
+pub(crate) struct MeasurementAuth {
+    config: MeasurementAuthConfig,
+    /// Verifier of tokens, `None` unless `config.tokens` is set
+    identity_provider: Option<Arc<IdentityProvider>>,
+}
+
+impl MeasurementAuth {
+    pub(crate) fn new(
+        config: MeasurementAuthConfig,
+        identity_provider: Option<Arc<IdentityProvider>>,
+    ) -> Result<Self> {
+        let identity_provider = match (config.tokens, identity_provider) {
+            (true, Some(identity_provider)) => Some(identity_provider),
+            (true, None) => return Err(anyhow!("Tokens need an [oidc] provider to verify them")),
+            (false, _) => None,
+        };
+        if config.api_keys.is_empty() && identity_provider.is_none() {
+            return Err(anyhow!("Neither API keys nor tokens would be accepted"));
+        }
+        Ok(MeasurementAuth {
+            config,
+            identity_provider,
+        })
+    }
+
+    /// Principal the client presenting `credentials` is measured as
+    pub(crate) fn authenticate(
+        &self,
+        credentials: &Credentials,
+        now: SystemTime,
+    ) -> Result<String, SessionRejection> {
+        if let Some(principal) = credentials
+            .api_key
+            .as_ref()
+            .and_then(|api_key| self.config.api_keys.get(api_key))
+        {
+            return Ok(principal.clone());
+        }
+        let error = match (&self.identity_provider, &credentials.token) {
+            (Some(identity_provider), Some(token)) => match identity_provider.subject(token, now) {
+                Ok(subject) => return Ok(subject),
+                Err(e) => e,
+            },
+            _ if credentials.api_key.is_some() => anyhow!("Unknown API key"),
+            _ => anyhow!("No credentials"),
+        };
+        info!("Refusing to measure an unauthenticated client: {}", error);
+        Err(SessionRejection::new(
+            RejectionReason::Unauthorized,
+            None,
+            "Server only measures authenticated clients".to_owned(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::identity::test_utils::{ec_key, identity_provider, sign, RS256_TOKEN};
+    use crate::measurements::auth::{Credentials, MeasurementAuth, MeasurementAuthConfig};
+    use std::sync::Arc;
+    use std::time::SystemTime;
+
+    #[test]
+    fn test_authenticate() {
+        let mut config = MeasurementAuthConfig::default();
+        assert!(MeasurementAuth::new(config.clone(), None).is_err());
+        config.tokens = true;
+        assert!(MeasurementAuth::new(config.clone(), None).is_err());
+
+        config
+            .api_keys
+            .insert("k3y".to_owned(), "fleet-eu".to_owned());
+        let key = ec_key();
+        let auth =
+            MeasurementAuth::new(config, Some(Arc::new(identity_provider(&key, false)))).unwrap();
+        let now = SystemTime::now();
+        let authenticate = |api_key: Option<&str>, token: Option<&str>| {
+            auth.authenticate(
+                &Credentials {
+                    api_key: api_key.map(str::to_owned),
+                    token: token.map(str::to_owned),
+                },
+                now,
+            )
+            .ok()
+        };
+
+        assert_eq!(authenticate(Some("k3y"), None).as_deref(), Some("fleet-eu"));
+        assert_eq!(
+            authenticate(None, Some(RS256_TOKEN)).as_deref(),
+            Some("alice")
+        );
+        // Unknown keys fall back to the token
+        assert_eq!(
+            authenticate(Some("other"), Some(RS256_TOKEN)).as_deref(),
+            Some("alice")
+        );
+        assert_eq!(authenticate(Some("other"), None), None);
+        assert_eq!(authenticate(None, None), None);
+        let expired = sign(
+            &key,
+            serde_json::json!({
+                "iss": "https://sso.example.com",
+                "aud": "reliability-api",
+                "sub": "bob",
+                "exp": 1,
+            }),
+        );
+        assert_eq!(authenticate(None, Some(&expired)), None);
+    }
+}
@@ -1,94 +1,1200 @@
-use std::time::Instant;
-
 use anyhow::{anyhow, Result};
 use futures::{SinkExt, StreamExt};
-use shared::{Challenge, Data, Message};
-use warp::ws::WebSocket;
+use shared::{
+    Challenge, ChallengeReport, Data, ErrorCode, MeasurementReport, Message, NetworkType,
+    ProgressUpdate, Response, ScoreBreakdown,
+};
 
+use crate::config::PlanConfig;
+use crate::measurements::datagram::DatagramEndpoint;
+use crate::measurements::handshake::{
+    perform_handshake, ClientRequirements, NegotiatedParameters, UnsupportedClient,
+};
 use crate::measurements::helpers::{
+    challenge_message, cpu_challenge_answer, verify_chunk_proof_response,
     verify_cpu_challenge_response, verify_network_challenge_response,
+    verify_spot_checked_network_challenge_response, NetworkVerification,
+};
+use crate::measurements::memory::{message_size, MemoryBudget, MemoryLimitExceeded};
+use crate::measurements::plausibility::{implausible_round, ImplausibleRound, PlausibilityBounds};
+use crate::measurements::plugins::{
+    completed_event, registered_plugins, ChallengePlugin, PluginScoring, RoundMode,
+};
+use crate::measurements::retry::RetryBudget;
+use crate::measurements::score::{
+    calculate_score, calibrated_squarings, correct_for_contention, cpu_round_squarings_per_second,
+    find_mean, network_round_mbps, normalize_cpu_round, round_score, Aggregation, ChallengeResults,
+    Scale, ScoreError, ScoringModel, Strictness,
+};
+use crate::measurements::surprise::{cpu_slowdown, SurpriseKind, SurpriseRound, SurpriseSchedule};
+use crate::tasks::TaskKind;
+use crate::transport::{
+    AuthenticatedTransport, MessageAuthentication, SessionAuthenticator, Transport,
 };
-use crate::measurements::score::calculate_score;
-use crate::types::{ClientData, Storage, WsMessage};
-use crate::utils::send_client_msg_with_profiling;
+use crate::types::{
+    ClientData, DurationBreakdown, RunConfiguration, RunStatus, ServerContext, SessionClock,
+    SessionEvent, SessionEventKind, SessionEvents, SessionParameters, Visibility,
+};
+use crate::utils::{
+    discard_late_response, is_disconnection, receive_client_msg,
+    send_chunked_client_msg_with_profiling, send_client_msg_with_profiling, ConnectionLost,
+    ProtocolViolation, ResponseTimedOut, MAX_CHUNKED_MESSAGE_BYTES,
+};
+use crate::verification::TaskTiming;
 use futures::stream::{SplitSink, SplitStream};
+use num_bigint::BigUint;
 use rand::rngs::OsRng;
-use rand::RngCore;
-use shared::challenges::roundtrip::Roundtrip;
-use shared::challenges::timelock::Timelock;
+use shared::challenges::bandwidth::ThroughputStats;
+use shared::challenges::datagram::{DatagramReceipt, DatagramStats};
+use shared::challenges::latency::{LatencyChallenge, LatencyStats};
+use shared::challenges::roundtrip::{RoundtripSession, RoundtripSpotVerifier, SpotCheck};
+use shared::challenges::timelock::{Timelock, TimelockFamily};
+use shared::hash::HashAlgorithm;
+use shared::{kinds, merkle, SessionLimits, SessionStatus};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 pub struct CPUChallengeConfiguration {
     pub squarings: u32,
+    pub batch_verification: bool,
     pub ideal_milliseconds: u128,
     pub max_milliseconds: u128,
+    pub aggregation: Aggregation,
+    pub scale: Scale,
+}
+
+/// Round preceding CPU rounds, scaling their squarings to take about `target_milliseconds`
+pub struct CpuCalibration {
+    pub squarings: u32,
+    pub target_milliseconds: u128,
+}
+
+/// How server verifies data returned by the client in network challenge
+pub enum NetworkVerificationMode {
+    /// Hash all of the returned data and compare it with hash of the original data
+    FullHash,
+    /// Compare client supplied merkle root and `sample_chunks` distinct chunks of the returned
+    /// data, drawn at random weighted by their length, with the original data. Useful for very
+    /// large payloads.
+    SpotCheck { sample_chunks: usize },
 }
 
 pub struct NetworkChallengeConfiguration {
     pub data_size_kb: usize,
     pub ideal_milliseconds: u128,
     pub max_milliseconds: u128,
+    pub verification_mode: NetworkVerificationMode,
+    pub aggregation: Aggregation,
+    pub scale: Scale,
+}
+
+impl NetworkChallengeConfiguration {
+    /// Kind of the challenges sent, see `kinds::challenge`
+    pub(crate) fn kind_id(&self) -> u16 {
+        match self.verification_mode {
+            NetworkVerificationMode::FullHash => kinds::challenge::NETWORK_CHALLENGE,
+            NetworkVerificationMode::SpotCheck { .. } => {
+                kinds::challenge::SPOT_CHECKED_NETWORK_CHALLENGE
+            }
+        }
+    }
+}
+
+/// Retries of a network round whose returned data doesn't match, each with a fresh payload
+const NETWORK_ROUND_RETRIES: usize = 1;
+
+/// Time to wait for the close frame to be sent at the end of a session
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Attempts to store a run before it is left to be retried in the background
+const STORE_ATTEMPTS: u32 = 3;
+
+/// Wait before the second attempt to store a run, doubled before each further one
+const STORE_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Time client has to answer a round of each challenge kind, a round
+/// which times out aborts the session
+pub struct RoundTimeouts {
+    pub cpu: Duration,
+    pub network: Duration,
+}
+
+/// Timings of a single challenge round
+struct RoundTiming {
+    /// Time taken by the client to respond
+    client_milliseconds: u128,
+    /// Time taken by the server to verify client's response
+    verification: TaskTiming,
+    /// Time taken by the server to generate the challenge
+    generation: TaskTiming,
+    /// Arrival, in microseconds since the round started, and size in bytes of each chunk of a
+    /// response client sent in chunks, empty if it was sent in a single frame
+    chunk_arrivals: Vec<(u128, usize)>,
+}
+
+impl RoundTiming {
+    /// Accounts time the server spent on the round, time of the client is
+    /// accounted by the caller as it depends on kind of the challenge
+    fn account_server_time(&self, breakdown: &mut DurationBreakdown) {
+        breakdown.queueing_microseconds +=
+            self.generation.queued_microseconds + self.verification.queued_microseconds;
+        breakdown.puzzle_generation_microseconds += self.generation.running_microseconds;
+        breakdown.verification_microseconds += self.verification.running_microseconds;
+    }
+}
+
+/// CPU rounds of a session whose puzzles share a modulus, so that their
+/// answers are verified with a single check once all of them are in
+struct CpuBatch {
+    family: Arc<TimelockFamily>,
+    /// Rounds whose answers are deferred to the batch check
+    rounds: Vec<BatchedRound>,
+    answers: Vec<(Timelock, BigUint)>,
+}
+
+/// CPU round whose answer is deferred to the batch check, it's scored until the check fails
+struct BatchedRound {
+    index: usize,
+    milliseconds: u128,
+    /// Position of its time in `Measurement::cpu_results`
+    result: usize,
+}
+
+/// Puzzle a CPU round is sent
+enum CpuRound<'a> {
+    /// Puzzle of its own modulus
+    Single { squarings: u32 },
+    /// Puzzle of round `index`, generated from the modulus of `batch`. Its time is scored as
+    /// result `result` of the session.
+    Batched {
+        index: usize,
+        result: usize,
+        batch: &'a mut CpuBatch,
+    },
+}
+
+/// What a session measured so far, kept for a partial run should a later round fail it
+struct Measurement {
+    clock: SessionClock,
+    events: SessionEvents,
+    budget: MemoryBudget,
+    duration_breakdown: DurationBreakdown,
+    /// Rounds completed, whether they were scored or not
+    runs_completed: u32,
+    /// Round the session is at, which it failed at should it fail
+    failed_at: RunStatus,
+    /// Squarings of CPU rounds, those of the plan unless the session was calibrated
+    cpu_squarings: u32,
+    calibration_milliseconds: Option<u128>,
+    // Rounds which failed are only scored in audit-only sessions, see `round_failed`
+    cpu_results: Vec<u128>,
+    cpu_verification_timings: Vec<u128>,
+    network_results: Vec<u128>,
+    network_verification_timings: Vec<u128>,
+    network_round_overlapping_transfers: Vec<usize>,
+    network_chunk_arrivals: Vec<Vec<(u128, usize)>>,
+    latency_probe_timings: Vec<u128>,
+    datagram: Option<DatagramStats>,
+    /// Time of each round of plugin kinds and of its verification, keyed by kind id
+    plugin_results: HashMap<u16, (Vec<u128>, Vec<u128>)>,
+    implausible_rounds: Vec<ImplausibleRound>,
+    retries: RetryBudget,
+    surprises: SurpriseSchedule,
+    surprise_rounds: Vec<SurpriseRound>,
+}
+
+impl Measurement {
+    /// Times of the scored rounds of the plugin of `kind_id`
+    fn plugin_rounds(&self, kind_id: u16) -> &[u128] {
+        self.plugin_results
+            .get(&kind_id)
+            .map_or(&[], |(results, _)| results)
+    }
+
+    /// Logs a round which failed with `e` and is tried again, and adds it to events of the session
+    fn record_retried_round(
+        &mut self,
+        client_id: u128,
+        kind_id: u16,
+        round: usize,
+        e: &anyhow::Error,
+    ) {
+        warn!(
+            "Retrying round {} of challenge kind {} of client {:x}: {}",
+            round, kind_id, client_id, e
+        );
+        self.events.push(SessionEvent {
+            timestamp: self.clock.now(),
+            kind: SessionEventKind::RoundRetried { kind_id, round },
+        });
+    }
+
+    /// Logs a round answered faster than possible and adds it to events of the session
+    fn record_implausible_round(&mut self, client_id: u128, implausible: ImplausibleRound) {
+        warn!(
+            "Client {:x} answered round {} of challenge kind {} in {}ms, at least {}ms are needed",
+            client_id,
+            implausible.round,
+            implausible.kind_id,
+            implausible.milliseconds,
+            implausible.min_milliseconds
+        );
+        self.events.push(SessionEvent {
+            timestamp: self.clock.now(),
+            kind: SessionEventKind::RoundImplausible {
+                kind_id: implausible.kind_id,
+                round: implausible.round,
+                milliseconds: implausible.milliseconds,
+                min_milliseconds: implausible.min_milliseconds,
+            },
+        });
+        self.implausible_rounds.push(implausible);
+    }
+}
+
+/// Wire format carries 64 bit values, which are plenty for milliseconds and scores
+fn saturate(value: u128) -> u64 {
+    u64::try_from(value).unwrap_or(u64::MAX)
+}
+
+/// Time spent running `work` on the current thread
+fn timed<T>(work: impl FnOnce() -> T) -> (T, TaskTiming) {
+    let started = Instant::now();
+    let result = work();
+    let timing = TaskTiming {
+        queued_microseconds: 0,
+        running_microseconds: started.elapsed().as_micros(),
+    };
+    (result, timing)
 }
 
-struct ClientChallenger {
-    pub cpu_challenge_config: CPUChallengeConfiguration,
-    pub network_challenge_config: NetworkChallengeConfiguration,
-    pub number_of_cpu_challenge: usize,
-    pub number_of_network_challenge: usize,
+/// Measures a single client over its websocket with the challenges of a plan
+pub struct ClientChallenger {
+    pub(crate) plan_name: String,
+    pub(crate) cpu_challenge_config: CPUChallengeConfiguration,
+    pub(crate) network_challenge_config: NetworkChallengeConfiguration,
+    pub(crate) cpu_calibration: Option<CpuCalibration>,
+    /// Whether network round times are corrected for transfers of other sessions overlapping them
+    pub(crate) contention_correction: bool,
+    /// Squarings rate thresholds CPU rounds are scored with, `None` if they are scored on time
+    pub(crate) cpu_ideal_squarings_per_second: Option<f64>,
+    pub(crate) cpu_min_squarings_per_second: Option<f64>,
+    /// Throughput thresholds network rounds are scored with, `None` if they are scored on time
+    pub(crate) network_ideal_mbps: Option<f64>,
+    pub(crate) network_min_mbps: Option<f64>,
+    /// Plans with network thresholds of their own for clients declaring these network types
+    pub(crate) network_type_plans: HashMap<NetworkType, PlanConfig>,
+    pub(crate) scoring_model: ScoringModel,
+    pub(crate) number_of_cpu_challenge: usize,
+    pub(crate) number_of_network_challenge: usize,
+    pub(crate) number_of_latency_probes: usize,
+    /// Probes sent over UDP after latency probes, skipped without a datagram endpoint
+    pub(crate) number_of_datagram_probes: usize,
+    pub(crate) datagram_interval_milliseconds: u32,
+    pub(crate) round_timeouts: RoundTimeouts,
+    /// Kinds measured by generic rounds after latency probes, in order
+    pub(crate) plugins: Vec<ChallengePlugin>,
+    /// Hash algorithms offered to the client for roundtrip verification, in order of preference
+    pub(crate) hash_algorithms: Vec<HashAlgorithm>,
+    /// Largest frame bulk transfers are sent in to clients which support chunks
+    pub(crate) max_frame_size_kb: Option<usize>,
+    /// Gap between chunks of a network round counted as a stall
+    pub(crate) network_stall_milliseconds: u64,
+    /// Payload size of the plan when memory pressure made session use a smaller one
+    pub(crate) payload_scaled_down_from_kb: Option<usize>,
+    /// Whether clients which don't bind nonces into their responses are refused
+    pub(crate) require_nonces: bool,
+    pub(crate) message_authentication: MessageAuthentication,
+    /// Least time rounds take, ones answered faster score the run 0
+    pub(crate) plausibility_bounds: PlausibilityBounds,
+    /// Unannounced rounds sent between the scheduled CPU and network rounds
+    pub(crate) number_of_surprise_rounds: usize,
+    /// Squarings of surprise CPU rounds
+    pub(crate) surprise_squarings: u32,
+    /// Retries a session may spend on rounds which failed without breaking the connection
+    pub(crate) round_retries: usize,
+    /// Points each retry takes off the score
+    pub(crate) retry_penalty: f64,
+    /// Whether sessions which fail after completing some rounds store a partial run
+    pub(crate) persist_partial_runs: bool,
+    /// Points each round a partial run didn't complete takes off its score, `None` scores it 0
+    pub(crate) partial_run_penalty: Option<f64>,
+    pub(crate) context: ServerContext,
+    pub(crate) session_parameters: SessionParameters,
 }
 
 impl ClientChallenger {
-    fn determine_score(&self, cpu_results: &Vec<u128>, network_results: &Vec<u128>) -> u128 {
-        calculate_score(
-            &self.cpu_challenge_config,
-            cpu_results,
-            &self.network_challenge_config,
-            network_results,
+    /// Run this session is paired with as its dual-stack counterpart. Pairing is only kept
+    /// if that run is stored, not paired itself and was measured over the other IP version.
+    async fn dual_stack_of(&self, client_id: u128) -> Option<u128> {
+        let run_id = self.session_parameters.dual_stack_of?;
+        let address_family = self.session_parameters.address_family;
+        self.paired_run(client_id, run_id, "over the other IP version", |run| {
+            run.dual_stack_of.is_none()
+                && run.address_family.zip(address_family).is_some_and(
+                    |(run_address_family, address_family)| run_address_family != address_family,
+                )
+        })
+        .await
+    }
+
+    /// Run this session is compared with to find the TLS overhead. Pairing is only kept if that
+    /// run is stored, not paired itself and exactly one of the two runs was measured over TLS.
+    async fn tls_comparison_of(&self, client_id: u128) -> Option<u128> {
+        let run_id = self.session_parameters.tls_comparison_of?;
+        let encrypted = self.session_parameters.tls_handshake.is_some();
+        self.paired_run(
+            client_id,
+            run_id,
+            "with the other transport security",
+            |run| {
+                run.tls_comparison_of.is_none()
+                    && run.tls_handshake_milliseconds.is_some() != encrypted
+            },
         )
+        .await
     }
 
-    /// Performs cpu challenge as per the configuration and
-    /// returns time elapsed
-    async fn perform_cpu_challenge<RNG>(
+    /// `run_id` if that run is stored, completed and `pairs` with it, `measured` tells how it
+    /// should have been measured when it doesn't
+    async fn paired_run(
         &self,
-        rng: &mut RNG,
         client_id: u128,
-        writer: &mut SplitSink<WebSocket, WsMessage>,
-        reader: &mut SplitStream<WebSocket>,
-    ) -> Result<u128>
+        run_id: u128,
+        measured: &str,
+        pairs: impl FnOnce(&ClientData) -> bool,
+    ) -> Option<u128> {
+        let run = match self.context.storage.get(run_id, Visibility::All).await {
+            Ok(run) => run,
+            Err(e) => {
+                warn!(
+                    "Unable to look up run {:x} to pair run {:x} with: {:?}",
+                    run_id, client_id, e
+                );
+                return None;
+            }
+        };
+        // Runs of other tenants aren't disclosed to the client by pairing with them
+        if run.as_ref().is_some_and(|run| {
+            run.is_completed() && run.tenant == self.session_parameters.tenant && pairs(run)
+        }) {
+            Some(run_id)
+        } else {
+            warn!(
+                "Run {:x} can't be paired with run {:x} measured {}",
+                client_id, run_id, measured
+            );
+            None
+        }
+    }
+
+    /// Stores the run, retrying failures of storage. Run storage still refuses after the last
+    /// attempt is kept pending and retried in the background. Returns whether it was stored.
+    async fn store(&self, client_id: u128, data: ClientData) -> bool {
+        let mut delay = STORE_RETRY_DELAY;
+        for attempt in 1..=STORE_ATTEMPTS {
+            match self.context.storage.insert(client_id, data.clone()).await {
+                Ok(()) => return true,
+                Err(e) => warn!(
+                    "Attempt {} to store run {:x} failed: {:?}",
+                    attempt, client_id, e
+                ),
+            }
+            if attempt < STORE_ATTEMPTS {
+                tokio::time::delay_for(delay).await;
+                delay *= 2;
+            }
+        }
+        self.context.pending_runs.add(client_id, data);
+        false
+    }
+
+    fn effective_configuration(
+        &self,
+        negotiated: &NegotiatedParameters,
+        calibrated_squarings: Option<u32>,
+    ) -> RunConfiguration {
+        let disk = self
+            .plugin_scoring(kinds::challenge::DISK_CHALLENGE)
+            .cloned()
+            .unwrap_or_default();
+        RunConfiguration {
+            plan_name: self.plan_name.clone(),
+            cpu_rounds: self.number_of_cpu_challenge,
+            squarings: self.cpu_challenge_config.squarings,
+            cpu_ideal_milliseconds: self.cpu_challenge_config.ideal_milliseconds,
+            cpu_max_milliseconds: self.cpu_challenge_config.max_milliseconds,
+            cpu_aggregation: self.cpu_challenge_config.aggregation,
+            cpu_ideal_squarings_per_second: self.cpu_ideal_squarings_per_second,
+            cpu_min_squarings_per_second: self.cpu_min_squarings_per_second,
+            calibrated_squarings,
+            network_rounds: self.number_of_network_challenge,
+            payload_size_kb: self.network_challenge_config.data_size_kb,
+            network_ideal_milliseconds: self.network_challenge_config.ideal_milliseconds,
+            network_max_milliseconds: self.network_challenge_config.max_milliseconds,
+            network_aggregation: self.network_challenge_config.aggregation,
+            network_ideal_mbps: self.network_ideal_mbps,
+            network_min_mbps: self.network_min_mbps,
+            payload_scaled_down_from_kb: self.payload_scaled_down_from_kb,
+            spot_check_sample_chunks: match self.network_challenge_config.verification_mode {
+                NetworkVerificationMode::FullHash => None,
+                NetworkVerificationMode::SpotCheck { sample_chunks } => Some(sample_chunks),
+            },
+            contention_correction: self.contention_correction,
+            disk_rounds: self.plugin_rounds(kinds::challenge::DISK_CHALLENGE),
+            disk_payload_size_kb: disk.data_size_kb,
+            disk_ideal_milliseconds: disk.ideal_milliseconds,
+            disk_max_milliseconds: disk.max_milliseconds,
+            disk_aggregation: disk.aggregation,
+            scoring: self.scoring_model,
+            strictness: self.session_parameters.strictness,
+            latency_probes: self.number_of_latency_probes,
+            datagram_probes: self.datagram_probes(),
+            download_rounds: self.plugin_rounds(kinds::challenge::DOWNLOAD_CHALLENGE),
+            upload_rounds: self.plugin_rounds(kinds::challenge::UPLOAD_CHALLENGE),
+            hash_algorithm: negotiated.hash_algorithm,
+            max_frame_size_kb: self
+                .max_frame_size_kb
+                .filter(|_| negotiated.max_frame_bytes.is_some()),
+            network_stall_milliseconds: self.network_stall_milliseconds,
+            encoding: "messagepack".to_owned(),
+            transport: "websocket".to_owned(),
+        }
+    }
+
+    /// Scores network rounds with the thresholds the plan has for `network_type`, if any
+    fn score_network_type(&mut self, network_type: NetworkType) {
+        let plan = match self.network_type_plans.get(&network_type) {
+            Some(plan) => plan,
+            None => return,
+        };
+        let (ideal_milliseconds, max_milliseconds) =
+            plan.network_thresholds(self.network_challenge_config.data_size_kb);
+        self.network_challenge_config.ideal_milliseconds = ideal_milliseconds;
+        self.network_challenge_config.max_milliseconds = max_milliseconds;
+        self.network_challenge_config.scale = plan.network_scale();
+        self.network_ideal_mbps = plan.network_ideal_mbps;
+        self.network_min_mbps = plan.network_min_mbps;
+        self.round_timeouts.network = plan.network_round_timeout();
+    }
+
+    /// Datagram probes of the session, none if server has no endpoint to send them from
+    fn datagram_probes(&self) -> usize {
+        match self.context.datagram_endpoint {
+            Some(_) => self.number_of_datagram_probes,
+            None => 0,
+        }
+    }
+
+    fn network_challenge_kind(&self) -> u16 {
+        self.network_challenge_config.kind_id()
+    }
+
+    fn session_limits(&self) -> SessionLimits {
+        let network_challenge_kind = self.network_challenge_kind();
+        // Only kinds the client will actually be sent
+        let challenge_kinds = [
+            (
+                self.number_of_cpu_challenge,
+                kinds::challenge::CPU_CHALLENGE,
+            ),
+            (self.number_of_network_challenge, network_challenge_kind),
+            (
+                self.number_of_latency_probes,
+                kinds::challenge::LATENCY_PROBE,
+            ),
+            (self.datagram_probes(), kinds::challenge::DATAGRAM_CHALLENGE),
+        ]
+        .iter()
+        .copied()
+        .chain(
+            self.plugins
+                .iter()
+                .map(|plugin| (plugin.rounds, plugin.generator.kind_id())),
+        )
+        .filter(|(rounds, _)| *rounds > 0)
+        .map(|(_, kind)| kind)
+        .collect();
+        SessionLimits {
+            max_payload_size_kb: self
+                .plugins
+                .iter()
+                .filter_map(|plugin| plugin.scoring.as_ref())
+                .map(|scoring| scoring.data_size_kb)
+                .fold(self.network_challenge_config.data_size_kb, std::cmp::max)
+                as u64,
+            cpu_rounds: self.number_of_cpu_challenge as u32,
+            network_rounds: self.number_of_network_challenge as u32,
+            disk_rounds: self.plugin_rounds(kinds::challenge::DISK_CHALLENGE) as u32,
+            challenge_kinds,
+            encodings: vec!["messagepack".to_owned()],
+            cpu_round_timeout_milliseconds: saturate(self.round_timeouts.cpu.as_millis()),
+            network_round_timeout_milliseconds: saturate(self.round_timeouts.network.as_millis()),
+            latency_probes: self.number_of_latency_probes as u32,
+            datagram_probes: self.datagram_probes() as u32,
+            download_rounds: self.plugin_rounds(kinds::challenge::DOWNLOAD_CHALLENGE) as u32,
+            upload_rounds: self.plugin_rounds(kinds::challenge::UPLOAD_CHALLENGE) as u32,
+            max_frame_size_kb: self.max_frame_size_kb.map(|kb| kb as u64),
+        }
+    }
+
+    /// Results of each scored challenge kind, along with the size reported with its sub-score
+    fn challenge_results<'a>(
+        &'a self,
+        measurement: &'a Measurement,
+    ) -> Vec<(ChallengeResults<'a>, usize)> {
+        let strictness = self.session_parameters.strictness;
+        let mut challenges = vec![
+            (
+                ChallengeResults::cpu(&self.cpu_challenge_config, &measurement.cpu_results),
+                self.cpu_challenge_config.squarings as usize,
+            ),
+            (
+                ChallengeResults::network(
+                    &self.network_challenge_config,
+                    &measurement.network_results,
+                ),
+                self.network_challenge_config.data_size_kb,
+            ),
+        ];
+        challenges.extend(self.plugins.iter().filter_map(|plugin| {
+            let results = plugin.results(measurement.plugin_rounds(plugin.generator.kind_id()))?;
+            Some((results, plugin.scoring.as_ref()?.data_size_kb))
+        }));
+        challenges
+            .into_iter()
+            .map(|(challenge, size)| (challenge.with_strictness(strictness), size))
+            .collect()
+    }
+
+    fn determine_score(&self, measurement: &Measurement) -> Result<ScoreBreakdown, ScoreError> {
+        let challenges: Vec<_> = self
+            .challenge_results(measurement)
+            .into_iter()
+            .map(|(challenge, _)| challenge)
+            .collect();
+        calculate_score(&self.scoring_model, &challenges)
+    }
+
+    fn measurement_report(
+        &self,
+        client_id: u128,
+        score_breakdown: ScoreBreakdown,
+        hash_algorithm: HashAlgorithm,
+        measurement: &Measurement,
+    ) -> Result<MeasurementReport, ScoreError> {
+        let mut challenges = Vec::new();
+        for (challenge, size) in self.challenge_results(measurement) {
+            if let Some(sub_score) = challenge.sub_score()? {
+                challenges.push(ChallengeReport {
+                    kind_id: challenge.kind_id(),
+                    round_milliseconds: challenge
+                        .rounds()
+                        .iter()
+                        .map(|round| saturate(*round))
+                        .collect(),
+                    sub_score: saturate(sub_score),
+                    ideal_milliseconds: saturate(challenge.ideal_milliseconds),
+                    max_milliseconds: saturate(challenge.max_milliseconds),
+                    size: size as u64,
+                });
+            }
+        }
+
+        Ok(MeasurementReport {
+            session_id: format!("{:x}", client_id),
+            score: saturate(round_score(score_breakdown.total)),
+            hash_algorithm,
+            challenges,
+            latency: LatencyStats::from_round_trips(
+                &measurement
+                    .latency_probe_timings
+                    .iter()
+                    .map(|round_trip| saturate(*round_trip))
+                    .collect::<Vec<_>>(),
+            ),
+            download: self
+                .throughput(measurement.plugin_rounds(kinds::challenge::DOWNLOAD_CHALLENGE)),
+            upload: self.throughput(measurement.plugin_rounds(kinds::challenge::UPLOAD_CHALLENGE)),
+            score_breakdown,
+            tls_handshake_milliseconds: self
+                .session_parameters
+                .tls_handshake
+                .map(|handshake| saturate(handshake.as_millis())),
+            datagram: measurement.datagram.clone(),
+        })
+    }
+
+    fn throughput(&self, rounds: &[u128]) -> Option<ThroughputStats> {
+        ThroughputStats::new(
+            self.network_challenge_config.data_size_kb as u64,
+            rounds.iter().map(|round| saturate(*round)).collect(),
+        )
+    }
+
+    /// Sends client running aggregates of the challenge kind whose round just completed
+    async fn send_progress<T: Transport>(
+        &self,
+        writer: &mut SplitSink<T, Vec<u8>>,
+        kind_id: u16,
+        results: ChallengeResults<'_>,
+        rounds_completed: u32,
+    ) -> Result<()> {
+        let results = results.with_strictness(self.session_parameters.strictness);
+        let rounds = results.rounds();
+        let progress = ProgressUpdate {
+            kind_id,
+            rounds_completed,
+            total_rounds: self.scored_rounds() as u32,
+            round_milliseconds: saturate(rounds.last().copied().unwrap_or_default()),
+            mean_milliseconds: saturate(find_mean(rounds).unwrap_or_default()),
+            sub_score: saturate(results.sub_score()?.unwrap_or_default()),
+        };
+        writer.send(Message::Progress(progress).encode()?).await?;
+        Ok(())
+    }
+
+    /// Runs `verification` on the verification pool, listed as a task of the session
+    async fn verify<F, T>(&self, client_id: u128, verification: F) -> Result<(T, TaskTiming)>
     where
-        RNG: RngCore,
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
     {
-        let start = Instant::now();
-        let (timelock, timelock_verifier) =
-            Timelock::generate(rng, self.cpu_challenge_config.squarings);
-        let time_passed = start.elapsed().as_millis();
+        let _task = self
+            .context
+            .tasks
+            .enter(TaskKind::Verification, format!("session {:x}", client_id));
+        self.context
+            .verification_pool
+            .verify(self.session_parameters.priority_class, verification)
+            .await
+    }
+
+    /// Runs puzzle generation on the verification pool, listed as a task of the session
+    async fn generate<F, T>(&self, client_id: u128, generation: F) -> Result<(T, TaskTiming)>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let _task = self.context.tasks.enter(
+            TaskKind::PuzzleGeneration,
+            format!("session {:x}", client_id),
+        );
+        self.context
+            .verification_pool
+            .run(self.session_parameters.priority_class, generation)
+            .await
+    }
+
+    /// Sends a challenge transferring bulk data, in chunks if negotiated, and awaits its response
+    /// within the network round timeout. Returns the response, time elapsed, number of transfers
+    /// of other sessions which overlapped it, see `ActiveTransfers`, and arrival and size of each
+    /// chunk of the response if client sent it in chunks.
+    async fn transfer<T: Transport>(
+        &self,
+        writer: &mut SplitSink<T, Vec<u8>>,
+        reader: &mut SplitStream<T>,
+        encoded_challenge_msg: Vec<u8>,
+        negotiated: &NegotiatedParameters,
+    ) -> Result<(Message, u128, usize, Vec<(u128, usize)>)> {
+        let transfer = self.context.active_transfers.enter().await;
+        let (client_response, time_elapsed, chunk_arrivals) =
+            send_chunked_client_msg_with_profiling(
+                writer,
+                reader,
+                encoded_challenge_msg,
+                negotiated.max_frame_bytes,
+                true,
+                self.round_timeouts.network,
+                self.session_parameters
+                    .memory_limit_bytes
+                    .unwrap_or(MAX_CHUNKED_MESSAGE_BYTES),
+            )
+            .await?;
+        Ok((
+            client_response,
+            time_elapsed,
+            transfer.overlapping(),
+            chunk_arrivals,
+        ))
+    }
+
+    /// Asks the client to send chunks of a spot checked round which came back corrupted again,
+    /// each with its proof against the merkle root it returned, instead of repeating the whole
+    /// round. Returns whether all of them match, and the time it took in milliseconds.
+    async fn retransmit_chunks<T: Transport>(
+        &self,
+        writer: &mut SplitSink<T, Vec<u8>>,
+        reader: &mut SplitStream<T>,
+        roundtrip_verifier: &RoundtripSpotVerifier,
+        indices: Vec<usize>,
+    ) -> Result<(NetworkVerification, u128)> {
+        let mut retransmission_time = 0;
+        for index in indices {
+            let request = Challenge::ChunkProofRequest(merkle::encode_chunk_index(index as u32));
+            let (client_response, time_elapsed) = send_client_msg_with_profiling(
+                writer,
+                reader,
+                Message::Challenge(request).encode()?.as_slice(),
+                true,
+                self.round_timeouts.network,
+            )
+            .await?;
+            retransmission_time += time_elapsed;
+            if !verify_chunk_proof_response(roundtrip_verifier, index, client_response) {
+                return Ok((NetworkVerification::Mismatch, retransmission_time));
+            }
+        }
+        Ok((NetworkVerification::Verified, retransmission_time))
+    }
+
+    /// Sends the latency probes one after another, each once the previous one came back,
+    /// and returns round trip time of each in microseconds. Probes which don't come back intact
+    /// are left out unless the session is strict.
+    async fn perform_latency_challenge<T: Transport>(
+        &self,
+        client_id: u128,
+        writer: &mut SplitSink<T, Vec<u8>>,
+        reader: &mut SplitStream<T>,
+        events: &mut SessionEvents,
+        clock: &SessionClock,
+    ) -> Result<Vec<u128>> {
+        let challenge =
+            LatencyChallenge::generate(&mut OsRng, self.number_of_latency_probes as u32);
+        // Probes are stamped relative to this, which the client can't observe
+        let started = Instant::now();
+        let mut round_trips = Vec::with_capacity(self.number_of_latency_probes);
+        for sequence in 0..challenge.probes() {
+            let probed_at = started.elapsed().as_micros();
+            let probe = challenge.probe(sequence, saturate(probed_at));
+            let encoded_challenge_msg =
+                Message::Challenge(Challenge::LatencyProbe(probe)).encode()?;
+            let (client_response, _) = send_client_msg_with_profiling(
+                writer,
+                reader,
+                encoded_challenge_msg.as_slice(),
+                true,
+                self.round_timeouts.network,
+            )
+            .await?;
+            let received_at = started.elapsed().as_micros();
+            let sent_at = match &client_response {
+                Message::Response(Response::LatencyProbeResponse(echoed)) => {
+                    challenge.verify(sequence, echoed)
+                }
+                _ => None,
+            };
+            match sent_at {
+                Some(sent_at) => round_trips.push(received_at.saturating_sub(sent_at.into())),
+                None => {
+                    info!(
+                        "Client {:x} did not echo latency probe {}",
+                        client_id, sequence
+                    );
+                    // Without the probe the round trip can't be told, so it is never scored
+                    self.round_failed("Latency", client_id, writer).await?;
+                    events.push(SessionEvent {
+                        timestamp: clock.now(),
+                        kind: SessionEventKind::RoundFailed {
+                            kind_id: kinds::challenge::LATENCY_PROBE,
+                            round: sequence as usize,
+                            milliseconds: received_at.saturating_sub(probed_at) / 1000,
+                            scored: false,
+                        },
+                    });
+                }
+            }
+        }
+        Ok(round_trips)
+    }
+
+    /// Sends a surprise round of `kind` and returns its kind id and time in microseconds, `None` if
+    /// the answer failed verification in a session which isn't strict
+    async fn perform_surprise_round<T: Transport>(
+        &self,
+        kind: SurpriseKind,
+        client_id: u128,
+        negotiated: &NegotiatedParameters,
+        writer: &mut SplitSink<T, Vec<u8>>,
+        reader: &mut SplitStream<T>,
+        budget: &mut MemoryBudget,
+    ) -> Result<Option<(u16, u128)>> {
+        match kind {
+            SurpriseKind::Cpu => {
+                let (verified, timing) = self
+                    .perform_cpu_challenge(
+                        client_id,
+                        CpuRound::Single {
+                            squarings: self.surprise_squarings,
+                        },
+                        negotiated,
+                        writer,
+                        reader,
+                        budget,
+                    )
+                    .await?;
+                if !verified {
+                    self.round_failed("CPU", client_id, writer).await?;
+                    return Ok(None);
+                }
+                Ok(Some((
+                    kinds::challenge::CPU_CHALLENGE,
+                    timing.client_milliseconds * 1000,
+                )))
+            }
+            SurpriseKind::Latency => {
+                let challenge = LatencyChallenge::generate(&mut OsRng, 1);
+                let started = Instant::now();
+                let probe = Message::Challenge(Challenge::LatencyProbe(challenge.probe(0, 0)));
+                let (client_response, _) = send_client_msg_with_profiling(
+                    writer,
+                    reader,
+                    probe.encode()?.as_slice(),
+                    true,
+                    self.round_timeouts.network,
+                )
+                .await?;
+                let round_trip = started.elapsed().as_micros();
+                match client_response {
+                    Message::Response(Response::LatencyProbeResponse(echoed))
+                        if challenge.verify(0, &echoed).is_some() =>
+                    {
+                        Ok(Some((kinds::challenge::LATENCY_PROBE, round_trip)))
+                    }
+                    _ => {
+                        self.round_failed("Latency", client_id, writer).await?;
+                        Ok(None)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends the datagram probes from `endpoint` and reads how many of them the client received.
+    /// Returns `None` if client can't send datagrams or none of its datagrams arrived.
+    async fn perform_datagram_challenge<T: Transport>(
+        &self,
+        client_id: u128,
+        endpoint: &Arc<DatagramEndpoint>,
+        writer: &mut SplitSink<T, Vec<u8>>,
+        reader: &mut SplitStream<T>,
+    ) -> Result<Option<DatagramStats>> {
+        let probes = self.datagram_probes() as u32;
+        let mut train = endpoint.open_train(probes, self.datagram_interval_milliseconds);
+        writer
+            .send(Message::Challenge(Challenge::DatagramChallenge(train.train.to_wire())).encode()?)
+            .await?;
+        let echoes = train.measure().await?;
+        drop(train);
+        // Client responds once it stopped echoing
+        let receipt = match receive_client_msg(writer, reader, self.round_timeouts.network).await? {
+            Message::Response(Response::DatagramChallengeResponse(receipt)) => {
+                DatagramReceipt::from_wire(&receipt)
+                    .map_err(|e| ProtocolViolation(e.to_string()))?
+            }
+            Message::Response(Response::UnsupportedChallenge { kind_id })
+                if kind_id == kinds::challenge::DATAGRAM_CHALLENGE =>
+            {
+                info!("Client {:x} can't send datagrams", client_id);
+                return Ok(None);
+            }
+            msg => {
+                return Err(ProtocolViolation(format!(
+                    "Expected response to datagram probes, got {}",
+                    msg
+                ))
+                .into())
+            }
+        };
+        match echoes {
+            Some(echoes) => Ok(Some(DatagramStats::new(
+                probes,
+                receipt.probes_received,
+                &echoes,
+            ))),
+            None => {
+                info!(
+                    "No datagram of client {:x} arrived, UDP may be blocked on the way",
+                    client_id
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    /// Performs a round of the plugin's challenge and returns whether the response answered it,
+    /// time elapsed along with time spent verifying the response
+    async fn perform_plugin_challenge<T: Transport>(
+        &self,
+        plugin: &ChallengePlugin,
+        negotiated: &NegotiatedParameters,
+        client_id: u128,
+        writer: &mut SplitSink<T, Vec<u8>>,
+        reader: &mut SplitStream<T>,
+        budget: &mut MemoryBudget,
+    ) -> Result<(bool, RoundTiming)> {
+        let name = plugin.generator.name();
+        let nonce = negotiated.challenge_nonce();
+        let ((challenge, verifier), generation) = timed(|| {
+            plugin
+                .generator
+                .generate(&mut OsRng, negotiated.hash_algorithm, &nonce)
+        });
+        let encoded_challenge_msg = challenge_message(challenge, nonce).encode()?;
+        // Generated data and the encoded challenge
+        let challenge_bytes = 2 * encoded_challenge_msg.len();
+        budget.allocate(challenge_bytes)?;
+
+        let (client_response, time_elapsed, chunk_arrivals) = match plugin.mode {
+            RoundMode::Transfer => {
+                let (client_response, time_elapsed, _, chunk_arrivals) = self
+                    .transfer(writer, reader, encoded_challenge_msg, negotiated)
+                    .await?;
+                (client_response, time_elapsed, chunk_arrivals)
+            }
+            // Client has to receive the challenge before working on it, so transfer is part of the timing
+            RoundMode::Compute { timeout } => {
+                let (client_response, time_elapsed) = send_client_msg_with_profiling(
+                    writer,
+                    reader,
+                    encoded_challenge_msg.as_slice(),
+                    true,
+                    timeout,
+                )
+                .await?;
+                (client_response, time_elapsed, Vec::new())
+            }
+        };
+        let response_bytes = message_size(&client_response);
+        budget.allocate(response_bytes)?;
+
+        let (verified, verification) = self
+            .verify(client_id, move || match client_response {
+                Message::Response(response) => verifier.verify(response),
+                _ => false,
+            })
+            .await?;
+        budget.release(challenge_bytes + response_bytes);
+
+        if verified {
+            info!(
+                "Successfully measured {} for client {:x}, time passed: {}ms",
+                name, client_id, time_elapsed
+            );
+        } else {
+            info!(
+                "Failed {} measurements for client {:x}, time passed: {}ms",
+                name, client_id, time_elapsed
+            );
+        }
+
+        Ok((
+            verified,
+            RoundTiming {
+                client_milliseconds: time_elapsed,
+                verification,
+                generation,
+                chunk_arrivals,
+            },
+        ))
+    }
+
+    /// Tells client its `name` measurements failed and returns the error failing the session
+    async fn challenge_failed<T: Transport>(
+        &self,
+        name: &str,
+        client_id: u128,
+        writer: &mut SplitSink<T, Vec<u8>>,
+    ) -> Result<anyhow::Error> {
+        writer
+            .send(
+                Message::Data(Data::Error {
+                    code: ErrorCode::ChallengeFailed,
+                    message: format!("Failed {} measurements (session {:x})", name, client_id),
+                })
+                .encode()?,
+            )
+            .await?;
+        Ok(anyhow!(
+            "{} measurement failed for client {:x}",
+            name,
+            client_id
+        ))
+    }
+
+    /// Handles a round of `name` measurements whose response failed verification as per
+    /// strictness of the session: strict sessions fail, others tell the client and go on.
+    /// Returns whether time of the round is scored nevertheless, which only audit-only sessions do.
+    async fn round_failed<T: Transport>(
+        &self,
+        name: &str,
+        client_id: u128,
+        writer: &mut SplitSink<T, Vec<u8>>,
+    ) -> Result<bool> {
+        let (scored, outcome) = match self.session_parameters.strictness {
+            Strictness::Strict => {
+                return Err(self.challenge_failed(name, client_id, writer).await?)
+            }
+            Strictness::Lenient => (false, "left out of the score"),
+            Strictness::AuditOnly => (true, "scored nevertheless"),
+        };
+        writer
+            .send(
+                Message::Data(Data::Info(format!(
+                    "Failed a round of {} measurements, {} (session {:x})",
+                    name, outcome, client_id
+                )))
+                .encode()?,
+            )
+            .await?;
+        Ok(scored)
+    }
+
+    /// Rounds of each challenge kind the session measures, plugin kinds included
+    fn round_totals(&self) -> HashMap<u16, usize> {
+        let mut rounds = HashMap::new();
+        rounds.insert(
+            kinds::challenge::CPU_CHALLENGE,
+            self.number_of_cpu_challenge,
+        );
+        rounds.insert(
+            kinds::challenge::NETWORK_CHALLENGE,
+            self.number_of_network_challenge,
+        );
+        rounds.insert(
+            kinds::challenge::LATENCY_PROBE,
+            self.number_of_latency_probes,
+        );
+        for plugin in &self.plugins {
+            *rounds.entry(plugin.generator.kind_id()).or_insert(0) += plugin.rounds;
+        }
+        rounds
+    }
+
+    /// Rounds of the plugin of `kind_id`, zero if it isn't registered
+    fn plugin_rounds(&self, kind_id: u16) -> usize {
+        self.plugins
+            .iter()
+            .filter(|plugin| plugin.generator.kind_id() == kind_id)
+            .map(|plugin| plugin.rounds)
+            .sum()
+    }
+
+    /// Thresholds of the plugin of `kind_id`, `None` if it isn't registered or isn't scored
+    fn plugin_scoring(&self, kind_id: u16) -> Option<&PluginScoring> {
+        self.plugins
+            .iter()
+            .find(|plugin| plugin.generator.kind_id() == kind_id)
+            .and_then(|plugin| plugin.scoring.as_ref())
+    }
+
+    /// Rounds counting towards the score, the client is sent progress of each
+    fn scored_rounds(&self) -> usize {
+        self.number_of_cpu_challenge
+            + self.number_of_network_challenge
+            + self
+                .plugins
+                .iter()
+                .filter(|plugin| plugin.scoring.is_some())
+                .map(|plugin| plugin.rounds)
+                .sum::<usize>()
+    }
+
+    /// Generates secret modulus of CPU challenge puzzles of `squarings`
+    async fn generate_timelock_family(
+        &self,
+        client_id: u128,
+        squarings: u32,
+    ) -> Result<(Arc<TimelockFamily>, TaskTiming)> {
+        let primes = self.context.primes.clone();
+        let (family, generation) = self
+            .generate(client_id, move || {
+                TimelockFamily::generate(&mut OsRng, squarings, &*primes, primes.bits())
+            })
+            .await?;
+        Ok((Arc::new(family?), generation))
+    }
+
+    /// Performs cpu challenge as per the configuration and returns whether the answer is right,
+    /// time elapsed along with time spent verifying the response.
+    /// A plain answer to a batched round is only recorded for the batch check instead of being verified.
+    async fn perform_cpu_challenge<T: Transport>(
+        &self,
+        client_id: u128,
+        round: CpuRound<'_>,
+        negotiated: &NegotiatedParameters,
+        writer: &mut SplitSink<T, Vec<u8>>,
+        reader: &mut SplitStream<T>,
+        budget: &mut MemoryBudget,
+    ) -> Result<(bool, RoundTiming)> {
+        let (family, mut generation) = match &round {
+            CpuRound::Batched { batch, .. } => (batch.family.clone(), TaskTiming::default()),
+            CpuRound::Single { squarings } => {
+                self.generate_timelock_family(client_id, *squarings).await?
+            }
+        };
+        let (timelock, puzzle_generation) = timed(|| family.puzzle(&mut OsRng));
+        generation.running_microseconds += puzzle_generation.running_microseconds;
+        let time_passed = generation.total_microseconds() / 1000;
         info!(
             "Internal: Generated CPU based puzzle in {}ms for client {:x}",
             time_passed, client_id
         );
 
+        // Client is sent the puzzle unbound and solves the one bound to the nonce
+        let nonce = negotiated.challenge_nonce();
         let challenge_msg = timelock.to_wire();
+        let timelock = timelock.bind(&nonce);
         let encoded_challenge_msg =
-            Message::Challenge(Challenge::CPUChallenge(challenge_msg)).encode()?;
+            challenge_message(Challenge::CPUChallenge(challenge_msg), nonce).encode()?;
+        let challenge_bytes = encoded_challenge_msg.len();
+        budget.allocate(challenge_bytes)?;
 
-        let (client_response, time_elapsed) =
-            send_client_msg_with_profiling(writer, reader, encoded_challenge_msg.as_slice(), false)
-                .await?;
+        let (client_response, time_elapsed) = send_client_msg_with_profiling(
+            writer,
+            reader,
+            encoded_challenge_msg.as_slice(),
+            false,
+            self.round_timeouts.cpu,
+        )
+        .await?;
+        let response_bytes = message_size(&client_response);
+        budget.allocate(response_bytes)?;
+
+        let (verified, verification) = match (round, cpu_challenge_answer(&client_response)) {
+            (
+                CpuRound::Batched {
+                    index,
+                    result,
+                    batch,
+                },
+                Some(answer),
+            ) => {
+                batch.rounds.push(BatchedRound {
+                    index,
+                    milliseconds: time_elapsed,
+                    result,
+                });
+                batch.answers.push((timelock, answer));
+                (true, TaskTiming::default())
+            }
+            // Answers with a proof are verified right away, as the proof is checked anyway
+            _ => {
+                self.verify(client_id, move || {
+                    verify_cpu_challenge_response(family.verifier(timelock), client_response)
+                })
+                .await?
+            }
+        };
+        budget.release(challenge_bytes + response_bytes);
 
-        if !verify_cpu_challenge_response(timelock_verifier, client_response) {
+        if !verified {
             info!(
                 "Failed CPU measurements for client {:x}, time passed: {}ms",
                 client_id, time_passed
             );
-            writer
-                .send(WsMessage::binary(
-                    Message::Data(Data::Error("Failed CPU measurements".to_owned())).encode()?,
-                ))
-                .await?;
-            return Err(anyhow!(format!(
-                "CPU measurement failed for client {:x}",
-                client_id
-            )));
         } else {
             info!(
                 "Successfully measured CPU power for client {:x}, time passed: {}ms",
@@ -96,132 +1202,1390 @@ impl ClientChallenger {
             );
         }
 
-        Ok(time_elapsed)
+        Ok((
+            verified,
+            RoundTiming {
+                client_milliseconds: time_elapsed,
+                verification,
+                generation,
+                chunk_arrivals: Vec::new(),
+            },
+        ))
     }
 
-    /// Performs network challenge as per the configuration and
-    /// returns time elapsed
-    async fn perform_network_challenge<RNG>(
+    /// Performs network challenge as per the configuration and returns outcome of verification,
+    /// time elapsed along with time spent verifying the response, and number of transfers of
+    /// other sessions overlapping it
+    async fn perform_network_challenge<T: Transport>(
         &self,
-        rng: &mut RNG,
+        roundtrip_session: &RoundtripSession,
+        negotiated: &NegotiatedParameters,
         client_id: u128,
-        writer: &mut SplitSink<WebSocket, WsMessage>,
-        reader: &mut SplitStream<WebSocket>,
-    ) -> Result<u128>
-    where
-        RNG: RngCore,
-    {
-        let (roundtrip, roundtrip_verifier) =
-            Roundtrip::generate(rng, self.network_challenge_config.data_size_kb);
-        let encoded_challenge_msg =
-            Message::Challenge(Challenge::NetworkChallenge(roundtrip.to_wire())).encode()?;
+        writer: &mut SplitSink<T, Vec<u8>>,
+        reader: &mut SplitStream<T>,
+        budget: &mut MemoryBudget,
+    ) -> Result<(NetworkVerification, RoundTiming, usize)> {
+        // Derived data and the encoded challenge, spot check also keeps a copy for verification
+        let payload_bytes = self.network_challenge_config.data_size_kb * 1024;
+        let challenge_bytes = match self.network_challenge_config.verification_mode {
+            NetworkVerificationMode::FullHash => 2 * payload_bytes,
+            NetworkVerificationMode::SpotCheck { .. } => 3 * payload_bytes,
+        };
+        budget.allocate(challenge_bytes)?;
 
-        let (client_response, time_elapsed) =
-            send_client_msg_with_profiling(writer, reader, encoded_challenge_msg.as_slice(), true)
-                .await?;
+        let (
+            time_elapsed,
+            overlapping_transfers,
+            chunk_arrivals,
+            response_bytes,
+            generation,
+            (outcome, verification),
+        ) = match self.network_challenge_config.verification_mode {
+            NetworkVerificationMode::FullHash => {
+                let nonce = negotiated.challenge_nonce();
+                let ((roundtrip, roundtrip_verifier), generation) = timed(|| {
+                    roundtrip_session.generate(&mut OsRng, negotiated.hash_algorithm, &nonce)
+                });
+                let encoded_challenge_msg =
+                    challenge_message(Challenge::NetworkChallenge(roundtrip.to_wire()), nonce)
+                        .encode()?;
+                let (client_response, time_elapsed, overlapping_transfers, chunk_arrivals) = self
+                    .transfer(writer, reader, encoded_challenge_msg, negotiated)
+                    .await?;
+                let response_bytes = message_size(&client_response);
+                budget.allocate(response_bytes)?;
+                let verification = self
+                    .verify(client_id, move || {
+                        verify_network_challenge_response(roundtrip_verifier, client_response)
+                    })
+                    .await?;
+                (
+                    time_elapsed,
+                    overlapping_transfers,
+                    chunk_arrivals,
+                    response_bytes,
+                    generation,
+                    verification,
+                )
+            }
+            NetworkVerificationMode::SpotCheck { sample_chunks } => {
+                let nonce = negotiated.challenge_nonce();
+                let ((roundtrip, roundtrip_verifier), generation) = timed(|| {
+                    roundtrip_session.generate_spot_checked(&mut OsRng, sample_chunks, &nonce)
+                });
+                let encoded_challenge_msg = challenge_message(
+                    Challenge::SpotCheckedNetworkChallenge(roundtrip.to_wire()),
+                    nonce,
+                )
+                .encode()?;
+                let (client_response, time_elapsed, overlapping_transfers, chunk_arrivals) = self
+                    .transfer(writer, reader, encoded_challenge_msg, negotiated)
+                    .await?;
+                let response_bytes = message_size(&client_response);
+                budget.allocate(response_bytes)?;
+                let ((spot_check, roundtrip_verifier), verification) = self
+                    .verify(client_id, move || {
+                        let spot_check = verify_spot_checked_network_challenge_response(
+                            &roundtrip_verifier,
+                            client_response,
+                        );
+                        (spot_check, roundtrip_verifier)
+                    })
+                    .await?;
+                let (outcome, retransmission_time) = match spot_check {
+                    Some(SpotCheck::Matched) => (NetworkVerification::Verified, 0),
+                    Some(SpotCheck::Mismatched) => (NetworkVerification::Mismatch, 0),
+                    Some(SpotCheck::CorruptedChunks(indices)) => {
+                        info!(
+                            "Client[{:x}] returned {} corrupted chunks, asking for them again",
+                            client_id,
+                            indices.len()
+                        );
+                        self.retransmit_chunks(writer, reader, &roundtrip_verifier, indices)
+                            .await?
+                    }
+                    None => (NetworkVerification::Invalid, 0),
+                };
+                (
+                    // Round isn't over until the corrupted chunks are sent again
+                    time_elapsed + retransmission_time,
+                    overlapping_transfers,
+                    chunk_arrivals,
+                    response_bytes,
+                    generation,
+                    (outcome, verification),
+                )
+            }
+        };
+        budget.release(challenge_bytes + response_bytes);
 
-        if !verify_network_challenge_response(roundtrip_verifier, client_response) {
-            info!(
-                "Failed Network measurements for client {:x}, time passed: {}ms",
-                client_id, time_elapsed
+        Ok((
+            outcome,
+            RoundTiming {
+                client_milliseconds: time_elapsed,
+                verification,
+                generation,
+                chunk_arrivals,
+            },
+            overlapping_transfers,
+        ))
+    }
+
+    /// Measures the client over `transport`, e.g. an upgraded websocket wrapped in
+    /// `WebSocketTransport`, and stores its run under `client_id`
+    pub async fn challenge_client<T: Transport>(
+        &mut self,
+        transport: T,
+        client_id: u128,
+    ) -> Result<()> {
+        let authenticator = SessionAuthenticator::default();
+        let (mut writer, mut reader) =
+            AuthenticatedTransport::new(transport, authenticator.clone()).split();
+        let mut runs_completed = 0;
+        let mut stored = false;
+        let result = self
+            .measure_client(
+                &mut writer,
+                &mut reader,
+                client_id,
+                &authenticator,
+                &mut runs_completed,
+                &mut stored,
+            )
+            .await;
+
+        let connection_lost = result
+            .as_ref()
+            .err()
+            .and_then(|e| e.downcast_ref::<ConnectionLost>());
+        if let Some(e) = connection_lost {
+            // Nobody is left to be told why, the session fails with the rounds client completed
+            warn!(
+                "Lost connection to client {:x} after {} completed rounds: {}",
+                client_id, runs_completed, e
             );
-            writer
-                .send(WsMessage::binary(
-                    Message::Data(Data::Error("Failed Network measurements".to_owned()))
-                        .encode()?,
-                ))
-                .await?;
-            return Err(anyhow!(format!(
-                "Network measurement failed for client {:x}",
-                client_id
-            )));
-        } else {
-            info!(
-                "Successfully measured Network bandwidth for client {:x}, time passed: {}ms",
-                client_id, time_elapsed
+        } else if let Err(e) = &result {
+            let reason = if let Some(e) = e.downcast_ref::<MemoryLimitExceeded>() {
+                Some((ErrorCode::MemoryLimitExceeded, e.to_string()))
+            } else if let Some(e) = e.downcast_ref::<ResponseTimedOut>() {
+                warn!("Client {:x} stalled: {}", client_id, e);
+                Some((ErrorCode::Timeout, e.to_string()))
+            } else if let Some(e) = e.downcast_ref::<UnsupportedClient>() {
+                Some((ErrorCode::UnsupportedVersion, e.to_string()))
+            } else {
+                e.downcast_ref::<ProtocolViolation>()
+                    .map(|e| (ErrorCode::ProtocolViolation, e.to_string()))
+            };
+            if let Some((code, reason)) = reason {
+                let error = Message::Data(Data::Error {
+                    code,
+                    message: format!("{} (session {:x})", reason, client_id),
+                });
+                if let Err(e) = writer.send(error.encode()?).await {
+                    warn!("Unable to send error to client {:x}: {:?}", client_id, e);
+                }
+            }
+        }
+
+        let session_closed = Message::SessionClosed {
+            status: if result.is_ok() {
+                SessionStatus::Completed
+            } else {
+                SessionStatus::Failed
+            },
+            runs_completed,
+            stored_run_id: if stored {
+                Some(format!("{:x}", client_id))
+            } else {
+                None
+            },
+        };
+        // Client may already be gone, which must not hide the error of the session itself
+        if let Err(e) = writer.send(session_closed.encode()?).await {
+            warn!(
+                "Unable to send session summary to client {:x}: {:?}",
+                client_id, e
             );
         }
+        // Close handshake may never complete with a stalled client, so it isn't awaited for long
+        match tokio::time::timeout(CLOSE_TIMEOUT, writer.close()).await {
+            Ok(Err(e)) => debug!(
+                "Unable to close connection of client {:x}: {:?}",
+                client_id, e
+            ),
+            Err(_) => debug!("Client {:x} did not take close frame in time", client_id),
+            Ok(Ok(())) => {}
+        }
 
-        Ok(time_elapsed)
+        result
     }
 
-    pub async fn challenge_client(
-        &self,
-        ws: WebSocket,
-        storage: Storage,
+    async fn measure_client<T: Transport>(
+        &mut self,
+        writer: &mut SplitSink<T, Vec<u8>>,
+        reader: &mut SplitStream<T>,
         client_id: u128,
+        authenticator: &SessionAuthenticator,
+        runs_completed: &mut u32,
+        stored: &mut bool,
     ) -> Result<()> {
-        let mut rng = OsRng::default();
-        let (mut writer, mut reader) = ws.split();
-        let mut cpu_results = vec![0u128; self.number_of_cpu_challenge];
-        let mut network_results = vec![0u128; self.number_of_network_challenge];
+        let clock = SessionClock::start();
+        let mut events = SessionEvents::new(
+            &self.context,
+            client_id,
+            self.plan_name.clone(),
+            self.session_parameters.private,
+            self.session_parameters.tenant.clone(),
+            self.session_parameters.api_key_digest.clone(),
+            self.round_totals(),
+        );
+        events.push(SessionEvent {
+            timestamp: clock.now(),
+            kind: SessionEventKind::SessionStarted,
+        });
+        let mut negotiated_parameters = perform_handshake(
+            writer,
+            reader,
+            client_id,
+            self.session_parameters
+                .client_identity
+                .as_ref()
+                .map(|identity| identity.token.clone()),
+            &self.hash_algorithms,
+            self.session_limits(),
+            ClientRequirements {
+                nonces: self.require_nonces,
+                message_authentication: self.message_authentication,
+            },
+        )
+        .await?;
+        *authenticator.lock().unwrap() = negotiated_parameters.message_authenticator.take();
+        info!(
+            "Internal: Client {:x} chose {:?} for roundtrip verification",
+            client_id, negotiated_parameters.hash_algorithm
+        );
+        if let Some(network_type) = negotiated_parameters.network_type {
+            self.score_network_type(network_type);
+        }
 
+        let mut measurement = Measurement {
+            clock,
+            events,
+            budget: MemoryBudget::new(self.session_parameters.memory_limit_bytes),
+            duration_breakdown: DurationBreakdown::default(),
+            runs_completed: 0,
+            failed_at: RunStatus::FailedAtCpu(0),
+            cpu_squarings: self.cpu_challenge_config.squarings,
+            calibration_milliseconds: None,
+            cpu_results: Vec::with_capacity(self.number_of_cpu_challenge),
+            cpu_verification_timings: Vec::with_capacity(self.number_of_cpu_challenge),
+            network_results: Vec::with_capacity(self.number_of_network_challenge),
+            network_verification_timings: Vec::with_capacity(self.number_of_network_challenge),
+            network_round_overlapping_transfers: Vec::with_capacity(
+                self.number_of_network_challenge,
+            ),
+            network_chunk_arrivals: Vec::with_capacity(self.number_of_network_challenge),
+            latency_probe_timings: vec![],
+            datagram: None,
+            plugin_results: HashMap::new(),
+            implausible_rounds: vec![],
+            retries: RetryBudget::new(self.round_retries),
+            surprises: SurpriseSchedule::draw(
+                &mut OsRng,
+                self.number_of_surprise_rounds,
+                self.number_of_cpu_challenge + self.number_of_network_challenge,
+            ),
+            surprise_rounds: vec![],
+        };
         info!(
             "Internal: Starting measurements for client {:x}\n",
             client_id
         );
+        // Phases fail the session with `?`, what they measured until then is kept for a partial run
+        let measured: Result<()> = async {
+            self.measure_cpu(
+                client_id,
+                &negotiated_parameters,
+                writer,
+                reader,
+                &mut measurement,
+            )
+            .await?;
+            self.measure_network(
+                client_id,
+                &negotiated_parameters,
+                writer,
+                reader,
+                &mut measurement,
+            )
+            .await?;
+            self.measure_latency(client_id, writer, reader, &mut measurement)
+                .await?;
+            self.measure_datagram(client_id, writer, reader, &mut measurement)
+                .await?;
+            self.measure_plugins(
+                client_id,
+                &negotiated_parameters,
+                writer,
+                reader,
+                &mut measurement,
+            )
+            .await
+        }
+        .await;
+        *runs_completed = measurement.runs_completed;
+        let status = match &measured {
+            Ok(()) => RunStatus::Completed,
+            Err(e) if is_disconnection(e) => RunStatus::Disconnected,
+            Err(_) => measurement.failed_at,
+        };
+        // Sessions which failed before completing a round have nothing worth storing
+        if measured.is_err() && !(self.persist_partial_runs && *runs_completed > 0) {
+            return measured;
+        }
+
+        let (mut data, report) = self
+            .finalize_run(client_id, &negotiated_parameters, measurement, status)
+            .await?;
+        let client_score = data.score;
+        for hook in &self.context.session_hooks {
+            hook.after_session(client_id, &mut data).await;
+        }
+        let storage_started = Instant::now();
+        *stored = self.store(client_id, data).await;
+        if *stored {
+            debug!(
+                "Stored run {:x} in {} microseconds",
+                client_id,
+                storage_started.elapsed().as_micros()
+            );
+        } else {
+            error!(
+                "Unable to store run {:x}, it is pending until storage recovers",
+                client_id
+            );
+        }
+        // Partial run is stored, the session fails all the same
+        measured?;
+        if !*stored {
+            // Results aren't lost, but client shouldn't look the run up until they are stored
+            writer
+                .send(
+                    Message::Data(Data::Info(format!(
+                        "Results couldn't be stored yet, they will be stored under run {:x} once storage recovers",
+                        client_id
+                    )))
+                    .encode()?,
+                )
+                .await?;
+        }
+
+        if let Some(policy_hook) = &self.context.policy_hook {
+            if let Some(effect) = self
+                .session_parameters
+                .tags
+                .get(policy_hook.node_tag())
+                .and_then(|node| policy_hook.observe(node, client_id, client_score))
+            {
+                let dead_letters = self.context.dead_letters.clone();
+                let task_name = format!("policy hook of session {:x}", client_id);
+                self.context
+                    .tasks
+                    .spawn(TaskKind::Background, task_name, async move {
+                        dead_letters.deliver(effect).await;
+                    });
+            }
+        }
+
+        if let Some(anomaly_detector) = self.context.anomaly_detector.clone().filter(|_| *stored) {
+            let task_name = format!("anomaly detection of run {:x}", client_id);
+            self.context
+                .tasks
+                .spawn(TaskKind::Background, task_name, async move {
+                    if let Err(e) = anomaly_detector.inspect(client_id).await {
+                        warn!(
+                            "Unable to look for anomalies of run {:x}: {:?}",
+                            client_id, e
+                        );
+                    }
+                });
+        }
 
+        if let Some(report) = report {
+            writer
+                .send(Message::MeasurementReport(Box::new(report)).encode()?)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Measures the calibration round, if any, the CPU rounds and surprise rounds due after them,
+    /// then checks answers of batched rounds
+    async fn measure_cpu<T: Transport>(
+        &self,
+        client_id: u128,
+        negotiated: &NegotiatedParameters,
+        writer: &mut SplitSink<T, Vec<u8>>,
+        reader: &mut SplitStream<T>,
+        measurement: &mut Measurement,
+    ) -> Result<()> {
         info!(
             "Internal: Starting CPU measurements for client {:x}\n",
             client_id
         );
+        if let Some(calibration) = self
+            .cpu_calibration
+            .as_ref()
+            .filter(|_| self.number_of_cpu_challenge > 0)
+        {
+            self.calibrate_cpu(
+                client_id,
+                calibration,
+                negotiated,
+                writer,
+                reader,
+                measurement,
+            )
+            .await?;
+        }
+
+        let mut cpu_batch = None;
+        if self.cpu_challenge_config.batch_verification && self.number_of_cpu_challenge > 1 {
+            let (family, generation) = self
+                .generate_timelock_family(client_id, measurement.cpu_squarings)
+                .await?;
+            measurement.duration_breakdown.queueing_microseconds += generation.queued_microseconds;
+            measurement
+                .duration_breakdown
+                .puzzle_generation_microseconds += generation.running_microseconds;
+            cpu_batch = Some(CpuBatch {
+                family,
+                rounds: vec![],
+                answers: vec![],
+            });
+        }
 
         for i in 0..self.number_of_cpu_challenge {
-            cpu_results[i] = self
-                .perform_cpu_challenge(&mut rng, client_id, &mut writer, &mut reader)
+            measurement.failed_at = RunStatus::FailedAtCpu(i);
+            let (verified, timing) = loop {
+                let held = measurement.budget.used();
+                match self
+                    .perform_cpu_challenge(
+                        client_id,
+                        match cpu_batch.as_mut() {
+                            Some(batch) => CpuRound::Batched {
+                                index: i,
+                                result: measurement.cpu_results.len(),
+                                batch,
+                            },
+                            None => CpuRound::Single {
+                                squarings: measurement.cpu_squarings,
+                            },
+                        },
+                        negotiated,
+                        writer,
+                        reader,
+                        &mut measurement.budget,
+                    )
+                    .await
+                {
+                    Err(e)
+                        if measurement
+                            .retries
+                            .retry(kinds::challenge::CPU_CHALLENGE, i, &e) =>
+                    {
+                        measurement.budget.release_to(held);
+                        measurement.record_retried_round(
+                            client_id,
+                            kinds::challenge::CPU_CHALLENGE,
+                            i,
+                            &e,
+                        );
+                        discard_late_response(reader, &e).await?;
+                    }
+                    result => break result?,
+                }
+            };
+            let scored = verified || {
+                let scored = self.round_failed("CPU", client_id, writer).await?;
+                measurement.events.push(SessionEvent {
+                    timestamp: measurement.clock.now(),
+                    kind: SessionEventKind::RoundFailed {
+                        kind_id: kinds::challenge::CPU_CHALLENGE,
+                        round: i,
+                        milliseconds: timing.client_milliseconds,
+                        scored,
+                    },
+                });
+                scored
+            };
+            if let Some(implausible) = implausible_round(
+                kinds::challenge::CPU_CHALLENGE,
+                i,
+                timing.client_milliseconds,
+                self.plausibility_bounds
+                    .cpu_milliseconds(measurement.cpu_squarings),
+            ) {
+                measurement.record_implausible_round(client_id, implausible);
+            }
+            if scored {
+                // Thresholds are set for squarings of the plan
+                measurement.cpu_results.push(normalize_cpu_round(
+                    timing.client_milliseconds,
+                    measurement.cpu_squarings,
+                    self.cpu_challenge_config.squarings,
+                ));
+            }
+            measurement
+                .cpu_verification_timings
+                .push(timing.verification.total_microseconds());
+            timing.account_server_time(&mut measurement.duration_breakdown);
+            measurement.duration_breakdown.client_compute_microseconds +=
+                timing.client_milliseconds * 1000;
+            measurement.runs_completed += 1;
+            if verified {
+                measurement.events.push(SessionEvent {
+                    timestamp: measurement.clock.now(),
+                    kind: SessionEventKind::CPUChallengeCompleted {
+                        round: i,
+                        milliseconds: timing.client_milliseconds,
+                    },
+                });
+            }
+            self.send_progress(
+                writer,
+                kinds::challenge::CPU_CHALLENGE,
+                ChallengeResults::cpu(&self.cpu_challenge_config, &measurement.cpu_results),
+                measurement.runs_completed,
+            )
+            .await?;
+            self.perform_due_surprise_rounds(
+                i + 1,
+                client_id,
+                negotiated,
+                writer,
+                reader,
+                measurement,
+            )
+            .await?;
+        }
+
+        if let Some(CpuBatch {
+            family,
+            rounds,
+            answers,
+        }) = cpu_batch.filter(|batch| !batch.rounds.is_empty())
+        {
+            // A failed batch check doesn't tell which rounds failed, their answers are then
+            // verified one by one
+            let (failed, verification) = self
+                .verify(client_id, move || {
+                    if family.verify_batch(&mut OsRng, &answers) {
+                        return vec![];
+                    }
+                    answers
+                        .into_iter()
+                        .enumerate()
+                        .filter_map(|(position, (puzzle, answer))| {
+                            match family.verifier(puzzle).verify(answer) {
+                                true => None,
+                                false => Some(position),
+                            }
+                        })
+                        .collect::<Vec<_>>()
+                })
                 .await?;
+            measurement.duration_breakdown.queueing_microseconds +=
+                verification.queued_microseconds;
+            measurement.duration_breakdown.verification_microseconds +=
+                verification.running_microseconds;
+            // Time of the batch check is split evenly between the rounds it covered
+            for round in &rounds {
+                measurement.cpu_verification_timings[round.index] =
+                    verification.total_microseconds() / rounds.len() as u128;
+            }
+            if !failed.is_empty() {
+                info!(
+                    "Failed batch verification of {} CPU rounds for client {:x}, {} answers are wrong",
+                    rounds.len(),
+                    client_id,
+                    failed.len()
+                );
+            }
+            // Rounds were scored when measured, those strictness doesn't score are taken back
+            let mut unscored = Vec::new();
+            for round in failed.into_iter().map(|position| &rounds[position]) {
+                measurement.failed_at = RunStatus::FailedAtCpu(round.index);
+                let scored = self.round_failed("CPU", client_id, writer).await?;
+                measurement.events.push(SessionEvent {
+                    timestamp: measurement.clock.now(),
+                    kind: SessionEventKind::RoundFailed {
+                        kind_id: kinds::challenge::CPU_CHALLENGE,
+                        round: round.index,
+                        milliseconds: round.milliseconds,
+                        scored,
+                    },
+                });
+                if !scored {
+                    unscored.push(round.result);
+                }
+            }
+            for result in unscored.into_iter().rev() {
+                measurement.cpu_results.remove(result);
+            }
         }
+        Ok(())
+    }
 
+    /// Measures the calibration round and scales squarings of CPU rounds from its time. Lenient
+    /// sessions whose calibration round fails are measured with squarings of the plan instead.
+    async fn calibrate_cpu<T: Transport>(
+        &self,
+        client_id: u128,
+        calibration: &CpuCalibration,
+        negotiated: &NegotiatedParameters,
+        writer: &mut SplitSink<T, Vec<u8>>,
+        reader: &mut SplitStream<T>,
+        measurement: &mut Measurement,
+    ) -> Result<()> {
+        let (verified, timing) = self
+            .perform_cpu_challenge(
+                client_id,
+                CpuRound::Single {
+                    squarings: calibration.squarings,
+                },
+                negotiated,
+                writer,
+                reader,
+                &mut measurement.budget,
+            )
+            .await?;
+        timing.account_server_time(&mut measurement.duration_breakdown);
+        measurement.duration_breakdown.client_compute_microseconds +=
+            timing.client_milliseconds * 1000;
+        let calibrated = verified || {
+            let scored = self.round_failed("CPU", client_id, writer).await?;
+            measurement.events.push(SessionEvent {
+                timestamp: measurement.clock.now(),
+                kind: SessionEventKind::CPUCalibrationFailed {
+                    milliseconds: timing.client_milliseconds,
+                    scored,
+                },
+            });
+            scored
+        };
+        if calibrated {
+            measurement.cpu_squarings = calibrated_squarings(
+                calibration.squarings,
+                timing.client_milliseconds,
+                calibration.target_milliseconds,
+            );
+            measurement.calibration_milliseconds = Some(timing.client_milliseconds);
+            info!(
+                "Internal: Calibrated CPU rounds of client {:x} to {} squarings",
+                client_id, measurement.cpu_squarings
+            );
+            measurement.events.push(SessionEvent {
+                timestamp: measurement.clock.now(),
+                kind: SessionEventKind::CPUCalibrated {
+                    milliseconds: timing.client_milliseconds,
+                    squarings: measurement.cpu_squarings,
+                },
+            });
+        }
+        Ok(())
+    }
+
+    /// Measures the network rounds and surprise rounds due after them
+    async fn measure_network<T: Transport>(
+        &self,
+        client_id: u128,
+        negotiated: &NegotiatedParameters,
+        writer: &mut SplitSink<T, Vec<u8>>,
+        reader: &mut SplitStream<T>,
+        measurement: &mut Measurement,
+    ) -> Result<()> {
+        // Suites may skip network rounds entirely, session buffer isn't needed then
+        if self.number_of_network_challenge == 0 {
+            return Ok(());
+        }
         info!(
             "Internal: Starting Network measurements for client {:x}",
             client_id
         );
 
+        // Payload of every round is derived from this buffer, instead of generating it from scratch
+        let (roundtrip_session, generation) =
+            timed(|| RoundtripSession::new(&mut OsRng, self.network_challenge_config.data_size_kb));
+        measurement
+            .duration_breakdown
+            .puzzle_generation_microseconds += generation.running_microseconds;
+        measurement.failed_at = RunStatus::FailedAtNetwork(0);
+        measurement
+            .budget
+            .allocate(self.network_challenge_config.data_size_kb * 1024)?;
+
         for i in 0..self.number_of_network_challenge {
-            network_results[i] = self
-                .perform_network_challenge(&mut rng, client_id, &mut writer, &mut reader)
-                .await?;
+            measurement.failed_at = RunStatus::FailedAtNetwork(i);
+            let mut attempt = 0;
+            let (verified, timing, overlapping_transfers) = loop {
+                let held = measurement.budget.used();
+                let (outcome, timing, overlapping_transfers) = match self
+                    .perform_network_challenge(
+                        &roundtrip_session,
+                        negotiated,
+                        client_id,
+                        writer,
+                        reader,
+                        &mut measurement.budget,
+                    )
+                    .await
+                {
+                    Err(e)
+                        if measurement
+                            .retries
+                            .retry(self.network_challenge_kind(), i, &e) =>
+                    {
+                        measurement.budget.release_to(held);
+                        measurement.record_retried_round(
+                            client_id,
+                            self.network_challenge_kind(),
+                            i,
+                            &e,
+                        );
+                        discard_late_response(reader, &e).await?;
+                        continue;
+                    }
+                    result => result?,
+                };
+                timing.account_server_time(&mut measurement.duration_breakdown);
+                measurement.duration_breakdown.network_transfer_microseconds +=
+                    timing.client_milliseconds * 1000;
+                match outcome {
+                    NetworkVerification::Verified => {
+                        info!(
+                            "Successfully measured Network bandwidth for client {:x}, time passed: {}ms",
+                            client_id, timing.client_milliseconds
+                        );
+                        break (true, timing, overlapping_transfers);
+                    }
+                    // Mangled data is retried with a fresh payload, a client which
+                    // cheats fails the retry as well
+                    NetworkVerification::Mismatch if attempt < NETWORK_ROUND_RETRIES => {
+                        warn!(
+                            "Data returned by client {:x} in network round {} doesn't match, retrying",
+                            client_id, i
+                        );
+                        measurement.events.push(SessionEvent {
+                            timestamp: measurement.clock.now(),
+                            kind: SessionEventKind::NetworkChallengeMismatched {
+                                round: i,
+                                milliseconds: timing.client_milliseconds,
+                            },
+                        });
+                        attempt += 1;
+                    }
+                    NetworkVerification::Mismatch | NetworkVerification::Invalid => {
+                        info!(
+                            "Failed Network measurements for client {:x}, time passed: {}ms, outcome: {:?}",
+                            client_id, timing.client_milliseconds, outcome
+                        );
+                        break (false, timing, overlapping_transfers);
+                    }
+                }
+            };
+            let scored = verified || {
+                let scored = self.round_failed("Network", client_id, writer).await?;
+                measurement.events.push(SessionEvent {
+                    timestamp: measurement.clock.now(),
+                    kind: SessionEventKind::RoundFailed {
+                        kind_id: self.network_challenge_kind(),
+                        round: i,
+                        milliseconds: timing.client_milliseconds,
+                        scored,
+                    },
+                });
+                scored
+            };
+            if let Some(implausible) = implausible_round(
+                self.network_challenge_kind(),
+                i,
+                timing.client_milliseconds,
+                self.plausibility_bounds
+                    .network_milliseconds(self.network_challenge_config.data_size_kb),
+            ) {
+                measurement.record_implausible_round(client_id, implausible);
+            }
+            // Records of each round are kept in step with its timing
+            if scored {
+                measurement
+                    .network_results
+                    .push(if self.contention_correction {
+                        correct_for_contention(timing.client_milliseconds, overlapping_transfers)
+                    } else {
+                        timing.client_milliseconds
+                    });
+                measurement
+                    .network_round_overlapping_transfers
+                    .push(overlapping_transfers);
+                measurement
+                    .network_chunk_arrivals
+                    .push(timing.chunk_arrivals);
+            }
+            if overlapping_transfers > 0 {
+                debug!(
+                    "Network round {} of client {:x} overlapped {} transfers of other sessions",
+                    i, client_id, overlapping_transfers
+                );
+            }
+            measurement
+                .network_verification_timings
+                .push(timing.verification.total_microseconds());
+            measurement.runs_completed += 1;
+            if verified {
+                measurement.events.push(SessionEvent {
+                    timestamp: measurement.clock.now(),
+                    kind: SessionEventKind::NetworkChallengeCompleted {
+                        round: i,
+                        milliseconds: timing.client_milliseconds,
+                    },
+                });
+            }
+            self.send_progress(
+                writer,
+                self.network_challenge_kind(),
+                ChallengeResults::network(
+                    &self.network_challenge_config,
+                    &measurement.network_results,
+                ),
+                measurement.runs_completed,
+            )
+            .await?;
+            self.perform_due_surprise_rounds(
+                self.number_of_cpu_challenge + i + 1,
+                client_id,
+                negotiated,
+                writer,
+                reader,
+                measurement,
+            )
+            .await?;
         }
+        Ok(())
+    }
 
-        let client_score = self.determine_score(&cpu_results, &network_results);
+    /// Sends the surprise rounds scheduled after `completed_rounds` scheduled rounds
+    async fn perform_due_surprise_rounds<T: Transport>(
+        &self,
+        completed_rounds: usize,
+        client_id: u128,
+        negotiated: &NegotiatedParameters,
+        writer: &mut SplitSink<T, Vec<u8>>,
+        reader: &mut SplitStream<T>,
+        measurement: &mut Measurement,
+    ) -> Result<()> {
+        for kind in measurement.surprises.due(completed_rounds) {
+            if let Some((kind_id, microseconds)) = self
+                .perform_surprise_round(
+                    kind,
+                    client_id,
+                    negotiated,
+                    writer,
+                    reader,
+                    &mut measurement.budget,
+                )
+                .await?
+            {
+                let round = SurpriseRound {
+                    kind_id,
+                    after_rounds: completed_rounds,
+                    microseconds,
+                };
+                measurement.events.push(SessionEvent {
+                    timestamp: measurement.clock.now(),
+                    kind: round.event(),
+                });
+                measurement.surprise_rounds.push(round);
+            }
+        }
+        Ok(())
+    }
+
+    /// Measures the latency probes, if the plan has any
+    async fn measure_latency<T: Transport>(
+        &self,
+        client_id: u128,
+        writer: &mut SplitSink<T, Vec<u8>>,
+        reader: &mut SplitStream<T>,
+        measurement: &mut Measurement,
+    ) -> Result<()> {
+        if self.number_of_latency_probes == 0 {
+            return Ok(());
+        }
+        measurement.failed_at = RunStatus::FailedAtRound {
+            kind_id: kinds::challenge::LATENCY_PROBE,
+            round: 0,
+        };
+        info!(
+            "Internal: Starting Latency measurements for client {:x}",
+            client_id
+        );
+        measurement.latency_probe_timings = self
+            .perform_latency_challenge(
+                client_id,
+                writer,
+                reader,
+                &mut measurement.events,
+                &measurement.clock,
+            )
+            .await?;
+        measurement.duration_breakdown.network_transfer_microseconds +=
+            measurement.latency_probe_timings.iter().sum::<u128>();
+        measurement.events.push(SessionEvent {
+            timestamp: measurement.clock.now(),
+            kind: SessionEventKind::LatencyProbesCompleted {
+                probes: measurement.latency_probe_timings.len(),
+                mean_microseconds: find_mean(&measurement.latency_probe_timings)
+                    .unwrap_or_default(),
+            },
+        });
+        Ok(())
+    }
+
+    /// Measures the datagram probes, if the plan has any and server has an endpoint to send them
+    async fn measure_datagram<T: Transport>(
+        &self,
+        client_id: u128,
+        writer: &mut SplitSink<T, Vec<u8>>,
+        reader: &mut SplitStream<T>,
+        measurement: &mut Measurement,
+    ) -> Result<()> {
+        let endpoint = match self
+            .context
+            .datagram_endpoint
+            .as_ref()
+            .filter(|_| self.number_of_datagram_probes > 0)
+        {
+            Some(endpoint) => endpoint,
+            None => return Ok(()),
+        };
+        info!(
+            "Internal: Starting Datagram measurements for client {:x}",
+            client_id
+        );
+        measurement.failed_at = RunStatus::FailedAtRound {
+            kind_id: kinds::challenge::DATAGRAM_CHALLENGE,
+            round: 0,
+        };
+        let started = Instant::now();
+        measurement.datagram = self
+            .perform_datagram_challenge(client_id, endpoint, writer, reader)
+            .await?;
+        measurement.duration_breakdown.network_transfer_microseconds +=
+            started.elapsed().as_micros();
+        if let Some(stats) = &measurement.datagram {
+            measurement.events.push(SessionEvent {
+                timestamp: measurement.clock.now(),
+                kind: SessionEventKind::DatagramProbesCompleted {
+                    probes: stats.probes as usize,
+                    echoed: stats.echoed as usize,
+                },
+            });
+        }
+        Ok(())
+    }
+
+    /// Measures rounds of each plugin in turn. Client is sent progress of scored plugins only,
+    /// the others are reported alongside the score.
+    async fn measure_plugins<T: Transport>(
+        &self,
+        client_id: u128,
+        negotiated: &NegotiatedParameters,
+        writer: &mut SplitSink<T, Vec<u8>>,
+        reader: &mut SplitStream<T>,
+        measurement: &mut Measurement,
+    ) -> Result<()> {
+        for plugin in self.plugins.iter().filter(|plugin| plugin.rounds > 0) {
+            let kind_id = plugin.generator.kind_id();
+            info!(
+                "Internal: Starting {} measurements for client {:x}",
+                plugin.generator.name(),
+                client_id
+            );
+            for i in 0..plugin.rounds {
+                measurement.failed_at = RunStatus::FailedAtRound { kind_id, round: i };
+                let (verified, timing) = loop {
+                    let held = measurement.budget.used();
+                    match self
+                        .perform_plugin_challenge(
+                            plugin,
+                            negotiated,
+                            client_id,
+                            writer,
+                            reader,
+                            &mut measurement.budget,
+                        )
+                        .await
+                    {
+                        Err(e) if measurement.retries.retry(kind_id, i, &e) => {
+                            measurement.budget.release_to(held);
+                            measurement.record_retried_round(client_id, kind_id, i, &e);
+                            discard_late_response(reader, &e).await?;
+                        }
+                        result => break result?,
+                    }
+                };
+                timing.account_server_time(&mut measurement.duration_breakdown);
+                match plugin.mode {
+                    RoundMode::Transfer => {
+                        measurement.duration_breakdown.network_transfer_microseconds +=
+                            timing.client_milliseconds * 1000
+                    }
+                    RoundMode::Compute { .. } => {
+                        measurement.duration_breakdown.client_compute_microseconds +=
+                            timing.client_milliseconds * 1000
+                    }
+                }
+                let scored = verified || {
+                    let scored = self
+                        .round_failed(plugin.generator.name(), client_id, writer)
+                        .await?;
+                    measurement.events.push(SessionEvent {
+                        timestamp: measurement.clock.now(),
+                        kind: SessionEventKind::RoundFailed {
+                            kind_id,
+                            round: i,
+                            milliseconds: timing.client_milliseconds,
+                            scored,
+                        },
+                    });
+                    scored
+                };
+                let (results, verification_timings) =
+                    measurement.plugin_results.entry(kind_id).or_default();
+                if scored {
+                    results.push(timing.client_milliseconds);
+                }
+                verification_timings.push(timing.verification.total_microseconds());
+                if verified {
+                    measurement.events.push(SessionEvent {
+                        timestamp: measurement.clock.now(),
+                        kind: completed_event(kind_id, i, timing.client_milliseconds),
+                    });
+                }
+                // Only scored plugins count towards rounds of a partial run
+                if plugin.scoring.is_some() {
+                    measurement.runs_completed += 1;
+                }
+                if let Some(results) = plugin.results(measurement.plugin_rounds(kind_id)) {
+                    self.send_progress(writer, kind_id, results, measurement.runs_completed)
+                        .await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Scores what the session measured and puts its run together, along with the report sent
+    /// to the client of a completed session. A session which didn't complete is scored as a
+    /// partial run, see `partial_run_score`.
+    async fn finalize_run(
+        &self,
+        client_id: u128,
+        negotiated: &NegotiatedParameters,
+        mut measurement: Measurement,
+        status: RunStatus,
+    ) -> Result<(ClientData, Option<MeasurementReport>)> {
+        let mut score_breakdown = match self.determine_score(&measurement) {
+            Ok(score_breakdown) => score_breakdown,
+            // Partial run may not have reached a scored round
+            Err(ScoreError::NoResults) if status != RunStatus::Completed => {
+                ScoreBreakdown::default()
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if status != RunStatus::Completed {
+            score_breakdown.total =
+                self.partial_run_score(score_breakdown.total, measurement.runs_completed);
+        }
+        // Whatever the strictness, as such a client didn't do the work
+        if !measurement.implausible_rounds.is_empty() {
+            score_breakdown.total = 0.0;
+        }
+        // A client whose rounds had to be retried doesn't score as well as a steady one
+        if measurement.retries.spent() > 0 {
+            score_breakdown.total = (score_breakdown.total
+                - self.retry_penalty * measurement.retries.spent() as f64)
+                .max(0.0);
+        }
+        let client_score = round_score(score_breakdown.total);
+        // Client is only sent a report of a completed session
+        let report = match status {
+            RunStatus::Completed => Some(self.measurement_report(
+                client_id,
+                score_breakdown.clone(),
+                negotiated.hash_algorithm,
+                &measurement,
+            )?),
+            _ => None,
+        };
+
+        let mut plugin_results = |kind_id| {
+            measurement
+                .plugin_results
+                .remove(&kind_id)
+                .unwrap_or_default()
+        };
+        let (download_results, _) = plugin_results(kinds::challenge::DOWNLOAD_CHALLENGE);
+        let (upload_results, _) = plugin_results(kinds::challenge::UPLOAD_CHALLENGE);
+        let (disk_results, disk_verification_timings) =
+            plugin_results(kinds::challenge::DISK_CHALLENGE);
         info!("Score for client {:x} is {}", client_id, client_score);
-        storage.write().await.insert(
+        info!(
+            "Internal: Verification for client {:x} took {}us",
             client_id,
-            ClientData {
+            measurement.cpu_verification_timings.iter().sum::<u128>()
+                + measurement
+                    .network_verification_timings
+                    .iter()
+                    .sum::<u128>()
+                + disk_verification_timings.iter().sum::<u128>()
+        );
+        measurement.events.push(SessionEvent {
+            timestamp: measurement.clock.now(),
+            kind: SessionEventKind::ScoreCalculated {
                 score: client_score,
-                cpu_challenge_timings_in_milis: cpu_results,
-                network_challenge_timings_in_milis: network_results,
             },
+        });
+        let cpu_challenge_squarings_per_second: Vec<f64> = measurement
+            .cpu_results
+            .iter()
+            .map(|round| {
+                cpu_round_squarings_per_second(self.cpu_challenge_config.squarings, *round)
+            })
+            .collect();
+        let surprise_cpu_squarings_per_second: Vec<f64> = measurement
+            .surprise_rounds
+            .iter()
+            .filter(|round| round.kind_id == kinds::challenge::CPU_CHALLENGE)
+            .map(|round| {
+                cpu_round_squarings_per_second(self.surprise_squarings, round.microseconds / 1000)
+            })
+            .collect();
+        let configuration = self.effective_configuration(
+            negotiated,
+            measurement
+                .calibration_milliseconds
+                .map(|_| measurement.cpu_squarings),
         );
+        let data = ClientData {
+            score: client_score,
+            score_breakdown,
+            surprise_cpu_slowdown: cpu_slowdown(
+                &cpu_challenge_squarings_per_second,
+                &surprise_cpu_squarings_per_second,
+            ),
+            cpu_challenge_squarings_per_second,
+            cpu_challenge_timings_in_milis: measurement.cpu_results,
+            network_challenge_throughput_in_mbps: measurement
+                .network_results
+                .iter()
+                .map(|round| network_round_mbps(self.network_challenge_config.data_size_kb, *round))
+                .collect(),
+            network_challenge_timings_in_milis: measurement.network_results,
+            disk_challenge_timings_in_milis: disk_results,
+            cpu_verification_timings_in_micros: measurement.cpu_verification_timings,
+            network_verification_timings_in_micros: measurement.network_verification_timings,
+            disk_verification_timings_in_micros: disk_verification_timings,
+            latency_probe_timings_in_micros: measurement.latency_probe_timings,
+            download_challenge_timings_in_milis: download_results,
+            upload_challenge_timings_in_milis: upload_results,
+            network_round_overlapping_transfers: measurement.network_round_overlapping_transfers,
+            network_chunk_arrivals_in_micros: measurement
+                .network_chunk_arrivals
+                .iter()
+                .map(|arrivals| {
+                    arrivals
+                        .iter()
+                        .map(|(microseconds, _)| *microseconds)
+                        .collect()
+                })
+                .collect(),
+            network_chunk_bytes: measurement
+                .network_chunk_arrivals
+                .iter()
+                .map(|arrivals| arrivals.iter().map(|(_, bytes)| *bytes).collect())
+                .collect(),
+            cpu_calibration_milliseconds: measurement.calibration_milliseconds,
+            configuration,
+            priority_class: self.session_parameters.priority_class,
+            tags: self.session_parameters.tags.clone(),
+            private: self.session_parameters.private,
+            address_family: self.session_parameters.address_family,
+            network_type: negotiated.network_type,
+            client_version: negotiated.client_version.clone(),
+            // Partial runs aren't compared with others
+            dual_stack_of: match status {
+                RunStatus::Completed => self.dual_stack_of(client_id).await,
+                _ => None,
+            },
+            tls_handshake_milliseconds: self
+                .session_parameters
+                .tls_handshake
+                .map(|handshake| handshake.as_millis()),
+            tls_comparison_of: match status {
+                RunStatus::Completed => self.tls_comparison_of(client_id).await,
+                _ => None,
+            },
+            identity: self
+                .session_parameters
+                .client_identity
+                .as_ref()
+                .map(|identity| identity.id),
+            datagram: measurement.datagram,
+            anomalies: vec![],
+            implausible_rounds: measurement.implausible_rounds,
+            surprise_rounds: measurement.surprise_rounds,
+            retried_rounds: measurement.retries.into_retried(),
+            status,
+            principal: self.session_parameters.principal.clone(),
+            tenant: self.session_parameters.tenant.clone(),
+            api_key_digest: self.session_parameters.api_key_digest.clone(),
+            started_at: measurement.clock.started_at(),
+            events: measurement.events.into_vec(),
+            duration_breakdown: measurement.duration_breakdown,
+        };
+        Ok((data, report))
+    }
 
-        writer
-            .send(WsMessage::binary(
-                Message::Data(Data::Info(
-                    format!("My score is: {}", client_score).to_owned(),
-                ))
-                .encode()?,
-            ))
-            .await?;
-
-        Ok(())
+    /// Score of a partial run which completed `runs_completed` rounds: `total` less the penalty
+    /// of each scored round it didn't complete, 0 if the plan has no penalty
+    fn partial_run_score(&self, total: f64, runs_completed: u32) -> f64 {
+        match self.partial_run_penalty {
+            Some(penalty) => {
+                let missed_rounds = self.scored_rounds().saturating_sub(runs_completed as usize);
+                (total - penalty * missed_rounds as f64).max(0.0)
+            }
+            None => 0.0,
+        }
     }
 }
 
-pub(crate) async fn perform_all(ws: WebSocket, storage: Storage, client_id: u128) -> Result<()> {
-    let challenger = ClientChallenger {
-        cpu_challenge_config: CPUChallengeConfiguration {
-            squarings: 200000,
-            ideal_milliseconds: 4500,
-            max_milliseconds: 120000,
-        },
-        network_challenge_config: NetworkChallengeConfiguration {
-            data_size_kb: 1024,
-            ideal_milliseconds: 200,
-            max_milliseconds: 25000,
-        },
-        number_of_cpu_challenge: 5,
-        number_of_network_challenge: 10,
-    };
+impl ClientChallenger {
+    /// Challenger of a session of `plan`, with payloads scaled down if server is under memory
+    /// pressure or the host can't serve them
+    pub fn new(
+        context: ServerContext,
+        plan: &PlanConfig,
+        session_parameters: SessionParameters,
+    ) -> Self {
+        let plan_payload_size_kb = plan.payload_size_kb;
+        let payload_size_kb = match &context.memory_watchdog {
+            Some(memory_watchdog) => memory_watchdog.payload_size_kb(plan_payload_size_kb),
+            None => plan_payload_size_kb,
+        };
+        let payload_size_kb = match &context.host_limits {
+            Some(host_limits) => payload_size_kb.min(host_limits.max_payload_size_kb),
+            None => payload_size_kb,
+        };
+        // Thresholds on throughput scale with the payload, so they follow it when it's scaled down
+        let (network_ideal_milliseconds, network_max_milliseconds) =
+            plan.network_thresholds(payload_size_kb);
+        let (cpu_ideal_milliseconds, cpu_max_milliseconds) = plan.cpu_thresholds();
+
+        ClientChallenger {
+            plan_name: plan.name.clone(),
+            cpu_challenge_config: CPUChallengeConfiguration {
+                squarings: plan.squarings,
+                batch_verification: plan.batch_cpu_verification,
+                ideal_milliseconds: cpu_ideal_milliseconds,
+                max_milliseconds: cpu_max_milliseconds,
+                aggregation: plan.cpu_aggregation,
+                scale: plan.cpu_scale(),
+            },
+            network_challenge_config: NetworkChallengeConfiguration {
+                data_size_kb: payload_size_kb,
+                ideal_milliseconds: network_ideal_milliseconds,
+                max_milliseconds: network_max_milliseconds,
+                verification_mode: match plan.spot_check_sample_chunks {
+                    Some(sample_chunks) => NetworkVerificationMode::SpotCheck { sample_chunks },
+                    None => NetworkVerificationMode::FullHash,
+                },
+                aggregation: plan.network_aggregation,
+                scale: plan.network_scale(),
+            },
+            cpu_calibration: plan.cpu_target_milliseconds.map(|target_milliseconds| {
+                CpuCalibration {
+                    squarings: plan.cpu_calibration_squarings,
+                    target_milliseconds: target_milliseconds.into(),
+                }
+            }),
+            contention_correction: plan.network_contention_correction,
+            cpu_ideal_squarings_per_second: plan.cpu_ideal_squarings_per_second,
+            cpu_min_squarings_per_second: plan.cpu_min_squarings_per_second,
+            network_ideal_mbps: plan.network_ideal_mbps,
+            network_min_mbps: plan.network_min_mbps,
+            network_type_plans: plan
+                .network_types
+                .keys()
+                .filter_map(|name| NetworkType::from_name(name))
+                .map(|network_type| (network_type, plan.for_network_type(network_type)))
+                .collect(),
+            scoring_model: plan.scoring,
+            number_of_cpu_challenge: plan.cpu_rounds,
+            number_of_network_challenge: plan.network_rounds,
+            number_of_latency_probes: plan.latency_probes,
+            number_of_datagram_probes: plan.datagram_probes,
+            datagram_interval_milliseconds: plan.datagram_interval_milliseconds,
+            round_timeouts: RoundTimeouts {
+                cpu: plan.cpu_round_timeout(),
+                network: plan.network_round_timeout(),
+            },
+            plugins: registered_plugins(
+                plan,
+                payload_size_kb,
+                session_parameters.disk_challenge_rounds,
+            ),
+            hash_algorithms: if plan.offer_xxhash64 {
+                vec![
+                    HashAlgorithm::XxHash64,
+                    HashAlgorithm::Blake3,
+                    HashAlgorithm::Sha256,
+                ]
+            } else {
+                vec![HashAlgorithm::Blake3, HashAlgorithm::Sha256]
+            },
+            max_frame_size_kb: plan.frame_size_kb(),
+            network_stall_milliseconds: plan.network_stall_milliseconds,
+            payload_scaled_down_from_kb: if payload_size_kb < plan_payload_size_kb {
+                Some(plan_payload_size_kb)
+            } else {
+                None
+            },
+            require_nonces: plan.require_nonces,
+            message_authentication: plan.message_authentication,
+            plausibility_bounds: plan.plausibility_bounds(),
+            number_of_surprise_rounds: plan.surprise_rounds,
+            surprise_squarings: plan.surprise_squarings,
+            round_retries: plan.round_retries,
+            retry_penalty: plan.retry_penalty.max(0.0),
+            persist_partial_runs: plan.persist_partial_runs,
+            partial_run_penalty: plan.partial_run_penalty.map(|penalty| penalty.max(0.0)),
+            context,
+            session_parameters,
+        }
+    }
+}
 
-    challenger.challenge_client(ws, storage, client_id).await
+pub(crate) async fn perform_all<T: Transport>(
+    transport: T,
+    context: ServerContext,
+    plan: &PlanConfig,
+    session_parameters: SessionParameters,
+    client_id: u128,
+) -> Result<()> {
+    ClientChallenger::new(context, plan, session_parameters)
+        .challenge_client(transport, client_id)
+        .await
 }
@@ -0,0 +1,323 @@
+//! Loss and latency measurement over UDP, see `shared::challenges::datagram`
+
+use anyhow::Result;
+use futures::future;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use shared::challenges::datagram::{
+    self, DatagramEchoes, DatagramTrain, IDLE_TIMEOUT_MILLISECONDS, TOKEN_SIZE,
+};
+use shared::challenges::latency::LatencyChallenge;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::net::udp::{RecvHalf, SendHalf};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// Datagrams of a train queued before further ones are dropped, a client can't flood the
+/// session by sending more than it was sent
+const MAX_QUEUED_DATAGRAMS: usize = 1024;
+
+/// Datagrams of trains are tens of bytes, larger ones are truncated and then ignored
+const MAX_DATAGRAM_BYTES: usize = 512;
+
+/// Echoes arriving this long after the last probe was sent count as lost
+const ECHO_GRACE: Duration = Duration::from_secs(1);
+
+type Trains = Mutex<HashMap<[u8; TOKEN_SIZE], mpsc::Sender<(SocketAddr, Vec<u8>)>>>;
+
+/// UDP socket datagram measurements of all sessions share, received datagrams are routed to
+/// the train of their token
+pub struct DatagramEndpoint {
+    sender: tokio::sync::Mutex<SendHalf>,
+    port: u16,
+    trains: Arc<Trains>,
+}
+
+impl DatagramEndpoint {
+    /// Binds `address` and spawns the task routing datagrams received at it, so it has to be
+    /// called within a tokio runtime. Register it with `ServerContext::with_datagram_endpoint`.
+    pub async fn bind(address: SocketAddr) -> Result<Arc<Self>> {
+        let socket = UdpSocket::bind(address).await?;
+        let port = socket.local_addr()?.port();
+        let (receiver, sender) = socket.split();
+        let trains = Arc::new(Trains::default());
+        tokio::spawn(route_datagrams(receiver, Arc::downgrade(&trains)));
+        Ok(Arc::new(DatagramEndpoint {
+            sender: tokio::sync::Mutex::new(sender),
+            port,
+            trains,
+        }))
+    }
+
+    /// New train of `probes`, datagrams with its token are received until it is dropped
+    pub(crate) fn open_train(
+        self: &Arc<Self>,
+        probes: u32,
+        interval_milliseconds: u32,
+    ) -> OpenTrain {
+        let mut token = [0u8; TOKEN_SIZE];
+        OsRng.fill_bytes(&mut token);
+        let (sender, arrivals) = mpsc::channel(MAX_QUEUED_DATAGRAMS);
+        self.trains.lock().unwrap().insert(token, sender);
+        OpenTrain {
+            train: DatagramTrain {
+                port: self.port,
+                token,
+                probes,
+                interval_milliseconds,
+            },
+            endpoint: self.clone(),
+            arrivals,
+        }
+    }
+}
+
+/// Passes each datagram to the train of its token, until the endpoint is dropped
+async fn route_datagrams(mut receiver: RecvHalf, trains: Weak<Trains>) {
+    let mut buffer = vec![0; MAX_DATAGRAM_BYTES];
+    loop {
+        let (read, address) = match receiver.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            Err(e) => {
+                // E.g. ICMP port unreachable of a client which went away
+                debug!("Unable to receive datagram: {:?}", e);
+                continue;
+            }
+        };
+        let trains = match trains.upgrade() {
+            Some(trains) => trains,
+            None => return,
+        };
+        let datagram = &buffer[..read];
+        if let Some(token) = datagram::token(datagram) {
+            if let Some(sender) = trains.lock().unwrap().get_mut(&token) {
+                // Full queue means the client sends more than it echoes, excess is dropped
+                let _ = sender.try_send((address, datagram.to_vec()));
+            }
+        }
+    }
+}
+
+/// Train of a session registered with the endpoint
+pub(crate) struct OpenTrain {
+    pub(crate) train: DatagramTrain,
+    endpoint: Arc<DatagramEndpoint>,
+    arrivals: mpsc::Receiver<(SocketAddr, Vec<u8>)>,
+}
+
+impl OpenTrain {
+    /// Waits for the client's hello, then sends the probes at the train's interval without
+    /// waiting for their echoes. Returns echoes which arrived in time, or `None` if no hello
+    /// arrived, e.g. as UDP is blocked on the way.
+    pub(crate) async fn measure(&mut self) -> Result<Option<DatagramEchoes>> {
+        let hello_deadline = Instant::now() + Duration::from_millis(IDLE_TIMEOUT_MILLISECONDS);
+        let client = loop {
+            match tokio::time::timeout_at(hello_deadline, self.arrivals.recv()).await {
+                Ok(Some((address, hello))) if hello == self.train.hello() => break address,
+                Ok(Some(_)) => continue,
+                Ok(None) | Err(_) => return Ok(None),
+            }
+        };
+
+        let probes = self.train.probes;
+        let challenge = LatencyChallenge::generate(&mut OsRng, probes);
+        let mut echoed = vec![false; probes as usize];
+        let mut echoes = DatagramEchoes {
+            round_trips: Vec::with_capacity(probes as usize),
+            ..Default::default()
+        };
+        let mut latest_echoed = None;
+        // Probes are stamped relative to this, which the client can't observe
+        let started = Instant::now();
+        let interval = Duration::from_millis(u64::from(self.train.interval_milliseconds).max(1));
+        let mut ticker = tokio::time::interval(interval);
+        let mut sequence = 0;
+        let mut last_sent = None;
+        while echoes.round_trips.len() < echoed.len() {
+            let grace_over = async {
+                match last_sent {
+                    Some(last_sent) => tokio::time::delay_until(last_sent + ECHO_GRACE).await,
+                    None => future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = ticker.tick(), if sequence < probes => {
+                    let probe = challenge.probe(sequence, microseconds(started.elapsed()));
+                    self.endpoint
+                        .sender
+                        .lock()
+                        .await
+                        .send_to(&self.train.probe_datagram(&probe), &client)
+                        .await?;
+                    sequence += 1;
+                    if sequence == probes {
+                        last_sent = Some(Instant::now());
+                    }
+                }
+                arrival = self.arrivals.recv() => {
+                    let datagram = match arrival {
+                        Some((_, datagram)) => datagram,
+                        None => break,
+                    };
+                    let received_at = microseconds(started.elapsed());
+                    let sequence = match self.train.sequence(&datagram) {
+                        Some(sequence) if sequence < probes => sequence,
+                        // Repeated hellos and garbage
+                        _ => continue,
+                    };
+                    let probe = self.train.probe(&datagram).unwrap_or_default();
+                    let sent_at = match challenge.verify(sequence, probe) {
+                        Some(sent_at) => sent_at,
+                        None => continue,
+                    };
+                    if echoed[sequence as usize] {
+                        echoes.duplicates += 1;
+                        continue;
+                    }
+                    echoed[sequence as usize] = true;
+                    echoes.round_trips.push(received_at.saturating_sub(sent_at));
+                    if latest_echoed > Some(sequence) {
+                        echoes.reordered += 1;
+                    } else {
+                        latest_echoed = Some(sequence);
+                    }
+                }
+                _ = grace_over => break,
+            }
+        }
+        Ok(Some(echoes))
+    }
+}
+
+impl Drop for OpenTrain {
+    fn drop(&mut self) {
+        self.endpoint
+            .trains
+            .lock()
+            .unwrap()
+            .remove(&self.train.token);
+    }
+}
+
+fn microseconds(elapsed: Duration) -> u64 {
+    u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{PlanConfig, ServerConfig};
+    use crate::measurements::datagram::DatagramEndpoint;
+    use crate::measurements::measurement_routes;
+    use crate::types::{ServerContext, Visibility};
+    use shared::SessionStatus;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use tokio::net::UdpSocket;
+
+    #[tokio::test]
+    async fn test_lossy_train() {
+        let endpoint = DatagramEndpoint::bind(([127, 0, 0, 1], 0).into())
+            .await
+            .unwrap();
+        let mut train = endpoint.open_train(20, 1);
+        let server: SocketAddr = ([127, 0, 0, 1], train.train.port).into();
+        let client_train = train.train.clone();
+        let client = tokio::spawn(async move {
+            let mut socket = UdpSocket::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+                .await
+                .unwrap();
+            socket.connect(server).await.unwrap();
+            // Datagrams of other trains are ignored
+            socket.send(&[7; 40]).await.unwrap();
+            socket.send(&client_train.hello()).await.unwrap();
+            let mut buffer = [0; 512];
+            let mut held_back = None;
+            loop {
+                let read = socket.recv(&mut buffer).await.unwrap();
+                let sequence = client_train.sequence(&buffer[..read]).unwrap();
+                // Echo of probe 5 is overtaken by the one of probe 6
+                if sequence == 5 {
+                    held_back = Some(buffer[..read].to_vec());
+                    continue;
+                }
+                // Every fourth echo is lost on the way back
+                if sequence % 4 != 0 {
+                    socket.send(&buffer[..read]).await.unwrap();
+                }
+                if let Some(echo) = held_back.take() {
+                    socket.send(&echo).await.unwrap();
+                }
+                if sequence == 19 {
+                    // Duplicates don't count twice
+                    socket.send(&buffer[..read]).await.unwrap();
+                    break;
+                }
+            }
+        });
+
+        let echoes = train.measure().await.unwrap().unwrap();
+        client.await.unwrap();
+        assert_eq!(echoes.round_trips.len(), 15);
+        assert_eq!(echoes.reordered, 1);
+        assert_eq!(echoes.duplicates, 1);
+        drop(train);
+        assert!(endpoint.trains.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_datagram_session() {
+        let config = Arc::new(ServerConfig {
+            plan: PlanConfig {
+                cpu_rounds: 1,
+                squarings: 1000,
+                network_rounds: 1,
+                payload_size_kb: 16,
+                datagram_probes: 20,
+                datagram_interval_milliseconds: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let endpoint = DatagramEndpoint::bind(([127, 0, 0, 1], 0).into())
+            .await
+            .unwrap();
+        let context = ServerContext::new(&config)
+            .unwrap()
+            .with_datagram_endpoint(endpoint);
+        let (address, server) = warp::serve(measurement_routes(context.clone(), config))
+            .bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let outcome = client::measure(client::Options {
+            url: format!("ws://{}/ws", address),
+            with_proof: false,
+            disk_directory: std::env::temp_dir(),
+            client_token: None,
+            network_type: None,
+            client_version: None,
+        })
+        .await
+        .unwrap();
+        assert_eq!(outcome.status, SessionStatus::Completed);
+        let datagram = outcome.report.unwrap().datagram.unwrap();
+        assert_eq!(datagram.probes, 20);
+        assert!(datagram.echoed > 0);
+        assert!(datagram.received_by_client >= datagram.echoed);
+        assert!(datagram.latency.is_some());
+
+        let run_id = u128::from_str_radix(&outcome.stored_run_id.unwrap(), 16).unwrap();
+        let run = context
+            .storage
+            .get(run_id, Visibility::All)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(run.datagram, Some(datagram));
+        assert_eq!(run.configuration.datagram_probes, 20);
+    }
+}
@@ -0,0 +1,189 @@
+use anyhow::Result;
+use futures::stream::{SplitSink, SplitStream};
+use rand::rngs::OsRng;
+use shared::hash::HashAlgorithm;
+use shared::mac::{KeyExchange, MessageAuthenticator, Role, TAG_SIZE};
+use shared::nonce;
+use shared::{Handshake, Message, NetworkType, SessionLimits, MAX_CLIENT_VERSION_BYTES};
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::time::Duration;
+
+use crate::transport::{MessageAuthentication, Transport};
+use crate::utils::{send_client_msg_with_profiling, ProtocolViolation};
+
+/// Time client has to answer `ServerHello`
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Parameters of the session client and server agreed upon during the handshake
+pub(crate) struct NegotiatedParameters {
+    pub(crate) hash_algorithm: HashAlgorithm,
+    /// Largest frame sent to the client, `None` if frames aren't limited or client can't
+    /// reassemble chunks
+    pub(crate) max_frame_bytes: Option<usize>,
+    /// Kind of link client declared it is on
+    pub(crate) network_type: Option<NetworkType>,
+    /// Version or deployment label client declared, `None` if it declared none or an empty one
+    pub(crate) client_version: Option<String>,
+    /// Whether client binds nonces into its responses, so that challenges are sent bound to one
+    pub(crate) binds_nonces: bool,
+    /// Authenticator of the session's messages, if client took up message authentication.
+    /// Taken by the caller to authenticate messages following `ClientHello`.
+    pub(crate) message_authenticator: Option<MessageAuthenticator>,
+}
+
+impl NegotiatedParameters {
+    /// Fresh nonce for a challenge, empty if client doesn't bind nonces
+    pub(crate) fn challenge_nonce(&self) -> Vec<u8> {
+        if self.binds_nonces {
+            nonce::generate(&mut OsRng)
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+/// Capabilities the plan requires from clients or offers them
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ClientRequirements {
+    /// Whether clients which don't bind nonces are refused
+    pub(crate) nonces: bool,
+    pub(crate) message_authentication: MessageAuthentication,
+}
+
+/// Returned when client lacks a capability the plan requires
+#[derive(Debug, PartialEq)]
+pub(crate) struct UnsupportedClient(pub(crate) String);
+
+impl Display for UnsupportedClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for UnsupportedClient {}
+
+/// Offers supported parameters and advertises limits to the client, then validates the client's
+/// choice. Fails with `UnsupportedClient` if client lacks a capability in `requirements`.
+pub(crate) async fn perform_handshake<T: Transport>(
+    writer: &mut SplitSink<T, Vec<u8>>,
+    reader: &mut SplitStream<T>,
+    client_id: u128,
+    client_token: Option<String>,
+    hash_algorithms: &[HashAlgorithm],
+    limits: SessionLimits,
+    requirements: ClientRequirements,
+) -> Result<NegotiatedParameters> {
+    let key_exchange = if requirements.message_authentication.is_offered() {
+        Some(KeyExchange::generate(&mut OsRng))
+    } else {
+        None
+    };
+    let max_frame_bytes = limits
+        .max_frame_size_kb
+        .map(|max_frame_size_kb| max_frame_size_kb as usize * 1024);
+    let server_hello = Message::Handshake(Handshake::ServerHello {
+        session_id: format!("{:x}", client_id),
+        hash_algorithms: hash_algorithms.to_vec(),
+        limits,
+        client_token,
+        mac_public_key: key_exchange.as_ref().map(KeyExchange::public_key),
+    })
+    .encode()?;
+
+    let (client_response, _) = send_client_msg_with_profiling(
+        writer,
+        reader,
+        server_hello.as_slice(),
+        false,
+        HANDSHAKE_TIMEOUT,
+    )
+    .await?;
+
+    match client_response {
+        Message::Handshake(Handshake::ClientHello {
+            client_version: Some(client_version),
+            ..
+        }) if client_version.len() > MAX_CLIENT_VERSION_BYTES => Err(ProtocolViolation(format!(
+            "Client {:x} declared a version longer than {} bytes",
+            client_id, MAX_CLIENT_VERSION_BYTES
+        ))
+        .into()),
+        Message::Handshake(Handshake::ClientHello {
+            binds_nonces: false,
+            ..
+        }) if requirements.nonces => Err(UnsupportedClient(format!(
+            "Client {:x} doesn't bind nonces into its responses, which this server requires",
+            client_id
+        ))
+        .into()),
+        Message::Handshake(Handshake::ClientHello {
+            mac_public_key: None,
+            ..
+        }) if requirements.message_authentication == MessageAuthentication::Required => {
+            Err(UnsupportedClient(format!(
+                "Client {:x} doesn't authenticate its messages, which this server requires",
+                client_id
+            ))
+            .into())
+        }
+        Message::Handshake(Handshake::ClientHello {
+            hash_algorithm,
+            supports_chunks,
+            network_type,
+            client_version,
+            binds_nonces,
+            mac_public_key,
+        }) if hash_algorithms.contains(&hash_algorithm) => {
+            let message_authenticator = match (key_exchange, mac_public_key) {
+                (Some(key_exchange), Some(mac_public_key)) => Some(
+                    key_exchange
+                        .agree(Role::Server, &mac_public_key)
+                        .map_err(|e| {
+                            ProtocolViolation(format!(
+                                "Client {:x} sent an unusable key: {}",
+                                client_id, e
+                            ))
+                        })?,
+                ),
+                (None, Some(_)) => {
+                    return Err(ProtocolViolation(format!(
+                        "Client {:x} sent a key though message authentication was not offered",
+                        client_id
+                    ))
+                    .into())
+                }
+                (_, None) => None,
+            };
+            Ok(NegotiatedParameters {
+                hash_algorithm,
+                // Sealed frames are longer by their tag, which must fit in the limit too
+                max_frame_bytes: max_frame_bytes.filter(|_| supports_chunks).map(
+                    |max_frame_bytes| {
+                        if message_authenticator.is_some() {
+                            max_frame_bytes - TAG_SIZE
+                        } else {
+                            max_frame_bytes
+                        }
+                    },
+                ),
+                network_type,
+                client_version: client_version.filter(|client_version| !client_version.is_empty()),
+                binds_nonces,
+                message_authenticator,
+            })
+        }
+        Message::Handshake(Handshake::ClientHello { hash_algorithm, .. }) => {
+            Err(ProtocolViolation(format!(
+                "Client {:x} chose hash algorithm {:?} which was not offered",
+                client_id, hash_algorithm
+            ))
+            .into())
+        }
+        msg => Err(ProtocolViolation(format!(
+            "Expected ClientHello from client {:x}, got {} message",
+            client_id, msg
+        ))
+        .into()),
+    }
+}
@@ -1,20 +1,78 @@
-use shared::{Message, Response};
+use shared::{Challenge, Message, Response};
 
 use num_bigint::BigUint;
-use shared::challenges::roundtrip::RoundtripVerifier;
+use shared::challenges::roundtrip::{RoundtripSpotVerifier, RoundtripVerifier, SpotCheck};
 use shared::challenges::timelock::TimelockVerifier;
+use shared::merkle;
+
+/// Outcome of checking data returned in a network challenge
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum NetworkVerification {
+    Verified,
+    /// Client answered the challenge, but returned data doesn't match what it was sent. Either
+    /// client cheated or data was mangled on the way, e.g. by a WebSocket intermediary.
+    Mismatch,
+    /// Response isn't an answer to the challenge
+    Invalid,
+}
+
+impl NetworkVerification {
+    fn from_match(matches: bool) -> Self {
+        if matches {
+            NetworkVerification::Verified
+        } else {
+            NetworkVerification::Mismatch
+        }
+    }
+}
+
+/// Message sending `challenge` bound to `nonce`, see `shared::nonce`. A plain challenge for
+/// an empty nonce, whose verifier then expects an unbound response.
+pub(crate) fn challenge_message(challenge: Challenge, nonce: Vec<u8>) -> Message {
+    if nonce.is_empty() {
+        Message::Challenge(challenge)
+    } else {
+        Message::BoundChallenge { nonce, challenge }
+    }
+}
 
 pub(crate) fn verify_network_challenge_response(
     roundtrip_verifier: RoundtripVerifier,
     response: Message,
+) -> NetworkVerification {
+    match response {
+        Message::Response(Response::NetworkChallengeResponse(serialized_answer)) => {
+            NetworkVerification::from_match(roundtrip_verifier.verify(serialized_answer))
+        }
+        _ => NetworkVerification::Invalid,
+    }
+}
+
+/// `None` if response isn't an answer to the challenge
+pub(crate) fn verify_spot_checked_network_challenge_response(
+    roundtrip_verifier: &RoundtripSpotVerifier,
+    response: Message,
+) -> Option<SpotCheck> {
+    match response {
+        Message::Response(Response::SpotCheckedNetworkChallengeResponse { data, merkle_root }) => {
+            Some(roundtrip_verifier.verify(data, merkle_root))
+        }
+        _ => None,
+    }
+}
+
+/// Whether response to a `Challenge::ChunkProofRequest` for chunk at `index` carries the original
+/// chunk along with its proof
+pub(crate) fn verify_chunk_proof_response(
+    roundtrip_verifier: &RoundtripSpotVerifier,
+    index: usize,
+    response: Message,
 ) -> bool {
     match response {
-        Message::Response(response) => match response {
-            Response::NetworkChallengeResponse(serialized_answer) => {
-                roundtrip_verifier.verify(serialized_answer)
-            }
-            _ => false,
-        },
+        Message::Response(Response::ChunkProofResponse { chunk, proof }) => {
+            merkle::decode_proof(&proof)
+                .is_some_and(|proof| roundtrip_verifier.verify_chunk(index, &chunk, &proof))
+        }
         _ => false,
     }
 }
@@ -29,8 +87,23 @@ pub(crate) fn verify_cpu_challenge_response(
                 let client_answer = BigUint::from_bytes_be(serialized_answer.as_slice());
                 timelock_verifier.verify(client_answer)
             }
+            Response::CPUChallengeProofResponse { answer, proof } => timelock_verifier
+                .verify_with_proof(
+                    BigUint::from_bytes_be(answer.as_slice()),
+                    BigUint::from_bytes_be(proof.as_slice()),
+                ),
             _ => false,
         },
         _ => false,
     }
 }
+
+/// Answer of a CPU challenge response which carries no proof, such answers can be verified in a batch
+pub(crate) fn cpu_challenge_answer(response: &Message) -> Option<BigUint> {
+    match response {
+        Message::Response(Response::CPUChallengeResponse(serialized_answer)) => {
+            Some(BigUint::from_bytes_be(serialized_answer.as_slice()))
+        }
+        _ => None,
+    }
+}
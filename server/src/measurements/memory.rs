@@ -0,0 +1,147 @@
+use shared::{Challenge, Data, Message, Response};
+use std::cmp;
+use std::error::Error;
+use std::fmt::{self, Display};
+
+/// Returned when a session would hold more memory than its ceiling allows
+#[derive(Debug, PartialEq)]
+pub(crate) struct MemoryLimitExceeded {
+    pub(crate) limit: usize,
+    pub(crate) requested: usize,
+}
+
+impl Display for MemoryLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Session memory limit of {} bytes exceeded, {} bytes requested",
+            self.limit, self.requested
+        )
+    }
+}
+
+impl Error for MemoryLimitExceeded {}
+
+/// Approximate accounting of memory held by a session: challenge payloads,
+/// data kept for verification and buffered responses.
+pub(crate) struct MemoryBudget {
+    limit: Option<usize>,
+    used: usize,
+}
+
+impl MemoryBudget {
+    /// `None` means no ceiling
+    pub(crate) fn new(limit: Option<usize>) -> Self {
+        MemoryBudget { limit, used: 0 }
+    }
+
+    /// Accounts `bytes` more held by the session, nothing is accounted if it exceeds the ceiling
+    pub(crate) fn allocate(&mut self, bytes: usize) -> Result<(), MemoryLimitExceeded> {
+        let requested = self.used.saturating_add(bytes);
+        match self.limit {
+            Some(limit) if requested > limit => Err(MemoryLimitExceeded { limit, requested }),
+            _ => {
+                self.used = requested;
+                Ok(())
+            }
+        }
+    }
+
+    pub(crate) fn release(&mut self, bytes: usize) {
+        self.used = self.used.saturating_sub(bytes);
+    }
+
+    /// Bytes accounted so far
+    pub(crate) fn used(&self) -> usize {
+        self.used
+    }
+
+    /// Releases whatever was accounted since `used` bytes were, e.g. by a round which failed
+    /// before it released its allocations
+    pub(crate) fn release_to(&mut self, used: usize) {
+        self.used = cmp::min(self.used, used);
+    }
+}
+
+fn challenge_size(challenge: &Challenge) -> usize {
+    match challenge {
+        Challenge::CPUChallenge(payload)
+        | Challenge::NetworkChallenge(payload)
+        | Challenge::SpotCheckedNetworkChallenge(payload)
+        | Challenge::DiskChallenge(payload)
+        | Challenge::LatencyProbe(payload)
+        | Challenge::DownloadChallenge(payload)
+        | Challenge::UploadChallenge(payload)
+        | Challenge::DatagramChallenge(payload)
+        | Challenge::ChunkProofRequest(payload)
+        | Challenge::Unsupported { payload, .. } => payload.len(),
+    }
+}
+
+/// Approximate number of bytes held by a decoded message
+pub(crate) fn message_size(message: &Message) -> usize {
+    match message {
+        Message::Challenge(challenge) => challenge_size(challenge),
+        Message::BoundChallenge { nonce, challenge } => nonce.len() + challenge_size(challenge),
+        Message::Response(response) => match response {
+            Response::CPUChallengeResponse(payload)
+            | Response::NetworkChallengeResponse(payload)
+            | Response::DiskChallengeResponse(payload)
+            | Response::LatencyProbeResponse(payload)
+            | Response::DownloadChallengeResponse(payload)
+            | Response::UploadChallengeResponse(payload)
+            | Response::DatagramChallengeResponse(payload) => payload.len(),
+            Response::SpotCheckedNetworkChallengeResponse { data, merkle_root } => {
+                data.len() + merkle_root.len()
+            }
+            Response::CPUChallengeProofResponse { answer, proof } => answer.len() + proof.len(),
+            Response::ChunkProofResponse { chunk, proof } => chunk.len() + proof.len(),
+            Response::UnsupportedChallenge { .. } | Response::Unknown { .. } => 0,
+        },
+        Message::Data(data) => match data {
+            Data::Info(text) | Data::Error { message: text, .. } | Data::Result(text) => text.len(),
+            Data::Unknown { .. } => 0,
+        },
+        Message::Rejected { message, .. } => message.len(),
+        Message::ChallengeChunk { data, .. } => data.len(),
+        Message::Unknown
+        | Message::Handshake(_)
+        | Message::SessionClosed { .. }
+        | Message::MeasurementReport(_)
+        | Message::Progress(_) => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::measurements::memory::{message_size, MemoryBudget, MemoryLimitExceeded};
+    use shared::{Message, Response};
+
+    #[test]
+    fn test_memory_budget() {
+        let mut budget = MemoryBudget::new(Some(100));
+        budget.allocate(60).unwrap();
+        assert_eq!(
+            budget.allocate(50),
+            Err(MemoryLimitExceeded {
+                limit: 100,
+                requested: 110
+            })
+        );
+        budget.release(60);
+        budget.allocate(100).unwrap();
+
+        let mut unlimited = MemoryBudget::new(None);
+        unlimited.allocate(usize::MAX).unwrap();
+        unlimited.allocate(1).unwrap();
+    }
+
+    #[test]
+    fn test_message_size() {
+        let message = Message::Response(Response::SpotCheckedNetworkChallengeResponse {
+            data: vec![0; 1024],
+            merkle_root: vec![0; 32],
+        });
+        assert_eq!(message_size(&message), 1056);
+    }
+}
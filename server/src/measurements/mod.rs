@@ -1,5 +1,27 @@
+mod auth;
 mod challenges;
+mod datagram;
+mod handshake;
 mod helpers;
+mod memory;
+mod plausibility;
+mod plugins;
+mod retry;
+mod route;
 mod score;
+mod surprise;
+mod tcp;
 
+pub(crate) use auth::{Credentials, MeasurementAuth, MeasurementAuthConfig};
 pub(crate) use challenges::perform_all;
+pub use challenges::ClientChallenger;
+pub use datagram::DatagramEndpoint;
+pub(crate) use plausibility::{ImplausibleRound, PlausibilityBounds};
+pub(crate) use retry::RetriedRound;
+pub use route::{measurement_route, measurement_routes, SessionRejection};
+pub(crate) use score::{
+    cpu_round_milliseconds, find_mean, network_round_milliseconds, preview_score, Aggregation,
+    Scale, ScorePreview, ScoringModel, Strictness,
+};
+pub(crate) use surprise::SurpriseRound;
+pub(crate) use tcp::serve_tcp;
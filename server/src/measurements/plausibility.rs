@@ -0,0 +1,82 @@
+//! Lower bounds on the time of a round, of the work it takes at the speed of the fastest client a
+//! plan is meant for. A client answering faster than that can't have done the work, e.g. it
+//! guessed or replayed answers, so its run is scored 0 and the round is recorded with it.
+
+use serde::{Deserialize, Serialize};
+
+/// Round client answered faster than the fastest client the plan is meant for could have
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub(crate) struct ImplausibleRound {
+    /// Kind of the challenge, see `kinds::challenge`
+    pub(crate) kind_id: u16,
+    /// Index of the round among rounds of its kind, starting at 0
+    pub(crate) round: usize,
+    pub(crate) milliseconds: u128,
+    /// Least time the round could have taken
+    pub(crate) min_milliseconds: u128,
+}
+
+/// Speed of the fastest client a plan is meant for, see `PlanConfig::fastest_squarings_per_second`
+/// and `PlanConfig::fastest_throughput_kb_per_second`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct PlausibilityBounds {
+    pub(crate) fastest_squarings_per_second: u64,
+    pub(crate) fastest_throughput_kb_per_second: u64,
+}
+
+impl PlausibilityBounds {
+    /// Least time a CPU round of `squarings` takes
+    pub(crate) fn cpu_milliseconds(&self, squarings: u32) -> u64 {
+        u64::from(squarings) * 1000 / self.fastest_squarings_per_second
+    }
+
+    /// Least time a network round of `payload_size_kb` takes, its payload travels both ways
+    pub(crate) fn network_milliseconds(&self, payload_size_kb: usize) -> u64 {
+        2 * payload_size_kb as u64 * 1000 / self.fastest_throughput_kb_per_second
+    }
+}
+
+/// Round of `kind_id` which took `milliseconds`, if that is less than `min_milliseconds`
+pub(crate) fn implausible_round(
+    kind_id: u16,
+    round: usize,
+    milliseconds: u128,
+    min_milliseconds: u64,
+) -> Option<ImplausibleRound> {
+    let min_milliseconds = u128::from(min_milliseconds);
+    if milliseconds < min_milliseconds {
+        Some(ImplausibleRound {
+            kind_id,
+            round,
+            milliseconds,
+            min_milliseconds,
+        })
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::measurements::plausibility::{implausible_round, PlausibilityBounds};
+    use shared::kinds;
+
+    #[test]
+    fn test_bounds() {
+        let bounds = PlausibilityBounds {
+            fastest_squarings_per_second: 2_000_000,
+            fastest_throughput_kb_per_second: 125_000,
+        };
+        assert_eq!(bounds.cpu_milliseconds(200_000), 100);
+        assert_eq!(bounds.network_milliseconds(1024), 16);
+        // Rounds too small to take a whole millisecond are never implausible
+        assert_eq!(bounds.cpu_milliseconds(1000), 0);
+        assert!(implausible_round(kinds::challenge::CPU_CHALLENGE, 0, 0, 0).is_none());
+
+        assert!(implausible_round(kinds::challenge::CPU_CHALLENGE, 2, 100, 100).is_none());
+        let implausible = implausible_round(kinds::challenge::CPU_CHALLENGE, 2, 12, 100).unwrap();
+        assert_eq!(implausible.round, 2);
+        assert_eq!(implausible.milliseconds, 12);
+        assert_eq!(implausible.min_milliseconds, 100);
+    }
+}
@@ -0,0 +1,187 @@
+use crate::config::PlanConfig;
+use crate::measurements::score::{Aggregation, ChallengeResults};
+use crate::types::SessionEventKind;
+use shared::challenges::bandwidth::{DownloadGenerator, UploadGenerator};
+use shared::challenges::disk::DiskChallengeGenerator;
+use shared::challenges::ChallengeGenerator;
+use shared::kinds;
+use std::time::Duration;
+
+/// How rounds of a plugin are sent and timed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum RoundMode {
+    /// Bulk transfer over the link: sent in chunks if negotiated, counted among active transfers,
+    /// given the network round timeout and accounted as network transfer time of the session
+    Transfer,
+    /// Work done by the client once the challenge arrived, accounted as its compute time
+    Compute { timeout: Duration },
+}
+
+/// Thresholds rounds of a scored plugin are held to. They count towards the disk sub-score and
+/// weight of `ScoringModel`, disk being the only scored plugin kind.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct PluginScoring {
+    /// Payload of a round, reported along with the sub-score
+    pub(crate) data_size_kb: usize,
+    pub(crate) ideal_milliseconds: u128,
+    pub(crate) max_milliseconds: u128,
+    pub(crate) aggregation: Aggregation,
+}
+
+/// Challenge kind whose rounds are a single challenge answered by a single response, run by
+/// `ClientChallenger` the same way whatever the kind measures
+pub(crate) struct ChallengePlugin {
+    pub(crate) generator: Box<dyn ChallengeGenerator>,
+    pub(crate) rounds: usize,
+    pub(crate) mode: RoundMode,
+    /// `None` for kinds which are only reported alongside the score
+    pub(crate) scoring: Option<PluginScoring>,
+}
+
+impl ChallengePlugin {
+    /// Round times of the plugin as they are scored, `None` if the kind isn't scored
+    pub(crate) fn results<'a>(&'a self, rounds: &'a [u128]) -> Option<ChallengeResults<'a>> {
+        let scoring = self.scoring.as_ref()?;
+        Some(ChallengeResults::plugin(
+            self.generator.name(),
+            self.generator.kind_id(),
+            scoring,
+            rounds,
+        ))
+    }
+}
+
+/// Plugins measured after CPU and network rounds and latency probes, in order they are measured.
+/// A new kind only needs a `ChallengeGenerator` listed here, kinds without rounds are skipped.
+pub(crate) fn registered_plugins(
+    plan: &PlanConfig,
+    payload_size_kb: usize,
+    disk_rounds: usize,
+) -> Vec<ChallengePlugin> {
+    vec![
+        ChallengePlugin {
+            generator: Box::new(DownloadGenerator {
+                size_in_kbs: payload_size_kb,
+            }),
+            rounds: plan.download_rounds,
+            mode: RoundMode::Transfer,
+            scoring: None,
+        },
+        ChallengePlugin {
+            generator: Box::new(UploadGenerator {
+                size_in_kbs: payload_size_kb as u32,
+            }),
+            rounds: plan.upload_rounds,
+            mode: RoundMode::Transfer,
+            scoring: None,
+        },
+        ChallengePlugin {
+            generator: Box::new(DiskChallengeGenerator {
+                size_in_kbs: plan.disk_payload_size_kb,
+            }),
+            rounds: disk_rounds,
+            mode: RoundMode::Compute {
+                timeout: Duration::from_millis(plan.disk_max_milliseconds),
+            },
+            scoring: Some(PluginScoring {
+                data_size_kb: plan.disk_payload_size_kb,
+                ideal_milliseconds: plan.disk_ideal_milliseconds.into(),
+                max_milliseconds: plan.disk_max_milliseconds.into(),
+                aggregation: plan.disk_aggregation,
+            }),
+        },
+    ]
+}
+
+/// Event recorded once a round of a plugin completed, kinds which predate plugins keep their own
+pub(crate) fn completed_event(kind_id: u16, round: usize, milliseconds: u128) -> SessionEventKind {
+    match kind_id {
+        kinds::challenge::DISK_CHALLENGE => SessionEventKind::DiskChallengeCompleted {
+            round,
+            milliseconds,
+        },
+        kinds::challenge::DOWNLOAD_CHALLENGE => SessionEventKind::DownloadChallengeCompleted {
+            round,
+            milliseconds,
+        },
+        kinds::challenge::UPLOAD_CHALLENGE => SessionEventKind::UploadChallengeCompleted {
+            round,
+            milliseconds,
+        },
+        kind_id => SessionEventKind::ChallengeCompleted {
+            kind_id,
+            round,
+            milliseconds,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::PlanConfig;
+    use crate::measurements::plugins::{
+        completed_event, registered_plugins, PluginScoring, RoundMode,
+    };
+    use crate::types::SessionEventKind;
+    use shared::kinds;
+    use std::time::Duration;
+
+    #[test]
+    fn test_registered_plugins() {
+        let plan = PlanConfig {
+            download_rounds: 2,
+            upload_rounds: 1,
+            disk_ideal_milliseconds: 100,
+            disk_max_milliseconds: 500,
+            ..PlanConfig::default()
+        };
+        let plugins = registered_plugins(&plan, 16, 3);
+        let registered: Vec<_> = plugins
+            .iter()
+            .map(|plugin| (plugin.generator.kind_id(), plugin.rounds, plugin.mode))
+            .collect();
+        assert_eq!(
+            registered,
+            vec![
+                (kinds::challenge::DOWNLOAD_CHALLENGE, 2, RoundMode::Transfer),
+                (kinds::challenge::UPLOAD_CHALLENGE, 1, RoundMode::Transfer),
+                (
+                    kinds::challenge::DISK_CHALLENGE,
+                    3,
+                    RoundMode::Compute {
+                        timeout: Duration::from_millis(500)
+                    }
+                ),
+            ]
+        );
+        // Only disk rounds are scored
+        assert!(plugins[..2].iter().all(|plugin| plugin.scoring.is_none()));
+        assert_eq!(
+            plugins[2].scoring,
+            Some(PluginScoring {
+                data_size_kb: plan.disk_payload_size_kb,
+                ideal_milliseconds: 100,
+                max_milliseconds: 500,
+                aggregation: plan.disk_aggregation,
+            })
+        );
+        assert!(plugins[0].results(&[20]).is_none());
+        assert_eq!(plugins[2].results(&[20]).unwrap().rounds(), &[20]);
+
+        assert!(matches!(
+            completed_event(kinds::challenge::DISK_CHALLENGE, 1, 20),
+            SessionEventKind::DiskChallengeCompleted {
+                round: 1,
+                milliseconds: 20
+            }
+        ));
+        assert!(matches!(
+            completed_event(0x7f00, 0, 20),
+            SessionEventKind::ChallengeCompleted {
+                kind_id: 0x7f00,
+                round: 0,
+                milliseconds: 20
+            }
+        ));
+    }
+}
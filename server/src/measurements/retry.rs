@@ -0,0 +1,95 @@
+//! Rounds tried again after a transient failure, e.g. a client which stalled once or sent a
+//! frame which couldn't be decoded, instead of failing the whole session. Each session has a
+//! budget of retries, and every retry takes `PlanConfig::retry_penalty` points off the score.
+
+use serde::{Deserialize, Serialize};
+
+use crate::utils::is_transient;
+
+/// Round which was tried again, as stored with the run
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub(crate) struct RetriedRound {
+    /// Kind of the challenge, see `kinds::challenge`
+    pub(crate) kind_id: u16,
+    /// Index of the round among rounds of its kind, starting at 0
+    pub(crate) round: usize,
+    /// Times it was tried again
+    pub(crate) retries: usize,
+}
+
+/// Retries a session has left and the rounds it spent them on
+pub(crate) struct RetryBudget {
+    left: usize,
+    retried: Vec<RetriedRound>,
+}
+
+impl RetryBudget {
+    pub(crate) fn new(retries: usize) -> Self {
+        RetryBudget {
+            left: retries,
+            retried: vec![],
+        }
+    }
+
+    /// Spends a retry on round `round` of `kind_id` which failed with `e`. False if the failure
+    /// left the connection unusable or no retry is left, the session fails then.
+    pub(crate) fn retry(&mut self, kind_id: u16, round: usize, e: &anyhow::Error) -> bool {
+        if self.left == 0 || !is_transient(e) {
+            return false;
+        }
+        self.left -= 1;
+        match self
+            .retried
+            .iter_mut()
+            .find(|retried| retried.kind_id == kind_id && retried.round == round)
+        {
+            Some(retried) => retried.retries += 1,
+            None => self.retried.push(RetriedRound {
+                kind_id,
+                round,
+                retries: 1,
+            }),
+        }
+        true
+    }
+
+    /// Retries spent over all rounds
+    pub(crate) fn spent(&self) -> usize {
+        self.retried.iter().map(|retried| retried.retries).sum()
+    }
+
+    pub(crate) fn into_retried(self) -> Vec<RetriedRound> {
+        self.retried
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::measurements::retry::RetryBudget;
+    use crate::utils::{ProtocolViolation, ResponseTimedOut};
+    use shared::kinds;
+    use std::time::Duration;
+
+    #[test]
+    fn test_retry_budget() {
+        let timed_out = || {
+            anyhow::Error::from(ResponseTimedOut {
+                timeout: Duration::from_secs(1),
+            })
+        };
+        let mut budget = RetryBudget::new(2);
+        // Connection can't be trusted after a protocol violation
+        assert!(!budget.retry(
+            kinds::challenge::CPU_CHALLENGE,
+            0,
+            &ProtocolViolation("Unexpected message".to_owned()).into()
+        ));
+        assert!(budget.retry(kinds::challenge::CPU_CHALLENGE, 0, &timed_out()));
+        assert!(budget.retry(kinds::challenge::CPU_CHALLENGE, 0, &timed_out()));
+        assert!(!budget.retry(kinds::challenge::NETWORK_CHALLENGE, 1, &timed_out()));
+        assert_eq!(budget.spent(), 2);
+        let retried = budget.into_retried();
+        assert_eq!(retried.len(), 1);
+        assert_eq!(retried[0].retries, 2);
+    }
+}
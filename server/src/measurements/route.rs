@@ -0,0 +1,2591 @@
+use crate::capacity::{SessionLimit, SessionSlot};
+use crate::client_identity::ClientIdentity;
+use crate::config::{EndpointConfig, PlanConfig, ServerConfig};
+use crate::fleet::parse_tags;
+use crate::measurements::{perform_all, Credentials};
+use crate::storage::api_key_digest;
+use crate::tasks::TaskKind;
+use crate::tls::TlsConnection;
+use crate::transport::{Transport, WebSocketTransport};
+use crate::tuning::current_plan;
+use crate::types::{AddressFamily, PriorityClass, ServerContext, SessionParameters};
+use crate::utils::routing::remote_address;
+use crate::watchdog::MemoryPressure;
+use futures::{SinkExt, StreamExt};
+use http::{HeaderValue, StatusCode};
+use shared::{Message, RejectionReason};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
+use uuid::Uuid;
+use warp::filters::BoxedFilter;
+use warp::reply::Reply;
+use warp::{Filter, Rejection};
+
+/// Retry hint of clients rejected because server is at its session limit
+const SESSION_LIMIT_RETRY_AFTER_SECONDS: u64 = 5;
+
+/// Why a client is not measured. Refused with an HTTP error before the upgrade, unless the
+/// client asked for `in_band_rejections` or the rejection came after it (e.g. a queue timeout),
+/// in which case it is sent as `Message::Rejected` over the upgraded connection.
+#[derive(Debug)]
+pub struct SessionRejection {
+    reason: RejectionReason,
+    retry_after_seconds: Option<u64>,
+    message: String,
+}
+
+impl SessionRejection {
+    /// `retry_after_seconds` is `None` when retrying is pointless, `message` is shown to the client
+    pub fn new(reason: RejectionReason, retry_after_seconds: Option<u64>, message: String) -> Self {
+        SessionRejection {
+            reason,
+            retry_after_seconds,
+            message,
+        }
+    }
+
+    fn into_message(self) -> Message {
+        Message::Rejected {
+            reason: self.reason,
+            retry_after_seconds: self.retry_after_seconds,
+            message: self.message,
+        }
+    }
+
+    /// 429 for rate limited, 403 for banned and 401 for unauthenticated clients, 503 otherwise. Body is JSON with the
+    /// reason, retry-after and message, retry-after is also set as `Retry-After` header.
+    fn into_http_response(self) -> warp::reply::Response {
+        let (status, reason) = match self.reason {
+            RejectionReason::RateLimited => (StatusCode::TOO_MANY_REQUESTS, "rate_limited"),
+            RejectionReason::Banned => (StatusCode::FORBIDDEN, "banned"),
+            RejectionReason::Unauthorized => (StatusCode::UNAUTHORIZED, "unauthorized"),
+            RejectionReason::Capacity => (StatusCode::SERVICE_UNAVAILABLE, "capacity"),
+            RejectionReason::Maintenance => (StatusCode::SERVICE_UNAVAILABLE, "maintenance"),
+            RejectionReason::Unknown { .. } => (StatusCode::SERVICE_UNAVAILABLE, "unknown"),
+        };
+        let mut response = warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({
+                "reason": reason,
+                "retry_after_seconds": self.retry_after_seconds,
+                "message": self.message,
+            })),
+            status,
+        )
+        .into_response();
+        if let Some(retry_after_seconds) = self.retry_after_seconds {
+            response
+                .headers_mut()
+                .insert("retry-after", HeaderValue::from(retry_after_seconds));
+        }
+        response
+    }
+}
+
+/// Filter upgrading the request to a WebSocket and measuring the client over it.
+/// It matches no path, so it can be mounted anywhere and combined with other filters,
+/// e.g. `warp::path("ws").and(auth).and(measurement_route(context, config))`.
+pub fn measurement_route(
+    context: ServerContext,
+    config: Arc<ServerConfig>,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = Rejection> + Clone {
+    session_route(context, config, None)
+}
+
+/// `/ws` measuring clients with the plan of `config` and `/ws/<name>` for each of its `endpoints`
+pub fn measurement_routes(
+    context: ServerContext,
+    config: Arc<ServerConfig>,
+) -> BoxedFilter<(warp::reply::Response,)> {
+    let routes = warp::path("ws")
+        .and(warp::path::end())
+        .and(measurement_route(context.clone(), config.clone()))
+        .boxed();
+    config
+        .endpoints
+        .iter()
+        .fold(routes, |routes, (name, endpoint_config)| {
+            let endpoint_route = warp::path("ws")
+                .and(warp::path(name.clone()))
+                .and(warp::path::end())
+                .and(session_route(
+                    context.clone(),
+                    config.clone(),
+                    Some(Endpoint::new(endpoint_config)),
+                ));
+            routes.or(endpoint_route).unify().boxed()
+        })
+}
+
+/// Endpoint of `EndpointConfig` with its session limit set up
+#[derive(Clone)]
+struct Endpoint {
+    plan: PlanConfig,
+    session_limit: Option<SessionLimit>,
+}
+
+impl Endpoint {
+    fn new(config: &EndpointConfig) -> Self {
+        Endpoint {
+            plan: config.plan.clone(),
+            session_limit: config.session_limit.clone().map(SessionLimit::new),
+        }
+    }
+}
+
+/// API key of the client. Browsers can't set headers of websockets, so they pass it in the query.
+fn api_key() -> impl Filter<Extract = (Option<String>,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("x-api-key")
+        .and(warp::query::<HashMap<String, String>>())
+        .map(|api_key: Option<String>, query: HashMap<String, String>| {
+            api_key.or_else(|| query.get("api_key").cloned())
+        })
+}
+
+/// Measures clients with the plan of `endpoint`, or if there is none with the plan of their
+/// tenant or the (possibly retuned) plan of `config`
+fn session_route(
+    context: ServerContext,
+    config: Arc<ServerConfig>,
+    endpoint: Option<Endpoint>,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = Rejection> + Clone {
+    let plan = {
+        let context = context.clone();
+        let config = config.clone();
+        let endpoint = endpoint.clone();
+        api_key().map(move |api_key: Option<String>| match &endpoint {
+            Some(endpoint) => endpoint.plan.clone(),
+            None => match config
+                .tenant(api_key.as_deref())
+                .and_then(|(_, tenant)| tenant.plan.as_ref())
+            {
+                Some(plan) => plan.clone(),
+                None => current_plan(&context, &config),
+            },
+        })
+    };
+    let session_limit = warp::any().map(move || {
+        endpoint
+            .as_ref()
+            .and_then(|endpoint| endpoint.session_limit.clone())
+    });
+    let context = warp::any().map(move || context.clone());
+    let keepalive = config.keepalive();
+    let session_parameters = api_key()
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(remote_address())
+        .and(warp::ext::optional())
+        .map(
+            move |api_key: Option<String>,
+                  authorization: Option<String>,
+                  query: HashMap<String, String>,
+                  remote: Option<SocketAddr>,
+                  tls_connection: Option<TlsConnection>| {
+                // Browsers can't set headers of websockets, so they pass tokens in the query too
+                let token = authorization
+                    .as_deref()
+                    .and_then(|authorization| authorization.strip_prefix("Bearer "))
+                    .map(|token| token.trim().to_owned())
+                    .or_else(|| query.get("access_token").cloned());
+                SessionParameters {
+                    priority_class: config.priority_class(api_key.as_deref()),
+                    strictness: config.strictness(api_key.as_deref()),
+                    tags: query
+                        .get("tags")
+                        .map(|tags| {
+                            parse_tags(tags).unwrap_or_else(|e| {
+                                warn!("Ignoring tags of the client: {:?}", e);
+                                Default::default()
+                            })
+                        })
+                        .unwrap_or_default(),
+                    memory_limit_bytes: config.session_memory_limit_bytes,
+                    disk_challenge_rounds: config.disk_challenge_rounds,
+                    private: query.get("private").map(String::as_str) == Some("true")
+                        || api_key
+                            .as_ref()
+                            .is_some_and(|api_key| config.private_tenants.contains(api_key)),
+                    address_family: remote.as_ref().map(AddressFamily::of),
+                    dual_stack_of: query.get("dual_stack_of").and_then(|run_id| {
+                        u128::from_str_radix(run_id, 16)
+                            .map_err(|e| warn!("Ignoring dual_stack_of of the client: {:?}", e))
+                            .ok()
+                    }),
+                    tls_handshake: tls_connection.map(|connection| connection.handshake),
+                    tls_comparison_of: query.get("tls_comparison_of").and_then(|run_id| {
+                        u128::from_str_radix(run_id, 16)
+                            .map_err(|e| warn!("Ignoring tls_comparison_of of the client: {:?}", e))
+                            .ok()
+                    }),
+                    client_identity: Some(ClientIdentity::resolve(
+                        query.get("client_token").map(String::as_str),
+                    )),
+                    principal: None,
+                    tenant: config
+                        .tenant(api_key.as_deref())
+                        .map(|(name, _)| name.to_owned()),
+                    api_key_digest: api_key
+                        .as_deref()
+                        .filter(|api_key| config.tenant(Some(api_key)).is_some())
+                        .map(api_key_digest),
+                    credentials: Credentials { api_key, token },
+                }
+            },
+        );
+
+    // Browsers and some websocket libraries hide the response of a failed upgrade, their clients
+    // ask for rejections to be sent over the upgraded connection instead
+    let in_band_rejections =
+        warp::query::<HashMap<String, String>>().map(|query: HashMap<String, String>| {
+            query.get("in_band_rejections").map(String::as_str) == Some("true")
+        });
+
+    warp::ws()
+        .and(context)
+        .and(plan)
+        .and(session_limit)
+        .and(session_parameters)
+        .and(in_band_rejections)
+        .and(remote_address())
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .and_then(
+            move |ws: warp::ws::Ws,
+                  context: ServerContext,
+                  plan: PlanConfig,
+                  endpoint_session_limit: Option<SessionLimit>,
+                  mut session_parameters: SessionParameters,
+                  in_band_rejections: bool,
+                  remote: Option<SocketAddr>,
+                  forwarded_for: Option<String>| async move {
+                // Correlation id of the session, also used as id of the stored run
+                let client_id = Uuid::new_v4().as_u128();
+                let admission = admission(
+                    &context,
+                    &plan,
+                    endpoint_session_limit.as_ref(),
+                    remote,
+                    forwarded_for.as_deref(),
+                    client_id,
+                    &mut session_parameters,
+                )
+                .await;
+                let mut response = match admission {
+                    Err(rejection) if in_band_rejections => ws
+                        .on_upgrade(move |socket| {
+                            reject(WebSocketTransport::new(socket), rejection, client_id)
+                        })
+                        .into_response(),
+                    Err(rejection) => {
+                        info!(
+                            "Refusing upgrade of client[{:x}]: {:?}",
+                            client_id, rejection
+                        );
+                        rejection.into_http_response()
+                    }
+                    Ok(slot) => {
+                        // Refuse messages which alone would exceed the memory ceiling before buffering them
+                        let ws = match session_parameters.memory_limit_bytes {
+                            Some(limit) => ws.max_message_size(limit),
+                            None => ws,
+                        };
+                        ws.on_upgrade(move |socket| {
+                            let transport = WebSocketTransport::new(socket);
+                            handle_connection(
+                                match keepalive {
+                                    Some(keepalive) => transport.with_keepalive(keepalive),
+                                    None => transport,
+                                },
+                                context,
+                                plan,
+                                session_parameters,
+                                client_id,
+                                slot,
+                            )
+                        })
+                        .into_response()
+                    }
+                };
+                response
+                    .headers_mut()
+                    .insert("access-control-allow-origin", HeaderValue::from_static("*"));
+                response.headers_mut().insert(
+                    "x-request-id",
+                    HeaderValue::from_str(&format!("{:x}", client_id))
+                        .expect("hex string is a valid header value"),
+                );
+                Ok::<_, Rejection>(response)
+            },
+        )
+}
+
+/// Slot of a session of `plan` if server and its session hooks admit it, limited by
+/// `endpoint_session_limit` or by the server wide session limit if the endpoint has none
+pub(super) async fn admission(
+    context: &ServerContext,
+    plan: &PlanConfig,
+    endpoint_session_limit: Option<&SessionLimit>,
+    remote: Option<SocketAddr>,
+    forwarded_for: Option<&str>,
+    client_id: u128,
+    session_parameters: &mut SessionParameters,
+) -> Result<SessionSlot, SessionRejection> {
+    if let Some(rejection) = rejection(context)
+        .or_else(|| plan_rejection(plan))
+        .or_else(|| rate_limit_rejection(context, remote, forwarded_for))
+    {
+        return Err(rejection);
+    }
+    // Credentials aren't kept around for the rest of the session
+    let credentials = std::mem::take(&mut session_parameters.credentials);
+    if let Some(measurement_auth) = &context.measurement_auth {
+        session_parameters.principal =
+            Some(measurement_auth.authenticate(&credentials, SystemTime::now())?);
+    }
+    for hook in &context.session_hooks {
+        hook.before_session(client_id, remote, session_parameters)
+            .await?;
+    }
+    admit(
+        endpoint_session_limit.or(context.session_limit.as_ref()),
+        session_parameters.priority_class,
+    )
+    .ok_or_else(session_limit_rejection)
+}
+
+/// Rejection of the session instead of starting a session, if server can't take one now
+fn rejection(context: &ServerContext) -> Option<SessionRejection> {
+    if context.draining.is_draining() {
+        return Some(SessionRejection {
+            reason: RejectionReason::Maintenance,
+            retry_after_seconds: None,
+            message: "Server is shutting down, try again later".to_owned(),
+        });
+    }
+    if let Some(memory_watchdog) = &context.memory_watchdog {
+        if memory_watchdog.pressure() == MemoryPressure::Hard {
+            let poll_interval = memory_watchdog.poll_interval();
+            return Some(SessionRejection {
+                reason: RejectionReason::Capacity,
+                // Rounded up, retrying before memory is sampled again is pointless
+                retry_after_seconds: Some(
+                    poll_interval.as_secs() + u64::from(poll_interval.subsec_nanos() > 0),
+                ),
+                message: "Server is low on memory, try again later".to_owned(),
+            });
+        }
+    }
+    None
+}
+
+/// Rejection of the session if no client could finish a session of `plan`, e.g. one passed
+/// to `measurement_route` without being validated. Retrying is pointless until the plan is fixed.
+fn plan_rejection(plan: &PlanConfig) -> Option<SessionRejection> {
+    let e = plan.validate().err()?;
+    error!("Refusing session of plan {}: {}", plan.name, e);
+    Some(SessionRejection {
+        reason: RejectionReason::Maintenance,
+        retry_after_seconds: None,
+        message: format!("Server is misconfigured: {}", e),
+    })
+}
+
+/// Rejection of the session if client's IP has started too many sessions recently
+fn rate_limit_rejection(
+    context: &ServerContext,
+    remote: Option<SocketAddr>,
+    forwarded_for: Option<&str>,
+) -> Option<SessionRejection> {
+    let rate_limiter = context.rate_limiter.as_ref()?;
+    let client_ip: IpAddr = rate_limiter.client_ip(remote, forwarded_for)?;
+    let retry_after = rate_limiter.check(client_ip, Instant::now()).err()?;
+    Some(SessionRejection {
+        reason: RejectionReason::RateLimited,
+        // Rounded up, retrying earlier would be rejected again
+        retry_after_seconds: retry_after
+            .map(|retry_after| retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0)),
+        message: format!("Too many sessions from {}, try again later", client_ip),
+    })
+}
+
+/// Slot of the session, `None` if `session_limit` is reached and its queue for `priority` is full
+fn admit(session_limit: Option<&SessionLimit>, priority: PriorityClass) -> Option<SessionSlot> {
+    match session_limit {
+        Some(session_limit) => session_limit.admit(priority),
+        None => Some(SessionSlot::Unlimited),
+    }
+}
+
+fn session_limit_rejection() -> SessionRejection {
+    SessionRejection {
+        reason: RejectionReason::Capacity,
+        retry_after_seconds: Some(SESSION_LIMIT_RETRY_AFTER_SECONDS),
+        message: "Server is measuring as many clients as it can, try again later".to_owned(),
+    }
+}
+
+/// Tells client why it is not measured and closes the connection
+pub(super) async fn reject<T: Transport>(
+    transport: T,
+    rejection: SessionRejection,
+    client_id: u128,
+) {
+    info!("Rejecting client[{:x}]: {:?}", client_id, rejection);
+    let (mut writer, _) = transport.split();
+    let result = async {
+        writer.send(rejection.into_message().encode()?).await?;
+        writer.close().await?;
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    if let Err(e) = result {
+        warn!("Unable to reject client[{:x}]: {:?}", client_id, e);
+    }
+}
+
+pub(super) async fn handle_connection<T: Transport>(
+    transport: T,
+    context: ServerContext,
+    plan: PlanConfig,
+    session_parameters: SessionParameters,
+    client_id: u128,
+    slot: SessionSlot,
+) {
+    let queued = matches!(slot, SessionSlot::Queued(..));
+    if queued {
+        info!(
+            "Queueing client[{:x}], server is at its session limit",
+            client_id
+        );
+    }
+    let _slot = match slot.wait().await {
+        Some(slot) => slot,
+        None => return reject(transport, session_limit_rejection(), client_id).await,
+    };
+    // Server may have started shutting down while the connection waited
+    if let Some(rejection) = rejection(&context).filter(|_| queued) {
+        return reject(transport, rejection, client_id).await;
+    }
+    let _active_session = context.active_sessions.enter();
+    let _task = context
+        .tasks
+        .enter(TaskKind::Connection, format!("session {:x}", client_id));
+    if let Err(e) = perform_all(transport, context, &plan, session_parameters, client_id).await {
+        error!("Error during measurements client[{:x}]: {:?}", client_id, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::capacity::{SessionLimit, SessionLimitConfig};
+    use crate::config::{EndpointConfig, PlanConfig, ServerConfig, TenantConfig};
+    use crate::measurements::route::{measurement_route, measurement_routes};
+    use crate::measurements::{MeasurementAuth, MeasurementAuthConfig, Strictness};
+    use crate::rate_limit::{RateLimitConfig, RateLimiter};
+    use crate::storage::{ChaosStorage, MemoryStorage, StorageChaosConfig};
+    use crate::transport::MessageAuthentication;
+    use crate::types::test_utils::server_context;
+    use crate::types::{
+        AddressFamily, ClientData, RunStatus, ServerContext, SessionEventKind, Storage, Visibility,
+    };
+    use crate::watchdog::{MemoryWatchdog, MemoryWatchdogConfig};
+    use rand::rngs::OsRng;
+    use shared::challenges::timelock::Timelock;
+    use shared::hash::HashAlgorithm;
+    use shared::mac::{KeyExchange, MessageAuthenticator, Role};
+    use shared::merkle::{chunk_with_proof, decode_chunk_index, merkle_root, CHUNK_SIZE};
+    use shared::{
+        kinds, Challenge, Data, ErrorCode, Handshake, Message, NetworkType, RejectionReason,
+        Response, SessionStatus, MAX_CLIENT_VERSION_BYTES,
+    };
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use warp::Filter;
+
+    /// Request upgrading to a websocket, for responses of refused upgrades
+    fn upgrade_request(path: &str) -> warp::test::RequestBuilder {
+        warp::test::request()
+            .path(path)
+            .header("connection", "upgrade")
+            .header("upgrade", "websocket")
+            .header("sec-websocket-version", "13")
+            .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+    }
+
+    #[tokio::test]
+    async fn test_measurement_route_composes() {
+        let route = warp::path("measure")
+            .and(warp::header::exact("authorization", "secret"))
+            .and(measurement_route(server_context(), Default::default()));
+
+        assert!(warp::test::ws()
+            .path("/measure")
+            .handshake(route.clone())
+            .await
+            .is_err());
+
+        let mut client = warp::test::ws()
+            .path("/measure")
+            .header("authorization", "secret")
+            .handshake(route)
+            .await
+            .unwrap();
+        let hello = client.recv().await.unwrap();
+        assert!(matches!(
+            Message::decode(hello.as_bytes()).unwrap(),
+            Message::Handshake(Handshake::ServerHello { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_embedded_in_application() {
+        let config = Arc::new(ServerConfig {
+            plan: PlanConfig {
+                cpu_rounds: 1,
+                squarings: 1000,
+                network_rounds: 1,
+                payload_size_kb: 16,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let context = ServerContext::new(&config).unwrap();
+        let application = warp::path("health").map(|| "ok");
+        let routes =
+            application.or(warp::path("measure").and(measurement_route(context.clone(), config)));
+        let (address, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let outcome = client::measure(client::Options {
+            url: format!("ws://{}/measure", address),
+            with_proof: false,
+            disk_directory: std::env::temp_dir(),
+            client_token: None,
+            network_type: None,
+            client_version: None,
+        })
+        .await
+        .unwrap();
+        assert_eq!(outcome.status, SessionStatus::Completed);
+        let run_id = u128::from_str_radix(&outcome.stored_run_id.unwrap(), 16).unwrap();
+        assert!(context.storage.contains(run_id).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_storage_failures() {
+        let config = Arc::new(ServerConfig {
+            plan: PlanConfig {
+                cpu_rounds: 1,
+                squarings: 1000,
+                network_rounds: 1,
+                payload_size_kb: 16,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let measure = |context: ServerContext| {
+            let route = measurement_route(context, config.clone());
+            let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+            tokio::spawn(server);
+            client::measure(client::Options {
+                url: format!("ws://{}/", address),
+                with_proof: false,
+                disk_directory: std::env::temp_dir(),
+                client_token: None,
+                network_type: None,
+                client_version: None,
+            })
+        };
+        let backend = Arc::new(MemoryStorage::default());
+        let chaos = |failure_percent, seed| {
+            Arc::new(
+                ChaosStorage::new(
+                    backend.clone(),
+                    StorageChaosConfig {
+                        max_latency_milliseconds: 0,
+                        failure_percent,
+                        seed: Some(seed),
+                    },
+                )
+                .unwrap(),
+            )
+        };
+
+        // Seed fails the first two attempts, the third one stores the run
+        let mut context = server_context();
+        context.storage = chaos(50.0, 3);
+        let outcome = measure(context.clone()).await.unwrap();
+        assert_eq!(outcome.status, SessionStatus::Completed);
+        let run_id = u128::from_str_radix(&outcome.stored_run_id.unwrap(), 16).unwrap();
+        assert!(backend.contains(run_id).await.unwrap());
+        assert_eq!(context.pending_runs.len(), 0);
+
+        // Client still gets its results while storage is down, the run is stored once it recovers
+        let mut context = server_context();
+        context.storage = chaos(100.0, 0);
+        let outcome = measure(context.clone()).await.unwrap();
+        assert_eq!(outcome.status, SessionStatus::Completed);
+        assert_eq!(outcome.stored_run_id, None);
+        let report = outcome.report.unwrap();
+        assert_eq!(context.pending_runs.len(), 1);
+        assert_eq!(context.pending_runs.persist(&*backend).await, 1);
+        let run_id = u128::from_str_radix(&report.session_id, 16).unwrap();
+        assert!(backend.contains(run_id).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_full_session() {
+        let context = server_context();
+        let config = ServerConfig {
+            disk_challenge_rounds: 1,
+            plan: PlanConfig {
+                cpu_rounds: 2,
+                squarings: 1000,
+                cpu_target_milliseconds: Some(20),
+                cpu_calibration_squarings: 1000,
+                network_rounds: 2,
+                payload_size_kb: 16,
+                latency_probes: 4,
+                download_rounds: 2,
+                upload_rounds: 1,
+                disk_payload_size_kb: 16,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let route = warp::path("ws").and(measurement_route(context.clone(), Arc::new(config)));
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let outcome = client::measure(client::Options {
+            url: format!("ws://{}/ws", address),
+            with_proof: true,
+            disk_directory: std::env::temp_dir(),
+            client_token: None,
+            network_type: None,
+            client_version: Some("1.4.2".to_owned()),
+        })
+        .await
+        .unwrap();
+        assert_eq!(outcome.status, SessionStatus::Completed);
+        let report = outcome.report.unwrap();
+        assert_eq!(report.challenges.len(), 3);
+        assert_eq!(outcome.stored_run_id.as_ref(), Some(&report.session_id));
+        let rounds_completed: Vec<u32> = outcome
+            .progress
+            .iter()
+            .map(|progress| progress.rounds_completed)
+            .collect();
+        assert_eq!(rounds_completed, vec![1, 2, 3, 4, 5]);
+        assert!(outcome
+            .progress
+            .iter()
+            .all(|progress| progress.total_rounds == 5));
+        let latency = report.latency.as_ref().unwrap();
+        assert_eq!(latency.probes, 4);
+        assert!(latency.min_microseconds <= latency.mean_microseconds);
+        assert!(latency.mean_microseconds <= latency.max_microseconds);
+        let download = report.download.as_ref().unwrap();
+        assert_eq!(download.size_kb, 16);
+        assert_eq!(download.round_milliseconds.len(), 2);
+        assert_eq!(report.upload.as_ref().unwrap().round_milliseconds.len(), 1);
+        let last = outcome.progress.last().unwrap();
+        let disk = report.challenges.last().unwrap();
+        assert_eq!(last.kind_id, disk.kind_id);
+        assert_eq!(last.sub_score, disk.sub_score);
+        let run_id = u128::from_str_radix(&report.session_id, 16).unwrap();
+        let run = context
+            .storage
+            .get(run_id, Visibility::All)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(run.score as u64, report.score);
+        assert_eq!(run.score_breakdown, report.score_breakdown);
+        assert!(report.score_breakdown.disk_score.is_some());
+        assert_eq!(run.address_family, Some(AddressFamily::Ipv4));
+        assert_eq!(run.client_version.as_deref(), Some("1.4.2"));
+        assert_eq!(run.latency().as_ref(), Some(latency));
+        assert_eq!(run.download().as_ref(), Some(download));
+        assert_eq!(run.upload(), report.upload);
+        assert!(run.configuration.calibrated_squarings.is_some());
+        // No other session was measured meanwhile
+        assert_eq!(run.network_round_overlapping_transfers, vec![0, 0]);
+        assert!(run.cpu_calibration_milliseconds.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_rejected_under_memory_pressure() {
+        let mut context = server_context();
+        let memory_watchdog = MemoryWatchdog::new(MemoryWatchdogConfig {
+            soft_limit_bytes: 1000,
+            hard_limit_bytes: 2000,
+            poll_interval: Duration::from_millis(1500),
+        });
+        memory_watchdog.record(2500);
+        context.memory_watchdog = Some(Arc::new(memory_watchdog));
+        let route = warp::path("ws").and(measurement_route(context, Default::default()));
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let options = client::Options {
+            url: format!("ws://{}/ws", address),
+            with_proof: false,
+            disk_directory: std::env::temp_dir(),
+            client_token: None,
+            network_type: None,
+            client_version: None,
+        };
+        let error = client::measure_with_retry(
+            options,
+            &client::RetryPolicy {
+                max_attempts: 1,
+                max_delay: Duration::from_secs(60),
+            },
+        )
+        .await
+        .unwrap_err();
+        let rejected = error.downcast_ref::<client::Rejected>().unwrap();
+        assert_eq!(rejected.reason, RejectionReason::Capacity);
+        // Rounded up to the next poll of the watchdog
+        assert_eq!(rejected.retry_after, Some(Duration::from_secs(2)));
+    }
+
+    #[tokio::test]
+    async fn test_rejected_while_draining() {
+        let context = server_context();
+        context.draining.start();
+        let route = warp::path("ws").and(measurement_route(context.clone(), Default::default()));
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let error = client::measure_with_retry(
+            client::Options {
+                url: format!("ws://{}/ws", address),
+                with_proof: false,
+                disk_directory: std::env::temp_dir(),
+                client_token: None,
+                network_type: None,
+                client_version: None,
+            },
+            &client::RetryPolicy {
+                max_attempts: 1,
+                max_delay: Duration::from_secs(60),
+            },
+        )
+        .await
+        .unwrap_err();
+        let rejected = error.downcast_ref::<client::Rejected>().unwrap();
+        assert_eq!(rejected.reason, RejectionReason::Maintenance);
+        assert_eq!(context.active_sessions.count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_session_limit() {
+        let mut context = server_context();
+        let session_limit = SessionLimit::new(SessionLimitConfig {
+            max_sessions: 1,
+            queue_length: 1,
+            queue_timeout: Duration::from_millis(200),
+        });
+        context.session_limit = Some(session_limit.clone());
+        let route = measurement_route(context, Default::default());
+        let decode = |message: warp::ws::Message| Message::decode(message.as_bytes()).unwrap();
+        let rejected = |message| match decode(message) {
+            Message::Rejected {
+                reason,
+                retry_after_seconds,
+                ..
+            } => reason == RejectionReason::Capacity && retry_after_seconds == Some(5),
+            _ => false,
+        };
+
+        let mut measured = warp::test::ws().handshake(route.clone()).await.unwrap();
+        assert!(matches!(
+            decode(measured.recv().await.unwrap()),
+            Message::Handshake(Handshake::ServerHello { .. })
+        ));
+        let mut queued = warp::test::ws().handshake(route.clone()).await.unwrap();
+        // Upgrade is refused when the queue is full, a queue timeout can only be told in band
+        let full = upgrade_request("/").reply(&route).await;
+        assert_eq!(full.status(), 503);
+        assert_eq!(full.headers()["retry-after"], "5");
+        assert_eq!(session_limit.queue_depth(), 1);
+        // Session of the first client outlasts the queue timeout
+        assert!(rejected(queued.recv().await.unwrap()));
+        assert_eq!(session_limit.queue_depth(), 0);
+
+        let mut waiting = warp::test::ws().handshake(route).await.unwrap();
+        drop(measured);
+        assert!(matches!(
+            decode(waiting.recv().await.unwrap()),
+            Message::Handshake(Handshake::ServerHello { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_endpoints() {
+        let context = server_context();
+        let quick = PlanConfig {
+            name: "quick".to_owned(),
+            cpu_rounds: 0,
+            network_rounds: 1,
+            payload_size_kb: 16,
+            ..Default::default()
+        };
+        let config = ServerConfig {
+            endpoints: vec![
+                (
+                    "quick".to_owned(),
+                    EndpointConfig {
+                        plan: quick,
+                        session_limit: None,
+                    },
+                ),
+                (
+                    "thorough".to_owned(),
+                    EndpointConfig {
+                        session_limit: Some(SessionLimitConfig {
+                            max_sessions: 0,
+                            queue_length: 0,
+                            queue_timeout: Duration::from_secs(1),
+                        }),
+                        ..Default::default()
+                    },
+                ),
+            ]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        };
+        let routes = measurement_routes(context.clone(), Arc::new(config));
+        let (address, server) = warp::serve(routes.clone()).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let outcome = client::measure(client::Options {
+            url: format!("ws://{}/ws/quick", address),
+            with_proof: false,
+            disk_directory: std::env::temp_dir(),
+            client_token: None,
+            network_type: None,
+            client_version: None,
+        })
+        .await
+        .unwrap();
+        assert_eq!(outcome.status, SessionStatus::Completed);
+        let report = outcome.report.unwrap();
+        assert_eq!(report.challenges.len(), 1);
+        let run_id = u128::from_str_radix(&outcome.stored_run_id.unwrap(), 16).unwrap();
+        let run = context
+            .storage
+            .get(run_id, Visibility::All)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(run.configuration.plan_name, "quick");
+
+        // Endpoint at its own limit rejects clients while the server wide one has room
+        let decode = |message: warp::ws::Message| Message::decode(message.as_bytes()).unwrap();
+        let thorough = upgrade_request("/ws/thorough").reply(&routes).await;
+        assert_eq!(thorough.status(), 503);
+        let mut default = warp::test::ws()
+            .path("/ws")
+            .handshake(routes.clone())
+            .await
+            .unwrap();
+        assert!(matches!(
+            decode(default.recv().await.unwrap()),
+            Message::Handshake(Handshake::ServerHello { .. })
+        ));
+        assert!(warp::test::ws()
+            .path("/ws/unknown")
+            .handshake(routes)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let mut context = server_context();
+        context.rate_limiter = Some(Arc::new(RateLimiter::new(RateLimitConfig {
+            sessions_per_minute: 1,
+            burst: None,
+            forwarded_for_proxies: 1,
+        })));
+        let route = measurement_route(context, Default::default());
+        let connect = |ip: &str| {
+            warp::test::ws()
+                .header("x-forwarded-for", format!("198.51.100.7, {}", ip))
+                .handshake(route.clone())
+        };
+        let decode = |message: warp::ws::Message| Message::decode(message.as_bytes()).unwrap();
+
+        let mut first = connect("192.0.2.1").await.unwrap();
+        assert!(matches!(
+            decode(first.recv().await.unwrap()),
+            Message::Handshake(Handshake::ServerHello { .. })
+        ));
+        let second = upgrade_request("/")
+            .header("x-forwarded-for", "198.51.100.7, 192.0.2.1")
+            .reply(&route)
+            .await;
+        assert_eq!(second.status(), 429);
+        assert_eq!(second.headers()["retry-after"], "60");
+        let body: serde_json::Value = serde_json::from_slice(second.body()).unwrap();
+        assert_eq!(body["reason"], "rate_limited");
+        assert_eq!(body["retry_after_seconds"], 60);
+        assert!(body["message"].as_str().unwrap().contains("192.0.2.1"));
+        let mut other = connect("192.0.2.2").await.unwrap();
+        assert!(matches!(
+            decode(other.recv().await.unwrap()),
+            Message::Handshake(Handshake::ServerHello { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_rejected_plan() {
+        let context = server_context();
+        let config = ServerConfig {
+            plan: PlanConfig {
+                squarings: u32::MAX,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let route = measurement_route(context.clone(), Arc::new(config));
+        let refused = upgrade_request("/").reply(&route).await;
+        assert_eq!(refused.status(), 503);
+        assert!(refused.headers().get("retry-after").is_none());
+        let body: serde_json::Value = serde_json::from_slice(refused.body()).unwrap();
+        assert_eq!(body["reason"], "maintenance");
+
+        // Reference client asks for rejections in band
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let error = client::measure(client::Options {
+            url: format!("ws://{}/", address),
+            with_proof: false,
+            disk_directory: std::env::temp_dir(),
+            client_token: None,
+            network_type: None,
+            client_version: None,
+        })
+        .await
+        .unwrap_err();
+        let rejected = error.downcast_ref::<client::Rejected>().unwrap();
+        assert_eq!(rejected.reason, RejectionReason::Maintenance);
+        assert_eq!(rejected.retry_after, None);
+        assert!(rejected.message.contains("4294967295 squarings"));
+        assert_eq!(context.active_sessions.count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_stalled_client() {
+        let config = ServerConfig {
+            plan: PlanConfig {
+                squarings: 10,
+                cpu_round_timeout_milliseconds: Some(100),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let route = measurement_route(server_context(), Arc::new(config));
+        let mut client = warp::test::ws().handshake(route).await.unwrap();
+        let decode = |message: warp::ws::Message| Message::decode(message.as_bytes()).unwrap();
+
+        let hello = decode(client.recv().await.unwrap());
+        match hello {
+            Message::Handshake(Handshake::ServerHello { limits, .. }) => {
+                assert_eq!(limits.cpu_round_timeout_milliseconds, 100)
+            }
+            message => panic!("Unexpected {}", message),
+        }
+        let client_hello = Message::Handshake(Handshake::ClientHello {
+            hash_algorithm: HashAlgorithm::Blake3,
+            supports_chunks: false,
+            network_type: None,
+            client_version: None,
+            binds_nonces: false,
+            mac_public_key: None,
+        });
+        client
+            .send(warp::ws::Message::binary(client_hello.encode().unwrap()))
+            .await;
+        assert!(matches!(
+            decode(client.recv().await.unwrap()),
+            Message::Challenge(Challenge::CPUChallenge(_))
+        ));
+
+        // Client never answers the challenge
+        match decode(client.recv().await.unwrap()) {
+            Message::Data(Data::Error { code, message }) => {
+                assert_eq!(code, ErrorCode::Timeout);
+                assert!(message.starts_with("No response within"));
+            }
+            message => panic!("Unexpected {}", message),
+        }
+        assert!(matches!(
+            decode(client.recv().await.unwrap()),
+            Message::SessionClosed {
+                status: SessionStatus::Failed,
+                runs_completed: 0,
+                ..
+            }
+        ));
+        client.recv_closed().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_lost_connection() {
+        let config = ServerConfig {
+            keepalive_interval: Duration::from_millis(20),
+            keepalive_timeout: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let (address, server) = warp::serve(measurement_route(server_context(), Arc::new(config)))
+            .bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        // Client upgrades the connection and goes silent, answering neither pings nor `ServerHello`
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(
+                b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: upgrade\r\nUpgrade: websocket\r\n\
+                  Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let started = Instant::now();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        // Server gives up long before the 10 seconds `ServerHello` may take to be answered
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(received.starts_with(b"HTTP/1.1 101"));
+
+        let header_end = received
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .unwrap();
+        let mut frames = &received[header_end + 4..];
+        let mut opcodes = vec![];
+        while frames.len() >= 2 {
+            let (length, header) = match frames[1] & 0x7f {
+                126 => (u16::from_be_bytes([frames[2], frames[3]]) as usize, 4),
+                length => (length as usize, 2),
+            };
+            opcodes.push(frames[0] & 0x0f);
+            frames = &frames[header + length..];
+        }
+        // `ServerHello`, pings, `SessionClosed` and the close frame
+        assert_eq!(opcodes.first(), Some(&0x2));
+        assert!(opcodes.contains(&0x9));
+        assert_eq!(opcodes.last(), Some(&0x8));
+
+        // Reference client keeps answering pings while it solves rounds outlasting the timeout
+        let context = server_context();
+        let config = ServerConfig {
+            keepalive_interval: Duration::from_millis(20),
+            keepalive_timeout: Duration::from_millis(50),
+            plan: PlanConfig {
+                cpu_rounds: 2,
+                squarings: 100_000,
+                network_rounds: 1,
+                payload_size_kb: 16,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let route = warp::path("ws").and(measurement_route(context.clone(), Arc::new(config)));
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let outcome = client::measure(client::Options {
+            url: format!("ws://{}/ws", address),
+            with_proof: false,
+            disk_directory: std::env::temp_dir(),
+            client_token: None,
+            network_type: None,
+            client_version: None,
+        })
+        .await
+        .unwrap();
+        assert_eq!(outcome.status, SessionStatus::Completed);
+        let run_id = u128::from_str_radix(&outcome.stored_run_id.unwrap(), 16).unwrap();
+        let run = context
+            .storage
+            .get(run_id, Visibility::All)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(run
+            .cpu_challenge_timings_in_milis
+            .iter()
+            .all(|milliseconds| *milliseconds > 50));
+    }
+
+    #[tokio::test]
+    async fn test_protocol_violation() {
+        let route = measurement_route(server_context(), Default::default());
+        let decode = |message: warp::ws::Message| Message::decode(message.as_bytes()).unwrap();
+
+        // Client answers `ServerHello` with something other than `ClientHello`, or declares a
+        // version too long to be stored
+        for response in [
+            Message::Response(Response::NetworkChallengeResponse(vec![1])),
+            Message::Handshake(Handshake::ClientHello {
+                hash_algorithm: HashAlgorithm::Blake3,
+                supports_chunks: false,
+                network_type: None,
+                client_version: Some("1".repeat(MAX_CLIENT_VERSION_BYTES + 1)),
+                binds_nonces: false,
+                mac_public_key: None,
+            }),
+        ] {
+            let mut client = warp::test::ws().handshake(route.clone()).await.unwrap();
+            assert!(matches!(
+                decode(client.recv().await.unwrap()),
+                Message::Handshake(Handshake::ServerHello { .. })
+            ));
+            client
+                .send(warp::ws::Message::binary(response.encode().unwrap()))
+                .await;
+            match decode(client.recv().await.unwrap()) {
+                Message::Data(Data::Error { code, .. }) => {
+                    assert_eq!(code, ErrorCode::ProtocolViolation)
+                }
+                message => panic!("Unexpected {}", message),
+            }
+            assert!(matches!(
+                decode(client.recv().await.unwrap()),
+                Message::SessionClosed {
+                    status: SessionStatus::Failed,
+                    ..
+                }
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_network_round_retry() {
+        let context = server_context();
+        let config = Arc::new(ServerConfig {
+            plan: PlanConfig {
+                cpu_rounds: 0,
+                network_rounds: 1,
+                payload_size_kb: 16,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let decode = |message: warp::ws::Message| Message::decode(message.as_bytes()).unwrap();
+        // Runs a session answering network challenges with data mangled `mangled` times,
+        // returns the closing message
+        let session = |mangled: usize| {
+            let route = measurement_route(context.clone(), config.clone());
+            async move {
+                let mut client = warp::test::ws().handshake(route).await.unwrap();
+                let client_hello = Message::Handshake(Handshake::ClientHello {
+                    hash_algorithm: HashAlgorithm::Blake3,
+                    supports_chunks: false,
+                    network_type: None,
+                    client_version: None,
+                    binds_nonces: false,
+                    mac_public_key: None,
+                });
+                client
+                    .send(warp::ws::Message::binary(client_hello.encode().unwrap()))
+                    .await;
+                let mut payloads = vec![];
+                loop {
+                    match decode(client.recv().await.unwrap()) {
+                        Message::Challenge(Challenge::NetworkChallenge(mut payload)) => {
+                            payloads.push(payload.clone());
+                            if payloads.len() <= mangled {
+                                *payload.last_mut().unwrap() ^= 1;
+                            }
+                            let response =
+                                Message::Response(Response::NetworkChallengeResponse(payload));
+                            client
+                                .send(warp::ws::Message::binary(response.encode().unwrap()))
+                                .await;
+                        }
+                        message @ Message::SessionClosed { .. } => return (message, payloads),
+                        _ => {}
+                    }
+                }
+            }
+        };
+
+        // Mangled data is retried with a fresh payload
+        let (closed, payloads) = session(1).await;
+        assert_eq!(payloads.len(), 2);
+        assert_ne!(payloads[0], payloads[1]);
+        let run_id = match closed {
+            Message::SessionClosed {
+                status: SessionStatus::Completed,
+                stored_run_id: Some(run_id),
+                ..
+            } => u128::from_str_radix(&run_id, 16).unwrap(),
+            message => panic!("Unexpected {}", message),
+        };
+        let run = context
+            .storage
+            .get(run_id, Visibility::All)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(run.events.iter().any(|event| matches!(
+            event.kind,
+            SessionEventKind::NetworkChallengeMismatched { round: 0, .. }
+        )));
+        assert_eq!(run.network_challenge_timings_in_milis.len(), 1);
+
+        // But only once
+        let (closed, payloads) = session(2).await;
+        assert_eq!(payloads.len(), 2);
+        assert!(matches!(
+            closed,
+            Message::SessionClosed {
+                status: SessionStatus::Failed,
+                runs_completed: 0,
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_spot_checked_network_rounds() {
+        let context = server_context();
+        let config = Arc::new(ServerConfig {
+            plan: PlanConfig {
+                cpu_rounds: 0,
+                network_rounds: 2,
+                payload_size_kb: 16,
+                spot_check_sample_chunks: Some(16),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let route = measurement_route(context.clone(), config.clone());
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let outcome = client::measure(client::Options {
+            url: format!("ws://{}/", address),
+            with_proof: false,
+            disk_directory: std::env::temp_dir(),
+            client_token: None,
+            network_type: None,
+            client_version: None,
+        })
+        .await
+        .unwrap();
+        assert_eq!(outcome.status, SessionStatus::Completed);
+        let run_id = u128::from_str_radix(&outcome.stored_run_id.unwrap(), 16).unwrap();
+        let run = context
+            .storage
+            .get(run_id, Visibility::All)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(run.configuration.spot_check_sample_chunks, Some(16));
+        assert_eq!(run.network_challenge_timings_in_milis.len(), 2);
+
+        // Runs a session returning payloads with `corrupted_chunks` leading chunks corrupted,
+        // along with the merkle root of the original payloads. Returns the closing message,
+        // number of network challenges and indices of chunks server asked for again.
+        let session = |corrupted_chunks: usize| {
+            let route = measurement_route(context.clone(), config.clone());
+            async move {
+                let mut client = warp::test::ws().handshake(route).await.unwrap();
+                let client_hello = Message::Handshake(Handshake::ClientHello {
+                    hash_algorithm: HashAlgorithm::Blake3,
+                    supports_chunks: false,
+                    network_type: None,
+                    client_version: None,
+                    binds_nonces: false,
+                    mac_public_key: None,
+                });
+                client
+                    .send(warp::ws::Message::binary(client_hello.encode().unwrap()))
+                    .await;
+                let mut rounds = 0;
+                let mut requested_chunks = vec![];
+                let mut last_payload = vec![];
+                loop {
+                    let message = Message::decode(client.recv().await.unwrap().as_bytes()).unwrap();
+                    let response = match message {
+                        Message::Challenge(Challenge::SpotCheckedNetworkChallenge(payload)) => {
+                            rounds += 1;
+                            let mut data = payload.clone();
+                            for byte in data.iter_mut().take(corrupted_chunks * CHUNK_SIZE) {
+                                *byte ^= 1;
+                            }
+                            last_payload = payload;
+                            Response::SpotCheckedNetworkChallengeResponse {
+                                merkle_root: merkle_root(&last_payload),
+                                data,
+                            }
+                        }
+                        Message::Challenge(Challenge::ChunkProofRequest(request)) => {
+                            let index = decode_chunk_index(&request).unwrap() as usize;
+                            requested_chunks.push(index);
+                            let (chunk, proof) = chunk_with_proof(&last_payload, index).unwrap();
+                            Response::ChunkProofResponse { chunk, proof }
+                        }
+                        message @ Message::SessionClosed { .. } => {
+                            return (message, rounds, requested_chunks)
+                        }
+                        _ => continue,
+                    };
+                    client
+                        .send(warp::ws::Message::binary(
+                            Message::Response(response).encode().unwrap(),
+                        ))
+                        .await;
+                }
+            }
+        };
+
+        // A corrupted chunk is sent again on its own, instead of the whole payload
+        let (closed, rounds, requested_chunks) = session(1).await;
+        assert_eq!(rounds, 2);
+        assert_eq!(requested_chunks, vec![0, 0]);
+        assert!(matches!(
+            closed,
+            Message::SessionClosed {
+                status: SessionStatus::Completed,
+                ..
+            }
+        ));
+
+        // Payload corrupted all over fails the round, once retried with a fresh payload
+        let (closed, rounds, requested_chunks) = session(16).await;
+        assert_eq!(rounds, 2);
+        assert!(requested_chunks.is_empty());
+        assert!(matches!(
+            closed,
+            Message::SessionClosed {
+                status: SessionStatus::Failed,
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_hash_algorithms() {
+        let context = server_context();
+        let mut plan = PlanConfig {
+            cpu_rounds: 0,
+            network_rounds: 1,
+            payload_size_kb: 16,
+            ..Default::default()
+        };
+        let decode = |message: warp::ws::Message| Message::decode(message.as_bytes()).unwrap();
+
+        // xxHash64 isn't offered by default, and a client can't pick it on its own
+        let route = measurement_route(
+            context.clone(),
+            Arc::new(ServerConfig {
+                plan: plan.clone(),
+                ..Default::default()
+            }),
+        );
+        let mut client = warp::test::ws().handshake(route).await.unwrap();
+        match decode(client.recv().await.unwrap()) {
+            Message::Handshake(Handshake::ServerHello {
+                hash_algorithms, ..
+            }) => assert_eq!(
+                hash_algorithms,
+                vec![HashAlgorithm::Blake3, HashAlgorithm::Sha256]
+            ),
+            message => panic!("Unexpected {}", message),
+        }
+        let client_hello = Message::Handshake(Handshake::ClientHello {
+            hash_algorithm: HashAlgorithm::XxHash64,
+            supports_chunks: false,
+            network_type: None,
+            client_version: None,
+            binds_nonces: false,
+            mac_public_key: None,
+        });
+        client
+            .send(warp::ws::Message::binary(client_hello.encode().unwrap()))
+            .await;
+        match decode(client.recv().await.unwrap()) {
+            Message::Data(Data::Error { code, .. }) => {
+                assert_eq!(code, ErrorCode::ProtocolViolation)
+            }
+            message => panic!("Unexpected {}", message),
+        }
+
+        // Once offered it comes first, so the reference client picks it
+        plan.offer_xxhash64 = true;
+        let route = measurement_route(
+            context.clone(),
+            Arc::new(ServerConfig {
+                plan,
+                ..Default::default()
+            }),
+        );
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let outcome = client::measure(client::Options {
+            url: format!("ws://{}/", address),
+            with_proof: false,
+            disk_directory: std::env::temp_dir(),
+            client_token: None,
+            network_type: None,
+            client_version: None,
+        })
+        .await
+        .unwrap();
+        assert_eq!(outcome.status, SessionStatus::Completed);
+        let run_id = u128::from_str_radix(&outcome.stored_run_id.unwrap(), 16).unwrap();
+        let run = context
+            .storage
+            .get(run_id, Visibility::All)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(run.configuration.hash_algorithm, HashAlgorithm::XxHash64);
+    }
+
+    #[tokio::test]
+    async fn test_strictness() {
+        let context = server_context();
+        let mut config = ServerConfig {
+            plan: PlanConfig {
+                cpu_rounds: 0,
+                network_rounds: 2,
+                payload_size_kb: 16,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        config
+            .strictness_modes
+            .insert("lenient".to_owned(), Strictness::Lenient);
+        config
+            .strictness_modes
+            .insert("audit".to_owned(), Strictness::AuditOnly);
+        let config = Arc::new(config);
+        let decode = |message: warp::ws::Message| Message::decode(message.as_bytes()).unwrap();
+        // Runs a session of the tenant of `api_key` mangling both attempts of the first network
+        // round, returns the stored run
+        let session = |api_key: &'static str| {
+            let route = measurement_route(context.clone(), config.clone());
+            let context = context.clone();
+            async move {
+                let mut client = warp::test::ws()
+                    .header("x-api-key", api_key)
+                    .handshake(route)
+                    .await
+                    .unwrap();
+                let client_hello = Message::Handshake(Handshake::ClientHello {
+                    hash_algorithm: HashAlgorithm::Blake3,
+                    supports_chunks: false,
+                    network_type: None,
+                    client_version: None,
+                    binds_nonces: false,
+                    mac_public_key: None,
+                });
+                client
+                    .send(warp::ws::Message::binary(client_hello.encode().unwrap()))
+                    .await;
+                let mut challenges = 0;
+                let run_id = loop {
+                    match decode(client.recv().await.unwrap()) {
+                        Message::Challenge(Challenge::NetworkChallenge(mut payload)) => {
+                            challenges += 1;
+                            if challenges <= 2 {
+                                *payload.last_mut().unwrap() ^= 1;
+                            }
+                            let response =
+                                Message::Response(Response::NetworkChallengeResponse(payload));
+                            client
+                                .send(warp::ws::Message::binary(response.encode().unwrap()))
+                                .await;
+                        }
+                        Message::SessionClosed {
+                            status: SessionStatus::Completed,
+                            runs_completed: 2,
+                            stored_run_id: Some(run_id),
+                        } => break u128::from_str_radix(&run_id, 16).unwrap(),
+                        message @ Message::SessionClosed { .. } => {
+                            panic!("Unexpected {}", message)
+                        }
+                        _ => {}
+                    }
+                };
+                context
+                    .storage
+                    .get(run_id, Visibility::All)
+                    .await
+                    .unwrap()
+                    .unwrap()
+            }
+        };
+        let failed = |run: &ClientData| {
+            run.events.iter().find_map(|event| match event.kind {
+                SessionEventKind::RoundFailed {
+                    kind_id: kinds::challenge::NETWORK_CHALLENGE,
+                    round: 0,
+                    scored,
+                    ..
+                } => Some(scored),
+                _ => None,
+            })
+        };
+
+        // Failed round is left out, score is that of the other one
+        let run = session("lenient").await;
+        assert_eq!(run.configuration.strictness, Strictness::Lenient);
+        assert_eq!(failed(&run), Some(false));
+        assert_eq!(run.network_challenge_timings_in_milis.len(), 1);
+        assert_eq!(run.network_verification_timings_in_micros.len(), 2);
+
+        let run = session("audit").await;
+        assert_eq!(run.configuration.strictness, Strictness::AuditOnly);
+        assert_eq!(failed(&run), Some(true));
+        assert_eq!(run.network_challenge_timings_in_milis.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_round_retries() {
+        let context = server_context();
+        let config = Arc::new(ServerConfig {
+            plan: PlanConfig {
+                cpu_rounds: 0,
+                network_rounds: 2,
+                payload_size_kb: 16,
+                round_retries: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let decode = |message: warp::ws::Message| Message::decode(message.as_bytes()).unwrap();
+        // Runs a session answering the first `errors` challenges with an error instead of the
+        // payload, returns how it closed
+        let session = |errors: usize| {
+            let route = measurement_route(context.clone(), config.clone());
+            async move {
+                let mut client = warp::test::ws().handshake(route).await.unwrap();
+                let client_hello = Message::Handshake(Handshake::ClientHello {
+                    hash_algorithm: HashAlgorithm::Blake3,
+                    supports_chunks: false,
+                    network_type: None,
+                    client_version: None,
+                    binds_nonces: false,
+                    mac_public_key: None,
+                });
+                client
+                    .send(warp::ws::Message::binary(client_hello.encode().unwrap()))
+                    .await;
+                let mut challenges = 0;
+                loop {
+                    match decode(client.recv().await.unwrap()) {
+                        Message::Challenge(Challenge::NetworkChallenge(payload)) => {
+                            challenges += 1;
+                            let response = if challenges <= errors {
+                                Message::Data(Data::Error {
+                                    code: ErrorCode::Unspecified,
+                                    message: "Transient failure".to_owned(),
+                                })
+                            } else {
+                                Message::Response(Response::NetworkChallengeResponse(payload))
+                            };
+                            client
+                                .send(warp::ws::Message::binary(response.encode().unwrap()))
+                                .await;
+                        }
+                        message @ Message::SessionClosed { .. } => break message,
+                        _ => {}
+                    }
+                }
+            }
+        };
+
+        // First round is tried again, the session goes on
+        let run_id = match session(1).await {
+            Message::SessionClosed {
+                status: SessionStatus::Completed,
+                runs_completed: 2,
+                stored_run_id: Some(run_id),
+            } => u128::from_str_radix(&run_id, 16).unwrap(),
+            message => panic!("Unexpected {}", message),
+        };
+        let run = context
+            .storage
+            .get(run_id, Visibility::All)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(run.retried_rounds.len(), 1);
+        assert_eq!(
+            (run.retried_rounds[0].kind_id, run.retried_rounds[0].round),
+            (kinds::challenge::NETWORK_CHALLENGE, 0)
+        );
+        assert_eq!(run.network_challenge_timings_in_milis.len(), 2);
+        let network_score = run.score_breakdown.network_score.unwrap();
+        assert_eq!(run.score_breakdown.total, (network_score - 5.0).max(0.0));
+        assert!(run
+            .events
+            .iter()
+            .any(|event| matches!(event.kind, SessionEventKind::RoundRetried { round: 0, .. })));
+
+        // Second failure is over the budget
+        assert!(matches!(
+            session(2).await,
+            Message::SessionClosed {
+                status: SessionStatus::Failed,
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_timed_out_round_retries() {
+        let context = server_context();
+        let config = Arc::new(ServerConfig {
+            plan: PlanConfig {
+                cpu_rounds: 0,
+                network_rounds: 2,
+                payload_size_kb: 16,
+                network_round_timeout_milliseconds: Some(200),
+                round_retries: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let decode = |message: warp::ws::Message| Message::decode(message.as_bytes()).unwrap();
+        let mut client = warp::test::ws()
+            .handshake(measurement_route(context.clone(), config))
+            .await
+            .unwrap();
+        let client_hello = Message::Handshake(Handshake::ClientHello {
+            hash_algorithm: HashAlgorithm::Blake3,
+            supports_chunks: false,
+            network_type: None,
+            client_version: None,
+            binds_nonces: false,
+            mac_public_key: None,
+        });
+        client
+            .send(warp::ws::Message::binary(client_hello.encode().unwrap()))
+            .await;
+        let mut challenges = 0;
+        let closed = loop {
+            match decode(client.recv().await.unwrap()) {
+                Message::Challenge(Challenge::NetworkChallenge(payload)) => {
+                    challenges += 1;
+                    // First challenge is answered after the round timed out
+                    if challenges == 1 {
+                        tokio::time::delay_for(Duration::from_millis(300)).await;
+                    }
+                    let response = Message::Response(Response::NetworkChallengeResponse(payload));
+                    client
+                        .send(warp::ws::Message::binary(response.encode().unwrap()))
+                        .await;
+                }
+                message @ Message::SessionClosed { .. } => break message,
+                _ => {}
+            }
+        };
+
+        // Late answer isn't taken for the answer to the retried round
+        let run_id = match closed {
+            Message::SessionClosed {
+                status: SessionStatus::Completed,
+                runs_completed: 2,
+                stored_run_id: Some(run_id),
+            } => u128::from_str_radix(&run_id, 16).unwrap(),
+            message => panic!("Unexpected {}", message),
+        };
+        assert_eq!(challenges, 3);
+        let run = context
+            .storage
+            .get(run_id, Visibility::All)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(run.retried_rounds.len(), 1);
+        assert_eq!(run.retried_rounds[0].round, 0);
+        assert!(!run.events.iter().any(|event| matches!(
+            event.kind,
+            SessionEventKind::NetworkChallengeMismatched { .. }
+                | SessionEventKind::RoundFailed { .. }
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_partial_runs() {
+        let context = server_context();
+        let config = Arc::new(ServerConfig {
+            plan: PlanConfig {
+                cpu_rounds: 0,
+                network_rounds: 4,
+                payload_size_kb: 16,
+                persist_partial_runs: true,
+                partial_run_penalty: Some(10.0),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let decode = |message: warp::ws::Message| Message::decode(message.as_bytes()).unwrap();
+        // Starts a session which completes `rounds` network rounds, returns its client then
+        let session = |rounds: u32| {
+            let route = measurement_route(context.clone(), config.clone());
+            async move {
+                let mut client = warp::test::ws().handshake(route).await.unwrap();
+                let client_hello = Message::Handshake(Handshake::ClientHello {
+                    hash_algorithm: HashAlgorithm::Blake3,
+                    supports_chunks: false,
+                    network_type: None,
+                    client_version: None,
+                    binds_nonces: false,
+                    mac_public_key: None,
+                });
+                client
+                    .send(warp::ws::Message::binary(client_hello.encode().unwrap()))
+                    .await;
+                loop {
+                    match decode(client.recv().await.unwrap()) {
+                        Message::Challenge(Challenge::NetworkChallenge(payload)) => {
+                            let response =
+                                Message::Response(Response::NetworkChallengeResponse(payload));
+                            client
+                                .send(warp::ws::Message::binary(response.encode().unwrap()))
+                                .await;
+                        }
+                        Message::Progress(progress) if progress.rounds_completed == rounds => {
+                            break client
+                        }
+                        message @ Message::SessionClosed { .. } => {
+                            panic!("Unexpected {}", message)
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        };
+
+        // Both attempts of the third round are mangled, session fails there
+        let mut client = session(2).await;
+        let run_id = loop {
+            match decode(client.recv().await.unwrap()) {
+                Message::Challenge(Challenge::NetworkChallenge(mut payload)) => {
+                    *payload.last_mut().unwrap() ^= 1;
+                    let response = Message::Response(Response::NetworkChallengeResponse(payload));
+                    client
+                        .send(warp::ws::Message::binary(response.encode().unwrap()))
+                        .await;
+                }
+                Message::SessionClosed {
+                    status: SessionStatus::Failed,
+                    runs_completed: 2,
+                    stored_run_id: Some(run_id),
+                } => break u128::from_str_radix(&run_id, 16).unwrap(),
+                message @ Message::SessionClosed { .. } => panic!("Unexpected {}", message),
+                _ => {}
+            }
+        };
+        let run = context
+            .storage
+            .get(run_id, Visibility::All)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(run.status, RunStatus::FailedAtNetwork(2));
+        assert_eq!(run.network_challenge_timings_in_milis.len(), 2);
+        // Two rounds weren't completed
+        let network_score = run.score_breakdown.network_score.unwrap();
+        assert_eq!(run.score_breakdown.total, (network_score - 20.0).max(0.0));
+
+        // Client leaves after a round
+        drop(session(1).await);
+        let started = Instant::now();
+        let run = loop {
+            let runs = context.storage.runs(Visibility::All).await.unwrap();
+            if let Some(run) = runs.into_iter().find(|(id, _)| *id != run_id) {
+                break run.1;
+            }
+            assert!(started.elapsed() < Duration::from_secs(5));
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        };
+        assert_eq!(run.status, RunStatus::Disconnected);
+        assert_eq!(run.network_challenge_timings_in_milis.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_batched_cpu_session() {
+        let context = server_context();
+        let config = ServerConfig {
+            plan: PlanConfig {
+                cpu_rounds: 3,
+                squarings: 1000,
+                batch_cpu_verification: true,
+                network_rounds: 1,
+                payload_size_kb: 16,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let route = warp::path("ws").and(measurement_route(context.clone(), Arc::new(config)));
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let outcome = client::measure(client::Options {
+            url: format!("ws://{}/ws", address),
+            with_proof: false,
+            disk_directory: std::env::temp_dir(),
+            client_token: None,
+            network_type: None,
+            client_version: None,
+        })
+        .await
+        .unwrap();
+        assert_eq!(outcome.status, SessionStatus::Completed);
+        let run_id = u128::from_str_radix(&outcome.stored_run_id.unwrap(), 16).unwrap();
+        let run = context
+            .storage
+            .get(run_id, Visibility::All)
+            .await
+            .unwrap()
+            .unwrap();
+        // Single batch check is split between all CPU rounds
+        let timings = &run.cpu_verification_timings_in_micros;
+        assert_eq!(timings.len(), 3);
+        assert!(timings.iter().all(|timing| *timing == timings[0]));
+    }
+
+    #[tokio::test]
+    async fn test_failed_cpu_batch() {
+        let context = server_context();
+        let mut config = ServerConfig {
+            plan: PlanConfig {
+                cpu_rounds: 3,
+                squarings: 1000,
+                batch_cpu_verification: true,
+                network_rounds: 1,
+                payload_size_kb: 16,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        config
+            .strictness_modes
+            .insert("lenient".to_owned(), Strictness::Lenient);
+        config
+            .strictness_modes
+            .insert("audit".to_owned(), Strictness::AuditOnly);
+        let config = Arc::new(config);
+        let decode = |message: warp::ws::Message| Message::decode(message.as_bytes()).unwrap();
+        // Runs a session of the tenant of `api_key` answering the second CPU round wrong, returns
+        // the stored run
+        let session = |api_key: &'static str| {
+            let route = measurement_route(context.clone(), config.clone());
+            let context = context.clone();
+            async move {
+                let mut client = warp::test::ws()
+                    .header("x-api-key", api_key)
+                    .handshake(route)
+                    .await
+                    .unwrap();
+                let client_hello = Message::Handshake(Handshake::ClientHello {
+                    hash_algorithm: HashAlgorithm::Blake3,
+                    supports_chunks: false,
+                    network_type: None,
+                    client_version: None,
+                    binds_nonces: false,
+                    mac_public_key: None,
+                });
+                client
+                    .send(warp::ws::Message::binary(client_hello.encode().unwrap()))
+                    .await;
+                let mut cpu_challenges = 0;
+                let run_id = loop {
+                    let response = match decode(client.recv().await.unwrap()) {
+                        Message::Challenge(Challenge::CPUChallenge(payload)) => {
+                            cpu_challenges += 1;
+                            let mut answer = Timelock::from_wire(payload)
+                                .unwrap()
+                                .perform_challenge()
+                                .to_bytes_be();
+                            if cpu_challenges == 2 {
+                                *answer.last_mut().unwrap() ^= 1;
+                            }
+                            Response::CPUChallengeResponse(answer)
+                        }
+                        Message::Challenge(Challenge::NetworkChallenge(payload)) => {
+                            Response::NetworkChallengeResponse(payload)
+                        }
+                        Message::SessionClosed {
+                            status: SessionStatus::Completed,
+                            runs_completed: 4,
+                            stored_run_id: Some(run_id),
+                        } => break u128::from_str_radix(&run_id, 16).unwrap(),
+                        message @ Message::SessionClosed { .. } => {
+                            panic!("Unexpected {}", message)
+                        }
+                        _ => continue,
+                    };
+                    client
+                        .send(warp::ws::Message::binary(
+                            Message::Response(response).encode().unwrap(),
+                        ))
+                        .await;
+                };
+                context
+                    .storage
+                    .get(run_id, Visibility::All)
+                    .await
+                    .unwrap()
+                    .unwrap()
+            }
+        };
+        let failed = |run: &ClientData| {
+            run.events
+                .iter()
+                .filter_map(|event| match event.kind {
+                    SessionEventKind::RoundFailed {
+                        kind_id: kinds::challenge::CPU_CHALLENGE,
+                        round,
+                        scored,
+                        ..
+                    } => Some((round, scored)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // Only the wrong answer of the failed batch fails its round, the session goes on
+        let run = session("lenient").await;
+        assert_eq!(failed(&run), vec![(1, false)]);
+        assert_eq!(run.cpu_challenge_timings_in_milis.len(), 2);
+        let timings = &run.cpu_verification_timings_in_micros;
+        assert_eq!(timings.len(), 3);
+        assert!(timings.iter().all(|timing| *timing == timings[0]));
+
+        let run = session("audit").await;
+        assert_eq!(failed(&run), vec![(1, true)]);
+        assert_eq!(run.cpu_challenge_timings_in_milis.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_required_nonces() {
+        let context = server_context();
+        let config = Arc::new(ServerConfig {
+            plan: PlanConfig {
+                cpu_rounds: 2,
+                squarings: 1000,
+                network_rounds: 1,
+                payload_size_kb: 16,
+                download_rounds: 1,
+                upload_rounds: 1,
+                require_nonces: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let route = warp::path("ws").and(measurement_route(context.clone(), config.clone()));
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        // Client binds nonces into answers of every kind
+        let outcome = client::measure(client::Options {
+            url: format!("ws://{}/ws", address),
+            with_proof: false,
+            disk_directory: std::env::temp_dir(),
+            client_token: None,
+            network_type: None,
+            client_version: None,
+        })
+        .await
+        .unwrap();
+        assert_eq!(outcome.status, SessionStatus::Completed);
+
+        let decode = |message: warp::ws::Message| Message::decode(message.as_bytes()).unwrap();
+        let mut client = warp::test::ws()
+            .handshake(measurement_route(context, config))
+            .await
+            .unwrap();
+        assert!(matches!(
+            decode(client.recv().await.unwrap()),
+            Message::Handshake(Handshake::ServerHello { .. })
+        ));
+        let client_hello = Message::Handshake(Handshake::ClientHello {
+            hash_algorithm: HashAlgorithm::Blake3,
+            supports_chunks: false,
+            network_type: None,
+            client_version: None,
+            binds_nonces: false,
+            mac_public_key: None,
+        });
+        client
+            .send(warp::ws::Message::binary(client_hello.encode().unwrap()))
+            .await;
+        match decode(client.recv().await.unwrap()) {
+            Message::Data(Data::Error { code, .. }) => {
+                assert_eq!(code, ErrorCode::UnsupportedVersion)
+            }
+            message => panic!("Unexpected {}", message),
+        }
+        assert!(matches!(
+            decode(client.recv().await.unwrap()),
+            Message::SessionClosed {
+                status: SessionStatus::Failed,
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_implausible_rounds() {
+        let context = server_context();
+        let config = Arc::new(ServerConfig {
+            plan: PlanConfig {
+                cpu_rounds: 2,
+                squarings: 1000,
+                network_rounds: 1,
+                payload_size_kb: 16,
+                // Client answers far faster than the plan deems possible
+                fastest_squarings_per_second: 100,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let route = warp::path("ws").and(measurement_route(context.clone(), config));
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let outcome = client::measure(client::Options {
+            url: format!("ws://{}/ws", address),
+            with_proof: false,
+            disk_directory: std::env::temp_dir(),
+            client_token: None,
+            network_type: None,
+            client_version: None,
+        })
+        .await
+        .unwrap();
+        assert_eq!(outcome.status, SessionStatus::Completed);
+        assert_eq!(outcome.report.unwrap().score_breakdown.total, 0.0);
+        let run_id = u128::from_str_radix(&outcome.stored_run_id.unwrap(), 16).unwrap();
+        let run = context
+            .storage
+            .get(run_id, Visibility::All)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(run.score, 0);
+        // Network rounds are within bounds of the default plan
+        assert_eq!(
+            run.implausible_rounds
+                .iter()
+                .map(|implausible| (implausible.kind_id, implausible.round))
+                .collect::<Vec<_>>(),
+            vec![
+                (kinds::challenge::CPU_CHALLENGE, 0),
+                (kinds::challenge::CPU_CHALLENGE, 1)
+            ]
+        );
+        assert!(run
+            .implausible_rounds
+            .iter()
+            .all(|implausible| implausible.min_milliseconds == 10_000));
+        assert!(run.events.iter().any(|event| matches!(
+            event.kind,
+            SessionEventKind::RoundImplausible { round: 1, .. }
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_surprise_rounds() {
+        let context = server_context();
+        let config = Arc::new(ServerConfig {
+            plan: PlanConfig {
+                cpu_rounds: 2,
+                squarings: 1000,
+                network_rounds: 2,
+                payload_size_kb: 16,
+                surprise_rounds: 6,
+                surprise_squarings: 1000,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let route = warp::path("ws").and(measurement_route(context.clone(), config));
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let outcome = client::measure(client::Options {
+            url: format!("ws://{}/ws", address),
+            with_proof: false,
+            disk_directory: std::env::temp_dir(),
+            client_token: None,
+            network_type: None,
+            client_version: None,
+        })
+        .await
+        .unwrap();
+        assert_eq!(outcome.status, SessionStatus::Completed);
+        // Surprise rounds aren't announced in progress
+        let last = outcome.progress.last().unwrap();
+        assert_eq!((last.rounds_completed, last.total_rounds), (4, 4));
+
+        let run_id = u128::from_str_radix(&outcome.stored_run_id.unwrap(), 16).unwrap();
+        let run = context
+            .storage
+            .get(run_id, Visibility::All)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(run.surprise_rounds.len(), 6);
+        assert!(run.surprise_rounds.iter().all(|round| {
+            (1..4).contains(&round.after_rounds)
+                && [
+                    kinds::challenge::CPU_CHALLENGE,
+                    kinds::challenge::LATENCY_PROBE,
+                ]
+                .contains(&round.kind_id)
+        }));
+        let surprise_cpu_rounds = run
+            .surprise_rounds
+            .iter()
+            .filter(|round| round.kind_id == kinds::challenge::CPU_CHALLENGE)
+            .count();
+        assert_eq!(run.surprise_cpu_slowdown.is_some(), surprise_cpu_rounds > 0);
+        assert_eq!(
+            run.events
+                .iter()
+                .filter(|event| matches!(
+                    event.kind,
+                    SessionEventKind::SurpriseRoundCompleted { .. }
+                ))
+                .count(),
+            6
+        );
+        assert_eq!(run.cpu_challenge_timings_in_milis.len(), 2);
+        assert_eq!(run.network_challenge_timings_in_milis.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_message_authentication() {
+        let context = server_context();
+        let config = Arc::new(ServerConfig {
+            plan: PlanConfig {
+                cpu_rounds: 1,
+                squarings: 1000,
+                network_rounds: 1,
+                payload_size_kb: 16,
+                max_frame_size_kb: Some(8),
+                message_authentication: MessageAuthentication::Required,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let route = warp::path("ws").and(measurement_route(context.clone(), config.clone()));
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        // Chunks are sealed one by one
+        let outcome = client::measure(client::Options {
+            url: format!("ws://{}/ws", address),
+            with_proof: false,
+            disk_directory: std::env::temp_dir(),
+            client_token: None,
+            network_type: None,
+            client_version: None,
+        })
+        .await
+        .unwrap();
+        assert_eq!(outcome.status, SessionStatus::Completed);
+
+        let mut client = warp::test::ws()
+            .handshake(measurement_route(context, config))
+            .await
+            .unwrap();
+        let server_public_key = match Message::decode(client.recv().await.unwrap().as_bytes()) {
+            Ok(Message::Handshake(Handshake::ServerHello {
+                mac_public_key: Some(mac_public_key),
+                ..
+            })) => mac_public_key,
+            message => panic!(
+                "Unexpected {:?}",
+                message.map(|message| message.to_string())
+            ),
+        };
+        let key_exchange = KeyExchange::generate(&mut OsRng);
+        let client_hello = Message::Handshake(Handshake::ClientHello {
+            hash_algorithm: HashAlgorithm::Blake3,
+            supports_chunks: false,
+            network_type: None,
+            client_version: None,
+            binds_nonces: false,
+            mac_public_key: Some(key_exchange.public_key()),
+        });
+        let mut authenticator = key_exchange
+            .agree(Role::Client, &server_public_key)
+            .unwrap();
+        client
+            .send(warp::ws::Message::binary(client_hello.encode().unwrap()))
+            .await;
+        async fn receive(
+            client: &mut warp::test::WsClient,
+            authenticator: &mut MessageAuthenticator,
+        ) -> Message {
+            let frame = client.recv().await.unwrap().into_bytes();
+            Message::decode(&authenticator.open(frame).unwrap()).unwrap()
+        }
+        assert!(matches!(
+            receive(&mut client, &mut authenticator).await,
+            Message::Challenge(Challenge::CPUChallenge(_))
+        ));
+
+        // Answer tampered with on its way
+        let response = Message::Response(Response::CPUChallengeResponse(vec![1, 2, 3]));
+        let mut frame = authenticator.seal(response.encode().unwrap());
+        frame[0] ^= 1;
+        client.send(warp::ws::Message::binary(frame)).await;
+        match receive(&mut client, &mut authenticator).await {
+            Message::Data(Data::Error { code, .. }) => {
+                assert_eq!(code, ErrorCode::ProtocolViolation)
+            }
+            message => panic!("Unexpected {}", message),
+        }
+        assert!(matches!(
+            receive(&mut client, &mut authenticator).await,
+            Message::SessionClosed {
+                status: SessionStatus::Failed,
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_client_identity() {
+        let context = server_context();
+        let config = ServerConfig {
+            plan: PlanConfig {
+                cpu_rounds: 1,
+                squarings: 1000,
+                network_rounds: 1,
+                payload_size_kb: 16,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let route = warp::path("ws").and(measurement_route(context.clone(), Arc::new(config)));
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let options = client::Options {
+            url: format!("ws://{}/ws", address),
+            with_proof: false,
+            disk_directory: std::env::temp_dir(),
+            client_token: None,
+            network_type: None,
+            client_version: None,
+        };
+
+        let first = client::measure(options.clone()).await.unwrap();
+        let token = first.client_token.clone().unwrap();
+        // Reconnecting with the token keeps the identity, a new session id is still assigned
+        let second = client::measure(client::Options {
+            client_token: Some(token.clone()),
+            network_type: None,
+            client_version: None,
+            ..options.clone()
+        })
+        .await
+        .unwrap();
+        assert_eq!(second.client_token.as_ref(), Some(&token));
+        assert_ne!(first.stored_run_id, second.stored_run_id);
+        let third = client::measure(options).await.unwrap();
+        assert_ne!(third.client_token.as_ref(), Some(&token));
+
+        let run_id = |outcome: &client::Outcome| {
+            u128::from_str_radix(outcome.stored_run_id.as_ref().unwrap(), 16).unwrap()
+        };
+        let identity = context
+            .storage
+            .get(run_id(&first), Visibility::All)
+            .await
+            .unwrap()
+            .unwrap()
+            .identity
+            .unwrap();
+        let history: Vec<u128> = context
+            .storage
+            .history(identity, Visibility::All)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(run_id, _)| run_id)
+            .collect();
+        assert_eq!(history, vec![run_id(&first), run_id(&second)]);
+    }
+
+    #[tokio::test]
+    async fn test_measurement_auth() {
+        let mut context = server_context();
+        let mut auth_config = MeasurementAuthConfig::default();
+        auth_config
+            .api_keys
+            .insert("k3y".to_owned(), "fleet-eu".to_owned());
+        context.measurement_auth = Some(Arc::new(MeasurementAuth::new(auth_config, None).unwrap()));
+        let config = ServerConfig {
+            plan: PlanConfig {
+                cpu_rounds: 1,
+                squarings: 1000,
+                network_rounds: 1,
+                payload_size_kb: 16,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let route = warp::path("ws").and(measurement_route(context.clone(), Arc::new(config)));
+
+        let response = upgrade_request("/ws").reply(&route).await;
+        assert_eq!(response.status(), 401);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["reason"], "unauthorized");
+        let response = upgrade_request("/ws")
+            .header("x-api-key", "other")
+            .reply(&route)
+            .await;
+        assert_eq!(response.status(), 401);
+
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let options = client::Options {
+            url: format!("ws://{}/ws", address),
+            with_proof: false,
+            disk_directory: std::env::temp_dir(),
+            client_token: None,
+            network_type: None,
+            client_version: None,
+        };
+        let error = client::measure(options.clone()).await.unwrap_err();
+        let rejected = error.downcast_ref::<client::Rejected>().unwrap();
+        assert_eq!(rejected.reason, RejectionReason::Unauthorized);
+        assert_eq!(rejected.retry_after, None);
+
+        // Clients which can't set headers pass the key in the query
+        let outcome = client::measure(client::Options {
+            url: format!("ws://{}/ws?api_key=k3y", address),
+            ..options
+        })
+        .await
+        .unwrap();
+        let run_id = u128::from_str_radix(&outcome.stored_run_id.unwrap(), 16).unwrap();
+        let run = context
+            .storage
+            .get(run_id, Visibility::All)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(run.principal.as_deref(), Some("fleet-eu"));
+    }
+
+    #[tokio::test]
+    async fn test_tenants() {
+        let context = server_context();
+        let config = ServerConfig {
+            plan: PlanConfig {
+                cpu_rounds: 1,
+                squarings: 1000,
+                network_rounds: 1,
+                payload_size_kb: 16,
+                ..Default::default()
+            },
+            tenants: vec![(
+                "payments".to_owned(),
+                TenantConfig {
+                    api_keys: vec!["k3y".to_owned()].into_iter().collect(),
+                    plan: Some(PlanConfig {
+                        name: "payments".to_owned(),
+                        cpu_rounds: 0,
+                        network_rounds: 1,
+                        payload_size_kb: 16,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            )]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        };
+        let route = measurement_routes(context.clone(), Arc::new(config));
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let measure = |query: &str| {
+            let options = client::Options {
+                url: format!("ws://{}/ws{}", address, query),
+                with_proof: false,
+                disk_directory: std::env::temp_dir(),
+                client_token: None,
+                network_type: None,
+                client_version: None,
+            };
+            let context = context.clone();
+            async move {
+                let outcome = client::measure(options).await.unwrap();
+                let run_id = u128::from_str_radix(&outcome.stored_run_id.unwrap(), 16).unwrap();
+                context
+                    .storage
+                    .get(run_id, Visibility::All)
+                    .await
+                    .unwrap()
+                    .unwrap()
+            }
+        };
+        let run = measure("?api_key=k3y").await;
+        assert_eq!(run.tenant.as_deref(), Some("payments"));
+        assert_eq!(run.configuration.plan_name, "payments");
+        assert!(run.cpu_challenge_timings_in_milis.is_empty());
+        let run = measure("").await;
+        assert_eq!(run.tenant, None);
+        assert_eq!(run.configuration.plan_name, "default");
+    }
+
+    #[tokio::test]
+    async fn test_chunked_transfers() {
+        let context = server_context();
+        let config = Arc::new(ServerConfig {
+            plan: PlanConfig {
+                cpu_rounds: 0,
+                network_rounds: 2,
+                payload_size_kb: 16,
+                download_rounds: 1,
+                upload_rounds: 1,
+                max_frame_size_kb: Some(8),
+                network_profile_chunk_kb: Some(4),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let route = warp::path("ws").and(measurement_route(context.clone(), config.clone()));
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let outcome = client::measure(client::Options {
+            url: format!("ws://{}/ws", address),
+            with_proof: false,
+            disk_directory: std::env::temp_dir(),
+            client_token: None,
+            network_type: None,
+            client_version: None,
+        })
+        .await
+        .unwrap();
+        assert_eq!(outcome.status, SessionStatus::Completed);
+        let run_id = u128::from_str_radix(&outcome.stored_run_id.unwrap(), 16).unwrap();
+        let run = context
+            .storage
+            .get(run_id, Visibility::All)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(run.configuration.max_frame_size_kb, Some(4));
+        // 16KB payload returned in frames of 4KB
+        assert_eq!(run.network_chunk_arrivals_in_micros.len(), 2);
+        for arrivals in &run.network_chunk_arrivals_in_micros {
+            assert_eq!(arrivals.len(), 5);
+            assert!(arrivals.windows(2).all(|pair| pair[0] <= pair[1]));
+        }
+        for bytes in &run.network_chunk_bytes {
+            assert_eq!(bytes.len(), 5);
+            assert!(bytes.iter().sum::<usize>() > 16 * 1024);
+        }
+        for profile in run.network_profiles() {
+            assert_eq!(profile.unwrap().chunks, 5);
+        }
+
+        // Clients which can't reassemble chunks get challenges in a single frame
+        let mut client = warp::test::ws()
+            .handshake(measurement_route(context, config))
+            .await
+            .unwrap();
+        let decode = |message: warp::ws::Message| Message::decode(message.as_bytes()).unwrap();
+        match decode(client.recv().await.unwrap()) {
+            Message::Handshake(Handshake::ServerHello { limits, .. }) => {
+                assert_eq!(limits.max_frame_size_kb, Some(4))
+            }
+            message => panic!("Unexpected {}", message),
+        }
+        let client_hello = Message::Handshake(Handshake::ClientHello {
+            hash_algorithm: HashAlgorithm::Blake3,
+            supports_chunks: false,
+            network_type: None,
+            client_version: None,
+            binds_nonces: false,
+            mac_public_key: None,
+        });
+        client
+            .send(warp::ws::Message::binary(client_hello.encode().unwrap()))
+            .await;
+        assert!(matches!(
+            decode(client.recv().await.unwrap()),
+            Message::Challenge(Challenge::NetworkChallenge(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_network_type_thresholds() {
+        let config = Arc::new(
+            ServerConfig::from_toml(
+                r#"
+                [plan]
+                cpu_rounds = 1
+                squarings = 1000
+                network_rounds = 1
+                payload_size_kb = 16
+
+                [plan.network_types.cellular]
+                network_ideal_milliseconds = 1000
+                "#,
+            )
+            .unwrap(),
+        );
+        let context = server_context();
+        let (address, server) = warp::serve(measurement_routes(context.clone(), config))
+            .bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        for network_type in [Some(NetworkType::Cellular), Some(NetworkType::Wifi), None] {
+            let outcome = client::measure(client::Options {
+                url: format!("ws://{}/ws", address),
+                with_proof: false,
+                disk_directory: std::env::temp_dir(),
+                client_token: None,
+                network_type,
+                client_version: None,
+            })
+            .await
+            .unwrap();
+            assert_eq!(outcome.status, SessionStatus::Completed);
+            let run_id = u128::from_str_radix(&outcome.stored_run_id.unwrap(), 16).unwrap();
+            let run = context
+                .storage
+                .get(run_id, Visibility::All)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(run.network_type, network_type);
+            // Only cellular has thresholds of its own
+            let expected_ideal_milliseconds = match network_type {
+                Some(NetworkType::Cellular) => 1000,
+                _ => 200,
+            };
+            assert_eq!(
+                run.configuration.network_ideal_milliseconds,
+                expected_ideal_milliseconds
+            );
+        }
+    }
+}
@@ -1,90 +1,647 @@
+use crate::config::PlanConfig;
 use crate::measurements::challenges::{CPUChallengeConfiguration, NetworkChallengeConfiguration};
+use crate::measurements::plugins::PluginScoring;
+use serde::{Deserialize, Serialize};
+use shared::{kinds, ScoreBreakdown, ScoreRejection};
+use std::cmp;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt::{self, Display};
 
-fn find_mean(data: &Vec<u128>) -> u128 {
-    let mut sum: u128 = 0;
+const MAX_SCORE: f64 = 100.0;
 
-    for element in data {
-        sum += *element;
+/// Reasons a score can't be calculated
+#[derive(Debug, PartialEq)]
+pub(crate) enum ScoreError {
+    /// `ideal_milliseconds` of the challenge is greater than its `max_milliseconds`
+    InvalidRange {
+        challenge: &'static str,
+        ideal_milliseconds: u128,
+        max_milliseconds: u128,
+    },
+    /// None of the challenges had any rounds
+    NoResults,
+    /// Results of the challenge are too large to be aggregated
+    Overflow { challenge: &'static str },
+    /// Parameter of the aggregation of the challenge is out of its range
+    InvalidAggregation {
+        challenge: &'static str,
+        aggregation: Aggregation,
+    },
+    /// Weight of the challenge in `ScoringModel` is negative or not finite
+    InvalidWeight { challenge: &'static str },
+    /// All challenges with results have zero weight
+    ZeroWeight,
+}
+
+impl Display for ScoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScoreError::InvalidRange {
+                challenge,
+                ideal_milliseconds,
+                max_milliseconds,
+            } => write!(
+                f,
+                "Ideal time {}ms of {} challenge is greater than max time {}ms",
+                ideal_milliseconds, challenge, max_milliseconds
+            ),
+            ScoreError::NoResults => write!(f, "No challenge results to score"),
+            ScoreError::Overflow { challenge } => {
+                write!(f, "Results of {} challenge overflow", challenge)
+            }
+            ScoreError::InvalidAggregation {
+                challenge,
+                aggregation,
+            } => write!(
+                f,
+                "Aggregation {:?} of {} challenge is out of range",
+                aggregation, challenge
+            ),
+            ScoreError::InvalidWeight { challenge } => write!(
+                f,
+                "Weight of {} challenge must be finite and not negative",
+                challenge
+            ),
+            ScoreError::ZeroWeight => write!(f, "All challenges with results have zero weight"),
+        }
     }
+}
+
+impl Error for ScoreError {}
 
-    sum / (data.len() as u128)
+/// How round timings of a challenge kind are aggregated before they are scored. Policies other
+/// than `Mean` keep a single outlier, e.g. a GC pause or a network hiccup, from skewing the score.
+/// A round over `max_milliseconds` still rejects the client whatever the aggregation.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum Aggregation {
+    #[default]
+    Mean,
+    /// Middle round, mean of the two middle rounds for an even number of rounds
+    Median,
+    /// Mean of the rounds left after dropping `percent` (below 50) of the fastest and as many of the slowest rounds
+    TrimmedMean { percent: u8 },
+    /// Nearest-rank percentile, `percentile` within `1..=100`
+    Percentile { percentile: u8 },
 }
 
-/// calculate_score calculates score by finding mean of both cpu challenge data
-/// and network challenge data. Both then mapped to domain of 0-50 and sum of both mapping
-/// is substrated from 100 to obtain final score.
-pub(crate) fn calculate_score(
-    cpu_challenge_config: &CPUChallengeConfiguration,
-    cpu_results: &Vec<u128>,
-    network_challenge_config: &NetworkChallengeConfiguration,
-    network_results: &Vec<u128>,
-) -> u128 {
-    let cpu_results_median = find_mean(cpu_results);
-    let network_results_median = find_mean(network_results);
+impl Aggregation {
+    fn is_valid(&self) -> bool {
+        match self {
+            Aggregation::Mean | Aggregation::Median => true,
+            Aggregation::TrimmedMean { percent } => *percent < 50,
+            Aggregation::Percentile { percentile } => (1..=100).contains(percentile),
+        }
+    }
+
+    /// `None` if `results` is empty or their sum overflows
+    pub(crate) fn aggregate(&self, results: &[u128]) -> Option<u128> {
+        if results.is_empty() {
+            return None;
+        }
+        let mut sorted = results.to_vec();
+        sorted.sort_unstable();
+        match self {
+            Aggregation::Mean => find_mean(results),
+            Aggregation::Median => {
+                let middle = sorted.len() / 2;
+                if sorted.len().is_multiple_of(2) {
+                    find_mean(&sorted[middle - 1..=middle])
+                } else {
+                    Some(sorted[middle])
+                }
+            }
+            Aggregation::TrimmedMean { percent } => {
+                let trimmed = sorted.len() * usize::from(*percent) / 100;
+                find_mean(&sorted[trimmed..sorted.len() - trimmed])
+            }
+            Aggregation::Percentile { percentile } => {
+                let rank = (sorted.len() * usize::from(*percentile)).div_ceil(100);
+                sorted.get(rank.max(1) - 1).copied()
+            }
+        }
+    }
+}
+
+/// How sessions treat rounds which fail, either because the response doesn't answer the
+/// challenge or because the round took more than `max_milliseconds` of its kind
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Strictness {
+    /// A failed response aborts the session and a round over `max_milliseconds` scores it 0
+    #[default]
+    Strict,
+    /// Failed rounds are recorded and left out, the score is calculated from the remaining rounds
+    Lenient,
+    /// Failed rounds are recorded and scored like any other, nothing rejects the client
+    AuditOnly,
+}
+
+/// Quantity the aggregate of a challenge kind is scored on between its thresholds
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum Scale {
+    /// Penalty grows linearly with time of the aggregate
+    #[default]
+    Time,
+    /// Penalty grows linearly with throughput, of data or of squarings, falling short of the one
+    /// at `ideal_milliseconds`, i.e. with the reciprocal of time, so the score doesn't depend on
+    /// the payload size or difficulty of the round
+    Throughput,
+}
+
+/// Megabits a network round of `payload_size_kb` moves, payload travels to the client and back
+fn round_megabits(payload_size_kb: usize) -> f64 {
+    2.0 * payload_size_kb as f64 * 1024.0 * 8.0 / 1_000_000.0
+}
+
+/// Time a network round of `payload_size_kb` takes at `mbps`
+pub(crate) fn network_round_milliseconds(payload_size_kb: usize, mbps: f64) -> u128 {
+    (round_megabits(payload_size_kb) * 1000.0 / mbps).round() as u128
+}
+
+/// Throughput of a network round of `payload_size_kb` which took `milliseconds`
+pub(crate) fn network_round_mbps(payload_size_kb: usize, milliseconds: u128) -> f64 {
+    round_megabits(payload_size_kb) * 1000.0 / cmp::max(milliseconds, 1) as f64
+}
 
-    // if any test took more than `max_milliseconds` we reject the client
-    for cpu_result in cpu_results {
-        if *cpu_result > cpu_challenge_config.max_milliseconds {
-            return 0;
+/// Time a CPU round of `squarings` takes at `squarings_per_second`
+pub(crate) fn cpu_round_milliseconds(squarings: u32, squarings_per_second: f64) -> u128 {
+    (f64::from(squarings) * 1000.0 / squarings_per_second).round() as u128
+}
+
+/// Rate of a CPU round of `squarings` which took `milliseconds`
+pub(crate) fn cpu_round_squarings_per_second(squarings: u32, milliseconds: u128) -> f64 {
+    f64::from(squarings) * 1000.0 / cmp::max(milliseconds, 1) as f64
+}
+
+/// Weights sub-scores of challenge kinds are combined with into the score. Only ratios of
+/// weights matter, kinds without rounds are left out and the remaining ones keep their ratios.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ScoringModel {
+    pub(crate) cpu_weight: f64,
+    pub(crate) network_weight: f64,
+    pub(crate) disk_weight: f64,
+}
+
+impl Default for ScoringModel {
+    /// Every measured kind counts the same
+    fn default() -> Self {
+        ScoringModel {
+            cpu_weight: 1.0,
+            network_weight: 1.0,
+            disk_weight: 1.0,
         }
     }
-    for network_result in network_results {
-        if *network_result > network_challenge_config.max_milliseconds {
-            return 0;
+}
+
+impl ScoringModel {
+    fn weight(&self, component: Component) -> f64 {
+        match component {
+            Component::Cpu => self.cpu_weight,
+            Component::Network => self.network_weight,
+            Component::Disk => self.disk_weight,
         }
     }
 
-    // Transform mean to 0-50 range
-    let cpu_score = if cpu_results_median < cpu_challenge_config.ideal_milliseconds {
-        0
-    } else {
-        ((cpu_results_median - cpu_challenge_config.ideal_milliseconds) * (50 - 0))
-            / (cpu_challenge_config.max_milliseconds - cpu_challenge_config.ideal_milliseconds)
-    };
+    fn validate(&self) -> Result<(), ScoreError> {
+        for (challenge, weight) in &[
+            ("CPU", self.cpu_weight),
+            ("network", self.network_weight),
+            ("disk", self.disk_weight),
+        ] {
+            if !weight.is_finite() || *weight < 0.0 {
+                return Err(ScoreError::InvalidWeight { challenge });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Challenge kind results belong to, picks their weight and place in `ScoreBreakdown`
+#[derive(Clone, Copy)]
+enum Component {
+    Cpu,
+    Network,
+    Disk,
+}
+
+/// Scoring parameters of a challenge kind along with results of its rounds
+pub(crate) struct ChallengeResults<'a> {
+    component: Component,
+    challenge: &'static str,
+    /// Kind of the challenge, see `kinds::challenge`
+    kind_id: u16,
+    pub(crate) ideal_milliseconds: u128,
+    pub(crate) max_milliseconds: u128,
+    aggregation: Aggregation,
+    scale: Scale,
+    strictness: Strictness,
+    results: &'a [u128],
+}
+
+impl<'a> ChallengeResults<'a> {
+    pub(crate) fn cpu(config: &CPUChallengeConfiguration, results: &'a [u128]) -> Self {
+        ChallengeResults {
+            component: Component::Cpu,
+            challenge: "CPU",
+            kind_id: kinds::challenge::CPU_CHALLENGE,
+            ideal_milliseconds: config.ideal_milliseconds,
+            max_milliseconds: config.max_milliseconds,
+            aggregation: config.aggregation,
+            scale: config.scale,
+            strictness: Strictness::Strict,
+            results,
+        }
+    }
+
+    pub(crate) fn network(config: &NetworkChallengeConfiguration, results: &'a [u128]) -> Self {
+        ChallengeResults {
+            component: Component::Network,
+            challenge: "network",
+            kind_id: config.kind_id(),
+            ideal_milliseconds: config.ideal_milliseconds,
+            max_milliseconds: config.max_milliseconds,
+            aggregation: config.aggregation,
+            scale: config.scale,
+            strictness: Strictness::Strict,
+            results,
+        }
+    }
+
+    /// Results of a scored plugin, see `ChallengePlugin::results`
+    pub(crate) fn plugin(
+        challenge: &'static str,
+        kind_id: u16,
+        scoring: &PluginScoring,
+        results: &'a [u128],
+    ) -> Self {
+        ChallengeResults {
+            component: Component::Disk,
+            challenge,
+            kind_id,
+            ideal_milliseconds: scoring.ideal_milliseconds,
+            max_milliseconds: scoring.max_milliseconds,
+            aggregation: scoring.aggregation,
+            scale: Scale::Time,
+            strictness: Strictness::Strict,
+            results,
+        }
+    }
 
-    let network_score = if network_results_median < network_challenge_config.ideal_milliseconds {
-        0
+    /// Kind of the challenge, see `kinds::challenge`
+    pub(crate) fn kind_id(&self) -> u16 {
+        self.kind_id
+    }
+
+    /// Scores rounds over `max_milliseconds` as per `strictness` instead of rejecting the client
+    pub(crate) fn with_strictness(mut self, strictness: Strictness) -> Self {
+        self.strictness = strictness;
+        self
+    }
+
+    fn validate(&self) -> Result<(), ScoreError> {
+        if self.ideal_milliseconds > self.max_milliseconds {
+            return Err(ScoreError::InvalidRange {
+                challenge: self.challenge,
+                ideal_milliseconds: self.ideal_milliseconds,
+                max_milliseconds: self.max_milliseconds,
+            });
+        }
+        if !self.aggregation.is_valid() {
+            return Err(ScoreError::InvalidAggregation {
+                challenge: self.challenge,
+                aggregation: self.aggregation,
+            });
+        }
+        Ok(())
+    }
+
+    /// Round timings this challenge is scored on
+    pub(crate) fn rounds(&self) -> &'a [u128] {
+        self.results
+    }
+
+    /// Score of this challenge alone rounded to a whole number, 0 if any round took more than
+    /// `max_milliseconds` in a strict session. `None` if the challenge had zero rounds.
+    pub(crate) fn sub_score(&self) -> Result<Option<u128>, ScoreError> {
+        self.validate()?;
+        Ok(self.stats()?.map(|stats| {
+            round_score(
+                self.penalty(&stats)
+                    .map_or(0.0, |penalty| MAX_SCORE - penalty),
+            )
+        }))
+    }
+
+    /// `None` if the challenge had zero rounds. Lenient sessions leave out rounds over
+    /// `max_milliseconds`, unless no other round is left.
+    fn stats(&self) -> Result<Option<Stats>, ScoreError> {
+        let within_max: Vec<u128>;
+        let results = match self.strictness {
+            Strictness::Lenient => {
+                within_max = self
+                    .results
+                    .iter()
+                    .copied()
+                    .filter(|result| *result <= self.max_milliseconds)
+                    .collect();
+                if within_max.is_empty() {
+                    self.results
+                } else {
+                    &within_max
+                }
+            }
+            Strictness::Strict | Strictness::AuditOnly => self.results,
+        };
+        let slowest = match results.iter().max() {
+            Some(slowest) => *slowest,
+            None => return Ok(None),
+        };
+        let aggregate = self
+            .aggregation
+            .aggregate(results)
+            .ok_or(ScoreError::Overflow {
+                challenge: self.challenge,
+            })?;
+        Ok(Some(Stats { aggregate, slowest }))
+    }
+
+    /// Maps aggregate of the results linearly from `ideal_milliseconds..=max_milliseconds`
+    /// to `0.0..=MAX_SCORE`, or from throughputs at these times with `Scale::Throughput`.
+    /// Aggregates at or below `ideal_milliseconds` get no penalty.
+    /// `None` if any round took more than `max_milliseconds`, unless session is audit-only which
+    /// caps the penalty instead. When `ideal_milliseconds == max_milliseconds` the mapping
+    /// degenerates to accepting every result without penalty up to `max_milliseconds`.
+    fn penalty(&self, stats: &Stats) -> Option<f64> {
+        // if any test took more than `max_milliseconds` we reject the client
+        if stats.slowest > self.max_milliseconds && self.strictness != Strictness::AuditOnly {
+            return None;
+        }
+        if stats.aggregate <= self.ideal_milliseconds {
+            return Some(0.0);
+        }
+
+        // Aggregate only exceeds `max_milliseconds` in audit-only sessions, where the range may
+        // be empty, dividing by zero yields the whole penalty then
+        let fraction = match self.scale {
+            Scale::Time => {
+                (stats.aggregate - self.ideal_milliseconds) as f64
+                    / (self.max_milliseconds - self.ideal_milliseconds) as f64
+            }
+            Scale::Throughput => {
+                let throughput = |milliseconds: u128| 1.0 / cmp::max(milliseconds, 1) as f64;
+                (throughput(self.ideal_milliseconds) - throughput(stats.aggregate))
+                    / (throughput(self.ideal_milliseconds) - throughput(self.max_milliseconds))
+            }
+        };
+        Some((fraction * MAX_SCORE).min(MAX_SCORE))
+    }
+
+    /// Rounds which took more than `max_milliseconds`
+    fn rejections(&self) -> impl Iterator<Item = ScoreRejection> + '_ {
+        let saturate = |value: u128| u64::try_from(value).unwrap_or(u64::MAX);
+        self.results
+            .iter()
+            .enumerate()
+            .filter(move |(_, result)| **result > self.max_milliseconds)
+            .map(move |(round, result)| ScoreRejection {
+                kind_id: self.kind_id,
+                round: u32::try_from(round).unwrap_or(u32::MAX),
+                round_milliseconds: saturate(*result),
+                max_milliseconds: saturate(self.max_milliseconds),
+                exceeded_by_milliseconds: saturate(*result - self.max_milliseconds),
+            })
+    }
+}
+
+/// Aggregates of results of a challenge kind with at least one round
+struct Stats {
+    /// Results aggregated as per `Aggregation` of the challenge
+    aggregate: u128,
+    slowest: u128,
+}
+
+/// Mean of `data`, `None` if it is empty or its sum overflows
+pub(crate) fn find_mean(data: &[u128]) -> Option<u128> {
+    if data.is_empty() {
+        return None;
+    }
+
+    let mut sum: u128 = 0;
+
+    for element in data {
+        sum = sum.checked_add(*element)?;
+    }
+
+    Some(sum / (data.len() as u128))
+}
+
+/// calculate_score calculates score by aggregating results of each challenge kind, by their mean
+/// unless the challenge is configured with another `Aggregation`.
+/// Each aggregate is then mapped to a penalty within 0-100, which is substracted from 100 to obtain
+/// sub-score of the kind. Total is mean of the sub-scores weighted by `model`, always within 0-100.
+/// Challenge kinds with zero rounds are left out, so remaining kinds get proportionally more weight.
+/// Client is scored 0 if any round took more than `max_milliseconds` of its challenge, such rounds
+/// are listed in `rejections` of the breakdown. Lenient sessions only score such a kind 0 when
+/// all of its rounds took too long and audit-only ones never reject, see `Strictness`.
+pub(crate) fn calculate_score(
+    model: &ScoringModel,
+    challenges: &[ChallengeResults],
+) -> Result<ScoreBreakdown, ScoreError> {
+    model.validate()?;
+    let mut breakdown = ScoreBreakdown::default();
+    let mut measured = false;
+    let mut rejected = false;
+    let mut weighted_sum = 0.0;
+    let mut total_weight = 0.0;
+    for challenge in challenges {
+        challenge.validate()?;
+        let stats = match challenge.stats()? {
+            Some(stats) => stats,
+            None => continue,
+        };
+        let penalty = challenge.penalty(&stats);
+        rejected |= penalty.is_none() && challenge.strictness == Strictness::Strict;
+        breakdown.rejections.extend(challenge.rejections());
+        // We need to subtract penalty from 100 because penalties are mapped in descending order.
+        let sub_score = penalty.map_or(0.0, |penalty| MAX_SCORE - penalty);
+        let weight = model.weight(challenge.component);
+        weighted_sum += weight * sub_score;
+        total_weight += weight;
+        measured = true;
+        *match challenge.component {
+            Component::Cpu => &mut breakdown.cpu_score,
+            Component::Network => &mut breakdown.network_score,
+            Component::Disk => &mut breakdown.disk_score,
+        } = Some(sub_score);
+    }
+
+    if !measured {
+        return Err(ScoreError::NoResults);
+    }
+    if total_weight == 0.0 {
+        return Err(ScoreError::ZeroWeight);
+    }
+    breakdown.total = if rejected {
+        0.0
     } else {
-        ((network_results_median - network_challenge_config.ideal_milliseconds) * (50 - 0))
-            / (network_challenge_config.max_milliseconds
-                - network_challenge_config.ideal_milliseconds)
+        (weighted_sum / total_weight).clamp(0.0, MAX_SCORE)
     };
+    Ok(breakdown)
+}
+
+/// Whole number score is stored and compared against thresholds such as `acceptable_score`
+pub(crate) fn round_score(score: f64) -> u128 {
+    score.round() as u128
+}
 
-    // We need to subtract our score from 100 because score we calculated is using domain mapping and
-    // in descending order.
-    100 - (cpu_score + network_score)
+/// Squarings which take a client about `target_milliseconds`, scaled from the time it took
+/// to solve a calibration puzzle of `calibration_squarings`
+pub(crate) fn calibrated_squarings(
+    calibration_squarings: u32,
+    calibration_milliseconds: u128,
+    target_milliseconds: u128,
+) -> u32 {
+    let squarings = u128::from(calibration_squarings) * target_milliseconds
+        / cmp::max(calibration_milliseconds, 1);
+    cmp::max(u32::try_from(squarings).unwrap_or(u32::MAX), 1)
+}
+
+/// Time a CPU round of `squarings` would have taken with `reference_squarings`, which
+/// thresholds of the plan are set for
+pub(crate) fn normalize_cpu_round(
+    milliseconds: u128,
+    squarings: u32,
+    reference_squarings: u32,
+) -> u128 {
+    milliseconds * u128::from(reference_squarings) / u128::from(cmp::max(squarings, 1))
+}
+
+/// Time a network round would have taken without `overlapping_transfers` of other sessions,
+/// assuming they shared uplink of the server evenly for the whole round. Transfers which
+/// overlapped the round only partly are overcounted, so corrected times err on the fast side.
+pub(crate) fn correct_for_contention(milliseconds: u128, overlapping_transfers: usize) -> u128 {
+    milliseconds / (overlapping_transfers as u128 + 1)
+}
+
+/// Score timings of a run would get with the thresholds of a plan
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct ScorePreview {
+    pub(crate) score: u128,
+    /// `None` for challenge kinds without rounds
+    pub(crate) cpu_sub_score: Option<u128>,
+    pub(crate) network_sub_score: Option<u128>,
+    pub(crate) disk_sub_score: Option<u128>,
+    /// Rounds which score the run 0
+    pub(crate) rejections: Vec<ScoreRejection>,
+}
+
+/// Scores round timings of each challenge kind with the thresholds of `plan`, so that
+/// candidate thresholds can be tried on historical runs before they are applied
+pub(crate) fn preview_score(
+    plan: &PlanConfig,
+    cpu_results: &[u128],
+    network_results: &[u128],
+    disk_results: &[u128],
+) -> Result<ScorePreview, ScoreError> {
+    let (cpu_ideal_milliseconds, cpu_max_milliseconds) = plan.cpu_thresholds();
+    let (network_ideal_milliseconds, network_max_milliseconds) =
+        plan.network_thresholds(plan.payload_size_kb);
+    let challenges = [
+        ChallengeResults {
+            component: Component::Cpu,
+            challenge: "CPU",
+            kind_id: kinds::challenge::CPU_CHALLENGE,
+            ideal_milliseconds: cpu_ideal_milliseconds,
+            max_milliseconds: cpu_max_milliseconds,
+            aggregation: plan.cpu_aggregation,
+            scale: plan.cpu_scale(),
+            strictness: Strictness::Strict,
+            results: cpu_results,
+        },
+        ChallengeResults {
+            component: Component::Network,
+            challenge: "network",
+            kind_id: kinds::challenge::NETWORK_CHALLENGE,
+            ideal_milliseconds: network_ideal_milliseconds,
+            max_milliseconds: network_max_milliseconds,
+            aggregation: plan.network_aggregation,
+            scale: plan.network_scale(),
+            strictness: Strictness::Strict,
+            results: network_results,
+        },
+        ChallengeResults {
+            component: Component::Disk,
+            challenge: "disk",
+            kind_id: kinds::challenge::DISK_CHALLENGE,
+            ideal_milliseconds: plan.disk_ideal_milliseconds.into(),
+            max_milliseconds: plan.disk_max_milliseconds.into(),
+            aggregation: plan.disk_aggregation,
+            scale: Scale::Time,
+            strictness: Strictness::Strict,
+            results: disk_results,
+        },
+    ];
+    let [cpu, network, disk] = &challenges;
+    let breakdown = calculate_score(&plan.scoring, &challenges)?;
+    Ok(ScorePreview {
+        score: round_score(breakdown.total),
+        cpu_sub_score: cpu.sub_score()?,
+        network_sub_score: network.sub_score()?,
+        disk_sub_score: disk.sub_score()?,
+        rejections: breakdown.rejections,
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use crate::measurements::challenges::{
-        CPUChallengeConfiguration, NetworkChallengeConfiguration,
+        CPUChallengeConfiguration, NetworkChallengeConfiguration, NetworkVerificationMode,
+    };
+    use crate::measurements::plugins::PluginScoring;
+    use crate::measurements::score::{
+        calculate_score, calibrated_squarings, correct_for_contention, cpu_round_milliseconds,
+        cpu_round_squarings_per_second, find_mean, network_round_mbps, network_round_milliseconds,
+        normalize_cpu_round, round_score, Aggregation, ChallengeResults, Scale, ScoreError,
+        ScoringModel, Strictness,
     };
-    use crate::measurements::score::calculate_score;
+    use shared::{kinds, ScoreBreakdown, ScoreRejection};
+
+    /// Rounded score with every kind weighted the same
+    fn total(challenges: &[ChallengeResults]) -> Result<u128, ScoreError> {
+        calculate_score(&ScoringModel::default(), challenges).map(|score| round_score(score.total))
+    }
 
     #[test]
     fn test_score_calculation() {
         let cpu_challenge_config = CPUChallengeConfiguration {
             squarings: 0,
+            batch_verification: false,
             ideal_milliseconds: 100,
             max_milliseconds: 1100,
+            aggregation: Aggregation::Mean,
+            scale: Scale::Time,
         };
 
         let network_challenge_config = NetworkChallengeConfiguration {
             data_size_kb: 0,
             ideal_milliseconds: 200,
             max_milliseconds: 2200,
+            verification_mode: NetworkVerificationMode::FullHash,
+            aggregation: Aggregation::Mean,
+            scale: Scale::Time,
         };
 
         let cpu_results: Vec<u128> = vec![200, 300, 200, 500];
         let network_results: Vec<u128> = vec![300, 400, 300, 600];
 
-        let score = calculate_score(
-            &cpu_challenge_config,
-            &cpu_results,
-            &network_challenge_config,
-            &network_results,
-        );
+        let score = total(&[
+            ChallengeResults::cpu(&cpu_challenge_config, &cpu_results),
+            ChallengeResults::network(&network_challenge_config, &network_results),
+        ])
+        .unwrap();
         assert_eq!(score, 100 - (10 + 5));
     }
 
@@ -92,14 +649,20 @@ mod tests {
     fn test_score_calculation_edge_cases() {
         let cpu_challenge_config = CPUChallengeConfiguration {
             squarings: 0,
+            batch_verification: false,
             ideal_milliseconds: 100,
             max_milliseconds: 1100,
+            aggregation: Aggregation::Mean,
+            scale: Scale::Time,
         };
 
         let network_challenge_config = NetworkChallengeConfiguration {
             data_size_kb: 0,
             ideal_milliseconds: 200,
             max_milliseconds: 2200,
+            verification_mode: NetworkVerificationMode::FullHash,
+            aggregation: Aggregation::Mean,
+            scale: Scale::Time,
         };
 
         // 1200 is outside max_milliseconds range, so we reject
@@ -107,24 +670,408 @@ mod tests {
         let cpu_results: Vec<u128> = vec![1200, 300, 200, 500];
         let network_results: Vec<u128> = vec![300, 400, 300, 600];
 
-        let score = calculate_score(
-            &cpu_challenge_config,
-            &cpu_results,
-            &network_challenge_config,
-            &network_results,
-        );
+        let score = total(&[
+            ChallengeResults::cpu(&cpu_challenge_config, &cpu_results),
+            ChallengeResults::network(&network_challenge_config, &network_results),
+        ])
+        .unwrap();
         assert_eq!(score, 0);
 
         // CPU results median would be less than ideal_miliseconds, in that case it is 50 out of 50.
         let cpu_results: Vec<u128> = vec![1, 2, 3, 4];
         let network_results: Vec<u128> = vec![300, 400, 300, 600];
 
-        let score = calculate_score(
-            &cpu_challenge_config,
-            &cpu_results,
-            &network_challenge_config,
-            &network_results,
-        );
+        let score = total(&[
+            ChallengeResults::cpu(&cpu_challenge_config, &cpu_results),
+            ChallengeResults::network(&network_challenge_config, &network_results),
+        ])
+        .unwrap();
         assert_eq!(score, 100 - (0 + 5));
     }
+
+    #[test]
+    fn test_strictness() {
+        let (cpu_challenge_config, network_challenge_config) = configs(100, 1100);
+        let cpu_results: Vec<u128> = vec![1200, 300, 200, 500];
+        let network_results: Vec<u128> = vec![300, 400, 300, 600];
+        let score = |strictness| {
+            calculate_score(
+                &ScoringModel::default(),
+                &[
+                    ChallengeResults::cpu(&cpu_challenge_config, &cpu_results)
+                        .with_strictness(strictness),
+                    ChallengeResults::network(&network_challenge_config, &network_results)
+                        .with_strictness(strictness),
+                ],
+            )
+            .unwrap()
+        };
+
+        let strict = score(Strictness::Strict);
+        assert_eq!(round_score(strict.total), 0);
+        assert_eq!(strict.rejections.len(), 1);
+        // Round over the maximum is left out, the rest of the CPU rounds average 333ms, network
+        // rounds 400ms
+        let lenient = score(Strictness::Lenient);
+        assert_eq!(round_score(lenient.total), 73);
+        assert_eq!(lenient.rejections.len(), 1);
+        // Mean of all CPU rounds is 550ms
+        let audit_only = score(Strictness::AuditOnly);
+        assert_eq!(round_score(audit_only.total), 63);
+        assert_eq!(audit_only.rejections.len(), 1);
+
+        // Kind whose rounds all took too long scores 0 in lenient sessions, without rejecting
+        let cpu_results: Vec<u128> = vec![1200, 1300];
+        let lenient = calculate_score(
+            &ScoringModel::default(),
+            &[
+                ChallengeResults::cpu(&cpu_challenge_config, &cpu_results)
+                    .with_strictness(Strictness::Lenient),
+                ChallengeResults::network(&network_challenge_config, &network_results)
+                    .with_strictness(Strictness::Lenient),
+            ],
+        )
+        .unwrap();
+        assert_eq!(lenient.cpu_score, Some(0.0));
+        assert_eq!(round_score(lenient.total), 35);
+    }
+
+    #[test]
+    fn test_throughput_scale() {
+        // 1MB payload moves 16.8 megabits in a round
+        assert_eq!(network_round_milliseconds(1024, 100.0), 168);
+        assert_eq!(network_round_milliseconds(1024, 10.0), 1678);
+        assert_eq!(network_round_mbps(1024, 336).round(), 50.0);
+
+        let mut config = NetworkChallengeConfiguration {
+            data_size_kb: 1024,
+            ideal_milliseconds: 100,
+            max_milliseconds: 1000,
+            verification_mode: NetworkVerificationMode::FullHash,
+            aggregation: Aggregation::Mean,
+            scale: Scale::Time,
+        };
+        let results = vec![200];
+        assert_eq!(
+            ChallengeResults::network(&config, &results).sub_score(),
+            Ok(Some(89))
+        );
+        // Half the ideal throughput is over half way to the minimum one, which is a tenth of it
+        config.scale = Scale::Throughput;
+        assert_eq!(
+            ChallengeResults::network(&config, &results).sub_score(),
+            Ok(Some(44))
+        );
+        assert_eq!(
+            ChallengeResults::network(&config, &[100]).sub_score(),
+            Ok(Some(100))
+        );
+        assert_eq!(
+            ChallengeResults::network(&config, &[1000]).sub_score(),
+            Ok(Some(0))
+        );
+        assert_eq!(
+            ChallengeResults::network(&config, &[1001]).sub_score(),
+            Ok(Some(0))
+        );
+    }
+
+    #[test]
+    fn test_squarings_rate_scale() {
+        assert_eq!(cpu_round_milliseconds(200_000, 50_000.0), 4000);
+        assert_eq!(cpu_round_squarings_per_second(200_000, 8000), 25_000.0);
+
+        // Rounds at the same rate score the same whatever their squarings
+        let sub_score = |squarings: u32| {
+            let config = CPUChallengeConfiguration {
+                squarings,
+                batch_verification: false,
+                ideal_milliseconds: cpu_round_milliseconds(squarings, 50_000.0),
+                max_milliseconds: cpu_round_milliseconds(squarings, 5_000.0),
+                aggregation: Aggregation::Mean,
+                scale: Scale::Throughput,
+            };
+            let results = vec![cpu_round_milliseconds(squarings, 25_000.0)];
+            ChallengeResults::cpu(&config, &results).sub_score()
+        };
+        assert_eq!(sub_score(200_000), Ok(Some(44)));
+        assert_eq!(sub_score(1_000_000), Ok(Some(44)));
+    }
+
+    fn configs(
+        ideal_milliseconds: u128,
+        max_milliseconds: u128,
+    ) -> (CPUChallengeConfiguration, NetworkChallengeConfiguration) {
+        (
+            CPUChallengeConfiguration {
+                squarings: 0,
+                batch_verification: false,
+                ideal_milliseconds,
+                max_milliseconds,
+                aggregation: Aggregation::Mean,
+                scale: Scale::Time,
+            },
+            NetworkChallengeConfiguration {
+                data_size_kb: 0,
+                ideal_milliseconds,
+                max_milliseconds,
+                verification_mode: NetworkVerificationMode::FullHash,
+                aggregation: Aggregation::Mean,
+                scale: Scale::Time,
+            },
+        )
+    }
+
+    fn score(
+        cpu: &CPUChallengeConfiguration,
+        cpu_results: &[u128],
+        network: &NetworkChallengeConfiguration,
+        network_results: &[u128],
+    ) -> Result<u128, ScoreError> {
+        total(&[
+            ChallengeResults::cpu(cpu, cpu_results),
+            ChallengeResults::network(network, network_results),
+        ])
+    }
+
+    #[test]
+    fn test_score_boundaries() {
+        // ideal == max: no penalty up to max, rejection above it
+        let (cpu, network) = configs(100, 100);
+        assert_eq!(score(&cpu, &[100], &network, &[100]), Ok(100));
+        assert_eq!(score(&cpu, &[101], &network, &[100]), Ok(0));
+
+        // Single round exactly at max gets the highest penalty of both challenges
+        let (cpu, network) = configs(100, 1100);
+        assert_eq!(score(&cpu, &[1100], &network, &[1100]), Ok(0));
+        assert_eq!(score(&cpu, &[0], &network, &[0]), Ok(100));
+
+        // Penalty is computed on values near u128::MAX without wrapping
+        let (cpu, network) = configs(0, u128::MAX);
+        assert_eq!(score(&cpu, &[u128::MAX], &network, &[0]), Ok(50));
+        assert_eq!(
+            score(&cpu, &[u128::MAX, 1], &network, &[0]),
+            Err(ScoreError::Overflow { challenge: "CPU" })
+        );
+    }
+
+    #[test]
+    fn test_score_invalid_configurations() {
+        let (cpu, network) = configs(200, 100);
+        assert_eq!(
+            score(&cpu, &[150], &network, &[150]),
+            Err(ScoreError::InvalidRange {
+                challenge: "CPU",
+                ideal_milliseconds: 200,
+                max_milliseconds: 100
+            })
+        );
+
+        let (cpu, network) = configs(100, 1100);
+        assert_eq!(score(&cpu, &[], &network, &[]), Err(ScoreError::NoResults));
+        assert_eq!(total(&[]), Err(ScoreError::NoResults));
+    }
+
+    #[test]
+    fn test_score_zero_rounds() {
+        let (cpu, network) = configs(100, 1100);
+
+        // Network only suite is scored on network alone
+        assert_eq!(score(&cpu, &[], &network, &[600]), Ok(50));
+        assert_eq!(score(&cpu, &[], &network, &[1200]), Ok(0));
+        // Same penalties for both kinds give the same score as a single kind
+        assert_eq!(score(&cpu, &[600], &network, &[600]), Ok(50));
+    }
+
+    #[test]
+    fn test_score_with_disk() {
+        let (cpu, network) = configs(100, 1100);
+        let disk = PluginScoring {
+            data_size_kb: 0,
+            ideal_milliseconds: 100,
+            max_milliseconds: 1100,
+            aggregation: Aggregation::Mean,
+        };
+        let score_with_disk = |disk_results: &[u128]| {
+            total(&[
+                ChallengeResults::cpu(&cpu, &[400]),
+                ChallengeResults::network(&network, &[100]),
+                ChallengeResults::plugin(
+                    "disk",
+                    kinds::challenge::DISK_CHALLENGE,
+                    &disk,
+                    disk_results,
+                ),
+            ])
+        };
+
+        // Penalties of 30, 0 and 60 out of 100
+        assert_eq!(score_with_disk(&[700]), Ok(70));
+        assert_eq!(score_with_disk(&[1200]), Ok(0));
+        // Disk wasn't measured, penalties of 30 and 0
+        assert_eq!(score_with_disk(&[]), Ok(85));
+    }
+
+    #[test]
+    fn test_sub_score() {
+        let (cpu, network) = configs(100, 1100);
+        assert_eq!(
+            ChallengeResults::cpu(&cpu, &[600]).sub_score(),
+            Ok(Some(50))
+        );
+        assert_eq!(
+            ChallengeResults::network(&network, &[1200]).sub_score(),
+            Ok(Some(0))
+        );
+        assert_eq!(ChallengeResults::cpu(&cpu, &[]).sub_score(), Ok(None));
+    }
+
+    #[test]
+    fn test_find_mean() {
+        assert_eq!(find_mean(&[]), None);
+        assert_eq!(find_mean(&[7]), Some(7));
+        assert_eq!(find_mean(&[1, 2]), Some(1));
+        assert_eq!(find_mean(&[u128::MAX, 1]), None);
+    }
+
+    #[test]
+    fn test_aggregations() {
+        let results = [300, 100, 200, 5000, 250];
+        assert_eq!(Aggregation::Mean.aggregate(&results), Some(1170));
+        assert_eq!(Aggregation::Median.aggregate(&results), Some(250));
+        assert_eq!(Aggregation::Median.aggregate(&[100, 200]), Some(150));
+        assert_eq!(
+            Aggregation::TrimmedMean { percent: 20 }.aggregate(&results),
+            Some(250)
+        );
+        // Too few rounds to drop any
+        assert_eq!(
+            Aggregation::TrimmedMean { percent: 10 }.aggregate(&results),
+            Some(1170)
+        );
+        assert_eq!(
+            Aggregation::Percentile { percentile: 80 }.aggregate(&results),
+            Some(300)
+        );
+        assert_eq!(
+            Aggregation::Percentile { percentile: 100 }.aggregate(&results),
+            Some(5000)
+        );
+        assert_eq!(
+            Aggregation::Percentile { percentile: 1 }.aggregate(&results),
+            Some(100)
+        );
+        assert_eq!(Aggregation::Median.aggregate(&[]), None);
+
+        let cpu = CPUChallengeConfiguration {
+            squarings: 0,
+            batch_verification: false,
+            ideal_milliseconds: 100,
+            max_milliseconds: 5100,
+            aggregation: Aggregation::TrimmedMean { percent: 50 },
+            scale: Scale::Time,
+        };
+        assert_eq!(
+            ChallengeResults::cpu(&cpu, &results).sub_score(),
+            Err(ScoreError::InvalidAggregation {
+                challenge: "CPU",
+                aggregation: Aggregation::TrimmedMean { percent: 50 },
+            })
+        );
+    }
+
+    #[test]
+    fn test_cpu_calibration() {
+        // A client solving 20000 squarings in 100ms needs 1000000 for a 5 second round
+        let squarings = calibrated_squarings(20000, 100, 5000);
+        assert_eq!(squarings, 1_000_000);
+        assert_eq!(calibrated_squarings(20000, 0, 5000), 100_000_000);
+        assert_eq!(calibrated_squarings(20000, 100_000, 1), 1);
+        assert_eq!(calibrated_squarings(u32::MAX, 1, 5000), u32::MAX);
+
+        // Its 5 second round is as fast as 1 second for the 200000 squarings of the plan
+        assert_eq!(normalize_cpu_round(5000, squarings, 200_000), 1000);
+        assert_eq!(normalize_cpu_round(4500, 200_000, 200_000), 4500);
+    }
+
+    #[test]
+    fn test_contention_correction() {
+        assert_eq!(correct_for_contention(900, 0), 900);
+        assert_eq!(correct_for_contention(900, 2), 300);
+    }
+
+    #[test]
+    fn test_scoring_model() {
+        let (cpu, network) = configs(100, 1100);
+        let challenges = [
+            ChallengeResults::cpu(&cpu, &[400]),
+            ChallengeResults::network(&network, &[255]),
+        ];
+        let model = |cpu_weight, network_weight| ScoringModel {
+            cpu_weight,
+            network_weight,
+            disk_weight: 1.0,
+        };
+
+        // Sub-scores of 70 and 84.5, weighted 3 to 1
+        assert_eq!(
+            calculate_score(&model(3.0, 1.0), &challenges),
+            Ok(ScoreBreakdown {
+                cpu_score: Some(70.0),
+                network_score: Some(84.5),
+                disk_score: None,
+                total: 73.625,
+                rejections: vec![],
+            })
+        );
+        assert_eq!(
+            calculate_score(&model(0.0, 1.0), &challenges).map(|score| score.total),
+            Ok(84.5)
+        );
+        assert_eq!(round_score(84.5), 85);
+
+        // Rejection by a kind scores 0 whatever its weight
+        let rejected = [
+            ChallengeResults::cpu(&cpu, &[1200]),
+            ChallengeResults::network(&network, &[100]),
+        ];
+        let breakdown = calculate_score(&model(0.0, 1.0), &rejected).unwrap();
+        assert_eq!(breakdown.cpu_score, Some(0.0));
+        assert_eq!(breakdown.network_score, Some(100.0));
+        assert_eq!(breakdown.total, 0.0);
+        assert_eq!(
+            breakdown.rejections,
+            vec![ScoreRejection {
+                kind_id: kinds::challenge::CPU_CHALLENGE,
+                round: 0,
+                round_milliseconds: 1200,
+                max_milliseconds: 1100,
+                exceeded_by_milliseconds: 100,
+            }]
+        );
+
+        assert_eq!(
+            calculate_score(&model(0.0, 0.0), &challenges),
+            Err(ScoreError::ZeroWeight)
+        );
+        // Disk has no rounds, so its weight doesn't matter
+        assert!(calculate_score(
+            &ScoringModel {
+                disk_weight: 0.0,
+                ..model(0.0, 1.0)
+            },
+            &challenges
+        )
+        .is_ok());
+        assert_eq!(
+            calculate_score(&model(-1.0, 1.0), &challenges),
+            Err(ScoreError::InvalidWeight { challenge: "CPU" })
+        );
+        assert_eq!(
+            calculate_score(&model(1.0, f64::NAN), &challenges),
+            Err(ScoreError::InvalidWeight {
+                challenge: "network"
+            })
+        );
+    }
 }
@@ -0,0 +1,113 @@
+//! Surprise rounds: small CPU challenges and latency probes sent at random points between the
+//! scheduled CPU and network rounds. They aren't announced in the session limits nor counted in
+//! progress, so a client which only bursts its resources when it expects to be measured answers
+//! them slower than the scheduled rounds. They don't affect the score.
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::types::SessionEventKind;
+
+/// Kind of a surprise round
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum SurpriseKind {
+    Cpu,
+    Latency,
+}
+
+/// Surprise round as stored with the run
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub(crate) struct SurpriseRound {
+    /// Kind of the challenge, see `kinds::challenge`
+    pub(crate) kind_id: u16,
+    /// Scheduled rounds completed before it, counting CPU rounds and then network rounds
+    pub(crate) after_rounds: usize,
+    pub(crate) microseconds: u128,
+}
+
+impl SurpriseRound {
+    pub(crate) fn event(&self) -> SessionEventKind {
+        SessionEventKind::SurpriseRoundCompleted {
+            kind_id: self.kind_id,
+            after_rounds: self.after_rounds,
+            microseconds: self.microseconds,
+        }
+    }
+}
+
+/// Points surprise rounds of a session are sent at, drawn when the session starts
+pub(crate) struct SurpriseSchedule {
+    /// Kind of each surprise round and the scheduled rounds completed before it, latest first
+    rounds: Vec<(usize, SurpriseKind)>,
+}
+
+impl SurpriseSchedule {
+    /// `surprises` rounds of random kinds, each between two of `scheduled_rounds` rounds picked
+    /// at random. Sessions of fewer than two scheduled rounds have no point between them.
+    pub(crate) fn draw<RNG: Rng>(rng: &mut RNG, surprises: usize, scheduled_rounds: usize) -> Self {
+        let mut rounds: Vec<(usize, SurpriseKind)> = if scheduled_rounds < 2 {
+            vec![]
+        } else {
+            (0..surprises)
+                .map(|_| {
+                    let kind = if rng.gen() {
+                        SurpriseKind::Cpu
+                    } else {
+                        SurpriseKind::Latency
+                    };
+                    (rng.gen_range(1, scheduled_rounds), kind)
+                })
+                .collect()
+        };
+        rounds.sort_by(|(a, _), (b, _)| b.cmp(a));
+        SurpriseSchedule { rounds }
+    }
+
+    /// Kinds of surprise rounds due once `completed_rounds` scheduled rounds are completed
+    pub(crate) fn due(&mut self, completed_rounds: usize) -> Vec<SurpriseKind> {
+        let mut due = vec![];
+        while let Some((after_rounds, kind)) = self.rounds.last().copied() {
+            if after_rounds > completed_rounds {
+                break;
+            }
+            self.rounds.pop();
+            due.push(kind);
+        }
+        due
+    }
+}
+
+/// Mean rate of the scheduled CPU rounds over the mean rate of surprise ones, above 1 if client
+/// was slower when it didn't expect to be measured. `None` without rounds of either.
+pub(crate) fn cpu_slowdown(scheduled_rates: &[f64], surprise_rates: &[f64]) -> Option<f64> {
+    if scheduled_rates.is_empty() || surprise_rates.is_empty() {
+        return None;
+    }
+    let mean = |rates: &[f64]| rates.iter().sum::<f64>() / rates.len() as f64;
+    Some(mean(scheduled_rates) / mean(surprise_rates))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::measurements::surprise::{cpu_slowdown, SurpriseSchedule};
+    use rand::rngs::OsRng;
+
+    #[test]
+    fn test_schedule() {
+        let mut schedule = SurpriseSchedule::draw(&mut OsRng, 20, 5);
+        let mut drawn = 0;
+        assert!(schedule.due(0).is_empty());
+        for completed_rounds in 1..5 {
+            drawn += schedule.due(completed_rounds).len();
+        }
+        assert_eq!(drawn, 20);
+        assert!(schedule.due(5).is_empty());
+
+        // No point between rounds
+        let mut schedule = SurpriseSchedule::draw(&mut OsRng, 3, 1);
+        assert!(schedule.due(1).is_empty());
+
+        assert_eq!(cpu_slowdown(&[200.0, 200.0], &[100.0]), Some(2.0));
+        assert_eq!(cpu_slowdown(&[200.0], &[]), None);
+    }
+}
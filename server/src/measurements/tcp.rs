@@ -0,0 +1,144 @@
+//! Measurement of clients connected over plain TCP, see `TcpTransport`
+
+use crate::config::ServerConfig;
+use crate::measurements::route::{admission, handle_connection, reject};
+use crate::transport::TcpTransport;
+use crate::tuning::current_plan;
+use crate::types::{AddressFamily, ServerContext, SessionParameters};
+use crate::utils::MAX_CHUNKED_MESSAGE_BYTES;
+
+use futures::Future;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use uuid::Uuid;
+
+/// Measures clients connecting to `listener` until `shutdown` resolves. They can't pass query
+/// parameters, so they are measured with the plan of `config` as anonymous clients without tags.
+/// Rejections are always sent as `Message::Rejected`.
+pub(crate) async fn serve_tcp(
+    context: ServerContext,
+    config: Arc<ServerConfig>,
+    mut listener: TcpListener,
+    shutdown: impl Future<Output = ()>,
+) {
+    tokio::pin!(shutdown);
+    loop {
+        let (stream, remote_addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Unable to accept TCP connection: {:?}", e);
+                    continue;
+                }
+            },
+            _ = &mut shutdown => return,
+        };
+        // Correlation id of the session, also used as id of the stored run
+        let client_id = Uuid::new_v4().as_u128();
+        info!(
+            "Client[{:x}] connected over TCP from {}",
+            client_id, remote_addr
+        );
+        let plan = current_plan(&context, &config);
+        let mut session_parameters = SessionParameters {
+            address_family: Some(AddressFamily::of(&remote_addr)),
+            ..SessionParameters::new(&config)
+        };
+        let transport = TcpTransport::new(
+            stream,
+            session_parameters
+                .memory_limit_bytes
+                .unwrap_or(MAX_CHUNKED_MESSAGE_BYTES),
+        );
+        let context = context.clone();
+        // Session hooks may take a while, so other clients aren't kept waiting to be accepted
+        tokio::spawn(async move {
+            let admission = admission(
+                &context,
+                &plan,
+                None,
+                Some(remote_addr),
+                None,
+                client_id,
+                &mut session_parameters,
+            )
+            .await;
+            match admission {
+                Ok(slot) => {
+                    handle_connection(
+                        transport,
+                        context,
+                        plan,
+                        session_parameters,
+                        client_id,
+                        slot,
+                    )
+                    .await
+                }
+                Err(rejection) => reject(transport, rejection, client_id).await,
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{PlanConfig, ServerConfig};
+    use crate::measurements::serve_tcp;
+    use crate::types::{AddressFamily, ServerContext, Visibility};
+    use shared::{RejectionReason, SessionStatus};
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+
+    fn options(url: String) -> client::Options {
+        client::Options {
+            url,
+            with_proof: false,
+            disk_directory: std::env::temp_dir(),
+            client_token: None,
+            network_type: None,
+            client_version: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tcp_session() {
+        let config = Arc::new(ServerConfig {
+            plan: PlanConfig {
+                cpu_rounds: 1,
+                squarings: 1000,
+                network_rounds: 1,
+                payload_size_kb: 16,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let context = ServerContext::new(&config).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("tcp://{}", listener.local_addr().unwrap());
+        tokio::spawn(serve_tcp(
+            context.clone(),
+            config,
+            listener,
+            futures::future::pending(),
+        ));
+
+        let outcome = client::measure(options(url.clone())).await.unwrap();
+        assert_eq!(outcome.status, SessionStatus::Completed);
+        assert_eq!(outcome.report.unwrap().challenges.len(), 2);
+        let run_id = u128::from_str_radix(&outcome.stored_run_id.unwrap(), 16).unwrap();
+        let run = context
+            .storage
+            .get(run_id, Visibility::All)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(run.address_family, Some(AddressFamily::Ipv4));
+
+        // Rejections are sent over the connection, there is no HTTP response to carry them
+        context.draining.start();
+        let error = client::measure(options(url)).await.unwrap_err();
+        let rejected = error.downcast_ref::<client::Rejected>().unwrap();
+        assert_eq!(rejected.reason, RejectionReason::Maintenance);
+    }
+}
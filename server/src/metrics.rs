@@ -0,0 +1,285 @@
+use serde::Serialize;
+use shared::hash::HashAlgorithm;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::certificate::to_hex;
+use crate::config::ServerConfig;
+
+/// Upper bounds of latency histogram buckets, requests slower than the last one fall into an overflow bucket
+const LATENCY_BUCKETS_MILLISECONDS: [u64; 11] = [1, 2, 5, 10, 25, 50, 100, 250, 500, 1000, 5000];
+
+/// Routes of the HTTP API, `{...}` segments match any value. Requests to other paths aren't recorded.
+const ROUTES: &[&str] = &[
+    "/runs/{id}",
+    "/clients/{id}",
+    "/runs/{id}/dual_stack",
+    "/runs/{id}/tls_overhead",
+    "/runs/{id}/certificate",
+    "/certificates/keys",
+    "/certificates/verify",
+    "/admin/certificate/rotate",
+    "/clients",
+    "/identities/{id}",
+    "/scores",
+    "/api/sessions",
+    "/api/scores/distribution",
+    "/api/history",
+    "/dashboard",
+    "/anomalies",
+    "/export",
+    "/compare",
+    "/fleets/{name}",
+    "/fleets/{name}/trend",
+    "/score/preview",
+    "/admin/capacity",
+    "/signal",
+    "/admin/tasks",
+    "/admin/primes",
+    "/admin/thresholds",
+    "/admin/metrics",
+    "/admin/dead_letters",
+    "/admin/dead_letters/{id}/requeue",
+    "/admin/dead_letters/{id}",
+    "/admin/ws",
+];
+
+/// Tenant of requests with neither a known API key nor a bearer token
+const ANONYMOUS: &str = "anonymous";
+/// Tenant of requests with a bearer token of the identity provider
+const SINGLE_SIGN_ON: &str = "sso";
+
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+struct RouteKey {
+    route: &'static str,
+    method: String,
+    tenant: String,
+}
+
+#[derive(Default)]
+struct RouteStats {
+    requests: u64,
+    client_errors: u64,
+    server_errors: u64,
+    total_micros: u128,
+    max_micros: u128,
+    /// Requests per bucket of `LATENCY_BUCKETS_MILLISECONDS`, followed by the overflow bucket
+    latency_buckets: [u64; LATENCY_BUCKETS_MILLISECONDS.len() + 1],
+}
+
+/// Counts and latencies of HTTP API requests, per route, method and tenant. Measurement
+/// sessions aren't included, they're covered by tasks and capacity.
+#[derive(Clone, Default)]
+pub(crate) struct RequestMetrics(Arc<Mutex<BTreeMap<RouteKey, RouteStats>>>);
+
+#[derive(Serialize)]
+pub(crate) struct LatencyBucket {
+    /// Upper bound of the bucket, `None` for requests slower than all bounds
+    le_milliseconds: Option<u64>,
+    requests: u64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct RouteMetrics {
+    route: &'static str,
+    method: String,
+    tenant: String,
+    requests: u64,
+    /// Requests answered with 4xx
+    client_errors: u64,
+    /// Requests answered with 5xx
+    server_errors: u64,
+    mean_milliseconds: f64,
+    max_milliseconds: f64,
+    latency_histogram: Vec<LatencyBucket>,
+}
+
+impl RequestMetrics {
+    pub(crate) fn record(
+        &self,
+        route: &'static str,
+        method: &str,
+        tenant: String,
+        status: u16,
+        elapsed: Duration,
+    ) {
+        let key = RouteKey {
+            route,
+            method: method.to_owned(),
+            tenant,
+        };
+        let mut routes = self.0.lock().unwrap();
+        let stats = routes.entry(key).or_default();
+        stats.requests += 1;
+        match status {
+            400..=499 => stats.client_errors += 1,
+            500..=599 => stats.server_errors += 1,
+            _ => {}
+        }
+        let micros = elapsed.as_micros();
+        stats.total_micros += micros;
+        stats.max_micros = stats.max_micros.max(micros);
+        let bucket = LATENCY_BUCKETS_MILLISECONDS
+            .iter()
+            .position(|bound| micros <= *bound as u128 * 1000)
+            .unwrap_or(LATENCY_BUCKETS_MILLISECONDS.len());
+        stats.latency_buckets[bucket] += 1;
+    }
+
+    pub(crate) fn report(&self) -> Vec<RouteMetrics> {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, stats)| RouteMetrics {
+                route: key.route,
+                method: key.method.clone(),
+                tenant: key.tenant.clone(),
+                requests: stats.requests,
+                client_errors: stats.client_errors,
+                server_errors: stats.server_errors,
+                mean_milliseconds: stats.total_micros as f64 / stats.requests as f64 / 1000.0,
+                max_milliseconds: stats.max_micros as f64 / 1000.0,
+                latency_histogram: stats
+                    .latency_buckets
+                    .iter()
+                    .enumerate()
+                    .map(|(bucket, requests)| LatencyBucket {
+                        le_milliseconds: LATENCY_BUCKETS_MILLISECONDS.get(bucket).copied(),
+                        requests: *requests,
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+}
+
+/// Route of the API `path` was served by, `base_path` is stripped from it first
+pub(crate) fn route_label(base_path: &str, path: &str) -> Option<&'static str> {
+    let base_segments = base_path.split('/').filter(|s| !s.is_empty()).count();
+    let segments: Vec<&str> = path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .skip(base_segments)
+        .collect();
+    ROUTES.iter().copied().find(|route| {
+        let template: Vec<&str> = route.split('/').filter(|s| !s.is_empty()).collect();
+        template.len() == segments.len()
+            && template
+                .iter()
+                .zip(&segments)
+                .all(|(expected, actual)| expected.starts_with('{') || expected == actual)
+    })
+}
+
+/// Tenant requests are labeled with. Keys of configured tenants are labeled with the tenant's
+/// name, other API keys known to the server are identified by the first 8 hex digits of their
+/// SHA-256, so that labels don't reveal them. Unknown keys aren't told apart, which keeps the
+/// number of labels bounded.
+pub(crate) fn tenant_label(
+    config: &ServerConfig,
+    api_key: Option<&str>,
+    authorization: Option<&str>,
+) -> String {
+    if let Some((name, _)) = config.tenant(api_key) {
+        return format!("tenant:{}", name);
+    }
+    match api_key {
+        Some(api_key)
+            if config.priority_classes.contains_key(api_key)
+                || config.private_tenants.contains(api_key)
+                || config.reader_api_keys.contains(api_key)
+                || config.admin_api_keys.contains(api_key) =>
+        {
+            format!(
+                "key:{}",
+                to_hex(&HashAlgorithm::Sha256.digest(api_key.as_bytes())[..4])
+            )
+        }
+        _ if authorization.is_some_and(|authorization| authorization.starts_with("Bearer ")) => {
+            SINGLE_SIGN_ON.to_owned()
+        }
+        _ => ANONYMOUS.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{ServerConfig, TenantConfig};
+    use crate::metrics::{route_label, tenant_label, RequestMetrics};
+    use std::time::Duration;
+
+    #[test]
+    fn test_labels() {
+        assert_eq!(route_label("", "/runs/abc"), Some("/runs/{id}"));
+        assert_eq!(
+            route_label("", "/runs/abc/dual_stack"),
+            Some("/runs/{id}/dual_stack")
+        );
+        assert_eq!(route_label("", "/clients"), Some("/clients"));
+        assert_eq!(
+            route_label("/reliability/v1/", "/reliability/v1/fleets/_/trend"),
+            Some("/fleets/{name}/trend")
+        );
+        assert_eq!(route_label("", "/runs/abc/other"), None);
+        assert_eq!(route_label("", "/ws"), None);
+
+        let mut config = ServerConfig::default();
+        config.private_tenants.insert("tenant".to_owned());
+        let label = tenant_label(&config, Some("tenant"), None);
+        assert!(label.starts_with("key:") && !label.contains("tenant"));
+        assert_eq!(label.len(), 12);
+        config.tenants.insert(
+            "payments".to_owned(),
+            TenantConfig {
+                api_keys: vec!["tenant".to_owned()].into_iter().collect(),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            tenant_label(&config, Some("tenant"), None),
+            "tenant:payments"
+        );
+        assert_eq!(tenant_label(&config, Some("unknown"), None), "anonymous");
+        assert_eq!(tenant_label(&config, None, Some("Bearer abc")), "sso");
+        assert_eq!(tenant_label(&config, None, None), "anonymous");
+    }
+
+    #[test]
+    fn test_request_metrics() {
+        let metrics = RequestMetrics::default();
+        let record = |tenant: &str, status, milliseconds| {
+            metrics.record(
+                "/scores",
+                "GET",
+                tenant.to_owned(),
+                status,
+                Duration::from_millis(milliseconds),
+            )
+        };
+        record("anonymous", 200, 3);
+        record("anonymous", 500, 30);
+        record("anonymous", 404, 6000);
+        record("sso", 200, 1);
+
+        let report = metrics.report();
+        assert_eq!(report.len(), 2);
+        let anonymous = &report[0];
+        assert_eq!(anonymous.tenant, "anonymous");
+        assert_eq!(anonymous.requests, 3);
+        assert_eq!(anonymous.client_errors, 1);
+        assert_eq!(anonymous.server_errors, 1);
+        assert_eq!(anonymous.max_milliseconds, 6000.0);
+        assert_eq!(anonymous.mean_milliseconds, 2011.0);
+        let counts: Vec<u64> = anonymous
+            .latency_histogram
+            .iter()
+            .map(|bucket| bucket.requests)
+            .collect();
+        assert_eq!(counts, vec![0, 0, 1, 0, 0, 1, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(anonymous.latency_histogram[11].le_milliseconds, None);
+        assert_eq!(report[1].tenant, "sso");
+        assert_eq!(report[1].latency_histogram[0].requests, 1);
+    }
+}
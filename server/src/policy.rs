@@ -0,0 +1,116 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::dead_letters::SideEffect;
+
+/// Configuration of the hook notifying orchestration systems (schedulers, load balancers)
+/// whenever a node becomes acceptable or stops being acceptable.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct PolicyHookConfig {
+    /// `http://` endpoint verdicts are POSTed to as JSON
+    pub(crate) url: String,
+    /// Minimal score for a node to be considered acceptable
+    pub(crate) acceptable_score: u128,
+    /// Tag identifying a node across runs, runs without it are ignored
+    pub(crate) node_tag: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Verdict {
+    Allow,
+    Deny,
+}
+
+#[derive(Serialize)]
+struct VerdictPayload<'a> {
+    node: &'a str,
+    run_id: String,
+    score: u128,
+    acceptable_score: u128,
+    verdict: Verdict,
+}
+
+pub(crate) struct PolicyHook {
+    config: PolicyHookConfig,
+    last_verdicts: Mutex<HashMap<String, Verdict>>,
+}
+
+impl PolicyHook {
+    pub(crate) fn new(config: PolicyHookConfig) -> Self {
+        PolicyHook {
+            config,
+            last_verdicts: Default::default(),
+        }
+    }
+
+    pub(crate) fn node_tag(&self) -> &str {
+        &self.config.node_tag
+    }
+
+    /// Records verdict for the node's latest score and returns it if it differs from
+    /// the previous one. First verdict for a node is always returned.
+    fn transition(&self, node: &str, score: u128) -> Option<Verdict> {
+        let verdict = if score >= self.config.acceptable_score {
+            Verdict::Allow
+        } else {
+            Verdict::Deny
+        };
+
+        let previous = self
+            .last_verdicts
+            .lock()
+            .unwrap()
+            .insert(node.to_owned(), verdict);
+        if previous == Some(verdict) {
+            None
+        } else {
+            Some(verdict)
+        }
+    }
+
+    /// Notification of the configured endpoint if node's score crossed the acceptable
+    /// threshold, to be delivered through the dead letter queue
+    pub(crate) fn observe(&self, node: &str, client_id: u128, score: u128) -> Option<SideEffect> {
+        let verdict = self.transition(node, score)?;
+        let body = serde_json::to_value(&VerdictPayload {
+            node,
+            run_id: format!("{:x}", client_id),
+            score,
+            acceptable_score: self.config.acceptable_score,
+            verdict,
+        })
+        .expect("verdict payload is always serializable");
+        info!(
+            "Sending {:?} verdict for node {} to policy hook",
+            verdict, node
+        );
+        Some(SideEffect::Webhook {
+            url: self.config.url.clone(),
+            body,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::policy::{PolicyHook, PolicyHookConfig, Verdict};
+
+    #[test]
+    fn test_verdict_transitions() {
+        let hook = PolicyHook::new(PolicyHookConfig {
+            url: "http://localhost".to_owned(),
+            acceptable_score: 60,
+            node_tag: "node".to_owned(),
+        });
+
+        assert_eq!(hook.transition("a", 70), Some(Verdict::Allow));
+        assert_eq!(hook.transition("a", 60), None);
+        assert_eq!(hook.transition("a", 59), Some(Verdict::Deny));
+        assert_eq!(hook.transition("a", 10), None);
+        assert_eq!(hook.transition("b", 10), Some(Verdict::Deny));
+        assert_eq!(hook.transition("a", 100), Some(Verdict::Allow));
+    }
+}
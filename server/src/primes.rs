@@ -0,0 +1,319 @@
+use anyhow::{anyhow, Result};
+use num_bigint::BigUint;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use shared::challenges::primes::{GlassPumpkin, NumBigIntDig, PrecomputedPrimes, PrimeGenerator};
+use shared::challenges::timelock::DEFAULT_PRIME_BITS;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum PrimeBackend {
+    GlassPumpkin,
+    /// Generator of the `rsa` crate, also works below 128 bits
+    NumBigintDig,
+    /// Primes read from `path`, one per line in decimal
+    Precomputed,
+}
+
+/// Source of the secret primes of CPU challenges
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct PrimesConfig {
+    pub(crate) backend: PrimeBackend,
+    /// Bits of each of the two primes, modulus of puzzles has twice as many
+    pub(crate) bits: usize,
+    /// List of primes of the `precomputed` backend. It must be kept secret, as squarings of a
+    /// puzzle whose primes are known can be skipped.
+    pub(crate) path: Option<PathBuf>,
+    /// Primes generated ahead of time by background threads, so that CPU rounds don't wait for
+    /// generation. Zero generates every prime when a round needs it.
+    pub(crate) pool_size: usize,
+    /// Threads filling the pool
+    pub(crate) pool_workers: usize,
+}
+
+impl Default for PrimesConfig {
+    fn default() -> Self {
+        PrimesConfig {
+            backend: PrimeBackend::GlassPumpkin,
+            bits: DEFAULT_PRIME_BITS,
+            path: None,
+            pool_size: 0,
+            pool_workers: 1,
+        }
+    }
+}
+
+/// Time spent generating primes, served at `/admin/primes`
+#[derive(Debug, Serialize)]
+pub(crate) struct PrimeMetrics {
+    pub(crate) backend: &'static str,
+    pub(crate) bits: usize,
+    pub(crate) generated: u64,
+    pub(crate) failures: u64,
+    pub(crate) mean_generation_micros: u64,
+    pub(crate) max_generation_micros: u64,
+    /// Primes waiting in the pool
+    pub(crate) pooled: u64,
+    /// Primes generated inline because the pool was empty
+    pub(crate) pool_misses: u64,
+}
+
+/// Configured generator along with how long generation takes, shared with pool workers
+struct TimedGenerator {
+    generator: Box<dyn PrimeGenerator>,
+    generated: AtomicU64,
+    failures: AtomicU64,
+    total_micros: AtomicU64,
+    max_micros: AtomicU64,
+}
+
+impl TimedGenerator {
+    fn generate(&self, rng: &mut dyn RngCore, bits: usize) -> Result<BigUint> {
+        let start = Instant::now();
+        let prime = self.generator.generate(rng, bits);
+        let micros = start.elapsed().as_micros() as u64;
+        match &prime {
+            Ok(_) => {
+                self.generated.fetch_add(1, Ordering::SeqCst);
+                self.total_micros.fetch_add(micros, Ordering::SeqCst);
+                self.max_micros.fetch_max(micros, Ordering::SeqCst);
+            }
+            Err(e) => {
+                warn!("Unable to generate prime: {:?}", e);
+                self.failures.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        prime
+    }
+}
+
+/// Primes of the configured size generated ahead of time. Pooled rather than whole puzzles, as
+/// squarings of a session's puzzles are only known once it's calibrated.
+struct PrimePool {
+    primes: Mutex<Receiver<BigUint>>,
+    /// Primes sent into the pool and not taken yet. A prime may be taken before its worker counts
+    /// it, which briefly takes the count below zero.
+    pooled: Arc<AtomicI64>,
+    misses: AtomicU64,
+}
+
+impl PrimePool {
+    /// Starts `workers` threads keeping up to `size` primes of `bits` ready.
+    /// Workers exit once the pool is dropped.
+    fn start(generator: Arc<TimedGenerator>, bits: usize, size: usize, workers: usize) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(size);
+        let pooled = Arc::new(AtomicI64::new(0));
+        for worker in 0..workers.max(1) {
+            let generator = generator.clone();
+            let sender = sender.clone();
+            let pooled = pooled.clone();
+            thread::Builder::new()
+                .name(format!("prime-pool-{}", worker))
+                .spawn(move || loop {
+                    match generator.generate(&mut OsRng, bits) {
+                        Ok(prime) => {
+                            // Blocks while the pool is full, the prime isn't pooled until it's sent
+                            if sender.send(prime).is_err() {
+                                break;
+                            }
+                            pooled.fetch_add(1, Ordering::SeqCst);
+                        }
+                        // Failures are counted by the generator, don't spin on one which keeps failing
+                        Err(_) => thread::sleep(Duration::from_secs(1)),
+                    }
+                })
+                .expect("Unable to start prime pool worker");
+        }
+        PrimePool {
+            primes: Mutex::new(receiver),
+            pooled,
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Takes a prime from the pool, `None` if it's empty
+    fn take(&self) -> Option<BigUint> {
+        let prime = self.primes.lock().unwrap().try_recv().ok();
+        match prime {
+            Some(_) => {
+                self.pooled.fetch_sub(1, Ordering::SeqCst);
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        prime
+    }
+}
+
+/// Configured prime generator which keeps track of how long generation takes
+pub(crate) struct PrimeSource {
+    generator: Arc<TimedGenerator>,
+    backend: PrimeBackend,
+    bits: usize,
+    pool: Option<PrimePool>,
+}
+
+impl PrimeSource {
+    pub(crate) fn new(config: &PrimesConfig) -> Result<Self> {
+        let generator: Box<dyn PrimeGenerator> = match config.backend {
+            PrimeBackend::GlassPumpkin => Box::new(GlassPumpkin),
+            PrimeBackend::NumBigintDig => Box::new(NumBigIntDig),
+            PrimeBackend::Precomputed => {
+                let path = config
+                    .path
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("Precomputed primes need a path"))?;
+                let list = fs::read_to_string(path)
+                    .map_err(|e| anyhow!("Unable to read {}: {:?}", path.display(), e))?;
+                Box::new(PrecomputedPrimes::parse(&list)?)
+            }
+        };
+        let generator = Arc::new(TimedGenerator {
+            generator,
+            generated: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+            total_micros: AtomicU64::new(0),
+            max_micros: AtomicU64::new(0),
+        });
+        // Precomputed primes are picked from memory, there's no generation to get ahead of
+        let pool =
+            (config.pool_size > 0 && config.backend != PrimeBackend::Precomputed).then(|| {
+                PrimePool::start(
+                    generator.clone(),
+                    config.bits,
+                    config.pool_size,
+                    config.pool_workers,
+                )
+            });
+        Ok(PrimeSource {
+            generator,
+            backend: config.backend,
+            bits: config.bits,
+            pool,
+        })
+    }
+
+    pub(crate) fn bits(&self) -> usize {
+        self.bits
+    }
+
+    pub(crate) fn metrics(&self) -> PrimeMetrics {
+        let generated = self.generator.generated.load(Ordering::SeqCst);
+        PrimeMetrics {
+            backend: match self.backend {
+                PrimeBackend::GlassPumpkin => "glass_pumpkin",
+                PrimeBackend::NumBigintDig => "num_bigint_dig",
+                PrimeBackend::Precomputed => "precomputed",
+            },
+            bits: self.bits,
+            generated,
+            failures: self.generator.failures.load(Ordering::SeqCst),
+            mean_generation_micros: self
+                .generator
+                .total_micros
+                .load(Ordering::SeqCst)
+                .checked_div(generated)
+                .unwrap_or_default(),
+            max_generation_micros: self.generator.max_micros.load(Ordering::SeqCst),
+            pooled: self
+                .pool
+                .as_ref()
+                .map_or(0, |pool| pool.pooled.load(Ordering::SeqCst).max(0) as u64),
+            pool_misses: self
+                .pool
+                .as_ref()
+                .map_or(0, |pool| pool.misses.load(Ordering::SeqCst)),
+        }
+    }
+}
+
+impl PrimeGenerator for PrimeSource {
+    /// Takes a pooled prime if there's one of `bits`, generates it otherwise
+    fn generate(&self, rng: &mut dyn RngCore, bits: usize) -> Result<BigUint> {
+        let pooled = self
+            .pool
+            .as_ref()
+            .filter(|_| bits == self.bits)
+            .and_then(PrimePool::take);
+        match pooled {
+            Some(prime) => Ok(prime),
+            None => self.generator.generate(rng, bits),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::primes::{PrimeBackend, PrimeSource, PrimesConfig};
+    use rand::rngs::OsRng;
+    use shared::challenges::primes::PrimeGenerator;
+    use shared::challenges::timelock::Timelock;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_prime_source() {
+        let source = PrimeSource::new(&PrimesConfig {
+            backend: PrimeBackend::NumBigintDig,
+            bits: 64,
+            ..Default::default()
+        })
+        .unwrap();
+        let (timelock, verifier) =
+            Timelock::generate_with_primes(&mut OsRng, 10, &source, source.bits()).unwrap();
+        assert!(verifier.verify(timelock.perform_challenge()));
+        assert!(source.generate(&mut OsRng, 1).is_err());
+
+        let metrics = source.metrics();
+        assert_eq!(metrics.backend, "num_bigint_dig");
+        assert_eq!(metrics.generated, 2);
+        assert_eq!(metrics.failures, 1);
+        assert!(metrics.max_generation_micros >= metrics.mean_generation_micros);
+        assert_eq!(metrics.pool_misses, 0);
+
+        assert!(PrimeSource::new(&PrimesConfig {
+            backend: PrimeBackend::Precomputed,
+            bits: 64,
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn test_prime_pool() {
+        let source = PrimeSource::new(&PrimesConfig {
+            backend: PrimeBackend::NumBigintDig,
+            bits: 64,
+            pool_size: 4,
+            pool_workers: 2,
+            ..Default::default()
+        })
+        .unwrap();
+        // Pool holds up to `pool_size` primes, those of workers waiting for room aren't counted
+        for _ in 0..100 {
+            if source.metrics().pooled >= 4 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(source.metrics().pooled, 4);
+
+        let (timelock, verifier) =
+            Timelock::generate_with_primes(&mut OsRng, 10, &source, source.bits()).unwrap();
+        assert!(verifier.verify(timelock.perform_challenge()));
+        assert_eq!(source.metrics().pool_misses, 0);
+        // Primes of other sizes aren't pooled
+        assert!(source.generate(&mut OsRng, 32).is_ok());
+        assert_eq!(source.metrics().pool_misses, 0);
+    }
+}
@@ -0,0 +1,207 @@
+//! Progress of sessions in flight, streamed to operators at `/admin/ws` as their events happen
+//! and listed at `/api/sessions`
+
+use futures::{SinkExt, StreamExt};
+use serde::Serialize;
+use shared::kinds;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::sync::broadcast::{self, RecvError};
+use warp::ws::{Message, WebSocket};
+
+use crate::api::unix_milliseconds;
+use crate::storage::{tenant_serves, TenantScope};
+use crate::types::{SessionEvent, SessionEventKind, Visibility};
+
+/// Events kept for watchers which fall behind, older ones are skipped
+const CAPACITY: usize = 1024;
+
+/// Event of a session as streamed, a JSON object per text frame
+#[derive(Serialize)]
+struct ProgressEvent<'a> {
+    run_id: String,
+    plan: &'a str,
+    elapsed_milliseconds: u128,
+    /// Rounds of the event's challenge kind the session measures, `None` for events of no round
+    rounds: Option<usize>,
+    #[serde(flatten)]
+    kind: &'a SessionEventKind,
+}
+
+/// Challenge kind of rounds `kind` is an event of
+fn kind_id(kind: &SessionEventKind) -> Option<u16> {
+    match kind {
+        SessionEventKind::CPUChallengeCompleted { .. } => Some(kinds::challenge::CPU_CHALLENGE),
+        SessionEventKind::NetworkChallengeCompleted { .. }
+        | SessionEventKind::NetworkChallengeMismatched { .. } => {
+            Some(kinds::challenge::NETWORK_CHALLENGE)
+        }
+        SessionEventKind::DiskChallengeCompleted { .. } => Some(kinds::challenge::DISK_CHALLENGE),
+        SessionEventKind::DownloadChallengeCompleted { .. } => {
+            Some(kinds::challenge::DOWNLOAD_CHALLENGE)
+        }
+        SessionEventKind::UploadChallengeCompleted { .. } => {
+            Some(kinds::challenge::UPLOAD_CHALLENGE)
+        }
+        SessionEventKind::ChallengeCompleted { kind_id, .. }
+        | SessionEventKind::RoundFailed { kind_id, .. }
+        | SessionEventKind::RoundImplausible { kind_id, .. }
+        | SessionEventKind::RoundRetried { kind_id, .. } => Some(*kind_id),
+        _ => None,
+    }
+}
+
+/// Session in flight as listed at `/api/sessions`
+#[derive(Clone, Serialize)]
+pub(crate) struct LiveSession {
+    run_id: String,
+    plan: String,
+    #[serde(skip)]
+    private: bool,
+    #[serde(skip)]
+    tenant: Option<String>,
+    #[serde(skip)]
+    api_key_digest: Option<String>,
+    started_at_unix_milliseconds: u128,
+    /// Latest event of the session, `None` until its first one
+    last_event: Option<SessionEventKind>,
+    /// Rounds of the challenge kind of `last_event` the session measures
+    rounds: Option<usize>,
+}
+
+/// Session events of all sessions, broadcast to every watcher, and the latest event of each
+/// session in flight
+#[derive(Clone)]
+pub(crate) struct SessionProgress {
+    sender: broadcast::Sender<String>,
+    live: Arc<Mutex<HashMap<u128, LiveSession>>>,
+}
+
+impl Default for SessionProgress {
+    fn default() -> Self {
+        SessionProgress {
+            sender: broadcast::channel(CAPACITY).0,
+            live: Default::default(),
+        }
+    }
+}
+
+impl SessionProgress {
+    /// Lists session `client_id` of `plan` and of a client of `tenant`, which sent the API key of
+    /// `api_key_digest`, until `end` is called
+    pub(crate) fn start(
+        &self,
+        client_id: u128,
+        plan: &str,
+        private: bool,
+        tenant: Option<String>,
+        api_key_digest: Option<String>,
+    ) {
+        self.live.lock().unwrap().insert(
+            client_id,
+            LiveSession {
+                run_id: format!("{:x}", client_id),
+                plan: plan.to_owned(),
+                private,
+                tenant,
+                api_key_digest,
+                started_at_unix_milliseconds: unix_milliseconds(SystemTime::now()),
+                last_event: None,
+                rounds: None,
+            },
+        );
+    }
+
+    pub(crate) fn end(&self, client_id: u128) {
+        self.live.lock().unwrap().remove(&client_id);
+    }
+
+    /// Sessions in flight that `visibility` may see within `scope`, oldest first, see
+    /// `tenant_serves`
+    pub(crate) fn live_sessions(
+        &self,
+        visibility: Visibility,
+        scope: Option<&TenantScope>,
+    ) -> Vec<LiveSession> {
+        let mut sessions: Vec<LiveSession> = self
+            .live
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|session| {
+                tenant_serves(
+                    scope,
+                    visibility,
+                    session.tenant.as_deref(),
+                    session.private,
+                    session.api_key_digest.as_deref(),
+                )
+            })
+            .cloned()
+            .collect();
+        sessions.sort_by_key(|session| session.started_at_unix_milliseconds);
+        sessions
+    }
+
+    /// Broadcasts `event` of session `client_id` of `plan`, which measures `rounds` rounds of
+    /// each challenge kind
+    pub(crate) fn publish(
+        &self,
+        client_id: u128,
+        plan: &str,
+        rounds: &HashMap<u16, usize>,
+        event: &SessionEvent,
+    ) {
+        let rounds = kind_id(&event.kind).and_then(|kind_id| rounds.get(&kind_id).copied());
+        if let Some(session) = self.live.lock().unwrap().get_mut(&client_id) {
+            session.last_event = Some(event.kind.clone());
+            session.rounds = rounds;
+        }
+        // Nobody to serialize the event for
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+        let progress = ProgressEvent {
+            run_id: format!("{:x}", client_id),
+            plan,
+            elapsed_milliseconds: event.timestamp.session_offset.as_millis(),
+            rounds,
+            kind: &event.kind,
+        };
+        match serde_json::to_string(&progress) {
+            Ok(progress) => {
+                // Watchers may have gone in the meantime
+                let _ = self.sender.send(progress);
+            }
+            Err(e) => error!("Unable to serialize progress of {:x}: {:?}", client_id, e),
+        }
+    }
+}
+
+/// Sends progress events over `socket` until the watcher goes away
+pub(crate) async fn stream_progress(socket: WebSocket, progress: SessionProgress) {
+    let (mut sender, mut receiver) = socket.split();
+    let mut events = progress.sender.subscribe();
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => {
+                    if let Err(e) = sender.send(Message::text(event)).await {
+                        debug!("Progress stream ended: {:?}", e);
+                        return;
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Progress stream fell behind, skipped {} events", skipped)
+                }
+                Err(RecvError::Closed) => return,
+            },
+            // Watcher sends nothing but the close frame
+            message = receiver.next() => match message {
+                Some(Ok(message)) if !message.is_close() => {}
+                _ => return,
+            },
+        }
+    }
+}
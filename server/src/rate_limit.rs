@@ -0,0 +1,205 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Tracked hosts above which hosts with their full allowance are forgotten
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// Sessions a single host may start. Every host has an allowance of `burst` sessions, refilled
+/// at `sessions_per_minute`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RateLimitConfig {
+    pub(crate) sessions_per_minute: u32,
+    /// Sessions a host may start at once after being idle, `sessions_per_minute` if not set
+    #[serde(default)]
+    pub(crate) burst: Option<u32>,
+    /// Number of reverse proxies in front of the server, each appending the address it received
+    /// the request from to `X-Forwarded-For`. Zero ignores the header, as clients can forge it.
+    #[serde(default)]
+    pub(crate) forwarded_for_proxies: usize,
+}
+
+struct Allowance {
+    sessions: f64,
+    updated: Instant,
+}
+
+/// Token bucket of every host. IPv6 hosts are keyed by their /64 prefix, which a single host
+/// usually has to itself, so that they can't dodge the limit by switching addresses.
+pub(crate) struct RateLimiter {
+    config: RateLimitConfig,
+    allowances: Mutex<HashMap<IpAddr, Allowance>>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(config: RateLimitConfig) -> Self {
+        RateLimiter {
+            config,
+            allowances: Default::default(),
+        }
+    }
+
+    /// Address of the client, taken from `X-Forwarded-For` when server is behind proxies.
+    /// Falls back to the peer address when the header has fewer entries than there are proxies.
+    pub(crate) fn client_ip(
+        &self,
+        remote: Option<SocketAddr>,
+        forwarded_for: Option<&str>,
+    ) -> Option<IpAddr> {
+        let proxies = self.config.forwarded_for_proxies;
+        let forwarded = forwarded_for
+            .filter(|_| proxies > 0)
+            .and_then(|forwarded_for| {
+                let hops: Vec<&str> = forwarded_for.split(',').map(str::trim).collect();
+                // Each proxy appends its peer, so the client is the entry added by the first one
+                hops.len()
+                    .checked_sub(proxies)
+                    .and_then(|client| hops[client].parse().ok())
+            });
+        forwarded.or_else(|| remote.map(|remote| remote.ip()))
+    }
+
+    /// Takes a session from the allowance of `ip`. If none is left, returns time until the next
+    /// one, `None` if allowances are never refilled.
+    pub(crate) fn check(&self, ip: IpAddr, now: Instant) -> Result<(), Option<Duration>> {
+        let burst = f64::from(self.config.burst.unwrap_or(self.config.sessions_per_minute));
+        let per_second = f64::from(self.config.sessions_per_minute) / 60.0;
+        let refilled = |allowance: &Allowance| {
+            let elapsed = now.saturating_duration_since(allowance.updated);
+            (allowance.sessions + elapsed.as_secs_f64() * per_second).min(burst)
+        };
+
+        let mut allowances = self.allowances.lock().unwrap();
+        if allowances.len() > PRUNE_THRESHOLD {
+            allowances.retain(|_, allowance| refilled(allowance) < burst);
+        }
+        let allowance = allowances.entry(host(ip)).or_insert(Allowance {
+            sessions: burst,
+            updated: now,
+        });
+        allowance.sessions = refilled(allowance);
+        allowance.updated = now;
+        if allowance.sessions >= 1.0 {
+            allowance.sessions -= 1.0;
+            Ok(())
+        } else if per_second > 0.0 {
+            Err(Some(Duration::from_secs_f64(
+                (1.0 - allowance.sessions) / per_second,
+            )))
+        } else {
+            Err(None)
+        }
+    }
+}
+
+/// Key of the host of `ip`
+fn host(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ipv4) => IpAddr::V4(ipv4),
+            None => {
+                let segments = ip.segments();
+                IpAddr::V6(Ipv6Addr::new(
+                    segments[0],
+                    segments[1],
+                    segments[2],
+                    segments[3],
+                    0,
+                    0,
+                    0,
+                    0,
+                ))
+            }
+        },
+        ip => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rate_limit::{RateLimitConfig, RateLimiter};
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+
+    fn rate_limiter(forwarded_for_proxies: usize) -> RateLimiter {
+        RateLimiter::new(RateLimitConfig {
+            sessions_per_minute: 6,
+            burst: Some(2),
+            forwarded_for_proxies,
+        })
+    }
+
+    #[test]
+    fn test_check() {
+        let rate_limiter = rate_limiter(0);
+        let now = Instant::now();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        assert_eq!(rate_limiter.check(ip, now), Ok(()));
+        assert_eq!(rate_limiter.check(ip, now), Ok(()));
+        assert_eq!(
+            rate_limiter.check(ip, now),
+            Err(Some(Duration::from_secs(10)))
+        );
+        // Other hosts have their own allowance
+        assert_eq!(
+            rate_limiter.check("192.0.2.2".parse().unwrap(), now),
+            Ok(())
+        );
+
+        // One session is refilled every 10 seconds
+        let later = now + Duration::from_secs(15);
+        assert_eq!(rate_limiter.check(ip, later), Ok(()));
+        assert_eq!(
+            rate_limiter.check(ip, later),
+            Err(Some(Duration::from_secs(5)))
+        );
+
+        // Addresses of an IPv6 /64 are one host
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(rate_limiter.check(ip, now), Ok(()));
+        assert_eq!(
+            rate_limiter.check("2001:db8::2".parse().unwrap(), now),
+            Ok(())
+        );
+        assert!(rate_limiter
+            .check("2001:db8::3".parse().unwrap(), now)
+            .is_err());
+        assert_eq!(
+            rate_limiter.check("2001:db8:0:1::1".parse().unwrap(), now),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let remote = Some(([10, 0, 0, 1], 4000).into());
+        let forwarded_for = Some("198.51.100.7, 192.0.2.1, 10.0.0.2");
+
+        // Header is ignored unless server is behind proxies
+        assert_eq!(
+            rate_limiter(0).client_ip(remote, forwarded_for),
+            Some([10, 0, 0, 1].into())
+        );
+        // Entries before the one added by the first proxy may be forged
+        assert_eq!(
+            rate_limiter(2).client_ip(remote, forwarded_for),
+            Some([192, 0, 2, 1].into())
+        );
+        assert_eq!(
+            rate_limiter(1).client_ip(remote, Some("192.0.2.1")),
+            Some([192, 0, 2, 1].into())
+        );
+        assert_eq!(
+            rate_limiter(4).client_ip(remote, forwarded_for),
+            Some([10, 0, 0, 1].into())
+        );
+        assert_eq!(
+            rate_limiter(1).client_ip(remote, None),
+            Some([10, 0, 0, 1].into())
+        );
+        assert_eq!(rate_limiter(1).client_ip(None, None), None);
+    }
+}
@@ -0,0 +1,77 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::capacity::ActiveSessions;
+
+/// How often active sessions are counted while draining
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Set once server starts shutting down, new sessions are rejected from then on
+/// while sessions in progress are left to finish
+#[derive(Clone, Default)]
+pub(crate) struct Draining(Arc<AtomicBool>);
+
+impl Draining {
+    pub(crate) fn start(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub(crate) fn is_draining(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Resolves once the process receives SIGTERM or SIGINT
+pub(crate) async fn termination_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Unable to listen for SIGTERM");
+    let mut interrupt = signal(SignalKind::interrupt()).expect("Unable to listen for SIGINT");
+    tokio::select! {
+        _ = terminate.recv() => info!("Received SIGTERM"),
+        _ = interrupt.recv() => info!("Received SIGINT"),
+    }
+}
+
+/// Waits until no session is active or `deadline` passes, returns number of sessions still active
+pub(crate) async fn drain(active_sessions: &ActiveSessions, deadline: Duration) -> usize {
+    let started = Instant::now();
+    loop {
+        let active = active_sessions.count();
+        if active == 0 || started.elapsed() >= deadline {
+            return active;
+        }
+        info!("Waiting for {} sessions to finish", active);
+        tokio::time::delay_for(DRAIN_POLL_INTERVAL.min(deadline.saturating_sub(started.elapsed())))
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::capacity::ActiveSessions;
+    use crate::shutdown::{drain, Draining};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_drain() {
+        let draining = Draining::default();
+        assert!(!draining.is_draining());
+        draining.clone().start();
+        assert!(draining.is_draining());
+
+        let active_sessions = ActiveSessions::default();
+        assert_eq!(drain(&active_sessions, Duration::from_secs(60)).await, 0);
+
+        // Session outliving the deadline is left behind
+        let session = active_sessions.enter();
+        assert_eq!(drain(&active_sessions, Duration::from_millis(150)).await, 1);
+
+        // Session finishing within the deadline is waited for
+        tokio::spawn(async move {
+            tokio::time::delay_for(Duration::from_millis(150)).await;
+            drop(session);
+        });
+        assert_eq!(drain(&active_sessions, Duration::from_secs(60)).await, 0);
+    }
+}
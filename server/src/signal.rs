@@ -0,0 +1,148 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+use crate::capacity::CapacityReport;
+use crate::measurements::find_mean;
+use crate::types::ClientData;
+use crate::watchdog::MemoryPressure;
+
+/// What an autoscaler should do with the pool of measurement servers
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Recommendation {
+    /// Server can't take more sessions without degrading measurements
+    ScaleUp,
+    Hold,
+    /// Server had nothing to measure during the whole window
+    ScaleDown,
+}
+
+/// Compact summary of score trend and load of the server, for external control loops
+#[derive(Debug, Serialize)]
+pub(crate) struct Signal {
+    window_minutes: u64,
+    /// Runs started within the last window
+    runs: usize,
+    /// Mean score of runs started within the last window, `None` if there were none
+    mean_score: Option<u128>,
+    /// Mean score of runs started within the window before it
+    previous_mean_score: Option<u128>,
+    /// Change of mean score between the two windows, negative when scores are falling
+    score_change: Option<i128>,
+    active_sessions: usize,
+    verification_backlog: usize,
+    additional_sessions_per_minute: Option<u128>,
+    memory_pressure: MemoryPressure,
+    recommendation: Recommendation,
+}
+
+/// Mean score of completed runs started within `window` ending `until` before `now`
+fn window_mean_score(
+    storage: &HashMap<u128, ClientData>,
+    now: SystemTime,
+    until: Duration,
+    window: Duration,
+) -> (usize, Option<u128>) {
+    let scores = storage
+        .values()
+        .filter(|data| {
+            // Runs started "in the future" due to clock adjustment count as the most recent ones
+            let age = now.duration_since(data.started_at).unwrap_or_default();
+            data.is_completed()
+                && age
+                    .checked_sub(until)
+                    .is_some_and(|since_until| since_until < window)
+        })
+        .map(|data| data.score)
+        .collect::<Vec<_>>();
+    (scores.len(), find_mean(&scores))
+}
+
+pub(crate) fn signal(
+    storage: &HashMap<u128, ClientData>,
+    capacity: &CapacityReport,
+    memory_pressure: MemoryPressure,
+    now: SystemTime,
+    window: Duration,
+) -> Signal {
+    let (runs, mean_score) = window_mean_score(storage, now, Duration::from_secs(0), window);
+    let (_, previous_mean_score) = window_mean_score(storage, now, window, window);
+    let score_change = match (mean_score, previous_mean_score) {
+        (Some(mean_score), Some(previous_mean_score)) => {
+            Some(mean_score as i128 - previous_mean_score as i128)
+        }
+        _ => None,
+    };
+
+    let recommendation = if memory_pressure != MemoryPressure::Normal
+        || capacity.additional_sessions_per_minute == Some(0)
+        || capacity.verification_backlog > capacity.verification_threads
+    {
+        Recommendation::ScaleUp
+    } else if runs == 0 && capacity.active_sessions == 0 {
+        Recommendation::ScaleDown
+    } else {
+        Recommendation::Hold
+    };
+
+    Signal {
+        window_minutes: window.as_secs() / 60,
+        runs,
+        mean_score,
+        previous_mean_score,
+        score_change,
+        active_sessions: capacity.active_sessions,
+        verification_backlog: capacity.verification_backlog,
+        additional_sessions_per_minute: capacity.additional_sessions_per_minute,
+        memory_pressure,
+        recommendation,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::capacity::{capacity_report, ActiveSessions};
+    use crate::signal::{signal, Recommendation};
+    use crate::types::test_utils::client_data;
+    use crate::verification::VerificationPool;
+    use crate::watchdog::MemoryPressure;
+    use std::collections::HashMap;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_signal() {
+        let pool = VerificationPool::new(Some(1)).unwrap();
+        let active_sessions = ActiveSessions::default();
+        let now = SystemTime::now();
+        let window = Duration::from_secs(15 * 60);
+
+        let storage = HashMap::new();
+        let capacity = capacity_report(&storage, &pool, &active_sessions, None, now);
+        let idle = signal(&storage, &capacity, MemoryPressure::Normal, now, window);
+        assert_eq!(idle.recommendation, Recommendation::ScaleDown);
+        assert_eq!(idle.mean_score, None);
+
+        let mut storage = HashMap::new();
+        storage.insert(1, client_data(60));
+        storage.insert(2, client_data(70));
+        let mut previous = client_data(80);
+        previous.started_at = now - Duration::from_secs(20 * 60);
+        storage.insert(3, previous);
+        let mut old = client_data(10);
+        old.started_at = now - Duration::from_secs(60 * 60);
+        storage.insert(4, old);
+
+        let capacity = capacity_report(&storage, &pool, &active_sessions, None, now);
+        let busy = signal(&storage, &capacity, MemoryPressure::Normal, now, window);
+        assert_eq!(busy.window_minutes, 15);
+        assert_eq!(busy.runs, 2);
+        assert_eq!(busy.mean_score, Some(65));
+        assert_eq!(busy.previous_mean_score, Some(80));
+        assert_eq!(busy.score_change, Some(-15));
+        assert_eq!(busy.recommendation, Recommendation::Hold);
+
+        let pressured = signal(&storage, &capacity, MemoryPressure::Soft, now, window);
+        assert_eq!(pressured.recommendation, Recommendation::ScaleUp);
+    }
+}
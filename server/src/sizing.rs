@@ -0,0 +1,155 @@
+//! Rates at which the host generates and hashes payloads of network challenges, measured on
+//! startup, and the largest payload and number of concurrent transfers the host can serve at them
+//! without its own work distorting measurements.
+
+use rand::rngs::OsRng;
+use serde::Deserialize;
+use shared::challenges::roundtrip::RoundtripSession;
+use shared::hash::HashAlgorithm;
+use std::cmp;
+use std::time::Instant;
+
+use crate::config::PlanConfig;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct HostSizingConfig {
+    /// Measures the host on startup and caps payloads and concurrent transfers to what it sustains
+    pub(crate) enabled: bool,
+    /// Random data generated and hashed by the benchmark
+    pub(crate) sample_kb: usize,
+    /// Longest time server may spend on the payload of a single round: generating it, hashing it
+    /// and hashing the client's response
+    pub(crate) max_round_work_milliseconds: u64,
+    /// Share of the server's threads transfers in flight may keep busy during ideal rounds
+    pub(crate) max_utilization: f64,
+}
+
+impl Default for HostSizingConfig {
+    fn default() -> Self {
+        HostSizingConfig {
+            enabled: true,
+            sample_kb: 4096,
+            max_round_work_milliseconds: 250,
+            max_utilization: 0.5,
+        }
+    }
+}
+
+/// Rates of the host in megabytes per second
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct HostBenchmark {
+    pub(crate) generation_mb_per_second: f64,
+    /// Rate of the slowest algorithm offered to clients
+    pub(crate) hashing_mb_per_second: f64,
+}
+
+impl HostBenchmark {
+    /// Generates and hashes `sample_kb` of random data with each of `hash_algorithms`
+    pub(crate) fn measure(sample_kb: usize, hash_algorithms: &[HashAlgorithm]) -> Self {
+        let sample_kb = cmp::max(sample_kb, 1);
+        let started = Instant::now();
+        let session = RoundtripSession::new(&mut OsRng, sample_kb);
+        let generation_mb_per_second = mb_per_second(sample_kb, started);
+        let (payload, _) = session.generate(&mut OsRng, HashAlgorithm::XxHash64, &[]);
+        let payload = payload.to_wire();
+        let hashing_mb_per_second = hash_algorithms
+            .iter()
+            .map(|algorithm| {
+                let started = Instant::now();
+                algorithm.digest(&payload);
+                mb_per_second(sample_kb, started)
+            })
+            .fold(f64::INFINITY, f64::min);
+        HostBenchmark {
+            generation_mb_per_second,
+            hashing_mb_per_second,
+        }
+    }
+
+    /// Time server spends on a round of `payload_size_kb`: payload is generated and hashed when
+    /// the round starts and the response is hashed once it arrives
+    fn round_work_microseconds(&self, payload_size_kb: usize) -> f64 {
+        let microseconds_per_kb = |mb_per_second: f64| 1_000_000.0 / (mb_per_second * 1024.0);
+        payload_size_kb as f64
+            * (microseconds_per_kb(self.generation_mb_per_second)
+                + 2.0 * microseconds_per_kb(self.hashing_mb_per_second))
+    }
+}
+
+fn mb_per_second(kilobytes: usize, started: Instant) -> f64 {
+    // A clock too coarse to time the sample shouldn't make the rate infinite
+    let seconds = started.elapsed().as_secs_f64().max(1e-6);
+    kilobytes as f64 / 1024.0 / seconds
+}
+
+/// What the host can serve at the rates of its benchmark
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct HostLimits {
+    pub(crate) max_payload_size_kb: usize,
+    /// Bulk transfers (network, download and upload rounds) in flight across all sessions
+    pub(crate) max_concurrent_transfers: usize,
+}
+
+impl HostLimits {
+    /// Limits for rounds of `plan` on a server with `threads` threads
+    pub(crate) fn new(
+        benchmark: &HostBenchmark,
+        config: &HostSizingConfig,
+        plan: &PlanConfig,
+        threads: usize,
+    ) -> Self {
+        let max_round_work_microseconds = config.max_round_work_milliseconds as f64 * 1000.0;
+        let max_payload_size_kb =
+            (max_round_work_microseconds / benchmark.round_work_microseconds(1)) as usize;
+        let max_payload_size_kb = cmp::max(max_payload_size_kb, 1);
+        let payload_size_kb = cmp::min(plan.payload_size_kb, max_payload_size_kb);
+        let (ideal_milliseconds, _) = plan.network_thresholds(payload_size_kb);
+        let busy_microseconds =
+            config.max_utilization * threads as f64 * ideal_milliseconds as f64 * 1000.0;
+        let max_concurrent_transfers =
+            (busy_microseconds / benchmark.round_work_microseconds(payload_size_kb)) as usize;
+        HostLimits {
+            max_payload_size_kb,
+            max_concurrent_transfers: cmp::max(max_concurrent_transfers, 1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::PlanConfig;
+    use crate::sizing::{HostBenchmark, HostLimits};
+    use shared::hash::HashAlgorithm;
+
+    #[test]
+    fn test_host_limits() {
+        let measured = HostBenchmark::measure(256, &[HashAlgorithm::Blake3, HashAlgorithm::Sha256]);
+        assert!(measured.generation_mb_per_second > 0.0);
+        assert!(measured.hashing_mb_per_second.is_finite());
+
+        // A round of 1 MB takes 10 ms to generate and 2 * 5 ms to hash
+        let benchmark = HostBenchmark {
+            generation_mb_per_second: 100.0,
+            hashing_mb_per_second: 200.0,
+        };
+        let plan = PlanConfig {
+            payload_size_kb: 1024,
+            network_ideal_milliseconds: 200,
+            ..Default::default()
+        };
+        let limits = HostLimits::new(&benchmark, &Default::default(), &plan, 4);
+        // 250 ms of work
+        assert_eq!(limits.max_payload_size_kb, 12800);
+        // Half of 4 threads over 200 ms
+        assert_eq!(limits.max_concurrent_transfers, 20);
+
+        let slow = HostBenchmark {
+            generation_mb_per_second: 1.0,
+            hashing_mb_per_second: 2.0,
+        };
+        let limits = HostLimits::new(&slow, &Default::default(), &plan, 1);
+        assert_eq!(limits.max_payload_size_kb, 128);
+        assert_eq!(limits.max_concurrent_transfers, 1);
+    }
+}
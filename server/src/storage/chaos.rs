@@ -0,0 +1,176 @@
+//! Storage wrapper injecting latency and transient failures into every call of the backend it
+//! wraps, to check how the server copes with a slow or flaky backend before adopting it.
+//! Meant for development and staging only.
+
+use crate::types::{ClientData, Storage, Visibility};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct StorageChaosConfig {
+    /// Delay added to every call, picked uniformly up to this many milliseconds
+    pub(crate) max_latency_milliseconds: u64,
+    /// Share of calls failing instead of reaching the backend, 0 to 100
+    pub(crate) failure_percent: f64,
+    /// Seed of the random source, so that failures can be reproduced. `None` seeds it randomly.
+    pub(crate) seed: Option<u64>,
+}
+
+pub(crate) struct ChaosStorage {
+    inner: Arc<dyn Storage>,
+    config: StorageChaosConfig,
+    rng: Mutex<StdRng>,
+}
+
+impl ChaosStorage {
+    pub(crate) fn new(inner: Arc<dyn Storage>, config: StorageChaosConfig) -> Result<Self> {
+        if !(0.0..=100.0).contains(&config.failure_percent) {
+            return Err(anyhow!(
+                "Failure percent {} is not between 0 and 100",
+                config.failure_percent
+            ));
+        }
+        let rng = match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Ok(ChaosStorage {
+            inner,
+            config,
+            rng: Mutex::new(rng),
+        })
+    }
+
+    /// Waits for the injected latency, then fails the call named `operation` if it was picked to
+    async fn disrupt(&self, operation: &str) -> Result<()> {
+        let (latency_milliseconds, fails) = {
+            let mut rng = self.rng.lock().unwrap();
+            (
+                rng.gen_range(0, self.config.max_latency_milliseconds + 1),
+                rng.gen::<f64>() * 100.0 < self.config.failure_percent,
+            )
+        };
+        if latency_milliseconds > 0 {
+            tokio::time::delay_for(Duration::from_millis(latency_milliseconds)).await;
+        }
+        if fails {
+            return Err(anyhow!("Injected failure of storage {}", operation));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for ChaosStorage {
+    async fn insert(&self, client_id: u128, data: ClientData) -> Result<()> {
+        self.disrupt("insert").await?;
+        self.inner.insert(client_id, data).await
+    }
+
+    async fn get(&self, client_id: u128, visibility: Visibility) -> Result<Option<ClientData>> {
+        self.disrupt("get").await?;
+        self.inner.get(client_id, visibility).await
+    }
+
+    async fn runs(&self, visibility: Visibility) -> Result<HashMap<u128, ClientData>> {
+        self.disrupt("runs").await?;
+        self.inner.runs(visibility).await
+    }
+
+    async fn history(
+        &self,
+        identity: u128,
+        visibility: Visibility,
+    ) -> Result<Vec<(u128, ClientData)>> {
+        self.disrupt("history").await?;
+        self.inner.history(identity, visibility).await
+    }
+
+    async fn contains(&self, client_id: u128) -> Result<bool> {
+        self.disrupt("contains").await?;
+        self.inner.contains(client_id).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::chaos::{ChaosStorage, StorageChaosConfig};
+    use crate::storage::MemoryStorage;
+    use crate::types::test_utils::client_data;
+    use crate::types::{Storage, Visibility};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn test_chaos_storage() {
+        let inner = Arc::new(MemoryStorage::default());
+        let failing = ChaosStorage::new(
+            inner.clone(),
+            StorageChaosConfig {
+                failure_percent: 100.0,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(failing.insert(1, client_data(50)).await.is_err());
+        assert!(failing.get(1, Visibility::All).await.is_err());
+        assert!(!inner.contains(1).await.unwrap());
+
+        let slow = ChaosStorage::new(
+            inner.clone(),
+            StorageChaosConfig {
+                max_latency_milliseconds: 20,
+                seed: Some(7),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let started = Instant::now();
+        for client_id in 0..10 {
+            slow.insert(client_id, client_data(50)).await.unwrap();
+        }
+        assert!(started.elapsed() > Duration::from_millis(20));
+        assert_eq!(inner.runs(Visibility::All).await.unwrap().len(), 10);
+
+        // Same seed fails the same calls
+        let outcomes = |seed| async move {
+            let flaky = ChaosStorage::new(
+                Arc::new(MemoryStorage::default()),
+                StorageChaosConfig {
+                    failure_percent: 50.0,
+                    seed: Some(seed),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            let mut outcomes = Vec::new();
+            for client_id in 0..20 {
+                outcomes.push(flaky.insert(client_id, client_data(50)).await.is_ok());
+            }
+            outcomes
+        };
+        let first = outcomes(3).await;
+        assert_eq!(first, outcomes(3).await);
+        assert!(first.contains(&true) && first.contains(&false));
+
+        assert!(ChaosStorage::new(
+            inner,
+            StorageChaosConfig {
+                failure_percent: 150.0,
+                ..Default::default()
+            }
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,141 @@
+use crate::certificate::from_hex;
+use anyhow::{anyhow, Result};
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::Deserialize;
+use std::convert::TryInto;
+use std::path::PathBuf;
+
+const NONCE_SIZE: usize = 24;
+
+/// Key stored runs are encrypted with, given either inline or as a file,
+/// e.g. one a KMS agent or secret manager writes the decrypted key to
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct EncryptionConfig {
+    /// Hex encoded 32 byte key
+    #[serde(default)]
+    pub(crate) key: Option<String>,
+    /// File holding hex encoded 32 byte key, surrounding whitespace is ignored
+    #[serde(default)]
+    pub(crate) key_file: Option<PathBuf>,
+}
+
+/// Seals runs with XChaCha20-Poly1305 under a random nonce. Id of the run is authenticated
+/// along with it, so that sealed data can't be moved to another run unnoticed.
+pub(crate) struct RunCipher {
+    cipher: XChaCha20Poly1305,
+}
+
+impl RunCipher {
+    pub(crate) fn new(config: &EncryptionConfig) -> Result<Self> {
+        let key = match (&config.key, &config.key_file) {
+            (Some(key), None) => key.clone(),
+            (None, Some(path)) => std::fs::read_to_string(path)
+                .map_err(|e| anyhow!("Unable to read {}: {}", path.display(), e))?,
+            _ => return Err(anyhow!("Exactly one of key and key_file must be set")),
+        };
+        let key = from_hex(key.trim())?;
+        let key: [u8; 32] = key
+            .as_slice()
+            .try_into()
+            .map_err(|_| anyhow!("Storage key must be 32 bytes, got {}", key.len()))?;
+        Ok(RunCipher {
+            cipher: XChaCha20Poly1305::new(&Key::from(key)),
+        })
+    }
+
+    /// Nonce followed by ciphertext of `plaintext`
+    pub(crate) fn seal(&self, run_id: &str, plaintext: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &XNonce::from(nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: run_id.as_bytes(),
+                },
+            )
+            .expect("encryption of a run doesn't fail");
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        sealed
+    }
+
+    pub(crate) fn open(&self, run_id: &str, sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < NONCE_SIZE {
+            return Err(anyhow!("Sealed run {} is truncated", run_id));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+        let nonce: [u8; NONCE_SIZE] = nonce.try_into().expect("nonce is split at its size");
+        self.cipher
+            .decrypt(
+                &XNonce::from(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: run_id.as_bytes(),
+                },
+            )
+            .map_err(|_| {
+                anyhow!(
+                    "Unable to decrypt run {}, wrong key or corrupted data",
+                    run_id
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::encryption::{EncryptionConfig, RunCipher};
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    fn cipher(key: &str) -> RunCipher {
+        RunCipher::new(&EncryptionConfig {
+            key: Some(key.to_owned()),
+            key_file: None,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_run_cipher() {
+        let cipher = cipher(KEY);
+        let sealed = cipher.seal("abc", b"run");
+        assert_ne!(sealed, cipher.seal("abc", b"run"));
+        assert_eq!(cipher.open("abc", &sealed).unwrap(), b"run");
+        assert!(cipher.open("abd", &sealed).is_err());
+        assert!(cipher.open("abc", &sealed[..10]).is_err());
+        assert!(self::cipher(&KEY.replace("00", "ff"))
+            .open("abc", &sealed)
+            .is_err());
+
+        let path = std::env::temp_dir().join(format!("storage-key-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&path, format!("{}\n", KEY)).unwrap();
+        let from_file = RunCipher::new(&EncryptionConfig {
+            key: None,
+            key_file: Some(path.clone()),
+        })
+        .unwrap();
+        assert_eq!(from_file.open("abc", &sealed).unwrap(), b"run");
+        std::fs::remove_file(path).unwrap();
+
+        for (key, key_file) in &[(None, None), (Some(KEY), Some("key"))] {
+            assert!(RunCipher::new(&EncryptionConfig {
+                key: key.map(str::to_owned),
+                key_file: key_file.map(Into::into),
+            })
+            .is_err());
+        }
+        assert!(RunCipher::new(&EncryptionConfig {
+            key: Some("abcd".to_owned()),
+            key_file: None,
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,250 @@
+mod chaos;
+mod encryption;
+mod pending;
+mod sqlite;
+mod tenant;
+
+use crate::types::{ClientData, Storage, Visibility};
+use anyhow::Result;
+use async_trait::async_trait;
+pub(crate) use chaos::{ChaosStorage, StorageChaosConfig};
+pub(crate) use encryption::EncryptionConfig;
+pub(crate) use pending::PendingRuns;
+use serde::Deserialize;
+use sqlite::SqliteStorage;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+pub(crate) use tenant::{api_key_digest, tenant_serves, TenantScope, TenantStorage};
+use tokio::sync::RwLock;
+
+/// Backend runs are stored in
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(tag = "backend", rename_all = "snake_case", deny_unknown_fields)]
+pub(crate) enum StorageConfig {
+    /// Runs are kept in memory and lost when server stops
+    #[default]
+    Memory,
+    /// Runs are kept in an SQLite database, created on first start
+    Sqlite {
+        path: PathBuf,
+        /// Runs are encrypted before they are written when set
+        #[serde(default)]
+        encryption: Option<EncryptionConfig>,
+    },
+}
+
+pub(crate) fn open(config: &StorageConfig) -> Result<Arc<dyn Storage>> {
+    Ok(match config {
+        StorageConfig::Memory => Arc::new(MemoryStorage::default()),
+        StorageConfig::Sqlite { path, encryption } => {
+            Arc::new(SqliteStorage::open(path, encryption.as_ref())?)
+        }
+    })
+}
+
+#[derive(Default)]
+pub(crate) struct MemoryStorage {
+    runs: RwLock<HashMap<u128, ClientData>>,
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn insert(&self, client_id: u128, data: ClientData) -> Result<()> {
+        self.runs.write().await.insert(client_id, data);
+        Ok(())
+    }
+
+    async fn get(&self, client_id: u128, visibility: Visibility) -> Result<Option<ClientData>> {
+        Ok(self
+            .runs
+            .read()
+            .await
+            .get(&client_id)
+            .filter(|data| visibility.allows(data))
+            .cloned())
+    }
+
+    async fn runs(&self, visibility: Visibility) -> Result<HashMap<u128, ClientData>> {
+        Ok(self
+            .runs
+            .read()
+            .await
+            .iter()
+            .filter(|(_, data)| visibility.allows(data))
+            .map(|(client_id, data)| (*client_id, data.clone()))
+            .collect())
+    }
+
+    async fn history(
+        &self,
+        identity: u128,
+        visibility: Visibility,
+    ) -> Result<Vec<(u128, ClientData)>> {
+        let mut history: Vec<_> = self
+            .runs
+            .read()
+            .await
+            .iter()
+            .filter(|(_, data)| data.identity == Some(identity) && visibility.allows(data))
+            .map(|(client_id, data)| (*client_id, data.clone()))
+            .collect();
+        history.sort_by_key(|(_, data)| data.started_at);
+        Ok(history)
+    }
+
+    async fn contains(&self, client_id: u128) -> Result<bool> {
+        Ok(self.runs.read().await.contains_key(&client_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::ServerConfig;
+    use crate::storage::{open, EncryptionConfig, StorageConfig};
+    use crate::types::test_utils::client_data;
+    use crate::types::{SessionClock, SessionEvent, SessionEventKind, Visibility};
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_storage_config() {
+        let config = ServerConfig::from_toml(
+            r#"
+            [storage]
+            backend = "sqlite"
+            path = "/var/lib/reliability/runs.db"
+
+            [storage.encryption]
+            key_file = "/run/secrets/storage-key"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.storage,
+            StorageConfig::Sqlite {
+                path: PathBuf::from("/var/lib/reliability/runs.db"),
+                encryption: Some(EncryptionConfig {
+                    key: None,
+                    key_file: Some(PathBuf::from("/run/secrets/storage-key")),
+                }),
+            }
+        );
+        assert_eq!(
+            ServerConfig::from_toml("").unwrap().storage,
+            StorageConfig::Memory
+        );
+    }
+
+    #[tokio::test]
+    async fn test_storage_backends() {
+        let path = std::env::temp_dir().join(format!("runs-{}.db", Uuid::new_v4()));
+        let encrypted_path = std::env::temp_dir().join(format!("runs-{}.db", Uuid::new_v4()));
+        let encryption = EncryptionConfig {
+            key: Some(
+                "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f".to_owned(),
+            ),
+            key_file: None,
+        };
+        for config in &[
+            StorageConfig::Memory,
+            StorageConfig::Sqlite {
+                path: path.clone(),
+                encryption: None,
+            },
+            StorageConfig::Sqlite {
+                path: encrypted_path.clone(),
+                encryption: Some(encryption.clone()),
+            },
+        ] {
+            let storage = open(config).unwrap();
+            let mut data = client_data(90);
+            data.events.push(SessionEvent {
+                timestamp: SessionClock::start().now(),
+                kind: SessionEventKind::ScoreCalculated { score: 90 },
+            });
+            data.identity = Some(7);
+            let started_at = data.started_at;
+            storage.insert(0xabc, data).await.unwrap();
+            let mut private = client_data(80);
+            private.private = true;
+            private.identity = Some(7);
+            private.started_at = started_at - Duration::from_secs(1);
+            storage.insert(0xabd, private).await.unwrap();
+
+            let stored = storage
+                .get(0xabc, Visibility::Public)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(stored.score, 90);
+            assert_eq!(stored.configuration, client_data(90).configuration);
+            assert!(matches!(
+                stored.events[0].kind,
+                SessionEventKind::ScoreCalculated { score: 90 }
+            ));
+            assert!(storage.get(0xabe, Visibility::All).await.unwrap().is_none());
+            assert!(storage.contains(0xabd).await.unwrap());
+            assert!(storage
+                .get(0xabd, Visibility::Public)
+                .await
+                .unwrap()
+                .is_none());
+            assert_eq!(storage.runs(Visibility::All).await.unwrap().len(), 2);
+            assert_eq!(storage.runs(Visibility::Public).await.unwrap().len(), 1);
+
+            let history = storage.history(7, Visibility::All).await.unwrap();
+            assert_eq!(
+                history.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+                vec![0xabd, 0xabc]
+            );
+            assert_eq!(
+                storage.history(7, Visibility::Public).await.unwrap().len(),
+                1
+            );
+            assert!(storage
+                .history(8, Visibility::All)
+                .await
+                .unwrap()
+                .is_empty());
+        }
+
+        // Runs survive reopening the database, migrations are not applied twice
+        let storage = open(&StorageConfig::Sqlite {
+            path: path.clone(),
+            encryption: None,
+        })
+        .unwrap();
+        assert_eq!(
+            storage.runs(Visibility::All).await.unwrap()[&0xabd].score,
+            80
+        );
+
+        // Runs stored before encryption was enabled stay readable next to encrypted ones
+        let storage = open(&StorageConfig::Sqlite {
+            path: path.clone(),
+            encryption: Some(encryption),
+        })
+        .unwrap();
+        storage.insert(0xabe, client_data(70)).await.unwrap();
+        let runs = storage.runs(Visibility::All).await.unwrap();
+        assert_eq!((runs[&0xabc].score, runs[&0xabe].score), (90, 70));
+
+        // Encrypted runs can't be read without the key and don't leak their contents
+        let storage = open(&StorageConfig::Sqlite {
+            path: encrypted_path.clone(),
+            encryption: None,
+        })
+        .unwrap();
+        assert!(storage.get(0xabc, Visibility::All).await.is_err());
+        assert!(storage.runs(Visibility::All).await.is_err());
+        let contents = std::fs::read(&encrypted_path).unwrap();
+        assert!(!contents
+            .windows(b"plan_name".len())
+            .any(|window| window == b"plan_name"));
+
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(encrypted_path).unwrap();
+    }
+}
@@ -0,0 +1,95 @@
+//! Runs measured while storage was failing, kept in memory and retried in the background so
+//! that a storage outage doesn't lose the results of sessions which completed during it.
+
+use crate::tasks::{TaskKind, TaskRegistry};
+use crate::types::{ClientData, Storage};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How often runs waiting for storage are retried
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Runs which couldn't be stored yet, keyed by client id
+#[derive(Clone, Default)]
+pub(crate) struct PendingRuns(Arc<Mutex<HashMap<u128, ClientData>>>);
+
+impl PendingRuns {
+    pub(crate) fn add(&self, client_id: u128, data: ClientData) {
+        self.0.lock().unwrap().insert(client_id, data);
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    /// Tries to store every pending run once, runs storage still refuses stay pending.
+    /// Returns number of runs stored.
+    pub(crate) async fn persist(&self, storage: &dyn Storage) -> usize {
+        let pending: Vec<(u128, ClientData)> = self
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(client_id, data)| (*client_id, data.clone()))
+            .collect();
+        let mut stored = 0;
+        for (client_id, data) in pending {
+            match storage.insert(client_id, data).await {
+                Ok(()) => {
+                    self.0.lock().unwrap().remove(&client_id);
+                    stored += 1;
+                    info!("Stored pending run {:x}", client_id);
+                }
+                Err(e) => warn!("Run {:x} is still pending: {:?}", client_id, e),
+            }
+        }
+        stored
+    }
+
+    pub(crate) fn spawn(&self, tasks: &TaskRegistry, storage: Arc<dyn Storage>) {
+        let pending = self.clone();
+        tasks.spawn(TaskKind::Background, "pending runs", async move {
+            let mut interval = tokio::time::interval(RETRY_INTERVAL);
+            loop {
+                interval.tick().await;
+                if pending.len() > 0 {
+                    pending.persist(&*storage).await;
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::chaos::{ChaosStorage, StorageChaosConfig};
+    use crate::storage::pending::PendingRuns;
+    use crate::storage::MemoryStorage;
+    use crate::types::test_utils::client_data;
+    use crate::types::Storage;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_pending_runs() {
+        let pending = PendingRuns::default();
+        pending.add(1, client_data(50));
+        pending.add(2, client_data(60));
+
+        let failing = ChaosStorage::new(
+            Arc::new(MemoryStorage::default()),
+            StorageChaosConfig {
+                failure_percent: 100.0,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(pending.persist(&failing).await, 0);
+        assert_eq!(pending.len(), 2);
+
+        let storage = MemoryStorage::default();
+        assert_eq!(pending.persist(&storage).await, 2);
+        assert_eq!(pending.len(), 0);
+        assert!(storage.contains(1).await.unwrap());
+    }
+}
@@ -0,0 +1,172 @@
+use crate::api::unix_milliseconds;
+use crate::storage::encryption::{EncryptionConfig, RunCipher};
+use crate::types::{ClientData, Storage, Visibility};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use rusqlite::types::Value;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Schema migrations in the order they are applied, `user_version` of the database
+/// records how many of them were. Never edit a released migration, append a new one.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE runs (
+        id TEXT PRIMARY KEY NOT NULL,
+        started_at_unix_milliseconds INTEGER NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX runs_started_at ON runs (started_at_unix_milliseconds);",
+    "ALTER TABLE runs ADD COLUMN private INTEGER NOT NULL DEFAULT 0;",
+    "ALTER TABLE runs ADD COLUMN identity TEXT;
+    CREATE INDEX runs_identity ON runs (identity, started_at_unix_milliseconds);",
+];
+
+/// Runs stored in an SQLite database, each as JSON of its `ClientData`, or as a blob of the
+/// JSON sealed by `RunCipher` when encryption is configured. Rows stored before encryption was
+/// enabled stay readable. SQLite calls block, so they are made on the blocking thread pool.
+pub(crate) struct SqliteStorage {
+    connection: Arc<Mutex<Connection>>,
+    cipher: Option<Arc<RunCipher>>,
+}
+
+impl SqliteStorage {
+    pub(crate) fn open(path: &Path, encryption: Option<&EncryptionConfig>) -> Result<Self> {
+        let cipher = encryption.map(RunCipher::new).transpose()?.map(Arc::new);
+        let mut connection = Connection::open(path)
+            .map_err(|e| anyhow!("Unable to open {}: {:?}", path.display(), e))?;
+        migrate(&mut connection)?;
+        Ok(SqliteStorage {
+            connection: Arc::new(Mutex::new(connection)),
+            cipher,
+        })
+    }
+
+    async fn with_connection<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || f(&connection.lock().unwrap())).await?
+    }
+}
+
+fn migrate(connection: &mut Connection) -> Result<()> {
+    let transaction = connection.transaction()?;
+    let applied: usize =
+        transaction.pragma_query_value(None, "user_version", |row| row.get::<_, i64>(0))? as usize;
+    for migration in MIGRATIONS.iter().skip(applied) {
+        transaction.execute_batch(migration)?;
+    }
+    transaction.pragma_update(None, "user_version", &(MIGRATIONS.len() as i64))?;
+    transaction.commit()?;
+    Ok(())
+}
+
+fn decode(cipher: Option<&RunCipher>, id: &str, data: Value) -> Result<ClientData> {
+    match data {
+        Value::Text(data) => Ok(serde_json::from_str(&data)?),
+        Value::Blob(sealed) => {
+            let cipher = cipher
+                .ok_or_else(|| anyhow!("Run {} is encrypted, but no key is configured", id))?;
+            Ok(serde_json::from_slice(&cipher.open(id, &sealed)?)?)
+        }
+        _ => Err(anyhow!("Run {} has no data", id)),
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn insert(&self, client_id: u128, data: ClientData) -> Result<()> {
+        let started_at = unix_milliseconds(data.started_at) as i64;
+        let private = data.private;
+        let identity = data.identity.map(|identity| format!("{:x}", identity));
+        let id = format!("{:x}", client_id);
+        let data = serde_json::to_string(&data)?;
+        let data = match &self.cipher {
+            Some(cipher) => Value::Blob(cipher.seal(&id, data.as_bytes())),
+            None => Value::Text(data),
+        };
+        self.with_connection(move |connection| {
+            connection.execute(
+                "INSERT OR REPLACE INTO runs \
+                 (id, started_at_unix_milliseconds, private, identity, data) \
+                 VALUES (?, ?, ?, ?, ?)",
+                params![id, started_at, private, identity, data],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get(&self, client_id: u128, visibility: Visibility) -> Result<Option<ClientData>> {
+        let cipher = self.cipher.clone();
+        self.with_connection(move |connection| {
+            let id = format!("{:x}", client_id);
+            let data: Option<Value> = connection
+                .query_row(
+                    "SELECT data FROM runs WHERE id = ? AND (private = 0 OR ?)",
+                    params![id, visibility == Visibility::All],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            data.map(|data| decode(cipher.as_deref(), &id, data))
+                .transpose()
+        })
+        .await
+    }
+
+    async fn runs(&self, visibility: Visibility) -> Result<HashMap<u128, ClientData>> {
+        let cipher = self.cipher.clone();
+        self.with_connection(move |connection| {
+            let mut statement =
+                connection.prepare("SELECT id, data FROM runs WHERE private = 0 OR ?")?;
+            let rows = statement.query_map(params![visibility == Visibility::All], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?;
+            rows.map(|row| {
+                let (id, data): (String, Value) = row?;
+                Ok((
+                    u128::from_str_radix(&id, 16)?,
+                    decode(cipher.as_deref(), &id, data)?,
+                ))
+            })
+            .collect()
+        })
+        .await
+    }
+
+    async fn history(
+        &self,
+        identity: u128,
+        visibility: Visibility,
+    ) -> Result<Vec<(u128, ClientData)>> {
+        let cipher = self.cipher.clone();
+        self.with_connection(move |connection| {
+            let mut statement = connection.prepare(
+                "SELECT id, data FROM runs WHERE identity = ? AND (private = 0 OR ?) \
+                 ORDER BY started_at_unix_milliseconds",
+            )?;
+            let rows = statement.query_map(
+                params![format!("{:x}", identity), visibility == Visibility::All],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?;
+            rows.map(|row| {
+                let (id, data): (String, Value) = row?;
+                Ok((
+                    u128::from_str_radix(&id, 16)?,
+                    decode(cipher.as_deref(), &id, data)?,
+                ))
+            })
+            .collect()
+        })
+        .await
+    }
+
+    /// Every insert is committed on its own, so once the connection is free nothing is pending
+    async fn flush(&self) -> Result<()> {
+        self.with_connection(|_| Ok(())).await
+    }
+}
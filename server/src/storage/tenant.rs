@@ -0,0 +1,185 @@
+//! Storage wrapper confining requests to the namespace of a tenant, so that teams sharing the
+//! server only see runs of their own clients. See `TenantConfig`.
+
+use crate::certificate::to_hex;
+use crate::types::{ClientData, Storage, Visibility};
+use anyhow::Result;
+use async_trait::async_trait;
+use shared::hash::HashAlgorithm;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Identifies the API key a run was measured with, without storing the key itself
+pub(crate) fn api_key_digest(api_key: &str) -> String {
+    to_hex(&HashAlgorithm::Sha256.digest(api_key.as_bytes()))
+}
+
+/// Namespace of a tenant a request is confined to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct TenantScope {
+    pub(crate) name: String,
+    /// Digest of the client API key of the request, whose own private runs are served. `None`
+    /// for reader keys of the tenant, which are served private runs of all its clients.
+    pub(crate) api_key_digest: Option<String>,
+}
+
+/// Whether a run or session of `tenant`, measured with the API key of `api_key_digest`, is
+/// served to a request confined to `scope`. Clients of a tenant see their own private runs and
+/// public runs of the tenant, runs of no tenant stay as visible as asked.
+pub(crate) fn tenant_serves(
+    scope: Option<&TenantScope>,
+    visibility: Visibility,
+    tenant: Option<&str>,
+    private: bool,
+    api_key_digest: Option<&str>,
+) -> bool {
+    match scope {
+        Some(scope) => {
+            tenant == Some(scope.name.as_str())
+                && (!private
+                    || scope.api_key_digest.is_none()
+                    || scope.api_key_digest.as_deref() == api_key_digest)
+        }
+        None => tenant.is_none() && (visibility == Visibility::All || !private),
+    }
+}
+
+pub(crate) struct TenantStorage {
+    inner: Arc<dyn Storage>,
+    /// Tenant whose runs are served, `None` serves runs of clients of no tenant
+    scope: Option<TenantScope>,
+}
+
+impl TenantStorage {
+    pub(crate) fn new(inner: Arc<dyn Storage>, scope: Option<TenantScope>) -> Self {
+        TenantStorage { inner, scope }
+    }
+
+    fn serves(&self, data: &ClientData, visibility: Visibility) -> bool {
+        tenant_serves(
+            self.scope.as_ref(),
+            visibility,
+            data.tenant.as_deref(),
+            data.private,
+            data.api_key_digest.as_deref(),
+        )
+    }
+
+    /// Private runs of a tenant are fetched and then filtered by `serves`, runs of no tenant stay
+    /// as visible as asked
+    fn visibility(&self, visibility: Visibility) -> Visibility {
+        match self.scope {
+            Some(_) => Visibility::All,
+            None => visibility,
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for TenantStorage {
+    async fn insert(&self, client_id: u128, mut data: ClientData) -> Result<()> {
+        data.tenant = self.scope.as_ref().map(|scope| scope.name.clone());
+        self.inner.insert(client_id, data).await
+    }
+
+    async fn get(&self, client_id: u128, visibility: Visibility) -> Result<Option<ClientData>> {
+        Ok(self
+            .inner
+            .get(client_id, self.visibility(visibility))
+            .await?
+            .filter(|data| self.serves(data, visibility)))
+    }
+
+    async fn runs(&self, visibility: Visibility) -> Result<HashMap<u128, ClientData>> {
+        let mut runs = self.inner.runs(self.visibility(visibility)).await?;
+        runs.retain(|_, data| self.serves(data, visibility));
+        Ok(runs)
+    }
+
+    async fn history(
+        &self,
+        identity: u128,
+        visibility: Visibility,
+    ) -> Result<Vec<(u128, ClientData)>> {
+        let mut history = self
+            .inner
+            .history(identity, self.visibility(visibility))
+            .await?;
+        history.retain(|(_, data)| self.serves(data, visibility));
+        Ok(history)
+    }
+
+    // Run ids are unique across tenants
+    async fn contains(&self, client_id: u128) -> Result<bool> {
+        self.inner.contains(client_id).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::tenant::{api_key_digest, TenantScope, TenantStorage};
+    use crate::storage::MemoryStorage;
+    use crate::types::test_utils::client_data;
+    use crate::types::{Storage, Visibility};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_tenant_storage() {
+        let inner = Arc::new(MemoryStorage::default());
+        let scope = |name: &str, api_key: Option<&str>| {
+            Some(TenantScope {
+                name: name.to_owned(),
+                api_key_digest: api_key.map(api_key_digest),
+            })
+        };
+        let payments = TenantStorage::new(inner.clone(), scope("payments", Some("a")));
+        let other_client = TenantStorage::new(inner.clone(), scope("payments", Some("b")));
+        let reader = TenantStorage::new(inner.clone(), scope("payments", None));
+        let search = TenantStorage::new(inner.clone(), scope("search", Some("c")));
+        let untenanted = TenantStorage::new(inner.clone(), None);
+
+        let mut private = client_data(60);
+        private.private = true;
+        private.identity = Some(7);
+        private.api_key_digest = Some(api_key_digest("a"));
+        payments.insert(1, private).await.unwrap();
+        search.insert(2, client_data(70)).await.unwrap();
+        untenanted.insert(3, client_data(80)).await.unwrap();
+        assert_eq!(
+            inner.get(1, Visibility::All).await.unwrap().unwrap().tenant,
+            Some("payments".to_owned())
+        );
+
+        // Clients see their own private runs and readers all of the tenant, but none of other
+        // clients or tenants
+        assert!(payments.get(1, Visibility::Public).await.unwrap().is_some());
+        assert!(reader.get(1, Visibility::Public).await.unwrap().is_some());
+        assert!(other_client
+            .get(1, Visibility::All)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(other_client.runs(Visibility::All).await.unwrap().is_empty());
+        assert!(payments.get(2, Visibility::All).await.unwrap().is_none());
+        assert_eq!(
+            payments.history(7, Visibility::Public).await.unwrap().len(),
+            1
+        );
+        assert!(other_client
+            .history(7, Visibility::Public)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(search.history(7, Visibility::All).await.unwrap().is_empty());
+        let runs = search.runs(Visibility::All).await.unwrap();
+        assert_eq!(runs.keys().copied().collect::<Vec<_>>(), vec![2]);
+        assert!(search.contains(1).await.unwrap());
+
+        let runs = untenanted.runs(Visibility::All).await.unwrap();
+        assert_eq!(runs.keys().copied().collect::<Vec<_>>(), vec![3]);
+    }
+}
@@ -0,0 +1,130 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Subsystem a task belongs to
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum TaskKind {
+    /// Measurement session of a connected client
+    Connection,
+    /// Generating a puzzle on the verification pool, including time spent queueing
+    PuzzleGeneration,
+    /// Verifying a response on the verification pool, including time spent queueing
+    Verification,
+    /// Work not tied to a request, e.g. sampling memory or notifying the policy hook
+    Background,
+}
+
+struct TaskEntry {
+    kind: TaskKind,
+    name: String,
+    started: Instant,
+}
+
+/// Named tasks currently in flight, so stuck or starving tasks can be spotted on a live server.
+/// tokio-console needs tokio 1.x, until the runtime is upgraded this serves the same purpose.
+#[derive(Clone, Default)]
+pub(crate) struct TaskRegistry {
+    tasks: Arc<Mutex<HashMap<u64, TaskEntry>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl TaskRegistry {
+    /// Lists task as in flight until returned guard is dropped
+    pub(crate) fn enter(&self, kind: TaskKind, name: impl Into<String>) -> TaskGuard {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.tasks.lock().unwrap().insert(
+            id,
+            TaskEntry {
+                kind,
+                name: name.into(),
+                started: Instant::now(),
+            },
+        );
+        TaskGuard {
+            tasks: self.tasks.clone(),
+            id,
+        }
+    }
+
+    /// Spawns `future` on the runtime, listed as in flight until it completes
+    pub(crate) fn spawn<F>(&self, kind: TaskKind, name: impl Into<String>, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let guard = self.enter(kind, name);
+        tokio::spawn(async move {
+            future.await;
+            drop(guard);
+        });
+    }
+
+    /// Tasks in flight, longest running first
+    pub(crate) fn snapshot(&self) -> Vec<TaskRecord> {
+        let tasks = self.tasks.lock().unwrap();
+        let mut tasks = tasks.values().collect::<Vec<_>>();
+        tasks.sort_by_key(|task| task.started);
+        tasks
+            .into_iter()
+            .map(|task| TaskRecord {
+                kind: task.kind,
+                name: task.name.clone(),
+                running_milliseconds: task.started.elapsed().as_millis(),
+            })
+            .collect()
+    }
+}
+
+pub(crate) struct TaskGuard {
+    tasks: Arc<Mutex<HashMap<u64, TaskEntry>>>,
+    id: u64,
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        self.tasks.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Task in flight as reported to operators
+#[derive(Debug, Serialize)]
+pub(crate) struct TaskRecord {
+    kind: TaskKind,
+    name: String,
+    running_milliseconds: u128,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tasks::{TaskKind, TaskRegistry};
+
+    #[tokio::test]
+    async fn test_task_registry() {
+        let tasks = TaskRegistry::default();
+        let connection = tasks.enter(TaskKind::Connection, "session abc");
+        let verification = tasks.enter(TaskKind::Verification, "session abc");
+
+        let snapshot = tasks.snapshot();
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot[0].kind, TaskKind::Connection);
+        assert_eq!(snapshot[0].name, "session abc");
+
+        drop(verification);
+        assert_eq!(tasks.snapshot().len(), 1);
+        drop(connection);
+        assert!(tasks.snapshot().is_empty());
+
+        let (sender, receiver) = tokio::sync::oneshot::channel::<()>();
+        tasks.spawn(TaskKind::Background, "job", async move {
+            let _ = receiver.await;
+        });
+        assert_eq!(tasks.snapshot()[0].kind, TaskKind::Background);
+        sender.send(()).unwrap();
+        tokio::time::delay_for(std::time::Duration::from_millis(10)).await;
+        assert!(tasks.snapshot().is_empty());
+    }
+}
@@ -0,0 +1,221 @@
+use anyhow::{anyhow, Result};
+use futures::Future;
+use hyper::server::conn::Http;
+use hyper::service::{service_fn, Service};
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::{NoClientAuth, ServerConfig};
+use serde::Deserialize;
+use std::fs;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use warp::{Filter, Rejection, Reply};
+
+/// Time client has to complete the TLS handshake
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// TLS termination, so that clients can connect over `wss://` without a reverse proxy
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TlsConfig {
+    /// PEM file with the certificate chain, leaf certificate first
+    pub(crate) cert_path: PathBuf,
+    /// PEM file with the PKCS#8 or RSA private key of the certificate
+    pub(crate) key_path: PathBuf,
+    /// Where the same routes are also served without TLS, so that clients can measure a cleartext
+    /// run to compare with. `None` serves TLS only.
+    #[serde(default)]
+    pub(crate) cleartext_bind_address: Option<SocketAddr>,
+}
+
+/// Connection server terminated TLS of, attached to each of its requests
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct TlsConnection {
+    pub(crate) remote_addr: SocketAddr,
+    /// From accepting the connection until the TLS handshake completed, round trips included
+    pub(crate) handshake: Duration,
+}
+
+/// Certificate chain and private key, checked to be usable by the TLS server
+pub(crate) struct TlsIdentity {
+    pub(crate) cert: Vec<u8>,
+    pub(crate) key: Vec<u8>,
+}
+
+fn read(path: &Path) -> Result<Vec<u8>> {
+    fs::read(path).map_err(|e| anyhow!("Unable to read {}: {:?}", path.display(), e))
+}
+
+impl TlsIdentity {
+    /// Reads the certificate and key. Server would panic on an unusable pair
+    /// once it starts listening, so they are checked upfront.
+    pub(crate) fn load(config: &TlsConfig) -> Result<Self> {
+        let identity = TlsIdentity {
+            cert: read(&config.cert_path)?,
+            key: read(&config.key_path)?,
+        };
+        identity.server_config()?;
+        Ok(identity)
+    }
+
+    fn server_config(&self) -> Result<ServerConfig> {
+        let chain = certs(&mut BufReader::new(self.cert.as_slice()))
+            .map_err(|_| anyhow!("Invalid PEM certificate"))?;
+        if chain.is_empty() {
+            return Err(anyhow!("No certificate found"));
+        }
+        // Same lookup order as the server: PKCS#8 first, RSA otherwise
+        let key = pkcs8_private_keys(&mut BufReader::new(self.key.as_slice()))
+            .ok()
+            .filter(|keys| !keys.is_empty())
+            .or_else(|| rsa_private_keys(&mut BufReader::new(self.key.as_slice())).ok())
+            .and_then(|mut keys| keys.drain(..).next())
+            .ok_or_else(|| anyhow!("No PKCS#8 or RSA private key found"))?;
+        let mut server_config = ServerConfig::new(NoClientAuth::new());
+        server_config
+            .set_single_cert(chain, key)
+            .map_err(|e| anyhow!("Unusable certificate or private key: {}", e))?;
+        Ok(server_config)
+    }
+}
+
+/// Serves `filter` over TLS on `listener` until `shutdown` resolves. Server terminates TLS itself
+/// rather than through warp, so that it can time handshake of each connection, see `TlsConnection`.
+pub(crate) async fn serve<F, R>(
+    filter: F,
+    identity: &TlsIdentity,
+    mut listener: TcpListener,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()>
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+    let acceptor = TlsAcceptor::from(Arc::new(identity.server_config()?));
+    tokio::pin!(shutdown);
+    loop {
+        let (stream, remote_addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Unable to accept connection: {:?}", e);
+                    continue;
+                }
+            },
+            _ = &mut shutdown => return Ok(()),
+        };
+        let acceptor = acceptor.clone();
+        let service = warp::service(filter.clone());
+        tokio::spawn(async move {
+            let started = Instant::now();
+            let stream =
+                match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(e)) => {
+                        debug!("TLS handshake with {} failed: {:?}", remote_addr, e);
+                        return;
+                    }
+                    Err(_) => {
+                        debug!("TLS handshake with {} timed out", remote_addr);
+                        return;
+                    }
+                };
+            let connection = TlsConnection {
+                remote_addr,
+                handshake: started.elapsed(),
+            };
+            let service = service_fn(move |mut request| {
+                request.extensions_mut().insert(connection);
+                service.clone().call(request)
+            });
+            if let Err(e) = Http::new()
+                .serve_connection(stream, service)
+                .with_upgrades()
+                .await
+            {
+                debug!("Connection of {} failed: {:?}", remote_addr, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tls::{serve, TlsConnection, TlsIdentity};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::rustls::{Certificate, ClientConfig};
+    use tokio_rustls::webpki::DNSNameRef;
+    use tokio_rustls::TlsConnector;
+    use warp::Filter;
+
+    #[test]
+    fn test_tls_identity() {
+        let certificate = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let identity = TlsIdentity {
+            cert: certificate.serialize_pem().unwrap().into_bytes(),
+            key: certificate.serialize_private_key_pem().into_bytes(),
+        };
+        assert!(identity.server_config().is_ok());
+
+        let missing_key = TlsIdentity {
+            cert: identity.cert,
+            key: b"not a key".to_vec(),
+        };
+        assert!(missing_key.server_config().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let certificate = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let identity = TlsIdentity {
+            cert: certificate.serialize_pem().unwrap().into_bytes(),
+            key: certificate.serialize_private_key_pem().into_bytes(),
+        };
+        let routes = warp::path::end().and(warp::ext::optional()).map(
+            |connection: Option<TlsConnection>| {
+                let connection = connection.unwrap();
+                assert!(connection.remote_addr.ip().is_loopback());
+                assert!(connection.handshake < Duration::from_secs(10));
+                "handshake timed"
+            },
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            serve(routes, &identity, listener, async {
+                stopped.await.ok();
+            })
+            .await
+        });
+
+        let mut client_config = ClientConfig::new();
+        client_config
+            .root_store
+            .add(&Certificate(certificate.serialize_der().unwrap()))
+            .unwrap();
+        let stream = TcpStream::connect(address).await.unwrap();
+        let mut stream = TlsConnector::from(Arc::new(client_config))
+            .connect(DNSNameRef::try_from_ascii_str("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("handshake timed"));
+
+        stop.send(()).unwrap();
+        assert!(server.await.unwrap().is_ok());
+    }
+}
@@ -0,0 +1,459 @@
+//! Transport measurements run over. Measurements only exchange encoded messages, so any
+//! `Transport` carries them: an upgraded websocket through `WebSocketTransport`, plain TCP with
+//! length prefixed messages through `TcpTransport`, or e.g. WebTransport or in-memory channels in tests.
+//! `AuthenticatedTransport` wraps any of them to seal and open messages once the handshake agreed
+//! on keys, see `shared::mac`.
+
+use crate::types::WsMessage;
+use crate::utils::{ConnectionLost, ProtocolViolation};
+
+use anyhow::{anyhow, Result};
+use futures::{Future, Sink, Stream};
+use serde::Deserialize;
+use shared::framing::{self, FrameDecoder};
+use shared::mac::MessageAuthenticator;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{delay_for, Delay, Instant};
+use warp::ws::WebSocket;
+
+/// Bytes read from a TCP connection at once
+const TCP_READ_BUFFER_BYTES: usize = 64 << 10;
+
+/// Bidirectional channel of messages, one encoded message per item. Stream ends once the client
+/// goes away, messages which can't be decoded as one (e.g. text frames) are errors.
+pub trait Transport:
+    Sink<Vec<u8>, Error = anyhow::Error> + Stream<Item = Result<Vec<u8>>> + Send + 'static
+{
+}
+
+impl<T> Transport for T where
+    T: Sink<Vec<u8>, Error = anyhow::Error> + Stream<Item = Result<Vec<u8>>> + Send + 'static
+{
+}
+
+/// Pings sent over a websocket while messages of the client are awaited, which tell a connection
+/// that went away without being closed from a client which is slow to answer
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Keepalive {
+    /// Time between pings
+    pub interval: Duration,
+    /// Time a ping may go unanswered, any frame of the client answers it
+    pub timeout: Duration,
+}
+
+struct KeepaliveTimers {
+    keepalive: Keepalive,
+    next_ping: Delay,
+    /// Set while a ping is unanswered
+    deadline: Option<Delay>,
+}
+
+/// Carries messages in binary frames of an upgraded websocket
+pub struct WebSocketTransport {
+    socket: WebSocket,
+    keepalive: Option<KeepaliveTimers>,
+}
+
+impl WebSocketTransport {
+    pub fn new(ws: WebSocket) -> Self {
+        WebSocketTransport {
+            socket: ws,
+            keepalive: None,
+        }
+    }
+
+    /// Pings the client while its messages are awaited, the stream fails with `ConnectionLost`
+    /// once a ping goes unanswered for `keepalive.timeout`
+    pub fn with_keepalive(mut self, keepalive: Keepalive) -> Self {
+        self.keepalive = Some(KeepaliveTimers {
+            keepalive,
+            next_ping: delay_for(keepalive.interval),
+            deadline: None,
+        });
+        self
+    }
+
+    /// Sends pings when they are due, ready with the error ending the stream once the client
+    /// stopped answering them
+    fn poll_keepalive(&mut self, cx: &mut Context<'_>) -> Poll<anyhow::Error> {
+        let WebSocketTransport { socket, keepalive } = self;
+        let timers = match keepalive.as_mut() {
+            Some(timers) => timers,
+            None => return Poll::Pending,
+        };
+        if let Some(deadline) = timers.deadline.as_mut() {
+            if Pin::new(deadline).poll(cx).is_ready() {
+                return Poll::Ready(
+                    ConnectionLost {
+                        timeout: timers.keepalive.timeout,
+                    }
+                    .into(),
+                );
+            }
+        }
+        while Pin::new(&mut timers.next_ping).poll(cx).is_ready() {
+            timers
+                .next_ping
+                .reset(Instant::now() + timers.keepalive.interval);
+            // Ping is skipped while a message is being sent, it is flushed with the next one
+            match Pin::new(&mut *socket).poll_ready(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(e.into()),
+                Poll::Pending => continue,
+            }
+            if let Err(e) = Pin::new(&mut *socket).start_send(WsMessage::ping(Vec::new())) {
+                return Poll::Ready(e.into());
+            }
+            if let Poll::Ready(Err(e)) = Pin::new(&mut *socket).poll_flush(cx) {
+                return Poll::Ready(e.into());
+            }
+            if timers.deadline.is_none() {
+                let mut deadline = delay_for(timers.keepalive.timeout);
+                // Registers the deadline's wakeup
+                let _ = Pin::new(&mut deadline).poll(cx);
+                timers.deadline = Some(deadline);
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl Sink<Vec<u8>> for WebSocketTransport {
+    type Error = anyhow::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.socket)
+            .poll_ready(cx)
+            .map_err(Into::into)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, bytes: Vec<u8>) -> Result<()> {
+        Pin::new(&mut self.socket)
+            .start_send(WsMessage::binary(bytes))
+            .map_err(Into::into)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.socket)
+            .poll_flush(cx)
+            .map_err(Into::into)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.socket)
+            .poll_close(cx)
+            .map_err(Into::into)
+    }
+}
+
+impl Stream for WebSocketTransport {
+    type Item = Result<Vec<u8>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        while let Poll::Ready(message) = Pin::new(&mut self.socket).poll_next(cx) {
+            let message = match message {
+                Some(Ok(message)) => message,
+                Some(Err(e)) => {
+                    return Poll::Ready(Some(Err(anyhow!("Error reading from stream: {:?}", e))))
+                }
+                None => return Poll::Ready(None),
+            };
+            // Any frame shows the client is still there
+            if let Some(timers) = self.keepalive.as_mut() {
+                timers.deadline = None;
+            }
+            // Pings of the client are answered by the websocket itself
+            if message.is_ping() || message.is_pong() {
+                continue;
+            }
+            // Client closing the connection ends the stream like a dropped one
+            if message.is_close() {
+                return Poll::Ready(None);
+            }
+            return Poll::Ready(Some(if message.is_binary() {
+                Ok(message.into_bytes())
+            } else {
+                Err(ProtocolViolation(
+                    "Wrong message format, expected to be a binary data".to_owned(),
+                )
+                .into())
+            }));
+        }
+        self.poll_keepalive(cx).map(|e| Some(Err(e)))
+    }
+}
+
+/// Carries messages over a TCP connection, each prefixed with its length, see `shared::framing`
+pub struct TcpTransport {
+    sink: Pin<Box<dyn Sink<Vec<u8>, Error = anyhow::Error> + Send>>,
+    stream: Pin<Box<dyn Stream<Item = Result<Vec<u8>>> + Send>>,
+}
+
+impl TcpTransport {
+    /// Messages larger than `max_message_bytes` are refused before they are buffered whole
+    pub fn new(stream: TcpStream, max_message_bytes: usize) -> Self {
+        let (reader, writer) = tokio::io::split(stream);
+        let sink = futures::sink::unfold(writer, |mut writer, bytes: Vec<u8>| async move {
+            writer.write_all(&framing::frame(&bytes)?).await?;
+            Ok::<_, anyhow::Error>(writer)
+        });
+        let decoder = FrameDecoder::new(max_message_bytes);
+        let buffer = vec![0; TCP_READ_BUFFER_BYTES];
+        // State is dropped after an error, ending the stream
+        let stream = futures::stream::unfold(Some((reader, decoder, buffer)), |state| async move {
+            let (mut reader, mut decoder, mut buffer) = state?;
+            loop {
+                match decoder.next_frame() {
+                    Ok(Some(frame)) => return Some((Ok(frame), Some((reader, decoder, buffer)))),
+                    Ok(None) => {}
+                    Err(e) => return Some((Err(ProtocolViolation(e.to_string()).into()), None)),
+                }
+                match reader.read(&mut buffer).await {
+                    Ok(0) if decoder.is_empty() => return None,
+                    Ok(0) => {
+                        return Some((
+                            Err(anyhow!("Connection closed in the middle of a message")),
+                            None,
+                        ))
+                    }
+                    Ok(read) => decoder.extend(&buffer[..read]),
+                    Err(e) => {
+                        return Some((Err(anyhow!("Error reading from stream: {:?}", e)), None))
+                    }
+                }
+            }
+        });
+        TcpTransport {
+            sink: Box::pin(sink),
+            stream: Box::pin(stream),
+        }
+    }
+}
+
+impl Sink<Vec<u8>> for TcpTransport {
+    type Error = anyhow::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.sink.as_mut().poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, bytes: Vec<u8>) -> Result<()> {
+        self.sink.as_mut().start_send(bytes)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.sink.as_mut().poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.sink.as_mut().poll_close(cx)
+    }
+}
+
+impl Stream for TcpTransport {
+    type Item = Result<Vec<u8>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.stream.as_mut().poll_next(cx)
+    }
+}
+
+/// Whether messages of a session are authenticated with keys agreed in the handshake, for
+/// deployments whose transport doesn't protect them, e.g. plain TCP or TLS terminated upstream
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageAuthentication {
+    #[default]
+    Off,
+    /// Offered to clients, sessions of clients which don't take it up aren't authenticated
+    Offered,
+    /// Clients which don't take it up are refused
+    Required,
+}
+
+impl MessageAuthentication {
+    pub(crate) fn is_offered(self) -> bool {
+        self != MessageAuthentication::Off
+    }
+}
+
+/// Authenticator of a session, set by the handshake once keys are agreed
+pub(crate) type SessionAuthenticator = Arc<Mutex<Option<MessageAuthenticator>>>;
+
+/// Passes messages through until `authenticator` is set, then seals messages sent and opens
+/// messages received, a message which fails to open is a `ProtocolViolation`
+pub struct AuthenticatedTransport<T> {
+    inner: Pin<Box<T>>,
+    authenticator: SessionAuthenticator,
+}
+
+impl<T> AuthenticatedTransport<T> {
+    pub(crate) fn new(inner: T, authenticator: SessionAuthenticator) -> Self {
+        AuthenticatedTransport {
+            inner: Box::pin(inner),
+            authenticator,
+        }
+    }
+}
+
+impl<T: Transport> Sink<Vec<u8>> for AuthenticatedTransport<T> {
+    type Error = anyhow::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.inner.as_mut().poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, bytes: Vec<u8>) -> Result<()> {
+        let bytes = match self.authenticator.lock().unwrap().as_mut() {
+            Some(authenticator) => authenticator.seal(bytes),
+            None => bytes,
+        };
+        self.inner.as_mut().start_send(bytes)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.inner.as_mut().poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.inner.as_mut().poll_close(cx)
+    }
+}
+
+impl<T: Transport> Stream for AuthenticatedTransport<T> {
+    type Item = Result<Vec<u8>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let bytes = match self.inner.as_mut().poll_next(cx) {
+            Poll::Ready(Some(Ok(bytes))) => bytes,
+            other => return other,
+        };
+        Poll::Ready(Some(match self.authenticator.lock().unwrap().as_mut() {
+            Some(authenticator) => authenticator
+                .open(bytes)
+                .map_err(|e| ProtocolViolation(e.to_string()).into()),
+            None => Ok(bytes),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utils::send_client_msg_with_profiling;
+
+    use anyhow::Result;
+    use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+    use futures::{Sink, SinkExt, Stream, StreamExt};
+    use shared::{Challenge, Message, Response};
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+
+    /// One end of an in-memory transport
+    struct MemoryTransport {
+        sender: UnboundedSender<Vec<u8>>,
+        receiver: UnboundedReceiver<Vec<u8>>,
+    }
+
+    fn memory_pair() -> (MemoryTransport, MemoryTransport) {
+        let (server_sender, client_receiver) = mpsc::unbounded();
+        let (client_sender, server_receiver) = mpsc::unbounded();
+        (
+            MemoryTransport {
+                sender: server_sender,
+                receiver: server_receiver,
+            },
+            MemoryTransport {
+                sender: client_sender,
+                receiver: client_receiver,
+            },
+        )
+    }
+
+    impl Sink<Vec<u8>> for MemoryTransport {
+        type Error = anyhow::Error;
+
+        fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+            Pin::new(&mut self.sender)
+                .poll_ready(cx)
+                .map_err(Into::into)
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, bytes: Vec<u8>) -> Result<()> {
+            Pin::new(&mut self.sender)
+                .start_send(bytes)
+                .map_err(Into::into)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+            Pin::new(&mut self.sender)
+                .poll_flush(cx)
+                .map_err(Into::into)
+        }
+
+        fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+            Pin::new(&mut self.sender)
+                .poll_close(cx)
+                .map_err(Into::into)
+        }
+    }
+
+    impl Stream for MemoryTransport {
+        type Item = Result<Vec<u8>>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            Pin::new(&mut self.receiver)
+                .poll_next(cx)
+                .map(|bytes| bytes.map(Ok))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_memory_transport() {
+        let (server, mut client) = memory_pair();
+        let echo = tokio::spawn(async move {
+            let bytes = client.next().await.unwrap().unwrap();
+            let reply = match Message::decode(&bytes).unwrap() {
+                Message::Challenge(Challenge::NetworkChallenge(payload)) => {
+                    Message::Response(Response::NetworkChallengeResponse(payload))
+                }
+                msg => panic!("Unexpected message {}", msg),
+            };
+            client.send(reply.encode().unwrap()).await.unwrap();
+            // Goes away after the first round
+        });
+
+        let (mut writer, mut reader) = server.split();
+        let challenge = Message::Challenge(Challenge::NetworkChallenge(vec![7; 1024]))
+            .encode()
+            .unwrap();
+        let timeout = Duration::from_secs(5);
+        let (response, _) =
+            send_client_msg_with_profiling(&mut writer, &mut reader, &challenge, true, timeout)
+                .await
+                .unwrap();
+        match response {
+            Message::Response(Response::NetworkChallengeResponse(payload)) => {
+                assert_eq!(payload, vec![7; 1024])
+            }
+            msg => panic!("Unexpected message {}", msg),
+        }
+        echo.await.unwrap();
+
+        // Client is gone, so the next round fails instead of waiting for the timeout
+        assert!(send_client_msg_with_profiling(
+            &mut writer,
+            &mut reader,
+            &challenge,
+            true,
+            timeout
+        )
+        .await
+        .is_err());
+    }
+}
@@ -0,0 +1,436 @@
+use crate::config::{PlanConfig, ServerConfig};
+use crate::measurements::{Aggregation, Scale};
+use crate::tasks::{TaskKind, TaskRegistry};
+use crate::types::{ClientData, ServerContext, Storage, Visibility};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Threshold changes kept in the audit log, oldest are dropped first
+const AUDIT_LOG_ENTRIES: usize = 1000;
+
+/// Periodic retuning of time thresholds of the plan from percentiles of recent runs
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ThresholdTuningConfig {
+    #[serde(
+        rename = "interval_milliseconds",
+        deserialize_with = "crate::config::milliseconds"
+    )]
+    pub(crate) interval: Duration,
+    /// Number of most recent runs of the plan percentiles are taken over
+    pub(crate) window_runs: usize,
+    /// Thresholds of a challenge kind are kept until at least this many runs measured it
+    pub(crate) min_runs: usize,
+    /// Percentile of aggregated round times of runs which becomes the ideal time
+    pub(crate) ideal_percentile: u8,
+    /// Percentile of aggregated round times of runs which becomes the max time
+    pub(crate) max_percentile: u8,
+    /// Thresholds only change once a new one differs from the current one by more than this
+    pub(crate) hysteresis_percent: f64,
+}
+
+impl Default for ThresholdTuningConfig {
+    fn default() -> Self {
+        ThresholdTuningConfig {
+            interval: Duration::from_secs(3600),
+            window_runs: 1000,
+            min_runs: 100,
+            ideal_percentile: 25,
+            max_percentile: 99,
+            hysteresis_percent: 10.0,
+        }
+    }
+}
+
+impl ThresholdTuningConfig {
+    fn validate(&self) -> Result<()> {
+        if self.ideal_percentile == 0 || self.ideal_percentile >= self.max_percentile {
+            return Err(anyhow!(
+                "Ideal percentile must be positive and below max percentile, got {} and {}",
+                self.ideal_percentile,
+                self.max_percentile
+            ));
+        }
+        if self.max_percentile > 100 {
+            return Err(anyhow!(
+                "Max percentile can't exceed 100, got {}",
+                self.max_percentile
+            ));
+        }
+        if self.interval.is_zero() || self.window_runs == 0 || self.min_runs == 0 {
+            return Err(anyhow!("Interval, window and min runs must be positive"));
+        }
+        if self.hysteresis_percent.is_nan() || self.hysteresis_percent < 0.0 {
+            return Err(anyhow!(
+                "Hysteresis can't be negative, got {}%",
+                self.hysteresis_percent
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum TunedChallenge {
+    Cpu,
+    Network,
+    Disk,
+}
+
+const TUNED_CHALLENGES: [TunedChallenge; 3] = [
+    TunedChallenge::Cpu,
+    TunedChallenge::Network,
+    TunedChallenge::Disk,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub(crate) struct Thresholds {
+    pub(crate) ideal_milliseconds: u64,
+    pub(crate) max_milliseconds: u64,
+}
+
+/// Entry of the audit log
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct ThresholdChange {
+    pub(crate) at_unix_seconds: u64,
+    pub(crate) challenge: TunedChallenge,
+    pub(crate) previous: Thresholds,
+    pub(crate) current: Thresholds,
+    /// Number of runs the percentiles were taken over
+    pub(crate) runs: usize,
+}
+
+/// Thresholds sessions are currently scored with and how they got there
+#[derive(Debug, Serialize)]
+pub(crate) struct TuningReport {
+    thresholds: BTreeMap<TunedChallenge, Thresholds>,
+    /// Oldest first
+    changes: Vec<ThresholdChange>,
+}
+
+struct TunerState {
+    plan: PlanConfig,
+    audit_log: VecDeque<ThresholdChange>,
+}
+
+/// Keeps scoring meaningful as hardware of the fleet changes: every `interval` ideal and max times of
+/// each challenge kind are recomputed as percentiles of round times of recent runs of the plan.
+/// Kinds scored on throughput or squarings rate aren't tuned, their thresholds don't depend on time.
+pub(crate) struct ThresholdTuner {
+    config: ThresholdTuningConfig,
+    state: Mutex<TunerState>,
+}
+
+impl ThresholdTuner {
+    /// Thresholds start as configured in `plan`
+    pub(crate) fn new(config: ThresholdTuningConfig, plan: PlanConfig) -> Result<Self> {
+        config
+            .validate()
+            .map_err(|e| anyhow!("Invalid threshold tuning: {}", e))?;
+        Ok(ThresholdTuner {
+            config,
+            state: Mutex::new(TunerState {
+                plan,
+                audit_log: VecDeque::new(),
+            }),
+        })
+    }
+
+    /// Retunes thresholds every `interval` for as long as the server runs
+    pub(crate) fn spawn(self: &Arc<Self>, tasks: &TaskRegistry, storage: Arc<dyn Storage>) {
+        let tuner = self.clone();
+        tasks.spawn(TaskKind::Background, "threshold tuning", async move {
+            let mut interval = tokio::time::interval(tuner.config.interval);
+            loop {
+                interval.tick().await;
+                match storage.runs(Visibility::All).await {
+                    Ok(runs) => tuner.retune(&runs, SystemTime::now()),
+                    Err(e) => warn!("Unable to load runs to tune thresholds with: {:?}", e),
+                }
+            }
+        });
+    }
+
+    /// Plan with the current thresholds
+    pub(crate) fn plan(&self) -> PlanConfig {
+        self.state.lock().unwrap().plan.clone()
+    }
+
+    pub(crate) fn report(&self) -> TuningReport {
+        let state = self.state.lock().unwrap();
+        TuningReport {
+            thresholds: TUNED_CHALLENGES
+                .iter()
+                .map(|challenge| (*challenge, thresholds(&state.plan, *challenge)))
+                .collect(),
+            changes: state.audit_log.iter().cloned().collect(),
+        }
+    }
+
+    /// Recomputes thresholds from `runs`, a change is only applied if it exceeds the hysteresis
+    /// and the plan with it still validates
+    pub(crate) fn retune(&self, runs: &HashMap<u128, ClientData>, now: SystemTime) {
+        let mut state = self.state.lock().unwrap();
+        let mut recent: Vec<&ClientData> = runs
+            .values()
+            .filter(|data| data.is_completed() && data.configuration.plan_name == state.plan.name)
+            .collect();
+        recent.sort_by_key(|data| cmp::Reverse(data.started_at));
+        recent.truncate(self.config.window_runs);
+
+        for challenge in TUNED_CHALLENGES {
+            let plan = &state.plan;
+            if scale(plan, challenge) == Scale::Throughput {
+                continue;
+            }
+            let aggregation = aggregation(plan, challenge);
+            let aggregates: Vec<u128> = recent
+                .iter()
+                .filter_map(|data| round_timings(plan, data, challenge))
+                .filter_map(|timings| aggregation.aggregate(timings))
+                .collect();
+            if aggregates.len() < self.config.min_runs {
+                continue;
+            }
+            let percentile = |percentile| {
+                Aggregation::Percentile { percentile }
+                    .aggregate(&aggregates)
+                    .map(|milliseconds| milliseconds.min(u64::MAX.into()) as u64)
+            };
+            let (ideal_milliseconds, max_milliseconds) = match (
+                percentile(self.config.ideal_percentile),
+                percentile(self.config.max_percentile),
+            ) {
+                (Some(ideal), Some(max)) => (ideal, max),
+                _ => continue,
+            };
+            let previous = thresholds(plan, challenge);
+            let current = Thresholds {
+                ideal_milliseconds,
+                // Plan has to be able to tell rounds apart even if the fleet is uniform
+                max_milliseconds: cmp::max(max_milliseconds, ideal_milliseconds.saturating_add(1)),
+            };
+            if !self.exceeds_hysteresis(previous.ideal_milliseconds, current.ideal_milliseconds)
+                && !self.exceeds_hysteresis(previous.max_milliseconds, current.max_milliseconds)
+            {
+                continue;
+            }
+            let mut tuned = plan.clone();
+            set_thresholds(&mut tuned, challenge, current);
+            if let Err(e) = tuned.validate() {
+                warn!(
+                    "Keeping {:?} thresholds {:?}, tuned ones {:?} are invalid: {}",
+                    challenge, previous, current, e
+                );
+                continue;
+            }
+            info!(
+                "Tuned {:?} thresholds from {:?} to {:?} over {} runs",
+                challenge,
+                previous,
+                current,
+                aggregates.len()
+            );
+            state.plan = tuned;
+            if state.audit_log.len() == AUDIT_LOG_ENTRIES {
+                state.audit_log.pop_front();
+            }
+            state.audit_log.push_back(ThresholdChange {
+                at_unix_seconds: now
+                    .duration_since(UNIX_EPOCH)
+                    .map(|duration| duration.as_secs())
+                    .unwrap_or(0),
+                challenge,
+                previous,
+                current,
+                runs: aggregates.len(),
+            });
+        }
+    }
+
+    fn exceeds_hysteresis(&self, previous: u64, current: u64) -> bool {
+        (current as f64 - previous as f64).abs()
+            > previous as f64 * self.config.hysteresis_percent / 100.0
+    }
+}
+
+/// Plan new sessions are measured with, with tuned thresholds if tuning is enabled
+pub(crate) fn current_plan(context: &ServerContext, config: &ServerConfig) -> PlanConfig {
+    match &context.threshold_tuner {
+        Some(threshold_tuner) => threshold_tuner.plan(),
+        None => config.plan.clone(),
+    }
+}
+
+fn scale(plan: &PlanConfig, challenge: TunedChallenge) -> Scale {
+    match challenge {
+        TunedChallenge::Cpu => plan.cpu_scale(),
+        TunedChallenge::Network => plan.network_scale(),
+        TunedChallenge::Disk => Scale::Time,
+    }
+}
+
+fn aggregation(plan: &PlanConfig, challenge: TunedChallenge) -> Aggregation {
+    match challenge {
+        TunedChallenge::Cpu => plan.cpu_aggregation,
+        TunedChallenge::Network => plan.network_aggregation,
+        TunedChallenge::Disk => plan.disk_aggregation,
+    }
+}
+
+/// Round times of the run, if it measured the challenge kind the way `plan` does
+fn round_timings<'a>(
+    plan: &PlanConfig,
+    data: &'a ClientData,
+    challenge: TunedChallenge,
+) -> Option<&'a [u128]> {
+    let configuration = &data.configuration;
+    let (comparable, timings) = match challenge {
+        TunedChallenge::Cpu => (
+            configuration.squarings == plan.squarings,
+            &data.cpu_challenge_timings_in_milis,
+        ),
+        TunedChallenge::Network => (
+            configuration.payload_size_kb == plan.payload_size_kb
+                && configuration.contention_correction == plan.network_contention_correction,
+            &data.network_challenge_timings_in_milis,
+        ),
+        TunedChallenge::Disk => (
+            configuration.disk_payload_size_kb == plan.disk_payload_size_kb,
+            &data.disk_challenge_timings_in_milis,
+        ),
+    };
+    Some(timings.as_slice()).filter(|timings| comparable && !timings.is_empty())
+}
+
+fn thresholds(plan: &PlanConfig, challenge: TunedChallenge) -> Thresholds {
+    let (ideal_milliseconds, max_milliseconds) = match challenge {
+        TunedChallenge::Cpu => (plan.cpu_ideal_milliseconds, plan.cpu_max_milliseconds),
+        TunedChallenge::Network => (
+            plan.network_ideal_milliseconds,
+            plan.network_max_milliseconds,
+        ),
+        TunedChallenge::Disk => (plan.disk_ideal_milliseconds, plan.disk_max_milliseconds),
+    };
+    Thresholds {
+        ideal_milliseconds,
+        max_milliseconds,
+    }
+}
+
+fn set_thresholds(plan: &mut PlanConfig, challenge: TunedChallenge, thresholds: Thresholds) {
+    let (ideal_milliseconds, max_milliseconds) = match challenge {
+        TunedChallenge::Cpu => (
+            &mut plan.cpu_ideal_milliseconds,
+            &mut plan.cpu_max_milliseconds,
+        ),
+        TunedChallenge::Network => (
+            &mut plan.network_ideal_milliseconds,
+            &mut plan.network_max_milliseconds,
+        ),
+        TunedChallenge::Disk => (
+            &mut plan.disk_ideal_milliseconds,
+            &mut plan.disk_max_milliseconds,
+        ),
+    };
+    *ideal_milliseconds = thresholds.ideal_milliseconds;
+    *max_milliseconds = thresholds.max_milliseconds;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::PlanConfig;
+    use crate::tuning::{ThresholdTuner, ThresholdTuningConfig, Thresholds, TunedChallenge};
+    use crate::types::test_utils::client_data;
+    use std::collections::HashMap;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn tuner() -> ThresholdTuner {
+        ThresholdTuner::new(
+            ThresholdTuningConfig {
+                min_runs: 4,
+                ..Default::default()
+            },
+            PlanConfig::default(),
+        )
+        .unwrap()
+    }
+
+    /// Runs of the default plan with given CPU round time, started a second apart
+    fn runs(cpu_milliseconds: &[u128]) -> HashMap<u128, crate::types::ClientData> {
+        cpu_milliseconds
+            .iter()
+            .enumerate()
+            .map(|(i, milliseconds)| {
+                let mut data = client_data(50);
+                data.configuration.plan_name = PlanConfig::default().name;
+                data.configuration.squarings = PlanConfig::default().squarings;
+                data.cpu_challenge_timings_in_milis = vec![*milliseconds];
+                data.started_at = UNIX_EPOCH + Duration::from_secs(i as u64);
+                (i as u128, data)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_retune_from_percentiles() {
+        let tuner = tuner();
+        let now = UNIX_EPOCH + Duration::from_secs(100);
+
+        // Too few runs
+        tuner.retune(&runs(&[1000, 2000, 3000]), now);
+        assert_eq!(tuner.plan().cpu_ideal_milliseconds, 4500);
+        assert!(tuner.report().changes.is_empty());
+
+        let times: Vec<u128> = (1..=100).map(|i| i * 100).collect();
+        tuner.retune(&runs(&times), now);
+        let plan = tuner.plan();
+        assert_eq!(plan.cpu_ideal_milliseconds, 2500);
+        assert_eq!(plan.cpu_max_milliseconds, 9900);
+        // Network runs were measured with another payload
+        assert_eq!(plan.network_ideal_milliseconds, 200);
+
+        let report = tuner.report();
+        assert_eq!(report.changes.len(), 1);
+        assert_eq!(report.changes[0].challenge, TunedChallenge::Cpu);
+        assert_eq!(report.changes[0].at_unix_seconds, 100);
+        assert_eq!(report.changes[0].runs, 100);
+        assert_eq!(
+            report.changes[0].previous,
+            Thresholds {
+                ideal_milliseconds: 4500,
+                max_milliseconds: 120000
+            }
+        );
+
+        // Within the hysteresis
+        let times: Vec<u128> = (1..=100).map(|i| i * 105).collect();
+        tuner.retune(&runs(&times), now);
+        assert_eq!(tuner.plan().cpu_ideal_milliseconds, 2500);
+        assert_eq!(tuner.report().changes.len(), 1);
+    }
+
+    #[test]
+    fn test_retune_keeps_valid_plan() {
+        let tuner = tuner();
+        // Default plan's squarings can't be solved within 10ms by the fastest client
+        tuner.retune(&runs(&[1, 2, 3, 10]), UNIX_EPOCH);
+        assert_eq!(tuner.plan().cpu_max_milliseconds, 120000);
+        assert!(tuner.report().changes.is_empty());
+
+        assert!(ThresholdTuner::new(
+            ThresholdTuningConfig {
+                ideal_percentile: 99,
+                max_percentile: 25,
+                ..Default::default()
+            },
+            PlanConfig::default(),
+        )
+        .is_err());
+    }
+}
@@ -1,13 +1,1053 @@
-use std::collections::HashMap;
+use crate::anomaly::{Anomaly, AnomalyDetector};
+#[cfg(feature = "event-bus")]
+use crate::bus::{EventBus, SessionPublisher};
+use crate::capacity::{ActiveSessions, ActiveTransfers, SessionLimit};
+use crate::certificate::CertificateSigner;
+use crate::client_identity::ClientIdentity;
+use crate::config::ServerConfig;
+use crate::dead_letters::DeadLetterQueue;
+use crate::hooks::SessionHook;
+use crate::identity::IdentityProvider;
+use crate::measurements::{
+    Aggregation, Credentials, DatagramEndpoint, ImplausibleRound, MeasurementAuth, RetriedRound,
+    ScoringModel, Strictness, SurpriseRound,
+};
+use crate::metrics::RequestMetrics;
+use crate::policy::PolicyHook;
+use crate::primes::PrimeSource;
+use crate::progress::SessionProgress;
+use crate::rate_limit::RateLimiter;
+use crate::shutdown::Draining;
+use crate::sizing::{HostBenchmark, HostLimits};
+use crate::storage::{self, ChaosStorage, PendingRuns};
+use crate::tasks::TaskRegistry;
+use crate::tuning::ThresholdTuner;
+use crate::verification::VerificationPool;
+use crate::watchdog::MemoryWatchdog;
+use anyhow::anyhow;
+use async_trait::async_trait;
+use serde::{Deserialize, Deserializer, Serialize};
+use shared::challenges::bandwidth::{ThroughputStats, TransferProfile};
+use shared::challenges::datagram::DatagramStats;
+use shared::challenges::latency::LatencyStats;
+use shared::hash::HashAlgorithm;
+use shared::{NetworkType, ScoreBreakdown};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::time::{Duration, Instant, SystemTime};
 
-pub(crate) struct ClientData {
+/// Point in time at which an event of a measurement session happened.
+/// Wall-clock time alone can jump backwards or forwards when server clock
+/// is adjusted (e.g. by NTP) during a long session, so we also keep
+/// monotonic time elapsed since the session started.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub(crate) struct EventTimestamp {
+    pub(crate) wall_clock: SystemTime,
+    pub(crate) session_offset: Duration,
+}
+
+/// Clock of a single measurement session, used to timestamp events of the session.
+pub(crate) struct SessionClock {
+    started_at: SystemTime,
+    started_instant: Instant,
+}
+
+impl SessionClock {
+    pub(crate) fn start() -> Self {
+        SessionClock {
+            started_at: SystemTime::now(),
+            started_instant: Instant::now(),
+        }
+    }
+
+    pub(crate) fn started_at(&self) -> SystemTime {
+        self.started_at
+    }
+
+    pub(crate) fn now(&self) -> EventTimestamp {
+        EventTimestamp {
+            wall_clock: SystemTime::now(),
+            session_offset: self.started_instant.elapsed(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum SessionEventKind {
+    SessionStarted,
+    CPUCalibrated {
+        #[serde(deserialize_with = "u128_from_u64")]
+        milliseconds: u128,
+        squarings: u32,
+    },
+    CPUChallengeCompleted {
+        round: usize,
+        #[serde(deserialize_with = "u128_from_u64")]
+        milliseconds: u128,
+    },
+    NetworkChallengeCompleted {
+        round: usize,
+        #[serde(deserialize_with = "u128_from_u64")]
+        milliseconds: u128,
+    },
+    /// Response to a round failed verification in a session which isn't strict, `scored` if its
+    /// time was scored nevertheless
+    RoundFailed {
+        kind_id: u16,
+        round: usize,
+        #[serde(deserialize_with = "u128_from_u64")]
+        milliseconds: u128,
+        scored: bool,
+    },
+    /// Round failed without breaking the connection and is tried again
+    RoundRetried {
+        kind_id: u16,
+        round: usize,
+    },
+    /// Round was answered faster than the fastest client the plan is meant for could have,
+    /// which scores the run 0
+    RoundImplausible {
+        kind_id: u16,
+        round: usize,
+        #[serde(deserialize_with = "u128_from_u64")]
+        milliseconds: u128,
+        #[serde(deserialize_with = "u128_from_u64")]
+        min_milliseconds: u128,
+    },
+    /// Response to the calibration round failed verification in a session which isn't strict,
+    /// `scored` if CPU rounds were calibrated with it nevertheless
+    CPUCalibrationFailed {
+        #[serde(deserialize_with = "u128_from_u64")]
+        milliseconds: u128,
+        scored: bool,
+    },
+    /// Data returned in an attempt of a network round didn't match, round was retried
+    NetworkChallengeMismatched {
+        round: usize,
+        #[serde(deserialize_with = "u128_from_u64")]
+        milliseconds: u128,
+    },
+    DiskChallengeCompleted {
+        round: usize,
+        #[serde(deserialize_with = "u128_from_u64")]
+        milliseconds: u128,
+    },
+    DownloadChallengeCompleted {
+        round: usize,
+        #[serde(deserialize_with = "u128_from_u64")]
+        milliseconds: u128,
+    },
+    UploadChallengeCompleted {
+        round: usize,
+        #[serde(deserialize_with = "u128_from_u64")]
+        milliseconds: u128,
+    },
+    /// Round of a plugin kind without an event of its own
+    ChallengeCompleted {
+        kind_id: u16,
+        round: usize,
+        #[serde(deserialize_with = "u128_from_u64")]
+        milliseconds: u128,
+    },
+    LatencyProbesCompleted {
+        probes: usize,
+        #[serde(deserialize_with = "u128_from_u64")]
+        mean_microseconds: u128,
+    },
+    /// Datagram probes were sent and their echoes awaited, client took part in it
+    DatagramProbesCompleted {
+        probes: usize,
+        echoed: usize,
+    },
+    /// Unannounced round sent after `after_rounds` scheduled CPU and network rounds
+    SurpriseRoundCompleted {
+        kind_id: u16,
+        after_rounds: usize,
+        #[serde(deserialize_with = "u128_from_u64")]
+        microseconds: u128,
+    },
+    ScoreCalculated {
+        #[serde(deserialize_with = "u128_from_u64")]
+        score: u128,
+    },
+}
+
+/// Internally tagged enums are buffered before deserialization and the buffer doesn't support u128
+fn u128_from_u64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u128, D::Error> {
+    u64::deserialize(deserializer).map(u128::from)
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct SessionEvent {
+    pub(crate) timestamp: EventTimestamp,
+    pub(crate) kind: SessionEventKind,
+}
+
+/// Events of a session in progress, streamed to watchers of `/admin/ws` and published to the
+/// event bus as they happen
+pub(crate) struct SessionEvents {
+    events: Vec<SessionEvent>,
+    client_id: u128,
+    plan_name: String,
+    /// Rounds of each challenge kind the session measures
+    rounds: HashMap<u16, usize>,
+    progress: SessionProgress,
+    #[cfg(feature = "event-bus")]
+    publisher: Option<SessionPublisher>,
+}
+
+impl SessionEvents {
+    /// Events of session `client_id`, which is listed as in flight until they are dropped
+    pub(crate) fn new(
+        context: &ServerContext,
+        client_id: u128,
+        plan_name: String,
+        private: bool,
+        tenant: Option<String>,
+        api_key_digest: Option<String>,
+        rounds: HashMap<u16, usize>,
+    ) -> Self {
+        context
+            .session_progress
+            .start(client_id, &plan_name, private, tenant, api_key_digest);
+        SessionEvents {
+            events: vec![],
+            client_id,
+            plan_name,
+            rounds,
+            progress: context.session_progress.clone(),
+            #[cfg(feature = "event-bus")]
+            publisher: context
+                .event_bus
+                .as_ref()
+                .map(|event_bus| event_bus.session(client_id)),
+        }
+    }
+
+    pub(crate) fn push(&mut self, event: SessionEvent) {
+        self.progress
+            .publish(self.client_id, &self.plan_name, &self.rounds, &event);
+        #[cfg(feature = "event-bus")]
+        if let Some(publisher) = &mut self.publisher {
+            publisher.publish(&event);
+        }
+        self.events.push(event);
+    }
+
+    pub(crate) fn into_vec(mut self) -> Vec<SessionEvent> {
+        std::mem::take(&mut self.events)
+    }
+}
+
+impl Drop for SessionEvents {
+    fn drop(&mut self) {
+        self.progress.end(self.client_id);
+    }
+}
+
+/// Scheduling priority of a session, sessions of higher class get their
+/// CPU heavy work done first when the server is busy.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum PriorityClass {
+    BestEffort,
+    #[default]
+    Normal,
+    High,
+}
+
+/// How far the session of a run got, runs of failed sessions are only stored with
+/// `PlanConfig::persist_partial_runs`
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum RunStatus {
+    #[default]
+    Completed,
+    /// Session failed at the CPU round of this index, e.g. answer was wrong or late
+    FailedAtCpu(usize),
+    /// Session failed at the network round of this index
+    FailedAtNetwork(usize),
+    /// Session failed at a round of a kind measured after network rounds, latency and datagram
+    /// probes count as a single round
+    FailedAtRound { kind_id: u16, round: usize },
+    /// Client went away, its stream ended or it stopped answering pings
+    Disconnected,
+}
+
+/// IP version of the connection a run was measured over
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum AddressFamily {
+    Ipv4,
+    Ipv6,
+}
+
+impl AddressFamily {
+    pub(crate) fn of(address: &SocketAddr) -> Self {
+        match address.ip() {
+            IpAddr::V4(_) => AddressFamily::Ipv4,
+            // Dual-stack sockets report IPv4 peers as mapped IPv6 addresses
+            IpAddr::V6(ip) if ip.to_ipv4_mapped().is_some() => AddressFamily::Ipv4,
+            IpAddr::V6(_) => AddressFamily::Ipv6,
+        }
+    }
+}
+
+/// Effective configuration a run was measured with, including parameters negotiated
+/// with the client. Two runs are only comparable if their configurations match.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub(crate) struct RunConfiguration {
+    pub(crate) plan_name: String,
+    pub(crate) cpu_rounds: usize,
+    pub(crate) squarings: u32,
+    /// Derived from `cpu_ideal_squarings_per_second` and `cpu_min_squarings_per_second` for
+    /// `squarings` when they are set
+    pub(crate) cpu_ideal_milliseconds: u128,
+    pub(crate) cpu_max_milliseconds: u128,
+    /// How round times were aggregated into the score
+    #[serde(default)]
+    pub(crate) cpu_aggregation: Aggregation,
+    /// Squarings rate thresholds CPU rounds were scored with, `None` if they were scored on time
+    #[serde(default)]
+    pub(crate) cpu_ideal_squarings_per_second: Option<f64>,
+    #[serde(default)]
+    pub(crate) cpu_min_squarings_per_second: Option<f64>,
+    /// Squarings CPU rounds were scaled to by the calibration round, `None` if run wasn't calibrated.
+    /// Round times are normalized to `squarings` either way.
+    #[serde(default)]
+    pub(crate) calibrated_squarings: Option<u32>,
+    pub(crate) network_rounds: usize,
+    pub(crate) payload_size_kb: usize,
+    /// Payload size of the plan if memory pressure or limits of the host made the run use a
+    /// smaller `payload_size_kb`,
+    /// needed to normalize scores of such runs
+    pub(crate) payload_scaled_down_from_kb: Option<usize>,
+    /// Derived from `network_ideal_mbps` and `network_min_mbps` for `payload_size_kb` when they are set
+    pub(crate) network_ideal_milliseconds: u128,
+    pub(crate) network_max_milliseconds: u128,
+    #[serde(default)]
+    pub(crate) network_aggregation: Aggregation,
+    /// Throughput thresholds network rounds were scored with, `None` if they were scored on time
+    #[serde(default)]
+    pub(crate) network_ideal_mbps: Option<f64>,
+    #[serde(default)]
+    pub(crate) network_min_mbps: Option<f64>,
+    /// Number of sampled chunks if network challenge was spot checked, `None` if fully hashed
+    pub(crate) spot_check_sample_chunks: Option<usize>,
+    /// Network round times were corrected for transfers of other sessions overlapping them
+    #[serde(default)]
+    pub(crate) contention_correction: bool,
+    pub(crate) disk_rounds: usize,
+    pub(crate) disk_payload_size_kb: usize,
+    pub(crate) disk_ideal_milliseconds: u128,
+    pub(crate) disk_max_milliseconds: u128,
+    #[serde(default)]
+    pub(crate) disk_aggregation: Aggregation,
+    /// Weights sub-scores were combined with
+    #[serde(default)]
+    pub(crate) scoring: ScoringModel,
+    /// How failed rounds were treated, runs of other modes may include rounds a strict one
+    /// would have been rejected for
+    #[serde(default)]
+    pub(crate) strictness: Strictness,
+    #[serde(default)]
+    pub(crate) latency_probes: usize,
+    #[serde(default)]
+    pub(crate) download_rounds: usize,
+    #[serde(default)]
+    pub(crate) upload_rounds: usize,
+    /// Probes sent over UDP, 0 if server had no datagram endpoint
+    #[serde(default)]
+    pub(crate) datagram_probes: usize,
+    /// Hash algorithm used for roundtrip verification, as negotiated with the client
+    pub(crate) hash_algorithm: HashAlgorithm,
+    /// Largest frame bulk transfers were sent in, `None` if they weren't split into chunks
+    #[serde(default)]
+    pub(crate) max_frame_size_kb: Option<usize>,
+    /// Gap between chunks of a network round counted as a stall in its profile
+    #[serde(default)]
+    pub(crate) network_stall_milliseconds: u64,
+    pub(crate) encoding: String,
+    pub(crate) transport: String,
+}
+
+/// Where time of a session was spent, in microseconds, so slow sessions
+/// can be attributed to the right subsystem. Time of writing the run to storage
+/// is only known once the run is stored, so it's logged instead.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub(crate) struct DurationBreakdown {
+    /// Waiting for a free thread of the verification pool
+    pub(crate) queueing_microseconds: u128,
+    /// Generating puzzles and payloads of challenges
+    pub(crate) puzzle_generation_microseconds: u128,
+    /// Client solving CPU challenges and writing disk challenges
+    pub(crate) client_compute_microseconds: u128,
+    /// Roundtrips of network challenges and transfers of download and upload challenges
+    pub(crate) network_transfer_microseconds: u128,
+    /// Verifying responses of the client, excluding time spent queueing
+    pub(crate) verification_microseconds: u128,
+}
+
+/// Attributes of a session known when the client connects
+#[derive(Clone, Debug, Default)]
+pub struct SessionParameters {
+    pub(crate) priority_class: PriorityClass,
+    /// Client supplied `key:value` tags, used to group runs into fleets
+    pub(crate) tags: BTreeMap<String, String>,
+    /// Ceiling of memory the session may hold, `None` means no ceiling
+    pub(crate) memory_limit_bytes: Option<usize>,
+    /// Number of disk challenge rounds, zero for clients without persistent storage
+    pub(crate) disk_challenge_rounds: usize,
+    /// Run is hidden from leaderboards and unauthenticated queries, see `Visibility`
+    pub(crate) private: bool,
+    /// IP version of the connection, `None` if remote address is unknown
+    pub(crate) address_family: Option<AddressFamily>,
+    /// Run this session is the second half of, measured over the other IP version
+    pub(crate) dual_stack_of: Option<u128>,
+    /// Time TLS handshake of the connection took, `None` unless server terminated TLS itself
+    pub(crate) tls_handshake: Option<Duration>,
+    /// Run this session is compared with to find the TLS overhead, measured over TLS if this
+    /// session is in cleartext and vice versa
+    pub(crate) tls_comparison_of: Option<u128>,
+    /// Identity of the client across sessions, `None` if the session has none
+    pub(crate) client_identity: Option<ClientIdentity>,
+    /// How rounds which fail are treated, as configured for the tenant of the session
+    pub(crate) strictness: Strictness,
+    /// What the client presented to authenticate, dropped once it's authenticated
+    pub(crate) credentials: Credentials,
+    /// Who the client authenticated as, `None` unless server only measures authenticated clients
+    pub(crate) principal: Option<String>,
+    /// Tenant the API key of the client belongs to, see `TenantConfig`
+    pub(crate) tenant: Option<String>,
+    /// Digest of the API key of the client if it belongs to a tenant, see `api_key_digest`
+    pub(crate) api_key_digest: Option<String>,
+}
+
+impl SessionParameters {
+    /// Parameters of an anonymous client of a server of `config`: default priority class, no
+    /// tags, no identity and a public run
+    pub fn new(config: &ServerConfig) -> Self {
+        SessionParameters {
+            priority_class: config.default_priority_class,
+            memory_limit_bytes: config.session_memory_limit_bytes,
+            disk_challenge_rounds: config.disk_challenge_rounds,
+            strictness: config.default_strictness,
+            ..Default::default()
+        }
+    }
+
+    /// Client supplied `key:value` tags of the session
+    pub fn tags(&self) -> &BTreeMap<String, String> {
+        &self.tags
+    }
+
+    /// Who the client authenticated as, `None` for clients measured without authentication
+    pub fn principal(&self) -> Option<&str> {
+        self.principal.as_deref()
+    }
+
+    /// Tags the run is stored with, e.g. for a `SessionHook` to add its own
+    pub fn tags_mut(&mut self) -> &mut BTreeMap<String, String> {
+        &mut self.tags
+    }
+}
+
+/// Measured run of a client as it is stored, opaque outside of the server. Storage backends
+/// persist it serialized.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ClientData {
+    /// `score_breakdown.total` rounded
     pub(crate) score: u128,
+    /// Unrounded sub-scores of challenge kinds and the score, default for runs stored before it
+    #[serde(default)]
+    pub(crate) score_breakdown: ScoreBreakdown,
+    /// Normalized to `configuration.squarings` if run was calibrated
     pub(crate) cpu_challenge_timings_in_milis: Vec<u128>,
+    /// Rate of each CPU round, squarings of `configuration.squarings` per second
+    #[serde(default)]
+    pub(crate) cpu_challenge_squarings_per_second: Vec<f64>,
+    /// Corrected for contention if `configuration.contention_correction` is set
     pub(crate) network_challenge_timings_in_milis: Vec<u128>,
+    /// Throughput of each network round, payload of `configuration.payload_size_kb` moved both ways
+    #[serde(default)]
+    pub(crate) network_challenge_throughput_in_mbps: Vec<f64>,
+    pub(crate) disk_challenge_timings_in_milis: Vec<u128>,
+    /// Time server spent verifying responses of each round, useful to spot
+    /// when verification and not the client is the bottleneck
+    pub(crate) cpu_verification_timings_in_micros: Vec<u128>,
+    pub(crate) network_verification_timings_in_micros: Vec<u128>,
+    pub(crate) disk_verification_timings_in_micros: Vec<u128>,
+    /// Round trip time of each latency probe
+    #[serde(default)]
+    pub(crate) latency_probe_timings_in_micros: Vec<u128>,
+    /// Time each download and upload challenge round took, payloads are `configuration.payload_size_kb`
+    #[serde(default)]
+    pub(crate) download_challenge_timings_in_milis: Vec<u128>,
+    #[serde(default)]
+    pub(crate) upload_challenge_timings_in_milis: Vec<u128>,
+    /// Transfers of other sessions which overlapped each network round, rounds with any
+    /// shared uplink of the server with them
+    #[serde(default)]
+    pub(crate) network_round_overlapping_transfers: Vec<usize>,
+    /// Arrival of each chunk of the response to each network round, in microseconds since the
+    /// round started, empty for rounds client answered in a single frame
+    #[serde(default)]
+    pub(crate) network_chunk_arrivals_in_micros: Vec<Vec<u128>>,
+    /// Size in bytes of each chunk of `network_chunk_arrivals_in_micros`
+    #[serde(default)]
+    pub(crate) network_chunk_bytes: Vec<Vec<usize>>,
+    /// Time of the CPU calibration round, `None` if run wasn't calibrated
+    #[serde(default)]
+    pub(crate) cpu_calibration_milliseconds: Option<u128>,
+    pub(crate) configuration: RunConfiguration,
+    pub(crate) priority_class: PriorityClass,
+    pub(crate) tags: BTreeMap<String, String>,
+    pub(crate) started_at: SystemTime,
+    pub(crate) events: Vec<SessionEvent>,
+    pub(crate) duration_breakdown: DurationBreakdown,
+    #[serde(default)]
+    pub(crate) private: bool,
+    #[serde(default)]
+    pub(crate) address_family: Option<AddressFamily>,
+    /// Kind of link client declared it was on, `None` if it declared none
+    #[serde(default)]
+    pub(crate) network_type: Option<NetworkType>,
+    /// Software version or deployment label client declared, `None` if it declared none
+    #[serde(default)]
+    pub(crate) client_version: Option<String>,
+    /// Run this one was paired with to compare IPv4 and IPv6 paths of the client
+    #[serde(default)]
+    pub(crate) dual_stack_of: Option<u128>,
+    /// Time TLS handshake of the connection took, `None` for runs measured in cleartext or over
+    /// TLS terminated in front of the server. Not part of network timings.
+    #[serde(default)]
+    pub(crate) tls_handshake_milliseconds: Option<u128>,
+    /// Run this one was paired with to find the TLS overhead of the client
+    #[serde(default)]
+    pub(crate) tls_comparison_of: Option<u128>,
+    /// Client identity whose history the run belongs to, `None` for runs measured without one
+    #[serde(default)]
+    pub(crate) identity: Option<u128>,
+    /// Losses of the datagram probes, `None` if run had none or client couldn't take part
+    #[serde(default)]
+    pub(crate) datagram: Option<DatagramStats>,
+    /// Deviations of the score from earlier runs, found once the run is stored
+    #[serde(default)]
+    pub(crate) anomalies: Vec<Anomaly>,
+    /// Rounds answered faster than the plan deems possible, which scored the run 0
+    #[serde(default)]
+    pub(crate) implausible_rounds: Vec<ImplausibleRound>,
+    /// Rounds tried again after a transient failure, each retry took points off the score
+    #[serde(default)]
+    pub(crate) retried_rounds: Vec<RetriedRound>,
+    /// Whether the session completed, runs of failed ones hold the rounds completed until then
+    #[serde(default)]
+    pub(crate) status: RunStatus,
+    /// Unannounced rounds sent between scheduled ones, see `PlanConfig::surprise_rounds`
+    #[serde(default)]
+    pub(crate) surprise_rounds: Vec<SurpriseRound>,
+    /// Mean rate of scheduled CPU rounds over that of surprise ones, `None` without either
+    #[serde(default)]
+    pub(crate) surprise_cpu_slowdown: Option<f64>,
+    /// Who the client authenticated as, `None` for runs measured without authentication
+    #[serde(default)]
+    pub(crate) principal: Option<String>,
+    /// Tenant whose namespace the run is stored in, `None` for runs of clients of no tenant
+    #[serde(default)]
+    pub(crate) tenant: Option<String>,
+    /// Digest of the tenant API key the run was measured with, which is served the run even if
+    /// private. `None` for runs of clients of no tenant.
+    #[serde(default)]
+    pub(crate) api_key_digest: Option<String>,
+}
+
+impl ClientData {
+    /// Client identity of the run, which `Storage::history` looks runs up by
+    pub fn identity(&self) -> Option<u128> {
+        self.identity
+    }
+
+    /// When the session started, `Storage::history` orders runs by it
+    pub fn started_at(&self) -> SystemTime {
+        self.started_at
+    }
+
+    /// Score of the run, within `0..=100`
+    pub fn score(&self) -> u128 {
+        self.score
+    }
+
+    /// Whether the session of the run completed. Partial runs are left out of statistics over
+    /// runs, as their scores don't measure the client the way completed ones do.
+    pub(crate) fn is_completed(&self) -> bool {
+        self.status == RunStatus::Completed
+    }
+
+    /// Tags the run is stored with, the client supplied ones unless a `SessionHook` changed them
+    pub fn tags(&self) -> &BTreeMap<String, String> {
+        &self.tags
+    }
+
+    pub fn tags_mut(&mut self) -> &mut BTreeMap<String, String> {
+        &mut self.tags
+    }
+
+    /// Aggregates of round trip times of the latency probes, `None` if run had none
+    pub(crate) fn latency(&self) -> Option<LatencyStats> {
+        let round_trips: Vec<u64> = self
+            .latency_probe_timings_in_micros
+            .iter()
+            .map(|round_trip| u64::try_from(*round_trip).unwrap_or(u64::MAX))
+            .collect();
+        LatencyStats::from_round_trips(&round_trips)
+    }
+
+    /// Throughput from server to client, `None` if run had no download rounds
+    pub(crate) fn download(&self) -> Option<ThroughputStats> {
+        self.throughput(&self.download_challenge_timings_in_milis)
+    }
+
+    /// Throughput from client to server, `None` if run had no upload rounds
+    pub(crate) fn upload(&self) -> Option<ThroughputStats> {
+        self.throughput(&self.upload_challenge_timings_in_milis)
+    }
+
+    /// Profile of each network round over time, `None` for rounds client didn't answer in chunks
+    pub(crate) fn network_profiles(&self) -> Vec<Option<TransferProfile>> {
+        self.network_chunk_arrivals_in_micros
+            .iter()
+            .zip(&self.network_chunk_bytes)
+            .map(|(arrivals, bytes)| {
+                let chunks: Vec<(u64, u64)> = arrivals
+                    .iter()
+                    .zip(bytes)
+                    .map(|(microseconds, bytes)| {
+                        (
+                            u64::try_from(*microseconds).unwrap_or(u64::MAX),
+                            *bytes as u64,
+                        )
+                    })
+                    .collect();
+                TransferProfile::from_chunks(
+                    &chunks,
+                    self.configuration.network_stall_milliseconds * 1000,
+                )
+            })
+            .collect()
+    }
+
+    fn throughput(&self, rounds: &[u128]) -> Option<ThroughputStats> {
+        ThroughputStats::new(
+            self.configuration.payload_size_kb as u64,
+            rounds
+                .iter()
+                .map(|round| u64::try_from(*round).unwrap_or(u64::MAX))
+                .collect(),
+        )
+    }
+}
+
+/// Which runs a storage query may return. Private runs still count toward
+/// fleet statistics and capacity estimates, which don't reveal individual runs.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Visibility {
+    /// Public runs only, for leaderboards and unauthenticated queries
+    Public,
+    /// Public and private runs
+    All,
+}
+
+impl Visibility {
+    pub fn allows(self, data: &ClientData) -> bool {
+        self == Visibility::All || !data.private
+    }
+}
+
+/// Where measured runs are kept, see `crate::storage` for the backends
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn insert(&self, client_id: u128, data: ClientData) -> anyhow::Result<()>;
+
+    async fn get(
+        &self,
+        client_id: u128,
+        visibility: Visibility,
+    ) -> anyhow::Result<Option<ClientData>>;
+
+    /// All stored runs with given visibility, keyed by client id
+    async fn runs(&self, visibility: Visibility) -> anyhow::Result<HashMap<u128, ClientData>>;
+
+    /// Runs of client `identity` with given visibility along with their ids, oldest first
+    async fn history(
+        &self,
+        identity: u128,
+        visibility: Visibility,
+    ) -> anyhow::Result<Vec<(u128, ClientData)>>;
+
+    async fn contains(&self, client_id: u128) -> anyhow::Result<bool> {
+        Ok(self.get(client_id, Visibility::All).await?.is_some())
+    }
+
+    /// Waits for writes in flight to be persisted, called before server exits
+    async fn flush(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Server wide state shared by all sessions
+#[derive(Clone)]
+pub struct ServerContext {
+    pub(crate) storage: Arc<dyn Storage>,
+    pub(crate) verification_pool: VerificationPool,
+    pub(crate) policy_hook: Option<Arc<PolicyHook>>,
+    pub(crate) certificate_signer: Option<Arc<CertificateSigner>>,
+    pub(crate) identity_provider: Option<Arc<IdentityProvider>>,
+    pub(crate) primes: Arc<PrimeSource>,
+    pub(crate) active_sessions: ActiveSessions,
+    /// Cap of concurrent sessions, `None` admits every session
+    pub(crate) session_limit: Option<SessionLimit>,
+    pub(crate) rate_limiter: Option<Arc<RateLimiter>>,
+    /// Set while server shuts down, new sessions are rejected
+    pub(crate) draining: Draining,
+    pub(crate) active_transfers: ActiveTransfers,
+    /// Largest payload and number of concurrent transfers the host can serve, `None` doesn't
+    /// limit them
+    pub(crate) host_limits: Option<HostLimits>,
+    /// Events of sessions in flight, for watchers of `/admin/ws`
+    pub(crate) session_progress: SessionProgress,
+    pub(crate) memory_watchdog: Option<Arc<MemoryWatchdog>>,
+    /// Retunes thresholds of the plan from recent runs, `None` keeps the configured ones
+    pub(crate) threshold_tuner: Option<Arc<ThresholdTuner>>,
+    pub(crate) tasks: TaskRegistry,
+    pub(crate) request_metrics: RequestMetrics,
+    /// Runs which couldn't be stored yet, retried in the background
+    pub(crate) pending_runs: PendingRuns,
+    /// Custom processing of sessions registered by the embedding application
+    pub(crate) session_hooks: Vec<Arc<dyn SessionHook>>,
+    /// Socket datagram probes are sent from, `None` skips them
+    pub(crate) datagram_endpoint: Option<Arc<DatagramEndpoint>>,
+    /// Side effects which failed, retried in the background
+    pub(crate) dead_letters: DeadLetterQueue,
+    /// Detector of runs deviating from earlier ones, `None` doesn't look for them
+    pub(crate) anomaly_detector: Option<Arc<AnomalyDetector>>,
+    /// Authentication of clients to be measured, `None` measures anyone
+    pub(crate) measurement_auth: Option<Arc<MeasurementAuth>>,
+    /// Publisher of session events, `None` doesn't publish them
+    #[cfg(feature = "event-bus")]
+    pub(crate) event_bus: Option<EventBus>,
 }
 
-pub(crate) type Storage = Arc<RwLock<HashMap<u128, ClientData>>>;
+impl ServerContext {
+    /// Context of a server of `config`, storing runs in the configured backend. Background tasks
+    /// of the server (memory watchdog, threshold tuning) are spawned, so it has to be called
+    /// within a tokio runtime.
+    pub fn new(config: &ServerConfig) -> anyhow::Result<Self> {
+        let storage = storage::open(&config.storage)
+            .map_err(|e| anyhow!("Unable to open storage: {:?}", e))?;
+        Self::with_storage(config, storage)
+    }
+
+    /// Context storing runs in `storage` instead of the configured backend
+    pub fn with_storage(config: &ServerConfig, storage: Arc<dyn Storage>) -> anyhow::Result<Self> {
+        let storage: Arc<dyn Storage> = match config.storage_chaos.clone() {
+            Some(storage_chaos_config) => {
+                warn!(
+                    "Injecting failures into storage: {:?}, this is not meant for production",
+                    storage_chaos_config
+                );
+                Arc::new(
+                    ChaosStorage::new(storage, storage_chaos_config)
+                        .map_err(|e| anyhow!("Invalid storage chaos: {:?}", e))?,
+                )
+            }
+            None => storage,
+        };
+        let verification_pool = VerificationPool::new(config.verification_threads)
+            .map_err(|e| anyhow!("Unable to start verification pool: {:?}", e))?;
+        let host_limits = if config.host_sizing.enabled {
+            let hash_algorithms = [HashAlgorithm::Blake3, HashAlgorithm::Sha256];
+            let benchmark = HostBenchmark::measure(config.host_sizing.sample_kb, &hash_algorithms);
+            let host_limits = HostLimits::new(
+                &benchmark,
+                &config.host_sizing,
+                &config.plan,
+                verification_pool.threads(),
+            );
+            info!(
+                "Host generates payloads at {:.0} MB/s and hashes them at {:.0} MB/s, serving payloads of up to {} KB in up to {} concurrent transfers",
+                benchmark.generation_mb_per_second,
+                benchmark.hashing_mb_per_second,
+                host_limits.max_payload_size_kb,
+                host_limits.max_concurrent_transfers
+            );
+            if config.plan.payload_size_kb > host_limits.max_payload_size_kb {
+                warn!(
+                    "Payloads of {} KB are more than the host can serve, sessions are measured with {} KB",
+                    config.plan.payload_size_kb, host_limits.max_payload_size_kb
+                );
+            }
+            Some(host_limits)
+        } else {
+            None
+        };
+        let tasks = TaskRegistry::default();
+        let pending_runs = PendingRuns::default();
+        pending_runs.spawn(&tasks, storage.clone());
+        let dead_letters = DeadLetterQueue::open(config.dead_letters.clone())
+            .map_err(|e| anyhow!("Unable to load dead letters: {:?}", e))?;
+        dead_letters.spawn(&tasks);
+        let anomaly_detector = match config.anomaly_detection.clone() {
+            Some(anomaly_detection_config) => Some(Arc::new(
+                AnomalyDetector::new(
+                    anomaly_detection_config,
+                    storage.clone(),
+                    dead_letters.clone(),
+                )
+                .map_err(|e| anyhow!("Invalid anomaly detection: {:?}", e))?,
+            )),
+            None => None,
+        };
+        #[cfg(feature = "event-bus")]
+        let event_bus = config.event_bus.clone().map(|event_bus_config| {
+            EventBus::spawn(event_bus_config, &tasks, dead_letters.clone())
+        });
+        let memory_watchdog = config
+            .memory_watchdog
+            .clone()
+            .map(|memory_watchdog_config| Arc::new(MemoryWatchdog::new(memory_watchdog_config)));
+        if let Some(memory_watchdog) = &memory_watchdog {
+            memory_watchdog.spawn(&tasks);
+        }
+        let threshold_tuner = match config.threshold_tuning.clone() {
+            Some(threshold_tuning_config) => Some(Arc::new(
+                ThresholdTuner::new(threshold_tuning_config, config.plan.clone())
+                    .map_err(|e| anyhow!("Unable to set up threshold tuning: {:?}", e))?,
+            )),
+            None => None,
+        };
+        if let Some(threshold_tuner) = &threshold_tuner {
+            threshold_tuner.spawn(&tasks, storage.clone());
+        }
+        let identity_provider = match config.oidc.clone() {
+            Some(oidc_config) => Some(Arc::new(
+                IdentityProvider::new(oidc_config)
+                    .map_err(|e| anyhow!("Unable to load identity provider keys: {:?}", e))?,
+            )),
+            None => None,
+        };
+        let measurement_auth = match config.measurement_auth.clone() {
+            Some(measurement_auth_config) => Some(Arc::new(
+                MeasurementAuth::new(measurement_auth_config, identity_provider.clone())
+                    .map_err(|e| anyhow!("Invalid measurement authentication: {:?}", e))?,
+            )),
+            None => None,
+        };
+        Ok(ServerContext {
+            storage,
+            verification_pool,
+            policy_hook: config
+                .policy_hook
+                .clone()
+                .map(|policy_hook_config| Arc::new(PolicyHook::new(policy_hook_config))),
+            certificate_signer: match &config.certificate {
+                Some(certificate_config) => Some(Arc::new(
+                    CertificateSigner::new(certificate_config)
+                        .map_err(|e| anyhow!("Unable to load certificate signing key: {:?}", e))?,
+                )),
+                None => None,
+            },
+            identity_provider,
+            primes: Arc::new(
+                PrimeSource::new(&config.primes)
+                    .map_err(|e| anyhow!("Unable to set up prime generation: {:?}", e))?,
+            ),
+            active_sessions: Default::default(),
+            session_limit: config.session_limit.clone().map(SessionLimit::new),
+            rate_limiter: config
+                .rate_limit
+                .clone()
+                .map(|rate_limit_config| Arc::new(RateLimiter::new(rate_limit_config))),
+            draining: Default::default(),
+            active_transfers: match &host_limits {
+                Some(host_limits) => ActiveTransfers::limited(host_limits.max_concurrent_transfers),
+                None => Default::default(),
+            },
+            host_limits,
+            session_progress: Default::default(),
+            memory_watchdog,
+            threshold_tuner,
+            tasks,
+            request_metrics: Default::default(),
+            pending_runs,
+            session_hooks: vec![],
+            datagram_endpoint: None,
+            dead_letters,
+            anomaly_detector,
+            measurement_auth,
+            #[cfg(feature = "event-bus")]
+            event_bus,
+        })
+    }
+
+    /// Registers `hook` to be invoked around every session, after the hooks registered before it
+    pub fn with_session_hook(mut self, hook: Arc<dyn SessionHook>) -> Self {
+        self.session_hooks.push(hook);
+        self
+    }
+
+    /// Sends datagram probes of the plan from `endpoint`, without one they are skipped
+    pub fn with_datagram_endpoint(mut self, endpoint: Arc<DatagramEndpoint>) -> Self {
+        self.datagram_endpoint = Some(endpoint);
+        self
+    }
+}
 
 pub(crate) type WsMessage = warp::ws::Message;
+
+#[cfg(test)]
+pub(crate) mod test_utils {
+    use crate::primes::PrimeSource;
+    use crate::storage::MemoryStorage;
+    use crate::types::{
+        ClientData, PriorityClass, RunConfiguration, RunStatus, ServerContext, SessionClock,
+    };
+    use crate::verification::VerificationPool;
+    use shared::hash::HashAlgorithm;
+    use shared::ScoreBreakdown;
+    use std::sync::Arc;
+
+    /// Context with empty in-memory storage and a single verification thread
+    pub(crate) fn server_context() -> ServerContext {
+        ServerContext {
+            storage: Arc::new(MemoryStorage::default()),
+            verification_pool: VerificationPool::new(Some(1)).unwrap(),
+            policy_hook: None,
+            certificate_signer: None,
+            identity_provider: None,
+            primes: Arc::new(PrimeSource::new(&Default::default()).unwrap()),
+            active_sessions: Default::default(),
+            session_limit: None,
+            rate_limiter: None,
+            draining: Default::default(),
+            active_transfers: Default::default(),
+            host_limits: None,
+            session_progress: Default::default(),
+            memory_watchdog: None,
+            threshold_tuner: None,
+            tasks: Default::default(),
+            request_metrics: Default::default(),
+            pending_runs: Default::default(),
+            session_hooks: vec![],
+            datagram_endpoint: None,
+            dead_letters: Default::default(),
+            anomaly_detector: None,
+            measurement_auth: None,
+            #[cfg(feature = "event-bus")]
+            event_bus: None,
+        }
+    }
+
+    /// Run with a single round of each challenge and no events
+    pub(crate) fn client_data(score: u128) -> ClientData {
+        let clock = SessionClock::start();
+        ClientData {
+            score,
+            score_breakdown: ScoreBreakdown {
+                total: score as f64,
+                ..Default::default()
+            },
+            cpu_challenge_timings_in_milis: vec![100],
+            cpu_challenge_squarings_per_second: vec![100.0],
+            network_challenge_timings_in_milis: vec![200],
+            network_challenge_throughput_in_mbps: vec![0.08192],
+            disk_challenge_timings_in_milis: vec![],
+            cpu_verification_timings_in_micros: vec![1],
+            network_verification_timings_in_micros: vec![2],
+            disk_verification_timings_in_micros: vec![],
+            latency_probe_timings_in_micros: vec![],
+            download_challenge_timings_in_milis: vec![],
+            upload_challenge_timings_in_milis: vec![],
+            network_round_overlapping_transfers: vec![],
+            network_chunk_arrivals_in_micros: vec![],
+            network_chunk_bytes: vec![],
+            cpu_calibration_milliseconds: None,
+            configuration: RunConfiguration {
+                plan_name: "default".to_owned(),
+                cpu_rounds: 1,
+                squarings: 10,
+                cpu_ideal_milliseconds: 100,
+                cpu_max_milliseconds: 1000,
+                cpu_aggregation: Default::default(),
+                cpu_ideal_squarings_per_second: None,
+                cpu_min_squarings_per_second: None,
+                calibrated_squarings: None,
+                network_rounds: 1,
+                payload_size_kb: 1,
+                payload_scaled_down_from_kb: None,
+                network_ideal_milliseconds: 200,
+                network_max_milliseconds: 2000,
+                network_aggregation: Default::default(),
+                network_ideal_mbps: None,
+                network_min_mbps: None,
+                spot_check_sample_chunks: None,
+                contention_correction: false,
+                disk_rounds: 0,
+                disk_payload_size_kb: 0,
+                disk_ideal_milliseconds: 0,
+                disk_max_milliseconds: 0,
+                disk_aggregation: Default::default(),
+                scoring: Default::default(),
+                strictness: Default::default(),
+                latency_probes: 0,
+                download_rounds: 0,
+                upload_rounds: 0,
+                datagram_probes: 0,
+                hash_algorithm: HashAlgorithm::Sha256,
+                max_frame_size_kb: None,
+                network_stall_milliseconds: 1000,
+                encoding: "messagepack".to_owned(),
+                transport: "websocket".to_owned(),
+            },
+            priority_class: PriorityClass::Normal,
+            tags: Default::default(),
+            started_at: clock.started_at(),
+            events: vec![],
+            duration_breakdown: Default::default(),
+            private: false,
+            address_family: None,
+            network_type: None,
+            client_version: None,
+            dual_stack_of: None,
+            tls_handshake_milliseconds: None,
+            tls_comparison_of: None,
+            identity: None,
+            datagram: None,
+            anomalies: vec![],
+            implausible_rounds: vec![],
+            retried_rounds: vec![],
+            status: RunStatus::Completed,
+            surprise_rounds: vec![],
+            surprise_cpu_slowdown: None,
+            principal: None,
+            tenant: None,
+            api_key_digest: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::types::AddressFamily;
+
+    #[test]
+    fn test_address_family() {
+        let family = |address: &str| AddressFamily::of(&address.parse().unwrap());
+        assert_eq!(family("192.0.2.1:80"), AddressFamily::Ipv4);
+        assert_eq!(family("[::ffff:192.0.2.1]:80"), AddressFamily::Ipv4);
+        assert_eq!(family("[2001:db8::1]:80"), AddressFamily::Ipv6);
+    }
+}
@@ -4,5 +4,10 @@ macro_rules! err {
 }
 
 pub mod network;
+pub mod routing;
 
-pub(crate) use network::send_client_msg_with_profiling;
+pub(crate) use network::{
+    discard_late_response, is_disconnection, is_transient, receive_client_msg,
+    send_chunked_client_msg_with_profiling, send_client_msg_with_profiling, ConnectionLost,
+    ProtocolViolation, ResponseTimedOut, MAX_CHUNKED_MESSAGE_BYTES,
+};
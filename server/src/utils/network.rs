@@ -1,60 +1,269 @@
-use crate::types::WsMessage;
-use shared::{Data, Message};
+use crate::transport::Transport;
+use shared::chunks::{self, ChunkAssembler};
+use shared::{Data, Message, Response};
 
 use anyhow::{anyhow, Result};
 use futures::stream::{SplitSink, SplitStream};
 use futures::{SinkExt, StreamExt};
-use std::time::Instant;
-use warp::ws::WebSocket;
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::time::{Duration, Instant};
+
+/// Returned when client did not respond to a message in time
+#[derive(Debug, PartialEq)]
+pub(crate) struct ResponseTimedOut {
+    pub(crate) timeout: Duration,
+}
+
+impl Display for ResponseTimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "No response within {} milliseconds",
+            self.timeout.as_millis()
+        )
+    }
+}
+
+impl Error for ResponseTimedOut {}
+
+/// Returned when client didn't answer a keepalive ping in time, its connection is gone without
+/// having been closed
+#[derive(Debug, PartialEq)]
+pub(crate) struct ConnectionLost {
+    pub(crate) timeout: Duration,
+}
+
+impl Display for ConnectionLost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Ping not answered within {} milliseconds",
+            self.timeout.as_millis()
+        )
+    }
+}
+
+impl Error for ConnectionLost {}
+
+/// Returned when the stream of the client ended while its response was awaited
+#[derive(Debug, PartialEq)]
+pub(crate) struct ConnectionClosed;
+
+impl Display for ConnectionClosed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Can't read client response, the stream was closed")
+    }
+}
+
+impl Error for ConnectionClosed {}
+
+/// Returned when client sent a message it wasn't expected to send
+#[derive(Debug, PartialEq)]
+pub(crate) struct ProtocolViolation(pub(crate) String);
+
+impl Display for ProtocolViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for ProtocolViolation {}
+
+/// Returned when client answered with an error or with a frame which can't be decoded. Its
+/// connection is still usable, unlike after most other errors.
+#[derive(Debug, PartialEq)]
+pub(crate) struct UnusableResponse(pub(crate) String);
+
+impl Display for UnusableResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for UnusableResponse {}
+
+/// Whether a round which failed with `e` may be tried again over the same connection: client
+/// stalled or answered with something unusable, but the connection didn't fail
+pub(crate) fn is_transient(e: &anyhow::Error) -> bool {
+    e.is::<ResponseTimedOut>() || e.is::<UnusableResponse>()
+}
+
+/// Whether `e` means client is gone, its stream ended or it stopped answering pings
+pub(crate) fn is_disconnection(e: &anyhow::Error) -> bool {
+    e.is::<ConnectionClosed>() || e.is::<ConnectionLost>()
+}
+
+/// Largest message reassembled from chunks when session has no memory limit,
+/// same as the largest websocket message accepted by default
+pub(crate) const MAX_CHUNKED_MESSAGE_BYTES: usize = 64 << 20;
 
 /// Send client challenge message and waits for response
 /// Time taken by client to respond to message is recorded.
 /// If `profile_roundtrip_time` is true then the time measurement
 /// starts before sending message, otherwise it starts after
 /// message is sent.
-pub(crate) async fn send_client_msg_with_profiling(
-    write_half: &mut SplitSink<WebSocket, WsMessage>,
-    read_half: &mut SplitStream<WebSocket>,
+/// Fails with `ResponseTimedOut` if sending the message and receiving
+/// the response takes longer than `timeout`, so a stalled client can't hold the session.
+pub(crate) async fn send_client_msg_with_profiling<T: Transport>(
+    write_half: &mut SplitSink<T, Vec<u8>>,
+    read_half: &mut SplitStream<T>,
     bytes: &[u8],
     profile_roundtrip_time: bool,
+    timeout: Duration,
 ) -> Result<(Message, u128)> {
-    let instant: Instant;
-    let msg = WsMessage::binary(bytes);
+    let (msg, time_elapsed, _) = exchange(
+        write_half,
+        read_half,
+        vec![bytes.to_vec()],
+        profile_roundtrip_time,
+        timeout,
+        MAX_CHUNKED_MESSAGE_BYTES,
+    )
+    .await?;
+    Ok((msg, time_elapsed))
+}
 
-    if profile_roundtrip_time {
-        instant = Instant::now();
-        write_half.send(msg).await?;
-    } else {
-        write_half.send(msg).await?;
-        instant = Instant::now();
-    }
+/// Same as `send_client_msg_with_profiling`, but a message larger than `max_frame_bytes` is sent
+/// as `Message::ChallengeChunk` messages. Along with the response and time elapsed, returns
+/// arrival, in microseconds since the time measurement started, and size in bytes of each chunk
+/// of a response client sent in chunks. Chunked responses are refused once they exceed `max_message_bytes`.
+pub(crate) async fn send_chunked_client_msg_with_profiling<T: Transport>(
+    write_half: &mut SplitSink<T, Vec<u8>>,
+    read_half: &mut SplitStream<T>,
+    bytes: Vec<u8>,
+    max_frame_bytes: Option<usize>,
+    profile_roundtrip_time: bool,
+    timeout: Duration,
+    max_message_bytes: usize,
+) -> Result<(Message, u128, Vec<(u128, usize)>)> {
+    let frames = match max_frame_bytes {
+        Some(max_frame_bytes) => chunks::split(bytes, max_frame_bytes)?,
+        None => vec![bytes],
+    };
+    exchange(
+        write_half,
+        read_half,
+        frames,
+        profile_roundtrip_time,
+        timeout,
+        max_message_bytes,
+    )
+    .await
+}
+
+/// Waits for the next message of the client without sending one, e.g. for the response to a
+/// challenge measured outside of the session's connection
+pub(crate) async fn receive_client_msg<T: Transport>(
+    write_half: &mut SplitSink<T, Vec<u8>>,
+    read_half: &mut SplitStream<T>,
+    timeout: Duration,
+) -> Result<Message> {
+    let (msg, _, _) = exchange(
+        write_half,
+        read_half,
+        vec![],
+        false,
+        timeout,
+        MAX_CHUNKED_MESSAGE_BYTES,
+    )
+    .await?;
+    Ok(msg)
+}
 
-    let response = read_half
-        .next()
+/// Reads and drops the response client still owes to a message whose exchange failed with `e`,
+/// so a round tried again isn't answered with the late response to its failed attempt. Client
+/// which timed out gets as long again to send it, the rest of a chunked response included, and
+/// fails with `ResponseTimedOut` if it doesn't. Nothing is owed after other failures.
+pub(crate) async fn discard_late_response<T: Transport>(
+    read_half: &mut SplitStream<T>,
+    e: &anyhow::Error,
+) -> Result<()> {
+    let timeout = match e.downcast_ref::<ResponseTimedOut>() {
+        Some(timed_out) => timed_out.timeout,
+        None => return Ok(()),
+    };
+    let discard = async {
+        loop {
+            let response = read_half.next().await.ok_or(ConnectionClosed)??;
+            match Message::decode(&response) {
+                Ok(Message::ChallengeChunk { index, total, .. }) if index + 1 < total => {}
+                _ => return Ok::<_, anyhow::Error>(()),
+            }
+        }
+    };
+    tokio::time::timeout(timeout, discard)
         .await
-        .ok_or_else(|| anyhow!("Can't read client response, the stream was closed"))?
-        .map_err(|e| anyhow!("Error reading from stream: {:?}", e))?;
+        .map_err(|_| ResponseTimedOut { timeout })?
+}
 
-    let time_elapsed = instant.elapsed().as_millis();
+async fn exchange<T: Transport>(
+    write_half: &mut SplitSink<T, Vec<u8>>,
+    read_half: &mut SplitStream<T>,
+    frames: Vec<Vec<u8>>,
+    profile_roundtrip_time: bool,
+    timeout: Duration,
+    max_message_bytes: usize,
+) -> Result<(Message, u128, Vec<(u128, usize)>)> {
+    let exchange = async {
+        let mut instant = Instant::now();
+        for frame in frames {
+            write_half.send(frame).await?;
+        }
+        if !profile_roundtrip_time {
+            instant = Instant::now();
+        }
 
-    if !response.is_binary() {
-        return Err(anyhow!(
-            "Wrong message format, expected to be a binary data"
-        ));
-    }
+        let mut assembler = ChunkAssembler::new(max_message_bytes);
+        let mut chunk_arrivals = Vec::new();
+        loop {
+            let response = read_half.next().await.ok_or(ConnectionClosed)??;
+            let time_elapsed = instant.elapsed();
+
+            let response = Message::decode(&response).map_err(|e| {
+                UnusableResponse(format!("Can't decode response of the client: {}", e))
+            })?;
+            match response {
+                Message::ChallengeChunk { index, total, data } => {
+                    chunk_arrivals.push((time_elapsed.as_micros(), data.len()));
+                    if let Some(msg) = assembler
+                        .push(index, total, &data)
+                        .map_err(|e| ProtocolViolation(e.to_string()))?
+                    {
+                        return Ok((msg, time_elapsed.as_millis(), chunk_arrivals));
+                    }
+                }
+                msg => {
+                    return Ok::<_, anyhow::Error>((msg, time_elapsed.as_millis(), chunk_arrivals))
+                }
+            }
+        }
+    };
+    let (msg, time_elapsed, chunk_arrivals) = tokio::time::timeout(timeout, exchange)
+        .await
+        .map_err(|_| ResponseTimedOut { timeout })??;
 
-    let msg = Message::decode(response.as_bytes())?;
     let client_error = match &msg {
         &Message::Data(ref data) => match data {
-            Data::Error(e) => Some(anyhow!(format!("Client returned an error: {}", e))),
+            Data::Error { code, message } => Some(
+                UnusableResponse(format!(
+                    "Client returned an error ({:?}): {}",
+                    code, message
+                ))
+                .into(),
+            ),
             _ => None,
         },
+        &Message::Response(Response::UnsupportedChallenge { kind_id }) => Some(anyhow!(
+            "Client does not support challenge of kind {}",
+            kind_id
+        )),
         _ => None,
     };
 
     if client_error.is_some() {
         Err(client_error.unwrap())
     } else {
-        Ok((msg, time_elapsed))
+        Ok((msg, time_elapsed, chunk_arrivals))
     }
 }
@@ -0,0 +1,54 @@
+use crate::tls::TlsConnection;
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use warp::filters::BoxedFilter;
+use warp::Filter;
+
+/// Filter matching `base_path` (e.g. `/reliability/v1`) at the start of the request path,
+/// so that all routes can be mounted under a common prefix. Empty `base_path` matches any request.
+pub(crate) fn base_path(base_path: &str) -> BoxedFilter<()> {
+    base_path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .fold(warp::any().boxed(), |filter, segment| {
+            filter.and(warp::path(segment.to_owned())).boxed()
+        })
+}
+
+/// Remote address of the connection. Warp doesn't know it for connections server terminated TLS
+/// of, they carry it in `TlsConnection` instead.
+pub(crate) fn remote_address(
+) -> impl Filter<Extract = (Option<SocketAddr>,), Error = Infallible> + Clone {
+    warp::addr::remote().and(warp::ext::optional()).map(
+        |remote: Option<SocketAddr>, connection: Option<TlsConnection>| {
+            remote.or_else(|| connection.map(|connection| connection.remote_addr))
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utils::routing::base_path;
+    use warp::Filter;
+
+    #[tokio::test]
+    async fn test_base_path() {
+        let filter = base_path("/reliability/v1/")
+            .and(warp::path!("ws"))
+            .map(warp::reply);
+
+        let response = warp::test::request()
+            .path("/reliability/v1/ws")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+
+        let response = warp::test::request().path("/ws").reply(&filter).await;
+        assert_eq!(response.status(), 404);
+
+        let filter = base_path("").and(warp::path!("ws")).map(warp::reply);
+        let response = warp::test::request().path("/ws").reply(&filter).await;
+        assert_eq!(response.status(), 200);
+    }
+}
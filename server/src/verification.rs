@@ -0,0 +1,219 @@
+use anyhow::{anyhow, Result};
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::oneshot;
+
+use crate::types::PriorityClass;
+
+struct PrioritizedTask {
+    priority: PriorityClass,
+    // Used to keep tasks of the same priority in FIFO order
+    sequence: u64,
+    task: Box<dyn FnOnce() + Send>,
+}
+
+impl PartialEq for PrioritizedTask {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for PrioritizedTask {}
+
+impl PartialOrd for PrioritizedTask {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for PrioritizedTask {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+/// Time a task spent on the pool
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct TaskTiming {
+    /// Time spent waiting for a free thread
+    pub(crate) queued_microseconds: u128,
+    /// Time spent doing the work itself
+    pub(crate) running_microseconds: u128,
+}
+
+impl TaskTiming {
+    pub(crate) fn total_microseconds(&self) -> u128 {
+        self.queued_microseconds + self.running_microseconds
+    }
+}
+
+/// Thread pool for CPU heavy work (hashing, big number comparisons, puzzle generation).
+/// Running this work here instead of on the async runtime keeps sessions responsive
+/// when many clients are being measured at the same time.
+/// Whenever a thread becomes free it picks the pending task of the highest priority class.
+#[derive(Clone)]
+pub(crate) struct VerificationPool {
+    pool: Arc<ThreadPool>,
+    pending: Arc<Mutex<BinaryHeap<PrioritizedTask>>>,
+    sequence: Arc<AtomicU64>,
+}
+
+impl VerificationPool {
+    pub(crate) fn new(threads: Option<usize>) -> Result<Self> {
+        let mut builder = ThreadPoolBuilder::new().thread_name(|i| format!("verifier-{}", i));
+        if let Some(threads) = threads {
+            builder = builder.num_threads(threads);
+        }
+
+        Ok(VerificationPool {
+            pool: Arc::new(
+                builder
+                    .build()
+                    .map_err(|e| anyhow!("Unable to build verification pool: {:?}", e))?,
+            ),
+            pending: Default::default(),
+            sequence: Default::default(),
+        })
+    }
+
+    /// Runs `work` on the pool and returns its result along with
+    /// time it spent waiting in the pool queue and running.
+    pub(crate) async fn run<F, T>(
+        &self,
+        priority: PriorityClass,
+        work: F,
+    ) -> Result<(T, TaskTiming)>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let start = Instant::now();
+
+        self.pending.lock().unwrap().push(PrioritizedTask {
+            priority,
+            sequence: self.sequence.fetch_add(1, AtomicOrdering::SeqCst),
+            task: Box::new(move || {
+                let queued_microseconds = start.elapsed().as_micros();
+                let started = Instant::now();
+                let result = work();
+                let timing = TaskTiming {
+                    queued_microseconds,
+                    running_microseconds: started.elapsed().as_micros(),
+                };
+                // Receiver is gone only if session was dropped, nothing to report in that case
+                let _ = sender.send((result, timing));
+            }),
+        });
+
+        // Every spawned job runs exactly one task, but not necessarily the one pushed above
+        let pending = self.pending.clone();
+        self.pool.spawn(move || {
+            let task = pending.lock().unwrap().pop();
+            if let Some(task) = task {
+                (task.task)();
+            }
+        });
+
+        receiver
+            .await
+            .map_err(|_| anyhow!("Verification task was cancelled"))
+    }
+
+    /// Number of threads of the pool
+    pub(crate) fn threads(&self) -> usize {
+        self.pool.current_num_threads()
+    }
+
+    /// Number of tasks waiting for a free thread
+    pub(crate) fn backlog(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    /// Runs `verification` on the pool, see `run`.
+    pub(crate) async fn verify<F, T>(
+        &self,
+        priority: PriorityClass,
+        verification: F,
+    ) -> Result<(T, TaskTiming)>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.run(priority, verification).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::types::PriorityClass;
+    use crate::verification::VerificationPool;
+    use std::sync::mpsc;
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn test_verification_pool() {
+        let pool = VerificationPool::new(Some(2)).unwrap();
+        let (verified, _) = pool.verify(PriorityClass::Normal, || true).await.unwrap();
+        assert!(verified);
+        let (verified, _) = pool.verify(PriorityClass::Normal, || false).await.unwrap();
+        assert!(!verified);
+    }
+
+    #[tokio::test]
+    async fn test_task_timing() {
+        let pool = VerificationPool::new(Some(1)).unwrap();
+        let (_, timing) = pool
+            .run(PriorityClass::Normal, || {
+                std::thread::sleep(std::time::Duration::from_millis(10))
+            })
+            .await
+            .unwrap();
+        assert!(timing.running_microseconds >= 10_000);
+        assert!(timing.total_microseconds() >= timing.running_microseconds);
+    }
+
+    #[tokio::test]
+    async fn test_verification_pool_priority() {
+        let pool = VerificationPool::new(Some(1)).unwrap();
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        // Keep the only thread busy until all other tasks are queued
+        let (unblock, blocked) = mpsc::channel::<()>();
+        let blocker = pool.run(PriorityClass::High, move || blocked.recv().unwrap());
+
+        let tasks = [
+            PriorityClass::BestEffort,
+            PriorityClass::Normal,
+            PriorityClass::High,
+            PriorityClass::Normal,
+        ]
+        .iter()
+        .enumerate()
+        .map(|(i, priority)| {
+            let order = order.clone();
+            pool.run(*priority, move || order.lock().unwrap().push(i))
+        })
+        .collect::<Vec<_>>();
+
+        // Polled after all tasks were queued
+        let unblocker = async move {
+            unblock.send(()).unwrap();
+        };
+
+        let (blocker_result, task_results, _) =
+            futures::join!(blocker, futures::future::join_all(tasks), unblocker);
+        blocker_result.unwrap();
+        for result in task_results {
+            result.unwrap();
+        }
+
+        assert_eq!(*order.lock().unwrap(), vec![2, 1, 3, 0]);
+    }
+}
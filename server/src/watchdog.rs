@@ -0,0 +1,144 @@
+use crate::tasks::{TaskKind, TaskRegistry};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::cmp;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Limits of resident memory of the server process
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct MemoryWatchdogConfig {
+    /// Above this, payloads of new sessions are scaled down
+    pub(crate) soft_limit_bytes: usize,
+    /// Above this, new sessions are not admitted
+    pub(crate) hard_limit_bytes: usize,
+    #[serde(
+        rename = "poll_interval_milliseconds",
+        deserialize_with = "crate::config::milliseconds"
+    )]
+    pub(crate) poll_interval: Duration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum MemoryPressure {
+    Normal,
+    Soft,
+    Hard,
+}
+
+/// Periodically samples resident memory of the process, so that server sheds load
+/// before the OOM killer takes it down in the middle of measurements.
+pub(crate) struct MemoryWatchdog {
+    config: MemoryWatchdogConfig,
+    rss_bytes: AtomicUsize,
+}
+
+impl MemoryWatchdog {
+    pub(crate) fn new(config: MemoryWatchdogConfig) -> Self {
+        MemoryWatchdog {
+            config,
+            rss_bytes: AtomicUsize::new(0),
+        }
+    }
+
+    /// Samples resident memory every `poll_interval` for as long as the server runs
+    pub(crate) fn spawn(self: &Arc<Self>, tasks: &TaskRegistry) {
+        let watchdog = self.clone();
+        tasks.spawn(TaskKind::Background, "memory watchdog", async move {
+            let mut interval = tokio::time::interval(watchdog.config.poll_interval);
+            loop {
+                interval.tick().await;
+                match resident_memory_bytes() {
+                    Ok(rss_bytes) => watchdog.record(rss_bytes),
+                    Err(e) => warn!("Unable to sample resident memory: {:?}", e),
+                }
+            }
+        });
+    }
+
+    pub(crate) fn record(&self, rss_bytes: usize) {
+        let previous = self.pressure();
+        self.rss_bytes.store(rss_bytes, Ordering::SeqCst);
+        let current = self.pressure();
+        if current != previous {
+            warn!(
+                "Memory pressure changed from {:?} to {:?}, resident memory is {} bytes",
+                previous, current, rss_bytes
+            );
+        }
+    }
+
+    /// Pressure only changes once memory is sampled again
+    pub(crate) fn poll_interval(&self) -> Duration {
+        self.config.poll_interval
+    }
+
+    pub(crate) fn pressure(&self) -> MemoryPressure {
+        let rss_bytes = self.rss_bytes.load(Ordering::SeqCst);
+        if rss_bytes > self.config.hard_limit_bytes {
+            MemoryPressure::Hard
+        } else if rss_bytes > self.config.soft_limit_bytes {
+            MemoryPressure::Soft
+        } else {
+            MemoryPressure::Normal
+        }
+    }
+
+    /// Payload size for a new session. Between the soft and the hard limit payload
+    /// shrinks linearly from `payload_size_kb` down to a quarter of it.
+    pub(crate) fn payload_size_kb(&self, payload_size_kb: usize) -> usize {
+        let rss_bytes = self.rss_bytes.load(Ordering::SeqCst);
+        if rss_bytes <= self.config.soft_limit_bytes {
+            return payload_size_kb;
+        }
+        let range = cmp::max(
+            self.config.hard_limit_bytes - self.config.soft_limit_bytes,
+            1,
+        );
+        let over = cmp::min(rss_bytes - self.config.soft_limit_bytes, range);
+        let reduction = (payload_size_kb * 3 / 4) as u128 * over as u128 / range as u128;
+        cmp::max(payload_size_kb - reduction as usize, 1)
+    }
+}
+
+/// Resident set size of the current process, as reported by `/proc/self/status`
+fn resident_memory_bytes() -> Result<usize> {
+    let status = std::fs::read_to_string("/proc/self/status")?;
+    status
+        .lines()
+        .find(|line| line.starts_with("VmRSS:"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|kilobytes| kilobytes.parse::<usize>().ok())
+        .map(|kilobytes| kilobytes * 1024)
+        .ok_or_else(|| anyhow!("VmRSS is missing from /proc/self/status"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::watchdog::{MemoryPressure, MemoryWatchdog, MemoryWatchdogConfig};
+    use std::time::Duration;
+
+    #[test]
+    fn test_memory_watchdog() {
+        let watchdog = MemoryWatchdog::new(MemoryWatchdogConfig {
+            soft_limit_bytes: 1000,
+            hard_limit_bytes: 2000,
+            poll_interval: Duration::from_secs(1),
+        });
+
+        watchdog.record(500);
+        assert_eq!(watchdog.pressure(), MemoryPressure::Normal);
+        assert_eq!(watchdog.payload_size_kb(1024), 1024);
+
+        watchdog.record(1500);
+        assert_eq!(watchdog.pressure(), MemoryPressure::Soft);
+        assert_eq!(watchdog.payload_size_kb(1024), 640);
+
+        watchdog.record(2500);
+        assert_eq!(watchdog.pressure(), MemoryPressure::Hard);
+        assert_eq!(watchdog.payload_size_kb(1024), 256);
+    }
+}
@@ -0,0 +1,398 @@
+//! Challenges measuring each direction of the link on its own, unlike `Roundtrip` whose time
+//! is the sum of both directions. Asymmetric links (e.g. most residential ones) upload much
+//! slower than they download, which a roundtrip can't tell apart from a slow link.
+
+#[cfg(feature = "std")]
+use crate::challenges::{ChallengeGenerator, ResponseVerifier};
+use crate::hash::HashAlgorithm;
+use crate::nonce;
+#[cfg(feature = "std")]
+use crate::std_alloc::Box;
+use crate::std_alloc::Vec;
+#[cfg(feature = "std")]
+use crate::{kinds, Challenge, Response};
+use anyhow::{anyhow, Result};
+use byteorder::{ByteOrder, NetworkEndian};
+use serde_derive::{Deserialize, Serialize};
+
+#[cfg(feature = "std")]
+use rand::RngCore;
+
+const SEED_SIZE: usize = 32;
+
+/// Bulk data sent by server, client returns only its hash
+/// calculated using the algorithm negotiated during the handshake.
+pub struct Download {
+    data: Vec<u8>,
+}
+
+impl Download {
+    /// Generate new data using RNG provided, verifier contains hash of `nonce` followed by
+    /// the data calculated using `algorithm`
+    #[cfg(feature = "std")]
+    pub fn generate<RNG>(
+        rng: &mut RNG,
+        size_in_kbs: usize,
+        algorithm: HashAlgorithm,
+        nonce: &[u8],
+    ) -> (Self, DigestVerifier)
+    where
+        RNG: RngCore,
+    {
+        let mut data = vec![0u8; size_in_kbs * 1024];
+        rng.fill_bytes(&mut data);
+
+        let hash = nonce::bound_digest(algorithm, nonce, &data);
+        (Download { data }, DigestVerifier { hash })
+    }
+
+    /// Hash client responds with
+    pub fn perform_challenge(&self, algorithm: HashAlgorithm, nonce: &[u8]) -> Vec<u8> {
+        nonce::bound_digest(algorithm, nonce, &self.data)
+    }
+
+    /// Serializes the data
+    /// Since there is no processing involved we are consuming `self` and returning inner data
+    pub fn to_wire(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// Deserializes the data
+    /// Consumes `data` argument and returns Download
+    pub fn from_wire(data: Vec<u8>) -> Self {
+        Self { data }
+    }
+}
+
+/// Seed and size of data client has to generate locally and upload, so that only
+/// a few bytes travel from server to client
+pub struct Upload {
+    seed: [u8; SEED_SIZE],
+    size_in_kbs: u32,
+}
+
+impl Upload {
+    /// Generate new seed using RNG provided, verifier contains hash of the data client
+    /// must upload for `nonce`, calculated using `algorithm`
+    #[cfg(feature = "std")]
+    pub fn generate<RNG>(
+        rng: &mut RNG,
+        size_in_kbs: u32,
+        algorithm: HashAlgorithm,
+        nonce: &[u8],
+    ) -> (Self, UploadVerifier)
+    where
+        RNG: RngCore,
+    {
+        let mut seed = [0u8; SEED_SIZE];
+        rng.fill_bytes(&mut seed);
+        let upload = Upload { seed, size_in_kbs };
+
+        let hash = algorithm.digest(&upload.perform_challenge(nonce));
+        (upload, UploadVerifier { algorithm, hash })
+    }
+
+    /// Data client uploads, expanded from the seed followed by `nonce` with BLAKE3 in
+    /// extendable output mode
+    pub fn perform_challenge(&self, nonce: &[u8]) -> Vec<u8> {
+        let mut data = vec![0u8; self.size_in_kbs as usize * 1024];
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&self.seed);
+        hasher.update(nonce);
+        hasher.finalize_xof().fill(&mut data);
+        data
+    }
+
+    /// Serialized form is seed followed by size in kilobytes
+    pub fn to_wire(&self) -> Vec<u8> {
+        let mut result = vec![0u8; SEED_SIZE + 4];
+        result[..SEED_SIZE].copy_from_slice(&self.seed);
+        NetworkEndian::write_u32(&mut result[SEED_SIZE..], self.size_in_kbs);
+        result
+    }
+
+    pub fn from_wire(data: Vec<u8>) -> Result<Self> {
+        if data.len() != SEED_SIZE + 4 {
+            return Err(anyhow!("unable to parse wire data"));
+        }
+        let mut seed = [0u8; SEED_SIZE];
+        seed.copy_from_slice(&data[..SEED_SIZE]);
+        Ok(Upload {
+            seed,
+            size_in_kbs: NetworkEndian::read_u32(&data[SEED_SIZE..]),
+        })
+    }
+}
+
+/// Checks hash returned by the client
+#[cfg(feature = "std")]
+pub struct DigestVerifier {
+    hash: Vec<u8>,
+}
+
+#[cfg(feature = "std")]
+impl DigestVerifier {
+    pub fn verify(&self, client_hash: Vec<u8>) -> bool {
+        client_hash == self.hash
+    }
+}
+
+/// Checks data uploaded by the client, only its hash is kept between generation and verification
+#[cfg(feature = "std")]
+pub struct UploadVerifier {
+    algorithm: HashAlgorithm,
+    hash: Vec<u8>,
+}
+
+#[cfg(feature = "std")]
+impl UploadVerifier {
+    pub fn verify(&self, uploaded: Vec<u8>) -> bool {
+        self.algorithm.digest(&uploaded) == self.hash
+    }
+}
+
+#[cfg(feature = "std")]
+impl ResponseVerifier for DigestVerifier {
+    fn verify(self: Box<Self>, response: Response) -> bool {
+        match response {
+            Response::DownloadChallengeResponse(client_hash) => client_hash == self.hash,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl ResponseVerifier for UploadVerifier {
+    fn verify(self: Box<Self>, response: Response) -> bool {
+        match response {
+            Response::UploadChallengeResponse(uploaded) => {
+                self.algorithm.digest(&uploaded) == self.hash
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Download challenges of `size_in_kbs` of data
+#[cfg(feature = "std")]
+pub struct DownloadGenerator {
+    pub size_in_kbs: usize,
+}
+
+#[cfg(feature = "std")]
+impl ChallengeGenerator for DownloadGenerator {
+    fn kind_id(&self) -> u16 {
+        kinds::challenge::DOWNLOAD_CHALLENGE
+    }
+
+    fn name(&self) -> &'static str {
+        "Download"
+    }
+
+    fn generate(
+        &self,
+        mut rng: &mut dyn RngCore,
+        algorithm: HashAlgorithm,
+        nonce: &[u8],
+    ) -> (Challenge, Box<dyn ResponseVerifier>) {
+        let (download, verifier) = Download::generate(&mut rng, self.size_in_kbs, algorithm, nonce);
+        (
+            Challenge::DownloadChallenge(download.to_wire()),
+            Box::new(verifier),
+        )
+    }
+}
+
+/// Upload challenges of `size_in_kbs` of data
+#[cfg(feature = "std")]
+pub struct UploadGenerator {
+    pub size_in_kbs: u32,
+}
+
+#[cfg(feature = "std")]
+impl ChallengeGenerator for UploadGenerator {
+    fn kind_id(&self) -> u16 {
+        kinds::challenge::UPLOAD_CHALLENGE
+    }
+
+    fn name(&self) -> &'static str {
+        "Upload"
+    }
+
+    fn generate(
+        &self,
+        mut rng: &mut dyn RngCore,
+        algorithm: HashAlgorithm,
+        nonce: &[u8],
+    ) -> (Challenge, Box<dyn ResponseVerifier>) {
+        let (upload, verifier) = Upload::generate(&mut rng, self.size_in_kbs, algorithm, nonce);
+        (
+            Challenge::UploadChallenge(upload.to_wire()),
+            Box::new(verifier),
+        )
+    }
+}
+
+/// Throughput of one direction of the link
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ThroughputStats {
+    /// Size of the data transferred in each round, in kilobytes
+    pub size_kb: u64,
+    /// Time each round took
+    pub round_milliseconds: Vec<u64>,
+    /// Data of all rounds over their total time, rounds faster than a millisecond count as one
+    pub kilobytes_per_second: u64,
+}
+
+impl ThroughputStats {
+    /// `None` if there were no rounds
+    pub fn new(size_kb: u64, round_milliseconds: Vec<u64>) -> Option<Self> {
+        if round_milliseconds.is_empty() {
+            return None;
+        }
+        let total_milliseconds: u128 = round_milliseconds
+            .iter()
+            .map(|round| u128::from((*round).max(1)))
+            .sum();
+        let total_kb = u128::from(size_kb) * round_milliseconds.len() as u128;
+        Some(ThroughputStats {
+            size_kb,
+            kilobytes_per_second: (total_kb * 1000 / total_milliseconds) as u64,
+            round_milliseconds,
+        })
+    }
+}
+
+/// Shape of a transfer received in chunks, which its total time hides: a link delivering the
+/// payload in bursts between stalls is less reliable than a steady one taking as long
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct TransferProfile {
+    pub chunks: u32,
+    /// Time until the first chunk arrived, before data flows steadily
+    pub ramp_up_microseconds: u64,
+    /// Data of the chunks after the first one over time from the first to the last one
+    pub sustained_kilobytes_per_second: u64,
+    /// Gaps between consecutive chunks longer than the stall threshold
+    pub stalls: u32,
+    pub stalled_microseconds: u64,
+    pub longest_stall_microseconds: u64,
+}
+
+impl TransferProfile {
+    /// `arrivals` are arrival time and size in bytes of each chunk, in order of arrival.
+    /// `None` for fewer than two chunks, whose profile is just the round time.
+    pub fn from_chunks(arrivals: &[(u64, u64)], stall_microseconds: u64) -> Option<Self> {
+        if arrivals.len() < 2 {
+            return None;
+        }
+        let (first_microseconds, _) = arrivals[0];
+        let (last_microseconds, _) = arrivals[arrivals.len() - 1];
+        let sustained_bytes: u128 = arrivals[1..]
+            .iter()
+            .map(|(_, bytes)| u128::from(*bytes))
+            .sum();
+        let sustained_microseconds = last_microseconds.saturating_sub(first_microseconds).max(1);
+        let stalls: Vec<u64> = arrivals
+            .windows(2)
+            .map(|pair| pair[1].0.saturating_sub(pair[0].0))
+            .filter(|gap| *gap > stall_microseconds)
+            .collect();
+        Some(TransferProfile {
+            chunks: arrivals.len() as u32,
+            ramp_up_microseconds: first_microseconds,
+            sustained_kilobytes_per_second: (sustained_bytes * 1_000_000
+                / 1024
+                / u128::from(sustained_microseconds))
+                as u64,
+            stalls: stalls.len() as u32,
+            stalled_microseconds: stalls.iter().sum(),
+            longest_stall_microseconds: stalls.iter().copied().max().unwrap_or(0),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::challenges::bandwidth::{Download, ThroughputStats, TransferProfile, Upload};
+    use crate::hash::HashAlgorithm;
+    use rand::rngs::OsRng;
+
+    #[test]
+    fn test_download_and_upload() {
+        let (download, verifier) = Download::generate(&mut OsRng, 4, HashAlgorithm::Blake3, &[]);
+        let download = Download::from_wire(download.to_wire());
+        assert!(verifier.verify(download.perform_challenge(HashAlgorithm::Blake3, &[])));
+        assert!(!verifier.verify(download.perform_challenge(HashAlgorithm::Sha256, &[])));
+
+        let (upload, verifier) = Upload::generate(&mut OsRng, 4, HashAlgorithm::Sha256, &[]);
+        let upload = Upload::from_wire(upload.to_wire()).unwrap();
+        let mut data = upload.perform_challenge(&[]);
+        assert_eq!(data.len(), 4 * 1024);
+        // Same seed always expands to the same data
+        assert_eq!(data, upload.perform_challenge(&[]));
+        assert!(verifier.verify(data.clone()));
+        data[0] ^= 1;
+        assert!(!verifier.verify(data));
+
+        assert!(Upload::from_wire(vec![0; 3]).is_err());
+    }
+
+    #[test]
+    fn test_bound_download_and_upload() {
+        let nonce = [3u8; 16];
+        let (download, verifier) = Download::generate(&mut OsRng, 4, HashAlgorithm::Blake3, &nonce);
+        assert!(verifier.verify(download.perform_challenge(HashAlgorithm::Blake3, &nonce)));
+        // Hash of the data alone, e.g. cached from an earlier round with the same payload
+        assert!(!verifier.verify(download.perform_challenge(HashAlgorithm::Blake3, &[])));
+
+        let (upload, verifier) = Upload::generate(&mut OsRng, 4, HashAlgorithm::Blake3, &nonce);
+        assert!(verifier.verify(upload.perform_challenge(&nonce)));
+        assert!(!verifier.verify(upload.perform_challenge(&[4u8; 16])));
+    }
+
+    #[test]
+    fn test_throughput_stats() {
+        assert_eq!(ThroughputStats::new(1024, vec![]), None);
+        let stats = ThroughputStats::new(1024, vec![500, 1500]).unwrap();
+        assert_eq!(stats.kilobytes_per_second, 1024);
+        assert_eq!(
+            ThroughputStats::new(16, vec![0])
+                .unwrap()
+                .kilobytes_per_second,
+            16000
+        );
+    }
+
+    #[test]
+    fn test_transfer_profile() {
+        assert_eq!(TransferProfile::from_chunks(&[(1000, 1024)], 1000), None);
+
+        // 4KB after the first chunk over 4ms, steady
+        let steady = [
+            (2000, 1024),
+            (3000, 1024),
+            (4000, 1024),
+            (5000, 1024),
+            (6000, 1024),
+        ];
+        let profile = TransferProfile::from_chunks(&steady, 500_000).unwrap();
+        assert_eq!(profile.chunks, 5);
+        assert_eq!(profile.ramp_up_microseconds, 2000);
+        assert_eq!(profile.sustained_kilobytes_per_second, 1000);
+        assert_eq!(profile.stalls, 0);
+
+        // Same data and time, delivered in two bursts
+        let bursty = [
+            (2000, 1024),
+            (2010, 1024),
+            (2020, 1024),
+            (5990, 1024),
+            (6000, 1024),
+        ];
+        let profile = TransferProfile::from_chunks(&bursty, 1000).unwrap();
+        assert_eq!(profile.sustained_kilobytes_per_second, 1000);
+        assert_eq!(profile.stalls, 1);
+        assert_eq!(profile.stalled_microseconds, 3970);
+        assert_eq!(profile.longest_stall_microseconds, 3970);
+    }
+}
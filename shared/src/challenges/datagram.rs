@@ -0,0 +1,273 @@
+//! Probes sent over UDP instead of the session's connection. TCP retransmits lost packets, so
+//! over the session a lossy link only looks slow; datagrams aren't retransmitted, which lets
+//! the server count losses in each direction.
+//!
+//! Server sends a `DatagramTrain` in `Challenge::DatagramChallenge`. Client sends its token as
+//! hello to the server's UDP port, repeating it every `HELLO_INTERVAL_MILLISECONDS` until the
+//! first probe arrives, so that the server learns its address through any NAT. Server then sends
+//! the probes at a steady pace without waiting for them to come back, client echoes each one
+//! as soon as it arrives and finally responds in `Response::DatagramChallengeResponse` with the
+//! `DatagramReceipt` of how many probes it received.
+
+use crate::challenges::latency::LatencyStats;
+use crate::std_alloc::Vec;
+use anyhow::{anyhow, Result};
+use byteorder::{ByteOrder, NetworkEndian};
+use serde_derive::{Deserialize, Serialize};
+
+/// Bytes of the token every datagram of a train starts with
+pub const TOKEN_SIZE: usize = 16;
+
+/// Client repeats its hello this often until the first probe arrives
+pub const HELLO_INTERVAL_MILLISECONDS: u64 = 100;
+
+/// Client stops echoing when no probe arrived for this long, server stops waiting for the hello
+/// after as long
+pub const IDLE_TIMEOUT_MILLISECONDS: u64 = 2000;
+
+const TRAIN_SIZE: usize = 2 + TOKEN_SIZE + 4 + 4;
+
+/// Train of probes of a session, see the module documentation
+#[derive(Clone, Debug, PartialEq)]
+pub struct DatagramTrain {
+    /// UDP port of the server, on the host client connected to for the session
+    pub port: u16,
+    /// Identifies datagrams of the session, server ignores datagrams with other tokens
+    pub token: [u8; TOKEN_SIZE],
+    pub probes: u32,
+    /// Time between consecutive probes
+    pub interval_milliseconds: u32,
+}
+
+impl DatagramTrain {
+    /// Serialized form is port, token, number of probes and interval
+    pub fn to_wire(&self) -> Vec<u8> {
+        let mut result = vec![0u8; TRAIN_SIZE];
+        NetworkEndian::write_u16(&mut result[..2], self.port);
+        result[2..2 + TOKEN_SIZE].copy_from_slice(&self.token);
+        NetworkEndian::write_u32(&mut result[2 + TOKEN_SIZE..6 + TOKEN_SIZE], self.probes);
+        NetworkEndian::write_u32(&mut result[6 + TOKEN_SIZE..], self.interval_milliseconds);
+        result
+    }
+
+    pub fn from_wire(data: &[u8]) -> Result<Self> {
+        if data.len() != TRAIN_SIZE {
+            return Err(anyhow!("unable to parse wire data"));
+        }
+        let mut token = [0u8; TOKEN_SIZE];
+        token.copy_from_slice(&data[2..2 + TOKEN_SIZE]);
+        Ok(DatagramTrain {
+            port: NetworkEndian::read_u16(&data[..2]),
+            token,
+            probes: NetworkEndian::read_u32(&data[2 + TOKEN_SIZE..6 + TOKEN_SIZE]),
+            interval_milliseconds: NetworkEndian::read_u32(&data[6 + TOKEN_SIZE..]),
+        })
+    }
+
+    /// Datagram client sends until the first probe arrives
+    pub fn hello(&self) -> Vec<u8> {
+        self.token.to_vec()
+    }
+
+    /// Datagram carrying `probe`, e.g. a probe of `LatencyChallenge`
+    pub fn probe_datagram(&self, probe: &[u8]) -> Vec<u8> {
+        let mut datagram = Vec::with_capacity(TOKEN_SIZE + probe.len());
+        datagram.extend_from_slice(&self.token);
+        datagram.extend_from_slice(probe);
+        datagram
+    }
+
+    /// Probe carried by `datagram`, `None` for a hello or a datagram of another train
+    pub fn probe<'a>(&self, datagram: &'a [u8]) -> Option<&'a [u8]> {
+        match token(datagram) {
+            Some(token) if token == self.token && datagram.len() > TOKEN_SIZE => {
+                Some(&datagram[TOKEN_SIZE..])
+            }
+            _ => None,
+        }
+    }
+
+    /// Sequence number of the probe carried by `datagram`, probes start with it
+    pub fn sequence(&self, datagram: &[u8]) -> Option<u32> {
+        self.probe(datagram)
+            .filter(|probe| probe.len() >= 4)
+            .map(|probe| NetworkEndian::read_u32(&probe[..4]))
+    }
+}
+
+/// Token of the train `datagram` belongs to
+pub fn token(datagram: &[u8]) -> Option<[u8; TOKEN_SIZE]> {
+    if datagram.len() < TOKEN_SIZE {
+        return None;
+    }
+    let mut token = [0u8; TOKEN_SIZE];
+    token.copy_from_slice(&datagram[..TOKEN_SIZE]);
+    Some(token)
+}
+
+/// Probes of the train client received, sent once it stopped echoing
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DatagramReceipt {
+    pub probes_received: u32,
+}
+
+impl DatagramReceipt {
+    pub fn to_wire(&self) -> Vec<u8> {
+        self.probes_received.to_be_bytes().to_vec()
+    }
+
+    pub fn from_wire(data: &[u8]) -> Result<Self> {
+        if data.len() != 4 {
+            return Err(anyhow!("unable to parse wire data"));
+        }
+        Ok(DatagramReceipt {
+            probes_received: NetworkEndian::read_u32(data),
+        })
+    }
+}
+
+/// Echoes of a probe train as the server received them
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DatagramEchoes {
+    /// Round trip time of each echoed probe in microseconds, in order of arrival
+    pub round_trips: Vec<u64>,
+    /// Echoes which arrived after the echo of a later probe
+    pub reordered: u32,
+    /// Echoes of probes which were already echoed
+    pub duplicates: u32,
+}
+
+/// Losses of a probe train in each direction along with round trip times of echoed probes
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct DatagramStats {
+    /// Probes server sent
+    pub probes: u32,
+    /// Probes client reported to have received, only as trustworthy as the client
+    pub received_by_client: u32,
+    /// Probes whose echo came back to the server
+    pub echoed: u32,
+    /// Probes whose echo didn't come back, out of all probes
+    pub loss_percent: f64,
+    /// Probes which didn't reach the client, out of all probes
+    pub downstream_loss_percent: f64,
+    /// Echoes which didn't reach the server, out of probes client received
+    pub upstream_loss_percent: f64,
+    /// `None` if no probe came back
+    pub latency: Option<LatencyStats>,
+    /// Echoed probes which arrived after the echo of a later probe, reordered on the way either
+    /// direction
+    #[serde(default)]
+    pub reordered: u32,
+    /// Echoes which arrived again, duplicated on the way either direction
+    #[serde(default)]
+    pub duplicates: u32,
+    /// Reordered probes out of echoed ones
+    #[serde(default)]
+    pub reorder_percent: f64,
+    /// Duplicate echoes out of echoed probes
+    #[serde(default)]
+    pub duplicate_percent: f64,
+}
+
+impl DatagramStats {
+    /// `received_by_client` is clamped between echoed and sent probes, a client can't have
+    /// echoed a probe it didn't receive
+    pub fn new(probes: u32, received_by_client: u32, echoes: &DatagramEchoes) -> Self {
+        let echoed = echoes.round_trips.len() as u32;
+        let received_by_client = received_by_client.max(echoed).min(probes.max(echoed));
+        let percent = |lost: u32, out_of: u32| match out_of {
+            0 => 0.0,
+            out_of => f64::from(lost) * 100.0 / f64::from(out_of),
+        };
+        DatagramStats {
+            probes,
+            received_by_client,
+            echoed,
+            loss_percent: percent(probes.saturating_sub(echoed), probes),
+            downstream_loss_percent: percent(probes - received_by_client, probes),
+            upstream_loss_percent: percent(received_by_client - echoed, received_by_client),
+            latency: LatencyStats::from_round_trips(&echoes.round_trips),
+            reordered: echoes.reordered,
+            duplicates: echoes.duplicates,
+            reorder_percent: percent(echoes.reordered, echoed),
+            duplicate_percent: percent(echoes.duplicates, echoed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::challenges::datagram::{
+        DatagramEchoes, DatagramReceipt, DatagramStats, DatagramTrain,
+    };
+
+    #[test]
+    fn test_datagram_train() {
+        let train = DatagramTrain {
+            port: 9002,
+            token: [3; 16],
+            probes: 50,
+            interval_milliseconds: 10,
+        };
+        assert_eq!(DatagramTrain::from_wire(&train.to_wire()).unwrap(), train);
+        assert!(DatagramTrain::from_wire(&[0; 4]).is_err());
+
+        let datagram = train.probe_datagram(&[0, 0, 0, 7, 1, 2]);
+        assert_eq!(train.sequence(&datagram), Some(7));
+        assert_eq!(train.probe(&datagram), Some(&[0, 0, 0, 7, 1, 2][..]));
+        assert_eq!(train.sequence(&train.hello()), None);
+        let other = DatagramTrain {
+            token: [4; 16],
+            ..train.clone()
+        };
+        assert_eq!(other.sequence(&datagram), None);
+
+        let receipt = DatagramReceipt {
+            probes_received: 42,
+        };
+        assert_eq!(
+            DatagramReceipt::from_wire(&receipt.to_wire()).unwrap(),
+            receipt
+        );
+    }
+
+    #[test]
+    fn test_datagram_stats() {
+        let echoes = |round_trips: &[u64]| DatagramEchoes {
+            round_trips: round_trips.to_vec(),
+            ..Default::default()
+        };
+        let stats = DatagramStats::new(
+            10,
+            8,
+            &DatagramEchoes {
+                reordered: 3,
+                duplicates: 1,
+                ..echoes(&[100, 300, 200, 200, 200, 200])
+            },
+        );
+        assert_eq!(stats.echoed, 6);
+        assert_eq!(stats.loss_percent, 40.0);
+        assert_eq!(stats.downstream_loss_percent, 20.0);
+        assert_eq!(stats.upstream_loss_percent, 25.0);
+        assert_eq!(stats.latency.unwrap().mean_microseconds, 200);
+        assert_eq!(stats.reorder_percent, 50.0);
+        assert_eq!(stats.duplicate_percent, 100.0 / 6.0);
+
+        // Client can't claim to have received fewer probes than it echoed, or more than were sent
+        assert_eq!(
+            DatagramStats::new(10, 2, &echoes(&[100; 6])).received_by_client,
+            6
+        );
+        assert_eq!(
+            DatagramStats::new(10, 50, &echoes(&[100; 6])).received_by_client,
+            10
+        );
+
+        let lost = DatagramStats::new(10, 0, &echoes(&[]));
+        assert_eq!(lost.loss_percent, 100.0);
+        assert_eq!(lost.upstream_loss_percent, 0.0);
+        assert_eq!(lost.latency, None);
+        assert_eq!(lost.reorder_percent, 0.0);
+    }
+}
@@ -0,0 +1,167 @@
+use crate::std_alloc::Vec;
+
+#[cfg(feature = "std")]
+use crate::challenges::{ChallengeGenerator, ResponseVerifier};
+#[cfg(feature = "std")]
+use crate::hash::HashAlgorithm;
+#[cfg(feature = "std")]
+use crate::nonce;
+#[cfg(feature = "std")]
+use crate::std_alloc::Box;
+#[cfg(feature = "std")]
+use crate::{kinds, Challenge, Response};
+#[cfg(feature = "std")]
+use rand::RngCore;
+
+/// Random blob client has to write to persistent storage, read back and hash
+/// using the algorithm negotiated during the handshake.
+pub struct DiskChallenge {
+    data: Vec<u8>,
+}
+
+impl DiskChallenge {
+    /// Generate new blob using RNG provided, verifier contains hash of `nonce` followed by the
+    /// blob calculated using `algorithm`, which client must return after reading the blob back.
+    #[cfg(feature = "std")]
+    pub fn generate<RNG>(
+        rng: &mut RNG,
+        size_in_kbs: usize,
+        algorithm: HashAlgorithm,
+        nonce: &[u8],
+    ) -> (Self, DiskChallengeVerifier)
+    where
+        RNG: RngCore,
+    {
+        let mut data = vec![0u8; size_in_kbs * 1024];
+        rng.fill_bytes(&mut data);
+
+        let hash = nonce::bound_digest(algorithm, nonce, &data);
+        (DiskChallenge { data }, DiskChallengeVerifier { hash })
+    }
+
+    /// Serializes the blob
+    /// Since there is no processing involved we are consuming `self` and returning inner data
+    pub fn to_wire(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// Deserializes the blob
+    /// Consumes `data` argument and returns DiskChallenge
+    pub fn from_wire(data: Vec<u8>) -> Self {
+        Self { data }
+    }
+}
+
+#[cfg(feature = "std")]
+pub struct DiskChallengeVerifier {
+    hash: Vec<u8>,
+}
+
+#[cfg(feature = "std")]
+impl DiskChallengeVerifier {
+    pub fn verify(&self, client_hash: Vec<u8>) -> bool {
+        client_hash == self.hash
+    }
+}
+
+#[cfg(feature = "std")]
+impl ResponseVerifier for DiskChallengeVerifier {
+    fn verify(self: Box<Self>, response: Response) -> bool {
+        match response {
+            Response::DiskChallengeResponse(client_hash) => client_hash == self.hash,
+            _ => false,
+        }
+    }
+}
+
+/// Disk challenges with blobs of `size_in_kbs`
+#[cfg(feature = "std")]
+pub struct DiskChallengeGenerator {
+    pub size_in_kbs: usize,
+}
+
+#[cfg(feature = "std")]
+impl ChallengeGenerator for DiskChallengeGenerator {
+    fn kind_id(&self) -> u16 {
+        kinds::challenge::DISK_CHALLENGE
+    }
+
+    fn name(&self) -> &'static str {
+        "Disk"
+    }
+
+    fn generate(
+        &self,
+        mut rng: &mut dyn RngCore,
+        algorithm: HashAlgorithm,
+        nonce: &[u8],
+    ) -> (Challenge, Box<dyn ResponseVerifier>) {
+        let (challenge, verifier) =
+            DiskChallenge::generate(&mut rng, self.size_in_kbs, algorithm, nonce);
+        (
+            Challenge::DiskChallenge(challenge.to_wire()),
+            Box::new(verifier),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::challenges::disk::{DiskChallenge, DiskChallengeGenerator};
+    use crate::challenges::ChallengeGenerator;
+    use crate::hash::HashAlgorithm;
+    use crate::nonce::bound_digest;
+    use crate::{kinds, Challenge, Response};
+    use rand::rngs::OsRng;
+
+    #[test]
+    fn test_disk_challenge_verifier() {
+        let mut rng = OsRng;
+        let (challenge, verifier) =
+            DiskChallenge::generate(&mut rng, 4, HashAlgorithm::Blake3, &[]);
+        let mut data = challenge.to_wire();
+        assert_eq!(data.len(), 4 * 1024);
+        assert!(verifier.verify(HashAlgorithm::Blake3.digest(&data)));
+        assert!(!verifier.verify(HashAlgorithm::Sha256.digest(&data)));
+
+        data[0] ^= 1;
+        assert!(!verifier.verify(HashAlgorithm::Blake3.digest(&data)));
+    }
+
+    #[test]
+    fn test_disk_challenge_generator() {
+        let generator = DiskChallengeGenerator { size_in_kbs: 4 };
+        assert_eq!(generator.kind_id(), kinds::challenge::DISK_CHALLENGE);
+        let answer = |algorithm: HashAlgorithm| {
+            let (challenge, verifier) = generator.generate(&mut OsRng, HashAlgorithm::Blake3, &[]);
+            assert_eq!(challenge.kind_id(), generator.kind_id());
+            let data = match challenge {
+                Challenge::DiskChallenge(data) => data,
+                challenge => panic!("Unexpected {:?}", challenge),
+            };
+            (verifier, algorithm.digest(&data))
+        };
+
+        let (verifier, hash) = answer(HashAlgorithm::Blake3);
+        assert!(verifier.verify(Response::DiskChallengeResponse(hash)));
+        let (verifier, hash) = answer(HashAlgorithm::Sha256);
+        assert!(!verifier.verify(Response::DiskChallengeResponse(hash)));
+        // Right hash in a response to another kind
+        let (verifier, hash) = answer(HashAlgorithm::Blake3);
+        assert!(!verifier.verify(Response::DownloadChallengeResponse(hash)));
+
+        // Bound challenges are answered with the hash of the nonce followed by the blob
+        let (challenge, verifier) = generator.generate(&mut OsRng, HashAlgorithm::Blake3, &[9; 16]);
+        let data = match challenge {
+            Challenge::DiskChallenge(data) => data,
+            challenge => panic!("Unexpected {:?}", challenge),
+        };
+        assert!(
+            verifier.verify(Response::DiskChallengeResponse(bound_digest(
+                HashAlgorithm::Blake3,
+                &[9; 16],
+                &data
+            )))
+        );
+    }
+}
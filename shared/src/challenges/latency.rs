@@ -0,0 +1,153 @@
+use serde_derive::{Deserialize, Serialize};
+
+#[cfg(feature = "std")]
+use crate::std_alloc::Vec;
+#[cfg(feature = "std")]
+use byteorder::{ByteOrder, NetworkEndian};
+#[cfg(feature = "std")]
+use rand::RngCore;
+#[cfg(feature = "std")]
+use sha2::{Digest, Sha256};
+
+/// Sequence number, send timestamp and authentication tag
+#[cfg(feature = "std")]
+const PROBE_SIZE: usize = 4 + 8 + TAG_SIZE;
+#[cfg(feature = "std")]
+const TAG_SIZE: usize = 16;
+
+/// Train of small probes client echoes back as soon as it receives them, next probe is only
+/// sent once the previous one came back. Unlike `Roundtrip`, whose time is dominated by
+/// bandwidth, it measures round trip time of the link and how much it varies.
+///
+/// Each probe carries its send timestamp, tagged with a secret key so that client can't
+/// make the link look faster by altering it.
+#[cfg(feature = "std")]
+pub struct LatencyChallenge {
+    key: [u8; 32],
+    probes: u32,
+}
+
+#[cfg(feature = "std")]
+impl LatencyChallenge {
+    pub fn generate<RNG>(rng: &mut RNG, probes: u32) -> Self
+    where
+        RNG: RngCore,
+    {
+        let mut key = [0u8; 32];
+        rng.fill_bytes(&mut key);
+        LatencyChallenge { key, probes }
+    }
+
+    /// Number of probes in the train
+    pub fn probes(&self) -> u32 {
+        self.probes
+    }
+
+    /// Serialized probe number `sequence`, `sent_at_microseconds` is read by the server's clock
+    pub fn probe(&self, sequence: u32, sent_at_microseconds: u64) -> Vec<u8> {
+        let mut probe = vec![0u8; PROBE_SIZE];
+        NetworkEndian::write_u32(&mut probe[..4], sequence);
+        NetworkEndian::write_u64(&mut probe[4..12], sent_at_microseconds);
+        let tag = self.tag(&probe[..12]);
+        probe[12..].copy_from_slice(&tag);
+        probe
+    }
+
+    /// Send timestamp of an echoed probe, `None` if it is not the unaltered probe `sequence`
+    pub fn verify(&self, sequence: u32, echoed: &[u8]) -> Option<u64> {
+        if echoed.len() != PROBE_SIZE
+            || NetworkEndian::read_u32(&echoed[..4]) != sequence
+            || self.tag(&echoed[..12]).as_slice() != &echoed[12..]
+        {
+            return None;
+        }
+        Some(NetworkEndian::read_u64(&echoed[4..12]))
+    }
+
+    fn tag(&self, header: &[u8]) -> [u8; TAG_SIZE] {
+        let mut hasher = Sha256::new();
+        hasher.update(self.key);
+        hasher.update(header);
+        let mut tag = [0u8; TAG_SIZE];
+        tag.copy_from_slice(&hasher.finalize()[..TAG_SIZE]);
+        tag
+    }
+}
+
+/// Aggregates of round trip times of a probe train, in microseconds
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct LatencyStats {
+    pub probes: u32,
+    pub min_microseconds: u64,
+    pub mean_microseconds: u64,
+    pub max_microseconds: u64,
+    /// Mean absolute difference between round trip times of consecutive probes,
+    /// similar to interarrival jitter of RFC 3550 but without smoothing
+    pub jitter_microseconds: u64,
+}
+
+impl LatencyStats {
+    /// `None` if no probe came back
+    pub fn from_round_trips(round_trips: &[u64]) -> Option<Self> {
+        let min_microseconds = *round_trips.iter().min()?;
+        let max_microseconds = *round_trips.iter().max()?;
+        let total: u128 = round_trips.iter().map(|rtt| u128::from(*rtt)).sum();
+        let variation: u128 = round_trips
+            .windows(2)
+            .map(|pair| u128::from(pair[0].abs_diff(pair[1])))
+            .sum();
+        Some(LatencyStats {
+            probes: round_trips.len() as u32,
+            min_microseconds,
+            mean_microseconds: (total / round_trips.len() as u128) as u64,
+            max_microseconds,
+            jitter_microseconds: match round_trips.len() {
+                1 => 0,
+                probes => (variation / (probes - 1) as u128) as u64,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::challenges::latency::{LatencyChallenge, LatencyStats};
+    use rand::rngs::OsRng;
+
+    #[test]
+    fn test_latency_probes() {
+        let challenge = LatencyChallenge::generate(&mut OsRng, 3);
+        let probe = challenge.probe(1, 1_500);
+        assert_eq!(challenge.verify(1, &probe), Some(1_500));
+        assert_eq!(challenge.verify(2, &probe), None);
+
+        // Client can't make the round trip look shorter by moving the timestamp forward
+        let mut altered = probe.clone();
+        altered[11] ^= 0xff;
+        assert_eq!(challenge.verify(1, &altered), None);
+
+        let other = LatencyChallenge::generate(&mut OsRng, 3);
+        assert_eq!(other.verify(1, &probe), None);
+    }
+
+    #[test]
+    fn test_latency_stats() {
+        assert_eq!(LatencyStats::from_round_trips(&[]), None);
+        assert_eq!(
+            LatencyStats::from_round_trips(&[1000, 1400, 1200, 1200]),
+            Some(LatencyStats {
+                probes: 4,
+                min_microseconds: 1000,
+                mean_microseconds: 1200,
+                max_microseconds: 1400,
+                jitter_microseconds: 200,
+            })
+        );
+        assert_eq!(
+            LatencyStats::from_round_trips(&[700])
+                .unwrap()
+                .jitter_microseconds,
+            0
+        );
+    }
+}
@@ -1,2 +1,47 @@
+//! Challenges server measures clients with. Kinds answered by a single response to a single
+//! challenge message implement `ChallengeGenerator` and `ResponseVerifier`, so that server runs
+//! their rounds the same way whatever they measure.
+
+pub mod bandwidth;
+pub mod datagram;
+pub mod disk;
+pub mod latency;
+#[cfg(feature = "std")]
+pub mod primes;
 pub mod roundtrip;
 pub mod timelock;
+
+#[cfg(feature = "std")]
+use crate::hash::HashAlgorithm;
+#[cfg(feature = "std")]
+use crate::std_alloc::Box;
+#[cfg(feature = "std")]
+use crate::{Challenge, Response};
+#[cfg(feature = "std")]
+use rand::RngCore;
+
+/// Generates challenges of one kind along with verifiers of their responses
+#[cfg(feature = "std")]
+pub trait ChallengeGenerator: Send + Sync {
+    /// Kind of the challenges, see `kinds::challenge`
+    fn kind_id(&self) -> u16;
+
+    /// Name of the measurement in logs and errors sent to clients, e.g. `Disk`
+    fn name(&self) -> &'static str;
+
+    /// New challenge whose response is checked against `algorithm` digests where the kind uses
+    /// them, and must have `nonce` bound into it, see `nonce`
+    fn generate(
+        &self,
+        rng: &mut dyn RngCore,
+        algorithm: HashAlgorithm,
+        nonce: &[u8],
+    ) -> (Challenge, Box<dyn ResponseVerifier>);
+}
+
+/// Checks the response to a single challenge
+#[cfg(feature = "std")]
+pub trait ResponseVerifier: Send {
+    /// `false` for a wrong answer as well as for a response to another kind of challenge
+    fn verify(self: Box<Self>, response: Response) -> bool;
+}
@@ -0,0 +1,116 @@
+//! Generators of the secret primes of timelock puzzles
+
+use crate::std_alloc::Vec;
+use anyhow::{anyhow, Result};
+use num_bigint::BigUint;
+use rand::RngCore;
+
+/// Source of the secret primes `p` and `q` of timelock puzzles
+pub trait PrimeGenerator: Send + Sync {
+    /// Random prime of `bits` bits. Backends may leave the top bits unset, making it slightly shorter.
+    fn generate(&self, rng: &mut dyn RngCore, bits: usize) -> Result<BigUint>;
+}
+
+/// Primes generated by `glass_pumpkin`, which needs at least 128 bits
+pub struct GlassPumpkin;
+
+impl PrimeGenerator for GlassPumpkin {
+    fn generate(&self, rng: &mut dyn RngCore, bits: usize) -> Result<BigUint> {
+        glass_pumpkin::prime::from_rng(bits, rng)
+            .map_err(|e| anyhow!("Unable to generate a {} bit prime: {}", bits, e))
+    }
+}
+
+/// Primes generated by `num-bigint-dig`, the generator `rsa` crate creates its keys with
+#[cfg(feature = "num-bigint-dig")]
+pub struct NumBigIntDig;
+
+#[cfg(feature = "num-bigint-dig")]
+impl PrimeGenerator for NumBigIntDig {
+    fn generate(&self, rng: &mut dyn RngCore, bits: usize) -> Result<BigUint> {
+        use num_bigint_dig::RandPrime;
+
+        // Generator panics below 2 bits
+        if bits < 2 {
+            return Err(anyhow!("Unable to generate a {} bit prime", bits));
+        }
+        Ok(BigUint::from_bytes_be(&rng.gen_prime(bits).to_bytes_be()))
+    }
+}
+
+/// Primes picked at random from a list generated offline, e.g. a list of safe primes.
+/// Puzzles are only as hard to shortcut as the list is secret and long: anyone who
+/// knows the list can factor `n` by trying its pairs.
+pub struct PrecomputedPrimes {
+    primes: Vec<BigUint>,
+}
+
+impl PrecomputedPrimes {
+    /// Parses decimal primes given one per line, `#` starts a comment. Every number is checked to be prime.
+    pub fn parse(list: &str) -> Result<Self> {
+        let primes = list
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or_default().trim())
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let prime = line
+                    .parse::<BigUint>()
+                    .map_err(|_| anyhow!("Invalid number {}", line))?;
+                if glass_pumpkin::prime::check(&prime) {
+                    Ok(prime)
+                } else {
+                    Err(anyhow!("{} is not a prime", line))
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(PrecomputedPrimes { primes })
+    }
+}
+
+impl PrimeGenerator for PrecomputedPrimes {
+    fn generate(&self, rng: &mut dyn RngCore, bits: usize) -> Result<BigUint> {
+        let candidates = self
+            .primes
+            .iter()
+            .filter(|prime| prime.bits() as usize == bits)
+            .collect::<Vec<_>>();
+        if candidates.is_empty() {
+            return Err(anyhow!("No precomputed prime has {} bits", bits));
+        }
+        Ok(candidates[(rng.next_u64() % candidates.len() as u64) as usize].clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::challenges::primes::{GlassPumpkin, PrecomputedPrimes, PrimeGenerator};
+    use rand::rngs::OsRng;
+
+    #[test]
+    fn test_prime_generators() {
+        let mut rng = OsRng;
+        let prime = GlassPumpkin.generate(&mut rng, 128).unwrap();
+        assert!(prime.bits() <= 128);
+        assert!(glass_pumpkin::prime::check(&prime));
+        assert!(GlassPumpkin.generate(&mut rng, 64).is_err());
+
+        #[cfg(feature = "num-bigint-dig")]
+        {
+            let prime = crate::challenges::primes::NumBigIntDig
+                .generate(&mut rng, 64)
+                .unwrap();
+            assert!(prime.bits() <= 64);
+            assert!(glass_pumpkin::prime::check(&prime));
+        }
+
+        // 2^61 - 1 and 2^31 - 1
+        let precomputed =
+            PrecomputedPrimes::parse("2305843009213693951 # Mersenne\n\n2147483647\n").unwrap();
+        assert_eq!(
+            precomputed.generate(&mut rng, 61).unwrap(),
+            2305843009213693951u64.into()
+        );
+        assert!(precomputed.generate(&mut rng, 128).is_err());
+        assert!(PrecomputedPrimes::parse("2305843009213693953").is_err());
+    }
+}
@@ -1,5 +1,13 @@
 use crate::std_alloc::Vec;
 
+#[cfg(feature = "std")]
+use crate::hash::HashAlgorithm;
+#[cfg(feature = "std")]
+use crate::merkle;
+#[cfg(feature = "std")]
+use crate::nonce;
+#[cfg(feature = "std")]
+use byteorder::{ByteOrder, NetworkEndian};
 #[cfg(feature = "std")]
 use rand::RngCore;
 
@@ -43,27 +51,73 @@ pub struct Roundtrip {
 }
 
 impl Roundtrip {
-    /// Generate new roundtrip data using RNG provided, verifier contains SHA2 hash of the data
-    /// which will be used to check integrity of data we get back from the client.
-    /// Verifier contains SHA2 hash, which is used to verify that client returned same data.
+    /// Generate new roundtrip data using RNG provided, verifier contains hash of the data
+    /// calculated using `algorithm`, which will be used to check integrity of data we get back
+    /// from the client.
     #[cfg(feature = "std")]
-    pub fn generate<RNG>(rng: &mut RNG, size_in_kbs: usize) -> (Self, RoundtripVerifier)
+    pub fn generate<RNG>(
+        rng: &mut RNG,
+        size_in_kbs: usize,
+        algorithm: HashAlgorithm,
+    ) -> (Self, RoundtripVerifier)
     where
         RNG: RngCore,
     {
-        let me = Roundtrip {
+        Roundtrip {
             data: roundtrip_utils::generate_random_data_kb(rng, size_in_kbs),
-        };
+        }
+        .with_verifier(algorithm, &[])
+    }
 
-        let mut hasher = Sha256::new();
-        hasher.update(&me.data);
+    /// Same as `generate`, but returns a verifier which does not hash the data client returned.
+    /// Instead, it checks merkle root supplied by the client against the one calculated at
+    /// generation time and compares `sample_chunks` distinct chunks of the returned data, chosen
+    /// by a secret key and weighted by their length, with the original data. This halves the
+    /// hashing server needs to do per round and client can't know which chunks will be checked.
+    #[cfg(feature = "std")]
+    pub fn generate_spot_checked<RNG>(
+        rng: &mut RNG,
+        size_in_kbs: usize,
+        sample_chunks: usize,
+    ) -> (Self, RoundtripSpotVerifier)
+    where
+        RNG: RngCore,
+    {
+        Roundtrip {
+            data: roundtrip_utils::generate_random_data_kb(rng, size_in_kbs),
+        }
+        .with_spot_verifier(rng, sample_chunks, &[])
+    }
 
-        (
-            me,
-            RoundtripVerifier {
-                hash: hasher.finalize().as_slice().to_vec(),
-            },
-        )
+    #[cfg(feature = "std")]
+    fn with_verifier(self, algorithm: HashAlgorithm, nonce: &[u8]) -> (Self, RoundtripVerifier) {
+        let hash = nonce::bound_digest(algorithm, nonce, &self.data);
+        (self, RoundtripVerifier { algorithm, hash })
+    }
+
+    #[cfg(feature = "std")]
+    fn with_spot_verifier<RNG>(
+        self,
+        rng: &mut RNG,
+        sample_chunks: usize,
+        nonce: &[u8],
+    ) -> (Self, RoundtripSpotVerifier)
+    where
+        RNG: RngCore,
+    {
+        let mut key = vec![0u8; 32];
+        rng.fill_bytes(&mut key);
+
+        let data_root = merkle::merkle_root(&self.data);
+        let verifier = RoundtripSpotVerifier {
+            merkle_root: nonce::bound_merkle_root(nonce, data_root.clone()),
+            data_root,
+            data: self.data.clone(),
+            key,
+            sample_chunks,
+        };
+
+        (self, verifier)
     }
 
     /// Serializes Roundtrip data
@@ -80,34 +134,317 @@ impl Roundtrip {
     }
 }
 
+/// Random buffer generated once per session, payload of every round is derived from it.
+/// Each round masks the buffer with a keystream of a fresh random key, so payloads of
+/// different rounds are unrelated to each other (and have different hashes), while the
+/// session draws only 32 bytes of randomness per round instead of the whole payload.
+#[cfg(feature = "std")]
+pub struct RoundtripSession {
+    base: Vec<u8>,
+}
+
+#[cfg(feature = "std")]
+impl RoundtripSession {
+    pub fn new<RNG>(rng: &mut RNG, size_in_kbs: usize) -> Self
+    where
+        RNG: RngCore,
+    {
+        RoundtripSession {
+            base: roundtrip_utils::generate_random_data_kb(rng, size_in_kbs),
+        }
+    }
+
+    fn derive<RNG>(&self, rng: &mut RNG) -> Roundtrip
+    where
+        RNG: RngCore,
+    {
+        let mut key = [0u8; blake3::KEY_LEN];
+        rng.fill_bytes(&mut key);
+
+        let mut data = vec![0u8; self.base.len()];
+        blake3::Hasher::new_keyed(&key)
+            .finalize_xof()
+            .fill(&mut data);
+        data.iter_mut()
+            .zip(&self.base)
+            .for_each(|(byte, base)| *byte ^= base);
+
+        Roundtrip { data }
+    }
+
+    /// Same as `Roundtrip::generate`, but derives data of the round from the session buffer.
+    /// Verifier expects the data echoed after `nonce`, see `nonce::bound_echo`.
+    pub fn generate<RNG>(
+        &self,
+        rng: &mut RNG,
+        algorithm: HashAlgorithm,
+        nonce: &[u8],
+    ) -> (Roundtrip, RoundtripVerifier)
+    where
+        RNG: RngCore,
+    {
+        self.derive(rng).with_verifier(algorithm, nonce)
+    }
+
+    /// Same as `Roundtrip::generate_spot_checked`, but derives data of the round from the session
+    /// buffer. Verifier expects the merkle root bound to `nonce`, see `nonce::bound_merkle_root`.
+    pub fn generate_spot_checked<RNG>(
+        &self,
+        rng: &mut RNG,
+        sample_chunks: usize,
+        nonce: &[u8],
+    ) -> (Roundtrip, RoundtripSpotVerifier)
+    where
+        RNG: RngCore,
+    {
+        self.derive(rng)
+            .with_spot_verifier(rng, sample_chunks, nonce)
+    }
+}
+
 #[cfg(feature = "std")]
 pub struct RoundtripVerifier {
+    algorithm: HashAlgorithm,
     hash: Vec<u8>,
 }
 
 #[cfg(feature = "std")]
 impl RoundtripVerifier {
     pub fn verify(&self, client_response: Vec<u8>) -> bool {
-        let mut hasher = Sha256::new();
-        hasher.update(&client_response);
-        hasher.finalize().to_vec().eq(&self.hash)
+        self.algorithm.digest(&client_response).eq(&self.hash)
     }
 }
 
+/// Verifier for roundtrip data which samples chunks of returned data instead of hashing all of it.
+/// It keeps copy of the original data, so it trades server memory for server CPU.
+#[cfg(feature = "std")]
+pub struct RoundtripSpotVerifier {
+    data: Vec<u8>,
+    /// Root client must return, bound to the nonce of the round
+    merkle_root: Vec<u8>,
+    /// Root proofs of retransmitted chunks are checked against
+    data_root: Vec<u8>,
+    key: Vec<u8>,
+    sample_chunks: usize,
+}
+
+#[cfg(feature = "std")]
+impl RoundtripSpotVerifier {
+    /// Returns indices of the distinct chunks that will be compared, derived from the secret key.
+    /// Chunks are drawn without replacement and weighted by their length, so that every byte of
+    /// the payload is as likely to be checked and a short last chunk isn't checked more often
+    /// than the others. Each chunk gets a key `ln(u) / length` for a keyed random `u` in (0, 1],
+    /// and the chunks with the largest keys are sampled (Efraimidis-Spirakis).
+    fn sampled_chunk_indices(&self) -> Vec<usize> {
+        let mut keyed_chunks: Vec<(f64, usize)> = self
+            .data
+            .chunks(merkle::CHUNK_SIZE)
+            .enumerate()
+            .map(|(index, chunk)| {
+                let mut hasher = Sha256::new();
+                hasher.update(&self.key);
+                hasher.update((index as u64).to_be_bytes());
+                let digest = hasher.finalize();
+                // 53 random bits, shifted into (0, 1] so that the logarithm is finite
+                let random = ((NetworkEndian::read_u64(&digest[..8]) >> 11) + 1) as f64
+                    / (1u64 << 53) as f64;
+                (random.ln() / chunk.len() as f64, index)
+            })
+            .collect();
+        keyed_chunks.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+        keyed_chunks
+            .into_iter()
+            .take(self.sample_chunks)
+            .map(|(_, index)| index)
+            .collect()
+    }
+
+    pub fn verify(&self, client_response: Vec<u8>, client_merkle_root: Vec<u8>) -> SpotCheck {
+        if client_response.len() != self.data.len() || client_merkle_root != self.merkle_root {
+            return SpotCheck::Mismatched;
+        }
+
+        let sampled = self.sampled_chunk_indices();
+        let corrupted: Vec<usize> = sampled
+            .iter()
+            .copied()
+            .filter(|index| {
+                merkle::chunk(&client_response, *index) != merkle::chunk(&self.data, *index)
+            })
+            .collect();
+        if corrupted.is_empty() {
+            SpotCheck::Matched
+        } else if corrupted.len() * 2 <= sampled.len() {
+            SpotCheck::CorruptedChunks(corrupted)
+        } else {
+            SpotCheck::Mismatched
+        }
+    }
+
+    /// Checks a chunk client sent again after `verify` found it corrupted, along with its proof
+    /// against the merkle root client returned with the data
+    pub fn verify_chunk(&self, index: usize, chunk: &[u8], proof: &[merkle::ProofStep]) -> bool {
+        merkle::chunk(&self.data, index) == Some(chunk)
+            && merkle::verify_proof(&self.data_root, chunk, proof)
+    }
+}
+
+/// Outcome of spot checking data client returned
+#[cfg(feature = "std")]
+#[derive(Debug, PartialEq)]
+pub enum SpotCheck {
+    Matched,
+    /// Length of the data or its merkle root doesn't match, or most sampled chunks don't
+    Mismatched,
+    /// Client returned the merkle root of the original data, but a few of the sampled chunks,
+    /// at these indices, came back corrupted, e.g. on the way back. Each can be sent again on its
+    /// own and checked with `RoundtripSpotVerifier::verify_chunk`, instead of the whole data.
+    CorruptedChunks(Vec<usize>),
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::challenges::roundtrip::Roundtrip;
+    use crate::challenges::roundtrip::{Roundtrip, RoundtripSession, SpotCheck};
+    use crate::hash::HashAlgorithm;
+    use crate::merkle::{merkle_root, MerkleTree, CHUNK_SIZE};
+    use crate::nonce::{bound_echo, bound_merkle_root};
+    use crate::std_alloc::Vec;
     use rand::rngs::OsRng;
 
     #[test]
     fn test_roundtrip_verifier() {
         let mut rng = OsRng::default();
-        let (roundtrip, roundtrip_verifier) = Roundtrip::generate(&mut rng, 1024);
-        assert_eq!(roundtrip.data.len(), 1024 * 1024);
-        assert!(roundtrip_verifier.verify(roundtrip.data.clone()));
-        // Let's pass data which is different by one byte
-        let mut invalid_data = roundtrip.data.clone();
-        invalid_data[2] = 5;
-        assert!(!roundtrip_verifier.verify(invalid_data));
+        for algorithm in &[
+            HashAlgorithm::Sha256,
+            HashAlgorithm::Blake3,
+            HashAlgorithm::XxHash64,
+        ] {
+            let (roundtrip, roundtrip_verifier) = Roundtrip::generate(&mut rng, 1024, *algorithm);
+            assert_eq!(roundtrip.data.len(), 1024 * 1024);
+            assert!(roundtrip_verifier.verify(roundtrip.data.clone()));
+            // Let's pass data which is different by one byte
+            let mut invalid_data = roundtrip.data.clone();
+            invalid_data[2] = roundtrip.data[2].wrapping_add(1);
+            assert!(!roundtrip_verifier.verify(invalid_data));
+        }
+    }
+
+    #[test]
+    fn test_roundtrip_spot_verifier() {
+        let mut rng = OsRng;
+        let (roundtrip, verifier) = Roundtrip::generate_spot_checked(&mut rng, 16, 64);
+        let root = merkle_root(&roundtrip.data);
+        assert_eq!(
+            verifier.verify(roundtrip.data.clone(), root.clone()),
+            SpotCheck::Matched
+        );
+
+        // Wrong merkle root
+        let mut invalid_root = root.clone();
+        invalid_root[0] ^= 1;
+        assert_eq!(
+            verifier.verify(roundtrip.data.clone(), invalid_root),
+            SpotCheck::Mismatched
+        );
+
+        // Truncated data
+        assert_eq!(
+            verifier.verify(roundtrip.data[..CHUNK_SIZE].to_vec(), root.clone()),
+            SpotCheck::Mismatched
+        );
+
+        // Correct root, but every chunk of data is corrupted
+        let invalid_data: Vec<u8> = roundtrip.data.iter().map(|b| b ^ 1).collect();
+        assert_eq!(verifier.verify(invalid_data, root), SpotCheck::Mismatched);
+    }
+
+    #[test]
+    fn test_corrupted_chunks() {
+        let mut rng = OsRng;
+        let (roundtrip, verifier) = Roundtrip::generate_spot_checked(&mut rng, 16, 16);
+        let root = merkle_root(&roundtrip.data);
+        let mut corrupted = roundtrip.data.clone();
+        corrupted[CHUNK_SIZE * 3 + 5] ^= 1;
+        assert_eq!(
+            verifier.verify(corrupted.clone(), root),
+            SpotCheck::CorruptedChunks(vec![3])
+        );
+
+        // Chunk sent again is checked against the committed root
+        let tree = MerkleTree::build(&roundtrip.data);
+        let proof = tree.proof(3).unwrap();
+        let chunk = &roundtrip.data[CHUNK_SIZE * 3..CHUNK_SIZE * 4];
+        assert!(verifier.verify_chunk(3, chunk, &proof));
+        assert!(!verifier.verify_chunk(3, &corrupted[CHUNK_SIZE * 3..CHUNK_SIZE * 4], &proof));
+        assert!(!verifier.verify_chunk(3, chunk, &tree.proof(4).unwrap()));
+        assert!(!verifier.verify_chunk(4, chunk, &proof));
+    }
+
+    #[test]
+    fn test_spot_verifier_sampling() {
+        let mut rng = OsRng;
+        let (roundtrip, verifier) = Roundtrip::generate_spot_checked(&mut rng, 16, 64);
+        // More samples than chunks compare each chunk once
+        let mut indices = verifier.sampled_chunk_indices();
+        indices.sort_unstable();
+        assert_eq!(indices, (0..16).collect::<Vec<_>>());
+
+        // Last chunk of a single byte is about a thousand times less likely to be checked
+        let mut short_chunk_samples = 0;
+        for _ in 0..100 {
+            let (_, verifier) = Roundtrip {
+                data: roundtrip.data[..CHUNK_SIZE * 3 + 1].to_vec(),
+            }
+            .with_spot_verifier(&mut rng, 3, &[]);
+            let mut indices = verifier.sampled_chunk_indices();
+            indices.sort_unstable();
+            indices.dedup();
+            assert_eq!(indices.len(), 3);
+            short_chunk_samples += indices.iter().filter(|index| **index == 3).count();
+        }
+        assert!(short_chunk_samples < 10);
+    }
+
+    #[test]
+    fn test_roundtrip_session() {
+        let mut rng = OsRng;
+        let session = RoundtripSession::new(&mut rng, 16);
+
+        let (first, first_verifier) = session.generate(&mut rng, HashAlgorithm::Blake3, &[]);
+        let (second, second_verifier) = session.generate(&mut rng, HashAlgorithm::Blake3, &[]);
+        assert_eq!(first.data.len(), 16 * 1024);
+        assert_ne!(first.data, second.data);
+        assert_ne!(first.data, session.base);
+        assert!(first_verifier.verify(first.data.clone()));
+        assert!(!second_verifier.verify(first.data));
+        assert!(second_verifier.verify(second.data));
+
+        let (spot_checked, verifier) = session.generate_spot_checked(&mut rng, 8, &[]);
+        let root = merkle_root(&spot_checked.data);
+        assert_eq!(verifier.verify(spot_checked.data, root), SpotCheck::Matched);
+    }
+
+    #[test]
+    fn test_roundtrip_nonce() {
+        let mut rng = OsRng;
+        let session = RoundtripSession::new(&mut rng, 16);
+        let nonce = [7u8; 16];
+
+        let (roundtrip, verifier) = session.generate(&mut rng, HashAlgorithm::Blake3, &nonce);
+        assert!(verifier.verify(bound_echo(&nonce, roundtrip.data.clone())));
+        // Data alone, or echoed after another nonce, is not a response to this round
+        assert!(!verifier.verify(roundtrip.data.clone()));
+        assert!(!verifier.verify(bound_echo(&[8u8; 16], roundtrip.data)));
+
+        let (spot_checked, verifier) = session.generate_spot_checked(&mut rng, 8, &nonce);
+        let root = merkle_root(&spot_checked.data);
+        assert_eq!(
+            verifier.verify(spot_checked.data.clone(), root.clone()),
+            SpotCheck::Mismatched
+        );
+        assert_eq!(
+            verifier.verify(spot_checked.data, bound_merkle_root(&nonce, root)),
+            SpotCheck::Matched
+        );
     }
 }
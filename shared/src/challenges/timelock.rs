@@ -2,12 +2,27 @@ use crate::std_alloc::Vec;
 use anyhow::{anyhow, Result};
 use byteorder::{ByteOrder, NetworkEndian};
 use num_bigint::BigUint;
+use sha2::{Digest, Sha256};
 
 #[cfg(feature = "std")]
-use glass_pumpkin::prime;
+use crate::challenges::primes::{GlassPumpkin, PrimeGenerator};
+#[cfg(feature = "std")]
+use core::ops::Sub;
 #[cfg(feature = "std")]
 use rand::RngCore;
 
+/// Bits of each of the secret primes of puzzles made by `Timelock::generate`
+#[cfg(feature = "std")]
+pub const DEFAULT_PRIME_BITS: usize = 128;
+
+/// Bits of the prime Wesolowski proofs are challenged with
+const CHALLENGE_PRIME_BITS: usize = 128;
+
+/// Bases of the Miller-Rabin test of challenge primes. Challenge primes are derived from
+/// a hash, prover can't pick a composite tailored to pass the test for these bases.
+const MILLER_RABIN_BASES: [u8; 12] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37];
+
+#[derive(Clone)]
 pub struct Timelock {
     a: BigUint,
     n: BigUint,
@@ -24,6 +39,82 @@ impl Timelock {
         computation
     }
 
+    /// Same as `perform_challenge`, but also returns Wesolowski proof of the answer,
+    /// which anyone can check with `verify_proof` without knowing factors of `n`.
+    /// Computing the proof takes about as long as computing the answer.
+    pub fn perform_challenge_with_proof(&self) -> (BigUint, BigUint) {
+        let answer = self.perform_challenge();
+        let l = self.challenge_prime(&answer);
+        let quotient = (BigUint::from(1u8) << self.squarings as usize) / &l;
+        let proof = self.a.modpow(&quotient, &self.n);
+        (answer, proof)
+    }
+
+    /// Checks that `answer` is `a^(2^squarings) mod n` given its Wesolowski `proof`, using public
+    /// parameters of the puzzle only: `proof^l * a^(2^squarings mod l) == answer (mod n)`,
+    /// where `l` is a prime derived from the puzzle and the answer.
+    pub fn verify_proof(&self, answer: &BigUint, proof: &BigUint) -> bool {
+        let zero = BigUint::from(0u8);
+        if self.n <= BigUint::from(1u8)
+            || answer >= &self.n
+            || proof >= &self.n
+            || answer == &zero
+            || proof == &zero
+        {
+            return false;
+        }
+
+        let l = self.challenge_prime(answer);
+        let r = BigUint::from(2u8).modpow(&BigUint::from(self.squarings), &l);
+        let expected = (proof.modpow(&l, &self.n) * self.a.modpow(&r, &self.n)) % &self.n;
+        &expected == answer
+    }
+
+    /// Puzzle whose base is SHA-256 of `nonce` followed by the base of this one, reduced modulo
+    /// `n`, so that its answer can't be known before the nonce is. This puzzle for an empty nonce.
+    pub fn bind(&self, nonce: &[u8]) -> Timelock {
+        if nonce.is_empty() {
+            return self.clone();
+        }
+        let mut hasher = Sha256::new();
+        hasher.update(nonce);
+        hasher.update(self.a.to_bytes_be());
+        Timelock {
+            a: BigUint::from_bytes_be(&hasher.finalize()) % &self.n,
+            n: self.n.clone(),
+            squarings: self.squarings,
+        }
+    }
+
+    /// Prime derived from hash of the puzzle and its answer (Fiat-Shamir)
+    fn challenge_prime(&self, answer: &BigUint) -> BigUint {
+        let mut transcript = Sha256::new();
+        for value in &[&self.a, &self.n, answer] {
+            let bytes = value.to_bytes_be();
+            transcript.update((bytes.len() as u64).to_be_bytes());
+            transcript.update(&bytes);
+        }
+        transcript.update(self.squarings.to_be_bytes());
+
+        let mut counter: u64 = 0;
+        loop {
+            let mut hasher = transcript.clone();
+            hasher.update(counter.to_be_bytes());
+            let digest = hasher.finalize();
+            let mut candidate_bytes = digest[..CHALLENGE_PRIME_BITS / 8].to_vec();
+            // Full size and odd
+            candidate_bytes[0] |= 0x80;
+            let last = candidate_bytes.len() - 1;
+            candidate_bytes[last] |= 1;
+
+            let candidate = BigUint::from_bytes_be(&candidate_bytes);
+            if is_probable_prime(&candidate) {
+                return candidate;
+            }
+            counter += 1;
+        }
+    }
+
     /// Serializes Timelock puzzle
     pub fn to_wire(&self) -> Vec<u8> {
         let a_bytes = self.a.to_bytes_be();
@@ -97,31 +188,136 @@ impl Timelock {
     /// But, since client does not know them, client need to do square of
     /// `a` repeatedly `squarings` time.
     #[cfg(feature = "std")]
-    pub fn generate<RNG>(rng: &mut RNG, squarings: u32) -> (Self, TimelockVerifier)
+    pub fn generate<RNG>(rng: &mut RNG, squarings: u32) -> Result<(Self, TimelockVerifier)>
     where
         RNG: RngCore,
     {
-        let p = prime::from_rng(128, rng).unwrap();
-        let q = prime::from_rng(128, rng).unwrap();
+        Self::generate_with_primes(rng, squarings, &GlassPumpkin, DEFAULT_PRIME_BITS)
+    }
+
+    /// Same as `generate`, with secret primes of `prime_bits` bits each taken from `primes`
+    #[cfg(feature = "std")]
+    pub fn generate_with_primes(
+        rng: &mut dyn RngCore,
+        squarings: u32,
+        primes: &dyn PrimeGenerator,
+        prime_bits: usize,
+    ) -> Result<(Self, TimelockVerifier)> {
+        let family = TimelockFamily::generate(rng, squarings, primes, prime_bits)?;
+        let puzzle = family.puzzle(rng);
+        Ok((puzzle.clone(), family.verifier(puzzle)))
+    }
+}
+
+/// Secret modulus shared by puzzles of several rounds. Each puzzle has its own random base,
+/// and answers to all of them can be checked at once, see `verify_batch`.
+#[cfg(feature = "std")]
+pub struct TimelockFamily {
+    n: BigUint,
+    /// `2^squarings mod phi(n)`, answer of a puzzle is `a^e mod n`
+    e: BigUint,
+    squarings: u32,
+}
+
+#[cfg(feature = "std")]
+impl TimelockFamily {
+    /// Generates a modulus of two secret primes of `prime_bits` bits each taken from `primes`
+    pub fn generate(
+        rng: &mut dyn RngCore,
+        squarings: u32,
+        primes: &dyn PrimeGenerator,
+        prime_bits: usize,
+    ) -> Result<Self> {
+        let p = primes.generate(rng, prime_bits)?;
+        // `n` must not be a square, otherwise its factor is easy to find.
+        // Small precomputed lists may give the same prime twice, so try a few times.
+        let mut q = primes.generate(rng, prime_bits)?;
+        for _ in 0..8 {
+            if q != p {
+                break;
+            }
+            q = primes.generate(rng, prime_bits)?;
+        }
+        if q == p {
+            return Err(anyhow!("Prime generator keeps returning the same prime"));
+        }
 
         let phi = (p.clone().sub(1 as u8)) * (q.clone().sub(1 as u8));
         let n = p * q;
+        let e = BigUint::from(2u8).modpow(&BigUint::from(squarings), &phi);
+        Ok(TimelockFamily { n, e, squarings })
+    }
 
+    /// New puzzle of the family with a random base
+    pub fn puzzle(&self, rng: &mut dyn RngCore) -> Timelock {
         let mut a_bytes: Vec<u8> = vec![0; 20];
         rng.fill_bytes(&mut a_bytes);
 
-        let a = BigUint::from_bytes_be(a_bytes.as_slice());
+        Timelock {
+            a: BigUint::from_bytes_be(a_bytes.as_slice()),
+            n: self.n.clone(),
+            squarings: self.squarings,
+        }
+    }
+
+    /// Whether `puzzle` was generated by this family
+    pub fn is_member(&self, puzzle: &Timelock) -> bool {
+        puzzle.n == self.n && puzzle.squarings == self.squarings
+    }
 
-        let e = BigUint::from(2 as u8).modpow(&BigUint::from(squarings), &phi);
-        let answer = a.modpow(&e, &n);
+    /// Verifier of a single puzzle of this family, for answers which can't be batched
+    pub fn verifier(&self, puzzle: Timelock) -> TimelockVerifier {
+        let answer = puzzle.a.modpow(&self.e, &self.n);
+        TimelockVerifier { answer, puzzle }
+    }
 
-        (Timelock { a, n, squarings }, TimelockVerifier { answer })
+    /// Checks answers to puzzles of this family with a single exponentiation by the trapdoor:
+    /// `(a_1^r_1 * ... * a_k^r_k)^e == y_1^r_1 * ... * y_k^r_k (mod n)` for random 64 bit `r_i`,
+    /// which a wrong answer passes with negligible probability. Answers are only checked up to
+    /// a factor of small order, e.g. `n - y` passes for `y`, but those can't be found without
+    /// doing the squarings either. False if any of the puzzles is not of this family.
+    pub fn verify_batch(&self, rng: &mut dyn RngCore, rounds: &[(Timelock, BigUint)]) -> bool {
+        let zero = BigUint::from(0u8);
+        let mut bases = BigUint::from(1u8);
+        let mut answers = BigUint::from(1u8);
+        for (puzzle, answer) in rounds {
+            if !self.is_member(puzzle) || answer == &zero || answer >= &self.n {
+                return false;
+            }
+            let r = BigUint::from(rng.next_u64());
+            bases = bases * puzzle.a.modpow(&r, &self.n) % &self.n;
+            answers = answers * answer.modpow(&r, &self.n) % &self.n;
+        }
+        bases.modpow(&self.e, &self.n) == answers
     }
 }
 
+/// Miller-Rabin test of an odd `n` greater than the largest base
+fn is_probable_prime(n: &BigUint) -> bool {
+    let one = BigUint::from(1u8);
+    let n_minus_one = n - &one;
+    let s = n_minus_one.trailing_zeros().unwrap_or(0);
+    let d = &n_minus_one >> s as usize;
+
+    MILLER_RABIN_BASES.iter().all(|base| {
+        let mut x = BigUint::from(*base).modpow(&d, n);
+        if x == one || x == n_minus_one {
+            return true;
+        }
+        for _ in 1..s {
+            x = x.modpow(&BigUint::from(2u8), n);
+            if x == n_minus_one {
+                return true;
+            }
+        }
+        false
+    })
+}
+
 #[cfg(feature = "std")]
 pub struct TimelockVerifier {
     answer: BigUint,
+    puzzle: Timelock,
 }
 
 #[cfg(feature = "std")]
@@ -129,22 +325,29 @@ impl TimelockVerifier {
     pub fn verify(&self, client_response: BigUint) -> bool {
         self.answer.eq(&client_response)
     }
+
+    /// Checks the answer using the trapdoor, and its proof the same way a third party would
+    pub fn verify_with_proof(&self, client_response: BigUint, proof: BigUint) -> bool {
+        self.puzzle.verify_proof(&client_response, &proof) && self.verify(client_response)
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::challenges::timelock::Timelock;
+    use crate::challenges::primes::GlassPumpkin;
+    use crate::challenges::timelock::{Timelock, TimelockFamily};
+    use crate::std_alloc::Vec;
     use core::ops::{Add, Sub};
     use rand::rngs::OsRng;
 
     #[test]
     fn test_timelock_correctness() {
         let mut rng = OsRng::default();
-        let (timelock, verifier) = Timelock::generate(&mut rng, 30);
+        let (timelock, verifier) = Timelock::generate(&mut rng, 30).unwrap();
         assert_eq!(timelock.perform_challenge(), verifier.answer);
 
         // Since, generated challenge are random, no two instances should be same.
-        let (new_timelock, new_timelock_verifier) = Timelock::generate(&mut rng, 30);
+        let (new_timelock, new_timelock_verifier) = Timelock::generate(&mut rng, 30).unwrap();
         assert_ne!(new_timelock.perform_challenge(), verifier.answer);
 
         // Modifying any of the parameter result in different answer.
@@ -183,10 +386,81 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_timelock_proof() {
+        let mut rng = OsRng;
+        let (timelock, verifier) = Timelock::generate(&mut rng, 1000).unwrap();
+        let (answer, proof) = timelock.perform_challenge_with_proof();
+        assert!(timelock.verify_proof(&answer, &proof));
+        assert!(verifier.verify_with_proof(answer.clone(), proof.clone()));
+
+        // Wrong answer with the proof of the right one
+        let wrong_answer = answer.clone().add(1u8);
+        assert!(!timelock.verify_proof(&wrong_answer, &proof));
+        // Tampered proof
+        assert!(!timelock.verify_proof(&answer, &proof.clone().add(1u8)));
+        // Proof of a different number of squarings
+        let fewer_squarings = Timelock {
+            squarings: 999,
+            ..timelock.clone()
+        };
+        let (other_answer, other_proof) = fewer_squarings.perform_challenge_with_proof();
+        assert!(!timelock.verify_proof(&other_answer, &other_proof));
+        // Values outside of the group
+        assert!(!timelock.verify_proof(&timelock.n, &proof));
+    }
+
+    #[test]
+    fn test_timelock_batch() {
+        let mut rng = OsRng;
+        let family = TimelockFamily::generate(&mut rng, 30, &GlassPumpkin, 128).unwrap();
+        let mut rounds = (0..4)
+            .map(|_| {
+                let puzzle = family.puzzle(&mut rng);
+                let answer = puzzle.perform_challenge();
+                (puzzle, answer)
+            })
+            .collect::<Vec<_>>();
+        assert!(family.verify_batch(&mut rng, &rounds));
+        let (puzzle, answer) = rounds[2].clone();
+        assert!(family.verifier(puzzle).verify(answer));
+
+        // A single wrong answer fails the whole batch
+        rounds[2].1 = rounds[2].1.clone().add(1u8);
+        assert!(!family.verify_batch(&mut rng, &rounds));
+
+        // Puzzles of another family can't be batched with this one
+        let (other, _) = Timelock::generate(&mut rng, 30).unwrap();
+        let other_answer = other.perform_challenge();
+        rounds[2] = (other, other_answer);
+        assert!(!family.verify_batch(&mut rng, &rounds));
+    }
+
+    #[test]
+    fn test_timelock_bind() {
+        let mut rng = OsRng;
+        let family = TimelockFamily::generate(&mut rng, 30, &GlassPumpkin, 128).unwrap();
+        let puzzle = family.puzzle(&mut rng);
+        assert_eq!(puzzle.bind(&[]).a, puzzle.a);
+
+        // Server verifies the bound puzzle it sent unbound, client binds it before solving
+        let bound = puzzle.bind(&[1, 2, 3]);
+        assert!(family.is_member(&bound));
+        let received = Timelock::from_wire(puzzle.to_wire()).unwrap();
+        let answer = received.bind(&[1, 2, 3]).perform_challenge();
+        assert!(family.verifier(bound.clone()).verify(answer.clone()));
+        assert!(family.verify_batch(&mut rng, &[(bound, answer.clone())]));
+        // Answer for another nonce, or of the unbound puzzle, is wrong
+        assert!(!family.verifier(puzzle.bind(&[1, 2, 4])).verify(answer));
+        assert!(!family
+            .verifier(puzzle.bind(&[1, 2, 3]))
+            .verify(received.perform_challenge()));
+    }
+
     #[test]
     fn test_timelock_to_wire_success() {
         let mut rng = OsRng::default();
-        let (timelock, verifier) = Timelock::generate(&mut rng, 30);
+        let (timelock, verifier) = Timelock::generate(&mut rng, 30).unwrap();
         let wire_output = timelock.to_wire();
         let possible_constructed_timelock = Timelock::from_wire(wire_output);
         assert!(possible_constructed_timelock.is_ok());
@@ -201,7 +475,7 @@ mod test {
     #[test]
     fn test_timelock_to_wire_failure() {
         let mut rng = OsRng::default();
-        let (timelock, verifier) = Timelock::generate(&mut rng, 30);
+        let (timelock, verifier) = Timelock::generate(&mut rng, 30).unwrap();
         let mut wire_output = timelock.to_wire();
 
         let mut first_wire_output = wire_output.clone();
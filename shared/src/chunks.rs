@@ -0,0 +1,151 @@
+//! Chunked framing of messages too large for a single websocket frame, as some proxies
+//! refuse frames above a size limit. Such a message is encoded as usual, its bytes are split
+//! into `Message::ChallengeChunk` messages each sent in its own frame, and the receiver
+//! reassembles them before decoding the message.
+
+use crate::std_alloc::Vec;
+use crate::Message;
+use anyhow::{anyhow, Result};
+use core::convert::TryFrom;
+
+/// Upper bound of bytes encoded `Message::ChallengeChunk` adds to its data
+pub const CHUNK_OVERHEAD_BYTES: usize = 32;
+
+/// Splits encoded message into frames of at most `max_frame_bytes`. Message which fits a frame
+/// is returned as is, a larger one as encoded `Message::ChallengeChunk` messages.
+pub fn split(encoded: Vec<u8>, max_frame_bytes: usize) -> Result<Vec<Vec<u8>>> {
+    if encoded.len() <= max_frame_bytes {
+        return Ok(vec![encoded]);
+    }
+    let chunk_bytes = match max_frame_bytes.checked_sub(CHUNK_OVERHEAD_BYTES) {
+        Some(chunk_bytes) if chunk_bytes > 0 => chunk_bytes,
+        _ => {
+            return Err(anyhow!(
+                "Frames of {} bytes are too small to carry a chunk",
+                max_frame_bytes
+            ))
+        }
+    };
+    let total = u32::try_from(encoded.chunks(chunk_bytes).len())
+        .map_err(|_| anyhow!("Message of {} bytes has too many chunks", encoded.len()))?;
+    encoded
+        .chunks(chunk_bytes)
+        .zip(0..)
+        .map(|(data, index)| {
+            Message::ChallengeChunk {
+                index,
+                total,
+                data: data.to_vec(),
+            }
+            .encode()
+        })
+        .collect()
+}
+
+/// Reassembles a message received as `Message::ChallengeChunk` messages
+pub struct ChunkAssembler {
+    max_bytes: usize,
+    total: u32,
+    received: u32,
+    bytes: Vec<u8>,
+}
+
+impl ChunkAssembler {
+    /// Messages larger than `max_bytes` are refused before they are buffered whole
+    pub fn new(max_bytes: usize) -> Self {
+        ChunkAssembler {
+            max_bytes,
+            total: 0,
+            received: 0,
+            bytes: Vec::new(),
+        }
+    }
+
+    /// Adds the next chunk, chunks have to arrive in order. Returns the message once its last
+    /// chunk arrived, assembler can then be reused for the next one.
+    pub fn push(&mut self, index: u32, total: u32, data: &[u8]) -> Result<Option<Message>> {
+        let expected_total = if self.received == 0 {
+            total
+        } else {
+            self.total
+        };
+        if index != self.received || total != expected_total || index >= total {
+            return Err(anyhow!(
+                "Expected chunk {} of {}, got chunk {} of {}",
+                self.received,
+                expected_total,
+                index,
+                total
+            ));
+        }
+        if self.bytes.len() + data.len() > self.max_bytes {
+            return Err(anyhow!("Chunked message exceeds {} bytes", self.max_bytes));
+        }
+        self.total = total;
+        self.received += 1;
+        self.bytes.extend_from_slice(data);
+        if self.received < total {
+            return Ok(None);
+        }
+        self.received = 0;
+        let bytes = core::mem::take(&mut self.bytes);
+        Message::decode(&bytes).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{split, ChunkAssembler, CHUNK_OVERHEAD_BYTES};
+    use crate::std_alloc::Vec;
+    use crate::{Challenge, Message};
+
+    fn reassemble(frames: &[Vec<u8>]) -> Message {
+        let mut assembler = ChunkAssembler::new(1 << 20);
+        let mut reassembled = None;
+        for frame in frames {
+            match Message::decode(frame).unwrap() {
+                Message::ChallengeChunk { index, total, data } => {
+                    assert!(reassembled.is_none());
+                    reassembled = assembler.push(index, total, &data).unwrap();
+                }
+                msg => panic!("Unexpected message {}", msg),
+            }
+        }
+        reassembled.unwrap()
+    }
+
+    #[test]
+    fn test_split_and_reassemble() {
+        let payload: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+        let message = Message::Challenge(Challenge::NetworkChallenge(payload.clone()));
+        let encoded = message.encode().unwrap();
+
+        // Message which fits a frame isn't chunked
+        assert_eq!(
+            split(encoded.clone(), encoded.len()).unwrap(),
+            vec![encoded.clone()]
+        );
+
+        let frames = split(encoded, 1024).unwrap();
+        assert_eq!(frames.len(), 11);
+        assert!(frames.iter().all(|frame| frame.len() <= 1024));
+        match reassemble(&frames) {
+            Message::Challenge(Challenge::NetworkChallenge(data)) => assert_eq!(data, payload),
+            msg => panic!("Unexpected message {}", msg),
+        }
+
+        assert!(split(vec![0; 100], CHUNK_OVERHEAD_BYTES).is_err());
+    }
+
+    #[test]
+    fn test_assembler_refuses_bad_chunks() {
+        let mut assembler = ChunkAssembler::new(8);
+        // Out of order
+        assert!(assembler.push(1, 2, &[1]).is_err());
+        assert!(assembler.push(0, 2, &[1]).unwrap().is_none());
+        // Total changed midway
+        assert!(assembler.push(1, 3, &[1]).is_err());
+        // Over the size limit
+        assert!(ChunkAssembler::new(8).push(0, 2, &[0; 9]).is_err());
+    }
+}
@@ -0,0 +1,178 @@
+//! Server's wall clock as streamed at `/time`, for clients which timestamp events themselves
+//! during long monitoring sessions. Server sends a `TimeTick` in a binary frame at a fixed
+//! cadence, client feeds each one to a `ClockDiscipline` along with the local time it arrived at
+//! and corrects its own timestamps with the fitted offset and drift.
+
+use crate::std_alloc::Vec;
+use anyhow::{anyhow, Result};
+use byteorder::{ByteOrder, NetworkEndian};
+
+/// Bytes of a serialized `TimeTick`
+pub const TICK_SIZE: usize = 16;
+
+/// Wall clock of the server when it sent the tick
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimeTick {
+    /// Position of the tick in the stream, gaps mean ticks were dropped
+    pub sequence: u64,
+    pub unix_microseconds: u64,
+}
+
+impl TimeTick {
+    /// Serialized form is sequence and timestamp
+    pub fn to_wire(&self) -> Vec<u8> {
+        let mut result = vec![0u8; TICK_SIZE];
+        NetworkEndian::write_u64(&mut result[..8], self.sequence);
+        NetworkEndian::write_u64(&mut result[8..], self.unix_microseconds);
+        result
+    }
+
+    pub fn from_wire(data: &[u8]) -> Result<Self> {
+        if data.len() != TICK_SIZE {
+            return Err(anyhow!("unable to parse wire data"));
+        }
+        Ok(TimeTick {
+            sequence: NetworkEndian::read_u64(&data[..8]),
+            unix_microseconds: NetworkEndian::read_u64(&data[8..]),
+        })
+    }
+}
+
+/// Offset and drift of the local clock against the server's, fitted by least squares over the
+/// most recent ticks. Offsets include the one-way delay of the stream, so corrected timestamps
+/// lag the server's by about that much.
+#[derive(Clone, Debug)]
+pub struct ClockDiscipline {
+    max_samples: usize,
+    /// Local arrival time and server minus local time of each tick, oldest first
+    samples: Vec<(u64, i64)>,
+}
+
+impl ClockDiscipline {
+    /// Fits over the last `max_samples` ticks, at least two
+    pub fn new(max_samples: usize) -> Self {
+        ClockDiscipline {
+            max_samples: max_samples.max(2),
+            samples: Vec::new(),
+        }
+    }
+
+    /// Records `tick`, which arrived when the local clock read `local_unix_microseconds`
+    pub fn observe(&mut self, tick: &TimeTick, local_unix_microseconds: u64) {
+        if self.samples.len() == self.max_samples {
+            self.samples.remove(0);
+        }
+        let offset = tick.unix_microseconds as i64 - local_unix_microseconds as i64;
+        self.samples.push((local_unix_microseconds, offset));
+    }
+
+    /// Number of ticks the fit is over
+    pub fn samples(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Intercept at the first sample and slope of the offset per microsecond of local time,
+    /// `None` without samples. The slope is zero until ticks span some time.
+    fn fit(&self) -> Option<(u64, f64, f64)> {
+        let (origin, _) = *self.samples.first()?;
+        let n = self.samples.len() as f64;
+        let points = self
+            .samples
+            .iter()
+            .map(|(local, offset)| (*local as f64 - origin as f64, *offset as f64));
+        let (sum_x, sum_y) = points
+            .clone()
+            .fold((0.0, 0.0), |(sum_x, sum_y), (x, y)| (sum_x + x, sum_y + y));
+        let (mean_x, mean_y) = (sum_x / n, sum_y / n);
+        let (covariance, variance) = points.fold((0.0, 0.0), |(covariance, variance), (x, y)| {
+            (
+                covariance + (x - mean_x) * (y - mean_y),
+                variance + (x - mean_x) * (x - mean_x),
+            )
+        });
+        let slope = if variance > 0.0 {
+            covariance / variance
+        } else {
+            0.0
+        };
+        Some((origin, mean_y - slope * mean_x, slope))
+    }
+
+    /// Rate the local clock gains on the server's in parts per million, negative if it is
+    /// slower. `None` until two ticks arrived.
+    pub fn drift_ppm(&self) -> Option<f64> {
+        if self.samples.len() < 2 {
+            return None;
+        }
+        self.fit().map(|(_, _, slope)| -slope * 1_000_000.0)
+    }
+
+    /// Server minus local time when the local clock reads `local_unix_microseconds`
+    pub fn offset_microseconds(&self, local_unix_microseconds: u64) -> Option<i64> {
+        self.fit().map(|(origin, intercept, slope)| {
+            let elapsed = local_unix_microseconds as f64 - origin as f64;
+            (intercept + slope * elapsed) as i64
+        })
+    }
+
+    /// Server's time when the local clock reads `local_unix_microseconds`
+    pub fn server_time(&self, local_unix_microseconds: u64) -> Option<u64> {
+        self.offset_microseconds(local_unix_microseconds)
+            .map(|offset| (local_unix_microseconds as i64 + offset).max(0) as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::{ClockDiscipline, TimeTick};
+
+    #[test]
+    fn test_time_tick() {
+        let tick = TimeTick {
+            sequence: 7,
+            unix_microseconds: 1_700_000_000_000_000,
+        };
+        assert_eq!(TimeTick::from_wire(&tick.to_wire()).unwrap(), tick);
+        assert!(TimeTick::from_wire(&[0; 8]).is_err());
+    }
+
+    #[test]
+    fn test_clock_discipline() {
+        let mut discipline = ClockDiscipline::new(100);
+        assert_eq!(discipline.server_time(1000), None);
+
+        // Local clock is 5ms behind and gains 50 microseconds per second, ticks take 300
+        // microseconds to arrive give or take 20
+        let start = 1_700_000_000_000_000u64;
+        for sequence in 0..200u64 {
+            let server = start + sequence * 1_000_000;
+            let jitter = if sequence % 2 == 0 { 20 } else { -20 };
+            let local = server as i64 - 5000 + 300 + jitter + (sequence as i64 * 50);
+            let tick = TimeTick {
+                sequence,
+                unix_microseconds: server,
+            };
+            discipline.observe(&tick, local as u64);
+        }
+        assert_eq!(discipline.samples(), 100);
+        let drift = discipline.drift_ppm().unwrap();
+        assert!((drift - 50.0).abs() < 1.0, "{}", drift);
+        // Arrival of the last tick maps to about the time server sent it, as offsets include the
+        // delay
+        let local = start + 199 * 1_000_000 - 5000 + 300 + 199 * 50;
+        let server = discipline.server_time(local).unwrap();
+        let error = server as i64 - (start + 199 * 1_000_000) as i64;
+        assert!(error.abs() <= 30, "{}", error);
+
+        let mut single = ClockDiscipline::new(10);
+        single.observe(
+            &TimeTick {
+                sequence: 0,
+                unix_microseconds: 2000,
+            },
+            1500,
+        );
+        assert_eq!(single.drift_ppm(), None);
+        assert_eq!(single.server_time(1600), Some(2100));
+    }
+}
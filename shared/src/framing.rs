@@ -0,0 +1,103 @@
+//! Length prefixed framing of messages over byte streams without message boundaries of their
+//! own, e.g. plain TCP. Each encoded message is preceded by its length in bytes as a 4 byte
+//! big-endian integer.
+
+use crate::std_alloc::Vec;
+use anyhow::{anyhow, Result};
+use core::convert::TryFrom;
+
+/// Bytes of the length prefix preceding each message
+pub const LENGTH_PREFIX_BYTES: usize = 4;
+
+/// Prefixes encoded message with its length
+pub fn frame(encoded: &[u8]) -> Result<Vec<u8>> {
+    let length = u32::try_from(encoded.len())
+        .map_err(|_| anyhow!("Message of {} bytes is too large to frame", encoded.len()))?;
+    let mut framed = Vec::with_capacity(LENGTH_PREFIX_BYTES + encoded.len());
+    framed.extend_from_slice(&length.to_be_bytes());
+    framed.extend_from_slice(encoded);
+    Ok(framed)
+}
+
+/// Splits bytes read from the stream back into encoded messages
+pub struct FrameDecoder {
+    max_frame_bytes: usize,
+    buffer: Vec<u8>,
+}
+
+impl FrameDecoder {
+    /// Messages larger than `max_frame_bytes` are refused before they are buffered whole
+    pub fn new(max_frame_bytes: usize) -> Self {
+        FrameDecoder {
+            max_frame_bytes,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Buffers bytes read from the stream
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Next complete message, `None` until all its bytes were buffered
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>> {
+        if self.buffer.len() < LENGTH_PREFIX_BYTES {
+            return Ok(None);
+        }
+        let mut prefix = [0; LENGTH_PREFIX_BYTES];
+        prefix.copy_from_slice(&self.buffer[..LENGTH_PREFIX_BYTES]);
+        let length = u32::from_be_bytes(prefix) as usize;
+        if length > self.max_frame_bytes {
+            return Err(anyhow!(
+                "Message of {} bytes exceeds {} bytes",
+                length,
+                self.max_frame_bytes
+            ));
+        }
+        if self.buffer.len() < LENGTH_PREFIX_BYTES + length {
+            return Ok(None);
+        }
+        let rest = self.buffer.split_off(LENGTH_PREFIX_BYTES + length);
+        let mut framed = core::mem::replace(&mut self.buffer, rest);
+        Ok(Some(framed.split_off(LENGTH_PREFIX_BYTES)))
+    }
+
+    /// Whether no part of a message is buffered, i.e. stream can end now without cutting one off
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{frame, FrameDecoder};
+    use crate::{Challenge, Message};
+
+    #[test]
+    fn test_frame_and_decode() {
+        let first = Message::Challenge(Challenge::NetworkChallenge(vec![1; 300]))
+            .encode()
+            .unwrap();
+        let second = Message::Challenge(Challenge::LatencyProbe(vec![2; 8]))
+            .encode()
+            .unwrap();
+        let mut stream = frame(&first).unwrap();
+        stream.extend(frame(&second).unwrap());
+
+        // Bytes arrive in pieces unrelated to message boundaries
+        let mut decoder = FrameDecoder::new(1024);
+        let mut frames = vec![];
+        for piece in stream.chunks(7) {
+            decoder.extend(piece);
+            while let Some(frame) = decoder.next_frame().unwrap() {
+                frames.push(frame);
+            }
+        }
+        assert_eq!(frames, vec![first, second]);
+        assert!(decoder.is_empty());
+
+        let mut decoder = FrameDecoder::new(100);
+        decoder.extend(&frame(&[0; 101]).unwrap()[..4]);
+        assert!(decoder.next_frame().is_err());
+    }
+}
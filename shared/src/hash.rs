@@ -0,0 +1,129 @@
+use crate::std_alloc::Vec;
+use core::hash::Hasher;
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use twox_hash::XxHash64;
+
+/// Hash algorithms which can be used to verify integrity of roundtrip data.
+/// Algorithm is negotiated during the handshake.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Sha256,
+    Blake3,
+    /// Non-cryptographic hash, a client can easily forge data having the same hash.
+    /// Should only be used for deployments where clients are trusted.
+    XxHash64,
+}
+
+impl HashAlgorithm {
+    /// Whether it is infeasible to construct different data having the same hash
+    pub fn is_cryptographic(&self) -> bool {
+        match self {
+            HashAlgorithm::Sha256 | HashAlgorithm::Blake3 => true,
+            HashAlgorithm::XxHash64 => false,
+        }
+    }
+
+    pub fn digest(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            HashAlgorithm::Sha256 => Sha256Digest::digest(data),
+            HashAlgorithm::Blake3 => Blake3Digest::digest(data),
+            HashAlgorithm::XxHash64 => XxHash64Digest::digest(data),
+        }
+    }
+
+    /// Digest of `parts` concatenated, without copying them into one buffer
+    pub fn digest_parts(&self, parts: &[&[u8]]) -> Vec<u8> {
+        match self {
+            HashAlgorithm::Sha256 => {
+                let mut hasher = Sha256::new();
+                parts.iter().for_each(|part| hasher.update(part));
+                hasher.finalize().to_vec()
+            }
+            HashAlgorithm::Blake3 => {
+                let mut hasher = blake3::Hasher::new();
+                parts.iter().for_each(|part| {
+                    hasher.update(part);
+                });
+                blake3::Hasher::finalize(&hasher).as_bytes().to_vec()
+            }
+            HashAlgorithm::XxHash64 => {
+                let mut hasher = XxHash64::with_seed(0);
+                parts.iter().for_each(|part| hasher.write(part));
+                hasher.finish().to_be_bytes().to_vec()
+            }
+        }
+    }
+}
+
+/// Common interface of digest functions used for roundtrip verification
+pub trait DataDigest {
+    fn digest(data: &[u8]) -> Vec<u8>;
+}
+
+pub struct Sha256Digest;
+
+impl DataDigest for Sha256Digest {
+    fn digest(data: &[u8]) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        hasher.update(data);
+        hasher.finalize().to_vec()
+    }
+}
+
+pub struct Blake3Digest;
+
+impl DataDigest for Blake3Digest {
+    fn digest(data: &[u8]) -> Vec<u8> {
+        blake3::hash(data).as_bytes().to_vec()
+    }
+}
+
+pub struct XxHash64Digest;
+
+impl DataDigest for XxHash64Digest {
+    fn digest(data: &[u8]) -> Vec<u8> {
+        let mut hasher = XxHash64::with_seed(0);
+        hasher.write(data);
+        hasher.finish().to_be_bytes().to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hash::HashAlgorithm;
+
+    #[test]
+    fn test_digests() {
+        let data = [1u8, 2, 3, 4];
+        let mut modified_data = data;
+        modified_data[3] = 5;
+
+        for algorithm in &[
+            HashAlgorithm::Sha256,
+            HashAlgorithm::Blake3,
+            HashAlgorithm::XxHash64,
+        ] {
+            assert_eq!(algorithm.digest(&data), algorithm.digest(&data));
+            assert_ne!(algorithm.digest(&data), algorithm.digest(&modified_data));
+        }
+
+        assert_eq!(HashAlgorithm::Sha256.digest(&data).len(), 32);
+        assert_eq!(HashAlgorithm::Blake3.digest(&data).len(), 32);
+        assert_eq!(HashAlgorithm::XxHash64.digest(&data).len(), 8);
+    }
+
+    #[test]
+    fn test_digest_parts() {
+        for algorithm in &[
+            HashAlgorithm::Sha256,
+            HashAlgorithm::Blake3,
+            HashAlgorithm::XxHash64,
+        ] {
+            assert_eq!(
+                algorithm.digest_parts(&[&[1, 2], &[], &[3, 4]]),
+                algorithm.digest(&[1, 2, 3, 4])
+            );
+        }
+    }
+}
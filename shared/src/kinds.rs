@@ -0,0 +1,155 @@
+//! Numeric ids of the variants of wire enums (`Message`, `Challenge`, `Response`, `Data`,
+//! `SessionStatus`, `RejectionReason`, `ErrorCode`).
+//!
+//! Every variant is encoded as its id followed by its payload, so adding or reordering
+//! variants never changes meaning of already assigned ids.
+//! * ids `1..=1023` are allocated by this crate, in order, and are never reused or reassigned
+//! * ids `1024..=65535` are reserved for deployment specific extensions, this crate never allocates them
+//! * id `0` is reserved for `Message::Unknown`
+//!
+//! A peer decodes variants with ids it does not know into the `Unknown`/`Unsupported`
+//! variant of the enum instead of failing.
+
+/// First id reserved for deployment specific extensions
+pub const FIRST_EXTENSION_ID: u16 = 1024;
+
+pub mod message {
+    pub const UNKNOWN: u16 = 0;
+    pub const CHALLENGE: u16 = 1;
+    pub const RESPONSE: u16 = 2;
+    pub const DATA: u16 = 3;
+    pub const HANDSHAKE: u16 = 4;
+    pub const SESSION_CLOSED: u16 = 5;
+    pub const MEASUREMENT_REPORT: u16 = 6;
+    pub const PROGRESS: u16 = 7;
+    pub const REJECTED: u16 = 8;
+    pub const CHALLENGE_CHUNK: u16 = 9;
+    pub const BOUND_CHALLENGE: u16 = 10;
+}
+
+pub mod challenge {
+    pub const CPU_CHALLENGE: u16 = 1;
+    pub const NETWORK_CHALLENGE: u16 = 2;
+    pub const SPOT_CHECKED_NETWORK_CHALLENGE: u16 = 3;
+    pub const DISK_CHALLENGE: u16 = 4;
+    pub const LATENCY_PROBE: u16 = 5;
+    pub const DOWNLOAD_CHALLENGE: u16 = 6;
+    pub const UPLOAD_CHALLENGE: u16 = 7;
+    pub const DATAGRAM_CHALLENGE: u16 = 8;
+    pub const CHUNK_PROOF_REQUEST: u16 = 9;
+}
+
+pub mod response {
+    pub const CPU_CHALLENGE_RESPONSE: u16 = 1;
+    pub const NETWORK_CHALLENGE_RESPONSE: u16 = 2;
+    pub const SPOT_CHECKED_NETWORK_CHALLENGE_RESPONSE: u16 = 3;
+    pub const UNSUPPORTED_CHALLENGE: u16 = 4;
+    pub const DISK_CHALLENGE_RESPONSE: u16 = 5;
+    pub const CPU_CHALLENGE_PROOF_RESPONSE: u16 = 6;
+    pub const LATENCY_PROBE_RESPONSE: u16 = 7;
+    pub const DOWNLOAD_CHALLENGE_RESPONSE: u16 = 8;
+    pub const UPLOAD_CHALLENGE_RESPONSE: u16 = 9;
+    pub const DATAGRAM_CHALLENGE_RESPONSE: u16 = 10;
+    pub const CHUNK_PROOF_RESPONSE: u16 = 11;
+}
+
+pub mod data {
+    pub const INFO: u16 = 1;
+    pub const ERROR: u16 = 2;
+    pub const RESULT: u16 = 3;
+    /// `Data::Error` with an error code, `ERROR` carries text only
+    pub const CODED_ERROR: u16 = 4;
+}
+
+pub mod session_status {
+    pub const COMPLETED: u16 = 1;
+    pub const FAILED: u16 = 2;
+}
+
+pub mod rejection_reason {
+    pub const RATE_LIMITED: u16 = 1;
+    pub const CAPACITY: u16 = 2;
+    pub const MAINTENANCE: u16 = 3;
+    pub const BANNED: u16 = 4;
+    pub const UNAUTHORIZED: u16 = 5;
+}
+
+pub mod network_type {
+    pub const WIFI: u16 = 1;
+    pub const ETHERNET: u16 = 2;
+    pub const CELLULAR: u16 = 3;
+}
+
+pub mod error_code {
+    pub const CHALLENGE_FAILED: u16 = 1;
+    pub const TIMEOUT: u16 = 2;
+    pub const PROTOCOL_VIOLATION: u16 = 3;
+    pub const RATE_LIMITED: u16 = 4;
+    pub const UNSUPPORTED_VERSION: u16 = 5;
+    pub const MEMORY_LIMIT_EXCEEDED: u16 = 6;
+}
+
+// Assigned ids are part of the protocol, changing any of them breaks compatibility
+// with already deployed peers, so the build fails if one of them changes.
+const _: () = {
+    assert!(message::UNKNOWN == 0);
+    assert!(message::CHALLENGE == 1);
+    assert!(message::RESPONSE == 2);
+    assert!(message::DATA == 3);
+    assert!(message::HANDSHAKE == 4);
+    assert!(message::SESSION_CLOSED == 5);
+    assert!(message::MEASUREMENT_REPORT == 6);
+    assert!(message::PROGRESS == 7);
+    assert!(message::REJECTED == 8);
+    assert!(message::CHALLENGE_CHUNK == 9);
+    assert!(message::BOUND_CHALLENGE == 10);
+
+    assert!(challenge::CPU_CHALLENGE == 1);
+    assert!(challenge::NETWORK_CHALLENGE == 2);
+    assert!(challenge::SPOT_CHECKED_NETWORK_CHALLENGE == 3);
+    assert!(challenge::DISK_CHALLENGE == 4);
+    assert!(challenge::LATENCY_PROBE == 5);
+    assert!(challenge::DOWNLOAD_CHALLENGE == 6);
+    assert!(challenge::UPLOAD_CHALLENGE == 7);
+    assert!(challenge::DATAGRAM_CHALLENGE == 8);
+    assert!(challenge::CHUNK_PROOF_REQUEST == 9);
+
+    assert!(response::CPU_CHALLENGE_RESPONSE == 1);
+    assert!(response::NETWORK_CHALLENGE_RESPONSE == 2);
+    assert!(response::SPOT_CHECKED_NETWORK_CHALLENGE_RESPONSE == 3);
+    assert!(response::UNSUPPORTED_CHALLENGE == 4);
+    assert!(response::DISK_CHALLENGE_RESPONSE == 5);
+    assert!(response::CPU_CHALLENGE_PROOF_RESPONSE == 6);
+    assert!(response::LATENCY_PROBE_RESPONSE == 7);
+    assert!(response::DOWNLOAD_CHALLENGE_RESPONSE == 8);
+    assert!(response::UPLOAD_CHALLENGE_RESPONSE == 9);
+    assert!(response::DATAGRAM_CHALLENGE_RESPONSE == 10);
+    assert!(response::CHUNK_PROOF_RESPONSE == 11);
+
+    assert!(data::INFO == 1);
+    assert!(data::ERROR == 2);
+    assert!(data::RESULT == 3);
+    assert!(data::CODED_ERROR == 4);
+
+    assert!(session_status::COMPLETED == 1);
+    assert!(session_status::FAILED == 2);
+
+    assert!(rejection_reason::RATE_LIMITED == 1);
+    assert!(rejection_reason::CAPACITY == 2);
+    assert!(rejection_reason::MAINTENANCE == 3);
+    assert!(rejection_reason::BANNED == 4);
+    assert!(rejection_reason::UNAUTHORIZED == 5);
+
+    assert!(network_type::WIFI == 1);
+    assert!(network_type::ETHERNET == 2);
+    assert!(network_type::CELLULAR == 3);
+
+    assert!(error_code::CHALLENGE_FAILED == 1);
+    assert!(error_code::TIMEOUT == 2);
+    assert!(error_code::PROTOCOL_VIOLATION == 3);
+    assert!(error_code::RATE_LIMITED == 4);
+    assert!(error_code::UNSUPPORTED_VERSION == 5);
+    assert!(error_code::MEMORY_LIMIT_EXCEEDED == 6);
+
+    assert!(FIRST_EXTENSION_ID == 1024);
+};
@@ -7,6 +7,7 @@ extern crate std;
 #[cfg(feature = "std")]
 mod std_alloc {
     pub(crate) use std::borrow::ToOwned;
+    pub(crate) use std::boxed::Box;
     pub(crate) use std::string::String;
     pub(crate) use std::vec::Vec;
 }
@@ -18,40 +19,421 @@ extern crate alloc;
 #[cfg(not(feature = "std"))]
 mod std_alloc {
     pub(crate) use alloc::borrow::ToOwned;
+    pub(crate) use alloc::boxed::Box;
     pub(crate) use alloc::string::String;
     pub(crate) use alloc::vec::Vec;
 }
 
 pub mod challenges;
+pub mod chunks;
+pub mod clock;
+pub mod framing;
+pub mod hash;
+pub mod kinds;
+pub mod mac;
+pub mod merkle;
+pub mod nonce;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+mod wire;
 
 use anyhow::{anyhow, Result};
+use challenges::bandwidth::ThroughputStats;
+use challenges::datagram::DatagramStats;
+use challenges::latency::LatencyStats;
 use core::fmt::{self, Display};
+use hash::HashAlgorithm;
 use serde_derive::{Deserialize, Serialize};
-use std_alloc::{String, ToOwned, Vec};
+use std_alloc::{Box, String, ToOwned, Vec};
 
-#[derive(Debug, Deserialize, Serialize)]
+/// Challenges sent by server, see `kinds::challenge` for their wire ids
+#[derive(Debug, PartialEq)]
 pub enum Challenge {
     CPUChallenge(Vec<u8>),
     NetworkChallenge(Vec<u8>),
+    /// Same as `NetworkChallenge`, but client must respond with
+    /// `Response::SpotCheckedNetworkChallengeResponse`
+    SpotCheckedNetworkChallenge(Vec<u8>),
+    /// Blob client must write to persistent storage, read back and respond with
+    /// its hash in `Response::DiskChallengeResponse`
+    DiskChallenge(Vec<u8>),
+    /// Probe of a latency measurement, client must echo it right away in
+    /// `Response::LatencyProbeResponse`, see `challenges::latency`
+    LatencyProbe(Vec<u8>),
+    /// Bulk data client must respond to with only its hash in `Response::DownloadChallengeResponse`,
+    /// see `challenges::bandwidth::Download`
+    DownloadChallenge(Vec<u8>),
+    /// Seed client must expand into data and upload in `Response::UploadChallengeResponse`,
+    /// see `challenges::bandwidth::Upload`
+    UploadChallenge(Vec<u8>),
+    /// Train of probes client must echo over UDP, then respond with how many it received in
+    /// `Response::DatagramChallengeResponse`, see `challenges::datagram`
+    DatagramChallenge(Vec<u8>),
+    /// Index of a chunk of the payload of the preceding `SpotCheckedNetworkChallenge`, see
+    /// `merkle::encode_chunk_index`. Client must respond with `Response::ChunkProofResponse`.
+    /// Sent for chunks which came back corrupted, so that only they are sent again instead of
+    /// the whole payload.
+    ChunkProofRequest(Vec<u8>),
+    /// Challenge of a kind unknown to this version of the protocol.
+    /// Client must respond to it with `Response::UnsupportedChallenge`.
+    Unsupported {
+        kind_id: u16,
+        payload: Vec<u8>,
+    },
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+impl Challenge {
+    pub fn kind_id(&self) -> u16 {
+        match self {
+            Challenge::CPUChallenge(_) => kinds::challenge::CPU_CHALLENGE,
+            Challenge::NetworkChallenge(_) => kinds::challenge::NETWORK_CHALLENGE,
+            Challenge::SpotCheckedNetworkChallenge(_) => {
+                kinds::challenge::SPOT_CHECKED_NETWORK_CHALLENGE
+            }
+            Challenge::DiskChallenge(_) => kinds::challenge::DISK_CHALLENGE,
+            Challenge::LatencyProbe(_) => kinds::challenge::LATENCY_PROBE,
+            Challenge::DownloadChallenge(_) => kinds::challenge::DOWNLOAD_CHALLENGE,
+            Challenge::UploadChallenge(_) => kinds::challenge::UPLOAD_CHALLENGE,
+            Challenge::DatagramChallenge(_) => kinds::challenge::DATAGRAM_CHALLENGE,
+            Challenge::ChunkProofRequest(_) => kinds::challenge::CHUNK_PROOF_REQUEST,
+            Challenge::Unsupported { kind_id, .. } => *kind_id,
+        }
+    }
+}
+
+/// Client's responses to challenges, see `kinds::response` for their wire ids
+#[derive(Debug, PartialEq)]
 pub enum Response {
     CPUChallengeResponse(Vec<u8>),
     NetworkChallengeResponse(Vec<u8>),
+    /// Returned data along with merkle root of it, calculated using `merkle::merkle_root`
+    SpotCheckedNetworkChallengeResponse {
+        data: Vec<u8>,
+        merkle_root: Vec<u8>,
+    },
+    /// Response to a `Challenge::Unsupported`, or to any challenge client can't perform
+    UnsupportedChallenge {
+        kind_id: u16,
+    },
+    /// Hash of the blob read back from persistent storage, calculated using the negotiated algorithm
+    DiskChallengeResponse(Vec<u8>),
+    /// Alternative to `CPUChallengeResponse`: answer along with its Wesolowski proof, see
+    /// `Timelock::perform_challenge_with_proof`. Both are big endian integers.
+    /// Anyone can verify it from the public puzzle, which makes the measurement auditable.
+    CPUChallengeProofResponse {
+        answer: Vec<u8>,
+        proof: Vec<u8>,
+    },
+    /// Unmodified probe of a `Challenge::LatencyProbe`
+    LatencyProbeResponse(Vec<u8>),
+    /// Hash of the data of a `Challenge::DownloadChallenge`, calculated using the negotiated algorithm
+    DownloadChallengeResponse(Vec<u8>),
+    /// Data expanded from the seed of a `Challenge::UploadChallenge`
+    UploadChallengeResponse(Vec<u8>),
+    /// `challenges::datagram::DatagramReceipt` of a `Challenge::DatagramChallenge`
+    DatagramChallengeResponse(Vec<u8>),
+    /// Chunk of a `Challenge::ChunkProofRequest` along with its proof against the merkle root
+    /// returned with the payload (before it was bound to the nonce), see `merkle::encode_proof`
+    ChunkProofResponse {
+        chunk: Vec<u8>,
+        proof: Vec<u8>,
+    },
+    /// Response of a kind unknown to this version of the protocol
+    Unknown {
+        kind_id: u16,
+    },
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// See `kinds::data` for wire ids
+#[derive(Debug, PartialEq)]
 pub enum Data {
     /// Indicates general log/information we want to be displayed at client side.
     Info(String),
     /// Indicates that an error occurred on other side of the connection
-    Error(String),
-    /// Same as `Info` but is used to convey results of the measurements
+    Error { code: ErrorCode, message: String },
+    /// Same as `Info` but is used to convey results of the measurements.
+    /// Superseded by `Message::MeasurementReport`, which clients can act on without parsing text.
     Result(String),
+    /// Data of a kind unknown to this version of the protocol
+    Unknown { kind_id: u16 },
 }
 
+/// Longest `client_version` of `Handshake::ClientHello` server accepts
+pub const MAX_CLIENT_VERSION_BYTES: usize = 64;
+
+/// Messages exchanged before measurements start, to agree on session parameters
 #[derive(Debug, Deserialize, Serialize)]
+pub enum Handshake {
+    /// Sent by server as the first message of the session
+    ServerHello {
+        /// Identifier of the session, also used as id of the stored run.
+        /// Should be reported along with any issue, to correlate it with server records.
+        session_id: String,
+        /// Supported algorithms in order of server preference
+        hash_algorithms: Vec<HashAlgorithm>,
+        /// Limits of the session, so client can adapt to them upfront
+        limits: SessionLimits,
+        /// Token identifying the client across sessions, runs of sessions started with the same
+        /// token are kept as one history. Client should keep it secret and present it as
+        /// `client_token` query parameter when it reconnects. `None` if server issues no tokens.
+        #[serde(default)]
+        client_token: Option<String>,
+        /// X25519 key of the server if it authenticates messages of the session, see `mac`.
+        /// `None` if it doesn't.
+        #[serde(default)]
+        mac_public_key: Option<Vec<u8>>,
+    },
+    /// Client's answer to `ServerHello`
+    ClientHello {
+        /// One of the algorithms offered by server
+        hash_algorithm: HashAlgorithm,
+        /// Whether client reassembles messages sent as `Message::ChallengeChunk`,
+        /// server never sends chunks to a client which doesn't
+        #[serde(default)]
+        supports_chunks: bool,
+        /// Kind of link client is measured over, as far as client knows. `None` if it doesn't
+        /// know or won't tell, runs are then scored with the plan's own thresholds.
+        #[serde(default)]
+        network_type: Option<NetworkType>,
+        /// Software version or deployment label of the client, e.g. `1.4.2` or `canary`, at
+        /// most `MAX_CLIENT_VERSION_BYTES` long. Stored with the run, so that scores can be
+        /// compared across releases of the client.
+        #[serde(default)]
+        client_version: Option<String>,
+        /// Whether client binds nonces into its responses, server sends such a client every
+        /// challenge but latency and datagram probes as `Message::BoundChallenge`, see `nonce`
+        #[serde(default)]
+        binds_nonces: bool,
+        /// X25519 key of the client, answering `mac_public_key` of `ServerHello`. Once it is
+        /// sent, every frame of both ends is sealed, see `mac`. `None` leaves frames unsealed.
+        #[serde(default)]
+        mac_public_key: Option<Vec<u8>>,
+    },
+}
+
+/// Kind of link a client declares in `Handshake::ClientHello`, see `kinds::network_type` for
+/// wire ids
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum NetworkType {
+    Wifi,
+    Ethernet,
+    Cellular,
+    /// Type unknown to this version of the protocol
+    Unknown {
+        kind_id: u16,
+    },
+}
+
+impl NetworkType {
+    /// Name used in configuration and the API, e.g. `wifi`
+    pub fn name(&self) -> &'static str {
+        match self {
+            NetworkType::Wifi => "wifi",
+            NetworkType::Ethernet => "ethernet",
+            NetworkType::Cellular => "cellular",
+            NetworkType::Unknown { .. } => "unknown",
+        }
+    }
+
+    /// Type of `name`, `None` for `unknown` and names of no type
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "wifi" => Some(NetworkType::Wifi),
+            "ethernet" => Some(NetworkType::Ethernet),
+            "cellular" => Some(NetworkType::Cellular),
+            _ => None,
+        }
+    }
+}
+
+/// Limits server advertises in `Handshake::ServerHello`
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SessionLimits {
+    /// Largest payload of a network challenge, in kilobytes
+    pub max_payload_size_kb: u64,
+    /// Number of CPU challenge rounds in the session
+    pub cpu_rounds: u32,
+    /// Number of network challenge rounds in the session
+    pub network_rounds: u32,
+    /// Number of disk challenge rounds in the session
+    pub disk_rounds: u32,
+    /// Kinds of challenges client will be sent, see `kinds::challenge`
+    pub challenge_kinds: Vec<u16>,
+    /// Message encodings server understands, e.g. `messagepack`
+    pub encodings: Vec<String>,
+    /// Time within which a CPU challenge must be answered, a slower round fails the session
+    pub cpu_round_timeout_milliseconds: u64,
+    /// Time within which a network challenge must be answered, a slower round fails the session
+    pub network_round_timeout_milliseconds: u64,
+    /// Number of latency probes in the session, each has to be echoed within the network round timeout
+    #[serde(default)]
+    pub latency_probes: u32,
+    /// Number of download challenge rounds in the session
+    #[serde(default)]
+    pub download_rounds: u32,
+    /// Number of upload challenge rounds in the session
+    #[serde(default)]
+    pub upload_rounds: u32,
+    /// Number of probes sent over UDP in the session, see `challenges::datagram`
+    #[serde(default)]
+    pub datagram_probes: u32,
+    /// Largest websocket frame server sends to clients which support chunks, larger messages
+    /// are split into `Message::ChallengeChunk` messages. Clients should split their responses
+    /// the same way, see `chunks`. `None` if frames aren't limited.
+    #[serde(default)]
+    pub max_frame_size_kb: Option<u64>,
+}
+
+/// Results of a measured challenge kind, part of `MeasurementReport`
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ChallengeReport {
+    /// Kind of the challenge, see `kinds::challenge`
+    pub kind_id: u16,
+    /// Time client took to answer each round
+    pub round_milliseconds: Vec<u64>,
+    /// Score of this challenge alone, within `0..=100`
+    pub sub_score: u64,
+    /// Rounds at or below this time get no penalty
+    pub ideal_milliseconds: u64,
+    /// A round slower than this scores the whole session 0
+    pub max_milliseconds: u64,
+    /// Squarings of CPU challenges, payload size in kilobytes of other challenges
+    pub size: u64,
+}
+
+/// Sub-scores of challenge kinds and the score they combine to, all within `0.0..=100.0`
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct ScoreBreakdown {
+    /// `None` for challenge kinds without rounds
+    pub cpu_score: Option<f64>,
+    pub network_score: Option<f64>,
+    pub disk_score: Option<f64>,
+    /// Sub-scores weighted by the scoring model of the server, 0 if any round was too slow or
+    /// answered faster than server deems possible
+    pub total: f64,
+    /// Rounds which scored the session 0, empty if none did
+    #[serde(default)]
+    pub rejections: Vec<ScoreRejection>,
+}
+
+/// Round which took longer than `max_milliseconds` of its challenge, scoring the session 0 unless
+/// server measured it leniently
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ScoreRejection {
+    /// Kind of the challenge, see `kinds::challenge`
+    pub kind_id: u16,
+    /// Index of the round among rounds of its kind, starting at 0
+    pub round: u32,
+    /// Time of the round as it was scored, see `ChallengeReport::round_milliseconds`
+    pub round_milliseconds: u64,
+    pub max_milliseconds: u64,
+    /// `round_milliseconds - max_milliseconds`
+    pub exceeded_by_milliseconds: u64,
+}
+
+/// Results of a session, sent by server once measurements are done
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct MeasurementReport {
+    /// Same as `session_id` of `Handshake::ServerHello`
+    pub session_id: String,
+    /// Aggregated score, within `0..=100`
+    pub score: u64,
+    /// Hash algorithm negotiated for the session
+    pub hash_algorithm: HashAlgorithm,
+    /// Challenge kinds which had at least one round, in order they were performed
+    pub challenges: Vec<ChallengeReport>,
+    /// Round trip times of latency probes, `None` if session had none. Not part of the score.
+    #[serde(default)]
+    pub latency: Option<LatencyStats>,
+    /// Throughput from server to client, `None` if session had no download rounds. Not part of the score.
+    #[serde(default)]
+    pub download: Option<ThroughputStats>,
+    /// Throughput from client to server, `None` if session had no upload rounds. Not part of the score.
+    #[serde(default)]
+    pub upload: Option<ThroughputStats>,
+    /// Unrounded sub-scores and score, `score` is `total` rounded
+    #[serde(default)]
+    pub score_breakdown: ScoreBreakdown,
+    /// Time TLS handshake of the connection took as seen by the server, `None` if server didn't
+    /// terminate TLS of the session. Network timings don't include it, not part of the score.
+    #[serde(default)]
+    pub tls_handshake_milliseconds: Option<u64>,
+    /// Losses and round trip times of probes sent over UDP, `None` if session had none or client
+    /// couldn't take part. Not part of the score.
+    #[serde(default)]
+    pub datagram: Option<DatagramStats>,
+}
+
+/// Running aggregates of a session, sent by server after every completed round so that long
+/// sessions give feedback before they end. Provisional, final results are those of `MeasurementReport`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ProgressUpdate {
+    /// Kind of the challenge of the completed round, see `kinds::challenge`
+    pub kind_id: u16,
+    /// Rounds of all challenge kinds completed so far
+    pub rounds_completed: u32,
+    /// Rounds of all challenge kinds in the session
+    pub total_rounds: u32,
+    /// Time client took to answer the completed round
+    pub round_milliseconds: u64,
+    /// Mean time of the rounds of this kind so far
+    pub mean_milliseconds: u64,
+    /// Score of the rounds of this kind so far, within `0..=100`
+    pub sub_score: u64,
+}
+
+/// Outcome of a session, see `kinds::session_status` for wire ids
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SessionStatus {
+    /// All measurements were performed
+    Completed,
+    /// Session ended early because of an error
+    Failed,
+    /// Status of a kind unknown to this version of the protocol
+    Unknown { kind_id: u16 },
+}
+
+/// Why server refused to measure a client, see `kinds::rejection_reason` for wire ids
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RejectionReason {
+    /// Client started too many sessions recently
+    RateLimited,
+    /// Server has no resources for another session
+    Capacity,
+    /// Server is not accepting sessions while it is being maintained
+    Maintenance,
+    /// Client is not allowed to be measured by this server
+    Banned,
+    /// Server only measures clients which authenticate, and the client didn't
+    Unauthorized,
+    /// Reason of a kind unknown to this version of the protocol
+    Unknown { kind_id: u16 },
+}
+
+/// What went wrong, carried by `Data::Error` so that clients can act on errors without
+/// parsing their text. See `kinds::error_code` for wire ids.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorCode {
+    /// Answer to a challenge was wrong or too slow
+    ChallengeFailed,
+    /// Peer didn't answer in time
+    Timeout,
+    /// Peer sent a message it wasn't expected to send at that point of the session
+    ProtocolViolation,
+    /// Peer sent too many requests
+    RateLimited,
+    /// Peer speaks a version of the protocol this one can't serve
+    UnsupportedVersion,
+    /// Session needed more memory than it's allowed
+    MemoryLimitExceeded,
+    /// Error of a peer which predates error codes, sent without one
+    Unspecified,
+    /// Code of a kind unknown to this version of the protocol
+    Unknown { kind_id: u16 },
+}
+
+/// See `kinds::message` for wire ids
+#[derive(Debug)]
 pub enum Message {
     /// Challenge message sent by server to client
     Challenge(Challenge),
@@ -59,8 +441,47 @@ pub enum Message {
     Response(Response),
     /// General data
     Data(Data),
-    /// Invalid Message; usually indicates a bug
+    /// Invalid Message or message of a kind unknown to this version of the protocol
     Unknown,
+    /// Session parameters negotiation
+    Handshake(Handshake),
+    /// Last message sent by server before closing the connection, sent even when
+    /// session ended early
+    SessionClosed {
+        status: SessionStatus,
+        /// Number of challenge rounds client completed
+        runs_completed: u32,
+        /// Id the run was stored under, `None` if results were not persisted
+        stored_run_id: Option<String>,
+    },
+    /// Results of the measurements
+    MeasurementReport(Box<MeasurementReport>),
+    /// Running aggregates of the session, sent after every completed round
+    Progress(ProgressUpdate),
+    /// Sent instead of `ServerHello` when server refuses to start a session, connection is
+    /// closed right after it
+    Rejected {
+        reason: RejectionReason,
+        /// Seconds client should wait before trying again, `None` if retrying is pointless
+        retry_after_seconds: Option<u64>,
+        /// Human readable explanation
+        message: String,
+    },
+    /// Slice of a challenge or response too large for a single websocket frame,
+    /// see `chunks` for splitting and reassembly
+    ChallengeChunk {
+        /// Position of the chunk, chunks are sent in order
+        index: u32,
+        /// Number of chunks the message was split into
+        total: u32,
+        data: Vec<u8>,
+    },
+    /// Challenge whose response must have `nonce` bound into it, see `nonce`. Only sent to
+    /// clients which negotiated `binds_nonces`.
+    BoundChallenge {
+        nonce: Vec<u8>,
+        challenge: Challenge,
+    },
 }
 
 impl Message {
@@ -86,7 +507,395 @@ impl Display for Message {
                 Message::Response(_) => "Response".to_owned(),
                 Message::Data(_) => "Data".to_owned(),
                 Message::Unknown => "Unknown".to_owned(),
+                Message::Handshake(_) => "Handshake".to_owned(),
+                Message::SessionClosed { .. } => "SessionClosed".to_owned(),
+                Message::MeasurementReport(_) => "MeasurementReport".to_owned(),
+                Message::Progress(_) => "Progress".to_owned(),
+                Message::Rejected { .. } => "Rejected".to_owned(),
+                Message::ChallengeChunk { .. } => "ChallengeChunk".to_owned(),
+                Message::BoundChallenge { .. } => "BoundChallenge".to_owned(),
             }
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::challenges::bandwidth::ThroughputStats;
+    use crate::challenges::datagram::{DatagramEchoes, DatagramStats};
+    use crate::challenges::latency::LatencyStats;
+    use crate::hash::HashAlgorithm;
+    use crate::kinds;
+    use crate::std_alloc::{Box, String, ToOwned};
+    use crate::{
+        Challenge, ChallengeReport, Data, ErrorCode, Handshake, MeasurementReport, Message,
+        NetworkType, ProgressUpdate, RejectionReason, Response, ScoreBreakdown, SessionLimits,
+        SessionStatus,
+    };
+
+    #[test]
+    fn test_challenge_roundtrip() {
+        let message = Message::Challenge(Challenge::NetworkChallenge(vec![1, 2, 255]));
+        match Message::decode(&message.encode().unwrap()).unwrap() {
+            Message::Challenge(challenge) => {
+                assert_eq!(challenge, Challenge::NetworkChallenge(vec![1, 2, 255]));
+                assert_eq!(challenge.kind_id(), kinds::challenge::NETWORK_CHALLENGE);
+            }
+            msg => panic!("Unexpected message {}", msg),
+        }
+    }
+
+    #[test]
+    fn test_unknown_challenge_kind() {
+        // Simulates a challenge introduced by a newer version of the protocol
+        let message = Message::Challenge(Challenge::Unsupported {
+            kind_id: 999,
+            payload: vec![4, 5, 6],
+        });
+        match Message::decode(&message.encode().unwrap()).unwrap() {
+            Message::Challenge(challenge) => assert_eq!(
+                challenge,
+                Challenge::Unsupported {
+                    kind_id: 999,
+                    payload: vec![4, 5, 6]
+                }
+            ),
+            msg => panic!("Unexpected message {}", msg),
+        }
+    }
+
+    #[test]
+    fn test_wire_format_is_stable() {
+        // Encoding of messages must never change, otherwise deployed peers can't decode them
+        let message = Message::Data(Data::Info("hi".to_owned()));
+        assert_eq!(
+            message.encode().unwrap(),
+            vec![0x92, 0x03, 0x92, 0x01, 0xa2, b'h', b'i']
+        );
+
+        let message = Message::Response(Response::NetworkChallengeResponse(vec![7]));
+        assert_eq!(
+            message.encode().unwrap(),
+            vec![0x92, 0x02, 0x92, 0x02, 0xc4, 0x01, 0x07]
+        );
+    }
+
+    #[test]
+    fn test_server_hello_roundtrip() {
+        let limits = SessionLimits {
+            max_payload_size_kb: 1024,
+            cpu_rounds: 5,
+            network_rounds: 10,
+            disk_rounds: 0,
+            challenge_kinds: vec![kinds::challenge::CPU_CHALLENGE],
+            encodings: vec!["messagepack".to_owned()],
+            cpu_round_timeout_milliseconds: 120000,
+            network_round_timeout_milliseconds: 25000,
+            latency_probes: 10,
+            download_rounds: 2,
+            upload_rounds: 2,
+            datagram_probes: 50,
+            max_frame_size_kb: Some(64),
+        };
+        let message = Message::Handshake(Handshake::ServerHello {
+            session_id: "abc".to_owned(),
+            hash_algorithms: vec![HashAlgorithm::Sha256],
+            limits: limits.clone(),
+            client_token: Some("token".to_owned()),
+            mac_public_key: Some(vec![9; 32]),
+        });
+        match Message::decode(&message.encode().unwrap()).unwrap() {
+            Message::Handshake(Handshake::ServerHello {
+                limits: decoded,
+                client_token,
+                mac_public_key,
+                ..
+            }) => {
+                assert_eq!(decoded, limits);
+                assert_eq!(client_token.as_deref(), Some("token"));
+                assert_eq!(mac_public_key, Some(vec![9; 32]));
+            }
+            msg => panic!("Unexpected message {}", msg),
+        }
+    }
+
+    #[test]
+    fn test_session_closed_roundtrip() {
+        let message = Message::SessionClosed {
+            status: SessionStatus::Failed,
+            runs_completed: 3,
+            stored_run_id: None,
+        };
+        match Message::decode(&message.encode().unwrap()).unwrap() {
+            Message::SessionClosed {
+                status,
+                runs_completed,
+                stored_run_id,
+            } => {
+                assert_eq!(status, SessionStatus::Failed);
+                assert_eq!(runs_completed, 3);
+                assert_eq!(stored_run_id, None);
+            }
+            msg => panic!("Unexpected message {}", msg),
+        }
+    }
+
+    #[test]
+    fn test_measurement_report_roundtrip() {
+        let report = MeasurementReport {
+            session_id: "abc".to_owned(),
+            score: 85,
+            hash_algorithm: HashAlgorithm::Blake3,
+            challenges: vec![ChallengeReport {
+                kind_id: kinds::challenge::NETWORK_CHALLENGE,
+                round_milliseconds: vec![300, 400],
+                sub_score: 85,
+                ideal_milliseconds: 200,
+                max_milliseconds: 2200,
+                size: 1024,
+            }],
+            latency: Some(LatencyStats {
+                probes: 10,
+                min_microseconds: 900,
+                mean_microseconds: 1100,
+                max_microseconds: 1500,
+                jitter_microseconds: 150,
+            }),
+            download: ThroughputStats::new(1024, vec![100, 120]),
+            upload: None,
+            score_breakdown: ScoreBreakdown {
+                cpu_score: None,
+                network_score: Some(85.0),
+                disk_score: None,
+                total: 85.0,
+                rejections: vec![],
+            },
+            tls_handshake_milliseconds: Some(12),
+            datagram: Some(DatagramStats::new(
+                50,
+                48,
+                &DatagramEchoes {
+                    round_trips: vec![900; 45],
+                    reordered: 2,
+                    duplicates: 1,
+                },
+            )),
+        };
+        let message = Message::MeasurementReport(Box::new(report.clone()));
+        match Message::decode(&message.encode().unwrap()).unwrap() {
+            Message::MeasurementReport(decoded) => assert_eq!(*decoded, report),
+            msg => panic!("Unexpected message {}", msg),
+        }
+    }
+
+    #[test]
+    fn test_client_hello_roundtrip() {
+        for network_type in [
+            Some(NetworkType::Cellular),
+            Some(NetworkType::Unknown { kind_id: 1500 }),
+            None,
+        ] {
+            let message = Message::Handshake(Handshake::ClientHello {
+                hash_algorithm: HashAlgorithm::Blake3,
+                supports_chunks: true,
+                network_type,
+                client_version: Some("1.4.2".to_owned()),
+                binds_nonces: true,
+                mac_public_key: None,
+            });
+            match Message::decode(&message.encode().unwrap()).unwrap() {
+                Message::Handshake(Handshake::ClientHello {
+                    network_type: decoded,
+                    client_version,
+                    binds_nonces,
+                    ..
+                }) => {
+                    assert_eq!(decoded, network_type);
+                    assert_eq!(client_version.as_deref(), Some("1.4.2"));
+                    assert!(binds_nonces);
+                }
+                msg => panic!("Unexpected message {}", msg),
+            }
+        }
+        assert_eq!(NetworkType::from_name("wifi"), Some(NetworkType::Wifi));
+        assert_eq!(
+            NetworkType::from_name(NetworkType::Ethernet.name()),
+            Some(NetworkType::Ethernet)
+        );
+        assert_eq!(NetworkType::from_name("unknown"), None);
+    }
+
+    #[test]
+    fn test_bound_challenge_roundtrip() {
+        let message = Message::BoundChallenge {
+            nonce: vec![7; 16],
+            challenge: Challenge::DownloadChallenge(vec![1, 2, 3]),
+        };
+        match Message::decode(&message.encode().unwrap()).unwrap() {
+            Message::BoundChallenge { nonce, challenge } => {
+                assert_eq!(nonce, vec![7; 16]);
+                assert_eq!(challenge, Challenge::DownloadChallenge(vec![1, 2, 3]));
+            }
+            msg => panic!("Unexpected message {}", msg),
+        }
+    }
+
+    #[test]
+    fn test_rejected_roundtrip() {
+        let message = Message::Rejected {
+            reason: RejectionReason::Capacity,
+            retry_after_seconds: Some(30),
+            message: "Server is low on memory".to_owned(),
+        };
+        match Message::decode(&message.encode().unwrap()).unwrap() {
+            Message::Rejected {
+                reason,
+                retry_after_seconds,
+                message,
+            } => {
+                assert_eq!(reason, RejectionReason::Capacity);
+                assert_eq!(retry_after_seconds, Some(30));
+                assert_eq!(message, "Server is low on memory");
+            }
+            msg => panic!("Unexpected message {}", msg),
+        }
+
+        let message = Message::Rejected {
+            reason: RejectionReason::Unknown { kind_id: 1500 },
+            retry_after_seconds: None,
+            message: String::new(),
+        };
+        match Message::decode(&message.encode().unwrap()).unwrap() {
+            Message::Rejected {
+                reason,
+                retry_after_seconds,
+                ..
+            } => {
+                assert_eq!(reason, RejectionReason::Unknown { kind_id: 1500 });
+                assert_eq!(retry_after_seconds, None);
+            }
+            msg => panic!("Unexpected message {}", msg),
+        }
+    }
+
+    #[test]
+    fn test_error_roundtrip() {
+        for code in [
+            ErrorCode::ChallengeFailed,
+            ErrorCode::Timeout,
+            ErrorCode::ProtocolViolation,
+            ErrorCode::MemoryLimitExceeded,
+            ErrorCode::Unspecified,
+            ErrorCode::Unknown { kind_id: 1500 },
+        ] {
+            let message = Message::Data(Data::Error {
+                code,
+                message: "No response within 5s".to_owned(),
+            });
+            match Message::decode(&message.encode().unwrap()).unwrap() {
+                Message::Data(data) => assert_eq!(
+                    data,
+                    Data::Error {
+                        code,
+                        message: "No response within 5s".to_owned()
+                    }
+                ),
+                msg => panic!("Unexpected message {}", msg),
+            }
+        }
+
+        // Errors of peers which predate error codes carry text only
+        let bytes = vec![0x92, 0x03, 0x92, 0x02, 0xa2, b'h', b'i'];
+        match Message::decode(&bytes).unwrap() {
+            Message::Data(data) => assert_eq!(
+                data,
+                Data::Error {
+                    code: ErrorCode::Unspecified,
+                    message: "hi".to_owned()
+                }
+            ),
+            msg => panic!("Unexpected message {}", msg),
+        }
+        let message = Message::Data(Data::Error {
+            code: ErrorCode::Unspecified,
+            message: "hi".to_owned(),
+        });
+        assert_eq!(message.encode().unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_progress_roundtrip() {
+        let progress = ProgressUpdate {
+            kind_id: kinds::challenge::CPU_CHALLENGE,
+            rounds_completed: 2,
+            total_rounds: 15,
+            round_milliseconds: 4000,
+            mean_milliseconds: 4200,
+            sub_score: 100,
+        };
+        let message = Message::Progress(progress.clone());
+        match Message::decode(&message.encode().unwrap()).unwrap() {
+            Message::Progress(decoded) => assert_eq!(decoded, progress),
+            msg => panic!("Unexpected message {}", msg),
+        }
+    }
+
+    #[test]
+    fn test_cpu_challenge_proof_roundtrip() {
+        let message = Message::Response(Response::CPUChallengeProofResponse {
+            answer: vec![1, 2],
+            proof: vec![3],
+        });
+        match Message::decode(&message.encode().unwrap()).unwrap() {
+            Message::Response(response) => assert_eq!(
+                response,
+                Response::CPUChallengeProofResponse {
+                    answer: vec![1, 2],
+                    proof: vec![3]
+                }
+            ),
+            msg => panic!("Unexpected message {}", msg),
+        }
+    }
+
+    #[test]
+    fn test_chunk_proof_roundtrip() {
+        let message = Message::Challenge(Challenge::ChunkProofRequest(vec![0, 0, 0, 7]));
+        match Message::decode(&message.encode().unwrap()).unwrap() {
+            Message::Challenge(challenge) => {
+                assert_eq!(challenge, Challenge::ChunkProofRequest(vec![0, 0, 0, 7]));
+                assert_eq!(challenge.kind_id(), kinds::challenge::CHUNK_PROOF_REQUEST);
+            }
+            msg => panic!("Unexpected message {}", msg),
+        }
+        let message = Message::Response(Response::ChunkProofResponse {
+            chunk: vec![1, 2],
+            proof: vec![0; 33],
+        });
+        match Message::decode(&message.encode().unwrap()).unwrap() {
+            Message::Response(response) => assert_eq!(
+                response,
+                Response::ChunkProofResponse {
+                    chunk: vec![1, 2],
+                    proof: vec![0; 33]
+                }
+            ),
+            msg => panic!("Unexpected message {}", msg),
+        }
+    }
+
+    #[test]
+    fn test_unknown_kinds() {
+        let message = Message::Response(Response::Unknown { kind_id: 2000 });
+        match Message::decode(&message.encode().unwrap()).unwrap() {
+            Message::Response(response) => {
+                assert_eq!(response, Response::Unknown { kind_id: 2000 })
+            }
+            msg => panic!("Unexpected message {}", msg),
+        }
+
+        // Message of unknown kind with a non trivial payload
+        let bytes = vec![0x92, 0xcd, 0x07, 0xd0, 0x92, 0x01, 0xa2, b'h', b'i'];
+        assert!(matches!(Message::decode(&bytes).unwrap(), Message::Unknown));
+    }
+}
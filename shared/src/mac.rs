@@ -0,0 +1,181 @@
+//! Authentication of messages with a key agreed per session, for deployments whose transport
+//! doesn't protect them, e.g. plain TCP or TLS terminated by a proxy in front of the server.
+//!
+//! Server offers an X25519 public key in `ServerHello`, client answers with one of its own in
+//! `ClientHello`, and every frame after the `ClientHello` is sealed by `MessageAuthenticator`:
+//! the encoded message followed by a BLAKE3 keyed hash of its position in its direction of the
+//! session and the message. A frame which was tampered with, injected, replayed or reordered
+//! fails to open.
+//!
+//! Keys are ephemeral and tied to no identity, so this protects sessions from frames injected
+//! once they are established, not from an attacker in the middle of the handshake.
+
+use crate::std_alloc::Vec;
+use anyhow::{anyhow, Result};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+#[cfg(feature = "std")]
+use rand::RngCore;
+
+pub const PUBLIC_KEY_SIZE: usize = 32;
+/// Bytes a sealed frame is longer than the message
+pub const TAG_SIZE: usize = blake3::OUT_LEN;
+
+const CLIENT_KEY_CONTEXT: &str = "reliability-measurement-server 2020-11 client message key";
+const SERVER_KEY_CONTEXT: &str = "reliability-measurement-server 2020-11 server message key";
+
+/// End of the session a `MessageAuthenticator` seals messages of
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+/// Ephemeral key pair of one end of the session
+pub struct KeyExchange {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl KeyExchange {
+    /// Key pair of a secret of 32 random bytes
+    pub fn from_secret(secret: [u8; 32]) -> Self {
+        let secret = StaticSecret::from(secret);
+        let public = PublicKey::from(&secret);
+        KeyExchange { secret, public }
+    }
+
+    #[cfg(feature = "std")]
+    pub fn generate<RNG>(rng: &mut RNG) -> Self
+    where
+        RNG: RngCore,
+    {
+        let mut secret = [0u8; 32];
+        rng.fill_bytes(&mut secret);
+        Self::from_secret(secret)
+    }
+
+    /// Key sent to the peer in the handshake
+    pub fn public_key(&self) -> Vec<u8> {
+        self.public.as_bytes().to_vec()
+    }
+
+    /// Authenticator of the `role` end of the session, fails for a malformed key of the peer
+    /// or one of low order, which would give a shared secret known to anyone
+    pub fn agree(self, role: Role, peer_public_key: &[u8]) -> Result<MessageAuthenticator> {
+        if peer_public_key.len() != PUBLIC_KEY_SIZE {
+            return Err(anyhow!("Public key must be {} bytes", PUBLIC_KEY_SIZE));
+        }
+        let mut peer = [0u8; PUBLIC_KEY_SIZE];
+        peer.copy_from_slice(peer_public_key);
+        let shared = self.secret.diffie_hellman(&PublicKey::from(peer));
+        if shared.as_bytes() == &[0u8; 32] {
+            return Err(anyhow!("Public key of the peer gives no shared secret"));
+        }
+
+        let own = *self.public.as_bytes();
+        let (client, server) = match role {
+            Role::Client => (own, peer),
+            Role::Server => (peer, own),
+        };
+        let mut material = Vec::with_capacity(3 * 32);
+        material.extend_from_slice(shared.as_bytes());
+        material.extend_from_slice(&client);
+        material.extend_from_slice(&server);
+        let mut client_key = [0u8; blake3::KEY_LEN];
+        blake3::derive_key(CLIENT_KEY_CONTEXT, &material, &mut client_key);
+        let mut server_key = [0u8; blake3::KEY_LEN];
+        blake3::derive_key(SERVER_KEY_CONTEXT, &material, &mut server_key);
+
+        let (send_key, receive_key) = match role {
+            Role::Client => (client_key, server_key),
+            Role::Server => (server_key, client_key),
+        };
+        Ok(MessageAuthenticator {
+            send_key,
+            receive_key,
+            sent: 0,
+            received: 0,
+        })
+    }
+}
+
+/// Seals frames sent and opens frames received by one end of the session, in order
+pub struct MessageAuthenticator {
+    send_key: [u8; blake3::KEY_LEN],
+    receive_key: [u8; blake3::KEY_LEN],
+    sent: u64,
+    received: u64,
+}
+
+impl MessageAuthenticator {
+    /// Encoded `message` followed by its tag
+    pub fn seal(&mut self, mut message: Vec<u8>) -> Vec<u8> {
+        let tag = tag(&self.send_key, self.sent, &message);
+        self.sent += 1;
+        message.extend_from_slice(tag.as_bytes());
+        message
+    }
+
+    /// Encoded message of a frame sealed by the peer, fails if its tag doesn't match
+    pub fn open(&mut self, mut frame: Vec<u8>) -> Result<Vec<u8>> {
+        if frame.len() < TAG_SIZE {
+            return Err(anyhow!("Frame is too short to carry a tag"));
+        }
+        let received_tag = frame.split_off(frame.len() - TAG_SIZE);
+        let mut received = [0u8; TAG_SIZE];
+        received.copy_from_slice(&received_tag);
+        // Comparison of `blake3::Hash` takes constant time
+        if tag(&self.receive_key, self.received, &frame) != received {
+            return Err(anyhow!("Frame {} failed authentication", self.received));
+        }
+        self.received += 1;
+        Ok(frame)
+    }
+}
+
+fn tag(key: &[u8; blake3::KEY_LEN], position: u64, message: &[u8]) -> blake3::Hash {
+    let mut hasher = blake3::Hasher::new_keyed(key);
+    hasher.update(&position.to_be_bytes());
+    hasher.update(message);
+    hasher.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mac::{KeyExchange, Role, TAG_SIZE};
+    use rand::rngs::OsRng;
+
+    #[test]
+    fn test_sealed_frames() {
+        let client = KeyExchange::generate(&mut OsRng);
+        let server = KeyExchange::generate(&mut OsRng);
+        let client_public_key = client.public_key();
+        let server_public_key = server.public_key();
+        let mut client = client.agree(Role::Client, &server_public_key).unwrap();
+        let mut server = server.agree(Role::Server, &client_public_key).unwrap();
+
+        let first = server.seal(vec![1, 2, 3]);
+        assert_eq!(first.len(), 3 + TAG_SIZE);
+        let second = server.seal(vec![1, 2, 3]);
+        assert_ne!(first, second);
+        assert_eq!(client.open(first.clone()).unwrap(), vec![1, 2, 3]);
+        // Replayed frame
+        assert!(client.open(first).is_err());
+        assert_eq!(client.open(second).unwrap(), vec![1, 2, 3]);
+
+        let mut tampered = client.seal(vec![4, 5]);
+        tampered[0] ^= 1;
+        assert!(server.open(tampered).is_err());
+        // Frame sealed by the server can't be reflected back to it
+        let reflected = server.seal(vec![6]);
+        assert!(server.open(reflected).is_err());
+        assert!(server.open(vec![0; TAG_SIZE - 1]).is_err());
+
+        // Keys of low order give no shared secret
+        let exchange = KeyExchange::generate(&mut OsRng);
+        assert!(exchange.agree(Role::Server, &[0; 32]).is_err());
+        let exchange = KeyExchange::generate(&mut OsRng);
+        assert!(exchange.agree(Role::Server, &[1; 31]).is_err());
+    }
+}
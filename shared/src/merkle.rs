@@ -0,0 +1,275 @@
+use crate::std_alloc::Vec;
+use core::convert::TryInto;
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Size of a single leaf chunk of the merkle tree
+pub const CHUNK_SIZE: usize = 1024;
+
+/// Size of each hash of the tree
+pub const HASH_SIZE: usize = 32;
+
+// Domain separation prefixes, so that a leaf can never be confused with an inner node
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+/// Hashes a single chunk of the data into a leaf of the merkle tree
+pub fn hash_leaf(chunk: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_PREFIX]);
+    hasher.update(chunk);
+    hasher.finalize().to_vec()
+}
+
+/// Hashes two children into their parent node
+pub fn hash_node(left: &[u8], right: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().to_vec()
+}
+
+/// Number of `CHUNK_SIZE` chunks (last one can be shorter) `data_len` bytes are split into
+pub fn number_of_chunks(data_len: usize) -> usize {
+    if data_len == 0 {
+        1
+    } else {
+        data_len.div_ceil(CHUNK_SIZE)
+    }
+}
+
+/// Sibling hash needed to compute parent of a node while verifying a chunk.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct ProofStep {
+    pub sibling: Vec<u8>,
+    /// Whether sibling is the left child of the parent
+    pub sibling_is_left: bool,
+}
+
+/// Merkle tree over data split into `CHUNK_SIZE` chunks.
+/// If a level has odd number of nodes, last node is promoted to the next level as is.
+pub struct MerkleTree {
+    // levels[0] contains leaves, last level contains only the root
+    levels: Vec<Vec<Vec<u8>>>,
+}
+
+impl MerkleTree {
+    pub fn build(data: &[u8]) -> Self {
+        let mut level: Vec<Vec<u8>> = if data.is_empty() {
+            vec![hash_leaf(data)]
+        } else {
+            data.chunks(CHUNK_SIZE).map(hash_leaf).collect()
+        };
+        let mut levels = Vec::new();
+
+        while level.len() > 1 {
+            let next_level = level
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => hash_node(left, right),
+                    [single] => single.clone(),
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(level);
+            level = next_level;
+        }
+        levels.push(level);
+
+        MerkleTree { levels }
+    }
+
+    pub fn root(&self) -> &[u8] {
+        &self.levels[self.levels.len() - 1][0]
+    }
+
+    pub fn number_of_chunks(&self) -> usize {
+        self.levels[0].len()
+    }
+
+    /// Returns proof that chunk at `index` is part of this tree, `None` if `index` is out of range.
+    /// Proof can be checked against the root using `verify_proof`.
+    pub fn proof(&self, index: usize) -> Option<Vec<ProofStep>> {
+        if index >= self.number_of_chunks() {
+            return None;
+        }
+
+        let mut proof = Vec::new();
+        let mut index = index;
+        for level in &self.levels[..self.levels.len() - 1] {
+            let sibling_index = index ^ 1;
+            // Promoted node does not have a sibling on this level
+            if sibling_index < level.len() {
+                proof.push(ProofStep {
+                    sibling: level[sibling_index].clone(),
+                    sibling_is_left: sibling_index < index,
+                });
+            }
+            index /= 2;
+        }
+
+        Some(proof)
+    }
+}
+
+/// Chunk at `index` of the data split into `CHUNK_SIZE` chunks, `None` if `index` is out of range
+pub fn chunk(data: &[u8], index: usize) -> Option<&[u8]> {
+    data.chunks(CHUNK_SIZE).nth(index)
+}
+
+/// Chunk at `index` of `data` along with its encoded proof, answering a
+/// `Challenge::ChunkProofRequest`. `None` if `index` is out of range.
+pub fn chunk_with_proof(data: &[u8], index: usize) -> Option<(Vec<u8>, Vec<u8>)> {
+    let chunk = chunk(data, index)?.to_vec();
+    let proof = MerkleTree::build(data).proof(index)?;
+    Some((chunk, encode_proof(&proof)))
+}
+
+/// Serializes index of a chunk as payload of `Challenge::ChunkProofRequest`
+pub fn encode_chunk_index(index: u32) -> Vec<u8> {
+    index.to_be_bytes().to_vec()
+}
+
+/// Counterpart of `encode_chunk_index`, `None` if `payload` isn't an index
+pub fn decode_chunk_index(payload: &[u8]) -> Option<u32> {
+    let bytes: [u8; 4] = payload.try_into().ok()?;
+    Some(u32::from_be_bytes(bytes))
+}
+
+/// Serializes proof for `Response::ChunkProofResponse`, each step as `1` if its sibling is
+/// the left child or `0` if not, followed by the sibling
+pub fn encode_proof(proof: &[ProofStep]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(proof.len() * (1 + HASH_SIZE));
+    for step in proof {
+        encoded.push(step.sibling_is_left as u8);
+        encoded.extend_from_slice(&step.sibling);
+    }
+    encoded
+}
+
+/// Counterpart of `encode_proof`, `None` if `encoded` isn't a proof
+pub fn decode_proof(encoded: &[u8]) -> Option<Vec<ProofStep>> {
+    if !encoded.len().is_multiple_of(1 + HASH_SIZE) {
+        return None;
+    }
+    encoded
+        .chunks(1 + HASH_SIZE)
+        .map(|step| {
+            let sibling_is_left = match step[0] {
+                0 => false,
+                1 => true,
+                _ => return None,
+            };
+            Some(ProofStep {
+                sibling: step[1..].to_vec(),
+                sibling_is_left,
+            })
+        })
+        .collect()
+}
+
+/// Calculates merkle root of the data split into `CHUNK_SIZE` chunks.
+pub fn merkle_root(data: &[u8]) -> Vec<u8> {
+    MerkleTree::build(data).root().to_vec()
+}
+
+/// Checks that `chunk` is part of the data whose merkle root is `root`, using `proof`
+/// generated by `MerkleTree::proof`. This allows identifying a corrupted chunk without
+/// transferring or hashing the whole data again.
+pub fn verify_proof(root: &[u8], chunk: &[u8], proof: &[ProofStep]) -> bool {
+    let computed_root = proof.iter().fold(hash_leaf(chunk), |node, step| {
+        if step.sibling_is_left {
+            hash_node(&step.sibling, &node)
+        } else {
+            hash_node(&node, &step.sibling)
+        }
+    });
+
+    computed_root == root
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::merkle::{
+        chunk, chunk_with_proof, decode_chunk_index, decode_proof, encode_chunk_index,
+        encode_proof, hash_leaf, hash_node, merkle_root, number_of_chunks, verify_proof,
+        MerkleTree, CHUNK_SIZE,
+    };
+    use crate::std_alloc::Vec;
+
+    #[test]
+    fn test_merkle_root() {
+        let data = vec![7u8; CHUNK_SIZE * 2 + 10];
+        assert_eq!(number_of_chunks(data.len()), 3);
+
+        let expected = hash_node(
+            &hash_node(
+                &hash_leaf(&data[..CHUNK_SIZE]),
+                &hash_leaf(&data[CHUNK_SIZE..CHUNK_SIZE * 2]),
+            ),
+            &hash_leaf(&data[CHUNK_SIZE * 2..]),
+        );
+        assert_eq!(merkle_root(&data), expected);
+
+        // Single chunk root is the leaf itself
+        assert_eq!(merkle_root(&data[..10]), hash_leaf(&data[..10]));
+
+        // Modifying a single byte changes root
+        let mut modified_data = data.clone();
+        modified_data[CHUNK_SIZE + 1] = 8;
+        assert_ne!(merkle_root(&modified_data), expected);
+    }
+
+    #[test]
+    fn test_merkle_proof() {
+        let data: Vec<u8> = (0..(CHUNK_SIZE * 5 + 3)).map(|i| i as u8).collect();
+        let tree = MerkleTree::build(&data);
+        assert_eq!(tree.number_of_chunks(), 6);
+
+        for (index, chunk) in data.chunks(CHUNK_SIZE).enumerate() {
+            let proof = tree.proof(index).unwrap();
+            assert!(verify_proof(tree.root(), chunk, &proof));
+            // Proof of one chunk can't be used for another chunk
+            let mut corrupted_chunk = chunk.to_vec();
+            corrupted_chunk[0] ^= 1;
+            assert!(!verify_proof(tree.root(), &corrupted_chunk, &proof));
+        }
+
+        assert!(tree.proof(6).is_none());
+    }
+
+    #[test]
+    fn test_proof_encoding() {
+        let data: Vec<u8> = (0..(CHUNK_SIZE * 5 + 3)).map(|i| i as u8).collect();
+        let tree = MerkleTree::build(&data);
+        let proof = tree.proof(5).unwrap();
+        let encoded = encode_proof(&proof);
+        assert_eq!(decode_proof(&encoded).unwrap(), proof);
+        assert!(verify_proof(
+            tree.root(),
+            chunk(&data, 5).unwrap(),
+            &decode_proof(&encoded).unwrap()
+        ));
+        assert_eq!(chunk(&data, 5).unwrap().len(), 3);
+        assert!(chunk(&data, 6).is_none());
+
+        // Truncated proof and unknown direction of a sibling
+        assert!(decode_proof(&encoded[1..]).is_none());
+        let mut invalid = encoded.clone();
+        invalid[0] = 2;
+        assert!(decode_proof(&invalid).is_none());
+
+        let (chunk, proof) = chunk_with_proof(&data, 2).unwrap();
+        assert_eq!(chunk, data[CHUNK_SIZE * 2..CHUNK_SIZE * 3]);
+        assert!(verify_proof(
+            tree.root(),
+            &chunk,
+            &decode_proof(&proof).unwrap()
+        ));
+        assert!(chunk_with_proof(&data, 6).is_none());
+
+        assert_eq!(decode_chunk_index(&encode_chunk_index(7)), Some(7));
+        assert_eq!(decode_chunk_index(&[0, 7]), None);
+    }
+}
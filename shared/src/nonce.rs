@@ -0,0 +1,88 @@
+//! Nonces binding responses to the challenge they answer, so that a client which has seen a
+//! payload before can't answer it again from a cached result. Server sends a fresh nonce
+//! with every challenge in `Message::BoundChallenge` to clients which negotiated
+//! `binds_nonces`, and each kind folds it into its answer:
+//! * CPU challenges are solved for the base bound by `Timelock::bind`
+//! * network challenges echo the nonce followed by the data, spot-checked ones return
+//!   `bound_merkle_root` of the data instead of its merkle root
+//! * download and disk challenges return `bound_digest` of their data
+//! * upload challenges expand the seed followed by the nonce, see `Upload::perform_challenge`
+//!
+//! An empty nonce binds nothing, every function then gives the unbound answer.
+
+use crate::hash::HashAlgorithm;
+use crate::std_alloc::Vec;
+use sha2::{Digest, Sha256};
+
+#[cfg(feature = "std")]
+use rand::RngCore;
+
+/// Size of nonces generated by server
+pub const NONCE_SIZE: usize = 16;
+
+/// New nonce of `NONCE_SIZE` random bytes
+#[cfg(feature = "std")]
+pub fn generate<RNG>(rng: &mut RNG) -> Vec<u8>
+where
+    RNG: RngCore,
+{
+    let mut nonce = vec![0u8; NONCE_SIZE];
+    rng.fill_bytes(&mut nonce);
+    nonce
+}
+
+/// Digest of `nonce` followed by `data`
+pub fn bound_digest(algorithm: HashAlgorithm, nonce: &[u8], data: &[u8]) -> Vec<u8> {
+    algorithm.digest_parts(&[nonce, data])
+}
+
+/// SHA-256 of `nonce` followed by `merkle_root`, `merkle_root` itself for an empty nonce
+pub fn bound_merkle_root(nonce: &[u8], merkle_root: Vec<u8>) -> Vec<u8> {
+    if nonce.is_empty() {
+        return merkle_root;
+    }
+    let mut hasher = Sha256::new();
+    hasher.update(nonce);
+    hasher.update(&merkle_root);
+    hasher.finalize().to_vec()
+}
+
+/// `nonce` followed by `data`, which clients echo in response to a bound network challenge
+pub fn bound_echo(nonce: &[u8], mut data: Vec<u8>) -> Vec<u8> {
+    if !nonce.is_empty() {
+        data.splice(0..0, nonce.iter().copied());
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hash::HashAlgorithm;
+    use crate::nonce::{bound_digest, bound_echo, bound_merkle_root, generate, NONCE_SIZE};
+    use rand::rngs::OsRng;
+
+    #[test]
+    fn test_binding() {
+        let nonce = generate(&mut OsRng);
+        assert_eq!(nonce.len(), NONCE_SIZE);
+        assert_ne!(nonce, generate(&mut OsRng));
+
+        let data = [1u8, 2, 3];
+        assert_eq!(
+            bound_digest(HashAlgorithm::Blake3, &[], &data),
+            HashAlgorithm::Blake3.digest(&data)
+        );
+        assert_ne!(
+            bound_digest(HashAlgorithm::Blake3, &nonce, &data),
+            HashAlgorithm::Blake3.digest(&data)
+        );
+
+        assert_eq!(bound_merkle_root(&[], vec![4, 5]), vec![4, 5]);
+        assert_eq!(bound_merkle_root(&nonce, vec![4, 5]).len(), 32);
+
+        assert_eq!(bound_echo(&[], data.to_vec()), data.to_vec());
+        let echo = bound_echo(&nonce, data.to_vec());
+        assert_eq!(&echo[..NONCE_SIZE], &nonce[..]);
+        assert_eq!(&echo[NONCE_SIZE..], &data[..]);
+    }
+}
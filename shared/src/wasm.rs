@@ -0,0 +1,491 @@
+//! Bindings for browser clients, e.g. `wasm-pack build shared -- --no-default-features --features wasm`.
+//! Byte buffers cross the boundary as `Uint8Array`, errors are thrown as strings.
+
+use crate::challenges::bandwidth::{Download, Upload};
+use crate::challenges::roundtrip::Roundtrip;
+use crate::challenges::timelock::Timelock;
+use crate::hash::HashAlgorithm;
+use crate::merkle::{self, merkle_root};
+use crate::nonce;
+use crate::std_alloc::{String, Vec};
+use crate::{Challenge, Data, Handshake, Message, NetworkType, Response, SessionStatus};
+use core::convert::TryFrom;
+use wasm_bindgen::prelude::*;
+
+fn to_js_error(e: anyhow::Error) -> JsValue {
+    JsValue::from_str(&format!("{:?}", e))
+}
+
+/// Message decoded from a websocket frame. `kind` is the name of the message
+/// (`Challenge`, `BoundChallenge`, `Handshake`, `Data`, `SessionClosed`, ...), other getters
+/// are only set for the kinds they apply to.
+#[wasm_bindgen]
+#[derive(Default)]
+pub struct DecodedMessage {
+    kind: String,
+    kind_id: Option<u16>,
+    payload: Option<Vec<u8>>,
+    nonce: Option<Vec<u8>>,
+    text: Option<String>,
+    session_id: Option<String>,
+    client_token: Option<String>,
+    hash_algorithms: Vec<String>,
+    score: Option<u32>,
+    runs_completed: Option<u32>,
+    completed: Option<bool>,
+    stored_run_id: Option<String>,
+    rejection_reason: Option<String>,
+    retry_after_seconds: Option<u32>,
+    error_code: Option<String>,
+}
+
+#[wasm_bindgen]
+impl DecodedMessage {
+    #[wasm_bindgen(getter)]
+    pub fn kind(&self) -> String {
+        self.kind.clone()
+    }
+
+    /// Kind id of a challenge, or of the challenge a progress update is about, see `kinds::challenge`
+    #[wasm_bindgen(getter, js_name = kindId)]
+    pub fn kind_id(&self) -> Option<u16> {
+        self.kind_id
+    }
+
+    /// Payload of a challenge, to be passed to `Timelock.fromWire` or `Roundtrip.fromWire`
+    #[wasm_bindgen(getter)]
+    pub fn payload(&self) -> Option<Vec<u8>> {
+        self.payload.clone()
+    }
+
+    /// Nonce of a `BoundChallenge`, to be passed along with its payload when answering it
+    #[wasm_bindgen(getter)]
+    pub fn nonce(&self) -> Option<Vec<u8>> {
+        self.nonce.clone()
+    }
+
+    /// Text of a `Data` message or explanation of a rejection
+    #[wasm_bindgen(getter)]
+    pub fn text(&self) -> Option<String> {
+        self.text.clone()
+    }
+
+    /// Session id of `ServerHello` or of a measurement report
+    #[wasm_bindgen(getter, js_name = sessionId)]
+    pub fn session_id(&self) -> Option<String> {
+        self.session_id.clone()
+    }
+
+    /// Token of `ServerHello` to reconnect with as `client_token` query parameter
+    #[wasm_bindgen(getter, js_name = clientToken)]
+    pub fn client_token(&self) -> Option<String> {
+        self.client_token.clone()
+    }
+
+    /// Algorithms offered in `ServerHello` in order of server preference, one of them
+    /// must be passed to `encodeClientHello`
+    #[wasm_bindgen(getter, js_name = hashAlgorithms)]
+    pub fn hash_algorithms(&self) -> Vec<JsValue> {
+        self.hash_algorithms
+            .iter()
+            .map(|algorithm| JsValue::from_str(algorithm))
+            .collect()
+    }
+
+    /// Score of a measurement report or running sub-score of a progress update, within `0..=100`
+    #[wasm_bindgen(getter)]
+    pub fn score(&self) -> Option<u32> {
+        self.score
+    }
+
+    #[wasm_bindgen(getter, js_name = runsCompleted)]
+    pub fn runs_completed(&self) -> Option<u32> {
+        self.runs_completed
+    }
+
+    /// Whether a closed session performed all of its measurements
+    #[wasm_bindgen(getter)]
+    pub fn completed(&self) -> Option<bool> {
+        self.completed
+    }
+
+    #[wasm_bindgen(getter, js_name = storedRunId)]
+    pub fn stored_run_id(&self) -> Option<String> {
+        self.stored_run_id.clone()
+    }
+
+    /// Why server refused the session (`RateLimited`, `Capacity`, `Maintenance`, `Banned`,
+    /// `Unauthorized`, ...)
+    #[wasm_bindgen(getter, js_name = rejectionReason)]
+    pub fn rejection_reason(&self) -> Option<String> {
+        self.rejection_reason.clone()
+    }
+
+    /// Seconds to wait before retrying a rejected session, unset if retrying is pointless
+    #[wasm_bindgen(getter, js_name = retryAfterSeconds)]
+    pub fn retry_after_seconds(&self) -> Option<u32> {
+        self.retry_after_seconds
+    }
+
+    /// What went wrong according to an error (`ChallengeFailed`, `Timeout`, `ProtocolViolation`, ...)
+    #[wasm_bindgen(getter, js_name = errorCode)]
+    pub fn error_code(&self) -> Option<String> {
+        self.error_code.clone()
+    }
+}
+
+impl From<Message> for DecodedMessage {
+    fn from(message: Message) -> Self {
+        let mut decoded = DecodedMessage {
+            kind: format!("{}", message),
+            ..Default::default()
+        };
+        match message {
+            Message::Challenge(challenge) => decoded.set_challenge(challenge),
+            Message::BoundChallenge { nonce, challenge } => {
+                decoded.set_challenge(challenge);
+                decoded.nonce = Some(nonce);
+            }
+            Message::Data(Data::Info(text)) | Message::Data(Data::Result(text)) => {
+                decoded.text = Some(text)
+            }
+            Message::Data(Data::Error { code, message }) => {
+                decoded.error_code = Some(format!("{:?}", code));
+                decoded.text = Some(message);
+            }
+            Message::Handshake(Handshake::ServerHello {
+                session_id,
+                hash_algorithms,
+                client_token,
+                ..
+            }) => {
+                decoded.session_id = Some(session_id);
+                decoded.client_token = client_token;
+                decoded.hash_algorithms = hash_algorithms
+                    .iter()
+                    .map(|algorithm| format!("{:?}", algorithm))
+                    .collect();
+            }
+            Message::SessionClosed {
+                status,
+                runs_completed,
+                stored_run_id,
+            } => {
+                decoded.completed = Some(status == SessionStatus::Completed);
+                decoded.runs_completed = Some(runs_completed);
+                decoded.stored_run_id = stored_run_id;
+            }
+            Message::MeasurementReport(report) => {
+                decoded.session_id = Some(report.session_id);
+                decoded.score = Some(report.score as u32);
+            }
+            Message::Progress(progress) => {
+                decoded.kind_id = Some(progress.kind_id);
+                decoded.runs_completed = Some(progress.rounds_completed);
+                decoded.score = Some(progress.sub_score as u32);
+            }
+            Message::Rejected {
+                reason,
+                retry_after_seconds,
+                message,
+            } => {
+                decoded.rejection_reason = Some(format!("{:?}", reason));
+                decoded.retry_after_seconds =
+                    retry_after_seconds.map(|seconds| u32::try_from(seconds).unwrap_or(u32::MAX));
+                decoded.text = Some(message);
+            }
+            _ => {}
+        }
+        decoded
+    }
+}
+
+impl DecodedMessage {
+    fn set_challenge(&mut self, challenge: Challenge) {
+        self.kind_id = Some(challenge.kind_id());
+        self.payload = match challenge {
+            Challenge::CPUChallenge(payload)
+            | Challenge::NetworkChallenge(payload)
+            | Challenge::SpotCheckedNetworkChallenge(payload)
+            | Challenge::DiskChallenge(payload)
+            | Challenge::LatencyProbe(payload)
+            | Challenge::DownloadChallenge(payload)
+            | Challenge::UploadChallenge(payload)
+            | Challenge::DatagramChallenge(payload)
+            | Challenge::ChunkProofRequest(payload)
+            | Challenge::Unsupported { payload, .. } => Some(payload),
+        };
+    }
+}
+
+#[wasm_bindgen(js_name = decodeMessage)]
+pub fn decode_message(bytes: &[u8]) -> Result<DecodedMessage, JsValue> {
+    Message::decode(bytes)
+        .map(DecodedMessage::from)
+        .map_err(to_js_error)
+}
+
+/// Answer to `ServerHello`, `algorithm` is one of its `hashAlgorithms`. `networkType` is
+/// `wifi`, `ethernet` or `cellular` if the browser knows it, e.g. from `navigator.connection`.
+/// `clientVersion` labels the run with the version or deployment of the web application.
+/// `bindsNonces` tells server the application answers `BoundChallenge` messages by passing
+/// their `nonce` to the encoders of responses.
+#[wasm_bindgen(js_name = encodeClientHello)]
+pub fn encode_client_hello(
+    algorithm: &str,
+    network_type: Option<String>,
+    client_version: Option<String>,
+    binds_nonces: Option<bool>,
+) -> Result<Vec<u8>, JsValue> {
+    let hash_algorithm = parse_hash_algorithm(algorithm)?;
+    let network_type = match network_type {
+        Some(name) => Some(
+            NetworkType::from_name(&name)
+                .ok_or_else(|| JsValue::from_str("Unknown network type"))?,
+        ),
+        None => None,
+    };
+    Message::Handshake(Handshake::ClientHello {
+        hash_algorithm,
+        supports_chunks: false,
+        network_type,
+        client_version,
+        binds_nonces: binds_nonces.unwrap_or(false),
+        mac_public_key: None,
+    })
+    .encode()
+    .map_err(to_js_error)
+}
+
+fn parse_hash_algorithm(algorithm: &str) -> Result<HashAlgorithm, JsValue> {
+    match algorithm {
+        "Sha256" => Ok(HashAlgorithm::Sha256),
+        "Blake3" => Ok(HashAlgorithm::Blake3),
+        "XxHash64" => Ok(HashAlgorithm::XxHash64),
+        _ => Err(JsValue::from_str("Unknown hash algorithm")),
+    }
+}
+
+/// Answer to a challenge the client can't perform, e.g. disk challenges in a browser
+#[wasm_bindgen(js_name = encodeUnsupportedChallenge)]
+pub fn encode_unsupported_challenge(kind_id: u16) -> Result<Vec<u8>, JsValue> {
+    Message::Response(Response::UnsupportedChallenge { kind_id })
+        .encode()
+        .map_err(to_js_error)
+}
+
+/// Answer to a `LatencyProbe`, should be sent as soon as the probe arrives
+#[wasm_bindgen(js_name = encodeLatencyProbeResponse)]
+pub fn encode_latency_probe_response(probe: Vec<u8>) -> Result<Vec<u8>, JsValue> {
+    Message::Response(Response::LatencyProbeResponse(probe))
+        .encode()
+        .map_err(to_js_error)
+}
+
+/// Answer to a `DownloadChallenge`, `algorithm` is the one chosen in `ClientHello`. `nonce`
+/// is the one of a `BoundChallenge`, unset for a plain `Challenge`.
+#[wasm_bindgen(js_name = encodeDownloadChallengeResponse)]
+pub fn encode_download_challenge_response(
+    payload: Vec<u8>,
+    algorithm: &str,
+    nonce: Option<Vec<u8>>,
+) -> Result<Vec<u8>, JsValue> {
+    let hash = Download::from_wire(payload).perform_challenge(
+        parse_hash_algorithm(algorithm)?,
+        nonce.as_deref().unwrap_or_default(),
+    );
+    Message::Response(Response::DownloadChallengeResponse(hash))
+        .encode()
+        .map_err(to_js_error)
+}
+
+/// Answer to an `UploadChallenge`, carrying the data expanded from its seed and `nonce`
+#[wasm_bindgen(js_name = encodeUploadChallengeResponse)]
+pub fn encode_upload_challenge_response(
+    payload: Vec<u8>,
+    nonce: Option<Vec<u8>>,
+) -> Result<Vec<u8>, JsValue> {
+    let data = Upload::from_wire(payload)
+        .map_err(to_js_error)?
+        .perform_challenge(nonce.as_deref().unwrap_or_default());
+    Message::Response(Response::UploadChallengeResponse(data))
+        .encode()
+        .map_err(to_js_error)
+}
+
+/// Timelock puzzle of a CPU challenge
+#[wasm_bindgen(js_name = Timelock)]
+pub struct WasmTimelock(Timelock);
+
+#[wasm_bindgen(js_class = Timelock)]
+impl WasmTimelock {
+    #[wasm_bindgen(js_name = fromWire)]
+    pub fn from_wire(payload: Vec<u8>) -> Result<WasmTimelock, JsValue> {
+        Timelock::from_wire(payload)
+            .map(WasmTimelock)
+            .map_err(to_js_error)
+    }
+
+    /// Solves the puzzle bound to `nonce` of a `BoundChallenge`, blocking for as long as it
+    /// takes. Should be run in a web worker. Returns the encoded response message.
+    #[wasm_bindgen(js_name = performChallenge)]
+    pub fn perform_challenge(&self, nonce: Option<Vec<u8>>) -> Result<Vec<u8>, JsValue> {
+        let answer = self
+            .0
+            .bind(nonce.as_deref().unwrap_or_default())
+            .perform_challenge()
+            .to_bytes_be();
+        Message::Response(Response::CPUChallengeResponse(answer))
+            .encode()
+            .map_err(to_js_error)
+    }
+}
+
+/// Payload of a network challenge
+#[wasm_bindgen(js_name = Roundtrip)]
+pub struct WasmRoundtrip {
+    data: Vec<u8>,
+}
+
+#[wasm_bindgen(js_class = Roundtrip)]
+impl WasmRoundtrip {
+    #[wasm_bindgen(js_name = fromWire)]
+    pub fn from_wire(payload: Vec<u8>) -> WasmRoundtrip {
+        WasmRoundtrip {
+            data: Roundtrip::from_wire(payload).to_wire(),
+        }
+    }
+
+    /// Encoded response to a `NetworkChallenge`, echoing the payload back after `nonce` of
+    /// a `BoundChallenge`
+    pub fn response(&self, nonce: Option<Vec<u8>>) -> Result<Vec<u8>, JsValue> {
+        let echo = nonce::bound_echo(nonce.as_deref().unwrap_or_default(), self.data.clone());
+        Message::Response(Response::NetworkChallengeResponse(echo))
+            .encode()
+            .map_err(to_js_error)
+    }
+
+    /// Encoded response to a `SpotCheckedNetworkChallenge`, echoing the payload back along with
+    /// its merkle root bound to `nonce` of a `BoundChallenge`
+    #[wasm_bindgen(js_name = spotCheckedResponse)]
+    pub fn spot_checked_response(&self, nonce: Option<Vec<u8>>) -> Result<Vec<u8>, JsValue> {
+        Message::Response(Response::SpotCheckedNetworkChallengeResponse {
+            merkle_root: nonce::bound_merkle_root(
+                nonce.as_deref().unwrap_or_default(),
+                merkle_root(&self.data),
+            ),
+            data: self.data.clone(),
+        })
+        .encode()
+        .map_err(to_js_error)
+    }
+
+    /// Encoded response to a `ChunkProofRequest` which followed the `SpotCheckedNetworkChallenge`
+    /// of this payload, `request` is the payload of the request
+    #[wasm_bindgen(js_name = chunkProofResponse)]
+    pub fn chunk_proof_response(&self, request: &[u8]) -> Result<Vec<u8>, JsValue> {
+        let (chunk, proof) = merkle::decode_chunk_index(request)
+            .and_then(|index| merkle::chunk_with_proof(&self.data, index as usize))
+            .ok_or_else(|| JsValue::from_str("No such chunk"))?;
+        Message::Response(Response::ChunkProofResponse { chunk, proof })
+            .encode()
+            .map_err(to_js_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::challenges::timelock::Timelock;
+    use crate::hash::HashAlgorithm;
+    use crate::std_alloc::ToOwned;
+    use crate::wasm::{decode_message, WasmRoundtrip, WasmTimelock};
+    use crate::{Challenge, Data, ErrorCode, Handshake, Message, Response, SessionLimits};
+    use rand::rngs::OsRng;
+
+    #[test]
+    fn test_session_messages() {
+        let hello = Message::Handshake(Handshake::ServerHello {
+            session_id: "abc".to_owned(),
+            hash_algorithms: vec![HashAlgorithm::Blake3, HashAlgorithm::Sha256],
+            limits: SessionLimits {
+                max_payload_size_kb: 1,
+                cpu_rounds: 1,
+                network_rounds: 1,
+                disk_rounds: 0,
+                challenge_kinds: vec![],
+                encodings: vec![],
+                cpu_round_timeout_milliseconds: 1,
+                network_round_timeout_milliseconds: 1,
+                latency_probes: 0,
+                download_rounds: 0,
+                upload_rounds: 0,
+                datagram_probes: 0,
+                max_frame_size_kb: None,
+            },
+            client_token: Some("token".to_owned()),
+            mac_public_key: None,
+        });
+        let decoded = decode_message(&hello.encode().unwrap()).unwrap();
+        assert_eq!(decoded.kind(), "Handshake");
+        assert_eq!(decoded.session_id().unwrap(), "abc");
+        assert_eq!(decoded.client_token().unwrap(), "token");
+        assert_eq!(decoded.hash_algorithms, vec!["Blake3", "Sha256"]);
+
+        let (timelock, verifier) = Timelock::generate(&mut OsRng, 10).unwrap();
+        let challenge = Message::Challenge(Challenge::CPUChallenge(timelock.to_wire()));
+        let decoded = decode_message(&challenge.encode().unwrap()).unwrap();
+        assert_eq!(decoded.kind_id(), Some(challenge_kind(&challenge)));
+        let response = WasmTimelock::from_wire(decoded.payload().unwrap())
+            .unwrap()
+            .perform_challenge(None)
+            .unwrap();
+        match Message::decode(&response).unwrap() {
+            Message::Response(Response::CPUChallengeResponse(answer)) => {
+                assert!(verifier.verify(num_bigint::BigUint::from_bytes_be(&answer)))
+            }
+            message => panic!("Unexpected {}", message),
+        }
+
+        let response = WasmRoundtrip::from_wire(vec![1, 2, 3])
+            .response(None)
+            .unwrap();
+        match Message::decode(&response).unwrap() {
+            Message::Response(response) => {
+                assert_eq!(response, Response::NetworkChallengeResponse(vec![1, 2, 3]))
+            }
+            message => panic!("Unexpected {}", message),
+        }
+
+        let bound = Message::BoundChallenge {
+            nonce: vec![5; 16],
+            challenge: Challenge::NetworkChallenge(vec![1, 2, 3]),
+        };
+        let decoded = decode_message(&bound.encode().unwrap()).unwrap();
+        assert_eq!(decoded.kind(), "BoundChallenge");
+        let response = WasmRoundtrip::from_wire(decoded.payload().unwrap())
+            .response(decoded.nonce())
+            .unwrap();
+        match Message::decode(&response).unwrap() {
+            Message::Response(Response::NetworkChallengeResponse(echo)) => {
+                assert_eq!(echo[..16], [5; 16]);
+                assert_eq!(echo[16..], [1, 2, 3]);
+            }
+            message => panic!("Unexpected {}", message),
+        }
+
+        let error = Message::Data(Data::Error {
+            code: ErrorCode::Timeout,
+            message: "No response within 5s".to_owned(),
+        });
+        let decoded = decode_message(&error.encode().unwrap()).unwrap();
+        assert_eq!(decoded.error_code().unwrap(), "Timeout");
+        assert_eq!(decoded.text().unwrap(), "No response within 5s");
+    }
+
+    fn challenge_kind(message: &Message) -> u16 {
+        match message {
+            Message::Challenge(challenge) => challenge.kind_id(),
+            _ => unreachable!(),
+        }
+    }
+}
@@ -0,0 +1,530 @@
+use crate::kinds::{
+    challenge, data, error_code, message, network_type, rejection_reason, response, session_status,
+};
+use crate::std_alloc::{Box, String, Vec};
+use crate::{
+    Challenge, Data, ErrorCode, Message, NetworkType, RejectionReason, Response, SessionStatus,
+};
+use core::fmt;
+use serde::de::{self, Expected, IgnoredAny, SeqAccess, Visitor};
+use serde::ser::SerializeTuple;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Opaque bytes, serialized as msgpack `bin` instead of an array of integers
+struct BorrowedPayload<'a>(&'a [u8]);
+
+impl<'a> Serialize for BorrowedPayload<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.0)
+    }
+}
+
+/// Deserialized counterpart of `BorrowedPayload`, also accepts an array of integers
+struct RawPayload(Vec<u8>);
+
+struct RawPayloadVisitor;
+
+impl<'de> Visitor<'de> for RawPayloadVisitor {
+    type Value = RawPayload;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("bytes")
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+        Ok(RawPayload(v.to_vec()))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+        Ok(RawPayload(v))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut bytes = Vec::new();
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(RawPayload(bytes))
+    }
+}
+
+impl<'de> Deserialize<'de> for RawPayload {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_byte_buf(RawPayloadVisitor)
+    }
+}
+
+fn serialize_variant<S: Serializer, T: Serialize>(
+    serializer: S,
+    kind_id: u16,
+    payload: &T,
+) -> Result<S::Ok, S::Error> {
+    let mut tuple = serializer.serialize_tuple(2)?;
+    tuple.serialize_element(&kind_id)?;
+    tuple.serialize_element(payload)?;
+    tuple.end()
+}
+
+fn next<'de, T: Deserialize<'de>, A: SeqAccess<'de>>(
+    seq: &mut A,
+    index: usize,
+    expected: &dyn Expected,
+) -> Result<T, A::Error> {
+    seq.next_element()?
+        .ok_or_else(|| de::Error::invalid_length(index, expected))
+}
+
+impl Serialize for Message {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Message::Challenge(challenge) => {
+                serialize_variant(serializer, message::CHALLENGE, challenge)
+            }
+            Message::Response(response) => {
+                serialize_variant(serializer, message::RESPONSE, response)
+            }
+            Message::Data(data) => serialize_variant(serializer, message::DATA, data),
+            Message::Unknown => serialize_variant(serializer, message::UNKNOWN, &()),
+            Message::Handshake(handshake) => {
+                serialize_variant(serializer, message::HANDSHAKE, handshake)
+            }
+            Message::SessionClosed {
+                status,
+                runs_completed,
+                stored_run_id,
+            } => serialize_variant(
+                serializer,
+                message::SESSION_CLOSED,
+                &(status, runs_completed, stored_run_id),
+            ),
+            Message::MeasurementReport(report) => {
+                serialize_variant(serializer, message::MEASUREMENT_REPORT, &**report)
+            }
+            Message::Progress(progress) => {
+                serialize_variant(serializer, message::PROGRESS, progress)
+            }
+            Message::Rejected {
+                reason,
+                retry_after_seconds,
+                message,
+            } => serialize_variant(
+                serializer,
+                message::REJECTED,
+                &(reason, retry_after_seconds, message),
+            ),
+            Message::ChallengeChunk { index, total, data } => serialize_variant(
+                serializer,
+                message::CHALLENGE_CHUNK,
+                &(index, total, BorrowedPayload(data)),
+            ),
+            Message::BoundChallenge { nonce, challenge } => serialize_variant(
+                serializer,
+                message::BOUND_CHALLENGE,
+                &(BorrowedPayload(nonce), challenge),
+            ),
+        }
+    }
+}
+
+struct MessageVisitor;
+
+impl<'de> Visitor<'de> for MessageVisitor {
+    type Value = Message;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("message kind id followed by its payload")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let kind_id: u16 = next(&mut seq, 0, &self)?;
+        Ok(match kind_id {
+            message::CHALLENGE => Message::Challenge(next(&mut seq, 1, &self)?),
+            message::RESPONSE => Message::Response(next(&mut seq, 1, &self)?),
+            message::DATA => Message::Data(next(&mut seq, 1, &self)?),
+            message::HANDSHAKE => Message::Handshake(next(&mut seq, 1, &self)?),
+            message::SESSION_CLOSED => {
+                let (status, runs_completed, stored_run_id): (SessionStatus, u32, Option<String>) =
+                    next(&mut seq, 1, &self)?;
+                Message::SessionClosed {
+                    status,
+                    runs_completed,
+                    stored_run_id,
+                }
+            }
+            message::MEASUREMENT_REPORT => {
+                Message::MeasurementReport(Box::new(next(&mut seq, 1, &self)?))
+            }
+            message::PROGRESS => Message::Progress(next(&mut seq, 1, &self)?),
+            message::REJECTED => {
+                let (reason, retry_after_seconds, message): (RejectionReason, Option<u64>, String) =
+                    next(&mut seq, 1, &self)?;
+                Message::Rejected {
+                    reason,
+                    retry_after_seconds,
+                    message,
+                }
+            }
+            message::CHALLENGE_CHUNK => {
+                let (index, total, RawPayload(data)): (u32, u32, RawPayload) =
+                    next(&mut seq, 1, &self)?;
+                Message::ChallengeChunk { index, total, data }
+            }
+            message::BOUND_CHALLENGE => {
+                let (RawPayload(nonce), challenge): (RawPayload, Challenge) =
+                    next(&mut seq, 1, &self)?;
+                Message::BoundChallenge { nonce, challenge }
+            }
+            _ => {
+                next::<IgnoredAny, A>(&mut seq, 1, &self)?;
+                Message::Unknown
+            }
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Message {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_tuple(2, MessageVisitor)
+    }
+}
+
+impl Serialize for Challenge {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let payload = match self {
+            Challenge::CPUChallenge(payload)
+            | Challenge::NetworkChallenge(payload)
+            | Challenge::SpotCheckedNetworkChallenge(payload)
+            | Challenge::DiskChallenge(payload)
+            | Challenge::LatencyProbe(payload)
+            | Challenge::DownloadChallenge(payload)
+            | Challenge::UploadChallenge(payload)
+            | Challenge::DatagramChallenge(payload)
+            | Challenge::ChunkProofRequest(payload)
+            | Challenge::Unsupported { payload, .. } => payload,
+        };
+        serialize_variant(serializer, self.kind_id(), &BorrowedPayload(payload))
+    }
+}
+
+struct ChallengeVisitor;
+
+impl<'de> Visitor<'de> for ChallengeVisitor {
+    type Value = Challenge;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("challenge kind id followed by its payload")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let kind_id: u16 = next(&mut seq, 0, &self)?;
+        // All challenges carry opaque bytes, so payload of an unknown challenge is kept as is
+        let payload = next::<RawPayload, A>(&mut seq, 1, &self)?.0;
+        Ok(match kind_id {
+            challenge::CPU_CHALLENGE => Challenge::CPUChallenge(payload),
+            challenge::NETWORK_CHALLENGE => Challenge::NetworkChallenge(payload),
+            challenge::SPOT_CHECKED_NETWORK_CHALLENGE => {
+                Challenge::SpotCheckedNetworkChallenge(payload)
+            }
+            challenge::DISK_CHALLENGE => Challenge::DiskChallenge(payload),
+            challenge::LATENCY_PROBE => Challenge::LatencyProbe(payload),
+            challenge::DOWNLOAD_CHALLENGE => Challenge::DownloadChallenge(payload),
+            challenge::UPLOAD_CHALLENGE => Challenge::UploadChallenge(payload),
+            challenge::DATAGRAM_CHALLENGE => Challenge::DatagramChallenge(payload),
+            challenge::CHUNK_PROOF_REQUEST => Challenge::ChunkProofRequest(payload),
+            kind_id => Challenge::Unsupported { kind_id, payload },
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Challenge {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_tuple(2, ChallengeVisitor)
+    }
+}
+
+impl Serialize for Response {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Response::CPUChallengeResponse(payload) => serialize_variant(
+                serializer,
+                response::CPU_CHALLENGE_RESPONSE,
+                &BorrowedPayload(payload),
+            ),
+            Response::NetworkChallengeResponse(payload) => serialize_variant(
+                serializer,
+                response::NETWORK_CHALLENGE_RESPONSE,
+                &BorrowedPayload(payload),
+            ),
+            Response::SpotCheckedNetworkChallengeResponse { data, merkle_root } => {
+                serialize_variant(
+                    serializer,
+                    response::SPOT_CHECKED_NETWORK_CHALLENGE_RESPONSE,
+                    &(BorrowedPayload(data), BorrowedPayload(merkle_root)),
+                )
+            }
+            Response::UnsupportedChallenge { kind_id } => {
+                serialize_variant(serializer, response::UNSUPPORTED_CHALLENGE, kind_id)
+            }
+            Response::DiskChallengeResponse(hash) => serialize_variant(
+                serializer,
+                response::DISK_CHALLENGE_RESPONSE,
+                &BorrowedPayload(hash),
+            ),
+            Response::CPUChallengeProofResponse { answer, proof } => serialize_variant(
+                serializer,
+                response::CPU_CHALLENGE_PROOF_RESPONSE,
+                &(BorrowedPayload(answer), BorrowedPayload(proof)),
+            ),
+            Response::LatencyProbeResponse(probe) => serialize_variant(
+                serializer,
+                response::LATENCY_PROBE_RESPONSE,
+                &BorrowedPayload(probe),
+            ),
+            Response::DownloadChallengeResponse(hash) => serialize_variant(
+                serializer,
+                response::DOWNLOAD_CHALLENGE_RESPONSE,
+                &BorrowedPayload(hash),
+            ),
+            Response::UploadChallengeResponse(data) => serialize_variant(
+                serializer,
+                response::UPLOAD_CHALLENGE_RESPONSE,
+                &BorrowedPayload(data),
+            ),
+            Response::DatagramChallengeResponse(receipt) => serialize_variant(
+                serializer,
+                response::DATAGRAM_CHALLENGE_RESPONSE,
+                &BorrowedPayload(receipt),
+            ),
+            Response::ChunkProofResponse { chunk, proof } => serialize_variant(
+                serializer,
+                response::CHUNK_PROOF_RESPONSE,
+                &(BorrowedPayload(chunk), BorrowedPayload(proof)),
+            ),
+            Response::Unknown { kind_id } => serialize_variant(serializer, *kind_id, &()),
+        }
+    }
+}
+
+struct ResponseVisitor;
+
+impl<'de> Visitor<'de> for ResponseVisitor {
+    type Value = Response;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("response kind id followed by its payload")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let kind_id: u16 = next(&mut seq, 0, &self)?;
+        Ok(match kind_id {
+            response::CPU_CHALLENGE_RESPONSE => {
+                Response::CPUChallengeResponse(next::<RawPayload, A>(&mut seq, 1, &self)?.0)
+            }
+            response::NETWORK_CHALLENGE_RESPONSE => {
+                Response::NetworkChallengeResponse(next::<RawPayload, A>(&mut seq, 1, &self)?.0)
+            }
+            response::SPOT_CHECKED_NETWORK_CHALLENGE_RESPONSE => {
+                let (data, merkle_root) = next::<(RawPayload, RawPayload), A>(&mut seq, 1, &self)?;
+                Response::SpotCheckedNetworkChallengeResponse {
+                    data: data.0,
+                    merkle_root: merkle_root.0,
+                }
+            }
+            response::UNSUPPORTED_CHALLENGE => Response::UnsupportedChallenge {
+                kind_id: next(&mut seq, 1, &self)?,
+            },
+            response::DISK_CHALLENGE_RESPONSE => {
+                Response::DiskChallengeResponse(next::<RawPayload, A>(&mut seq, 1, &self)?.0)
+            }
+            response::CPU_CHALLENGE_PROOF_RESPONSE => {
+                let (answer, proof) = next::<(RawPayload, RawPayload), A>(&mut seq, 1, &self)?;
+                Response::CPUChallengeProofResponse {
+                    answer: answer.0,
+                    proof: proof.0,
+                }
+            }
+            response::LATENCY_PROBE_RESPONSE => {
+                Response::LatencyProbeResponse(next::<RawPayload, A>(&mut seq, 1, &self)?.0)
+            }
+            response::DOWNLOAD_CHALLENGE_RESPONSE => {
+                Response::DownloadChallengeResponse(next::<RawPayload, A>(&mut seq, 1, &self)?.0)
+            }
+            response::UPLOAD_CHALLENGE_RESPONSE => {
+                Response::UploadChallengeResponse(next::<RawPayload, A>(&mut seq, 1, &self)?.0)
+            }
+            response::DATAGRAM_CHALLENGE_RESPONSE => {
+                Response::DatagramChallengeResponse(next::<RawPayload, A>(&mut seq, 1, &self)?.0)
+            }
+            response::CHUNK_PROOF_RESPONSE => {
+                let (chunk, proof) = next::<(RawPayload, RawPayload), A>(&mut seq, 1, &self)?;
+                Response::ChunkProofResponse {
+                    chunk: chunk.0,
+                    proof: proof.0,
+                }
+            }
+            kind_id => {
+                next::<IgnoredAny, A>(&mut seq, 1, &self)?;
+                Response::Unknown { kind_id }
+            }
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Response {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_tuple(2, ResponseVisitor)
+    }
+}
+
+impl Serialize for Data {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Data::Info(info) => serialize_variant(serializer, data::INFO, info),
+            // Sent the way peers which predate error codes send errors
+            Data::Error {
+                code: ErrorCode::Unspecified,
+                message,
+            } => serialize_variant(serializer, data::ERROR, message),
+            Data::Error { code, message } => {
+                serialize_variant(serializer, data::CODED_ERROR, &(code, message))
+            }
+            Data::Result(result) => serialize_variant(serializer, data::RESULT, result),
+            Data::Unknown { kind_id } => serialize_variant(serializer, *kind_id, &()),
+        }
+    }
+}
+
+struct DataVisitor;
+
+impl<'de> Visitor<'de> for DataVisitor {
+    type Value = Data;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("data kind id followed by its payload")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let kind_id: u16 = next(&mut seq, 0, &self)?;
+        Ok(match kind_id {
+            data::INFO => Data::Info(next(&mut seq, 1, &self)?),
+            data::ERROR => Data::Error {
+                code: ErrorCode::Unspecified,
+                message: next(&mut seq, 1, &self)?,
+            },
+            data::CODED_ERROR => {
+                let (code, message) = next(&mut seq, 1, &self)?;
+                Data::Error { code, message }
+            }
+            data::RESULT => Data::Result(next(&mut seq, 1, &self)?),
+            kind_id => {
+                next::<IgnoredAny, A>(&mut seq, 1, &self)?;
+                Data::Unknown { kind_id }
+            }
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Data {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_tuple(2, DataVisitor)
+    }
+}
+
+impl Serialize for SessionStatus {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u16(match self {
+            SessionStatus::Completed => session_status::COMPLETED,
+            SessionStatus::Failed => session_status::FAILED,
+            SessionStatus::Unknown { kind_id } => *kind_id,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for SessionStatus {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(match u16::deserialize(deserializer)? {
+            session_status::COMPLETED => SessionStatus::Completed,
+            session_status::FAILED => SessionStatus::Failed,
+            kind_id => SessionStatus::Unknown { kind_id },
+        })
+    }
+}
+
+impl Serialize for RejectionReason {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u16(match self {
+            RejectionReason::RateLimited => rejection_reason::RATE_LIMITED,
+            RejectionReason::Capacity => rejection_reason::CAPACITY,
+            RejectionReason::Maintenance => rejection_reason::MAINTENANCE,
+            RejectionReason::Banned => rejection_reason::BANNED,
+            RejectionReason::Unauthorized => rejection_reason::UNAUTHORIZED,
+            RejectionReason::Unknown { kind_id } => *kind_id,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for RejectionReason {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(match u16::deserialize(deserializer)? {
+            rejection_reason::RATE_LIMITED => RejectionReason::RateLimited,
+            rejection_reason::CAPACITY => RejectionReason::Capacity,
+            rejection_reason::MAINTENANCE => RejectionReason::Maintenance,
+            rejection_reason::BANNED => RejectionReason::Banned,
+            rejection_reason::UNAUTHORIZED => RejectionReason::Unauthorized,
+            kind_id => RejectionReason::Unknown { kind_id },
+        })
+    }
+}
+
+impl Serialize for NetworkType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u16(match self {
+            NetworkType::Wifi => network_type::WIFI,
+            NetworkType::Ethernet => network_type::ETHERNET,
+            NetworkType::Cellular => network_type::CELLULAR,
+            NetworkType::Unknown { kind_id } => *kind_id,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for NetworkType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(match u16::deserialize(deserializer)? {
+            network_type::WIFI => NetworkType::Wifi,
+            network_type::ETHERNET => NetworkType::Ethernet,
+            network_type::CELLULAR => NetworkType::Cellular,
+            kind_id => NetworkType::Unknown { kind_id },
+        })
+    }
+}
+
+impl Serialize for ErrorCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u16(match self {
+            ErrorCode::ChallengeFailed => error_code::CHALLENGE_FAILED,
+            ErrorCode::Timeout => error_code::TIMEOUT,
+            ErrorCode::ProtocolViolation => error_code::PROTOCOL_VIOLATION,
+            ErrorCode::RateLimited => error_code::RATE_LIMITED,
+            ErrorCode::UnsupportedVersion => error_code::UNSUPPORTED_VERSION,
+            ErrorCode::MemoryLimitExceeded => error_code::MEMORY_LIMIT_EXCEEDED,
+            // `Data` sends errors without a code as `data::ERROR`, so this is never on the wire
+            ErrorCode::Unspecified => 0,
+            ErrorCode::Unknown { kind_id } => *kind_id,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for ErrorCode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(match u16::deserialize(deserializer)? {
+            error_code::CHALLENGE_FAILED => ErrorCode::ChallengeFailed,
+            error_code::TIMEOUT => ErrorCode::Timeout,
+            error_code::PROTOCOL_VIOLATION => ErrorCode::ProtocolViolation,
+            error_code::RATE_LIMITED => ErrorCode::RateLimited,
+            error_code::UNSUPPORTED_VERSION => ErrorCode::UnsupportedVersion,
+            error_code::MEMORY_LIMIT_EXCEEDED => ErrorCode::MemoryLimitExceeded,
+            0 => ErrorCode::Unspecified,
+            kind_id => ErrorCode::Unknown { kind_id },
+        })
+    }
+}